@@ -0,0 +1,58 @@
+use std::sync::Arc;
+
+use bytes::Bytes;
+use criterion::{criterion_group, criterion_main, Criterion, Throughput};
+use lasagnedb::log_store::LogStore;
+use lasagnedb::Db;
+
+const MESSAGES: u64 = 10000;
+
+fn criterion_benchmark(c: &mut Criterion) {
+    let tmp_dir = tempfile::tempdir().unwrap();
+    let db = Arc::new(Db::open(tmp_dir.path()).unwrap());
+    let log = LogStore::new(db, "log/");
+    let value = Bytes::from(vec![b'm'; 100]);
+
+    let mut group = c.benchmark_group("log");
+    group.sample_size(10);
+    group.throughput(Throughput::Elements(MESSAGES));
+    // 顺序追加，key 单调递增
+    group.bench_function("append", |b| {
+        b.iter(|| {
+            for _ in 0..MESSAGES {
+                log.append("bench", value.clone()).unwrap();
+            }
+        })
+    });
+    // 每批 100 条
+    group.bench_function("append batch", |b| {
+        b.iter(|| {
+            for _ in 0..MESSAGES / 100 {
+                log.append_batch("bench", vec![value.clone(); 100]).unwrap();
+            }
+        })
+    });
+    // 从头尾随读取已写入的消息
+    group.bench_function("tail", |b| {
+        b.iter(|| {
+            let start = log.offsets("bench").unwrap().start;
+            let mut tail = log.tail("bench", start).unwrap();
+            for _ in 0..MESSAGES {
+                tail.poll().unwrap().unwrap();
+            }
+        })
+    });
+    // 截断最早的消息，之后的读取要跳过删除标记
+    group.bench_function("truncate", |b| {
+        b.iter(|| {
+            let start = log.offsets("bench").unwrap().start;
+            log.truncate("bench", start + MESSAGES).unwrap();
+            log.append_batch("bench", vec![value.clone(); MESSAGES as usize])
+                .unwrap();
+        })
+    });
+    group.finish();
+}
+
+criterion_group!(benches, criterion_benchmark);
+criterion_main!(benches);
@@ -1,8 +1,10 @@
 use bytes::{Bytes, BytesMut};
 use criterion::{criterion_group, criterion_main, Criterion};
-use lasagnedb::KB;
+use lasagnedb::{DbOptions, MicroBatchOptions, KB};
 use rand::RngCore;
 use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
 use tracing_subscriber::layer::SubscriberExt;
 use tracing_subscriber::Registry;
 
@@ -20,6 +22,24 @@ fn put_small_value(db: Arc<lasagnedb::Db>) {
     db.put(key, value).unwrap();
 }
 
+/// `threads` 个线程各写入 `iters` 个小 value 的总耗时，用于比较是否开启分组提交
+fn put_small_values_concurrently(db: &lasagnedb::Db, threads: u64, iters: u64) -> Duration {
+    let started_at = Instant::now();
+    thread::scope(|s| {
+        for _ in 0..threads {
+            s.spawn(|| {
+                let mut rng = rand::thread_rng();
+                for _ in 0..iters {
+                    let key = Bytes::from(format!("{:020}", rng.next_u64()));
+                    let value = Bytes::from(format!("{:020}", rng.next_u64()));
+                    db.put(key, value).unwrap();
+                }
+            });
+        }
+    });
+    started_at.elapsed()
+}
+
 fn setup() {
     if let Some(jaeger_endpoint) = option_env!("JAEGER_ENDPOINT") {
         println!("JAEGER_ENDPOINT: {}", jaeger_endpoint);
@@ -47,6 +67,28 @@ fn criterion_benchmark(c: &mut Criterion) {
         b.iter(|| put_small_value(db.clone()))
     });
     c.bench_function("put big value", |b| b.iter(|| put_big_value(db.clone())));
+
+    const THREADS: u64 = 8;
+    for (name, micro_batch) in [
+        ("put small value x8 threads", None),
+        (
+            "put small value x8 threads (micro batch)",
+            Some(MicroBatchOptions::default()),
+        ),
+    ] {
+        let dir = tempfile::tempdir().unwrap();
+        let options = DbOptions {
+            micro_batch,
+            ..Default::default()
+        };
+        let db = lasagnedb::Db::open_with_options(dir.path(), options).unwrap();
+        // 每次迭代计为一个线程的一次写入
+        c.bench_function(name, |b| {
+            b.iter_custom(|iters| {
+                put_small_values_concurrently(&db, THREADS, iters.div_ceil(THREADS))
+            })
+        });
+    }
 }
 
 criterion_group!(benches, criterion_benchmark);
@@ -0,0 +1,32 @@
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+use lasagnedb::test_util::RecoveryBench;
+
+fn criterion_benchmark(c: &mut Criterion) {
+    let mut group = c.benchmark_group("recovery");
+    group.sample_size(10);
+    for records in [10000, 100000] {
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let bench = RecoveryBench::new(tmp_dir.path()).records(records);
+        bench.populate().unwrap();
+
+        // 单次打开的各阶段耗时，用于定位退化发生在哪个阶段
+        let recovery = bench.measure().unwrap();
+        println!(
+            "{} WAL records: {:?}, replay {:.1} MB/s",
+            records,
+            recovery,
+            recovery.wal_replay_throughput() / (1024.0 * 1024.0)
+        );
+
+        group.throughput(Throughput::Elements(records as u64));
+        group.bench_with_input(
+            BenchmarkId::new("open with WAL backlog", records),
+            &bench,
+            |b, bench| b.iter(|| bench.measure().unwrap()),
+        );
+    }
+    group.finish();
+}
+
+criterion_group!(benches, criterion_benchmark);
+criterion_main!(benches);
@@ -0,0 +1,61 @@
+use std::ops::Bound;
+
+use bytes::Bytes;
+use criterion::{criterion_group, criterion_main, Criterion, Throughput};
+use lasagnedb::{Db, ReadOptions, StorageIterator};
+
+const RECORDS: usize = 100000;
+
+fn populate(db: &Db, value_size: usize) {
+    for i in 0..RECORDS {
+        let key = Bytes::from(format!("{:020}", i));
+        let value = Bytes::from(format!("{:0width$}", i, width = value_size));
+        db.put(key, value).unwrap();
+    }
+    db.flush().unwrap();
+}
+
+fn scan(db: &Db, options: &ReadOptions) -> usize {
+    let mut iter = db
+        .scan_with_options(Bound::Unbounded, Bound::Unbounded, options)
+        .unwrap();
+    let mut count = 0;
+    while iter.is_valid() {
+        count += 1;
+        iter.next().unwrap();
+    }
+    count
+}
+
+fn criterion_benchmark(c: &mut Criterion) {
+    let tmp_dir = tempfile::tempdir().unwrap();
+    let db = Db::open(tmp_dir.path()).unwrap();
+    populate(&db, 100);
+
+    let mut group = c.benchmark_group("scan");
+    group.sample_size(10);
+    group.throughput(Throughput::Elements(RECORDS as u64));
+    // 块已在缓存中，只有迭代和取出 entry 的开销
+    let options = ReadOptions::default();
+    assert_eq!(scan(&db, &options), RECORDS);
+    group.bench_function("cached blocks", |b| b.iter(|| scan(&db, &options)));
+    // 每次都从文件读出并解码块
+    let options = ReadOptions {
+        fill_cache: false,
+        ..Default::default()
+    };
+    group.bench_function("uncached blocks", |b| b.iter(|| scan(&db, &options)));
+
+    // 所有 value 都分离到 VSST 中，每个 entry 都要再读一次 VSST
+    let tmp_dir = tempfile::tempdir().unwrap();
+    let db = Db::open(tmp_dir.path()).unwrap();
+    db.set_value_separation_threshold(Some(64));
+    populate(&db, 200);
+    let options = ReadOptions::default();
+    assert_eq!(scan(&db, &options), RECORDS);
+    group.bench_function("separated values", |b| b.iter(|| scan(&db, &options)));
+    group.finish();
+}
+
+criterion_group!(benches, criterion_benchmark);
+criterion_main!(benches);
@@ -0,0 +1,139 @@
+//! 以不同的 KV 分离阈值（包括关闭）运行相同的负载，比较写入、点查和范围查询的吞吐以及占用的磁盘空间
+//!
+//! value 大小可以用环境变量 `SEPARATION_BENCH_VALUE_SIZES` 指定，以逗号分隔，单位为字节，例如
+//! `SEPARATION_BENCH_VALUE_SIZES=512,8192 cargo bench --bench lasagnedb_separation_bench`
+
+use std::fs;
+use std::ops::Bound;
+use std::path::Path;
+
+use bytes::{Bytes, BytesMut};
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+use lasagnedb::{Db, DbOptions, StorageIterator, KB, MB};
+use rand::{Rng, RngCore};
+
+/// 每组负载写入的数据总量，key 数量为总量除以 value 大小
+const DATASET_SIZE: usize = 32 * MB;
+const DEFAULT_VALUE_SIZES: [usize; 3] = [100, KB, 16 * KB];
+
+fn value_sizes() -> Vec<usize> {
+    match std::env::var("SEPARATION_BENCH_VALUE_SIZES") {
+        Ok(sizes) => sizes
+            .split(',')
+            .map(|size| size.trim().parse().expect("invalid value size"))
+            .collect(),
+        Err(_) => DEFAULT_VALUE_SIZES.to_vec(),
+    }
+}
+
+/// 关闭 KV 分离、较小的阈值和默认阈值
+fn thresholds() -> [Option<u64>; 3] {
+    [
+        None,
+        Some(256),
+        DbOptions::default().value_separation_threshold,
+    ]
+}
+
+fn threshold_name(threshold: Option<u64>) -> String {
+    match threshold {
+        None => "disabled".to_string(),
+        Some(threshold) => format!("threshold={}", threshold),
+    }
+}
+
+fn key_of(i: usize) -> Bytes {
+    Bytes::from(format!("{:020}", i))
+}
+
+fn random_value(value_size: usize) -> Bytes {
+    let mut value = BytesMut::zeroed(value_size);
+    rand::thread_rng().fill_bytes(&mut value);
+    value.freeze()
+}
+
+fn open_db(path: &Path, threshold: Option<u64>) -> Db {
+    let db = Db::open(path).unwrap();
+    db.set_value_separation_threshold(threshold);
+    db
+}
+
+fn populate(db: &Db, records: usize, value_size: usize) {
+    for i in 0..records {
+        db.put(key_of(i), random_value(value_size)).unwrap();
+    }
+    db.flush().unwrap();
+}
+
+fn scan(db: &Db) -> usize {
+    let mut iter = db.scan(Bound::Unbounded, Bound::Unbounded).unwrap();
+    let mut count = 0;
+    while iter.is_valid() {
+        count += 1;
+        iter.next().unwrap();
+    }
+    count
+}
+
+/// 目录下所有文件的总大小
+fn disk_usage(path: &Path) -> u64 {
+    fs::read_dir(path)
+        .unwrap()
+        .map(|entry| entry.unwrap().metadata().unwrap())
+        .filter(|metadata| metadata.is_file())
+        .map(|metadata| metadata.len())
+        .sum()
+}
+
+fn criterion_benchmark(c: &mut Criterion) {
+    for value_size in value_sizes() {
+        let records = (DATASET_SIZE / value_size).max(1);
+        let mut group = c.benchmark_group(format!("separation/value={}", value_size));
+        group.sample_size(10);
+
+        for threshold in thresholds() {
+            let name = threshold_name(threshold);
+
+            group.throughput(Throughput::Bytes(value_size as u64));
+            let tmp_dir = tempfile::tempdir().unwrap();
+            let db = open_db(tmp_dir.path(), threshold);
+            let mut i = 0;
+            group.bench_function(BenchmarkId::new("put", &name), |b| {
+                b.iter(|| {
+                    db.put(key_of(i), random_value(value_size)).unwrap();
+                    i += 1;
+                })
+            });
+            drop(db);
+
+            let tmp_dir = tempfile::tempdir().unwrap();
+            let db = open_db(tmp_dir.path(), threshold);
+            populate(&db, records, value_size);
+            // 写入相同的数据后占用的空间，包括 WAL 和 MANIFEST
+            println!(
+                "separation/value={}/{}: {} keys, {} bytes on disk",
+                value_size,
+                name,
+                records,
+                disk_usage(tmp_dir.path())
+            );
+
+            group.bench_function(BenchmarkId::new("get", &name), |b| {
+                let mut rng = rand::thread_rng();
+                b.iter(|| {
+                    let key = key_of(rng.gen_range(0..records));
+                    assert!(db.get(&key).unwrap().is_some());
+                })
+            });
+
+            group.throughput(Throughput::Bytes((records * value_size) as u64));
+            group.bench_function(BenchmarkId::new("scan", &name), |b| {
+                b.iter(|| assert_eq!(scan(&db), records))
+            });
+        }
+        group.finish();
+    }
+}
+
+criterion_group!(benches, criterion_benchmark);
+criterion_main!(benches);
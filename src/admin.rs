@@ -0,0 +1,1434 @@
+//! 面向运维工具的离线接口：导出 MANIFEST 和 SST、校验数据文件、修复 MANIFEST、查看历史版本、合并记录和审计日志、升级旧文件的元数据、
+//! 校验和重建 VSST 引用计数、复制数据库
+//!
+//! 这些接口直接读写数据目录，调用时数据库不能被打开
+use std::collections::{BTreeMap, HashMap};
+use std::fs::{self, File, OpenOptions};
+use std::io::{Read, Write};
+use std::os::unix::ffi::OsStrExt;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::SystemTime;
+
+use anyhow::{anyhow, Context, Result};
+use bytes::Bytes;
+
+use crate::block::iterator::BlockIterator;
+use crate::cache::BlockCache;
+use crate::checksum::ChecksumType;
+use crate::daemon::{CompactionJob, DbDaemon, MergeParams};
+use crate::entry::Entry;
+use crate::inspect::Inspection;
+use crate::meta::manifest::{Manifest, ManifestActor, ManifestItem, ManifestState, RecordOrigin};
+use crate::record::RecordBuilder;
+use crate::registry::Registration;
+use crate::sstable::builder::SsTable;
+use crate::sstable::compression::CompressionType;
+use crate::sstable::iterator::SsTableIterator;
+use crate::stats::CompactionSummary;
+use crate::storage::file::{self as file_storage, FileStorage};
+use crate::storage::header::FileType;
+use crate::wal::{Journal, JournalOptions};
+use crate::{Db, DbOptions, OpType, StorageIterator, BLOCK_CACHE_SIZE, SST_LEVEL_LIMIT};
+
+/// SST / VSST 文件的概要
+#[derive(Debug, Clone)]
+pub struct SstSummary {
+    /// 文件尾记录的类型，加入文件头之前写入的旧文件为 `None`
+    pub file_type: Option<FileType>,
+    pub size: u64,
+    pub blocks: usize,
+    pub pairs: usize,
+    pub compression: CompressionType,
+    pub checksum_type: ChecksumType,
+    pub dictionary_size: usize,
+    /// (first key, last key)，空文件为 `None`
+    pub key_range: Option<(Bytes, Bytes)>,
+}
+
+#[derive(Debug, Clone)]
+pub struct SstEntry {
+    pub key: Bytes,
+    pub op_type: OpType,
+    /// value 是否分离到 VSST 中，此时 `value` 为 vsst id
+    pub separated: bool,
+    pub value: Bytes,
+}
+
+#[derive(Debug, Default, Clone)]
+pub struct VerifyReport {
+    pub checked_files: usize,
+    pub errors: Vec<String>,
+    /// WAL 段末尾的零填充的字节数，检查时不截掉，下次打开数据库时才截掉
+    pub wal_padding_bytes: u64,
+}
+
+impl VerifyReport {
+    pub fn is_ok(&self) -> bool {
+        self.errors.is_empty()
+    }
+}
+
+#[derive(Debug, Default, Clone)]
+pub struct RepairReport {
+    /// 因缺失或损坏而从 MANIFEST 中移除的 SST (level, sst id)
+    pub dropped_ssts: Vec<(u32, u64)>,
+    /// 因缺失或损坏而从 MANIFEST 中移除的 VSST
+    pub dropped_vssts: Vec<u64>,
+}
+
+/// [`copy_db`] 和 [`Db::checkpoint`] 复制到目标目录的文件
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct CopyReport {
+    /// (level, sst id)，按层和 id 排序
+    pub ssts: Vec<(u32, u64)>,
+    pub vssts: Vec<u64>,
+    /// 复制的 WAL，按冻结顺序，不包括 [`Db::checkpoint`] 新建的空 WAL
+    pub wals: Vec<u64>,
+    /// 复制的总字节数
+    pub bytes: u64,
+}
+
+#[derive(Debug, Default, Clone)]
+pub struct RebuildReport {
+    /// 重新生成了元数据的 SST
+    pub rebuilt_ssts: Vec<u64>,
+    /// 重新生成了元数据的 VSST
+    pub rebuilt_vssts: Vec<u64>,
+}
+
+/// VSST 的一处引用计数差异，见 [`check_vsst_refs`]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct VSstRefMismatch {
+    pub vsst_id: u64,
+    /// MANIFEST 中记录的引用计数，没有记录时为 0
+    pub recorded: u32,
+    /// SST 中实际指向该 VSST 的 KV 分离项数量
+    pub actual: u32,
+}
+
+#[derive(Debug, Default, Clone)]
+pub struct VSstRefReport {
+    pub checked_ssts: usize,
+    /// 按 vsst id 排序
+    pub mismatches: Vec<VSstRefMismatch>,
+    /// 被 SST 引用、但不在 MANIFEST 中的 VSST，引用计数无法修正，读取时用 [`crate::MissingVSstPolicy`] 处理
+    pub unknown_vssts: Vec<u64>,
+    /// 是否已把修正后的引用计数写入 MANIFEST，见 [`rebuild_vsst_refs`]
+    pub rebuilt: bool,
+}
+
+impl VSstRefReport {
+    pub fn is_consistent(&self) -> bool {
+        self.mismatches.is_empty() && self.unknown_vssts.is_empty()
+    }
+}
+
+fn current_manifest_path(path: &Path) -> Result<PathBuf> {
+    let mut name = String::new();
+    File::open(Db::path_of_current(path))
+        .context("open CURRENT failed")?
+        .read_to_string(&mut name)?;
+    Ok(path.join(name))
+}
+
+fn replay_manifest(path: &Path) -> Result<ManifestState> {
+    let manifest = Arc::new(Manifest::open(current_manifest_path(path)?)?);
+    ManifestState::replay(manifest)
+}
+
+fn open_table(path: &Path) -> Result<Arc<SsTable>> {
+    if !path.is_file() {
+        return Err(anyhow!("{:?} does not exist", path));
+    }
+    Ok(Arc::new(SsTable::open(0, None, FileStorage::open(path)?)?))
+}
+
+/// 检查 SST 的每个块的校验和、key 的顺序以及 KV 数量
+fn verify_table(path: &Path) -> Result<()> {
+    let table = open_table(path)?;
+    let mut pairs = 0;
+    let mut last_key: Option<Vec<u8>> = None;
+    for idx in 0..table.num_of_blocks() {
+        let block = table.read_block(idx)?;
+        if !block.verify_checksum(table.checksum_type()) {
+            return Err(anyhow!("{:?} block {} checksum mismatch", path, idx));
+        }
+        let mut iter = BlockIterator::create_and_seek_to_first(block);
+        while iter.is_valid() {
+            if let Some(ref last_key) = last_key {
+                if last_key.as_slice() > iter.key() {
+                    return Err(anyhow!("{:?} block {} keys out of order", path, idx));
+                }
+            }
+            last_key = Some(iter.key().to_vec());
+            pairs += 1;
+            iter.next();
+        }
+    }
+    if pairs != table.num_of_pairs() {
+        return Err(anyhow!(
+            "{:?} has {} pairs, but footer records {}",
+            path,
+            pairs,
+            table.num_of_pairs()
+        ));
+    }
+    Ok(())
+}
+
+/// 按顺序列出 MANIFEST 中的所有变更
+pub fn dump_manifest(path: impl AsRef<Path>) -> Result<Vec<String>> {
+    let manifest = Manifest::open(current_manifest_path(path.as_ref())?)?;
+    let mut items = vec![];
+    for idx in 0..manifest.num_of_records() {
+        let record = manifest.read_record(idx)?;
+        for i in 0..record.num_of_items() {
+            items.push(format!("{:?}", record.item(i)));
+        }
+    }
+    Ok(items)
+}
+
+/// 读出单个 SST / VSST 文件的概要和全部 entry
+pub fn dump_sst(file: impl AsRef<Path>) -> Result<(SstSummary, Vec<SstEntry>)> {
+    let table = open_table(file.as_ref())?;
+    let summary = SstSummary {
+        file_type: table.file_type(),
+        size: table.size(),
+        blocks: table.num_of_blocks(),
+        pairs: table.num_of_pairs(),
+        compression: table.compression(),
+        checksum_type: table.checksum_type(),
+        dictionary_size: table.dictionary().len(),
+        key_range: if table.num_of_blocks() > 0 {
+            Some(table.key_range())
+        } else {
+            None
+        },
+    };
+    let mut entries = Vec::with_capacity(table.num_of_pairs());
+    for idx in 0..table.num_of_blocks() {
+        let mut iter = BlockIterator::create_and_seek_to_first(table.read_block(idx)?);
+        while iter.is_valid() {
+            let entry = iter.entry();
+            entries.push(SstEntry {
+                op_type: entry.op_type(),
+                separated: entry.value_separate(),
+                key: entry.key,
+                value: entry.value,
+            });
+            iter.next();
+        }
+    }
+    Ok((summary, entries))
+}
+
+/// 校验 MANIFEST 引用的所有 SST、VSST 和 WAL
+pub fn verify(path: impl AsRef<Path>) -> Result<VerifyReport> {
+    verify_with_options(path, &DbOptions::default())
+}
+
+/// 同 [`verify`]，开启了 WAL 保护的数据库需要传入打开时使用的选项
+pub fn verify_with_options(path: impl AsRef<Path>, options: &DbOptions) -> Result<VerifyReport> {
+    let path = path.as_ref();
+    let state = replay_manifest(path)?;
+    let mut report = VerifyReport::default();
+
+    let mut sst_ids: Vec<_> = state.sst_map.values().flatten().cloned().collect();
+    sst_ids.sort();
+    for sst_id in sst_ids {
+        report.checked_files += 1;
+        if let Err(e) = verify_table(&Db::path_of_sst(path, sst_id)) {
+            report.errors.push(format!("sst {}: {}", sst_id, e));
+        }
+    }
+    let mut vsst_ids: Vec<_> = state.vsst_set.iter().cloned().collect();
+    vsst_ids.sort();
+    for vsst_id in vsst_ids {
+        report.checked_files += 1;
+        if let Err(e) = verify_table(&Db::path_of_vsst(path, vsst_id)) {
+            report.errors.push(format!("vsst {}: {}", vsst_id, e));
+        }
+    }
+
+    let mut log_ids = state.frozen_log_ids.clone();
+    log_ids.push(state.now_log_id);
+    for log_id in log_ids {
+        let num_segments = state.wal_segments.get(&log_id).cloned().unwrap_or(1);
+        let segments: Vec<_> = (0..num_segments)
+            .map(|seg| Db::path_of_wal_segment(path, log_id, seg))
+            .collect();
+        report.checked_files += segments.len();
+        if let Some(missing) = segments.iter().find(|p| !p.is_file()) {
+            report
+                .errors
+                .push(format!("wal {}: {:?} does not exist", log_id, missing));
+            continue;
+        }
+        let journal_options = JournalOptions {
+            read_only: true,
+            ..options.journal_options()
+        };
+        match Journal::open_segments(log_id, segments, journal_options) {
+            Ok(wal) => report.wal_padding_bytes += wal.padding_bytes(),
+            Err(e) => report.errors.push(format!("wal {}: {}", log_id, e)),
+        }
+    }
+    Ok(report)
+}
+
+/// 从 MANIFEST 中移除缺失或损坏的 SST 和 VSST，并用重放后的状态重写 MANIFEST
+///
+/// 被移除文件中的数据会丢失，引用了被移除 VSST 的 key 读取时仍会出错
+pub fn repair(path: impl AsRef<Path>) -> Result<RepairReport> {
+    let path = path.as_ref();
+    let manifest_path = current_manifest_path(path)?;
+    let mut state = ManifestState::replay(Arc::new(Manifest::open(&manifest_path)?))?;
+    let mut report = RepairReport::default();
+
+    for (level, sst_ids) in state.sst_map.iter_mut() {
+        sst_ids.retain(|sst_id| {
+            let ok = verify_table(&Db::path_of_sst(path, *sst_id)).is_ok();
+            if !ok {
+                report.dropped_ssts.push((*level, *sst_id));
+            }
+            ok
+        });
+    }
+    report.dropped_ssts.sort();
+    let mut dropped_vssts: Vec<_> = state
+        .vsst_set
+        .iter()
+        .filter(|vsst_id| verify_table(&Db::path_of_vsst(path, **vsst_id)).is_err())
+        .cloned()
+        .collect();
+    dropped_vssts.sort();
+    for vsst_id in &dropped_vssts {
+        state.vsst_set.remove(vsst_id);
+        state.vsst_rc.remove(vsst_id);
+    }
+    report.dropped_vssts = dropped_vssts;
+
+    rewrite_manifest(&manifest_path, &state)?;
+    Ok(report)
+}
+
+/// 用 `state` 重写 MANIFEST，先写临时文件再替换，避免中途失败破坏原 MANIFEST
+fn rewrite_manifest(manifest_path: &Path, state: &ManifestState) -> Result<()> {
+    let tmp_path = manifest_path.with_extension("MANIFEST.tmp");
+    if tmp_path.exists() {
+        fs::remove_file(&tmp_path)?;
+    }
+    let mut manifest = Manifest::open(&tmp_path)?;
+    let mut r = RecordBuilder::new();
+    for item in state.to_items(1) {
+        r.add(item);
+    }
+    r.add(ManifestItem::Origin(RecordOrigin::now(
+        ManifestActor::Manual,
+    )));
+    manifest.add(&r.build())?;
+    drop(manifest);
+    fs::rename(&tmp_path, manifest_path)?;
+    Ok(())
+}
+
+/// 校验数据库后把它复制到 `dst`，副本可以直接作为数据库打开，用于在环境之间迁移数据
+///
+/// 先用 [`verify`] 检查 MANIFEST 引用的所有文件，有错误时不复制。只复制当前版本引用的 SST、VSST 和 WAL，
+/// 文件编号和层级不变，等待删除的文件不复制；副本的 MANIFEST 只有一条记录，与原数据库的 MANIFEST 历史无关。
+/// 所有文件 fsync 之后才写入 CURRENT，中途失败时 `dst` 不是可打开的数据库；`dst` 中已有数据库时返回错误。
+/// 数据库打开时用 [`Db::checkpoint`]
+pub fn copy_db(src: impl AsRef<Path>, dst: impl AsRef<Path>) -> Result<CopyReport> {
+    copy_db_with_options(src, dst, &DbOptions::default())
+}
+
+/// 同 [`copy_db`]，开启了 WAL 保护的数据库需要传入打开时使用的选项
+pub fn copy_db_with_options(
+    src: impl AsRef<Path>,
+    dst: impl AsRef<Path>,
+    options: &DbOptions,
+) -> Result<CopyReport> {
+    let (src, dst) = (src.as_ref(), dst.as_ref());
+    let _registration = Registration::acquire(src)?;
+    let report = verify_with_options(src, options)?;
+    if !report.is_ok() {
+        return Err(anyhow!(
+            "{:?} failed verification: {}",
+            src,
+            report.errors.join("; ")
+        ));
+    }
+    let mut state = replay_manifest(src)?;
+    state.pending_deletes.clear();
+    copy_state(src, dst, &state, true)
+}
+
+/// 把 `state` 引用的 SST、VSST 和冻结的 WAL 从 `src` 复制到 `dst` 并逐个 fsync，再以 `state` 写入新的 MANIFEST 和 CURRENT
+///
+/// `copy_wal` 为 `false` 时不复制当前 WAL，在 `dst` 中新建一个空的
+pub(crate) fn copy_state(
+    src: &Path,
+    dst: &Path,
+    state: &ManifestState,
+    copy_wal: bool,
+) -> Result<CopyReport> {
+    if Db::path_of_current(dst).exists() {
+        return Err(anyhow!("{:?} already contains a database", dst));
+    }
+    fs::create_dir_all(dst)?;
+    let mut report = CopyReport::default();
+    let mut copy = |from: PathBuf, to: PathBuf| -> Result<()> {
+        report.bytes += fs::copy(&from, &to).with_context(|| format!("copy {:?} failed", from))?;
+        File::open(&to)?.sync_all()?;
+        Ok(())
+    };
+
+    let mut ssts: Vec<_> = state
+        .sst_map
+        .iter()
+        .flat_map(|(level, sst_ids)| sst_ids.iter().map(|sst_id| (*level, *sst_id)))
+        .collect();
+    ssts.sort();
+    for (_, sst_id) in &ssts {
+        copy(Db::path_of_sst(src, *sst_id), Db::path_of_sst(dst, *sst_id))?;
+    }
+    let mut vssts: Vec<_> = state.vsst_set.iter().cloned().collect();
+    vssts.sort();
+    for vsst_id in &vssts {
+        copy(
+            Db::path_of_vsst(src, *vsst_id),
+            Db::path_of_vsst(dst, *vsst_id),
+        )?;
+    }
+    let mut wals = state.frozen_log_ids.clone();
+    if copy_wal {
+        wals.push(state.now_log_id);
+    }
+    for log_id in &wals {
+        let num_segments = state.wal_segments.get(log_id).cloned().unwrap_or(1);
+        for segment_id in 0..num_segments {
+            copy(
+                Db::path_of_wal_segment(src, *log_id, segment_id),
+                Db::path_of_wal_segment(dst, *log_id, segment_id),
+            )?;
+        }
+    }
+    if !copy_wal {
+        Journal::open(state.now_log_id, Db::path_of_wal(dst, state.now_log_id))?.sync_all()?;
+    }
+    report.ssts = ssts;
+    report.vssts = vssts;
+    report.wals = wals;
+
+    let manifest_path = Db::path_of_manifest(dst, 1);
+    let mut manifest = Manifest::open(&manifest_path)?;
+    manifest.set_strict_sync(true);
+    let mut r = RecordBuilder::new();
+    for item in state.to_items(1) {
+        r.add(item);
+    }
+    r.add(ManifestItem::Origin(RecordOrigin::now(
+        ManifestActor::Manual,
+    )));
+    manifest.add(&r.build())?;
+    drop(manifest);
+
+    let mut current = OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .open(Db::path_of_current(dst))?;
+    current.write_all(manifest_path.file_name().unwrap().as_bytes())?;
+    current.sync_all()?;
+    file_storage::sync_dir(dst)?;
+    Ok(report)
+}
+
+/// 容忍数据文件缺失的离线打开，见 [`open_for_repair`]
+///
+/// 持有期间数据目录在进程内登记为已打开，drop 时不修改任何文件
+pub struct RepairSession {
+    manifest_path: PathBuf,
+    /// 移除了不可用文件后的状态
+    state: ManifestState,
+    view: VersionView,
+    report: PartialOpenReport,
+    _registration: Registration,
+}
+
+/// MANIFEST 引用的文件不可用的原因
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum FileProblem {
+    Missing,
+    /// 文件存在但无法打开或校验失败
+    Corrupted(String),
+}
+
+/// MANIFEST 引用的不可用的 SST 或 VSST
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DamagedFile {
+    pub file_type: FileType,
+    pub id: u64,
+    /// SST 所在的层，VSST 为 `None`
+    pub level: Option<u32>,
+    pub problem: FileProblem,
+}
+
+#[derive(Debug, Default, Clone)]
+pub struct PartialOpenReport {
+    pub loaded_ssts: usize,
+    pub loaded_vssts: usize,
+    /// 先按层列出 SST，再列出 VSST，同类按 id 排序
+    pub damaged_files: Vec<DamagedFile>,
+    /// 已加载的 SST 中 value 分离到不可用 VSST 的项数，提交后读取这些 key 仍会出错
+    pub dangling_values: usize,
+}
+
+impl PartialOpenReport {
+    pub fn is_clean(&self) -> bool {
+        self.damaged_files.is_empty()
+    }
+}
+
+/// 检查 `file` 是否可用，可用时打开它
+fn load_table(file: &Path) -> std::result::Result<Arc<SsTable>, FileProblem> {
+    if !file.is_file() {
+        return Err(FileProblem::Missing);
+    }
+    verify_table(file)
+        .and_then(|_| open_table(file))
+        .map_err(|e| FileProblem::Corrupted(format!("{:#}", e)))
+}
+
+/// 打开 MANIFEST 的最新版本，跳过缺失或损坏的 SST 和 VSST，介于正常打开和 [`repair`] 之间
+///
+/// 返回的 [`RepairSession`] 可以读取剩余文件中的数据并查看报告，确认后调用 [`RepairSession::commit`]
+/// 写入移除了不可用文件的 MANIFEST，不提交则不修改数据目录。只检查 SST 和 VSST，
+/// 与其他离线接口一样只能看到已刷写到 SST 的数据，WAL 用 [`verify`] 检查
+pub fn open_for_repair(path: impl AsRef<Path>) -> Result<RepairSession> {
+    let path = path.as_ref();
+    let registration = Registration::acquire(path)?;
+    let manifest_path = current_manifest_path(path)?;
+    let manifest = Arc::new(Manifest::open(&manifest_path)?);
+    let record_idx = manifest.num_of_records().saturating_sub(1);
+    let mut state = ManifestState::replay(manifest)?;
+    let mut report = PartialOpenReport::default();
+    let mut missing_files = vec![];
+
+    let mut levels = vec![];
+    for (level, mut sst_ids) in level_ids(&state).into_iter().enumerate() {
+        sst_ids.sort();
+        let mut tables = vec![];
+        for sst_id in sst_ids {
+            let file = Db::path_of_sst(path, sst_id);
+            match load_table(&file) {
+                Ok(table) => tables.push(table),
+                Err(problem) => {
+                    report.damaged_files.push(DamagedFile {
+                        file_type: FileType::Sst,
+                        id: sst_id,
+                        level: Some(level as u32),
+                        problem,
+                    });
+                    missing_files.push(file);
+                }
+            }
+        }
+        // L0 按新到旧排列
+        if level == 0 {
+            tables.reverse();
+        }
+        levels.push(tables);
+    }
+    let mut vsst_ids: Vec<_> = state.vsst_set.iter().cloned().collect();
+    vsst_ids.sort();
+    let mut vssts = HashMap::new();
+    for vsst_id in vsst_ids {
+        let file = Db::path_of_vsst(path, vsst_id);
+        match load_table(&file) {
+            Ok(table) => {
+                vssts.insert(vsst_id, table);
+            }
+            Err(problem) => {
+                report.damaged_files.push(DamagedFile {
+                    file_type: FileType::VSst,
+                    id: vsst_id,
+                    level: None,
+                    problem,
+                });
+                missing_files.push(file);
+            }
+        }
+    }
+
+    for _sst in levels.iter().flatten() {
+        let mut iter = SsTableIterator::create_and_seek_to_first(_sst.clone())?;
+        while iter.is_valid() {
+            if Entry::is_separate(iter.meta())
+                && !vssts.contains_key(&Entry::separated_vsst_id(iter.value()))
+            {
+                report.dangling_values += 1;
+            }
+            iter.next()?;
+        }
+    }
+    report.loaded_ssts = levels.iter().map(Vec::len).sum();
+    report.loaded_vssts = vssts.len();
+
+    for damaged in &report.damaged_files {
+        match damaged.level {
+            Some(level) => {
+                if let Some(sst_ids) = state.sst_map.get_mut(&level) {
+                    sst_ids.retain(|sst_id| *sst_id != damaged.id);
+                }
+            }
+            None => {
+                state.vsst_set.remove(&damaged.id);
+                state.vsst_rc.remove(&damaged.id);
+            }
+        }
+    }
+
+    Ok(RepairSession {
+        manifest_path,
+        state,
+        view: VersionView {
+            record_idx,
+            levels,
+            vssts,
+            missing_files,
+        },
+        report,
+        _registration: registration,
+    })
+}
+
+impl RepairSession {
+    pub fn report(&self) -> &PartialOpenReport {
+        &self.report
+    }
+
+    /// 剩余文件中的数据，不可用的文件记录在 [`VersionView::missing_files`] 中
+    pub fn view(&self) -> &VersionView {
+        &self.view
+    }
+
+    /// 写入移除了不可用文件的 MANIFEST，之后数据库可以正常打开，被移除文件中的数据丢失。
+    /// 报告没有问题时不重写 MANIFEST
+    pub fn commit(self) -> Result<PartialOpenReport> {
+        if !self.report.is_clean() {
+            rewrite_manifest(&self.manifest_path, &self.state)?;
+        }
+        Ok(self.report)
+    }
+}
+
+/// 为 MANIFEST 引用的旧格式 SST 和 VSST 补上 bloom filter、删除标记数量和文件头
+///
+/// 缺少这些元数据的文件点查时无法跳过，统计中也没有删除标记数量。只重写文件尾，data block 原样复制，
+/// 先写临时文件再替换，中途失败不会破坏原文件。已是当前格式的文件不会被改动，可以重复执行
+pub fn rebuild_legacy_metadata(path: impl AsRef<Path>) -> Result<RebuildReport> {
+    let path = path.as_ref();
+    let state = replay_manifest(path)?;
+    let mut report = RebuildReport::default();
+
+    let mut sst_ids: Vec<_> = state.sst_map.values().flatten().cloned().collect();
+    sst_ids.sort();
+    for sst_id in sst_ids {
+        if rebuild_table(&Db::path_of_sst(path, sst_id), FileType::Sst)? {
+            report.rebuilt_ssts.push(sst_id);
+        }
+    }
+    let mut vsst_ids: Vec<_> = state.vsst_set.iter().cloned().collect();
+    vsst_ids.sort();
+    for vsst_id in vsst_ids {
+        if rebuild_table(&Db::path_of_vsst(path, vsst_id), FileType::VSst)? {
+            report.rebuilt_vssts.push(vsst_id);
+        }
+    }
+    Ok(report)
+}
+
+/// 文件需要时重新生成元数据，返回是否重写了文件
+fn rebuild_table(path: &Path, file_type: FileType) -> Result<bool> {
+    let table = open_table(path)?;
+    if !table.needs_metadata_rebuild()? {
+        return Ok(false);
+    }
+    let mut tmp_path = path.as_os_str().to_owned();
+    tmp_path.push(".tmp");
+    let tmp_path = PathBuf::from(tmp_path);
+    table
+        .rebuild_metadata(file_type, &tmp_path)
+        .with_context(|| format!("rebuild metadata of {:?} failed", path))?;
+    drop(table);
+    fs::rename(&tmp_path, path)?;
+    Ok(true)
+}
+
+/// 扫描 MANIFEST 引用的所有 SST，统计每个 VSST 实际被引用的次数，与 MANIFEST 中的引用计数比较
+///
+/// 引用计数在刷写和合并时按增量更新，崩溃或旧版本的问题可能让它与实际不符：偏大时 VSST 永远不会被回收，
+/// 偏小时合并可能删除仍被引用的 VSST。只读取文件，不修改数据目录
+pub fn check_vsst_refs(path: impl AsRef<Path>) -> Result<VSstRefReport> {
+    let path = path.as_ref();
+    let _registration = Registration::acquire(path)?;
+    let state = replay_manifest(path)?;
+    vsst_ref_report(path, &state)
+}
+
+/// 同 [`check_vsst_refs`]，有差异时在 MANIFEST 末尾追加一条记录，把引用计数改为实际值
+///
+/// 不再被引用的 VSST 与合并回收它们时一样从 MANIFEST 中移除，下次打开时删除文件。
+/// 被引用但不在 MANIFEST 中的 VSST 不做处理。没有差异时不写入，可以重复执行
+pub fn rebuild_vsst_refs(path: impl AsRef<Path>) -> Result<VSstRefReport> {
+    let path = path.as_ref();
+    let _registration = Registration::acquire(path)?;
+    let manifest_path = current_manifest_path(path)?;
+    let manifest = Arc::new(Manifest::open(&manifest_path)?);
+    let state = ManifestState::replay(manifest.clone())?;
+    let mut report = vsst_ref_report(path, &state)?;
+    if report.mismatches.is_empty() {
+        return Ok(report);
+    }
+
+    let mut manifest =
+        Arc::try_unwrap(manifest).map_err(|_| anyhow!("manifest is still referenced"))?;
+    let mut r = RecordBuilder::new();
+    r.add(ManifestItem::Origin(RecordOrigin::now(
+        ManifestActor::Manual,
+    )));
+    for mismatch in &report.mismatches {
+        let vsst_id = mismatch.vsst_id;
+        r.add(ManifestItem::VSstRefCnt(vsst_id, mismatch.actual));
+        if mismatch.actual == 0 && state.vsst_set.contains(&vsst_id) {
+            r.add(ManifestItem::DelVSst(vsst_id));
+            r.add(ManifestItem::PendingDelete(FileType::VSst, vsst_id));
+        }
+    }
+    manifest.add(&r.build())?;
+    manifest.sync_all()?;
+    report.rebuilt = true;
+    Ok(report)
+}
+
+fn vsst_ref_report(path: &Path, state: &ManifestState) -> Result<VSstRefReport> {
+    let mut levels = vec![];
+    for sst_ids in state.sst_map.values() {
+        let mut tables = vec![];
+        for sst_id in sst_ids {
+            tables.push(open_table(&Db::path_of_sst(path, *sst_id))?);
+        }
+        levels.push(tables);
+    }
+    let refs = Db::count_vsst_refs(&levels)?;
+
+    let mut report = VSstRefReport {
+        checked_ssts: levels.iter().map(|tables| tables.len()).sum(),
+        ..Default::default()
+    };
+    // MANIFEST 中不存在的 VSST 遗留的引用计数同样修正为 0
+    let mut vsst_ids: Vec<_> = state
+        .vsst_set
+        .iter()
+        .chain(state.vsst_rc.keys())
+        .cloned()
+        .collect();
+    vsst_ids.sort();
+    vsst_ids.dedup();
+    for vsst_id in vsst_ids {
+        let recorded = state.vsst_rc.get(&vsst_id).cloned().unwrap_or(0);
+        let actual = if state.vsst_set.contains(&vsst_id) {
+            refs.get(&vsst_id).cloned().unwrap_or(0)
+        } else {
+            0
+        };
+        if recorded != actual {
+            report.mismatches.push(VSstRefMismatch {
+                vsst_id,
+                recorded,
+                actual,
+            });
+        }
+    }
+    report.unknown_vssts = refs
+        .keys()
+        .filter(|vsst_id| !state.vsst_set.contains(vsst_id))
+        .cloned()
+        .collect();
+    report.unknown_vssts.sort();
+    Ok(report)
+}
+
+/// MANIFEST 中一条记录应用后的版本
+#[derive(Debug, Clone)]
+pub struct VersionSummary {
+    /// 版本对应的 MANIFEST 记录下标，版本由前 `record_idx + 1` 条记录重放得到
+    pub record_idx: usize,
+    /// 该记录中的变更
+    pub changes: Vec<String>,
+    /// 每层的 sst id，层号从 0 开始
+    pub levels: Vec<Vec<u64>>,
+    pub vssts: Vec<u64>,
+}
+
+/// 按顺序遍历 MANIFEST 定义的所有历史版本，见 [`versions`]
+pub struct Versions {
+    manifest: Manifest,
+    state: ManifestState,
+    next_idx: usize,
+}
+
+impl Iterator for Versions {
+    type Item = Result<VersionSummary>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.next_idx >= self.manifest.num_of_records() {
+            return None;
+        }
+        let record_idx = self.next_idx;
+        self.next_idx += 1;
+        let record = match self.manifest.read_record(record_idx) {
+            Ok(record) => record,
+            Err(e) => return Some(Err(e)),
+        };
+        self.state.apply_record(&record);
+        let mut vssts: Vec<_> = self.state.vsst_set.iter().cloned().collect();
+        vssts.sort();
+        Some(Ok(VersionSummary {
+            record_idx,
+            changes: (0..record.num_of_items())
+                .map(|i| format!("{:?}", record.item(i)))
+                .collect(),
+            levels: level_ids(&self.state),
+            vssts,
+        }))
+    }
+}
+
+fn level_ids(state: &ManifestState) -> Vec<Vec<u64>> {
+    (0..SST_LEVEL_LIMIT)
+        .map(|level| state.sst_map.get(&level).cloned().unwrap_or_default())
+        .collect()
+}
+
+/// 按顺序列出 MANIFEST 中每条记录应用后的版本，用于定位某次 compaction 前后 SST 的变化
+pub fn versions(path: impl AsRef<Path>) -> Result<Versions> {
+    let manifest = Manifest::open(current_manifest_path(path.as_ref())?)?;
+    Ok(Versions {
+        manifest,
+        state: ManifestState {
+            seq_num: 1,
+            ..Default::default()
+        },
+        next_idx: 0,
+    })
+}
+
+/// MANIFEST 中记录的一次合并，见 [`compaction_history`]
+#[derive(Debug, Clone)]
+pub struct CompactionRecord {
+    /// 合并所在的 MANIFEST 记录下标，可用 [`open_version`] 打开合并前后的版本
+    pub record_idx: usize,
+    pub summary: CompactionSummary,
+    /// 被替换的 SST (level, sst id)
+    pub inputs: Vec<(u32, u64)>,
+    /// 输出的 SST (level, sst id)
+    pub outputs: Vec<(u32, u64)>,
+    /// 输出的 VSST id
+    pub output_vssts: Vec<u64>,
+}
+
+/// 按顺序列出 MANIFEST 中记录的所有合并，用于排查问题和统计写放大的历史
+///
+/// 只包含写入了概要的合并，不包括旧版本执行的合并和重写 MANIFEST 之前的合并
+pub fn compaction_history(path: impl AsRef<Path>) -> Result<Vec<CompactionRecord>> {
+    let manifest = Manifest::open(current_manifest_path(path.as_ref())?)?;
+    let mut history = vec![];
+    for record_idx in 0..manifest.num_of_records() {
+        let record = manifest.read_record(record_idx)?;
+        let items: Vec<_> = (0..record.num_of_items())
+            .map(|i| *record.item(i))
+            .collect();
+        let Some(summary) = items.iter().find_map(|item| match item {
+            ManifestItem::CompactionSummary(summary) => Some(*summary),
+            _ => None,
+        }) else {
+            continue;
+        };
+        let mut compaction = CompactionRecord {
+            record_idx,
+            summary,
+            inputs: vec![],
+            outputs: vec![],
+            output_vssts: vec![],
+        };
+        for item in items {
+            match item {
+                ManifestItem::DelSst(level, sst_id) => compaction.inputs.push((level, sst_id)),
+                ManifestItem::NewSst(level, sst_id) => compaction.outputs.push((level, sst_id)),
+                ManifestItem::NewVSst(vsst_id) => compaction.output_vssts.push(vsst_id),
+                _ => {}
+            }
+        }
+        history.push(compaction);
+    }
+    Ok(history)
+}
+
+/// MANIFEST 中一条记录引起的文件变化，见 [`audit_log`]
+#[derive(Debug, Clone)]
+pub struct AuditRecord {
+    pub record_idx: usize,
+    /// 写入记录的操作，旧版本写入的记录为 `None`
+    pub actor: Option<ManifestActor>,
+    /// 写入记录的时间，旧版本写入的记录为 `None`
+    pub time: Option<SystemTime>,
+    /// 加入元数据的 SST、VSST 和 WAL
+    pub added: Vec<(FileType, u64)>,
+    /// 从元数据中移除的 SST、VSST 和 WAL，SST 和 VSST 文件之后由后台删除
+    pub removed: Vec<(FileType, u64)>,
+    /// 已删除的文件
+    pub deleted: Vec<(FileType, u64)>,
+}
+
+/// 按顺序列出 MANIFEST 中每条记录由什么操作在什么时间写入、加入和移除了哪些文件，用于追查文件出现和消失的原因
+///
+/// 打开数据库时重写的记录列出当时的全部文件；只包含当前 MANIFEST 中的记录，不包括之前的 MANIFEST
+pub fn audit_log(path: impl AsRef<Path>) -> Result<Vec<AuditRecord>> {
+    let manifest = Manifest::open(current_manifest_path(path.as_ref())?)?;
+    let mut log = vec![];
+    for record_idx in 0..manifest.num_of_records() {
+        let record = manifest.read_record(record_idx)?;
+        let mut audit = AuditRecord {
+            record_idx,
+            actor: None,
+            time: None,
+            added: vec![],
+            removed: vec![],
+            deleted: vec![],
+        };
+        for i in 0..record.num_of_items() {
+            match *record.item(i) {
+                ManifestItem::Origin(origin) => {
+                    audit.actor = Some(origin.actor);
+                    audit.time = Some(origin.time);
+                }
+                ManifestItem::NewSst(_, sst_id) => audit.added.push((FileType::Sst, sst_id)),
+                ManifestItem::NewVSst(vsst_id) => audit.added.push((FileType::VSst, vsst_id)),
+                ManifestItem::FreezeAndCreateWal(_, log_id) => {
+                    audit.added.push((FileType::Log, log_id))
+                }
+                ManifestItem::DelSst(_, sst_id) => audit.removed.push((FileType::Sst, sst_id)),
+                ManifestItem::DelVSst(vsst_id) => audit.removed.push((FileType::VSst, vsst_id)),
+                ManifestItem::DelFrozenWal(log_id) => audit.removed.push((FileType::Log, log_id)),
+                ManifestItem::FileDeleted(file_type, id) => audit.deleted.push((file_type, id)),
+                _ => {}
+            }
+        }
+        log.push(audit);
+    }
+    Ok(log)
+}
+
+/// 历史版本的只读视图，见 [`open_version`]
+///
+/// 只包含当时已经刷写到 SST 的数据，WAL 和 memtable 中的数据不可见
+#[derive(Debug)]
+pub struct VersionView {
+    pub record_idx: usize,
+    /// 每层的 SST，L0 按新到旧排列
+    levels: Vec<Vec<Arc<SsTable>>>,
+    vssts: HashMap<u64, Arc<SsTable>>,
+    /// 版本引用了但已被删除的文件，其中的数据在视图中不可见
+    pub missing_files: Vec<PathBuf>,
+}
+
+/// 打开第 `record_idx` 条 MANIFEST 记录应用后的版本，版本引用的文件若已被 compaction 删除则跳过，
+/// 记录在 [`VersionView::missing_files`] 中
+///
+/// 用于排查怀疑由 compaction 导致的数据丢失：对比 compaction 前后两个版本中同一个 key 的值
+pub fn open_version(path: impl AsRef<Path>, record_idx: usize) -> Result<VersionView> {
+    let path = path.as_ref();
+    let manifest = Manifest::open(current_manifest_path(path)?)?;
+    if record_idx >= manifest.num_of_records() {
+        return Err(anyhow!(
+            "version {} does not exist, manifest has {} records",
+            record_idx,
+            manifest.num_of_records()
+        ));
+    }
+    let state = ManifestState::replay_until(&manifest, record_idx + 1)?;
+
+    let mut missing_files = vec![];
+    let mut open = |file: PathBuf| {
+        if file.is_file() {
+            Some(open_table(&file))
+        } else {
+            missing_files.push(file);
+            None
+        }
+    };
+    let mut levels = vec![];
+    for (level, mut sst_ids) in level_ids(&state).into_iter().enumerate() {
+        if level == 0 {
+            sst_ids.sort_by(|a, b| b.cmp(a));
+        }
+        let mut tables = vec![];
+        for sst_id in sst_ids {
+            if let Some(table) = open(Db::path_of_sst(path, sst_id)) {
+                tables.push(table?);
+            }
+        }
+        levels.push(tables);
+    }
+    let mut vssts = HashMap::new();
+    for vsst_id in state.vsst_set {
+        if let Some(table) = open(Db::path_of_vsst(path, vsst_id)) {
+            vssts.insert(vsst_id, table?);
+        }
+    }
+    missing_files.sort();
+
+    Ok(VersionView {
+        record_idx,
+        levels,
+        vssts,
+        missing_files,
+    })
+}
+
+impl VersionView {
+    /// 读取 key 在该版本中的值，已删除或不存在时返回 `None`
+    pub fn get(&self, key: &Bytes) -> Result<Option<Bytes>> {
+        for table in self.levels.iter().flatten() {
+            if table.num_of_blocks() == 0 || !table.maybe_contains_key(key) {
+                continue;
+            }
+            let iter = SsTableIterator::create_and_seek_to_key(table.clone(), key)?;
+            if iter.is_valid() && iter.key() == key {
+                return self.visible_value(key, iter.meta(), iter.value());
+            }
+        }
+        Ok(None)
+    }
+
+    /// 该版本中所有可见的 KV，按 key 排序
+    pub fn entries(&self) -> Result<Vec<(Bytes, Bytes)>> {
+        // 按新到旧遍历，每个 key 只保留第一次遇到的版本
+        let mut latest: BTreeMap<Bytes, (Bytes, Bytes)> = BTreeMap::new();
+        for table in self.levels.iter().flatten() {
+            if table.num_of_blocks() == 0 {
+                continue;
+            }
+            let mut iter = SsTableIterator::create_and_seek_to_first(table.clone())?;
+            while iter.is_valid() {
+                latest
+                    .entry(Bytes::copy_from_slice(iter.key()))
+                    .or_insert_with(|| {
+                        (
+                            Bytes::copy_from_slice(iter.meta()),
+                            Bytes::copy_from_slice(iter.value()),
+                        )
+                    });
+                iter.next()?;
+            }
+        }
+        let mut entries = vec![];
+        for (key, (meta, value)) in latest {
+            if let Some(value) = self.visible_value(&key, &meta, &value)? {
+                entries.push((key, value));
+            }
+        }
+        Ok(entries)
+    }
+
+    fn visible_value(&self, key: &[u8], meta: &[u8], value: &[u8]) -> Result<Option<Bytes>> {
+        if Entry::op_type_of(meta) == OpType::Delete {
+            return Ok(None);
+        }
+        if !Entry::is_separate(meta) {
+            return Ok((!value.is_empty()).then(|| Bytes::copy_from_slice(value)));
+        }
+        let vsst_id = Entry::separated_vsst_id(value);
+        let vsst = self
+            .vssts
+            .get(&vsst_id)
+            .ok_or_else(|| anyhow!("vsst {} of key {:?} is missing", vsst_id, key))?;
+        let iter = SsTableIterator::create_and_seek_to_key(vsst.clone(), key)?;
+        Ok(Some(Bytes::copy_from_slice(iter.value())))
+    }
+}
+
+/// 在任务目录中执行 [`Db::export_compaction`] 导出的合并，输出的 SST 写入任务目录，不需要打开数据库
+///
+/// 合并时不迁移 VSST 中的 value，分离项保持原来的引用；任务已执行过时直接返回
+pub fn run_compaction_job(dir: impl AsRef<Path>) -> Result<CompactionJob> {
+    let dir = dir.as_ref();
+    let mut job = CompactionJob::load(dir)?;
+    if job.is_finished() {
+        return Ok(job);
+    }
+    let cache = Arc::new(BlockCache::new(BLOCK_CACHE_SIZE));
+    let ssts = job
+        .inputs
+        .iter()
+        .map(|input| {
+            let sst = SsTable::open(
+                input.id,
+                Some(cache.clone()),
+                FileStorage::open(Db::path_of_sst(dir, input.id))?,
+            )?;
+            sst.check_file_type(FileType::Sst)?;
+            Ok(Arc::new(sst))
+        })
+        .collect::<Result<Vec<_>>>()?;
+    // 输出的 id 排在所有输入之后，不会覆盖任务目录中的输入
+    let max_input_id = job.inputs.iter().map(|input| input.id).max().unwrap_or(0);
+    let (new_ssts, _, vsst_rc_delta, _) = DbDaemon::merge(
+        MergeParams {
+            now_sst_id: max_input_id,
+            ..MergeParams::new(dir, cache)
+        },
+        ssts,
+        &mut Inspection::disabled(),
+    )?;
+    job.outputs = Some(new_ssts.iter().map(|sst| sst.id()).collect());
+    job.vsst_rc_delta = vsst_rc_delta
+        .iter()
+        .map(|(id, delta)| (*id, *delta))
+        .collect();
+    job.vsst_rc_delta.sort();
+    job.save(dir)?;
+    Ok(job)
+}
+
+impl SstEntry {
+    /// value 为 vsst id 时返回该 id
+    pub fn vsst_id(&self) -> Option<u64> {
+        if self.separated && self.value.len() >= 4 {
+            Some(Entry::separated_vsst_id(&self.value))
+        } else {
+            None
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use bytes::{Bytes, BytesMut};
+
+    use std::path::Path;
+
+    use crate::admin::{
+        check_vsst_refs, current_manifest_path, dump_manifest, dump_sst, open_table, open_version,
+        rebuild_legacy_metadata, rebuild_vsst_refs, repair, verify, versions, DamagedFile,
+        FileProblem, VSstRefMismatch,
+    };
+    use crate::meta::manifest::{Manifest, ManifestItem};
+    use crate::record::RecordBuilder;
+    use crate::storage::header::{FileType, FILE_HEADER_SIZE};
+    use crate::{Db, DbOptions, OpType, MIN_VSST_SIZE};
+
+    #[test]
+    fn test_verify_and_repair() {
+        let dir = tempfile::tempdir().unwrap();
+        {
+            let db = Db::open_file(dir.path()).unwrap();
+            db.put(Bytes::from("k1"), Bytes::from("v1")).unwrap();
+            db.flush().unwrap();
+            db.put(Bytes::from("k2"), Bytes::from("v2")).unwrap();
+            db.delete(Bytes::from("k1")).unwrap();
+            db.flush().unwrap();
+        }
+        assert!(!dump_manifest(dir.path()).unwrap().is_empty());
+        assert!(verify(dir.path()).unwrap().is_ok());
+
+        let (summary, entries) = dump_sst(Db::path_of_sst(dir.path(), 2)).unwrap();
+        assert_eq!(summary.pairs, 2);
+        assert_eq!(entries[0].key, Bytes::from("k1"));
+        assert_eq!(entries[0].op_type, OpType::Delete);
+        assert_eq!(entries[1].value, Bytes::from("v2"));
+
+        fs::remove_file(Db::path_of_sst(dir.path(), 1)).unwrap();
+        let report = verify(dir.path()).unwrap();
+        assert_eq!(report.errors.len(), 1);
+
+        let report = repair(dir.path()).unwrap();
+        assert_eq!(report.dropped_ssts, vec![(0, 1)]);
+        assert!(verify(dir.path()).unwrap().is_ok());
+
+        let db = Db::open_file(dir.path()).unwrap();
+        assert_eq!(db.get(&Bytes::from("k1")).unwrap(), None);
+        assert_eq!(db.get(&Bytes::from("k2")).unwrap(), Some(Bytes::from("v2")));
+    }
+
+    #[test]
+    fn test_verify_keeps_wal_padding() {
+        let dir = tempfile::tempdir().unwrap();
+        {
+            let db = Db::open_file(dir.path()).unwrap();
+            db.put(Bytes::from("k1"), Bytes::from("v1")).unwrap();
+        }
+        let wal_path = fs::read_dir(dir.path())
+            .unwrap()
+            .map(|entry| entry.unwrap().path())
+            .filter(|path| path.extension().is_some_and(|ext| ext == "LOG"))
+            .max()
+            .unwrap();
+        let len = fs::metadata(&wal_path).unwrap().len();
+        let mut data = fs::read(&wal_path).unwrap();
+        data.resize(4096, 0);
+        fs::write(&wal_path, &data).unwrap();
+
+        // 检查只报告填充，不修改 WAL
+        let report = verify(dir.path()).unwrap();
+        assert!(report.is_ok());
+        assert_eq!(report.wal_padding_bytes, 4096 - len);
+        assert_eq!(fs::metadata(&wal_path).unwrap().len(), 4096);
+
+        let db = Db::open_file(dir.path()).unwrap();
+        assert_eq!(db.get(&Bytes::from("k1")).unwrap(), Some(Bytes::from("v1")));
+    }
+
+    #[test]
+    fn test_open_for_repair() {
+        let dir = tempfile::tempdir().unwrap();
+        let big_value = BytesMut::zeroed(MIN_VSST_SIZE as usize + 1).freeze();
+        {
+            let db = Db::open_file(dir.path()).unwrap();
+            db.put(Bytes::from("k1"), Bytes::from("v1")).unwrap();
+            db.flush().unwrap();
+            db.put(Bytes::from("k2"), Bytes::from("v2")).unwrap();
+            db.put(Bytes::from("k3"), big_value).unwrap();
+            db.flush().unwrap();
+        }
+        fs::remove_file(Db::path_of_sst(dir.path(), 1)).unwrap();
+        fs::write(Db::path_of_vsst(dir.path(), 2), b"broken").unwrap();
+
+        let session = Db::open_for_repair(dir.path()).unwrap();
+        // 持有期间不能正常打开
+        assert!(Db::open_file(dir.path()).is_err());
+        let report = session.report();
+        assert_eq!(report.loaded_ssts, 1);
+        assert_eq!(
+            report.damaged_files[0],
+            DamagedFile {
+                file_type: FileType::Sst,
+                id: 1,
+                level: Some(0),
+                problem: FileProblem::Missing,
+            }
+        );
+        assert_eq!(report.damaged_files[1].file_type, FileType::VSst);
+        assert!(matches!(
+            report.damaged_files[1].problem,
+            FileProblem::Corrupted(_)
+        ));
+        assert_eq!(report.dangling_values, 1);
+        assert_eq!(session.view().missing_files.len(), 2);
+        assert_eq!(session.view().get(&Bytes::from("k1")).unwrap(), None);
+        assert_eq!(
+            session.view().get(&Bytes::from("k2")).unwrap(),
+            Some(Bytes::from("v2"))
+        );
+        assert!(session.view().get(&Bytes::from("k3")).is_err());
+
+        // 不提交时不修改数据目录
+        drop(session);
+        assert_eq!(verify(dir.path()).unwrap().errors.len(), 2);
+
+        let report = Db::open_for_repair(dir.path()).unwrap().commit().unwrap();
+        assert_eq!(report.damaged_files.len(), 2);
+        assert!(verify(dir.path()).unwrap().is_ok());
+        let db = Db::open_file(dir.path()).unwrap();
+        assert_eq!(db.get(&Bytes::from("k2")).unwrap(), Some(Bytes::from("v2")));
+        drop(db);
+        assert!(Db::open_for_repair(dir.path()).unwrap().report().is_clean());
+    }
+
+    #[test]
+    fn test_versions() {
+        let dir = tempfile::tempdir().unwrap();
+        let big_value = BytesMut::zeroed(MIN_VSST_SIZE as usize).freeze();
+        {
+            let db = Db::open_file(dir.path()).unwrap();
+            db.put(Bytes::from("k1"), Bytes::from("v1")).unwrap();
+            db.put(Bytes::from("k3"), big_value.clone()).unwrap();
+            db.flush().unwrap();
+            db.put(Bytes::from("k2"), Bytes::from("v2")).unwrap();
+            db.delete(Bytes::from("k1")).unwrap();
+            db.flush().unwrap();
+        }
+
+        let history: Vec<_> = versions(dir.path())
+            .unwrap()
+            .collect::<anyhow::Result<_>>()
+            .unwrap();
+        assert!(history.iter().all(|v| !v.changes.is_empty()));
+        // 第一次刷写后的版本
+        let first_flush = history
+            .iter()
+            .find(|v| v.levels[0] == vec![1])
+            .unwrap()
+            .record_idx;
+        let view = open_version(dir.path(), first_flush).unwrap();
+        assert!(view.missing_files.is_empty());
+        assert_eq!(
+            view.get(&Bytes::from("k1")).unwrap(),
+            Some(Bytes::from("v1"))
+        );
+        assert_eq!(view.get(&Bytes::from("k2")).unwrap(), None);
+        assert_eq!(
+            view.get(&Bytes::from("k3")).unwrap(),
+            Some(big_value.clone())
+        );
+
+        // 两次刷写后 L0 中较新的删除标记覆盖 k1
+        let view = open_version(dir.path(), history.len() - 1).unwrap();
+        assert_eq!(view.get(&Bytes::from("k1")).unwrap(), None);
+        assert_eq!(
+            view.entries().unwrap(),
+            vec![
+                (Bytes::from("k2"), Bytes::from("v2")),
+                (Bytes::from("k3"), big_value.clone())
+            ]
+        );
+        assert!(open_version(dir.path(), history.len()).is_err());
+
+        // compaction 删除了 L0 的 SST，旧版本中这部分数据不可见
+        {
+            let db = Db::open(dir.path()).unwrap();
+            db.compact(0).unwrap();
+            db.daemon.delete_obsolete_files().unwrap();
+        }
+        let last = versions(dir.path()).unwrap().last().unwrap().unwrap();
+        assert!(last.levels[0].is_empty());
+        let view = open_version(dir.path(), first_flush).unwrap();
+        assert_eq!(view.missing_files, vec![Db::path_of_sst(dir.path(), 1)]);
+        assert_eq!(view.get(&Bytes::from("k1")).unwrap(), None);
+        let view = open_version(dir.path(), last.record_idx).unwrap();
+        assert!(view.missing_files.is_empty());
+        assert_eq!(
+            view.get(&Bytes::from("k2")).unwrap(),
+            Some(Bytes::from("v2"))
+        );
+    }
+
+    /// 把当前格式的 SST 改写为加入文件头和删除标记数量之前的格式
+    fn downgrade_to_legacy(path: &Path) {
+        let data = fs::read(path).unwrap();
+        let footer_end = data.len() - FILE_HEADER_SIZE;
+        let footer_begin = footer_end - 28;
+        // 降为格式版本 1：去掉版本 4 的校验和、版本 3 的最大 seq num 和版本 2 的删除标记数量
+        let mut legacy = data[..footer_begin - 16].to_vec();
+        legacy.extend(&data[footer_begin..]);
+        let version_idx = legacy.len() - FILE_HEADER_SIZE + 5;
+        legacy[version_idx] = 1;
+        fs::write(path, legacy).unwrap();
+    }
+
+    #[test]
+    fn test_rebuild_legacy_metadata() {
+        let dir = tempfile::tempdir().unwrap();
+        {
+            let db = Db::open_file(dir.path()).unwrap();
+            db.put(Bytes::from("k1"), Bytes::from("v1")).unwrap();
+            db.put(Bytes::from("k2"), Bytes::from("v2")).unwrap();
+            db.delete(Bytes::from("k3")).unwrap();
+            db.flush().unwrap();
+        }
+        let sst_path = Db::path_of_sst(dir.path(), 1);
+        downgrade_to_legacy(&sst_path);
+        let legacy = open_table(&sst_path).unwrap();
+        assert_eq!(legacy.num_of_deletes(), None);
+        assert!(legacy.needs_metadata_rebuild().unwrap());
+        drop(legacy);
+
+        let report = rebuild_legacy_metadata(dir.path()).unwrap();
+        assert_eq!(report.rebuilt_ssts, vec![1]);
+        assert!(report.rebuilt_vssts.is_empty());
+        let rebuilt = open_table(&sst_path).unwrap();
+        assert_eq!(rebuilt.num_of_deletes(), Some(1));
+        assert_eq!(rebuilt.num_of_pairs(), 3);
+        rebuilt.check_file_type(FileType::Sst).unwrap();
+        assert!(!rebuilt.needs_metadata_rebuild().unwrap());
+        drop(rebuilt);
+        assert!(verify(dir.path()).unwrap().is_ok());
+        // 已是当前格式时不再改动
+        let report = rebuild_legacy_metadata(dir.path()).unwrap();
+        assert!(report.rebuilt_ssts.is_empty());
+
+        // 打开时重新生成
+        downgrade_to_legacy(&sst_path);
+        let options = DbOptions {
+            rebuild_legacy_metadata: true,
+            ..Default::default()
+        };
+        let db = Db::open_file_with_options(dir.path(), options).unwrap();
+        assert_eq!(db.get(&Bytes::from("k1")).unwrap(), Some(Bytes::from("v1")));
+        assert_eq!(db.get(&Bytes::from("k3")).unwrap(), None);
+        let levels = db.level_metadata();
+        assert_eq!(levels[0].files[0].num_of_deletes, Some(1));
+    }
+
+    fn append_manifest(dir: &Path, items: Vec<ManifestItem>) {
+        let mut manifest = Manifest::open(current_manifest_path(dir).unwrap()).unwrap();
+        let mut r = RecordBuilder::new();
+        for item in items {
+            r.add(item);
+        }
+        manifest.add(&r.build()).unwrap();
+    }
+
+    #[test]
+    fn test_vsst_refs() {
+        let dir = tempfile::tempdir().unwrap();
+        let big_value = BytesMut::zeroed(MIN_VSST_SIZE as usize + 1).freeze();
+        {
+            let db = Db::open_file(dir.path()).unwrap();
+            db.put(Bytes::from("k1"), big_value.clone()).unwrap();
+            db.put(Bytes::from("k2"), big_value.clone()).unwrap();
+            db.flush().unwrap();
+        }
+        let report = check_vsst_refs(dir.path()).unwrap();
+        assert!(report.is_consistent());
+        assert_eq!(report.checked_ssts, 1);
+
+        // 引用计数偏大时 VSST 永远不会被回收
+        append_manifest(dir.path(), vec![ManifestItem::VSstRefCnt(1, 7)]);
+        let report = check_vsst_refs(dir.path()).unwrap();
+        assert_eq!(
+            report.mismatches,
+            vec![VSstRefMismatch {
+                vsst_id: 1,
+                recorded: 7,
+                actual: 2,
+            }]
+        );
+        assert!(!report.rebuilt);
+        // 只检查时不修改
+        assert!(!check_vsst_refs(dir.path()).unwrap().is_consistent());
+
+        let report = rebuild_vsst_refs(dir.path()).unwrap();
+        assert!(report.rebuilt);
+        assert!(check_vsst_refs(dir.path()).unwrap().is_consistent());
+        assert!(!rebuild_vsst_refs(dir.path()).unwrap().rebuilt);
+        {
+            let db = Db::open_file(dir.path()).unwrap();
+            assert_eq!(db.get(&Bytes::from("k1")).unwrap(), Some(big_value));
+        }
+
+        // 引用它的 SST 被移除后 VSST 不再被引用，重建时回收
+        append_manifest(dir.path(), vec![ManifestItem::DelSst(0, 1)]);
+        let report = check_vsst_refs(dir.path()).unwrap();
+        assert_eq!(report.mismatches[0].actual, 0);
+        rebuild_vsst_refs(dir.path()).unwrap();
+        assert!(check_vsst_refs(dir.path()).unwrap().is_consistent());
+        Db::open_file(dir.path()).unwrap().close().unwrap();
+        assert!(!Db::path_of_vsst(dir.path(), 1).exists());
+    }
+}
@@ -0,0 +1,191 @@
+use std::collections::{HashSet, VecDeque};
+use std::mem;
+
+use bytes::{Buf, Bytes};
+
+use crate::entry::{Entry, EntryBuilder, EntryError};
+use crate::OpType;
+use crate::OpType::{Delete, Put};
+
+/// 批量写入的幂等 token，可以由 u64 序号或 uuid（`Uuid::as_u128`）转换得到
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Hash)]
+pub struct IdempotencyToken(pub u128);
+
+impl From<u64> for IdempotencyToken {
+    fn from(token: u64) -> Self {
+        IdempotencyToken(token as u128)
+    }
+}
+
+impl From<u128> for IdempotencyToken {
+    fn from(token: u128) -> Self {
+        IdempotencyToken(token)
+    }
+}
+
+impl IdempotencyToken {
+    /// 编码为写入 WAL 的 entry
+    pub(crate) fn to_entry(self) -> Entry {
+        EntryBuilder::new()
+            .op_type(Put)
+            .idempotency_token(true)
+            .key_value(Bytes::new(), Bytes::from(self.0.to_le_bytes().to_vec()))
+            .build()
+    }
+
+    pub(crate) fn from_entry(entry: &Entry) -> Option<Self> {
+        if !entry.is_idempotency_token() || entry.value.len() != mem::size_of::<u128>() {
+            return None;
+        }
+        Some(IdempotencyToken((&entry.value[..]).get_u128_le()))
+    }
+}
+
+/// 原子写入的一组 put / delete，同一个批次的修改在 WAL 中是一条记录
+///
+/// 设置了幂等 token 的批次只会被应用一次：客户端重试或 WAL 重放时，
+/// 若 token 仍在最近应用过的 token 中（见 [`crate::IDEMPOTENCY_TOKEN_LIMIT`]），该批次会被跳过
+#[derive(Debug, Clone, Default)]
+pub struct WriteBatch {
+    entries: Vec<Entry>,
+    token: Option<IdempotencyToken>,
+}
+
+impl WriteBatch {
+    pub fn new() -> Self {
+        WriteBatch::default()
+    }
+
+    pub fn with_idempotency_token(token: impl Into<IdempotencyToken>) -> Self {
+        WriteBatch {
+            entries: vec![],
+            token: Some(token.into()),
+        }
+    }
+
+    pub fn put(&mut self, key: Bytes, value: Bytes) -> Result<&mut Self, EntryError> {
+        self.push(Put, key, value)
+    }
+
+    pub fn delete(&mut self, key: Bytes) -> Result<&mut Self, EntryError> {
+        self.push(Delete, key, Bytes::new())
+    }
+
+    fn push(&mut self, op_type: OpType, key: Bytes, value: Bytes) -> Result<&mut Self, EntryError> {
+        let entry = EntryBuilder::new()
+            .op_type(op_type)
+            .key_value(key, value)
+            .try_build()?;
+        self.entries.push(entry);
+        Ok(self)
+    }
+
+    pub fn idempotency_token(&self) -> Option<IdempotencyToken> {
+        self.token
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    pub(crate) fn into_entries(self) -> Vec<Entry> {
+        self.entries
+    }
+}
+
+/// 最近应用过的幂等 token，超过容量时淘汰最早的 token
+#[derive(Debug, Default)]
+pub struct IdempotencyTokens {
+    tokens: HashSet<IdempotencyToken>,
+    order: VecDeque<IdempotencyToken>,
+    capacity: usize,
+    /// 累计插入的 token 数，包括已淘汰的
+    inserted: u64,
+}
+
+impl IdempotencyTokens {
+    pub(crate) fn new(capacity: usize) -> Self {
+        IdempotencyTokens {
+            tokens: HashSet::new(),
+            order: VecDeque::new(),
+            capacity,
+            inserted: 0,
+        }
+    }
+
+    pub(crate) fn contains(&self, token: &IdempotencyToken) -> bool {
+        self.tokens.contains(token)
+    }
+
+    pub(crate) fn insert(&mut self, token: IdempotencyToken) {
+        if self.capacity == 0 || !self.tokens.insert(token) {
+            return;
+        }
+        self.order.push_back(token);
+        self.inserted += 1;
+        while self.order.len() > self.capacity {
+            let oldest = self.order.pop_front().unwrap();
+            self.tokens.remove(&oldest);
+        }
+    }
+
+    #[cfg(test)]
+    pub(crate) fn len(&self) -> usize {
+        self.order.len()
+    }
+
+    pub(crate) fn inserted(&self) -> u64 {
+        self.inserted
+    }
+
+    /// 按应用顺序编码累计插入 `inserted` 个之后插入的、仍未淘汰的 token，用于在新的 WAL 开头延续记录
+    pub(crate) fn entries_since(&self, inserted: u64) -> Vec<Entry> {
+        let skip = self
+            .order
+            .len()
+            .saturating_sub((self.inserted - inserted) as usize);
+        self.order
+            .iter()
+            .skip(skip)
+            .map(|token| token.to_entry())
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::batch::{IdempotencyToken, IdempotencyTokens};
+    use crate::entry::Entry;
+
+    #[test]
+    fn test_idempotency_tokens() {
+        let mut tokens = IdempotencyTokens::new(2);
+        tokens.insert(1u64.into());
+        tokens.insert(2u64.into());
+        tokens.insert(1u64.into());
+        assert_eq!(tokens.len(), 2);
+        tokens.insert(3u64.into());
+        assert!(!tokens.contains(&1u64.into()));
+        assert!(tokens.contains(&2u64.into()));
+        assert!(tokens.contains(&3u64.into()));
+
+        let decode = |entries: Vec<Entry>| -> Vec<IdempotencyToken> {
+            assert!(entries.iter().all(|e| e.validate().is_ok()));
+            entries
+                .iter()
+                .filter_map(IdempotencyToken::from_entry)
+                .collect()
+        };
+        assert_eq!(tokens.inserted(), 3);
+        assert_eq!(
+            decode(tokens.entries_since(0)),
+            vec![2u64.into(), 3u64.into()]
+        );
+        assert_eq!(decode(tokens.entries_since(2)), vec![3u64.into()]);
+        assert!(tokens.entries_since(3).is_empty());
+    }
+}
@@ -0,0 +1,436 @@
+use std::ops::Bound;
+use std::path::PathBuf;
+
+use anyhow::{anyhow, Result};
+use bytes::Bytes;
+use clap::{Parser, Subcommand};
+use lasagnedb::trace::ReplayOptions;
+use lasagnedb::{admin, Db, StorageIterator, WalReader};
+
+/// lasagnedb 管理工具
+#[derive(Parser, Debug)]
+#[command(name = "lasagnedb-cli", version)]
+struct Cli {
+    /// 数据目录
+    #[arg(short, long)]
+    db: PathBuf,
+
+    #[command(subcommand)]
+    command: Command,
+}
+
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// 读取一个 key
+    Get { key: String },
+    /// 写入一个 key
+    Put { key: String, value: String },
+    /// 删除一个 key
+    Delete { key: String },
+    /// 按 key 顺序扫描
+    Scan {
+        /// 起始 key（包含）
+        #[arg(long)]
+        from: Option<String>,
+        /// 结束 key（不包含）
+        #[arg(long)]
+        to: Option<String>,
+        #[arg(long, default_value_t = 100)]
+        limit: usize,
+    },
+    /// 输出运行状态
+    Stats,
+    /// 将 memtable 刷写到 L0
+    Flush,
+    /// 对指定层执行一次合并
+    Compact {
+        level: u32,
+        /// 合并结果写入的层，默认为下一层
+        #[arg(long)]
+        output_level: Option<u32>,
+    },
+    /// 列出 MANIFEST 中的所有变更
+    Manifest,
+    /// 列出 MANIFEST 每条记录应用后的历史版本
+    Versions,
+    /// 列出 MANIFEST 中记录的合并及其输入输出的数据量
+    Compactions,
+    /// 读取历史版本中的数据，只包含当时已刷写到 SST 的部分
+    Version {
+        /// 版本对应的 MANIFEST 记录下标，见 `versions`
+        record: usize,
+        /// 只读取一个 key，不指定时输出全部 KV
+        #[arg(long)]
+        key: Option<String>,
+    },
+    /// 导出 SST / VSST 文件的内容
+    Sst {
+        file: PathBuf,
+        /// 只输出概要
+        #[arg(long)]
+        summary: bool,
+    },
+    /// 按写入顺序导出 WAL 中的记录，不需要打开数据库，不支持开启了 WAL 保护的数据库
+    Wal {
+        /// WAL 的所有段文件，按段的顺序排列
+        #[arg(required = true)]
+        segments: Vec<PathBuf>,
+        /// 第一条记录的提交序号，指定后输出每条记录的序号
+        #[arg(long)]
+        first_seq: Option<u64>,
+    },
+    /// 校验 MANIFEST 引用的数据文件
+    Verify,
+    /// 从 MANIFEST 中移除缺失或损坏的文件
+    Repair,
+    /// 跳过缺失或损坏的文件打开并输出报告，确认后再写入修正后的 MANIFEST
+    OpenForRepair {
+        /// 写入移除了不可用文件的 MANIFEST，不指定时只输出报告
+        #[arg(long)]
+        commit: bool,
+    },
+    /// 为旧格式的 SST / VSST 重新生成 bloom filter 等元数据
+    RebuildMetadata,
+    /// 按 SST 中的实际引用校验 MANIFEST 中的 VSST 引用计数
+    VsstRefs {
+        /// 把修正后的引用计数写入 MANIFEST，不指定时只输出差异
+        #[arg(long)]
+        rebuild: bool,
+    },
+    /// 校验数据库后复制到另一个目录，副本有新的 MANIFEST
+    Copy {
+        /// 目标目录，不能已有数据库
+        dest: PathBuf,
+    },
+    /// 在数据库上重放操作记录，用于基准测试和复现问题
+    Replay {
+        /// `Db::start_trace` 写入的记录文件
+        trace: PathBuf,
+        /// 按记录中的时间间隔执行操作，不指定时尽快执行
+        #[arg(long)]
+        preserve_timing: bool,
+    },
+}
+
+fn display(data: &[u8]) -> String {
+    String::from_utf8_lossy(data).into_owned()
+}
+
+fn run(cli: Cli) -> Result<()> {
+    match cli.command {
+        Command::Get { key } => {
+            let db = Db::open_file(&cli.db)?;
+            match db.get(&Bytes::from(key))? {
+                None => println!("(not found)"),
+                Some(value) => println!("{}", display(&value)),
+            }
+            db.close()?;
+        }
+        Command::Put { key, value } => {
+            let db = Db::open_file(&cli.db)?;
+            db.put(Bytes::from(key), Bytes::from(value))?;
+            db.close()?;
+        }
+        Command::Delete { key } => {
+            let db = Db::open_file(&cli.db)?;
+            db.delete(Bytes::from(key))?;
+            db.close()?;
+        }
+        Command::Scan { from, to, limit } => {
+            let db = Db::open_file(&cli.db)?;
+            let lower = from.map_or(Bound::Unbounded, |k| Bound::Included(Bytes::from(k)));
+            let upper = to.map_or(Bound::Unbounded, |k| Bound::Excluded(Bytes::from(k)));
+            let mut iter = db.scan(lower, upper)?;
+            let mut count = 0;
+            while iter.is_valid() && count < limit {
+                println!("{} => {}", display(iter.key()), display(iter.value()));
+                count += 1;
+                iter.next()?;
+            }
+            drop(iter);
+            db.close()?;
+        }
+        Command::Stats => {
+            let db = Db::open_file(&cli.db)?;
+            println!("{:#?}", db.stats());
+            println!("{:#?}", db.health());
+            for level in db.level_metadata() {
+                let tombstones = match level.tombstone_density() {
+                    Some(density) => format!("{:.2}%", density * 100.0),
+                    None => "unknown".to_string(),
+                };
+                println!(
+                    "L{}: {} files, {} bytes (avg {}), {} pairs, tombstones {}, {} .. {}",
+                    level.level,
+                    level.files.len(),
+                    level.size,
+                    level.average_file_size(),
+                    level.num_of_pairs,
+                    tombstones,
+                    level.smallest_key_preview().unwrap_or_default(),
+                    level.largest_key_preview().unwrap_or_default()
+                );
+            }
+            db.close()?;
+        }
+        Command::Flush => {
+            let db = Db::open_file(&cli.db)?;
+            db.flush()?;
+            db.close()?;
+        }
+        Command::Compact {
+            level,
+            output_level,
+        } => {
+            let db = Db::open_file(&cli.db)?;
+            match output_level {
+                Some(output_level) => db.compact_to(level, output_level)?,
+                None => db.compact(level)?,
+            }
+            db.close()?;
+        }
+        Command::Manifest => {
+            for (idx, item) in admin::dump_manifest(&cli.db)?.iter().enumerate() {
+                println!("{:>6} {}", idx, item);
+            }
+        }
+        Command::Compactions => {
+            let (mut input_bytes, mut output_bytes) = (0, 0);
+            for compaction in admin::compaction_history(&cli.db)? {
+                let summary = &compaction.summary;
+                println!(
+                    "{:>6} L{} -> L{}: {} files {} bytes -> {} files {} bytes, dropped {} of {} entries, {:?}",
+                    compaction.record_idx,
+                    summary.level,
+                    summary.output_level,
+                    summary.input_files,
+                    summary.input_bytes,
+                    summary.output_files,
+                    summary.output_bytes,
+                    summary.dropped_entries,
+                    summary.input_entries,
+                    summary.duration
+                );
+                println!("       inputs: {:?}", compaction.inputs);
+                println!("       outputs: {:?}", compaction.outputs);
+                input_bytes += summary.input_bytes;
+                output_bytes += summary.output_bytes;
+            }
+            println!(
+                "total: read {} bytes, wrote {} bytes",
+                input_bytes, output_bytes
+            );
+        }
+        Command::Versions => {
+            for version in admin::versions(&cli.db)? {
+                let version = version?;
+                println!("{:>6} {}", version.record_idx, version.changes.join(", "));
+                for (level, sst_ids) in version.levels.iter().enumerate() {
+                    if !sst_ids.is_empty() {
+                        println!("       L{}: {:?}", level, sst_ids);
+                    }
+                }
+                println!("       vssts: {:?}", version.vssts);
+            }
+        }
+        Command::Version { record, key } => {
+            let view = admin::open_version(&cli.db, record)?;
+            for file in &view.missing_files {
+                println!("missing {:?}", file);
+            }
+            match key {
+                Some(key) => match view.get(&Bytes::from(key))? {
+                    None => println!("(not found)"),
+                    Some(value) => println!("{}", display(&value)),
+                },
+                None => {
+                    for (key, value) in view.entries()? {
+                        println!("{} => {}", display(&key), display(&value));
+                    }
+                }
+            }
+        }
+        Command::Sst { file, summary } => {
+            let (sst_summary, entries) = admin::dump_sst(&file)?;
+            println!("{:#?}", sst_summary);
+            if !summary {
+                for entry in entries {
+                    match entry.vsst_id() {
+                        Some(vsst_id) => println!(
+                            "{:?} {} => (vsst {})",
+                            entry.op_type,
+                            display(&entry.key),
+                            vsst_id
+                        ),
+                        None => println!(
+                            "{:?} {} => {}",
+                            entry.op_type,
+                            display(&entry.key),
+                            display(&entry.value)
+                        ),
+                    }
+                }
+            }
+        }
+        Command::Wal {
+            segments,
+            first_seq,
+        } => {
+            let mut reader = WalReader::new(&segments[0]);
+            for segment in &segments[1..] {
+                reader = reader.segment(segment);
+            }
+            if let Some(seq) = first_seq {
+                reader = reader.first_seq(seq);
+            }
+            for record in reader.records() {
+                let record = record?;
+                let seq = record
+                    .seq
+                    .map_or(String::new(), |seq| format!(" seq {}", seq));
+                println!(
+                    "record {}{} at {:?}:{}{}",
+                    record.record_idx,
+                    seq,
+                    record.segment,
+                    record.offset,
+                    record
+                        .idempotency_token
+                        .map_or(String::new(), |token| format!(" token {}", token.0))
+                );
+                for entry in record.entries {
+                    println!(
+                        "  {:?} {} => {}",
+                        entry.op_type,
+                        display(&entry.key),
+                        display(&entry.value)
+                    );
+                }
+            }
+        }
+        Command::Verify => {
+            let report = admin::verify(&cli.db)?;
+            for error in &report.errors {
+                println!("{}", error);
+            }
+            println!(
+                "checked {} files, {} errors",
+                report.checked_files,
+                report.errors.len()
+            );
+            if !report.is_ok() {
+                return Err(anyhow!("verify failed"));
+            }
+        }
+        Command::Repair => {
+            let report = admin::repair(&cli.db)?;
+            for (level, sst_id) in &report.dropped_ssts {
+                println!("dropped L{} sst {}", level, sst_id);
+            }
+            for vsst_id in &report.dropped_vssts {
+                println!("dropped vsst {}", vsst_id);
+            }
+            println!("manifest rewritten");
+        }
+        Command::OpenForRepair { commit } => {
+            let session = Db::open_for_repair(&cli.db)?;
+            let report = session.report();
+            for damaged in &report.damaged_files {
+                let level = damaged
+                    .level
+                    .map(|level| format!("L{} ", level))
+                    .unwrap_or_default();
+                println!(
+                    "{}{:?} {}: {:?}",
+                    level, damaged.file_type, damaged.id, damaged.problem
+                );
+            }
+            println!(
+                "loaded {} ssts, {} vssts, {} damaged files, {} dangling values",
+                report.loaded_ssts,
+                report.loaded_vssts,
+                report.damaged_files.len(),
+                report.dangling_values
+            );
+            if commit {
+                session.commit()?;
+                println!("manifest rewritten");
+            } else if !report.is_clean() {
+                println!("run with --commit to drop the damaged files from the manifest");
+            }
+        }
+        Command::RebuildMetadata => {
+            let report = admin::rebuild_legacy_metadata(&cli.db)?;
+            for sst_id in &report.rebuilt_ssts {
+                println!("rebuilt sst {}", sst_id);
+            }
+            for vsst_id in &report.rebuilt_vssts {
+                println!("rebuilt vsst {}", vsst_id);
+            }
+        }
+        Command::VsstRefs { rebuild } => {
+            let report = if rebuild {
+                admin::rebuild_vsst_refs(&cli.db)?
+            } else {
+                admin::check_vsst_refs(&cli.db)?
+            };
+            for mismatch in &report.mismatches {
+                println!(
+                    "vsst {}: recorded {}, actual {}",
+                    mismatch.vsst_id, mismatch.recorded, mismatch.actual
+                );
+            }
+            for vsst_id in &report.unknown_vssts {
+                println!("vsst {}: referenced but not in manifest", vsst_id);
+            }
+            println!(
+                "checked {} ssts, {} mismatches",
+                report.checked_ssts,
+                report.mismatches.len()
+            );
+            if report.rebuilt {
+                println!("reference counts rewritten");
+            } else if !report.mismatches.is_empty() {
+                println!("run with --rebuild to write the actual reference counts");
+            }
+        }
+        Command::Copy { dest } => {
+            let report = admin::copy_db(&cli.db, &dest)?;
+            println!(
+                "copied {} ssts, {} vssts, {} wals ({} bytes) to {:?}",
+                report.ssts.len(),
+                report.vssts.len(),
+                report.wals.len(),
+                report.bytes,
+                dest
+            );
+        }
+        Command::Replay {
+            trace,
+            preserve_timing,
+        } => {
+            let db = Db::open_file(&cli.db)?;
+            let options = ReplayOptions {
+                preserve_timing,
+                ..ReplayOptions::default()
+            };
+            let report = lasagnedb::trace::replay(&trace, &db, &options)?;
+            println!(
+                "replayed {} writes, {} gets ({} mismatched), {} scans in {:?}",
+                report.writes, report.gets, report.get_mismatches, report.scans, report.elapsed
+            );
+            if report.truncated {
+                println!("the last record of the trace is incomplete");
+            }
+            db.close()?;
+        }
+    }
+    Ok(())
+}
+
+fn main() {
+    if let Err(e) = run(Cli::parse()) {
+        eprintln!("error: {:#}", e);
+        std::process::exit(1);
+    }
+}
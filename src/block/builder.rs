@@ -1,19 +1,40 @@
+use crate::checksum::ChecksumType;
 use crate::entry::Entry;
 use crate::BLOCK_SIZE;
 use bytes::{Buf, BufMut, Bytes, BytesMut};
 use std::mem;
+use thiserror::Error;
+
+#[derive(Error, Debug, Eq, PartialEq)]
+pub enum BlockError {
+    #[error("block too short: {0} bytes")]
+    TooShort(usize),
+    #[error("block has {entry_num} entries, but only {len} bytes")]
+    InvalidEntryNum { entry_num: usize, len: usize },
+    #[error("entry {idx} at offset {offset} exceeds block data of {data_len} bytes")]
+    InvalidEntry {
+        idx: usize,
+        offset: usize,
+        data_len: usize,
+    },
+}
 
-/// `Block` 是持久化存储中的最小读写单元，大小 4KB
+/// `Block` 是持久化存储中的最小读写单元，默认大小 4KB
 ///
 /// ```text
-/// +---------------+--------------------------+------------------+-------------------+
-/// | data(entries) | offsets(2byte*entry num) | checksum(4bytes) | entry num(2bytes) |
-/// +---------------+--------------------------+------------------+-------------------+
+/// +---------------+----------------------------+------------------+-------------------+
+/// | data(entries) | offsets(2/4byte*entry num) | checksum(4bytes) | entry num(2bytes) |
+/// +---------------+----------------------------+------------------+-------------------+
 /// ```
+///
+/// offset 默认为 2 字节；块大小超过 64KB、最后一个 entry 的起始位置超出 u16 时改为 4 字节，
+/// 并在 entry num 的最高位上标记 [`WIDE_OFFSETS_FLAG`]，因此一个块最多有 [`MAX_BLOCK_ENTRIES`] 个 entry
+///
+/// `data` 是读出的块数据的切片，不复制，从块中取出的 key / value 同样共享这段内存
 #[derive(Debug, Eq, PartialEq, Clone)]
 pub struct Block {
-    pub(crate) data: Vec<u8>,
-    pub(crate) offsets: Vec<u16>,
+    pub(crate) data: Bytes,
+    pub(crate) offsets: Vec<u32>,
     pub(crate) checksum: u32,
     pub(crate) entry_num: u16,
 }
@@ -21,63 +42,163 @@ pub struct Block {
 const SIZEOF_U16: usize = mem::size_of::<u16>();
 const SIZEOF_U32: usize = mem::size_of::<u32>();
 
+/// entry num 的最高位，置位时 offset 为 4 字节
+pub const WIDE_OFFSETS_FLAG: u16 = 0x8000;
+/// 一个块中 entry 数量的上限，最高位留给 [`WIDE_OFFSETS_FLAG`]
+pub const MAX_BLOCK_ENTRIES: usize = (WIDE_OFFSETS_FLAG - 1) as usize;
+
+/// 最后一个 entry 从 `last_offset` 开始时每个 offset 需要的字节数
+fn offset_width(last_offset: usize) -> usize {
+    if last_offset > u16::MAX as usize {
+        SIZEOF_U32
+    } else {
+        SIZEOF_U16
+    }
+}
+
 impl Block {
+    fn offset_width(&self) -> usize {
+        offset_width(self.offsets.last().map_or(0, |offset| *offset as usize))
+    }
+
     pub fn encode(&self) -> Bytes {
-        let mut b = BytesMut::new();
+        let width = self.offset_width();
+        let mut b = BytesMut::with_capacity(
+            self.data.len() + self.offsets.len() * width + SIZEOF_U32 + SIZEOF_U16,
+        );
         b.put(&self.data[..]);
         for offset in &self.offsets {
-            b.put_u16_le(*offset);
+            match width {
+                SIZEOF_U16 => b.put_u16_le(*offset as u16),
+                _ => b.put_u32_le(*offset),
+            }
         }
         b.put_u32_le(self.checksum);
-        b.put_u16_le(self.entry_num);
+        match width {
+            SIZEOF_U16 => b.put_u16_le(self.entry_num),
+            _ => b.put_u16_le(self.entry_num | WIDE_OFFSETS_FLAG),
+        }
         // TODO snappy 压缩 和 检查校验和
         b.freeze()
     }
 
-    pub fn decode(data: &[u8]) -> Self {
-        let entry_num = (&data[data.len() - SIZEOF_U16..]).get_u16_le() as usize;
-        let checksum = (&data[data.len() - SIZEOF_U16 - SIZEOF_U32..]).get_u32_le();
+    /// 块占用的内存（字节），`data` 按自身的长度计算，不包括与它共享读缓冲的其他块
+    pub(crate) fn memory(&self) -> u64 {
+        (mem::size_of::<Block>() + self.data.len() + self.offsets.len() * SIZEOF_U32) as u64
+    }
 
-        let data_end = data.len() - SIZEOF_U16 - SIZEOF_U32 - entry_num * SIZEOF_U16;
+    /// 校验数据部分的 crc32 是否与保存的校验和一致
+    /// 用写入时的算法校验 entries 部分，算法记录在 SST footer 中
+    pub fn verify_checksum(&self, checksum_type: ChecksumType) -> bool {
+        checksum_type.checksum(&self.data) == self.checksum
+    }
 
-        let offsets_raw = &data[data_end..data.len() - SIZEOF_U16 - SIZEOF_U32];
-        let offsets = offsets_raw
-            .chunks(SIZEOF_U16)
-            .map(|mut x| x.get_u16_le())
-            .collect();
+    /// 解码一个块，数据不完整或 entry 越界时返回错误而不是 panic
+    ///
+    /// 块的数据部分是 `data` 的切片，不复制
+    pub fn decode(data: Bytes) -> Result<Self, BlockError> {
+        if data.len() < SIZEOF_U16 + SIZEOF_U32 {
+            return Err(BlockError::TooShort(data.len()));
+        }
+        let entry_num = (&data[data.len() - SIZEOF_U16..]).get_u16_le();
+        let checksum = (&data[data.len() - SIZEOF_U16 - SIZEOF_U32..]).get_u32_le();
+        let (entry_num, width) = match entry_num & WIDE_OFFSETS_FLAG {
+            0 => (entry_num as usize, SIZEOF_U16),
+            _ => ((entry_num & !WIDE_OFFSETS_FLAG) as usize, SIZEOF_U32),
+        };
+
+        let data_end = (data.len() - SIZEOF_U16 - SIZEOF_U32)
+            .checked_sub(entry_num * width)
+            .ok_or(BlockError::InvalidEntryNum {
+                entry_num,
+                len: data.len(),
+            })?;
 
-        let data = data[0..data_end].to_vec();
+        let offsets_raw = &data[data_end..data.len() - SIZEOF_U16 - SIZEOF_U32];
+        let offsets: Vec<u32> = match width {
+            SIZEOF_U16 => offsets_raw
+                .chunks(width)
+                .map(|mut x| x.get_u16_le() as u32)
+                .collect(),
+            _ => offsets_raw
+                .chunks(width)
+                .map(|mut x| x.get_u32_le())
+                .collect(),
+        };
+
+        let data = data.slice(0..data_end);
+        for (idx, offset) in offsets.iter().enumerate() {
+            Self::check_entry(&data, *offset as usize).ok_or(BlockError::InvalidEntry {
+                idx,
+                offset: *offset as usize,
+                data_len: data.len(),
+            })?;
+        }
 
-        Self {
+        Ok(Self {
             data,
             offsets,
             checksum,
             entry_num: entry_num as u16,
-        }
+        })
+    }
+
+    /// 检查 `offset` 处的 entry 是否完整位于 data 内，layout 见 [`Entry`]
+    fn check_entry(data: &[u8], offset: usize) -> Option<()> {
+        let key_len_end = offset.checked_add(4 + 8)?;
+        let key_len = (&data.get(offset + 4..key_len_end)?[..]).get_u64_le() as usize;
+        let value_len_off = key_len_end.checked_add(key_len)?;
+        let value_len_end = value_len_off.checked_add(8)?;
+        let value_len = (&data.get(value_len_off..value_len_end)?[..]).get_u64_le() as usize;
+        let value_end = value_len_end.checked_add(value_len)?;
+        (value_end <= data.len()).then_some(())
     }
 }
 
 pub struct BlockBuilder {
     data: Vec<Entry>,
-    offsets: Vec<u16>,
+    offsets: Vec<u32>,
     entry_size: usize,
+    checksum_type: ChecksumType,
+    block_size: usize,
 }
 
 impl BlockBuilder {
+    #[cfg(test)]
     pub fn new() -> BlockBuilder {
+        Self::with_checksum(crate::BLOCK_CHECKSUM)
+    }
+
+    pub fn with_checksum(checksum_type: ChecksumType) -> BlockBuilder {
+        Self::with_options(checksum_type, BLOCK_SIZE)
+    }
+
+    /// 块大小超过 `block_size` 后不再接受新的 entry，块中至少有一个 entry，
+    /// 因此 `block_size` 为 0 时每个块只有一个 entry。`block_size` 超过 64KB 时 offset 可能改为 4 字节，见 [`Block`]
+    pub fn with_options(checksum_type: ChecksumType, block_size: usize) -> BlockBuilder {
         BlockBuilder {
             data: Vec::new(),
             offsets: Vec::new(),
             entry_size: 0,
+            checksum_type,
+            block_size,
         }
     }
 
+    /// 加入一个 entry，块已满时返回 false，entry 数量达到 [`MAX_BLOCK_ENTRIES`] 时同样视为已满
+    ///
+    /// 单个 entry 的大小不受限制，超过 `block_size` 的 entry 单独成为一个块。
+    /// entry 的起始位置超出 u32 时 offset 无法表示，会 panic，`block_size` 不应接近 4GB
     pub fn add(&mut self, e: &Entry) -> bool {
-        if self.size() + e.size() > BLOCK_SIZE && !self.is_empty() {
+        if !self.is_empty()
+            && (self.size_with(e) > self.block_size || self.len() >= MAX_BLOCK_ENTRIES)
+        {
             return false;
         }
 
-        self.offsets.push(self.entry_size as u16);
+        let offset = u32::try_from(self.entry_size)
+            .unwrap_or_else(|_| panic!("block offset {} overflows u32", self.entry_size));
+        self.offsets.push(offset);
         self.data.push(e.clone());
         self.entry_size += e.size();
         true
@@ -88,11 +209,11 @@ impl BlockBuilder {
         for e in &self.data {
             b.put(e.encode());
         }
-        let checksum = crc::crc32::checksum_ieee(&b);
+        let checksum = self.checksum_type.checksum(&b);
         let entry_num = self.data.len() as u16;
 
         Block {
-            data: b.to_vec(),
+            data: b.freeze(),
             offsets: self.offsets,
             checksum,
             entry_num,
@@ -103,8 +224,19 @@ impl BlockBuilder {
         self.offsets.is_empty()
     }
 
+    pub fn len(&self) -> usize {
+        self.offsets.len()
+    }
+
     pub fn size(&self) -> usize {
+        let last_offset = self.offsets.last().map_or(0, |offset| *offset as usize);
         // entries + offsets + checksum(4bytes) + entry num(2bytes)
-        self.entry_size + self.offsets.len() * SIZEOF_U16 + SIZEOF_U32 + SIZEOF_U16
+        self.entry_size + self.offsets.len() * offset_width(last_offset) + SIZEOF_U32 + SIZEOF_U16
+    }
+
+    /// 同 `size() + e.size()`，`e` 的起始位置超出 u16 时已有的 offset 都按 4 字节计算
+    fn size_with(&self, e: &Entry) -> usize {
+        let width = offset_width(self.entry_size);
+        self.entry_size + e.size() + self.offsets.len() * width + SIZEOF_U32 + SIZEOF_U16
     }
 }
@@ -1,13 +1,19 @@
 pub use crate::block::builder::Block;
 use crate::entry::{Entry, EntryBuilder};
+use bytes::Buf;
+use std::ops::Range;
 use std::sync::Arc;
 
 /// Iterates on a block.
+///
+/// 迭代时不会为每个 entry 拷贝出 key / value，只记录它们在 block 数据中的位置，
+/// 需要 owned 的 [`Entry`] 时再调用 [`BlockIterator::entry`] 构造
 #[derive(Debug)]
 pub struct BlockIterator {
     block: Arc<Block>,
-    meta: Vec<u8>,
-    entry: Entry,
+    meta: [u8; 4],
+    key: Range<usize>,
+    value: Range<usize>,
     valid: bool,
     idx: usize,
 }
@@ -16,8 +22,9 @@ impl BlockIterator {
     fn new(block: Arc<Block>) -> Self {
         Self {
             block,
-            meta: vec![],
-            entry: EntryBuilder::empty(),
+            meta: [0; 4],
+            key: 0..0,
+            value: 0..0,
             valid: false,
             idx: 0,
         }
@@ -37,28 +44,35 @@ impl BlockIterator {
         iter
     }
 
-    /// Return the current entry.
-    pub fn entry(&self) -> &Entry {
+    /// Return the current entry, key and value are slices of the block data, not copied.
+    pub fn entry(&self) -> Entry {
         debug_assert!(self.valid, "invalid iterator");
-        &self.entry
+        if !self.valid {
+            return EntryBuilder::empty();
+        }
+        Entry {
+            meta: u32::from_le_bytes(self.meta),
+            key: self.block.data.slice(self.key.clone()),
+            value: self.block.data.slice(self.value.clone()),
+        }
     }
 
     /// Returns meta info of the current entry.
     pub fn meta(&self) -> &[u8] {
         debug_assert!(self.valid, "invalid iterator");
-        &self.meta[..]
+        &self.meta
     }
 
     /// Returns the key of the current entry.
     pub fn key(&self) -> &[u8] {
         debug_assert!(self.valid, "invalid iterator");
-        &self.entry.key[..]
+        &self.block.data[self.key.clone()]
     }
 
     /// Returns the value of the current entry.
     pub fn value(&self) -> &[u8] {
         debug_assert!(self.valid, "invalid iterator");
-        &self.entry.value[..]
+        &self.block.data[self.value.clone()]
     }
 
     /// Returns true if the iterator is valid.
@@ -74,7 +88,8 @@ impl BlockIterator {
     /// Seeks to the idx-th key in the block.
     fn seek_to(&mut self, idx: usize) {
         if idx >= self.block.offsets.len() {
-            self.entry = EntryBuilder::empty();
+            self.key = 0..0;
+            self.value = 0..0;
             self.valid = false;
             return;
         }
@@ -89,14 +104,23 @@ impl BlockIterator {
         self.seek_to(self.idx);
     }
 
+    /// 只解析 entry 的头部，layout 见 [`Entry`]
     fn seek_to_offset(&mut self, offset: usize) {
-        let entry = Entry::decode(&self.block.data[offset..]);
-        self.entry = entry;
-        self.meta = self.entry.meta.to_le_bytes().to_vec();
+        let data = &self.block.data[offset..];
+        self.meta.copy_from_slice(&data[..4]);
+        let key_len = (&data[4..12]).get_u64_le() as usize;
+        let key_start = offset + 12;
+        let value_len_off = key_start + key_len;
+        let value_len = (&self.block.data[value_len_off..value_len_off + 8]).get_u64_le() as usize;
+        let value_start = value_len_off + 8;
+        self.key = key_start..value_len_off;
+        self.value = value_start..value_start + value_len;
         self.valid = true;
     }
 
     /// Seek to the first key that >= `key`.
+    ///
+    /// 同一个 key 可能有多个版本（新的在前），相等时继续向前查找，保证定位到第一个
     pub fn seek_to_key(&mut self, key: &[u8]) {
         let mut low = 0;
         let mut high = self.block.offsets.len();
@@ -106,8 +130,7 @@ impl BlockIterator {
             assert!(self.is_valid());
             match self.key().cmp(key) {
                 std::cmp::Ordering::Less => low = mid + 1,
-                std::cmp::Ordering::Greater => high = mid,
-                std::cmp::Ordering::Equal => return,
+                std::cmp::Ordering::Greater | std::cmp::Ordering::Equal => high = mid,
             }
         }
         self.seek_to(low);
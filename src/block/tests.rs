@@ -1,7 +1,9 @@
-use crate::block::builder::{Block, BlockBuilder};
+use crate::block::builder::{
+    Block, BlockBuilder, BlockError, MAX_BLOCK_ENTRIES, WIDE_OFFSETS_FLAG,
+};
 use crate::block::iterator::BlockIterator;
 use crate::entry::{Entry, EntryBuilder};
-use crate::OpType;
+use crate::{OpType, BLOCK_CHECKSUM};
 use bytes::Bytes;
 use rand::distributions::{Alphanumeric, DistString};
 use rand::{thread_rng, Rng};
@@ -17,7 +19,7 @@ pub fn rand_gen_entries(num: usize) -> Vec<Entry> {
     for _ in 0..num {
         ret.push(
             EntryBuilder::new()
-                .op_type(OpType::Get)
+                .op_type(OpType::Put)
                 .key_value(Bytes::from(rand_str()), Bytes::from(rand_str()))
                 .build(),
         )
@@ -39,11 +41,11 @@ fn rand_gen_block() -> (Block, Vec<Entry>) {
 fn test_block_builder() {
     let (block, entries) = rand_gen_block();
 
-    let mut offsets: Vec<u16> = Vec::new();
-    let mut off: u16 = 0;
+    let mut offsets: Vec<u32> = Vec::new();
+    let mut off: u32 = 0;
     for i in &entries {
         offsets.push(off);
-        off += i.size() as u16;
+        off += i.size() as u32;
     }
     assert_eq!(block.offsets, offsets);
 }
@@ -52,8 +54,130 @@ fn test_block_builder() {
 fn test_block_encode() {
     let (block, _) = rand_gen_block();
     let block_encode = block.encode();
-    let block2 = Block::decode(&block_encode[..]);
+    let block2 = Block::decode(block_encode.clone()).unwrap();
     assert_eq!(block, block2);
+    assert!(block2.verify_checksum(BLOCK_CHECKSUM));
+    // 数据部分和取出的 entry 都是读缓冲的切片
+    assert_eq!(block2.data.as_ptr(), block_encode.as_ptr());
+    let entry = BlockIterator::create_and_seek_to_first(Arc::new(block2)).entry();
+    let range = block_encode.as_ptr_range();
+    assert!(range.contains(&entry.key.as_ptr()));
+    assert!(range.contains(&entry.value.as_ptr()));
+}
+
+#[test]
+fn test_block_decode_corrupted() {
+    assert_eq!(
+        Block::decode(Bytes::from_static(&[1, 2, 3])),
+        Err(BlockError::TooShort(3))
+    );
+
+    let (block, _) = rand_gen_block();
+    let mut data = block.encode().to_vec();
+    let len = data.len();
+    data[len - 2..].copy_from_slice(&u16::MAX.to_le_bytes());
+    assert!(matches!(
+        Block::decode(Bytes::from(data)),
+        Err(BlockError::InvalidEntryNum { .. })
+    ));
+
+    let mut data = block.encode().to_vec();
+    data[4..12].copy_from_slice(&u64::MAX.to_le_bytes());
+    assert!(matches!(
+        Block::decode(Bytes::from(data)),
+        Err(BlockError::InvalidEntry { idx: 0, .. })
+    ));
+}
+
+/// 大小为 `size` 字节的 entry
+fn entry_of_size(key: &str, size: usize) -> Entry {
+    EntryBuilder::new()
+        .op_type(OpType::Put)
+        .key_value(
+            Bytes::copy_from_slice(key.as_bytes()),
+            Bytes::from(vec![b'v'; size - 20 - key.len()]),
+        )
+        .build()
+}
+
+/// 编码后 entry num 上是否标记了 4 字节的 offset
+fn is_wide(encoded: &[u8]) -> bool {
+    let entry_num = u16::from_le_bytes(encoded[encoded.len() - 2..].try_into().unwrap());
+    entry_num & WIDE_OFFSETS_FLAG != 0
+}
+
+fn assert_round_trip(block: Block, entries: &[Entry]) {
+    let decoded = Block::decode(block.encode()).unwrap();
+    assert_eq!(decoded, block);
+    let mut iter = BlockIterator::create_and_seek_to_first(Arc::new(decoded.clone()));
+    for e in entries {
+        assert_eq!(e, &iter.entry());
+        iter.next();
+    }
+    assert!(!iter.is_valid());
+    let last = entries.last().unwrap();
+    let iter = BlockIterator::create_and_seek_to_key(Arc::new(decoded), &last.key);
+    assert_eq!(last, &iter.entry());
+}
+
+#[test]
+fn test_block_offset_width_boundary() {
+    // 第二个 entry 从 u16::MAX 开始时 offset 仍为 2 字节，从 u16::MAX + 1 开始时改为 4 字节
+    for (first_size, wide) in [(u16::MAX as usize, false), (u16::MAX as usize + 1, true)] {
+        let entries = vec![entry_of_size("a", first_size), entry_of_size("b", 100)];
+        let mut builder = BlockBuilder::with_options(BLOCK_CHECKSUM, 1 << 20);
+        entries.iter().for_each(|e| assert!(builder.add(e)));
+        let size = builder.size();
+        let block = builder.build();
+        assert_eq!(block.offsets, vec![0, first_size as u32]);
+        let encoded = block.encode();
+        assert_eq!(encoded.len(), size);
+        assert_eq!(is_wide(&encoded), wide);
+        assert_round_trip(block, &entries);
+    }
+
+    // 默认块大小下超过 64KB 的 entry 单独成为一个块，offset 仍为 2 字节
+    let entries = vec![entry_of_size("a", 1 << 20)];
+    let mut builder = BlockBuilder::new();
+    assert!(builder.add(&entries[0]));
+    assert!(!builder.add(&entry_of_size("b", 100)));
+    let block = builder.build();
+    assert!(!is_wide(&block.encode()));
+    assert_round_trip(block, &entries);
+}
+
+#[test]
+fn test_block_wide_offsets() {
+    let entries: Vec<_> = (0..200)
+        .map(|i| entry_of_size(&format!("key{:03}", i), 1000))
+        .collect();
+    let mut builder = BlockBuilder::with_options(BLOCK_CHECKSUM, 1 << 20);
+    entries.iter().for_each(|e| assert!(builder.add(e)));
+    let size = builder.size();
+    let block = builder.build();
+    assert_eq!(*block.offsets.last().unwrap(), 199 * 1000);
+    let encoded = block.encode();
+    assert_eq!(encoded.len(), size);
+    assert!(is_wide(&encoded));
+    assert_round_trip(block, &entries);
+}
+
+#[test]
+fn test_block_max_entries() {
+    let mut builder = BlockBuilder::with_options(BLOCK_CHECKSUM, usize::MAX);
+    let mut entries = vec![];
+    for i in 0.. {
+        let entry = entry_of_size(&format!("{:06}", i), 30);
+        if !builder.add(&entry) {
+            break;
+        }
+        entries.push(entry);
+    }
+    assert_eq!(entries.len(), MAX_BLOCK_ENTRIES);
+    let block = builder.build();
+    assert_eq!(block.entry_num as usize, MAX_BLOCK_ENTRIES);
+    assert!(is_wide(&block.encode()));
+    assert_round_trip(block, &entries);
 }
 
 #[test]
@@ -64,6 +188,10 @@ fn test_block_iterator() {
 
     entries.iter().for_each(|e| {
         assert_eq!(&e.key[..], iter.key());
+        assert_eq!(&e.value[..], iter.value());
+        assert_eq!(&e.meta.to_le_bytes(), iter.meta());
+        assert_eq!(e, &iter.entry());
         iter.next();
     });
+    assert!(!iter.is_valid());
 }
@@ -1,5 +1,388 @@
 use crate::block::builder::Block;
+use crate::perf_context;
+use crate::sstable::builder::CorruptionError;
+use crate::sstable::meta::MetaBlock;
+use anyhow::anyhow;
+use bytes::Bytes;
+use moka::sync::ConcurrentCacheExt;
+use parking_lot::{Condvar, Mutex};
+use std::collections::HashMap;
+use std::fmt::{Debug, Formatter};
+use std::sync::atomic::{AtomicU64, Ordering};
 use std::sync::Arc;
 
 // (sst id, block id)
-pub type BlockCache = moka::sync::Cache<(u32, usize), Arc<Block>>;
+pub type BlockKey = (u64, usize);
+
+/// 后台任务读写 SST 时填充块缓存的方式，见 [`crate::DbOptions::compaction_cache_fill`] 和 [`crate::DbOptions::flush_cache_fill`]
+#[derive(Debug, Clone, Copy, Default, Eq, PartialEq)]
+pub enum CacheFillPolicy {
+    /// 读取输入时把从文件读出的块放入缓存
+    OnRead,
+    /// 读取输入时不放入缓存，只在写出新文件后把其中可能被读取的块读入缓存
+    #[default]
+    OnWrite,
+    /// 读写都不填充缓存，已在缓存中的块仍然会被使用
+    None,
+}
+
+impl CacheFillPolicy {
+    pub(crate) fn fill_on_read(self) -> bool {
+        self == CacheFillPolicy::OnRead
+    }
+
+    pub(crate) fn fill_on_write(self) -> bool {
+        self == CacheFillPolicy::OnWrite
+    }
+}
+
+/// SST 的块缓存，容量按块占用的内存（字节）计算
+///
+/// 同一个块同时只会被一个线程从磁盘读取，其他同时未命中该块的线程等待它的结果，
+/// 避免热点块被淘汰后并发的读取和扫描各自读一遍文件
+pub struct BlockCache {
+    blocks: moka::sync::Cache<BlockKey, CachedBlock>,
+    // 压缩的 SST 的块以压缩后的形式缓存，见 [`BlockCache::with_compressed_blocks`]
+    compressed: bool,
+    // 按 sst id 缓存的 meta，为 `None` 时 SST 的 meta 常驻内存，见 [`BlockCache::with_index_capacity`]
+    indexes: Option<moka::sync::Cache<u64, Arc<Vec<MetaBlock>>>>,
+    index_loads: AtomicU64,
+    loading: Mutex<HashMap<BlockKey, Arc<Loading>>>,
+    loads: AtomicU64,
+    deduplicated_loads: AtomicU64,
+    hits: AtomicU64,
+    compressed_hits: AtomicU64,
+}
+
+/// 缓存中的一个块，同一个块只以其中一种形式缓存
+#[derive(Clone)]
+pub(crate) enum CachedBlock {
+    /// 解码后的块，命中时直接使用
+    Decoded(Arc<Block>),
+    /// 从文件读出的压缩数据，命中时需要解压和解码
+    Compressed(Bytes),
+}
+
+impl CachedBlock {
+    /// 缓存项占用的内存（字节）
+    fn memory(&self) -> u64 {
+        match self {
+            CachedBlock::Decoded(block) => block.memory(),
+            CachedBlock::Compressed(data) => (std::mem::size_of::<Bytes>() + data.len()) as u64,
+        }
+    }
+}
+
+/// 缓存中的块按形式分别占用的内存（字节）
+#[derive(Debug, Clone, Copy, Default, Eq, PartialEq)]
+pub(crate) struct BlockMemory {
+    pub(crate) decoded: u64,
+    pub(crate) compressed: u64,
+}
+
+/// meta 占用的内存（字节），包括 key 和每个 meta 的固定开销
+pub(crate) fn index_memory(metas: &[MetaBlock]) -> u64 {
+    metas
+        .iter()
+        .map(|meta| std::mem::size_of::<MetaBlock>() + meta.first_key.len() + meta.last_key.len())
+        .sum::<usize>() as u64
+}
+
+/// 正在从磁盘读取的块，读取完成后唤醒等待的线程
+#[derive(Default)]
+pub(crate) struct Loading {
+    result: Mutex<Option<Result<Arc<Block>, LoadError>>>,
+    done: Condvar,
+}
+
+/// 读取者遇到的错误，分享给等待的线程，块损坏时保留 [`CorruptionError`] 以便调用方识别
+#[derive(Clone)]
+enum LoadError {
+    Corruption(CorruptionError),
+    Other(String),
+}
+
+impl From<LoadError> for anyhow::Error {
+    fn from(e: LoadError) -> Self {
+        match e {
+            LoadError::Corruption(corruption) => corruption.into(),
+            LoadError::Other(reason) => anyhow!(reason),
+        }
+    }
+}
+
+/// 读取一个块的结果
+pub(crate) enum BlockLoad<'a> {
+    Cached(Arc<Block>),
+    /// 缓存中是压缩的块，由调用方解压和解码，不需要再校验
+    Compressed(Bytes),
+    /// 其他线程正在读取该块
+    Waiting(Arc<Loading>),
+    /// 由当前线程读取，读完后通过 [`LoadTicket::finish`] 交给等待的线程
+    Leader(LoadTicket<'a>),
+}
+
+impl BlockCache {
+    pub fn new(max_capacity: u64) -> Self {
+        Self {
+            blocks: moka::sync::Cache::builder()
+                .max_capacity(max_capacity)
+                .weigher(|_, block: &CachedBlock| block.memory().try_into().unwrap_or(u32::MAX))
+                .build(),
+            compressed: false,
+            indexes: None,
+            index_loads: AtomicU64::new(0),
+            loading: Mutex::new(HashMap::new()),
+            loads: AtomicU64::new(0),
+            deduplicated_loads: AtomicU64::new(0),
+            hits: AtomicU64::new(0),
+            compressed_hits: AtomicU64::new(0),
+        }
+    }
+
+    /// 压缩的 SST 的块以压缩后的形式缓存，同样的容量可以缓存更多的块，但每次命中都要解压和解码，
+    /// 用 CPU 换内存。未压缩的 SST 的块仍以解码后的形式缓存
+    pub fn with_compressed_blocks(mut self) -> Self {
+        self.compressed = true;
+        self
+    }
+
+    /// 压缩的 SST 的块是否以压缩后的形式缓存
+    pub(crate) fn caches_compressed(&self) -> bool {
+        self.compressed
+    }
+
+    /// 使用该缓存的 SST 只常驻 footer、块数和 key 范围，meta 放入最多占用 `capacity` 字节的缓存，
+    /// 被淘汰后在下次访问时从文件中重新读取，用于限制大量冷 SST 的 meta 常驻的内存
+    pub fn with_index_capacity(mut self, capacity: u64) -> Self {
+        self.indexes = Some(
+            moka::sync::Cache::builder()
+                .max_capacity(capacity)
+                .weigher(|_, metas: &Arc<Vec<MetaBlock>>| {
+                    index_memory(metas).try_into().unwrap_or(u32::MAX)
+                })
+                .build(),
+        );
+        self
+    }
+
+    /// SST 的 meta 是否经由缓存加载
+    pub(crate) fn caches_index(&self) -> bool {
+        self.indexes.is_some()
+    }
+
+    pub(crate) fn get_index(&self, sst_id: u64) -> Option<Arc<Vec<MetaBlock>>> {
+        self.indexes.as_ref()?.get(&sst_id)
+    }
+
+    /// `from_disk` 表示 meta 是缓存未命中后从文件中读出的，计入 [`BlockCache::index_loads`]
+    pub(crate) fn insert_index(&self, sst_id: u64, metas: Arc<Vec<MetaBlock>>, from_disk: bool) {
+        if let Some(indexes) = &self.indexes {
+            if from_disk {
+                self.index_loads.fetch_add(1, Ordering::Relaxed);
+            }
+            indexes.insert(sst_id, metas);
+        }
+    }
+
+    /// 缓存中的 meta 占用的内存（字节）
+    pub fn index_memory(&self) -> u64 {
+        self.indexes.as_ref().map_or(0, |indexes| {
+            // 先处理挂起的插入和淘汰，否则统计会滞后
+            indexes.sync();
+            indexes.weighted_size()
+        })
+    }
+
+    /// 缓存中的块占用的内存（字节），见 [`Block::memory`]
+    pub fn block_memory(&self) -> u64 {
+        // 先处理挂起的插入和淘汰，否则统计会滞后
+        self.blocks.sync();
+        self.blocks.weighted_size()
+    }
+
+    /// 缓存中解码后的块和压缩的块分别占用的内存，缓存压缩块时需要遍历缓存
+    pub(crate) fn block_memory_by_kind(&self) -> BlockMemory {
+        if !self.compressed {
+            return BlockMemory {
+                decoded: self.block_memory(),
+                compressed: 0,
+            };
+        }
+        let mut memory = BlockMemory::default();
+        for (_, block) in self.blocks.iter() {
+            match block {
+                CachedBlock::Decoded(_) => memory.decoded += block.memory(),
+                CachedBlock::Compressed(_) => memory.compressed += block.memory(),
+            }
+        }
+        memory
+    }
+
+    /// meta 缓存未命中后从文件中读取的次数
+    pub fn index_loads(&self) -> u64 {
+        self.index_loads.load(Ordering::Relaxed)
+    }
+
+    /// 缓存中解码后的块，以压缩形式缓存的块不会返回
+    pub fn get(&self, key: &BlockKey) -> Option<Arc<Block>> {
+        match self.blocks.get(key)? {
+            CachedBlock::Decoded(block) => Some(block),
+            CachedBlock::Compressed(_) => None,
+        }
+    }
+
+    pub fn insert(&self, key: BlockKey, block: Arc<Block>) {
+        self.blocks.insert(key, CachedBlock::Decoded(block))
+    }
+
+    pub fn contains_key(&self, key: &BlockKey) -> bool {
+        self.blocks.contains_key(key)
+    }
+
+    /// 经由缓存从磁盘读取块的次数
+    pub fn loads(&self) -> u64 {
+        self.loads.load(Ordering::Relaxed)
+    }
+
+    /// 因同一个块已在读取中而等待其结果、没有重复读盘的次数
+    pub fn deduplicated_loads(&self) -> u64 {
+        self.deduplicated_loads.load(Ordering::Relaxed)
+    }
+
+    /// 命中解码后的块的次数
+    pub fn hits(&self) -> u64 {
+        self.hits.load(Ordering::Relaxed)
+    }
+
+    /// 命中压缩的块、需要解压的次数
+    pub fn compressed_hits(&self) -> u64 {
+        self.compressed_hits.load(Ordering::Relaxed)
+    }
+
+    fn hit(&self, block: CachedBlock) -> BlockLoad<'_> {
+        perf_context::record(|c| c.block_cache_hits += 1);
+        match block {
+            CachedBlock::Decoded(block) => {
+                self.hits.fetch_add(1, Ordering::Relaxed);
+                BlockLoad::Cached(block)
+            }
+            CachedBlock::Compressed(data) => {
+                self.compressed_hits.fetch_add(1, Ordering::Relaxed);
+                BlockLoad::Compressed(data)
+            }
+        }
+    }
+
+    /// 开始读取 `key`，已缓存时直接返回，其他线程正在读取时返回等待对象
+    pub(crate) fn begin_load(&self, key: BlockKey) -> BlockLoad<'_> {
+        if let Some(block) = self.blocks.get(&key) {
+            return self.hit(block);
+        }
+        let mut loading = self.loading.lock();
+        if let Some(waiting) = loading.get(&key) {
+            self.deduplicated_loads.fetch_add(1, Ordering::Relaxed);
+            return BlockLoad::Waiting(waiting.clone());
+        }
+        // 读取者先放入缓存再移除读取记录，持锁后再查一次，避免刚读完的块被再读一遍
+        if let Some(block) = self.blocks.get(&key) {
+            return self.hit(block);
+        }
+        BlockLoad::Leader(self.new_ticket(&mut loading, key))
+    }
+
+    /// 块既不在缓存中也没有在读取时由当前线程读取，用于合并读取相邻的块
+    pub(crate) fn try_begin_load(&self, key: BlockKey) -> Option<LoadTicket<'_>> {
+        let mut loading = self.loading.lock();
+        if loading.contains_key(&key) || self.blocks.contains_key(&key) {
+            return None;
+        }
+        Some(self.new_ticket(&mut loading, key))
+    }
+
+    fn new_ticket(
+        &self,
+        loading: &mut HashMap<BlockKey, Arc<Loading>>,
+        key: BlockKey,
+    ) -> LoadTicket<'_> {
+        self.loads.fetch_add(1, Ordering::Relaxed);
+        let state = Arc::new(Loading::default());
+        loading.insert(key, state.clone());
+        LoadTicket {
+            cache: self,
+            key,
+            state,
+            finished: false,
+        }
+    }
+}
+
+impl Debug for BlockCache {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("BlockCache")
+            .field("entry_count", &self.blocks.entry_count())
+            .field("compressed", &self.compressed)
+            .field("index_memory", &self.index_memory())
+            .field("loads", &self.loads())
+            .field("deduplicated_loads", &self.deduplicated_loads())
+            .finish()
+    }
+}
+
+impl Loading {
+    /// 等待读取者完成
+    pub(crate) fn wait(&self) -> anyhow::Result<Arc<Block>> {
+        let mut result = self.result.lock();
+        while result.is_none() {
+            self.done.wait(&mut result);
+        }
+        result.as_ref().unwrap().clone().map_err(|e| e.into())
+    }
+}
+
+/// 当前线程负责读取一个块，drop 时尚未完成的读取以错误结束，不会让等待的线程一直阻塞
+pub(crate) struct LoadTicket<'a> {
+    cache: &'a BlockCache,
+    key: BlockKey,
+    state: Arc<Loading>,
+    finished: bool,
+}
+
+impl LoadTicket<'_> {
+    /// 结束读取并唤醒等待的线程，读取成功且 `entry` 不为 `None` 时将其放入缓存
+    pub(crate) fn finish(
+        mut self,
+        result: Result<&Arc<Block>, &anyhow::Error>,
+        entry: Option<CachedBlock>,
+    ) {
+        let result = match result {
+            Ok(block) => {
+                if let Some(entry) = entry {
+                    self.cache.blocks.insert(self.key, entry);
+                }
+                Ok(block.clone())
+            }
+            Err(e) => Err(match e.downcast_ref::<CorruptionError>() {
+                Some(corruption) => LoadError::Corruption(corruption.clone()),
+                None => LoadError::Other(format!("{:#}", e)),
+            }),
+        };
+        self.complete(result);
+    }
+
+    fn complete(&mut self, result: Result<Arc<Block>, LoadError>) {
+        self.finished = true;
+        self.cache.loading.lock().remove(&self.key);
+        *self.state.result.lock() = Some(result);
+        self.state.done.notify_all();
+    }
+}
+
+impl Drop for LoadTicket<'_> {
+    fn drop(&mut self) {
+        if !self.finished {
+            let reason = format!("load of block {:?} was abandoned", self.key);
+            self.complete(Err(LoadError::Other(reason)));
+        }
+    }
+}
@@ -0,0 +1,224 @@
+use std::sync::atomic::{AtomicBool, Ordering};
+
+use anyhow::{anyhow, ensure};
+use bytes::{Buf, BufMut, Bytes, BytesMut};
+use crossbeam::channel;
+use parking_lot::{Mutex, MutexGuard};
+
+use crate::entry::{Entry, EntryBuilder};
+use crate::keys;
+use crate::system::{is_system_key, system_key, CDC_NAMESPACE};
+use crate::OpType;
+
+/// 已投递到 [`CdcSink`] 的最大变更序号，CDC 的状态都在系统 key 空间的 `cdc` 命名空间中，见 [`crate::system`]
+pub(crate) fn offset_key() -> Bytes {
+    system_key(CDC_NAMESPACE, b"offset")
+}
+
+/// 变更日志的前缀，每次写入一条日志，key 为前缀加大端编码的变更序号
+pub(crate) fn log_prefix() -> Bytes {
+    system_key(CDC_NAMESPACE, b"log/")
+}
+
+pub(crate) fn log_key(seq: u64) -> Bytes {
+    let prefix = log_prefix();
+    let mut key = BytesMut::with_capacity(prefix.len() + 8);
+    key.put_slice(&prefix);
+    key.put_slice(&keys::encode_u64(seq));
+    key.freeze()
+}
+
+pub(crate) fn seq_of_log_key(key: &[u8]) -> anyhow::Result<u64> {
+    let seq = key
+        .strip_prefix(log_prefix().as_ref())
+        .ok_or_else(|| anyhow!("invalid change log key {:?}", Bytes::copy_from_slice(key)))?;
+    Ok(keys::decode_u64(seq)?)
+}
+
+/// 一次已提交的修改，`value` 为 `None` 表示删除
+///
+/// 同一次写入（批次）中的修改有相同的 `seq`，`seq` 随写入递增，重启后继续递增。
+/// 投递保证至少一次：同一个修改可能重复投递，接收方可以按 `seq` 去重
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct CdcEvent {
+    pub seq: u64,
+    pub key: Bytes,
+    pub value: Option<Bytes>,
+}
+
+/// 接收已提交修改的下游，例如索引或搜索系统，见 [`crate::Db::start_cdc`]
+pub trait CdcSink: Send + Sync + 'static {
+    /// 按提交顺序投递一批修改。返回错误时稍后重新投递同一批修改，返回成功后这批修改不会再投递（除非进程在记录投递进度前退出）
+    fn deliver(&self, events: &[CdcEvent]) -> anyhow::Result<()>;
+}
+
+impl<F> CdcSink for F
+where
+    F: Fn(&[CdcEvent]) -> anyhow::Result<()> + Send + Sync + 'static,
+{
+    fn deliver(&self, events: &[CdcEvent]) -> anyhow::Result<()> {
+        self(events)
+    }
+}
+
+/// 把写入的修改记录到系统 key 空间中的变更日志，日志与修改写入同一条 WAL 记录，崩溃后一起恢复
+///
+/// 记录日志的写入按变更序号串行执行，变更日志的 key 顺序与写入 memtable 的顺序一致
+#[derive(Debug)]
+pub(crate) struct ChangeLog {
+    // 下一条日志的变更序号
+    next_seq: Mutex<u64>,
+    started: AtomicBool,
+    appended: (channel::Sender<()>, channel::Receiver<()>),
+}
+
+/// 持有变更序号的锁直到修改写入 memtable，drop 时通知投递线程，见 [`ChangeLog::capture`]
+pub(crate) struct Capture<'a> {
+    _next_seq: MutexGuard<'a, u64>,
+    log: &'a ChangeLog,
+}
+
+impl Drop for Capture<'_> {
+    fn drop(&mut self) {
+        let _ = self.log.appended.0.try_send(());
+    }
+}
+
+impl ChangeLog {
+    /// 从已投递的序号 `offset` 和最后一条日志的序号 `last_logged` 之后继续
+    pub(crate) fn new(offset: u64, last_logged: u64) -> Self {
+        Self {
+            next_seq: Mutex::new(offset.max(last_logged) + 1),
+            started: AtomicBool::new(false),
+            appended: channel::bounded(1),
+        }
+    }
+
+    /// 为 `entries` 中用户的修改追加一条日志 entry，没有用户的修改（只有幂等 token 或内部的 key）时返回 `None`
+    ///
+    /// 写入失败时分配的序号不再使用，变更日志的序号可能不连续
+    pub(crate) fn capture(&self, entries: &mut Vec<Entry>) -> Option<Capture<'_>> {
+        let changes: Vec<_> = entries
+            .iter()
+            .filter(|e| !e.is_idempotency_token() && !is_system_key(&e.key))
+            .collect();
+        if changes.is_empty() {
+            return None;
+        }
+        let mut value = BytesMut::new();
+        value.put_u32_le(changes.len() as u32);
+        for e in changes {
+            value.put_u8(e.op_type() as u8);
+            value.put_u32_le(e.key.len() as u32);
+            value.put_slice(&e.key);
+            value.put_u32_le(e.value.len() as u32);
+            value.put_slice(&e.value);
+        }
+        let mut next_seq = self.next_seq.lock();
+        let mut builder = EntryBuilder::new();
+        builder
+            .op_type(OpType::Put)
+            .key_value(log_key(*next_seq), value.freeze());
+        entries.push(builder.build());
+        *next_seq += 1;
+        Some(Capture {
+            _next_seq: next_seq,
+            log: self,
+        })
+    }
+
+    /// 只能启动一个投递线程，已启动时返回 `false`
+    pub(crate) fn start(&self) -> bool {
+        !self.started.swap(true, Ordering::AcqRel)
+    }
+
+    /// 有新日志写入时收到通知
+    pub(crate) fn appended(&self) -> channel::Receiver<()> {
+        self.appended.1.clone()
+    }
+}
+
+/// 解码一条变更日志
+pub(crate) fn decode_log(seq: u64, mut data: &[u8]) -> anyhow::Result<Vec<CdcEvent>> {
+    let truncated = || anyhow!("change log {} truncated", seq);
+    ensure!(data.remaining() >= 4, truncated());
+    let count = data.get_u32_le();
+    let mut events = Vec::with_capacity(count as usize);
+    let read_bytes = |data: &mut &[u8]| -> anyhow::Result<Bytes> {
+        ensure!(data.remaining() >= 4, truncated());
+        let len = data.get_u32_le() as usize;
+        ensure!(data.remaining() >= len, truncated());
+        Ok(data.copy_to_bytes(len))
+    };
+    for _ in 0..count {
+        ensure!(data.remaining() >= 1, truncated());
+        let op_type = data.get_u8();
+        let key = read_bytes(&mut data)?;
+        let value = read_bytes(&mut data)?;
+        let value = match op_type {
+            op if op == OpType::Delete as u8 => None,
+            op if op == OpType::Put as u8 => Some(value),
+            op => return Err(anyhow!("change log {} has invalid op type {}", seq, op)),
+        };
+        events.push(CdcEvent { seq, key, value });
+    }
+    Ok(events)
+}
+
+#[cfg(test)]
+mod tests {
+    use bytes::Bytes;
+
+    use crate::cdc::{decode_log, log_key, seq_of_log_key, CdcEvent, ChangeLog};
+    use crate::entry::{Entry, EntryBuilder};
+    use crate::system::is_system_key;
+    use crate::OpType;
+
+    fn entry(key: &'static str, op_type: OpType) -> Entry {
+        let mut builder = EntryBuilder::new();
+        builder
+            .op_type(op_type)
+            .key_value(Bytes::from(key), Bytes::from("v"));
+        builder.build()
+    }
+
+    #[test]
+    fn test_capture() {
+        let log = ChangeLog::new(3, 7);
+        let mut entries = vec![entry("k1", OpType::Put), entry("k2", OpType::Delete)];
+        drop(log.capture(&mut entries).unwrap());
+        assert!(log.appended().try_recv().is_ok());
+        assert_eq!(entries.len(), 3);
+        let logged = entries.pop().unwrap();
+        assert!(is_system_key(&logged.key));
+        assert_eq!(seq_of_log_key(&logged.key).unwrap(), 8);
+        assert_eq!(
+            decode_log(8, &logged.value).unwrap(),
+            vec![
+                CdcEvent {
+                    seq: 8,
+                    key: Bytes::from("k1"),
+                    value: Some(Bytes::from("v")),
+                },
+                CdcEvent {
+                    seq: 8,
+                    key: Bytes::from("k2"),
+                    value: None,
+                },
+            ]
+        );
+        assert!(decode_log(8, &logged.value[..logged.value.len() - 1]).is_err());
+
+        // 只有内部 key 的写入不记录日志
+        let mut entries = vec![logged];
+        assert!(log.capture(&mut entries).is_none());
+        assert_eq!(entries.len(), 1);
+
+        let mut entries = vec![entry("k3", OpType::Put)];
+        let _capture = log.capture(&mut entries).unwrap();
+        assert_eq!(entries[1].key, log_key(9));
+        assert!(log.appended().is_empty());
+        drop(_capture);
+        assert!(log.appended().try_recv().is_ok());
+    }
+}
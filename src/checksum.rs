@@ -0,0 +1,62 @@
+use anyhow::{anyhow, Result};
+
+/// 数据块校验和算法，记录在 SST footer 中
+///
+/// crc32c 在支持 SSE4.2 / ARMv8 CRC 指令的平台上使用硬件加速，xxh3 取 64 位结果的低 32 位
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum ChecksumType {
+    Crc32 = 0,
+    Crc32c = 1,
+    Xxh3 = 2,
+}
+
+impl ChecksumType {
+    pub fn from(num: u32) -> Result<Self> {
+        match num {
+            0 => Ok(ChecksumType::Crc32),
+            1 => Ok(ChecksumType::Crc32c),
+            2 => Ok(ChecksumType::Xxh3),
+            _ => Err(anyhow!("unsupported checksum type: {}", num)),
+        }
+    }
+
+    pub fn encode(&self) -> u32 {
+        *self as u32
+    }
+
+    pub fn checksum(&self, data: &[u8]) -> u32 {
+        match self {
+            ChecksumType::Crc32 => crc::crc32::checksum_ieee(data),
+            ChecksumType::Crc32c => crc32c::crc32c(data),
+            ChecksumType::Xxh3 => xxhash_rust::xxh3::xxh3_64(data) as u32,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::checksum::ChecksumType;
+
+    #[test]
+    fn test_checksum_type() {
+        let data = b"lasagnedb";
+        for checksum_type in [
+            ChecksumType::Crc32,
+            ChecksumType::Crc32c,
+            ChecksumType::Xxh3,
+        ] {
+            assert_eq!(
+                ChecksumType::from(checksum_type.encode()).unwrap(),
+                checksum_type
+            );
+            assert_eq!(checksum_type.checksum(data), checksum_type.checksum(data));
+            assert_ne!(
+                checksum_type.checksum(data),
+                checksum_type.checksum(b"lasagnedB")
+            );
+        }
+        // crc32c 的标准测试向量
+        assert_eq!(ChecksumType::Crc32c.checksum(b"123456789"), 0xE3069283);
+        assert!(ChecksumType::from(3).is_err());
+    }
+}
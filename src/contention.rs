@@ -0,0 +1,121 @@
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, Instant};
+
+use parking_lot::{Mutex, MutexGuard, RwLock, RwLockReadGuard, RwLockWriteGuard};
+
+use crate::stats::LockWaitStats;
+
+/// 获取锁时等待的次数和总时间
+///
+/// 先尝试不等待地获取，失败时才计时，没有竞争时只多一次 try_lock
+#[derive(Debug, Default)]
+pub(crate) struct LockWaitCounters {
+    contended: AtomicU64,
+    wait_nanos: AtomicU64,
+}
+
+impl LockWaitCounters {
+    pub(crate) const fn new() -> Self {
+        LockWaitCounters {
+            contended: AtomicU64::new(0),
+            wait_nanos: AtomicU64::new(0),
+        }
+    }
+
+    /// `try_acquire` 失败时用 `acquire` 等待，并记录等待的时间
+    pub(crate) fn acquire<G>(
+        &self,
+        try_acquire: impl FnOnce() -> Option<G>,
+        acquire: impl FnOnce() -> G,
+    ) -> G {
+        if let Some(guard) = try_acquire() {
+            return guard;
+        }
+        let start = Instant::now();
+        let guard = acquire();
+        self.contended.fetch_add(1, Ordering::Relaxed);
+        self.wait_nanos
+            .fetch_add(start.elapsed().as_nanos() as u64, Ordering::Relaxed);
+        guard
+    }
+
+    pub(crate) fn lock<'a, T>(&self, mutex: &'a Mutex<T>) -> MutexGuard<'a, T> {
+        self.acquire(|| mutex.try_lock(), || mutex.lock())
+    }
+
+    pub(crate) fn stats(&self) -> LockWaitStats {
+        LockWaitStats {
+            contended: self.contended.load(Ordering::Relaxed),
+            wait: Duration::from_nanos(self.wait_nanos.load(Ordering::Relaxed)),
+        }
+    }
+}
+
+/// 分别记录读锁和写锁等待的 [`RwLock`]，用于 `DbInner` 和 MANIFEST 这类所有读写都要经过的锁
+#[derive(Debug, Default)]
+pub(crate) struct TrackedRwLock<T> {
+    lock: RwLock<T>,
+    read_waits: LockWaitCounters,
+    write_waits: LockWaitCounters,
+}
+
+impl<T> TrackedRwLock<T> {
+    pub(crate) fn new(value: T) -> Self {
+        TrackedRwLock {
+            lock: RwLock::new(value),
+            read_waits: LockWaitCounters::new(),
+            write_waits: LockWaitCounters::new(),
+        }
+    }
+
+    pub(crate) fn read(&self) -> RwLockReadGuard<'_, T> {
+        self.read_waits
+            .acquire(|| self.lock.try_read(), || self.lock.read())
+    }
+
+    pub(crate) fn write(&self) -> RwLockWriteGuard<'_, T> {
+        self.write_waits
+            .acquire(|| self.lock.try_write(), || self.lock.write())
+    }
+
+    /// 获取读锁时的等待
+    pub(crate) fn read_waits(&self) -> LockWaitStats {
+        self.read_waits.stats()
+    }
+
+    /// 获取写锁时的等待
+    pub(crate) fn write_waits(&self) -> LockWaitStats {
+        self.write_waits.stats()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    use crate::contention::TrackedRwLock;
+
+    #[test]
+    fn test_tracked_rw_lock() {
+        let lock = Arc::new(TrackedRwLock::new(0));
+        *lock.write() += 1;
+        assert_eq!(*lock.read(), 1);
+        assert_eq!(lock.read_waits().contended, 0);
+        assert_eq!(lock.write_waits().contended, 0);
+
+        // 持有写锁时读取需要等待
+        let guard = lock.write();
+        let reader = {
+            let lock = lock.clone();
+            std::thread::spawn(move || *lock.read())
+        };
+        std::thread::sleep(Duration::from_millis(50));
+        drop(guard);
+        assert_eq!(reader.join().unwrap(), 1);
+        let waits = lock.read_waits();
+        assert_eq!(waits.contended, 1);
+        assert!(waits.wait >= Duration::from_millis(10));
+        assert_eq!(lock.write_waits().contended, 0);
+    }
+}
@@ -0,0 +1,125 @@
+use std::cell::Cell;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::thread;
+use std::time::Instant;
+
+use tracing::debug;
+
+thread_local! {
+    static YIELD_INTERVAL: Cell<Option<u64>> = const { Cell::new(None) };
+}
+
+static COOPERATIVE_YIELDS: AtomicU64 = AtomicU64::new(0);
+
+/// 长循环为其他线程让出 CPU 的次数，进程内的所有数据库共享该计数
+pub fn cooperative_yields() -> u64 {
+    COOPERATIVE_YIELDS.load(Ordering::Relaxed)
+}
+
+/// 在作用域内，当前线程上的长循环每处理 `interval` 项让出一次 CPU，为 `None` 时不让出，离开作用域时恢复之前的设置
+///
+/// 后台线程按 [`crate::DbOptions::yield_interval`] 进入，线程数少于后台任务数时合并不会长时间独占一个核
+pub(crate) struct YieldScope {
+    prev: Option<u64>,
+}
+
+impl YieldScope {
+    pub(crate) fn enter(interval: Option<u64>) -> Self {
+        Self {
+            prev: YIELD_INTERVAL.with(|i| i.replace(interval.filter(|interval| *interval > 0))),
+        }
+    }
+}
+
+impl Drop for YieldScope {
+    fn drop(&mut self) {
+        YIELD_INTERVAL.with(|i| i.set(self.prev));
+    }
+}
+
+/// 长循环的检查点，每处理一项调用一次 [`LoopCheckpoint::tick`]，按当前线程的 [`YieldScope`] 定期让出 CPU，
+/// 结束时记录循环处理的项数和速度
+pub(crate) struct LoopCheckpoint {
+    name: &'static str,
+    interval: Option<u64>,
+    entries: u64,
+    yields: u64,
+    started_at: Instant,
+}
+
+impl LoopCheckpoint {
+    pub(crate) fn new(name: &'static str) -> Self {
+        Self {
+            name,
+            interval: YIELD_INTERVAL.with(|i| i.get()),
+            entries: 0,
+            yields: 0,
+            started_at: Instant::now(),
+        }
+    }
+
+    #[inline]
+    pub(crate) fn tick(&mut self) {
+        self.entries += 1;
+        if let Some(interval) = self.interval {
+            if self.entries == interval * (self.yields + 1) {
+                self.yields += 1;
+                COOPERATIVE_YIELDS.fetch_add(1, Ordering::Relaxed);
+                thread::yield_now();
+            }
+        }
+    }
+
+    /// 开始以来每秒处理的项数
+    pub(crate) fn entries_per_sec(&self) -> f64 {
+        let elapsed = self.started_at.elapsed().as_secs_f64();
+        if elapsed == 0.0 {
+            return 0.0;
+        }
+        self.entries as f64 / elapsed
+    }
+}
+
+impl Drop for LoopCheckpoint {
+    fn drop(&mut self) {
+        if self.entries > 0 {
+            debug!(
+                "{} loop processed {} entries in {:?} ({:.0} entries/s), yielded {} times",
+                self.name,
+                self.entries,
+                self.started_at.elapsed(),
+                self.entries_per_sec(),
+                self.yields
+            );
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::cooperative::{cooperative_yields, LoopCheckpoint, YieldScope};
+
+    #[test]
+    fn test_loop_checkpoint() {
+        // 不在作用域内时不让出
+        let mut checkpoint = LoopCheckpoint::new("test");
+        (0..100).for_each(|_| checkpoint.tick());
+        assert_eq!(checkpoint.yields, 0);
+
+        {
+            let _scope = YieldScope::enter(Some(10));
+            let _inner = YieldScope::enter(None);
+            let mut checkpoint = LoopCheckpoint::new("test");
+            (0..100).for_each(|_| checkpoint.tick());
+            assert_eq!(checkpoint.yields, 0);
+        }
+        let _scope = YieldScope::enter(Some(10));
+        let yields = cooperative_yields();
+        let mut checkpoint = LoopCheckpoint::new("test");
+        (0..105).for_each(|_| checkpoint.tick());
+        assert_eq!(checkpoint.entries, 105);
+        assert_eq!(checkpoint.yields, 10);
+        assert!(cooperative_yields() >= yields + 10);
+        assert!(checkpoint.entries_per_sec() > 0.0);
+    }
+}
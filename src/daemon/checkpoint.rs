@@ -0,0 +1,59 @@
+use crate::admin::{self, CopyReport};
+use crate::daemon::DbDaemon;
+use crate::meta::manifest::ManifestState;
+use std::path::Path;
+use std::sync::Arc;
+use tracing::{info, instrument};
+
+impl DbDaemon {
+    /// 刷写 memtable 后把当前版本引用的文件复制到 `dir`，见 [`crate::Db::checkpoint`]
+    ///
+    /// 复制期间持有刷写锁，冻结的 WAL 不会被删除，新的刷写等到复制完成后再进行；
+    /// 持有的版本引用着所有要复制的 SST 和 VSST，合并替换掉它们之后文件也不会被删除
+    #[instrument(skip(self))]
+    pub(crate) fn checkpoint(&self, dir: &Path) -> anyhow::Result<CopyReport> {
+        self.freeze_and_flush()?;
+        let _flush = self.flush_lock.lock();
+        // 合并在持有写锁时同时修改各层和 VSST 引用计数，持有读锁时读到的是一致的版本
+        // 复制完成前一直持有版本和其中的 VSST
+        let (_version, _vssts, state) = {
+            let guard = self.inner.read();
+            let inner = Arc::clone(&guard);
+            let vssts: Vec<_> = inner.vssts.read().values().cloned().collect();
+            let mut state = ManifestState {
+                vsst_set: vssts.iter().map(|vsst| vsst.id()).collect(),
+                vsst_rc: inner.vsst_rc.read().clone(),
+                frozen_log_ids: inner.frozen_wal.iter().map(|wal| wal.id()).collect(),
+                wal_segments: inner
+                    .frozen_wal
+                    .iter()
+                    .map(|wal| (wal.id(), wal.num_of_segments() as u32))
+                    .collect(),
+                commit_seq: inner.commit_seq.last_allocated(),
+                stats: self.counters.snapshot(),
+                now_sst_id: inner.sst_id,
+                now_vsst_id: inner.vsst_id,
+                now_log_id: inner.log_id,
+                seq_num: inner.seq_num,
+                ..Default::default()
+            };
+            for (level, tables) in inner.levels.iter().enumerate() {
+                state.sst_map.insert(
+                    level as u32,
+                    tables.iter().map(|table| table.id()).collect(),
+                );
+            }
+            (inner, vssts, state)
+        };
+        let report = admin::copy_state(self.path.as_ref(), dir, &state, false)?;
+        info!(
+            "checkpoint {} SSTs, {} VSSTs, {} WALs ({} bytes) to {:?}",
+            report.ssts.len(),
+            report.vssts.len(),
+            report.wals.len(),
+            report.bytes,
+            dir
+        );
+        Ok(report)
+    }
+}
@@ -1,82 +1,508 @@
-use crate::daemon::DbDaemon;
-use crate::entry::EntryBuilder;
+use crate::cooperative::LoopCheckpoint;
+use crate::daemon::{DbDaemon, ObsoleteFile};
+use crate::db::DbInner;
+use crate::entry::{Entry, EntryBuilder};
+use crate::inspect::Inspection;
 use crate::iterator::merge_iterator::MergeIterator;
-use crate::iterator::StorageIterator;
-use crate::meta::manifest::ManifestItem;
+use crate::iterator::{checked, StorageIterator};
+use crate::meta::manifest::{ManifestActor, ManifestItem, RecordOrigin};
 use crate::record::RecordBuilder;
-use crate::sstable::builder::{SsTable, SsTableBuilder};
-use crate::sstable::iterator::{SsTableIterator, VSsTableIterator};
+use crate::sstable::builder::{
+    BlockReadOptions, FilterLoading, KeyOrderCheck, SsTable, SsTableBuilder,
+};
+use crate::sstable::compression::CompressionType;
+use crate::sstable::iterator::{MissingVSsts, SsTableIterator, VSsTableIterator};
+use crate::stats::{CompactionSummary, KeyPrefixStats};
+use crate::storage::file::IoPriorityScope;
+use crate::storage::header::FileType;
 use crate::{
-    Db, OpType, MAX_LEVEL_SIZE, MAX_SST_SIZE, MAX_VSST_SPARE_RATIO, MIN_VSST_SIZE, SST_LEVEL_LIMIT,
+    Db, OpType, BLOCK_SIZE, MAX_COMPACTION_MIGRATION_SIZE, MAX_LEVEL_SIZE, MAX_SST_SIZE,
+    MAX_VSST_SPARE_RATIO, SEEK_MISS_COMPACTION_THRESHOLD, SST_LEVEL_LIMIT, VSST_BLOCK_SIZE,
 };
-use bytes::{Buf, BufMut, Bytes, BytesMut};
+use anyhow::anyhow;
+use bytes::Bytes;
 use std::collections::{HashMap, HashSet};
 use std::fmt::Debug;
 use std::path::Path;
 use std::ptr::read;
 
-use crate::cache::BlockCache;
+use crate::cache::{BlockCache, CacheFillPolicy};
 use crate::iterator::rc_merge_iterator::RcMergeIterator;
-use parking_lot::RwLock;
+use parking_lot::{RwLock, RwLockWriteGuard};
 use std::sync::atomic::Ordering;
 use std::sync::Arc;
-use tracing::{info, instrument, span, warn};
+use std::time::{Duration, Instant, SystemTime};
+use tracing::{debug, info, instrument, span, warn};
+
+/// 一次合并输出的 SST、VSST 和 VSST 引用计数的变化，见 [`DbDaemon::merge`]
+pub(crate) struct CompactionOutput {
+    pub(crate) new_ssts: Vec<Arc<SsTable>>,
+    pub(crate) new_vssts: Vec<Arc<SsTable>>,
+    pub(crate) vsst_rc_delta: Arc<HashMap<u64, i32>>,
+    /// 合并开始的时间，用于计算 [`CompactionSummary::duration`]
+    pub(crate) started_at: Instant,
+}
+
+/// [`DbDaemon::merge`] 的参数，[`MergeParams::new`] 给出不迁移、不读回 value、不填充缓存、不压缩的默认值，
+/// 调用方按需要覆盖其中的字段
+#[derive(Debug)]
+pub(crate) struct MergeParams<'a> {
+    /// 输出文件所在的目录
+    pub(crate) path: &'a Path,
+    /// 输出的 SST 从 `now_sst_id + 1` 开始编号
+    pub(crate) now_sst_id: u64,
+    pub(crate) sst_cache: Arc<BlockCache>,
+    /// 迁移 value 输出的 VSST 从 `now_vsst_id + 1` 开始编号
+    pub(crate) now_vsst_id: u64,
+    pub(crate) vssts: Arc<RwLock<HashMap<u64, Arc<SsTable>>>>,
+    pub(crate) vsst_cache: Arc<BlockCache>,
+    pub(crate) vsst_rc: Arc<RwLock<HashMap<u64, u32>>>,
+    /// 已丢失的 VSST，引用它们的分离项改写为删除标记
+    pub(crate) missing_vssts: Arc<MissingVSsts>,
+    pub(crate) filter_loading: FilterLoading,
+    /// 从空洞率过高的 VSST 中迁移的 value 总字节数的上限，为 0 时不迁移
+    pub(crate) max_migration_size: u64,
+    /// 长度不超过该值的已分离 value 读回 SST，为 0 时不读回
+    pub(crate) inline_threshold: u64,
+    pub(crate) cache_fill: CacheFillPolicy,
+    /// 输出 SST 的 bloom filter 每个 key 使用的位数
+    pub(crate) filter_bits_per_key: Option<u32>,
+    /// 输出 SST 和 VSST 的 bloom filter 种子，见 [`SsTableBuilder::with_filter_seed`]
+    pub(crate) filter_seed: Option<[u8; 32]>,
+    pub(crate) key_order_check: KeyOrderCheck,
+    /// 输出 SST 和 VSST 的压缩方式
+    pub(crate) compression: CompressionType,
+    /// zstd 字典大小，为 0 时不训练字典
+    pub(crate) dict_size: usize,
+    /// 输出 SST 的 data block 大小
+    pub(crate) block_size: usize,
+    /// 输出 VSST 的 data block 大小
+    pub(crate) vsst_block_size: usize,
+}
+
+impl<'a> MergeParams<'a> {
+    pub(crate) fn new(path: &'a Path, cache: Arc<BlockCache>) -> Self {
+        MergeParams {
+            path,
+            now_sst_id: 0,
+            sst_cache: cache.clone(),
+            now_vsst_id: 0,
+            vssts: Arc::new(RwLock::new(HashMap::new())),
+            vsst_cache: cache,
+            vsst_rc: Arc::new(RwLock::new(HashMap::new())),
+            missing_vssts: Arc::default(),
+            filter_loading: FilterLoading::Disabled,
+            max_migration_size: 0,
+            inline_threshold: 0,
+            cache_fill: CacheFillPolicy::None,
+            filter_bits_per_key: None,
+            filter_seed: None,
+            key_order_check: KeyOrderCheck::Always,
+            compression: CompressionType::None,
+            dict_size: 0,
+            block_size: BLOCK_SIZE,
+            vsst_block_size: VSST_BLOCK_SIZE,
+        }
+    }
+}
+
+/// Li 和 Li+1 中参与合并的 SST，见 [`DbDaemon::select_inputs`]
+pub(crate) type LevelInputs = (Vec<Arc<SsTable>>, Vec<Arc<SsTable>>);
+
+/// 合并计划，描述一次合并会选中哪些文件以及预计的数据量，但并不实际执行
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CompactionPlan {
+    /// 被合并的层
+    pub level: u32,
+    /// 合并结果写入的层
+    pub output_level: u32,
+    /// `level` 层参与合并的 SST id
+    pub level_inputs: Vec<u64>,
+    /// `output_level` 层参与合并的 SST id
+    pub output_level_inputs: Vec<u64>,
+    /// `level` 与 `output_level` 之间各层参与合并的 SST id (level, sst ids)，合并到下一层时为空
+    pub intermediate_inputs: Vec<(u32, Vec<u64>)>,
+    /// 输入文件总字节数
+    pub input_bytes: u64,
+    /// 预计输出字节数，不考虑覆盖写和删除，是一个上限
+    pub estimated_output_bytes: u64,
+    /// 预计输出的 SST 数量
+    pub estimated_output_files: usize,
+}
+
+/// 各层参与合并的 SST (level, ssts)，从浅到深排列，第一项为被合并的层，最后一项为输出层
+pub(crate) type CompactionInputs = Vec<(u32, Vec<Arc<SsTable>>)>;
+
+impl CompactionPlan {
+    fn new(inputs: &CompactionInputs) -> Self {
+        let ids = |ssts: &[Arc<SsTable>]| ssts.iter().map(|_sst| _sst.id()).collect::<Vec<_>>();
+        let input_bytes: u64 = inputs
+            .iter()
+            .flat_map(|(_, ssts)| ssts)
+            .map(|_sst| _sst.size())
+            .sum();
+        let estimated_output_files = input_bytes.div_ceil(MAX_SST_SIZE).max(1);
+        let (level, level_inputs) = inputs.first().unwrap();
+        let (output_level, output_level_inputs) = inputs.last().unwrap();
+        Self {
+            level: *level,
+            output_level: *output_level,
+            level_inputs: ids(level_inputs),
+            output_level_inputs: ids(output_level_inputs),
+            intermediate_inputs: inputs[1..inputs.len() - 1]
+                .iter()
+                .map(|(_level, ssts)| (*_level, ids(ssts)))
+                .collect(),
+            input_bytes,
+            estimated_output_bytes: input_bytes,
+            estimated_output_files: estimated_output_files as usize,
+        }
+    }
+}
 
 impl DbDaemon {
     #[instrument]
     pub fn compaction(&self, level: u32) -> anyhow::Result<()> {
-        self.compaction_count.fetch_add(1, Ordering::Release);
-        if level == SST_LEVEL_LIMIT {
-            return Ok(());
+        // 最后一层无法再向下合并，超出 MAX_LEVEL_SIZE 后继续增长，见 `check_bottom_level`。
+        // 对它的合并请求表示按 `cache_capacity` 淘汰数据，见 `eviction`
+        if level + 1 >= SST_LEVEL_LIMIT {
+            return self.evict();
         }
+        self.compaction_to(level, level + 1)
+    }
+
+    /// 合并 `level` 层的一组 SST，结果直接写入 `output_level` 层，两层之间与之重叠的 SST 一起参与合并
+    #[instrument]
+    pub(crate) fn compaction_to(&self, level: u32, output_level: u32) -> anyhow::Result<()> {
+        self.compaction_count.fetch_add(1, Ordering::Release);
+        let _priority = IoPriorityScope::enter(self.options.compaction_io_priority);
+        let started_at = Instant::now();
 
         let mut guard = self.inner.write();
-        let mut snapshot = guard.as_ref().clone();
+        let snapshot = guard.clone();
 
-        // 选择基准SST
-        let _base_sst = Self::pick_base_sst(&snapshot.levels, level);
-        if _base_sst.is_none() {
-            println!("l0 sst is empty");
-            return Ok(());
+        // 选择参与合并的 SST
+        let inputs = match Self::select_inputs_to(
+            &snapshot.levels,
+            level,
+            output_level,
+            self.options.max_compaction_bytes,
+        ) {
+            None => {
+                info!("L{} has nothing to compact", level);
+                return Ok(());
+            }
+            Some(inputs) => inputs,
+        };
+
+        self.merge_and_install(&mut guard, inputs, output_level, started_at)
+    }
+
+    /// 合并 `inputs` 并把输出安装到 `output_level` 层，调用方从选择输入开始一直持有写锁
+    fn merge_and_install(
+        &self,
+        guard: &mut RwLockWriteGuard<'_, Arc<DbInner>>,
+        inputs: CompactionInputs,
+        output_level: u32,
+        started_at: Instant,
+    ) -> anyhow::Result<()> {
+        let snapshot = guard.as_ref().clone();
+        // 浅层的数据较新，排在前面
+        let ssts = inputs
+            .iter()
+            .flat_map(|(_, ssts)| ssts.iter().cloned())
+            .collect();
+
+        // 合并
+        let mut inspection = self.value_inspectors.start();
+        let (new_ssts, new_vssts, vsst_rc_delta, key_prefixes) = Self::merge(
+            MergeParams {
+                max_migration_size: MAX_COMPACTION_MIGRATION_SIZE,
+                inline_threshold: self.value_separation_threshold.load(Ordering::Relaxed),
+                cache_fill: self.options.compaction_cache_fill,
+                ..self.merge_params(&snapshot, output_level)
+            },
+            ssts,
+            &mut inspection,
+        )?;
+        self.key_prefixes.lock().merge(&key_prefixes);
+        self.value_inspectors.finish(inspection);
+        self.install_compaction(
+            guard,
+            inputs,
+            output_level,
+            CompactionOutput {
+                new_ssts,
+                new_vssts,
+                vsst_rc_delta,
+                started_at,
+            },
+        )
+    }
+
+    /// 重写文件写出超过 `max_age` 的 SST，返回执行的合并次数，见 [`crate::DbOptions::periodic_compaction`]
+    ///
+    /// 开始时记下已过期的 SST，每次选出其中仍未被合并掉的最旧的一个：L0 到倒数第二层的 SST 作为基准 SST，
+    /// 与重叠的 SST 一起合并到下一层；最后一层的 SST 在原层单独重写。输出是新文件，不在本次重写的范围内，
+    /// 直到记下的 SST 都被合并掉，或自动合并被暂停
+    #[instrument(skip(self))]
+    pub(crate) fn periodic_compaction(&self, max_age: Duration) -> anyhow::Result<usize> {
+        let _priority = IoPriorityScope::enter(self.options.compaction_io_priority);
+        let expired = Self::expired_ssts(&self.inner.read().levels, max_age);
+        let mut compactions = 0;
+        while !self.compactions_paused() {
+            let started_at = Instant::now();
+            let mut guard = self.inner.write();
+            let Some((level, base_sst)) = Self::pick_expired_sst(&guard.levels, &expired) else {
+                break;
+            };
+            info!(
+                "periodic compaction of L{} {}.SST older than {:?}",
+                level,
+                base_sst.id(),
+                max_age
+            );
+            let (inputs, output_level) = if level + 1 < SST_LEVEL_LIMIT {
+                let (mut li_sst, li1_sst) = Self::select_overlap_sst(
+                    &guard.levels,
+                    level,
+                    base_sst,
+                    self.options.max_compaction_bytes,
+                );
+                li_sst.sort_by_key(|_sst| std::cmp::Reverse(_sst.recency()));
+                (vec![(level, li_sst), (level + 1, li1_sst)], level + 1)
+            } else {
+                (vec![(level, vec![base_sst])], level)
+            };
+            self.compaction_count.fetch_add(1, Ordering::Release);
+            self.merge_and_install(&mut guard, inputs, output_level, started_at)?;
+            compactions += 1;
         }
-        let base_sst = _base_sst.unwrap();
-        // 获取有重叠key范围的SST
-        let (li_sst, li1_sst) = Self::select_overlap_sst(&snapshot.levels, 0, base_sst);
+        Ok(compactions)
+    }
 
-        let mut ssts = vec![];
-        for _sst in &li_sst {
-            ssts.push(_sst.clone());
+    /// 文件写出超过 `max_age` 的 SST 的 id 和年龄
+    pub(crate) fn expired_ssts(
+        levels: &[Vec<Arc<SsTable>>],
+        max_age: Duration,
+    ) -> HashMap<u64, Duration> {
+        levels
+            .iter()
+            .flatten()
+            .filter_map(|_sst| Some((_sst.id(), _sst.age()?)))
+            .filter(|(_, age)| *age >= max_age)
+            .collect()
+    }
+
+    /// `expired` 中仍在各层中的最旧的 SST (level, sst)，没有时返回 `None`
+    pub(crate) fn pick_expired_sst(
+        levels: &[Vec<Arc<SsTable>>],
+        expired: &HashMap<u64, Duration>,
+    ) -> Option<(u32, Arc<SsTable>)> {
+        levels
+            .iter()
+            .enumerate()
+            .flat_map(|(level, ssts)| ssts.iter().map(move |_sst| (level as u32, _sst)))
+            .filter_map(|(level, _sst)| Some((level, _sst, expired.get(&_sst.id())?)))
+            .max_by_key(|(_, _, age)| **age)
+            .map(|(level, _sst, _)| (level, _sst.clone()))
+    }
+
+    /// 逐个重写引用了缺失 VSST 的 SST，引用改写为删除标记，输出留在原来的层，返回重写的 SST 数量，
+    /// 见 [`crate::MissingVSstPolicy::Repair`]。重写时不读回也不迁移其它 value
+    #[instrument(skip_all)]
+    pub(crate) fn repair_dangling_refs(&self) -> anyhow::Result<usize> {
+        let snapshot = self.inner.read().clone();
+        if snapshot.missing_vssts.is_empty() {
+            return Ok(0);
+        }
+        let mut targets = vec![];
+        for (level, ssts) in snapshot.levels.iter().enumerate() {
+            for _sst in ssts {
+                if _sst.num_of_blocks() == 0 {
+                    continue;
+                }
+                let mut iter = SsTableIterator::create_and_seek_to_first(_sst.clone())?;
+                while iter.is_valid() {
+                    if Entry::is_separate(iter.meta())
+                        && snapshot
+                            .missing_vssts
+                            .contains(Entry::separated_vsst_id(iter.value()))
+                    {
+                        targets.push((level as u32, _sst.clone()));
+                        break;
+                    }
+                    iter.next()?;
+                }
+            }
         }
-        for _sst in &li1_sst {
-            ssts.push(_sst.clone());
+
+        let mut repaired = 0;
+        for (level, _sst) in targets {
+            let started_at = Instant::now();
+            let mut guard = self.inner.write();
+            let snapshot = guard.clone();
+            // 期间被合并掉的 SST 已由那次合并清理
+            if !snapshot.levels[level as usize]
+                .iter()
+                .any(|other| other.id() == _sst.id())
+            {
+                continue;
+            }
+            info!(
+                "repair L{} {}.SST referencing missing VSSTs",
+                level,
+                _sst.id()
+            );
+            let (new_ssts, new_vssts, vsst_rc_delta, _) = Self::merge(
+                self.merge_params(&snapshot, level),
+                vec![_sst.clone()],
+                &mut Inspection::disabled(),
+            )?;
+            self.install_compaction(
+                &mut guard,
+                vec![(level, vec![_sst])],
+                level,
+                CompactionOutput {
+                    new_ssts,
+                    new_vssts,
+                    vsst_rc_delta,
+                    started_at,
+                },
+            )?;
+            repaired += 1;
+        }
+        Ok(repaired)
+    }
+
+    /// 用合并的输出替换各层的输入，输出写入 `output_level` 层，在同一条 MANIFEST 记录中写入所有变更和合并的概要后发布新的 `DbInner`
+    ///
+    /// 调用方需要在选择输入到安装输出期间一直持有写锁，保证输入仍在原来的层中
+    pub(crate) fn install_compaction(
+        &self,
+        guard: &mut RwLockWriteGuard<'_, Arc<DbInner>>,
+        inputs: CompactionInputs,
+        output_level: u32,
+        output: CompactionOutput,
+    ) -> anyhow::Result<()> {
+        let CompactionOutput {
+            new_ssts,
+            new_vssts,
+            vsst_rc_delta,
+            started_at,
+        } = output;
+        // 严格模式下输出先落盘，之后写入的 MANIFEST 记录才能引用它们并删除输入
+        if self.options.strict_manifest_sync {
+            for _sst in new_ssts.iter().chain(&new_vssts) {
+                _sst.sync_all()?;
+            }
         }
+        let mut snapshot = guard.as_ref().clone();
         let mut sst_ids = HashSet::new();
-        for _sst in &ssts {
+        for _sst in inputs.iter().flat_map(|(_, ssts)| ssts) {
             sst_ids.insert(_sst.id());
         }
+        let bytes_read: u64 = inputs
+            .iter()
+            .flat_map(|(_, ssts)| ssts)
+            .map(|_sst| _sst.size())
+            .sum();
 
-        // 合并
-        let (new_ssts, new_vssts, vsst_rc_delta) = Self::merge(
-            &self.path.as_path(),
-            snapshot.sst_id,
-            ssts,
-            self.sst_cache.clone(),
-            snapshot.vsst_id,
-            snapshot.vssts.clone(),
-            self.vsst_cache.clone(),
-            snapshot.vsst_rc.clone(),
-        )?;
         let mut r = RecordBuilder::new();
+        r.add(ManifestItem::Origin(RecordOrigin::now(
+            ManifestActor::Compaction,
+        )));
+        let bytes_written: u64 = new_ssts
+            .iter()
+            .chain(&new_vssts)
+            .map(|_sst| _sst.size())
+            .sum();
+        self.counters.on_compaction(bytes_read, bytes_written);
+        let input_entries: u64 = inputs
+            .iter()
+            .flat_map(|(_, ssts)| ssts)
+            .map(|_sst| _sst.num_of_pairs() as u64)
+            .sum();
+        let output_entries: u64 = new_ssts.iter().map(|_sst| _sst.num_of_pairs() as u64).sum();
+        let mut summary = CompactionSummary {
+            level: inputs.first().map_or(output_level, |(_level, _)| *_level),
+            output_level,
+            input_files: sst_ids.len() as u32,
+            output_files: (new_ssts.len() + new_vssts.len()) as u32,
+            input_bytes: bytes_read,
+            output_bytes: bytes_written,
+            input_entries,
+            dropped_entries: input_entries.saturating_sub(output_entries),
+            duration: Duration::ZERO,
+            finished_at: SystemTime::UNIX_EPOCH,
+        };
 
         // 添加新SST和清理过期SST
-        snapshot.levels[level as usize].retain(|_sst| !sst_ids.contains(&_sst.id()));
-        snapshot.levels[(level + 1) as usize].retain(|_sst| !sst_ids.contains(&_sst.id()));
-        snapshot.levels[(level + 1) as usize].extend(new_ssts);
+        for (_level, _) in &inputs {
+            snapshot.levels[*_level as usize].retain(|_sst| !sst_ids.contains(&_sst.id()));
+        }
+        // 推进文件 ID，保证之后刷写的 SST id 一定更大（L0 中最大 seq num 相同时依赖 id 判断新旧）
+        if let Some(max_sst_id) = new_ssts.iter().map(|_sst| _sst.id()).max() {
+            snapshot.sst_id = snapshot.sst_id.max(max_sst_id);
+        }
+        if let Some(max_vsst_id) = new_vssts.iter().map(|_vsst| _vsst.id()).max() {
+            snapshot.vsst_id = snapshot.vsst_id.max(max_vsst_id);
+        }
+        for _sst in &new_ssts {
+            info!("NEW L{} {}.SST", output_level, _sst.id());
+            r.add(ManifestItem::NewSst(output_level, _sst.id()));
+        }
+        snapshot.levels[output_level as usize].extend(new_ssts);
         for _vsst in new_vssts {
+            info!("NEW {}.VSST", _vsst.id());
+            r.add(ManifestItem::NewVSst(_vsst.id()));
             snapshot.vssts.write().insert(_vsst.id(), _vsst.clone());
         }
-        // 处理 VSST 引用计数
-        for (_vsst_id, _delta) in vsst_rc_delta.as_ref() {
+        // 处理 VSST 引用计数，文件在元数据写入后交给后台线程删除
+        let mut obsolete_files = vec![];
+        self.apply_vsst_rc_delta(&mut snapshot, &vsst_rc_delta, &mut r, &mut obsolete_files);
+
+        // 更新元数据，新增 / 删除 SST 和 VSST 引用计数的变更在同一条记录中，崩溃后不会出现不一致的引用计数
+        for (_level, _ssts) in inputs {
+            for _sst in _ssts {
+                info!("DEL L{} {}.SST", _level, _sst.id());
+                r.add(ManifestItem::DelSst(_level, _sst.id()));
+                r.add(ManifestItem::PendingDelete(FileType::Sst, _sst.id()));
+                obsolete_files.push(ObsoleteFile::new(FileType::Sst, _sst.id(), Some(_sst)));
+            }
+        }
+        summary.duration = started_at.elapsed();
+        summary.finished_at = SystemTime::now();
+        r.add(ManifestItem::CompactionSummary(summary));
+        r.add(ManifestItem::Stats(self.counters.snapshot()));
+        {
+            let mut manifest = self.manifest.write();
+            manifest.add(&r.build())?;
+        }
+        info!("compaction finished: {:?}", summary);
+        self.schedule_delete(obsolete_files);
+
+        // 按合并后的各层分数决定下一次合并
+        self.schedule_compaction(&snapshot.levels);
+        self.schedule_eviction(&snapshot);
+        self.check_bottom_level(&snapshot.levels);
+        **guard = Arc::new(snapshot);
+
+        Ok(())
+    }
+
+    /// 把 VSST 引用计数的变化应用到 `snapshot` 并记入 `r`，引用计数降为 0 的 VSST 放入 `obsolete_files`
+    pub(crate) fn apply_vsst_rc_delta(
+        &self,
+        snapshot: &mut DbInner,
+        vsst_rc_delta: &HashMap<u64, i32>,
+        r: &mut RecordBuilder<ManifestItem>,
+        obsolete_files: &mut Vec<ObsoleteFile>,
+    ) {
+        // 按 id 顺序处理，同样的合并写出同样的 MANIFEST 记录
+        let mut vsst_rc_delta: Vec<_> = vsst_rc_delta.iter().collect();
+        vsst_rc_delta.sort_unstable();
+        for (_vsst_id, _delta) in vsst_rc_delta {
             let old_rc = snapshot.vsst_rc.read().get(&_vsst_id).unwrap_or(&0).clone();
             let new_rc = old_rc as i32 + _delta;
             if new_rc <= 0 {
@@ -84,14 +510,18 @@ impl DbDaemon {
                 let _enter = _span.enter();
 
                 info!("DEL {}.VSST", _vsst_id);
-                {
-                    let reader = snapshot.vssts.read();
-                    match reader.get(_vsst_id) {
-                        Some(_delete_vsst) => _delete_vsst.delete()?,
-                        None => warn!("{}.VSST not existed", _vsst_id),
+                match snapshot.vssts.write().remove(_vsst_id) {
+                    Some(_delete_vsst) => {
+                        self.counters.on_gc(_delete_vsst.size());
+                        obsolete_files.push(ObsoleteFile::new(
+                            FileType::VSst,
+                            *_vsst_id,
+                            Some(_delete_vsst),
+                        ));
+                        r.add(ManifestItem::PendingDelete(FileType::VSst, *_vsst_id));
                     }
+                    None => warn!("{}.VSST not existed", _vsst_id),
                 }
-                snapshot.vssts.write().remove(_vsst_id);
                 snapshot.vsst_rc.write().remove(_vsst_id);
                 r.add(ManifestItem::VSstRefCnt(*_vsst_id, 0));
                 r.add(ManifestItem::DelVSst(*_vsst_id));
@@ -100,45 +530,189 @@ impl DbDaemon {
                 r.add(ManifestItem::VSstRefCnt(*_vsst_id, new_rc as u32));
             }
         }
+    }
 
-        // 更新元数据
-        for _sst in li_sst {
-            info!("DEL L{} {}.SST", level, _sst.id());
-            r.add(ManifestItem::DelSst(level, _sst.id()));
-            _sst.delete()?;
+    /// 计算各层的合并分数，分数大于 1 表示该层超出了限制
+    ///
+    /// L0 为 SST 数量与 `l0_sst_num_limit` 之比，其余层为总大小与 [`MAX_LEVEL_SIZE`] 之比，
+    /// 最后一层无法再向下合并，分数总是 0
+    pub(crate) fn level_scores(levels: &[Vec<Arc<SsTable>>], l0_sst_num_limit: usize) -> Vec<f64> {
+        (0..SST_LEVEL_LIMIT as usize)
+            .map(|level| {
+                if level + 1 >= SST_LEVEL_LIMIT as usize {
+                    return 0.0;
+                }
+                if level == 0 {
+                    return levels[0].len() as f64 / l0_sst_num_limit as f64;
+                }
+                let size: u64 = levels[level].iter().map(|_sst| _sst.size()).sum();
+                size as f64 / MAX_LEVEL_SIZE[level] as f64
+            })
+            .collect()
+    }
+
+    /// `level` 层的总大小超出 [`MAX_LEVEL_SIZE`] 的字节数，未超出时为 0
+    pub(crate) fn level_overflow(levels: &[Vec<Arc<SsTable>>], level: usize) -> u64 {
+        let size: u64 = levels[level].iter().map(|_sst| _sst.size()).sum();
+        size.saturating_sub(MAX_LEVEL_SIZE[level])
+    }
+
+    /// L1 到倒数第二层中各层超出 [`MAX_LEVEL_SIZE`] 的字节数之和，即合并还需要搬走的数据量
+    pub(crate) fn compaction_debt(levels: &[Vec<Arc<SsTable>>]) -> u64 {
+        (1..SST_LEVEL_LIMIT as usize - 1)
+            .map(|level| Self::level_overflow(levels, level))
+            .sum()
+    }
+
+    /// 最后一层超出 [`MAX_LEVEL_SIZE`] 的字节数
+    ///
+    /// 最后一层没有可以继续合并的下一层，超出限制后仍接收合并的输出，读写不受影响，
+    /// 只是数据量已超过层级设计的容量，见 [`crate::DbStats::bottom_level_overflow`]
+    pub(crate) fn bottom_level_overflow(levels: &[Vec<Arc<SsTable>>]) -> u64 {
+        Self::level_overflow(levels, SST_LEVEL_LIMIT as usize - 1)
+    }
+
+    /// 最后一层开始超出或回到 [`MAX_LEVEL_SIZE`] 以内时记录日志
+    fn check_bottom_level(&self, levels: &[Vec<Arc<SsTable>>]) {
+        let overflow = Self::bottom_level_overflow(levels);
+        let overflowed = overflow > 0;
+        if self
+            .bottom_level_overflowed
+            .swap(overflowed, Ordering::Relaxed)
+            == overflowed
+        {
+            return;
         }
-        for _sst in li1_sst {
-            info!("DEL L{} {}.SST", level, _sst.id());
-            r.add(ManifestItem::DelSst(level + 1, _sst.id()));
-            _sst.delete()?;
+        if overflowed {
+            warn!(
+                "L{} exceeds its size limit {} by {} bytes, it will keep growing",
+                SST_LEVEL_LIMIT - 1,
+                MAX_LEVEL_SIZE[SST_LEVEL_LIMIT as usize - 1],
+                overflow
+            );
+        } else {
+            info!("L{} is back within its size limit", SST_LEVEL_LIMIT - 1);
         }
-        {
-            let mut manifest = self.manifest.write();
-            manifest.add(&r.build());
+    }
+
+    /// 返回分数最高且大于 1 的层，分数相同时取较浅的层，没有需要合并的层时返回 `None`
+    pub(crate) fn pick_compaction_level(
+        levels: &[Vec<Arc<SsTable>>],
+        l0_sst_num_limit: usize,
+    ) -> Option<u32> {
+        let scores = Self::level_scores(levels, l0_sst_num_limit);
+        debug!("level scores: {:?}", scores);
+        let (level, score) =
+            scores
+                .into_iter()
+                .enumerate()
+                .fold(
+                    (0, 0.0),
+                    |max, (level, score)| {
+                        if score > max.1 {
+                            (level, score)
+                        } else {
+                            max
+                        }
+                    },
+                );
+        (score > 1.0).then_some(level as u32)
+    }
+
+    /// flush 或合并完成后调用，请求合并分数最高的层
+    pub(crate) fn schedule_compaction(&self, levels: &[Vec<Arc<SsTable>>]) {
+        if let Some(level) = Self::pick_compaction_level(levels, self.options.l0_sst_num_limit) {
+            self.request_compaction(level);
         }
+    }
 
-        // 检查是否需要触发新的合并
-        let mut leveli1_size = 0;
-        snapshot.levels[(level + 1) as usize]
-            .iter()
-            .for_each(|_sst| leveli1_size += _sst.size());
-        *guard = Arc::new(snapshot);
+    /// 只执行选择文件的逻辑，返回合并计划
+    pub(crate) fn plan_compaction(
+        levels: &Vec<Vec<Arc<SsTable>>>,
+        level: u32,
+        output_level: u32,
+        max_bytes: Option<u64>,
+    ) -> Option<CompactionPlan> {
+        let inputs = Self::select_inputs_to(levels, level, output_level, max_bytes)?;
+        Some(CompactionPlan::new(&inputs))
+    }
 
-        if leveli1_size > MAX_LEVEL_SIZE[(level + 1) as usize] {
-            if let Err(e) = self.compaction_chan.0.try_send(level + 1) {
-                warn!("send compaction message failed {}", e);
+    /// 选择从 `level` 层合并到 `output_level` 层的输入
+    ///
+    /// 先选择 Li 和 Li+1 的输入，再依次选择之后各层与已选输入的 key 范围重叠的 SST，
+    /// 保证合并后较深层中不会留下比输出更新的数据。`output_level` 不在 `level` 之后或 Li 为空时返回 `None`
+    pub(crate) fn select_inputs_to(
+        levels: &Vec<Vec<Arc<SsTable>>>,
+        level: u32,
+        output_level: u32,
+        max_bytes: Option<u64>,
+    ) -> Option<CompactionInputs> {
+        if output_level <= level || output_level >= SST_LEVEL_LIMIT {
+            return None;
+        }
+        let (li_sst, li1_sst) = Self::select_inputs(levels, level, max_bytes)?;
+        let (mut min_key, mut max_key) = li_sst
+            .iter()
+            .chain(&li1_sst)
+            .map(|_sst| _sst.key_range())
+            .reduce(|(min_a, max_a), (min_b, max_b)| (min_a.min(min_b), max_a.max(max_b)))
+            .unwrap();
+        let mut inputs = vec![(level, li_sst), (level + 1, li1_sst)];
+        for _level in level + 2..=output_level {
+            let mut overlapped = vec![];
+            for _sst in &levels[_level as usize] {
+                let (_min_key, _max_key) = _sst.key_range();
+                if min_key <= _max_key && _min_key <= max_key {
+                    overlapped.push(_sst.clone());
+                }
+            }
+            // 同一层的 SST 互不重叠，选完一层后再扩大范围
+            for _sst in &overlapped {
+                let (_min_key, _max_key) = _sst.key_range();
+                min_key = min_key.min(_min_key);
+                max_key = max_key.max(_max_key);
             }
+            inputs.push((_level, overlapped));
         }
+        Some(inputs)
+    }
 
-        Ok(())
+    /// 选择 Li 和 Li+1 中参与合并的 SST，最后一层或 Li 为空时返回 `None`
+    ///
+    /// 设置了 `max_bytes` 时，L1 及以下只有不超过该字节数时才继续加入 Li 中与输入范围重叠的其他 SST
+    pub(crate) fn select_inputs(
+        levels: &Vec<Vec<Arc<SsTable>>>,
+        level: u32,
+        max_bytes: Option<u64>,
+    ) -> Option<LevelInputs> {
+        if level + 1 >= SST_LEVEL_LIMIT {
+            return None;
+        }
+        // 选择基准SST
+        let base_sst = Self::pick_base_sst(levels, level)?;
+        // 获取有重叠key范围的SST
+        let (mut li_sst, li1_sst) = Self::select_overlap_sst(levels, level, base_sst, max_bytes);
+        // 合并时 key 相同优先取靠前的输入，所以 Li 按新到旧排列，且整体排在 Li+1 之前
+        li_sst.sort_by_key(|_sst| std::cmp::Reverse(_sst.recency()));
+        Some((li_sst, li1_sst))
     }
 
     pub(crate) fn pick_base_sst(
         levels: &Vec<Vec<Arc<SsTable>>>,
         level: u32,
     ) -> Option<Arc<SsTable>> {
+        // 优先选择点查未命中次数达到阈值的 SST，其余情况选第一个
         // TODO 更好的挑选方法
-        levels[level as usize].get(0).cloned()
+        let ssts = &levels[level as usize];
+        if SEEK_MISS_COMPACTION_THRESHOLD > 0 {
+            let most_missed = ssts.iter().max_by_key(|_sst| _sst.seek_misses());
+            if let Some(_sst) = most_missed {
+                if _sst.seek_misses() >= SEEK_MISS_COMPACTION_THRESHOLD {
+                    return Some(_sst.clone());
+                }
+            }
+        }
+        ssts.get(0).cloned()
     }
 
     #[instrument]
@@ -146,6 +720,7 @@ impl DbDaemon {
         levels: &Vec<Vec<Arc<SsTable>>>,
         level: u32,
         base_sst: Arc<SsTable>,
+        max_bytes: Option<u64>,
     ) -> (Vec<Arc<SsTable>>, Vec<Arc<SsTable>>) {
         let (mut min_key, mut max_key) = base_sst.key_range();
         let mut li_sst_id = HashSet::new();
@@ -180,10 +755,22 @@ impl DbDaemon {
                 }
             }
         }
-        // 再反过来选Li重叠的
+        // 再反过来选Li重叠的，L0 的 SST 互相重叠，必须全部加入
+        let size_of = |level: u32, ids: &HashSet<u64>| -> u64 {
+            levels[level as usize]
+                .iter()
+                .filter(|_sst| ids.contains(&_sst.id()))
+                .map(|_sst| _sst.size())
+                .sum()
+        };
+        let mut total_bytes = size_of(level, &li_sst_id) + size_of(level + 1, &li1_sst_id);
         for _sst in &levels[level as usize] {
             let (_min_key, _max_key) = _sst.key_range();
             if min_key <= _max_key && _min_key <= max_key && !li_sst_id.contains(&_sst.id()) {
+                if level > 0 && max_bytes.is_some_and(|max| total_bytes + _sst.size() > max) {
+                    continue;
+                }
+                total_bytes += _sst.size();
                 li_sst_id.insert(_sst.id());
                 if _min_key < min_key {
                     min_key = _min_key;
@@ -208,85 +795,269 @@ impl DbDaemon {
         (li_sst, li1_sst)
     }
 
+    /// compaction 输出的 SST 按配置压缩，启用 zstd 时用本次合并的 KV 采样训练字典
+    fn new_sst_builder(
+        compression: CompressionType,
+        dict_size: usize,
+        block_size: usize,
+    ) -> SsTableBuilder {
+        SsTableBuilder::with_compression(compression, dict_size).with_block_size(block_size)
+    }
+
+    /// VSST 使用较小的 data block，见 [`crate::DbOptions::vsst_block_size`]
+    fn new_vsst_builder(
+        compression: CompressionType,
+        dict_size: usize,
+        block_size: usize,
+    ) -> SsTableBuilder {
+        Self::new_sst_builder(compression, dict_size, block_size).with_file_type(FileType::VSst)
+    }
+
+    /// 将 key 范围按起始 key 排序并合并重叠部分
+    pub(crate) fn merge_key_ranges(mut ranges: Vec<(Bytes, Bytes)>) -> Vec<(Bytes, Bytes)> {
+        ranges.sort();
+        let mut merged: Vec<(Bytes, Bytes)> = Vec::with_capacity(ranges.len());
+        for (first, last) in ranges {
+            match merged.last_mut() {
+                Some((_, prev_last)) if first <= *prev_last => {
+                    if last > *prev_last {
+                        *prev_last = last;
+                    }
+                }
+                _ => merged.push((first, last)),
+            }
+        }
+        merged
+    }
+
+    /// 数据库自己执行合并时的参数，输出写入 `output_level` 层，不迁移和读回 value
+    fn merge_params<'a>(&'a self, snapshot: &DbInner, output_level: u32) -> MergeParams<'a> {
+        MergeParams {
+            now_sst_id: snapshot.sst_id,
+            sst_cache: self.sst_cache.clone(),
+            now_vsst_id: snapshot.vsst_id,
+            vssts: snapshot.vssts.clone(),
+            vsst_cache: self.vsst_cache.clone(),
+            vsst_rc: snapshot.vsst_rc.clone(),
+            missing_vssts: snapshot.missing_vssts.clone(),
+            filter_loading: self.options.filter_loading,
+            filter_bits_per_key: self.options.filter_bits_per_key_of(output_level),
+            key_order_check: self.options.sst_key_order_check,
+            compression: self.options.compaction_compression,
+            dict_size: self.options.zstd_dict_size,
+            block_size: self.options.block_size,
+            vsst_block_size: self.options.vsst_block_size,
+            ..MergeParams::new(self.path.as_path(), self.sst_cache.clone())
+        }
+    }
+
+    /// 合并 `ssts` 并输出新的 SST，空洞率过高的 VSST 中的 value 迁移到新 VSST，
+    /// 长度不超过 `inline_threshold` 的已分离 value 读回 SST。
+    /// 读取输入和写出输出时按 `cache_fill` 填充块缓存，输出的 key 按 `key_order_check` 检查顺序，见 [`MergeParams`]。
+    /// 写出的 value 交给 `inspection` 抽样检查，只在 SST 中保存 VSST 位置的 value 没有读出，不检查。
+    /// 引用 `missing_vssts` 中的 VSST 的分离项改写为删除标记，并减少该 VSST 的引用计数
     #[instrument]
     pub(crate) fn merge(
-        path: impl AsRef<Path> + Debug,
-        now_sst_id: u32,
+        params: MergeParams,
         ssts: Vec<Arc<SsTable>>,
-        sst_cache: Arc<BlockCache>,
-        now_vsst_id: u32,
-        vssts: Arc<RwLock<HashMap<u32, Arc<SsTable>>>>,
-        vsst_cache: Arc<BlockCache>,
-        vsst_rc: Arc<RwLock<HashMap<u32, u32>>>,
+        inspection: &mut Inspection,
     ) -> anyhow::Result<(
         Vec<Arc<SsTable>>,      //  new sst
         Vec<Arc<SsTable>>,      // new vsst
-        Arc<HashMap<u32, i32>>, // vsst rc delta
+        Arc<HashMap<u64, i32>>, // vsst rc delta
+        KeyPrefixStats,         // key prefix stats of new sst
     )> {
+        let MergeParams {
+            path,
+            now_sst_id,
+            sst_cache,
+            now_vsst_id,
+            vssts,
+            vsst_cache,
+            vsst_rc,
+            missing_vssts,
+            filter_loading,
+            max_migration_size,
+            inline_threshold,
+            cache_fill,
+            filter_bits_per_key,
+            filter_seed,
+            key_order_check,
+            compression,
+            dict_size,
+            block_size,
+            vsst_block_size,
+        } = params;
+        // 合并开始前已在缓存中的块视为热点
+        let hot_ranges = if cache_fill.fill_on_write() {
+            Self::merge_key_ranges(
+                ssts.iter()
+                    .flat_map(|_sst| _sst.cached_key_ranges())
+                    .collect(),
+            )
+        } else {
+            vec![]
+        };
+
+        // 输出的 SST 记录所有输入中最大的 seq num
+        let max_seq = ssts
+            .iter()
+            .filter_map(|_sst| _sst.max_seq())
+            .max()
+            .unwrap_or(0);
+        let new_sst_builder = || {
+            Self::new_sst_builder(compression, dict_size, block_size)
+                .with_max_seq(max_seq)
+                .with_filter_bits_per_key(filter_bits_per_key)
+                .with_filter_seed(filter_seed)
+                .with_key_order_check(key_order_check)
+        };
+        let read_options = BlockReadOptions {
+            fill_cache: cache_fill.fill_on_read(),
+            ..Default::default()
+        };
         let mut sst_iters = vec![];
         for _sst in ssts {
-            sst_iters.push(Box::new(SsTableIterator::create_and_seek_to_first(_sst)?));
+            sst_iters.push(Box::new(checked(
+                SsTableIterator::create_and_seek_to_first_with_options(_sst, read_options)?,
+                "compaction sst",
+                true,
+            )));
         }
 
         // 创建多个SST
         let mut iter = RcMergeIterator::create(sst_iters);
         let mut new_ssts = vec![];
-        let mut builder = SsTableBuilder::new();
+        let mut builder = new_sst_builder();
+        let mut key_prefixes = KeyPrefixStats::default();
 
         let mut new_vssts = vec![];
-        let mut vsst_builder = SsTableBuilder::new();
-        let mut vsst_rc_delta: HashMap<u32, i32> = HashMap::new();
+        let mut vsst_builder = Self::new_vsst_builder(compression, dict_size, vsst_block_size)
+            .with_filter_seed(filter_seed)
+            .with_key_order_check(key_order_check);
+        let mut vsst_rc_delta: HashMap<u64, i32> = HashMap::new();
 
         let mut next_sst_id = now_sst_id + 1;
         let mut next_vsst_id = now_vsst_id + 1;
 
+        // 迁移 value 时每个源 VSST 共用一个迭代器，key 递增，只需向后定位
+        let mut migration_iters: HashMap<u64, SsTableIterator> = HashMap::new();
+        let mut migrated_size = 0;
+        let mut inlined_size = 0;
+        // 各缺失的 VSST 被改写为删除标记的引用数量
+        let mut dangling_refs: HashMap<u64, i32> = HashMap::new();
+        // 各源 VSST 的 KV 数量，旧文件第一次用到时数出，数不出来时为 0，不按空洞率迁移
+        let mut vsst_pairs: HashMap<u64, usize> = HashMap::new();
+
+        let mut checkpoint = LoopCheckpoint::new("compaction");
         while iter.is_valid() {
-            let is_separate = iter.value().len() as u64 > MIN_VSST_SIZE;
+            checkpoint.tick();
+            // SST 中分离项的 value 是 vsst id，只能通过 meta 中的标记判断
+            let is_separate = Entry::is_separate(iter.meta());
 
             let mut merge = false;
+            let mut inline = false;
+            let mut dangling = false;
             let mut vsst_id = 0;
             if is_separate {
+                vsst_id = Entry::separated_vsst_id(iter.value());
+                // value 已随 VSST 丢失，既不读回也不迁移
+                dangling = missing_vssts.contains(vsst_id);
+                // 旧版本写入的分离项没有记录 value 长度，不读回
+                inline = !dangling
+                    && Entry::separated_value_len(iter.value())
+                        .is_some_and(|value_len| value_len <= inline_threshold);
+            }
+            // 迁移量达到上限后剩余的 value 留在原 VSST 中，等待之后的合并
+            if is_separate && !inline && !dangling && migrated_size < max_migration_size {
                 // 若该项 KV 分离，判断对应 VSST 空洞率
-                vsst_id = iter.value().get_u32_le();
                 if let Some(ref_cnt) = vsst_rc.read().get(&vsst_id) {
-                    let tot_cnt = vssts.read().get(&vsst_id).unwrap().num_of_pairs();
-                    if *ref_cnt as f32 / tot_cnt as f32 > MAX_VSST_SPARE_RATIO {
+                    let tot_cnt = *vsst_pairs.entry(vsst_id).or_insert_with(|| {
+                        let Some(vsst) = vssts.read().get(&vsst_id).cloned() else {
+                            return 0;
+                        };
+                        vsst.verified_num_of_pairs().unwrap_or_else(|e| {
+                            warn!("count pairs of {}.VSST failed: {:?}", vsst_id, e);
+                            0
+                        })
+                    });
+                    if tot_cnt > 0 && *ref_cnt as f32 / tot_cnt as f32 > MAX_VSST_SPARE_RATIO {
                         merge = true;
                     }
                 }
             }
 
             let mut entry_builder = EntryBuilder::new();
-            // 如果空洞率超限，迁移到新 VSST
-            if merge {
-                // 先读原来那个 VSST 的数据（顺便要减引用计数
-                let mut _iter = SsTableIterator::create_and_seek_to_key(
-                    vssts.read().get(&vsst_id).unwrap().clone(),
-                    iter.key(),
-                )?;
-                let key = Bytes::copy_from_slice(iter.key());
-                let value = Bytes::copy_from_slice(_iter.value());
+            // 迁移和读回都先读出原 VSST 中的 value，并减少原 VSST 的引用计数
+            let mut read_separated_value = |key: &Bytes| -> anyhow::Result<Bytes> {
+                Db::check_vsst_contains(vssts.read().get(&vsst_id).unwrap(), key)?;
+                if let Some(_iter) = migration_iters.get_mut(&vsst_id) {
+                    _iter.seek_forward(key)?;
+                } else {
+                    let _iter = SsTableIterator::create_and_seek_to_key_with_options(
+                        vssts.read().get(&vsst_id).unwrap().clone(),
+                        key,
+                        read_options,
+                    )?;
+                    migration_iters.insert(vsst_id, _iter);
+                }
+                let _iter = &migration_iters[&vsst_id];
+                if !_iter.is_valid() || _iter.key() != key {
+                    return Err(anyhow!("{}.VSST has no value for key {:?}", vsst_id, key));
+                }
                 vsst_rc_delta.insert(vsst_id, vsst_rc_delta.get(&vsst_id).unwrap_or(&0) - 1);
+                Ok(Bytes::copy_from_slice(_iter.value()))
+            };
+            if dangling {
+                // 改写为删除标记，同时遮盖更深层中的旧版本，避免之后读到过期的 value
+                *dangling_refs.entry(vsst_id).or_insert(0) += 1;
+                entry_builder
+                    .op_type(OpType::Delete)
+                    .key_value(Bytes::copy_from_slice(iter.key()), Bytes::new())
+                    .build();
+            } else if inline {
+                // value 不超过 KV 分离的阈值，读回 SST
+                let key = Bytes::copy_from_slice(iter.key());
+                let value = read_separated_value(&key)?;
+                inlined_size += value.len() as u64;
+                inspection.inspect(&key, &value);
+                entry_builder
+                    .op_type(Entry::op_type_of(iter.meta()))
+                    .key_value(key, value)
+                    .build();
+            } else if merge {
+                // 如果空洞率超限，迁移到新 VSST
+                let key = Bytes::copy_from_slice(iter.key());
+                let value = read_separated_value(&key)?;
+                let value_len = value.len() as u64;
+                migrated_size += value_len;
+                inspection.inspect(&key, &value);
 
                 // 然后写到新 VSST 里（增加引用计数
-                vsst_builder.add(&EntryBuilder::new().key_value(key.clone(), value).build());
+                vsst_builder.add(
+                    &EntryBuilder::new()
+                        .op_type(OpType::Put)
+                        .key_value(key.clone(), value)
+                        .build(),
+                );
                 vsst_rc_delta.insert(
                     next_vsst_id,
                     vsst_rc_delta.get(&next_vsst_id).unwrap_or(&0) + 1,
                 );
 
                 // 最后合并 SST 的时候修改 value 为新 VSST ID
-                let mut sst_id_value = BytesMut::new();
-                sst_id_value.put_u32_le(next_vsst_id);
                 entry_builder
                     .op_type(OpType::Put)
                     .kv_separate(true)
-                    .key_value(key, sst_id_value.freeze())
+                    .key_value(key, Entry::separated_value(next_vsst_id, value_len))
                     .build();
             } else {
                 // 常规操作，只合并 SST
+                if !is_separate && Entry::op_type_of(iter.meta()) == OpType::Put {
+                    inspection.inspect(iter.key(), iter.value());
+                }
                 entry_builder
-                    .op_type(OpType::Put)
+                    .op_type(Entry::op_type_of(iter.meta()))
                     .kv_separate(is_separate)
                     .key_value(
                         Bytes::copy_from_slice(iter.key()),
@@ -297,40 +1068,77 @@ impl DbDaemon {
 
             let entry = entry_builder.build();
             if builder.size() + entry.size() > MAX_SST_SIZE as usize {
-                builder.build(
-                    next_sst_id,
-                    Some(sst_cache.clone()),
-                    Db::path_of_sst(&path, next_sst_id),
-                )?;
-
+                let full_builder = std::mem::replace(&mut builder, new_sst_builder());
+                key_prefixes.merge(full_builder.key_prefixes());
+                new_ssts.push(Arc::new(
+                    full_builder
+                        .build(
+                            next_sst_id,
+                            Some(sst_cache.clone()),
+                            Db::path_of_sst(path, next_sst_id),
+                        )?
+                        .with_filter_loading(filter_loading),
+                ));
                 next_sst_id += 1;
-                builder = SsTableBuilder::new();
             }
             builder.add(&entry);
 
             iter.next()?;
         }
 
+        if migrated_size > 0 {
+            info!(
+                "migrate {} bytes of values out of sparse VSSTs",
+                migrated_size
+            );
+        }
+        if inlined_size > 0 {
+            info!("inline {} bytes of separated values", inlined_size);
+        }
+        if !dangling_refs.is_empty() {
+            warn!("drop references to missing VSSTs: {:?}", dangling_refs);
+        }
+
         if builder.size() > 0 {
-            new_ssts.push(Arc::new(builder.build(
-                next_sst_id,
-                Some(sst_cache.clone()),
-                Db::path_of_sst(&path, next_sst_id),
-            )?));
+            key_prefixes.merge(builder.key_prefixes());
+            new_ssts.push(Arc::new(
+                builder
+                    .build(
+                        next_sst_id,
+                        Some(sst_cache.clone()),
+                        Db::path_of_sst(path, next_sst_id),
+                    )?
+                    .with_filter_loading(filter_loading),
+            ));
         }
-        if vsst_builder.size() > 0 {
-            new_vssts.push(Arc::new(vsst_builder.build(
-                next_vsst_id,
-                Some(vsst_cache.clone()),
-                Db::path_of_vsst(&path, next_vsst_id),
-            )?));
+        if !vsst_builder.is_empty() {
+            new_vssts.push(Arc::new(
+                vsst_builder
+                    .build(
+                        next_vsst_id,
+                        Some(vsst_cache.clone()),
+                        Db::path_of_vsst(path, next_vsst_id),
+                    )?
+                    .with_filter_loading(filter_loading),
+            ));
+        }
+
+        if !hot_ranges.is_empty() {
+            let mut warmed = 0;
+            for _sst in &new_ssts {
+                warmed += _sst.warm_cache(&hot_ranges)?;
+            }
+            info!("warm {} blocks of compaction output", warmed);
         }
 
         let _vsst_rc_delta = iter.vsst_rc_delta();
+        for (vsst_id, refs) in dangling_refs {
+            vsst_rc_delta.insert(vsst_id, vsst_rc_delta.get(&vsst_id).unwrap_or(&0) - refs);
+        }
         for (vsst_id, delta) in _vsst_rc_delta {
             vsst_rc_delta.insert(vsst_id, vsst_rc_delta.get(&vsst_id).unwrap_or(&0) + delta);
         }
 
-        Ok((new_ssts, new_vssts, Arc::new(vsst_rc_delta)))
+        Ok((new_ssts, new_vssts, Arc::new(vsst_rc_delta), key_prefixes))
     }
 }
@@ -0,0 +1,201 @@
+use crate::daemon::DbDaemon;
+use crate::meta::manifest::{ManifestActor, ManifestItem, RecordOrigin};
+use crate::record::RecordBuilder;
+use crate::sstable::builder::SsTable;
+use crate::storage::header::FileType;
+use crate::wal::Journal;
+use crate::{Db, DELETE_TRUNCATE_CHUNK_SIZE};
+use std::fs::{self, OpenOptions};
+use std::io::ErrorKind;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tracing::{info, instrument, warn};
+
+/// 已从元数据中移除、等待后台删除的 SST / VSST 文件
+#[derive(Debug)]
+pub(crate) struct ObsoleteFile {
+    pub(crate) file_type: FileType,
+    pub(crate) id: u64,
+    /// 合并时移除的表，快照可能仍在读取；重启后恢复的待删除文件没有
+    table: Option<Arc<SsTable>>,
+}
+
+impl ObsoleteFile {
+    pub(crate) fn new(file_type: FileType, id: u64, table: Option<Arc<SsTable>>) -> Self {
+        Self {
+            file_type,
+            id,
+            table,
+        }
+    }
+
+    fn path(&self, base_path: impl AsRef<Path>) -> PathBuf {
+        match self.file_type {
+            FileType::VSst => Db::path_of_vsst(base_path, self.id),
+            _ => Db::path_of_sst(base_path, self.id),
+        }
+    }
+}
+
+impl DbDaemon {
+    /// 将已在 MANIFEST 中记为 [`ManifestItem::PendingDelete`] 的文件交给后台线程删除
+    pub(crate) fn schedule_delete(&self, files: Vec<ObsoleteFile>) {
+        if files.is_empty() {
+            return;
+        }
+        self.obsolete_files.lock().extend(files);
+        // 后台线程每次唤醒都会删完整个队列，唤醒消息已满时不需要再发送
+        let _ = self.delete_chan.0.try_send(());
+    }
+
+    pub(crate) fn num_of_pending_deletes(&self) -> usize {
+        self.obsolete_files.lock().len()
+    }
+
+    /// 按加入的顺序删除队列中的文件，每删除一个文件在 MANIFEST 中记录一次 [`ManifestItem::FileDeleted`]
+    ///
+    /// 设置了 `delete_rate_limit` 时按限速分多次截断文件再删除，避免一次删除大文件占满磁盘带宽；
+    /// 删除失败的文件留在队列头部，下次唤醒时重试
+    #[instrument(skip(self))]
+    pub(crate) fn delete_obsolete_files(&self) -> anyhow::Result<()> {
+        loop {
+            let Some(mut file) = self.obsolete_files.lock().pop_front() else {
+                return Ok(());
+            };
+            let path = file.path(self.path.as_ref());
+            // 只剩队列持有时没有快照会再读取该文件，可以截断
+            let exclusive = match file.table.take() {
+                Some(table) => Arc::try_unwrap(table).is_ok(),
+                None => true,
+            };
+            let deleted = match self.options.trash_retention {
+                Some(_) => move_to_trash(self.path.as_ref(), &path),
+                None => delete_file(&path, exclusive, self.options.delete_rate_limit),
+            };
+            let size = match deleted {
+                Ok(size) => size,
+                Err(e) => {
+                    self.obsolete_files.lock().push_front(file);
+                    return Err(anyhow::Error::from(e).context(format!("delete {:?} failed", path)));
+                }
+            };
+            info!("DEL {:?} ({} bytes)", path, size);
+
+            let mut r = RecordBuilder::new();
+            r.add(ManifestItem::Origin(RecordOrigin::now(ManifestActor::Gc)));
+            r.add(ManifestItem::FileDeleted(file.file_type, file.id));
+            self.manifest.write().add(&r.build())?;
+        }
+    }
+
+    /// 删除已刷写的 WAL 的所有段，开启回收站时移到回收站
+    pub(crate) fn delete_wal(&self, wal: &Journal) -> anyhow::Result<()> {
+        if self.options.trash_retention.is_none() {
+            return wal.delete();
+        }
+        for path in wal.segment_paths() {
+            let size = move_to_trash(self.path.as_ref(), &path)
+                .map_err(|e| anyhow::Error::from(e).context(format!("delete {:?} failed", path)))?;
+            info!("DEL {:?} ({} bytes)", path, size);
+        }
+        Ok(())
+    }
+
+    /// 删除回收站中超过保留时间的文件，按 `delete_rate_limit` 限速，未开启回收站时什么也不做
+    #[instrument(skip(self))]
+    pub(crate) fn purge_trash(&self) -> anyhow::Result<()> {
+        let Some(retention) = self.options.trash_retention else {
+            return Ok(());
+        };
+        let trash = Db::path_of_trash(self.path.as_ref());
+        let entries = match fs::read_dir(&trash) {
+            Ok(entries) => entries,
+            Err(e) if e.kind() == ErrorKind::NotFound => return Ok(()),
+            Err(e) => return Err(e.into()),
+        };
+        let now = unix_secs();
+        for entry in entries {
+            let path = entry?.path();
+            // 不是由回收站放入的文件不处理
+            let Some(deleted_at) = trashed_at(&path) else {
+                continue;
+            };
+            if now.saturating_sub(deleted_at) < retention.as_secs() {
+                continue;
+            }
+            let size = delete_file(&path, true, self.options.delete_rate_limit)
+                .map_err(|e| anyhow::Error::from(e).context(format!("purge {:?} failed", path)))?;
+            info!("PURGE {:?} ({} bytes)", path, size);
+        }
+        Ok(())
+    }
+
+    pub(crate) fn delete_receiver(&self) -> crossbeam::channel::Receiver<()> {
+        self.delete_chan.1.clone()
+    }
+}
+
+fn unix_secs() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |d| d.as_secs())
+}
+
+/// 将文件移到回收站并返回其大小，回收站中的文件名为 `{删除时间}-{原文件名}`，删除时间为 unix 时间戳（秒）
+fn move_to_trash(base_path: &Path, path: &Path) -> std::io::Result<u64> {
+    #[cfg(feature = "durability-check")]
+    crate::storage::durability::on_delete(path);
+    let size = match fs::metadata(path) {
+        Ok(metadata) => metadata.len(),
+        Err(e) if e.kind() == ErrorKind::NotFound => {
+            warn!("{:?} has already been deleted", path);
+            return Ok(0);
+        }
+        Err(e) => return Err(e),
+    };
+    let trash = Db::path_of_trash(base_path);
+    fs::create_dir_all(&trash)?;
+    let name = path.file_name().unwrap_or_default().to_string_lossy();
+    fs::rename(path, trash.join(format!("{}-{}", unix_secs(), name)))?;
+    Ok(size)
+}
+
+/// 从回收站中的文件名取出删除时间
+fn trashed_at(path: &Path) -> Option<u64> {
+    let name = path.file_name()?.to_str()?;
+    name.split_once('-')?.0.parse().ok()
+}
+
+/// 删除文件并返回其大小，文件已不存在时（删除后、记录前崩溃）返回 0
+fn delete_file(path: &Path, exclusive: bool, rate_limit: Option<u64>) -> std::io::Result<u64> {
+    #[cfg(feature = "durability-check")]
+    crate::storage::durability::on_delete(path);
+    let size = match fs::metadata(path) {
+        Ok(metadata) => metadata.len(),
+        Err(e) if e.kind() == ErrorKind::NotFound => {
+            warn!("{:?} has already been deleted", path);
+            return Ok(0);
+        }
+        Err(e) => return Err(e),
+    };
+    let Some(rate) = rate_limit.filter(|rate| *rate > 0) else {
+        fs::remove_file(path)?;
+        return Ok(size);
+    };
+    let pace = |bytes: u64| thread::sleep(Duration::from_secs_f64(bytes as f64 / rate as f64));
+
+    let mut remaining = size;
+    if exclusive {
+        let file = OpenOptions::new().write(true).open(path)?;
+        while remaining > DELETE_TRUNCATE_CHUNK_SIZE {
+            remaining -= DELETE_TRUNCATE_CHUNK_SIZE;
+            file.set_len(remaining)?;
+            pace(DELETE_TRUNCATE_CHUNK_SIZE);
+        }
+    }
+    fs::remove_file(path)?;
+    pace(remaining);
+    Ok(size)
+}
@@ -0,0 +1,122 @@
+use crate::cooperative::LoopCheckpoint;
+use crate::daemon::{DbDaemon, ObsoleteFile};
+use crate::db::DbInner;
+use crate::entry::Entry;
+use crate::iterator::StorageIterator;
+use crate::meta::manifest::{ManifestActor, ManifestItem, RecordOrigin};
+use crate::record::RecordBuilder;
+use crate::sstable::builder::{BlockReadOptions, SsTable};
+use crate::sstable::iterator::SsTableIterator;
+use crate::storage::header::FileType;
+use crate::SST_LEVEL_LIMIT;
+use std::collections::HashMap;
+use std::sync::Arc;
+use tracing::{info, instrument};
+
+impl DbDaemon {
+    /// 所有 SST 和 VSST 的总大小
+    pub(crate) fn total_size(snapshot: &DbInner) -> u64 {
+        snapshot
+            .levels
+            .iter()
+            .flatten()
+            .chain(snapshot.vssts.read().values())
+            .map(|_sst| _sst.size())
+            .sum()
+    }
+
+    /// 开启缓存模式且总大小超过 `cache_capacity` 时请求后台淘汰
+    pub(crate) fn schedule_eviction(&self, snapshot: &DbInner) {
+        if let Some(capacity) = self.options.cache_capacity {
+            if Self::total_size(snapshot) > capacity {
+                self.request_compaction(SST_LEVEL_LIMIT - 1);
+            }
+        }
+    }
+
+    /// `sst` 中分离项对每个 VSST 的引用数，`sst` 即将被淘汰，读出的块不放入缓存
+    fn vsst_refs(sst: Arc<SsTable>) -> anyhow::Result<HashMap<u64, i32>> {
+        let mut refs = HashMap::new();
+        let options = BlockReadOptions {
+            fill_cache: false,
+            ..Default::default()
+        };
+        let mut iter = SsTableIterator::create_and_seek_to_first_with_options(sst, options)?;
+        let mut checkpoint = LoopCheckpoint::new("eviction");
+        while iter.is_valid() {
+            checkpoint.tick();
+            if Entry::is_separate(iter.meta()) {
+                *refs
+                    .entry(Entry::separated_vsst_id(iter.value()))
+                    .or_insert(0) += 1;
+            }
+            iter.next()?;
+        }
+        Ok(refs)
+    }
+
+    /// 淘汰最早写入的 SST，直到总大小不超过 `cache_capacity`
+    ///
+    /// 越深的层数据越旧，按从最深的层到 L0、同层 id 从小到大的顺序整个删除 SST。
+    /// 被删除的 SST 所在层以下没有数据，不会让被覆盖或删除的旧版本重新可见。
+    /// VSST 在引用它的 SST 都被淘汰后随引用计数一起回收
+    #[instrument]
+    pub(crate) fn evict(&self) -> anyhow::Result<()> {
+        let Some(capacity) = self.options.cache_capacity else {
+            return Ok(());
+        };
+        let mut guard = self.inner.write();
+        let mut snapshot = guard.as_ref().clone();
+        let mut total_size = Self::total_size(&snapshot);
+        if total_size <= capacity {
+            return Ok(());
+        }
+
+        let mut victims = vec![];
+        let mut vsst_rc_delta: HashMap<u64, i32> = HashMap::new();
+        'pick: for level in (0..SST_LEVEL_LIMIT as usize).rev() {
+            let mut ssts = snapshot.levels[level].clone();
+            ssts.sort_by_key(|_sst| _sst.id());
+            for _sst in ssts {
+                if total_size <= capacity {
+                    break 'pick;
+                }
+                total_size -= _sst.size();
+                for (vsst_id, refs) in Self::vsst_refs(_sst.clone())? {
+                    let rc = *snapshot.vsst_rc.read().get(&vsst_id).unwrap_or(&0) as i32;
+                    let delta = vsst_rc_delta.entry(vsst_id).or_insert(0);
+                    let was_alive = rc + *delta > 0;
+                    *delta -= refs;
+                    if was_alive && rc + *delta <= 0 {
+                        if let Some(_vsst) = snapshot.vssts.read().get(&vsst_id) {
+                            total_size = total_size.saturating_sub(_vsst.size());
+                        }
+                    }
+                }
+                victims.push((level as u32, _sst));
+            }
+        }
+
+        let mut r = RecordBuilder::new();
+        r.add(ManifestItem::Origin(RecordOrigin::now(ManifestActor::Gc)));
+        let mut obsolete_files = vec![];
+        for (level, _sst) in victims {
+            info!("EVICT L{} {}.SST", level, _sst.id());
+            snapshot.levels[level as usize].retain(|other| other.id() != _sst.id());
+            r.add(ManifestItem::DelSst(level, _sst.id()));
+            r.add(ManifestItem::PendingDelete(FileType::Sst, _sst.id()));
+            obsolete_files.push(ObsoleteFile::new(FileType::Sst, _sst.id(), Some(_sst)));
+        }
+        self.apply_vsst_rc_delta(&mut snapshot, &vsst_rc_delta, &mut r, &mut obsolete_files);
+        r.add(ManifestItem::Stats(self.counters.snapshot()));
+        self.manifest.write().add(&r.build())?;
+        self.schedule_delete(obsolete_files);
+        info!(
+            "evict to {} bytes, cache capacity {} bytes",
+            total_size, capacity
+        );
+
+        *guard = Arc::new(snapshot);
+        Ok(())
+    }
+}
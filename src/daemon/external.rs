@@ -0,0 +1,195 @@
+use crate::daemon::compaction::CompactionOutput;
+use crate::daemon::DbDaemon;
+use crate::sstable::builder::SsTable;
+use crate::storage::file::FileStorage;
+use crate::storage::header::FileType;
+use crate::Db;
+use anyhow::{anyhow, Context};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fs::{self, File};
+use std::path::Path;
+use std::sync::Arc;
+use std::time::Instant;
+use tracing::{info, instrument};
+
+/// 任务目录中保存 [`CompactionJob`] 的文件名
+pub const COMPACTION_JOB_FILE: &str = "COMPACTION_JOB";
+
+/// 导出的合并输入文件
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct CompactionJobFile {
+    pub level: u32,
+    pub id: u64,
+    pub size: u64,
+    pub smallest_key: Vec<u8>,
+    pub largest_key: Vec<u8>,
+}
+
+/// 在数据库进程之外执行的一次合并
+///
+/// [`Db::export_compaction`] 把输入 SST 放入任务目录并写入任务描述，
+/// 其他进程或机器用 [`crate::admin::run_compaction_job`] 在任务目录中合并出新的 SST，
+/// 最后由 [`Db::import_compaction`] 在一条 MANIFEST 记录中用输出替换输入
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct CompactionJob {
+    /// 被合并的层，输出写入下一层
+    pub level: u32,
+    /// 输入 SST，`level` 层的按新到旧排在前面，之后是下一层的，合并时 key 相同取靠前的版本
+    pub inputs: Vec<CompactionJobFile>,
+    /// 合并输出的 SST id，文件在任务目录中，未执行时为 `None`
+    pub outputs: Option<Vec<u64>>,
+    /// 合并中丢弃的 KV 分离项引起的 VSST 引用计数变化 (vsst id, delta)
+    pub vsst_rc_delta: Vec<(u64, i32)>,
+}
+
+impl CompactionJob {
+    pub fn is_finished(&self) -> bool {
+        self.outputs.is_some()
+    }
+
+    /// 从任务目录读取任务描述
+    pub fn load(dir: impl AsRef<Path>) -> anyhow::Result<Self> {
+        let path = dir.as_ref().join(COMPACTION_JOB_FILE);
+        let data = fs::read(&path).with_context(|| format!("read {:?} failed", path))?;
+        Ok(postcard::from_bytes(&data)?)
+    }
+
+    /// 写入任务描述，先写临时文件再重命名，读者不会看到写了一半的描述
+    pub fn save(&self, dir: impl AsRef<Path>) -> anyhow::Result<()> {
+        let path = dir.as_ref().join(COMPACTION_JOB_FILE);
+        let tmp_path = path.with_extension("tmp");
+        fs::write(&tmp_path, postcard::to_allocvec(self)?)?;
+        File::open(&tmp_path)?.sync_all()?;
+        fs::rename(&tmp_path, &path)?;
+        Ok(())
+    }
+}
+
+/// 优先建立硬链接，跨文件系统时退化为复制
+fn link_or_copy(from: &Path, to: &Path) -> std::io::Result<()> {
+    if fs::hard_link(from, to).is_ok() {
+        return Ok(());
+    }
+    fs::copy(from, to)?;
+    File::open(to)?.sync_all()
+}
+
+impl DbDaemon {
+    /// 选择 `level` 层的合并输入并导出到 `dir`，没有可合并的文件时返回 `None`
+    ///
+    /// 输入文件以硬链接导出，导出后即使数据库删除了这些文件，任务目录中的副本仍然可读
+    #[instrument(skip(self))]
+    pub(crate) fn export_compaction(
+        &self,
+        level: u32,
+        dir: &Path,
+    ) -> anyhow::Result<Option<CompactionJob>> {
+        let snapshot = self.inner.read().clone();
+        let Some((li_sst, li1_sst)) =
+            Self::select_inputs(&snapshot.levels, level, self.options.max_compaction_bytes)
+        else {
+            info!("L{} has nothing to compact", level);
+            return Ok(None);
+        };
+        fs::create_dir_all(dir)?;
+        let mut inputs = vec![];
+        for (_level, _sst) in li_sst
+            .iter()
+            .map(|_sst| (level, _sst))
+            .chain(li1_sst.iter().map(|_sst| (level + 1, _sst)))
+        {
+            link_or_copy(
+                &Db::path_of_sst(self.path.as_ref(), _sst.id()),
+                &Db::path_of_sst(dir, _sst.id()),
+            )?;
+            let (smallest_key, largest_key) = _sst.key_range();
+            inputs.push(CompactionJobFile {
+                level: _level,
+                id: _sst.id(),
+                size: _sst.size(),
+                smallest_key: smallest_key.to_vec(),
+                largest_key: largest_key.to_vec(),
+            });
+        }
+        let job = CompactionJob {
+            level,
+            inputs,
+            outputs: None,
+            vsst_rc_delta: vec![],
+        };
+        job.save(dir)?;
+        info!("export L{} compaction of {} SSTs", level, job.inputs.len());
+        Ok(Some(job))
+    }
+
+    /// 导入 `dir` 中已执行的合并任务，输入必须仍在导出时的层中，否则任务已过时，返回错误
+    #[instrument(skip(self))]
+    pub(crate) fn import_compaction(&self, dir: &Path) -> anyhow::Result<()> {
+        let started_at = Instant::now();
+        let job = CompactionJob::load(dir)?;
+        let outputs = job
+            .outputs
+            .as_ref()
+            .ok_or(anyhow!("compaction job in {:?} has not been run", dir))?;
+
+        let mut guard = self.inner.write();
+        let mut li_sst = vec![];
+        let mut li1_sst = vec![];
+        for input in &job.inputs {
+            let _sst = guard
+                .levels
+                .get(input.level as usize)
+                .and_then(|ssts| ssts.iter().find(|_sst| _sst.id() == input.id))
+                .ok_or(anyhow!(
+                    "stale compaction job: L{} {}.SST no longer exists",
+                    input.level,
+                    input.id
+                ))?;
+            if input.level == job.level {
+                li_sst.push(_sst.clone());
+            } else {
+                li1_sst.push(_sst.clone());
+            }
+        }
+
+        // 按数据库中的 id 重新编号，复制到数据目录后再打开
+        let mut new_ssts = vec![];
+        for (idx, output_id) in outputs.iter().enumerate() {
+            let sst_id = guard.sst_id + 1 + idx as u64;
+            let path = Db::path_of_sst(self.path.as_ref(), sst_id);
+            fs::copy(Db::path_of_sst(dir, *output_id), &path)?;
+            let file = FileStorage::open(&path)?;
+            file.sync()?;
+            let _sst = SsTable::open_with_filter_loading(
+                sst_id,
+                Some(self.sst_cache.clone()),
+                file,
+                self.options.filter_loading,
+            )
+            .with_context(|| format!("open {:?} failed", path))?;
+            _sst.check_file_type(FileType::Sst)?;
+            new_ssts.push(Arc::new(_sst));
+        }
+        info!(
+            "import L{} compaction: {} SSTs -> {} SSTs",
+            job.level,
+            job.inputs.len(),
+            new_ssts.len()
+        );
+
+        self.install_compaction(
+            &mut guard,
+            vec![(job.level, li_sst), (job.level + 1, li1_sst)],
+            job.level + 1,
+            CompactionOutput {
+                new_ssts,
+                new_vssts: vec![],
+                vsst_rc_delta: Arc::new(
+                    job.vsst_rc_delta.iter().cloned().collect::<HashMap<_, _>>(),
+                ),
+                started_at,
+            },
+        )
+    }
+}
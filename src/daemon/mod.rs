@@ -1,48 +1,131 @@
 use crate::cache::BlockCache;
-use crate::db::DbInner;
+use crate::contention::TrackedRwLock;
+use crate::db::{DbInner, DbOptions};
+use crate::inspect::ValueInspectors;
+use crate::memtable::MemTable;
 use crate::meta::manifest::Manifest;
+use crate::stats::{
+    CumulativeCounters, CumulativeStats, FlushJobId, FlushJobStatus, KeyPrefixStats, QueueGauge,
+    QueueStats,
+};
+use crate::wal::Journal;
 use crossbeam::channel;
-use parking_lot::RwLock;
+use parking_lot::Mutex;
+use std::collections::VecDeque;
 use std::path::PathBuf;
-use std::sync::atomic::AtomicU64;
-use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, OnceLock, Weak};
+use tracing::{span, trace, warn};
 
+mod checkpoint;
 mod compaction;
+mod deleter;
+mod eviction;
+mod external;
 mod rotate;
+mod shared;
+mod wal_sync;
+
+pub use compaction::CompactionPlan;
+pub(crate) use compaction::MergeParams;
+pub(crate) use deleter::ObsoleteFile;
+pub use external::{CompactionJob, CompactionJobFile, COMPACTION_JOB_FILE};
+use shared::SchedulerCore;
+pub(crate) use shared::SchedulerMembership;
+pub use shared::SharedScheduler;
 
 #[cfg(test)]
 mod tests;
 
 #[derive(Debug)]
 pub(crate) struct DbDaemon {
-    inner: Arc<RwLock<Arc<DbInner>>>,
+    inner: Arc<TrackedRwLock<Arc<DbInner>>>,
     sst_cache: Arc<BlockCache>,
     vsst_cache: Arc<BlockCache>,
-    manifest: Arc<RwLock<Manifest>>,
+    manifest: Arc<TrackedRwLock<Manifest>>,
     path: Arc<PathBuf>,
 
     flush_chan: (channel::Sender<()>, channel::Receiver<()>),
     compaction_chan: (channel::Sender<u32>, channel::Receiver<u32>),
     exit_chan: (channel::Sender<()>, channel::Receiver<()>),
+    // 加入共享调度器后设置，发出刷写和合并请求时通知它，见 `SharedScheduler`
+    scheduler: OnceLock<Arc<SchedulerCore>>,
+
+    // 打开数据库的选项，flush 和 compaction 输出的 SST 和新建的 WAL 沿用其中的设置
+    options: DbOptions,
+    // 运行中可修改的 KV 分离阈值，关闭 KV 分离时为 u64::MAX
+    value_separation_threshold: AtomicU64,
 
     compaction_count: AtomicU64,
     rotate_count: AtomicU64,
+    // 已 fsync 到 WAL 的最大提交序号，见 `sync_wal`
+    durable_seq: AtomicU64,
+    // 最后一层是否超出了 MAX_LEVEL_SIZE，只在状态变化时记录日志，见 `check_bottom_level`
+    bottom_level_overflowed: AtomicBool,
+    // 暂停自动合并，见 `Db::set_compactions_paused`
+    compactions_paused: AtomicBool,
+    // 同时只有一次冻结和刷写，保证 memtable 按冻结的顺序进入 L0
+    flush_lock: Mutex<()>,
+    // 提前创建好的下一个 WAL，冻结时直接换入，不在写锁内创建文件，见 `freeze_and_flush`
+    next_wal: Mutex<Option<Journal>>,
+
+    pub(crate) flush_gauge: QueueGauge,
+    pub(crate) compaction_gauge: QueueGauge,
+    flush_jobs: Mutex<FlushJobs>,
+    // 累计计数，随刷写和合并的元数据一起写入 MANIFEST
+    pub(crate) counters: CumulativeCounters,
+    // 打开以来刷写和合并写入的 key 的公共前缀统计
+    pub(crate) key_prefixes: Mutex<KeyPrefixStats>,
+    // 刷写和合并时抽样检查 value 的回调
+    pub(crate) value_inspectors: ValueInspectors,
+
+    // 等待后台删除的文件，见 `deleter`
+    obsolete_files: Mutex<VecDeque<ObsoleteFile>>,
+    delete_chan: (channel::Sender<()>, channel::Receiver<()>),
+
+    // 已刷写的 memtable，迭代器和快照可能仍持有它们，见 `pinned_memtable_memory`
+    retired_memtables: Mutex<Vec<Weak<MemTable>>>,
+}
+
+/// 创建 [`DbDaemon`] 所需的、与 [`crate::Db`] 共享的状态
+pub(crate) struct DaemonParams {
+    pub(crate) inner: Arc<TrackedRwLock<Arc<DbInner>>>,
+    pub(crate) sst_cache: Arc<BlockCache>,
+    pub(crate) vsst_cache: Arc<BlockCache>,
+    pub(crate) manifest: Arc<TrackedRwLock<Manifest>>,
+    pub(crate) path: Arc<PathBuf>,
+    pub(crate) flush_chan: (channel::Sender<()>, channel::Receiver<()>),
+    pub(crate) compaction_chan: (channel::Sender<u32>, channel::Receiver<u32>),
+    pub(crate) exit_chan: (channel::Sender<()>, channel::Receiver<()>),
+    pub(crate) options: DbOptions,
+    /// 从 MANIFEST 恢复出的累计计数
+    pub(crate) stats: CumulativeStats,
+}
+
+#[derive(Debug, Default)]
+struct FlushJobs {
+    last_id: FlushJobId,
+    status: FlushJobStatus,
 }
 
 impl DbDaemon {
-    pub fn new(
-        db_inner: Arc<RwLock<Arc<DbInner>>>,
-        sst_cache: Arc<BlockCache>,
-        vsst_cache: Arc<BlockCache>,
-        manifest: Arc<RwLock<Manifest>>,
-        path: Arc<PathBuf>,
-
-        flush_chan: (channel::Sender<()>, channel::Receiver<()>),
-        compaction_chan: (channel::Sender<u32>, channel::Receiver<u32>),
-        exit_chan: (channel::Sender<()>, channel::Receiver<()>),
-    ) -> Self {
+    pub fn new(params: DaemonParams) -> Self {
+        let DaemonParams {
+            inner,
+            sst_cache,
+            vsst_cache,
+            manifest,
+            path,
+            flush_chan,
+            compaction_chan,
+            exit_chan,
+            options,
+            stats,
+        } = params;
+        // 打开时恢复出的数据都来自磁盘
+        let durable_seq = inner.read().commit_seq.last_allocated();
         DbDaemon {
-            inner: db_inner,
+            inner,
             sst_cache,
             vsst_cache,
             manifest,
@@ -51,9 +134,173 @@ impl DbDaemon {
             flush_chan,
             compaction_chan,
             exit_chan,
+            scheduler: OnceLock::new(),
+
+            value_separation_threshold: AtomicU64::new(
+                options.value_separation_threshold.unwrap_or(u64::MAX),
+            ),
+            value_inspectors: ValueInspectors::new(options.value_inspection_sampling),
+            options,
 
             compaction_count: AtomicU64::new(0),
             rotate_count: AtomicU64::new(0),
+            durable_seq: AtomicU64::new(durable_seq),
+            bottom_level_overflowed: AtomicBool::new(false),
+            compactions_paused: AtomicBool::new(false),
+            flush_lock: Mutex::new(()),
+            next_wal: Mutex::new(None),
+
+            flush_gauge: QueueGauge::default(),
+            compaction_gauge: QueueGauge::default(),
+            flush_jobs: Mutex::new(FlushJobs::default()),
+            counters: CumulativeCounters::new(stats),
+            key_prefixes: Mutex::new(KeyPrefixStats::default()),
+
+            obsolete_files: Mutex::new(VecDeque::new()),
+            delete_chan: channel::bounded(1),
+
+            retired_memtables: Mutex::new(vec![]),
+        }
+    }
+
+    /// 已刷写、已从 `DbInner` 中移除但仍被迭代器或快照持有的 memtable 占用的内存，同时清理已释放的记录
+    pub(crate) fn pinned_memtable_memory(&self) -> u64 {
+        let mut retired = self.retired_memtables.lock();
+        retired.retain(|memtable| memtable.strong_count() > 0);
+        retired
+            .iter()
+            .filter_map(|memtable| memtable.upgrade())
+            .map(|memtable| memtable.size() as u64)
+            .sum()
+    }
+
+    pub(crate) fn set_value_separation_threshold(&self, threshold: Option<u64>) {
+        self.value_separation_threshold
+            .store(threshold.unwrap_or(u64::MAX), Ordering::Relaxed);
+    }
+
+    pub(crate) fn value_separation_threshold(&self) -> Option<u64> {
+        match self.value_separation_threshold.load(Ordering::Relaxed) {
+            u64::MAX => None,
+            threshold => Some(threshold),
+        }
+    }
+
+    /// 请求后台线程刷写 memtable，返回负责这次刷写的任务 id
+    ///
+    /// 已有尚未开始的任务时直接返回该任务，不再重复唤醒后台线程
+    pub(crate) fn request_flush(&self) -> FlushJobId {
+        let mut jobs = self.flush_jobs.lock();
+        if let Some(id) = jobs.status.pending {
+            trace!("coalesce flush request into job {}", id);
+            return id;
         }
+        jobs.last_id += 1;
+        let id = jobs.last_id;
+        jobs.status.pending = Some(id);
+        // 先登记再发送，否则后台线程可能在登记之前就取走请求
+        self.flush_gauge.on_enqueue();
+        if let Err(e) = self.flush_chan.0.try_send(()) {
+            self.flush_gauge.on_cancel();
+            warn!("{}", e);
+        }
+        self.notify_scheduler();
+        id
+    }
+
+    /// 后台线程开始执行等待中的刷写任务
+    pub(crate) fn start_flush_job(&self) -> Option<FlushJobId> {
+        let mut jobs = self.flush_jobs.lock();
+        let id = jobs.status.pending.take();
+        jobs.status.running = id;
+        id
+    }
+
+    pub(crate) fn finish_flush_job(&self, id: Option<FlushJobId>) {
+        let mut jobs = self.flush_jobs.lock();
+        jobs.status.running = None;
+        if id.is_some() {
+            jobs.status.last_completed = id;
+        }
+    }
+
+    pub(crate) fn flush_job_status(&self) -> FlushJobStatus {
+        self.flush_jobs.lock().status
+    }
+
+    /// 取出一个排队的刷写请求，没有时返回 `false`，只在没有后台线程时使用，见 [`crate::SchedulerHandle`]
+    pub(crate) fn take_flush_request(&self) -> bool {
+        self.flush_chan.1.try_recv().is_ok()
+    }
+
+    /// 取出一个排队的合并请求，见 [`DbDaemon::take_flush_request`]
+    pub(crate) fn take_compaction_request(&self) -> Option<u32> {
+        self.compaction_chan.1.try_recv().ok()
+    }
+
+    /// 执行一个已取出的刷写请求：memtable 超过大小限制时冻结并刷写，同时更新队列和任务状态
+    pub(crate) fn run_flush_request(&self) -> anyhow::Result<()> {
+        let _span = span!(tracing::Level::TRACE, "flush daemon");
+        let _enter = _span.enter();
+        self.flush_gauge.on_start();
+        let job = self.start_flush_job();
+        let result = self.rotate();
+        self.finish_flush_job(job);
+        self.flush_gauge.on_complete();
+        result
+    }
+
+    /// 执行一个已取出的合并 `level` 层的请求，同时更新队列状态；自动合并暂停时取消请求
+    pub(crate) fn run_compaction_request(&self, level: u32) -> anyhow::Result<()> {
+        if self.compactions_paused() {
+            self.compaction_gauge.on_cancel();
+            return Ok(());
+        }
+        let _span = span!(tracing::Level::TRACE, "compaction daemon");
+        let _enter = _span.enter();
+        self.compaction_gauge.on_start();
+        let result = self.compaction(level);
+        self.compaction_gauge.on_complete();
+        result
+    }
+
+    /// 暂停或恢复自动合并，恢复时按当前各层的状态重新发起需要的合并
+    pub(crate) fn set_compactions_paused(&self, paused: bool) {
+        let was_paused = self.compactions_paused.swap(paused, Ordering::AcqRel);
+        if was_paused && !paused {
+            let levels = self.inner.read().levels.clone();
+            self.schedule_compaction(&levels);
+        }
+    }
+
+    pub(crate) fn compactions_paused(&self) -> bool {
+        self.compactions_paused.load(Ordering::Acquire)
+    }
+
+    /// 请求后台线程合并 `level` 层，自动合并暂停时忽略
+    pub(crate) fn request_compaction(&self, level: u32) {
+        if self.compactions_paused() {
+            return;
+        }
+        self.compaction_gauge.on_enqueue();
+        if let Err(e) = self.compaction_chan.0.try_send(level) {
+            self.compaction_gauge.on_cancel();
+            warn!("send compaction message failed {}", e);
+        }
+        self.notify_scheduler();
+    }
+
+    fn notify_scheduler(&self) {
+        if let Some(scheduler) = self.scheduler.get() {
+            scheduler.notify();
+        }
+    }
+
+    pub(crate) fn flush_queue_stats(&self) -> QueueStats {
+        self.flush_gauge.stats(self.flush_chan.0.len())
+    }
+
+    pub(crate) fn compaction_queue_stats(&self) -> QueueStats {
+        self.compaction_gauge.stats(self.compaction_chan.0.len())
     }
 }
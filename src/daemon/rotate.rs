@@ -1,12 +1,15 @@
 use crate::daemon::DbDaemon;
-use crate::entry::EntryBuilder;
+use crate::entry::{Entry, EntryBuilder};
 use crate::memtable::MemTable;
-use crate::meta::manifest::ManifestItem;
+use crate::meta::manifest::{ManifestActor, ManifestItem, RecordOrigin};
 use crate::record::RecordBuilder;
 use crate::sstable::builder::SsTableBuilder;
+use crate::storage::file;
+use crate::storage::header::FileType;
 use crate::wal::Journal;
-use crate::{Db, L0_SST_NUM_LIMIT, MEMTABLE_SIZE_LIMIT, MIN_VSST_SIZE};
-use bytes::{BufMut, BytesMut};
+use crate::{Db, OpType};
+use bytes::Bytes;
+use fail::fail_point;
 use std::sync::atomic::Ordering;
 use std::sync::Arc;
 use tracing::{debug, info, instrument, span, trace, warn};
@@ -17,7 +20,10 @@ impl DbDaemon {
         let mut rotate = false;
         {
             let guard = self.inner.read();
-            if guard.memtable.size() > MEMTABLE_SIZE_LIMIT {
+            // 冻结列表中留下的 memtable 见 `freeze_and_flush`
+            if guard.memtable.size() > self.options.memtable_size_limit
+                || !guard.frozen_memtable.is_empty()
+            {
                 rotate = true;
             }
         }
@@ -25,88 +31,275 @@ impl DbDaemon {
             return Ok(());
         }
 
+        self.freeze_and_flush()
+    }
+
+    /// 冻结当前 memtable 并刷写到 L0 SST，不检查 memtable 大小
+    ///
+    /// 冻结和刷写完成各自以一次替换 `DbInner` 发布，读者看到的 memtable 要么在冻结列表中，
+    /// 要么已在 L0 中，不会同时缺失。
+    /// 冻结列表中还可能有恢复时从冻结的 WAL 重放出来的、或之前刷写失败留下的 memtable，
+    /// 按冻结顺序先刷写它们，刷写后对应的 WAL 从 MANIFEST 中移除，之后重启不再重放
+    #[instrument]
+    pub(crate) fn freeze_and_flush(&self) -> anyhow::Result<()> {
+        // 后台刷写和 `Db::flush` 可能同时调用，必须按冻结顺序依次完成，
+        // 否则较新的 memtable 先进入 L0 后，读取会先在冻结列表中读到较旧的版本
+        let _flush = self.flush_lock.lock();
+        if self.inner.read().memtable.size() > 0 {
+            self.freeze()?;
+        }
+        while let Some((flush_memtable, flush_log_id)) = self.oldest_frozen() {
+            self.flush_frozen(flush_memtable, flush_log_id)?;
+        }
+        Ok(())
+    }
+
+    /// 冻结列表中最早的 memtable 和它的 WAL id
+    fn oldest_frozen(&self) -> Option<(Arc<MemTable>, u64)> {
+        let inner = self.inner.read();
+        let memtable = inner.frozen_memtable.first()?.clone();
+        let log_id = inner.frozen_wal.first()?.id();
+        Some((memtable, log_id))
+    }
+
+    /// 冻结当前 memtable 和 WAL，换入新的 memtable 和 WAL，调用者持有 `flush_lock`
+    fn freeze(&self) -> anyhow::Result<()> {
         self.rotate_count.fetch_add(1, Ordering::Release);
+        // 新 WAL 在写锁外准备好：创建文件和写入文件头都要经过文件系统，在写锁内进行会阻塞所有写入。
+        // `log_id` 只在持有 `flush_lock` 时改变
+        let new_log_id = self.inner.read().log_id + 1;
+        let (new_wal, mut carried) = self.take_next_wal(new_log_id)?;
         let flush_memtable;
-        let sst_id: u32;
-        let vsst_id: u32;
 
         // 冻结 memtable 和 wal
         {
-            let mut guard = self.inner.write();
+            // 写入带 token 的批次时持有读锁，写锁内 token 不再变化；
+            // 写入新 WAL 之后又有新的 token 时在锁外补写后重试，写锁内只交换指针
+            let mut guard = loop {
+                let guard = self.inner.write();
+                if guard.idempotency_tokens.lock().inserted() == carried {
+                    break guard;
+                }
+                drop(guard);
+                carried = self.carry_tokens(&new_wal, carried)?;
+            };
             let mut snapshot = guard.as_ref().clone();
             let old_memtable = std::mem::replace(&mut snapshot.memtable, Arc::new(MemTable::new()));
-            let new_log_id = snapshot.log_id + 1;
-            let old_wal = std::mem::replace(
-                &mut snapshot.wal,
-                Arc::new(Journal::open(
-                    new_log_id,
-                    Db::path_of_wal(self.path.as_ref(), new_log_id),
-                )?),
-            );
+            new_wal.reset_segment_age();
+            let old_wal = std::mem::replace(&mut snapshot.wal, Arc::new(new_wal));
 
             flush_memtable = old_memtable.clone();
-            sst_id = snapshot.sst_id + 1;
-            vsst_id = snapshot.vsst_id + 1;
-            snapshot.sst_id = sst_id;
-            snapshot.vsst_id = vsst_id;
             snapshot.log_id = new_log_id;
             snapshot.frozen_memtable.push(old_memtable);
             snapshot.frozen_wal.push(old_wal.clone());
 
             let mut builder = RecordBuilder::new();
+            builder.add(ManifestItem::Origin(RecordOrigin::now(
+                ManifestActor::Flush,
+            )));
             builder.add(ManifestItem::FreezeAndCreateWal(old_wal.id(), new_log_id));
-            self.manifest.write().add(&builder.build());
+            // 持有写锁时没有进行中的写入，旧 WAL 中的写入都已分配序号
+            builder.add(ManifestItem::CommitSeq(
+                snapshot.commit_seq.last_allocated(),
+            ));
+            self.manifest.write().add(&builder.build())?;
 
             *guard = Arc::new(snapshot);
         }
+        self.prepare_next_wal(new_log_id + 1);
+        // 刷写期间读取仍会查这个 memtable，在锁外建立索引，不阻塞写入
+        flush_memtable.freeze();
+        Ok(())
+    }
+
+    /// 把冻结的 memtable 刷写到 L0 SST，完成后从冻结列表中移除它，并删除 id 为 `flush_log_id` 的 WAL
+    fn flush_frozen(&self, flush_memtable: Arc<MemTable>, flush_log_id: u64) -> anyhow::Result<()> {
+        let sst_id: u64;
+        let vsst_id: u64;
+        let older;
+        {
+            let mut guard = self.inner.write();
+            let mut snapshot = guard.as_ref().clone();
+            sst_id = snapshot.sst_id + 1;
+            vsst_id = snapshot.vsst_id + 1;
+            snapshot.sst_id = sst_id;
+            snapshot.vsst_id = vsst_id;
+            *guard = Arc::new(snapshot);
+            older = guard.clone();
+        }
 
         // 写入到 L0 SST
-        let mut sst_builder = SsTableBuilder::new();
-        let mut vsst_builder = SsTableBuilder::new();
-        flush_memtable.for_each(|_key, _value| {
-            let user_key = _key.user_key.clone();
-            let value = _value.clone();
-            // KV 分离
-            if _value.len() as u64 > MIN_VSST_SIZE {
-                let mut _sst_value = BytesMut::new();
-                _sst_value.put_u32_le(vsst_id);
+        let mut sst_builder = SsTableBuilder::new()
+            .with_block_size(self.options.block_size)
+            .with_filter_bits_per_key(self.options.filter_bits_per_key_of(0))
+            .with_key_order_check(self.options.sst_key_order_check);
+        let mut vsst_builder = SsTableBuilder::new()
+            .with_block_size(self.options.vsst_block_size)
+            .with_file_type(FileType::VSst)
+            .with_key_order_check(self.options.sst_key_order_check);
+        // 同一 key 只写入最新版本：快照持有的是 memtable 本身，刷写后仍从 memtable 读取旧版本，
+        // 不会读到这个 SST，丢弃旧版本不影响任何快照
+        let mut last_user_key: Option<Bytes> = None;
+        let mut max_seq = 0;
+        let separation_threshold = self.value_separation_threshold.load(Ordering::Relaxed);
+        let mut inspection = self.value_inspectors.start();
+        // 最新版本是删除标记、同一 memtable 中更早写入过该 key 时，如果更早冻结的 memtable 和各层 SST 中都不可能有该 key，
+        // 删除标记没有可以遮盖的数据，连同被它删除的版本一起不写入
+        let position = older
+            .frozen_memtable
+            .iter()
+            .position(|memtable| Arc::ptr_eq(memtable, &flush_memtable))
+            .unwrap_or(older.frozen_memtable.len());
+        let older_frozen = &older.frozen_memtable[..position];
+        let may_exist_below = |user_key: &Bytes| {
+            older_frozen
+                .iter()
+                .any(|memtable| memtable.may_contain(user_key))
+                || older.levels.iter().flatten().any(|table| {
+                    if table.num_of_blocks() == 0 {
+                        return false;
+                    }
+                    let (min_key, max_key) = table.key_range();
+                    *user_key >= min_key
+                        && *user_key <= max_key
+                        && table.maybe_contains_key(user_key)
+                })
+        };
+        let mut dropped_deletes = 0;
+        // 所有 key 都被丢弃时仍写入最后一个删除标记，刷写总是输出一个非空的 SST
+        let mut last_dropped = None;
+        let mut add = |user_key: Bytes, op_type: OpType, value: Bytes, shadows_put: bool| {
+            if op_type == OpType::Delete && shadows_put && !may_exist_below(&user_key) {
+                dropped_deletes += 1;
+                last_dropped = Some((user_key, value));
+                return;
+            }
+            // KV 分离，关闭时阈值为 u64::MAX
+            if value.len() as u64 > separation_threshold {
                 let sst_entry = EntryBuilder::new()
-                    .op_type(_key.op_type)
+                    .op_type(op_type)
                     .kv_separate(true)
-                    .key_value(user_key.clone(), _sst_value.freeze())
+                    .key_value(
+                        user_key.clone(),
+                        Entry::separated_value(vsst_id, value.len() as u64),
+                    )
+                    .build();
+                let vsst_entry = EntryBuilder::new()
+                    .op_type(OpType::Put)
+                    .key_value(user_key, value)
                     .build();
-                let vsst_entry = EntryBuilder::new().key_value(user_key, value).build();
                 sst_builder.add(&sst_entry);
                 vsst_builder.add(&vsst_entry);
             } else {
                 let entry = EntryBuilder::new()
-                    .op_type(_key.op_type)
+                    .op_type(op_type)
                     .key_value(user_key, value)
                     .build();
                 sst_builder.add(&entry);
             }
+        };
+        // 删除标记等看完同一 key 的旧版本后再决定是否写入
+        let mut pending_delete: Option<(Bytes, Bytes)> = None;
+        let mut shadows_put = false;
+        flush_memtable.for_each(|_key, _value| {
+            if last_user_key.as_ref() == Some(&_key.user_key) {
+                shadows_put |= _key.op_type == OpType::Put;
+                return;
+            }
+            if let Some((user_key, value)) = pending_delete.take() {
+                add(user_key, OpType::Delete, value, shadows_put);
+            }
+            last_user_key = Some(_key.user_key.clone());
+            max_seq = max_seq.max(_key.seq_num);
+            if _key.op_type == OpType::Delete {
+                pending_delete = Some((_key.user_key.clone(), _value.clone()));
+                shadows_put = false;
+                return;
+            }
+            inspection.inspect(&_key.user_key, _value);
+            add(_key.user_key.clone(), _key.op_type, _value.clone(), false);
         });
-        let sst = Arc::new(sst_builder.build(
-            sst_id,
-            Some(self.sst_cache.clone()),
-            Db::path_of_sst(self.path.as_ref(), sst_id),
-        )?);
+        if let Some((user_key, value)) = pending_delete.take() {
+            add(user_key, OpType::Delete, value, shadows_put);
+        }
+        if sst_builder.is_empty() {
+            if let Some((user_key, value)) = last_dropped.take() {
+                sst_builder.add(
+                    &EntryBuilder::new()
+                        .op_type(OpType::Delete)
+                        .key_value(user_key, value)
+                        .build(),
+                );
+                dropped_deletes -= 1;
+            }
+        }
+        if dropped_deletes > 0 {
+            debug!(
+                "drop {} deletes shadowing only puts in the flushed memtable",
+                dropped_deletes
+            );
+        }
+        self.key_prefixes.lock().merge(sst_builder.key_prefixes());
+        self.value_inspectors.finish(inspection);
+        let sst = Arc::new(
+            sst_builder
+                .with_max_seq(max_seq)
+                .build(
+                    sst_id,
+                    Some(self.sst_cache.clone()),
+                    Db::path_of_sst(self.path.as_ref(), sst_id),
+                )?
+                .with_filter_loading(self.options.filter_loading),
+        );
         let mut vsst = None;
-        let kv_separate = vsst_builder.size() > 0;
+        let kv_separate = !vsst_builder.is_empty();
         if kv_separate {
-            vsst = Some(Arc::new(vsst_builder.build(
-                vsst_id,
-                Some(self.vsst_cache.clone()),
-                Db::path_of_vsst(self.path.as_ref(), vsst_id),
-            )?));
+            vsst = Some(Arc::new(
+                vsst_builder
+                    .build(
+                        vsst_id,
+                        Some(self.vsst_cache.clone()),
+                        Db::path_of_vsst(self.path.as_ref(), vsst_id),
+                    )?
+                    .with_filter_loading(self.options.filter_loading),
+            ));
+        }
+
+        if self.options.flush_cache_fill.fill_on_write() {
+            let warmed = sst.warm_all()?;
+            debug!("warm {} blocks of {}.SST", warmed, sst_id);
+        }
+
+        // 刷写出的文件和它们的目录项先落盘，之后写入的 MANIFEST 记录才能引用它们并删除 WAL，
+        // 否则掉电后 WAL 已被删除，而 MANIFEST 引用的 SST 可能不完整。严格模式下写入 MANIFEST 前会 fsync 目录
+        sst.sync_all()?;
+        if let Some(vsst) = &vsst {
+            vsst.sync_all()?;
+        }
+        if !self.options.strict_manifest_sync {
+            file::sync_dir(self.path.as_ref())?;
         }
+        fail_point!("flush::before_manifest", |_| Err(anyhow::anyhow!(
+            "injected failure before recording {}.SST",
+            sst_id
+        )));
 
         // 更新 SST 信息到 inner 和写入元数据
         {
             let mut guard = self.inner.write();
             let mut snapshot = guard.as_ref().clone();
-            let mut _old_wal = snapshot.frozen_wal.pop();
-            snapshot.frozen_memtable.pop();
+            // 移除刷写的 memtable 和它的 WAL
+            let _old_wal = snapshot
+                .frozen_wal
+                .iter()
+                .position(|wal| wal.id() == flush_log_id)
+                .map(|idx| snapshot.frozen_wal.remove(idx));
+            snapshot
+                .frozen_memtable
+                .retain(|memtable| !Arc::ptr_eq(memtable, &flush_memtable));
+            self.retired_memtables
+                .lock()
+                .push(Arc::downgrade(&flush_memtable));
             snapshot.levels[0].push(sst);
             let mut vsst_pair_count = 0;
             if let Some(_vsst) = vsst {
@@ -116,8 +309,18 @@ impl DbDaemon {
             }
 
             // 更新元数据
+            let bytes_written = snapshot.levels[0].last().unwrap().size()
+                + if kv_separate {
+                    snapshot.vssts.read()[&vsst_id].size()
+                } else {
+                    0
+                };
+            self.counters.on_flush(bytes_written);
             let mut manifest = self.manifest.write();
             let mut r = RecordBuilder::new();
+            r.add(ManifestItem::Origin(RecordOrigin::now(
+                ManifestActor::Flush,
+            )));
             let level = 0;
             r.add(ManifestItem::NewSst(level, sst_id));
             info!("NEW L{} {}.SST", level, sst_id);
@@ -127,27 +330,71 @@ impl DbDaemon {
                 info!("NEW {}.VSST", vsst_id);
             }
             r.add(ManifestItem::MaxSeqNum(snapshot.seq_num));
+            r.add(ManifestItem::Stats(self.counters.snapshot()));
             if let Some(old_wal) = &_old_wal {
                 r.add(ManifestItem::DelFrozenWal(old_wal.id()));
             }
-            manifest.add(&r.build());
-
-            if let Some(old_wal) = _old_wal {
-                old_wal.delete()?;
+            manifest.add(&r.build())?;
+            // 删除 WAL 之前记录本身也要落盘，严格模式下 `add` 已经 fsync
+            if _old_wal.is_some() && !self.options.strict_manifest_sync {
+                manifest.sync_all()?;
             }
 
-            let l0_compaction = snapshot.levels[0].len() > L0_SST_NUM_LIMIT;
-
+            // 按刷写后的各层分数决定是否触发合并
+            self.schedule_compaction(&snapshot.levels);
+            self.schedule_eviction(&snapshot);
             *guard = Arc::new(snapshot);
 
-            // L0 SST 数量过多，触发合并
-            if l0_compaction {
-                if let Err(e) = self.compaction_chan.0.try_send(0) {
-                    warn!("send compaction message failed {}", e);
-                }
+            // 新状态发布后再删除 WAL，删除失败时内存中的状态仍与 MANIFEST 一致
+            if let Some(old_wal) = _old_wal {
+                fail_point!("flush::before_delete_wal", |_| Err(anyhow::anyhow!(
+                    "injected failure before deleting {:?}",
+                    old_wal.segment_paths()
+                )));
+                self.delete_wal(&old_wal)?;
             }
         }
 
         Ok(())
     }
+
+    /// 取出提前创建的 WAL，没有准备好（第一次冻结或上次创建失败）时现在创建，
+    /// 并在开头写入当前的幂等 token，返回 WAL 和写入到的 token 累计插入数
+    fn take_next_wal(&self, log_id: u64) -> anyhow::Result<(Journal, u64)> {
+        let wal = match self.next_wal.lock().take() {
+            Some(wal) if wal.id() == log_id => wal,
+            _ => Db::open_wal(self.path.as_ref(), log_id, 1, &self.options)?,
+        };
+        let carried = self.carry_tokens(&wal, 0)?;
+        Ok((wal, carried))
+    }
+
+    /// 在新 WAL 中延续累计插入 `carried` 个之后的幂等 token，旧 WAL 删除后重启仍能识别重复的批次，
+    /// 返回延续到的累计插入数
+    fn carry_tokens(&self, wal: &Journal, carried: u64) -> anyhow::Result<u64> {
+        let (entries, inserted) = {
+            let inner = self.inner.read();
+            let tokens = inner.idempotency_tokens.lock();
+            (tokens.entries_since(carried), tokens.inserted())
+        };
+        if !entries.is_empty() {
+            wal.write(entries)?;
+            wal.flush()?;
+        }
+        Ok(inserted)
+    }
+
+    /// 为下一次冻结提前创建 WAL，失败时只记录日志，下一次冻结时再创建
+    ///
+    /// 创建的 WAL 在下一次冻结取出之前不会写入记录，也不在 MANIFEST 中，重启后下一次冻结时会重新打开这个文件
+    fn prepare_next_wal(&self, log_id: u64) {
+        match Db::open_wal(self.path.as_ref(), log_id, 1, &self.options) {
+            Ok(wal) => *self.next_wal.lock() = Some(wal),
+            Err(e) => warn!(
+                "create {:?} in advance failed: {:?}",
+                Db::path_of_wal(self.path.as_ref(), log_id),
+                e
+            ),
+        }
+    }
 }
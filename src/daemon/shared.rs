@@ -0,0 +1,310 @@
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::Arc;
+use std::thread::{self, JoinHandle};
+
+use anyhow::Context;
+use parking_lot::{Condvar, Mutex};
+use tracing::{error, Span};
+
+use crate::cooperative::YieldScope;
+use crate::daemon::DbDaemon;
+
+/// 多个数据库共用的刷写和合并线程池，见 [`crate::DbOptions::shared_scheduler`]
+///
+/// 适合一个进程中打开大量小数据库（例如每个租户一个）的场景：每个数据库不再各自启动刷写和合并线程，
+/// 后台线程数不随数据库数量增长。删除文件、定期 fsync WAL 和定期合并的线程仍由各个数据库自己启动。
+///
+/// 调度规则：
+///
+/// - 所有数据库的刷写先于合并执行，刷写阻塞写入，合并只是整理数据
+/// - 同一类任务在数据库之间轮流执行，一个数据库的大量合并请求不会让其他数据库一直等待
+/// - 每个数据库同时执行的任务不超过它的 [`crate::DbOptions::scheduler_quota`]，
+///   且与独立的后台线程相同，同一个数据库同时最多一个刷写和一个合并
+///
+/// 数据库关闭时退出调度，等待它正在执行的任务完成，已请求的刷写在关闭的线程上执行完，排队的合并取消。
+/// 打开的数据库持有调度器，所有数据库关闭并释放后调度器才会 drop 并等待工作线程退出
+///
+/// ```
+/// use std::sync::Arc;
+///
+/// use bytes::Bytes;
+/// use lasagnedb::{Db, DbOptions, SharedScheduler};
+///
+/// let scheduler = Arc::new(SharedScheduler::new(2).unwrap());
+/// let dirs: Vec<_> = (0..4).map(|_| tempfile::tempdir().unwrap()).collect();
+/// let dbs: Vec<_> = dirs
+///     .iter()
+///     .map(|dir| {
+///         let options = DbOptions {
+///             shared_scheduler: Some(scheduler.clone()),
+///             ..Default::default()
+///         };
+///         Db::open_file_with_options(dir.path(), options).unwrap()
+///     })
+///     .collect();
+/// assert_eq!(scheduler.num_of_members(), 4);
+/// dbs[0].put(Bytes::from("k"), Bytes::from("v")).unwrap();
+/// for db in &dbs {
+///     db.close().unwrap();
+/// }
+/// assert_eq!(scheduler.num_of_members(), 0);
+/// ```
+pub struct SharedScheduler {
+    core: Arc<SchedulerCore>,
+    workers: Mutex<Vec<JoinHandle<()>>>,
+}
+
+impl SharedScheduler {
+    /// 启动 `threads` 个工作线程，至少为 1
+    pub fn new(threads: usize) -> anyhow::Result<Self> {
+        let scheduler = Self {
+            core: Arc::new(SchedulerCore::default()),
+            workers: Mutex::new(vec![]),
+        };
+        for i in 0..threads.max(1) {
+            let core = scheduler.core.clone();
+            let handle = thread::Builder::new()
+                .name(format!("lasagnedb-shared-{}", i))
+                .spawn(move || core.work())
+                .context("spawn shared scheduler thread failed")?;
+            scheduler.workers.lock().push(handle);
+        }
+        Ok(scheduler)
+    }
+
+    /// 工作线程的数量
+    pub fn threads(&self) -> usize {
+        self.workers.lock().len()
+    }
+
+    /// 使用该调度器、尚未关闭的数据库的数量
+    pub fn num_of_members(&self) -> usize {
+        self.core.state.lock().members.len()
+    }
+
+    /// 加入调度，队列中已有的请求立即可以被执行；任务在 `span` 中执行，按 `yield_interval` 让出 CPU
+    pub(crate) fn join(
+        &self,
+        daemon: Arc<DbDaemon>,
+        quota: usize,
+        span: Span,
+        yield_interval: Option<u64>,
+    ) -> SchedulerMembership {
+        let _ = daemon.scheduler.set(self.core.clone());
+        let id = {
+            let mut state = self.core.state.lock();
+            state.next_id += 1;
+            let id = state.next_id;
+            state.members.push(Member {
+                id,
+                daemon,
+                quota: quota.max(1),
+                span,
+                yield_interval,
+                flushing: false,
+                compacting: false,
+                leaving: false,
+            });
+            id
+        };
+        self.core.changed.notify_all();
+        SchedulerMembership {
+            core: self.core.clone(),
+            id,
+        }
+    }
+}
+
+impl Drop for SharedScheduler {
+    fn drop(&mut self) {
+        self.core.state.lock().shutdown = true;
+        self.core.changed.notify_all();
+        for handle in std::mem::take(&mut *self.workers.lock()) {
+            if handle.thread().id() == thread::current().id() {
+                continue;
+            }
+            if handle.join().is_err() {
+                error!("shared scheduler thread panicked");
+            }
+        }
+    }
+}
+
+impl std::fmt::Debug for SharedScheduler {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("SharedScheduler")
+            .field("threads", &self.threads())
+            .field("members", &self.num_of_members())
+            .finish()
+    }
+}
+
+/// 一个数据库在共享调度器中的登记，见 [`SharedScheduler::join`]
+#[derive(Debug)]
+pub(crate) struct SchedulerMembership {
+    core: Arc<SchedulerCore>,
+    id: u64,
+}
+
+impl SchedulerMembership {
+    /// 退出调度：不再为该数据库取出新的请求，等待正在执行的任务完成后返回，队列中剩下的请求由调用方处理
+    pub(crate) fn leave(self) {
+        let mut state = self.core.state.lock();
+        if let Some(member) = state.members.iter_mut().find(|m| m.id == self.id) {
+            member.leaving = true;
+        }
+        while state
+            .members
+            .iter()
+            .any(|m| m.id == self.id && m.running() > 0)
+        {
+            self.core.changed.wait(&mut state);
+        }
+        state.members.retain(|m| m.id != self.id);
+    }
+}
+
+#[derive(Default)]
+pub(crate) struct SchedulerCore {
+    state: Mutex<SchedulerState>,
+    // 有新的请求、任务完成或调度器关闭
+    changed: Condvar,
+}
+
+#[derive(Default)]
+struct SchedulerState {
+    members: Vec<Member>,
+    next_id: u64,
+    // 下一次从这个位置开始寻找请求，实现轮流执行
+    cursor: usize,
+    shutdown: bool,
+}
+
+struct Member {
+    id: u64,
+    daemon: Arc<DbDaemon>,
+    quota: usize,
+    span: Span,
+    yield_interval: Option<u64>,
+    flushing: bool,
+    compacting: bool,
+    leaving: bool,
+}
+
+impl Member {
+    fn running(&self) -> usize {
+        self.flushing as usize + self.compacting as usize
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+enum Job {
+    Flush,
+    Compaction(u32),
+}
+
+impl SchedulerState {
+    /// 取出下一个可以执行的请求并标记为执行中，所有数据库的刷写先于合并，同类请求从 `cursor` 开始轮流取
+    fn pick(&mut self) -> Option<(usize, Job)> {
+        let n = self.members.len();
+        for flush in [true, false] {
+            for i in 0..n {
+                let idx = (self.cursor + i) % n;
+                let member = &mut self.members[idx];
+                if member.leaving || member.running() >= member.quota {
+                    continue;
+                }
+                let job = if flush {
+                    if member.flushing || !member.daemon.take_flush_request() {
+                        continue;
+                    }
+                    member.flushing = true;
+                    Job::Flush
+                } else {
+                    if member.compacting {
+                        continue;
+                    }
+                    let Some(level) = member.daemon.take_compaction_request() else {
+                        continue;
+                    };
+                    member.compacting = true;
+                    Job::Compaction(level)
+                };
+                self.cursor = idx + 1;
+                return Some((idx, job));
+            }
+        }
+        None
+    }
+}
+
+impl SchedulerCore {
+    /// 数据库发出请求后调用，先拿一次锁，避免在工作线程检查完队列、开始等待之前通知而丢失唤醒
+    pub(crate) fn notify(&self) {
+        drop(self.state.lock());
+        self.changed.notify_all();
+    }
+
+    fn work(&self) {
+        loop {
+            let (id, job, daemon, span, yield_interval) = {
+                let mut state = self.state.lock();
+                loop {
+                    if state.shutdown {
+                        return;
+                    }
+                    if let Some((idx, job)) = state.pick() {
+                        let member = &state.members[idx];
+                        break (
+                            member.id,
+                            job,
+                            member.daemon.clone(),
+                            member.span.clone(),
+                            member.yield_interval,
+                        );
+                    }
+                    self.changed.wait(&mut state);
+                }
+            };
+
+            // 任务 panic 时只结束这个任务，工作线程继续为其他数据库服务
+            let result = panic::catch_unwind(AssertUnwindSafe(|| {
+                let _yield = YieldScope::enter(yield_interval);
+                let _enter = span.enter();
+                match job {
+                    Job::Flush => {
+                        if let Err(err) = daemon.run_flush_request() {
+                            error!("rotate failed: {}", err)
+                        }
+                    }
+                    Job::Compaction(level) => {
+                        if let Err(err) = daemon.run_compaction_request(level) {
+                            error!("compaction failed: {}", err)
+                        }
+                    }
+                }
+            }));
+            if result.is_err() {
+                error!("background task {:?} panicked", job);
+            }
+
+            let mut state = self.state.lock();
+            if let Some(member) = state.members.iter_mut().find(|m| m.id == id) {
+                match job {
+                    Job::Flush => member.flushing = false,
+                    Job::Compaction(_) => member.compacting = false,
+                }
+            }
+            drop(state);
+            self.changed.notify_all();
+        }
+    }
+}
+
+impl std::fmt::Debug for SchedulerCore {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("SchedulerCore")
+            .field("members", &self.state.lock().members.len())
+            .finish()
+    }
+}
@@ -1,11 +1,13 @@
-use crate::daemon::DbDaemon;
+use crate::cache::{BlockCache, CacheFillPolicy};
+use crate::daemon::{DbDaemon, MergeParams};
 use crate::entry::{Entry, EntryBuilder};
-use crate::sstable::builder::{SsTable, SsTableBuilder};
+use crate::inspect::Inspection;
+use crate::sstable::builder::{FilterLoading, SsTable, SsTableBuilder};
 use crate::sstable::iterator::SsTableIterator;
-use crate::{OpType, StorageIterator};
-use bytes::Bytes;
+use crate::storage::header::FileType;
+use crate::{OpType, StorageIterator, L0_SST_NUM_LIMIT, MAX_LEVEL_SIZE};
+use bytes::{Buf, Bytes};
 use lazy_static::lazy_static;
-use moka::sync::Cache;
 use parking_lot::RwLock;
 use std::collections::HashMap;
 use std::env::join_paths;
@@ -28,7 +30,7 @@ fn map_to_string(num: u32) -> String {
     result
 }
 
-fn generate_rang_sst(path: impl AsRef<Path>, id: u32, from: u32, to: u32) -> Arc<SsTable> {
+fn generate_rang_sst(path: impl AsRef<Path>, id: u64, from: u32, to: u32) -> Arc<SsTable> {
     let mut b = SsTableBuilder::new();
 
     for i in from..=to {
@@ -59,7 +61,7 @@ fn test_select_overlap_sst() {
     levels[1].push(generate_rang_sst(base_path, 8, 60, 200)); // be picked
     levels[1].push(generate_rang_sst(base_path, 9, 201, 300));
 
-    let res = DbDaemon::select_overlap_sst(&levels, 0, levels[0][0].clone());
+    let res = DbDaemon::select_overlap_sst(&levels, 0, levels[0][0].clone(), None);
     assert_eq!(res.0.len(), 4);
     res.0
         .iter()
@@ -81,16 +83,19 @@ fn test_merge() {
     levels.push(generate_rang_sst(base_path, 2, 3, 4));
     levels.push(generate_rang_sst(base_path, 3, 1, 2));
 
-    let temp_cache = Arc::new(Cache::new(0));
-    let (mut new_ssts, _, _) = DbDaemon::merge(
-        base_path,
-        1,
+    let temp_cache = Arc::new(BlockCache::new(0));
+    let (mut new_ssts, _, _, _) = DbDaemon::merge(
+        MergeParams {
+            now_sst_id: 1,
+            now_vsst_id: 1,
+            vssts: vsst.clone(),
+            filter_loading: FilterLoading::Eager,
+            max_migration_size: u64::MAX,
+            cache_fill: CacheFillPolicy::OnWrite,
+            ..MergeParams::new(base_path, temp_cache.clone())
+        },
         levels,
-        temp_cache.clone(),
-        1,
-        vsst.clone(),
-        temp_cache.clone(),
-        Arc::new(RwLock::new(HashMap::default())),
+        &mut Inspection::disabled(),
     )
     .unwrap();
     assert_eq!(new_ssts.len(), 1);
@@ -102,3 +107,390 @@ fn test_merge() {
     }
     assert!(!iter.is_valid());
 }
+
+#[test]
+fn test_plan_compaction() {
+    let tempdir = tempfile::tempdir().unwrap();
+    let base_path = tempdir.path();
+
+    let mut levels = vec![vec![]; 6];
+    levels[0].push(generate_rang_sst(base_path, 1, 2, 100));
+    levels[0].push(generate_rang_sst(base_path, 2, 15, 70));
+    levels[0].push(generate_rang_sst(base_path, 3, 201, 300));
+    levels[1].push(generate_rang_sst(base_path, 4, 1, 50));
+    levels[1].push(generate_rang_sst(base_path, 5, 150, 200));
+
+    let plan = DbDaemon::plan_compaction(&levels, 0, 1, None).unwrap();
+    assert_eq!(plan.level, 0);
+    assert_eq!(plan.output_level, 1);
+    // 新的 L0 SST 排在前面
+    assert_eq!(plan.level_inputs, vec![2, 1]);
+    assert_eq!(plan.output_level_inputs, vec![4]);
+    let input_bytes = levels[0][0].size() + levels[0][1].size() + levels[1][0].size();
+    assert_eq!(plan.input_bytes, input_bytes);
+    assert_eq!(plan.estimated_output_files, 1);
+
+    // 空层和最后一层都没有可执行的合并
+    assert!(DbDaemon::plan_compaction(&levels, 2, 3, None).is_none());
+    assert!(DbDaemon::plan_compaction(&levels, 5, 6, None).is_none());
+}
+
+#[test]
+fn test_plan_compaction_to_output_level() {
+    let tempdir = tempfile::tempdir().unwrap();
+    let base_path = tempdir.path();
+
+    let mut levels = vec![vec![]; 6];
+    levels[0].push(generate_rang_sst(base_path, 1, 2, 100));
+    levels[1].push(generate_rang_sst(base_path, 4, 1, 50));
+    levels[2].push(generate_rang_sst(base_path, 6, 40, 150));
+    levels[2].push(generate_rang_sst(base_path, 7, 200, 300));
+    levels[3].push(generate_rang_sst(base_path, 8, 140, 160));
+    levels[3].push(generate_rang_sst(base_path, 9, 301, 400));
+
+    let plan = DbDaemon::plan_compaction(&levels, 0, 3, None).unwrap();
+    assert_eq!(plan.output_level, 3);
+    assert_eq!(plan.level_inputs, vec![1]);
+    // L2 的 SST 扩大了 key 范围，L3 中与扩大后的范围重叠的 SST 也参与合并
+    assert_eq!(plan.intermediate_inputs, vec![(1, vec![4]), (2, vec![6])]);
+    assert_eq!(plan.output_level_inputs, vec![8]);
+
+    assert!(DbDaemon::plan_compaction(&levels, 0, 0, None).is_none());
+    assert!(DbDaemon::plan_compaction(&levels, 0, 6, None).is_none());
+}
+
+#[test]
+fn test_max_compaction_bytes() {
+    let tempdir = tempfile::tempdir().unwrap();
+    let base_path = tempdir.path();
+
+    let mut levels = vec![vec![]; 6];
+    levels[1].push(generate_rang_sst(base_path, 1, 1, 50));
+    levels[1].push(generate_rang_sst(base_path, 2, 60, 100));
+    levels[2].push(generate_rang_sst(base_path, 3, 40, 70));
+
+    let plan = DbDaemon::plan_compaction(&levels, 1, 2, None).unwrap();
+    assert_eq!(plan.level_inputs, vec![2, 1]);
+    assert_eq!(plan.output_level_inputs, vec![3]);
+
+    // 超过上限时不再加入 L1 中只与 L2 输入重叠的 SST
+    let max_bytes = levels[1][0].size() + levels[2][0].size();
+    let plan = DbDaemon::plan_compaction(&levels, 1, 2, Some(max_bytes)).unwrap();
+    assert_eq!(plan.level_inputs, vec![1]);
+    assert_eq!(plan.output_level_inputs, vec![3]);
+    assert_eq!(plan.input_bytes, max_bytes);
+
+    // 与基准 SST 重叠的文件不受上限影响
+    let plan = DbDaemon::plan_compaction(&levels, 1, 2, Some(1)).unwrap();
+    assert_eq!(plan.output_level_inputs, vec![3]);
+}
+
+#[test]
+fn test_pick_compaction_level() {
+    let tempdir = tempfile::tempdir().unwrap();
+    let base_path = tempdir.path();
+
+    let mut levels = vec![vec![]; 6];
+    assert_eq!(
+        DbDaemon::pick_compaction_level(&levels, L0_SST_NUM_LIMIT),
+        None
+    );
+
+    for id in 1..=L0_SST_NUM_LIMIT as u64 {
+        levels[0].push(generate_rang_sst(base_path, id, 1, 10));
+    }
+    // L0 刚好达到数量限制时不需要合并
+    assert_eq!(DbDaemon::level_scores(&levels, L0_SST_NUM_LIMIT)[0], 1.0);
+    assert_eq!(
+        DbDaemon::pick_compaction_level(&levels, L0_SST_NUM_LIMIT),
+        None
+    );
+    levels[0].push(generate_rang_sst(base_path, 10, 1, 10));
+    assert_eq!(
+        DbDaemon::pick_compaction_level(&levels, L0_SST_NUM_LIMIT),
+        Some(0)
+    );
+
+    // 分数更高的层优先合并
+    let sst = generate_rang_sst(base_path, 11, 1, 10);
+    let count = (MAX_LEVEL_SIZE[1] * 2 / sst.size()) as usize;
+    levels[1] = vec![sst; count];
+    let scores = DbDaemon::level_scores(&levels, L0_SST_NUM_LIMIT);
+    assert!(scores[1] > scores[0]);
+    assert_eq!(
+        DbDaemon::pick_compaction_level(&levels, L0_SST_NUM_LIMIT),
+        Some(1)
+    );
+
+    // 最后一层无法合并
+    let sst = generate_rang_sst(base_path, 12, 1, 10);
+    levels[5] = vec![sst; 100];
+    assert_eq!(DbDaemon::level_scores(&levels, L0_SST_NUM_LIMIT)[5], 0.0);
+    assert_eq!(
+        DbDaemon::pick_compaction_level(&levels, L0_SST_NUM_LIMIT),
+        Some(1)
+    );
+
+    // 超出限制的字节数
+    let level1_size: u64 = levels[1].iter().map(|sst| sst.size()).sum();
+    assert_eq!(
+        DbDaemon::level_overflow(&levels, 1),
+        level1_size - MAX_LEVEL_SIZE[1]
+    );
+    assert_eq!(
+        DbDaemon::compaction_debt(&levels),
+        level1_size - MAX_LEVEL_SIZE[1]
+    );
+    assert_eq!(DbDaemon::bottom_level_overflow(&levels), 0);
+}
+
+#[test]
+fn test_merge_warm_cache() {
+    let tempdir = tempfile::tempdir().unwrap();
+    let base_path = tempdir.path();
+    let cache = Arc::new(BlockCache::new(1 << 20));
+
+    let mut b = SsTableBuilder::new();
+    for i in 1..=300 {
+        b.add(&generate_entry(Bytes::from(map_to_string(i)), Bytes::new()));
+    }
+    let sst = Arc::new(
+        b.build(1, Some(cache.clone()), base_path.join("1.sst"))
+            .unwrap(),
+    );
+    assert!(sst.num_of_blocks() > 4);
+    let hot_block = sst.num_of_blocks() / 2;
+    sst.read_block(hot_block).unwrap();
+    let hot_range = sst.cached_key_ranges();
+    assert_eq!(hot_range.len(), 1);
+
+    let (new_ssts, _, _, _) = DbDaemon::merge(
+        MergeParams {
+            now_sst_id: 1,
+            now_vsst_id: 1,
+            filter_loading: FilterLoading::Eager,
+            max_migration_size: u64::MAX,
+            cache_fill: CacheFillPolicy::OnWrite,
+            ..MergeParams::new(base_path, cache.clone())
+        },
+        vec![sst],
+        &mut Inspection::disabled(),
+    )
+    .unwrap();
+    let new_sst = &new_ssts[0];
+    let cached = new_sst.cached_key_ranges();
+    // 只有覆盖热点块的输出块被读入缓存
+    assert!(!cached.is_empty() && cached.len() < new_sst.num_of_blocks());
+    assert!(cached
+        .iter()
+        .all(|(first, last)| first <= &hot_range[0].1 && &hot_range[0].0 <= last));
+}
+
+fn generate_separated_sst(
+    path: impl AsRef<Path>,
+    vsst_id: u64,
+    num: u32,
+) -> (Arc<SsTable>, Arc<SsTable>) {
+    let mut b = SsTableBuilder::new();
+    let mut vb = SsTableBuilder::new().with_file_type(FileType::VSst);
+    for i in 1..=num {
+        let key = Bytes::from(format!("key_{:03}", i));
+        let value = Bytes::from(format!("value_{:03}", i).repeat(10));
+        let mut eb = EntryBuilder::new();
+        b.add(
+            &eb.op_type(OpType::Put)
+                .kv_separate(true)
+                .key_value(
+                    key.clone(),
+                    Entry::separated_value(vsst_id, value.len() as u64),
+                )
+                .build(),
+        );
+        vb.add(&generate_entry(key, value));
+    }
+    let sst = b.build(1, None, path.as_ref().join("1.sst")).unwrap();
+    let vsst = vb
+        .build(
+            vsst_id,
+            None,
+            path.as_ref().join(format!("{}.vsst", vsst_id)),
+        )
+        .unwrap();
+    (Arc::new(sst), Arc::new(vsst))
+}
+
+#[test]
+fn test_merge_migrate_vsst() {
+    let tempdir = tempfile::tempdir().unwrap();
+    let base_path = tempdir.path();
+    let num = 200;
+    let (sst, vsst) = generate_separated_sst(base_path, 1, num);
+    let value_size = vsst.size();
+
+    let merge = |max_migration_size: u64| {
+        let vssts = Arc::new(RwLock::new(HashMap::from([(1, vsst.clone())])));
+        let vsst_rc = Arc::new(RwLock::new(HashMap::from([(1, num)])));
+        let cache = Arc::new(BlockCache::new(0));
+        DbDaemon::merge(
+            MergeParams {
+                now_sst_id: 1,
+                now_vsst_id: 1,
+                vssts,
+                vsst_rc,
+                filter_loading: FilterLoading::Eager,
+                max_migration_size,
+                cache_fill: CacheFillPolicy::OnWrite,
+                ..MergeParams::new(base_path, cache)
+            },
+            vec![sst.clone()],
+            &mut Inspection::disabled(),
+        )
+        .unwrap()
+    };
+
+    // 不限制迁移量时全部 value 迁移到新 VSST
+    let (new_ssts, new_vssts, delta, _) = merge(u64::MAX);
+    assert_eq!(new_vssts.len(), 1);
+    assert_eq!(new_vssts[0].id(), 2);
+    assert_eq!(new_vssts[0].num_of_pairs(), num as usize);
+    assert_eq!(delta[&1], -(num as i32));
+    assert_eq!(delta[&2], num as i32);
+    let mut iter = SsTableIterator::create_and_seek_to_first(new_ssts[0].clone()).unwrap();
+    let mut viter = SsTableIterator::create_and_seek_to_first(new_vssts[0].clone()).unwrap();
+    for i in 1..=num {
+        let key = format!("key_{:03}", i);
+        assert_eq!(iter.key(), key.as_bytes());
+        assert_eq!((&iter.value()[..4]).get_u32_le(), 2);
+        assert_eq!(viter.key(), key.as_bytes());
+        assert_eq!(
+            viter.value(),
+            format!("value_{:03}", i).repeat(10).as_bytes()
+        );
+        iter.next().unwrap();
+        viter.next().unwrap();
+    }
+    assert!(!iter.is_valid());
+    assert!(!viter.is_valid());
+
+    // 达到迁移上限后剩余的 value 留在原 VSST 中
+    let (new_ssts, new_vssts, delta, _) = merge(value_size / 4);
+    let migrated = new_vssts[0].num_of_pairs() as u32;
+    assert!(migrated > 0 && migrated < num);
+    assert_eq!(delta[&1], -(migrated as i32));
+    let mut iter = SsTableIterator::create_and_seek_to_first(new_ssts[0].clone()).unwrap();
+    for i in 1..=num {
+        let expected_vsst_id = if i <= migrated { 2 } else { 1 };
+        assert_eq!((&iter.value()[..4]).get_u32_le(), expected_vsst_id);
+        iter.next().unwrap();
+    }
+}
+
+#[test]
+fn test_merge_inline_separated_values() {
+    let tempdir = tempfile::tempdir().unwrap();
+    let base_path = tempdir.path();
+    let num = 200;
+    let (sst, vsst) = generate_separated_sst(base_path, 1, num);
+    let vssts = Arc::new(RwLock::new(HashMap::from([(1, vsst)])));
+    let vsst_rc = Arc::new(RwLock::new(HashMap::from([(1, num)])));
+    let cache = Arc::new(BlockCache::new(0));
+
+    // value 长度为 90，不超过阈值时全部读回 SST，不迁移也不输出 VSST
+    let (new_ssts, new_vssts, delta, _) = DbDaemon::merge(
+        MergeParams {
+            now_sst_id: 1,
+            now_vsst_id: 1,
+            vssts,
+            vsst_rc,
+            filter_loading: FilterLoading::Eager,
+            max_migration_size: u64::MAX,
+            inline_threshold: 90,
+            cache_fill: CacheFillPolicy::OnWrite,
+            ..MergeParams::new(base_path, cache)
+        },
+        vec![sst],
+        &mut Inspection::disabled(),
+    )
+    .unwrap();
+    assert!(new_vssts.is_empty());
+    assert_eq!(delta[&1], -(num as i32));
+    let mut iter = SsTableIterator::create_and_seek_to_first(new_ssts[0].clone()).unwrap();
+    for i in 1..=num {
+        assert_eq!(iter.key(), format!("key_{:03}", i).as_bytes());
+        assert!(!Entry::is_separate(iter.meta()));
+        assert_eq!(
+            iter.value(),
+            format!("value_{:03}", i).repeat(10).as_bytes()
+        );
+        iter.next().unwrap();
+    }
+    assert!(!iter.is_valid());
+}
+
+#[test]
+fn test_merge_key_ranges() {
+    let range = |a: &'static str, b: &'static str| (Bytes::from(a), Bytes::from(b));
+    let merged = DbDaemon::merge_key_ranges(vec![
+        range("d", "f"),
+        range("a", "b"),
+        range("e", "g"),
+        range("b", "c"),
+        range("x", "z"),
+    ]);
+    assert_eq!(
+        merged,
+        vec![range("a", "c"), range("d", "g"), range("x", "z")]
+    );
+}
+
+#[test]
+fn test_l0_recency_by_max_seq() {
+    let tempdir = tempfile::tempdir().unwrap();
+    let base_path = tempdir.path();
+    let sst = |id: u64, max_seq: u64, value: &'static str| {
+        let mut b = SsTableBuilder::new().with_max_seq(max_seq);
+        b.add(&generate_entry(Bytes::from("k"), Bytes::from(value)));
+        Arc::new(
+            b.build(id, None, base_path.join(format!("{}.sst", id)))
+                .unwrap(),
+        )
+    };
+
+    // id 更大的 SST 中的数据反而更旧，以最大 seq num 为准
+    let mut levels = vec![vec![]; 6];
+    levels[0].push(sst(1, 20, "new"));
+    levels[0].push(sst(2, 10, "old"));
+    let (li_sst, li1_sst) = DbDaemon::select_inputs(&levels, 0, None).unwrap();
+    assert_eq!(
+        li_sst.iter().map(|_sst| _sst.id()).collect::<Vec<_>>(),
+        vec![1, 2]
+    );
+    assert!(li1_sst.is_empty());
+
+    let temp_cache = Arc::new(BlockCache::new(0));
+    let (new_ssts, _, _, _) = DbDaemon::merge(
+        MergeParams {
+            now_sst_id: 2,
+            filter_loading: FilterLoading::Eager,
+            max_migration_size: u64::MAX,
+            cache_fill: CacheFillPolicy::OnWrite,
+            ..MergeParams::new(base_path, temp_cache)
+        },
+        li_sst,
+        &mut Inspection::disabled(),
+    )
+    .unwrap();
+    assert_eq!(new_ssts.len(), 1);
+    assert_eq!(new_ssts[0].max_seq(), Some(20));
+    let iter = SsTableIterator::create_and_seek_to_first(new_ssts[0].clone()).unwrap();
+    assert_eq!(iter.value(), b"new");
+
+    // 最大 seq num 相同时 id 更大的更新，没有记录最大 seq num 的旧文件按 0 比较
+    levels[0].push(sst(3, 0, "legacy"));
+    levels[0].push(sst(4, 20, "newest"));
+    let (li_sst, _) = DbDaemon::select_inputs(&levels, 0, None).unwrap();
+    assert_eq!(
+        li_sst.iter().map(|_sst| _sst.id()).collect::<Vec<_>>(),
+        vec![4, 1, 2, 3]
+    );
+}
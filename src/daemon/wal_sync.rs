@@ -0,0 +1,29 @@
+use crate::daemon::DbDaemon;
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+use tracing::{instrument, trace};
+
+impl DbDaemon {
+    /// fsync 当前和已冻结的 WAL，返回并记录之后的持久化水位：不超过该序号且写入了 WAL 的修改掉电后都能恢复
+    ///
+    /// 序号在写入 WAL 之后才分配，先读取已分配的最大序号再取 WAL 列表，该序号及之前的记录要么在列表中的 WAL 里，
+    /// 要么所在的 WAL 已刷写到 SST
+    #[instrument(skip_all)]
+    pub(crate) fn sync_wal(&self) -> anyhow::Result<u64> {
+        let seq = self.inner.read().commit_seq.last_allocated();
+        let inner = {
+            let guard = self.inner.read();
+            Arc::clone(&guard)
+        };
+        for wal in inner.frozen_wal.iter().chain([&inner.wal]) {
+            wal.sync_all()?;
+        }
+        let durable = self.durable_seq.fetch_max(seq, Ordering::AcqRel).max(seq);
+        trace!("sync WAL {}, durable seq {}", inner.wal.id(), durable);
+        Ok(durable)
+    }
+
+    pub(crate) fn durable_seq(&self) -> u64 {
+        self.durable_seq.load(Ordering::Acquire)
+    }
+}
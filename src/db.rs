@@ -7,37 +7,79 @@ use std::fmt::Debug;
 use std::ops::Bound;
 use std::os::unix::ffi::OsStrExt;
 use std::path::{Path, PathBuf};
-use std::sync::atomic::AtomicU64;
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
 use std::sync::Arc;
+use std::thread::JoinHandle;
+use std::time::{Duration, Instant};
 use std::{fs, thread};
 
-use anyhow::Context;
+use anyhow::{anyhow, Context};
 use bytes::Bytes;
 
 use crossbeam::channel;
 
-use parking_lot::RwLock;
+use parking_lot::{Mutex, RwLock};
+use thiserror::Error;
 
-use tracing::{debug, error, instrument, span, trace, warn};
+use tracing::{debug, error, info, instrument, span, trace, warn};
+use xxhash_rust::xxh3::xxh3_64;
 
-use crate::cache::BlockCache;
-use crate::{Key, OpType, BLOCK_CACHE_SIZE, MEMTABLE_SIZE_LIMIT, SST_LEVEL_LIMIT};
+use crate::admin::{self, CopyReport, RepairSession};
+use crate::batch::{IdempotencyToken, IdempotencyTokens, WriteBatch};
+use crate::cache::{BlockCache, CacheFillPolicy};
+use crate::cdc::{self, CdcSink, ChangeLog};
+use crate::contention::TrackedRwLock;
+use crate::cooperative::{cooperative_yields, LoopCheckpoint, YieldScope};
+use crate::system::is_system_key;
+use crate::{
+    keys, Key, OpType, BACKGROUND_LAG_LIMIT, BLOCK_CACHE_SIZE, BLOCK_SIZE, CDC_DELIVERY_BATCH,
+    CDC_POLL_INTERVAL, CDC_RETRY_BACKOFF, CDC_RETRY_MAX_BACKOFF, COMPACTION_COMPRESSION,
+    COMPACTION_DEBT_STALL_LIMIT, FROZEN_LIMIT_POLL_INTERVAL, IDEMPOTENCY_TOKEN_LIMIT,
+    L0_SST_NUM_LIMIT, LOW_PRIORITY_WRITE_DELAY, MAX_SEQ_NUM, MAX_VALUE_SIZE, MEMTABLE_SIZE_LIMIT,
+    MIN_VSST_SIZE, MULTI_GET_THREADS, PERIODIC_COMPACTION_CHECK_INTERVAL, RECOVERY_OPEN_THREADS,
+    SCHEDULER_QUOTA, SEEK_MISS_COMPACTION_THRESHOLD, SST_LEVEL_LIMIT, TRASH_PURGE_INTERVAL,
+    VALUE_INSPECTION_SAMPLING, VSST_BLOCK_SIZE, WAL_SEGMENT_AGE_LIMIT, WAL_SEGMENT_SIZE_LIMIT,
+    YIELD_INTERVAL, ZSTD_DICT_SIZE,
+};
 
-use crate::daemon::DbDaemon;
-use crate::db_iterator::{DbIterator, FusedIterator};
-use crate::entry::EntryBuilder;
-use crate::iterator::merge_iterator::MergeIterator;
-use crate::iterator::two_merge_iterator::TwoMergeIterator;
+use crate::daemon::{
+    CompactionJob, CompactionPlan, DaemonParams, DbDaemon, ObsoleteFile, SchedulerMembership,
+    SharedScheduler,
+};
+use crate::db_iterator::{DbIterator, FusedIterator, ScanChunk, TailIterator};
+use crate::entry::{Entry, EntryBuilder};
+use crate::export::{self, ExportReport};
+use crate::inspect::ValueMetrics;
 use crate::iterator::StorageIterator;
+use crate::key_lock::KeyLocks;
+use crate::memory_limit;
 use crate::memtable::MemTable;
-use crate::meta::iterator::ManifestIterator;
-use crate::meta::manifest::{Manifest, ManifestItem};
+use crate::meta::manifest::{Manifest, ManifestActor, ManifestItem, ManifestState, RecordOrigin};
+use crate::micro_batch::{MicroBatchOptions, MicroBatcher};
+use crate::negative_cache::NegativeCache;
+use crate::perf_context::{self, PerfContextScope};
 use crate::record::RecordBuilder;
-use crate::sstable::builder::SsTable;
-use crate::sstable::iterator::VSsTableIterator;
-use crate::storage::file::FileStorage;
+use crate::registry::{self, Registration};
+use crate::sequence::CommitSequence;
+use crate::snapshot::{Snapshot, SnapshotTracker};
+use crate::sstable::builder::{
+    checksum_failures, verified_blocks, BlockReadOptions, FilterLoading, KeyOrderCheck, SsTable,
+};
+use crate::sstable::compression::CompressionType;
+use crate::sstable::iterator::{MissingVSsts, SsTableIterator};
+use crate::staging::ResultStaging;
+use crate::stats::{
+    ActiveIterator, CumulativeStats, DbStats, Health, HealthStatus, LevelMetadata,
+    LockContentionStats, MemoryUsage, PrefixAdvice, RecoveryStats, SstMetadata, StallCause,
+    WriteStallCounters,
+};
+use crate::storage::file::{self, FileStorage, IoPriority};
+use crate::storage::header::FileType;
+use crate::trace::{TraceOptions, TraceSummary, Tracer};
+use crate::validate::{self, Rejection, Validators, WriteOp};
 use crate::wal::iterator::JournalIterator;
-use crate::wal::Journal;
+use crate::wal::{Journal, JournalOptions, WalProtection};
+use crate::watch::{WatchEvent, Watchers};
 use crate::OpType::{Delete, Get, Put};
 
 #[derive(Clone, Debug)]
@@ -48,66 +90,666 @@ pub(crate) struct DbInner {
     pub(crate) frozen_memtable: Vec<Arc<MemTable>>,
 
     pub(crate) levels: Vec<Vec<Arc<SsTable>>>,
-    pub(crate) vssts: Arc<RwLock<HashMap<u32, Arc<SsTable>>>>,
-    pub(crate) vsst_rc: Arc<RwLock<HashMap<u32, u32>>>,
+    pub(crate) vssts: Arc<RwLock<HashMap<u64, Arc<SsTable>>>>,
+    pub(crate) vsst_rc: Arc<RwLock<HashMap<u64, u32>>>,
+    /// 打开时文件已不存在的 VSST，见 [`MissingVSstPolicy`]，所有快照共享
+    pub(crate) missing_vssts: Arc<MissingVSsts>,
+    /// 最近应用过的批量写入幂等 token，所有快照共享
+    pub(crate) idempotency_tokens: Arc<Mutex<IdempotencyTokens>>,
+    /// 写入的提交序号，所有快照共享
+    pub(crate) commit_seq: Arc<CommitSequence>,
 
     pub(crate) seq_num: u64,
-    pub(crate) log_id: u32,
-    pub(crate) sst_id: u32,
-    pub(crate) vsst_id: u32,
+    pub(crate) log_id: u64,
+    pub(crate) sst_id: u64,
+    pub(crate) vsst_id: u64,
+}
+
+impl DbInner {
+    /// 按新旧顺序返回某一层的 SST，越新的越靠前
+    ///
+    /// L0 的 SST 之间 key 范围会重叠，同一个 key 以最大 seq num 最大（最近一次刷写）的 SST 中的版本为准，
+    /// 见 [`SsTable::recency`]；其它层内 SST 互不重叠，顺序不影响结果
+    pub(crate) fn tables_newest_first(&self, level: u32) -> Vec<Arc<SsTable>> {
+        let mut tables = self.levels[level as usize].clone();
+        tables.sort_by_key(|table| std::cmp::Reverse(table.recency()));
+        tables
+    }
 }
 
 #[derive(Debug)]
 pub struct Db {
-    pub(crate) inner: Arc<RwLock<Arc<DbInner>>>,
+    pub(crate) inner: Arc<TrackedRwLock<Arc<DbInner>>>,
 
     path: Arc<PathBuf>,
     version: AtomicU64,
     sst_cache: Arc<BlockCache>,
-    vsst_cache: Arc<BlockCache>,
+    pub(crate) vsst_cache: Arc<BlockCache>,
 
     flush_chan: (channel::Sender<()>, channel::Receiver<()>),
     compaction_chan: (channel::Sender<u32>, channel::Receiver<u32>),
     exit_chan: (channel::Sender<()>, channel::Receiver<()>),
-    daemon: Arc<DbDaemon>,
-    manifest: Arc<RwLock<Manifest>>,
+    pub(crate) daemon: Arc<DbDaemon>,
+    manifest: Arc<TrackedRwLock<Manifest>>,
+    snapshots: Arc<SnapshotTracker>,
+    watchers: Watchers,
+    validators: Validators,
+    pub(crate) key_locks: KeyLocks,
+    next_staging_id: AtomicU64,
+    write_stalls: WriteStallCounters,
+    recovery: RecoveryStats,
+    pub(crate) registration: Registration,
+    options: DbOptions,
+    // 见 `Db::instance_tag`
+    instance_tag: String,
+    // 见 `Db::shutdown`
+    closed: AtomicBool,
+    // 见 `Db::set_read_only`
+    read_only: AtomicBool,
+    pub(crate) background_tasks: Mutex<Vec<JoinHandle<()>>>,
+    // 使用共享调度器时在其中的登记，关闭时退出调度
+    scheduler_membership: Mutex<Option<SchedulerMembership>>,
+    // 开启 `change_capture` 时记录变更日志
+    change_log: Option<ChangeLog>,
+    // 开启 `micro_batch` 时汇集小写入分组提交
+    micro_batch: Option<MicroBatcher>,
+    // 开启 `negative_cache_capacity` 时缓存最近确认不存在的 key
+    negative_cache: Option<NegativeCache>,
+    // 见 `Db::start_trace`
+    tracer: Tracer,
+}
+
+/// 数据库关闭后写入返回的错误
+#[derive(Debug, Clone, Error, PartialEq, Eq)]
+#[error("database is closed")]
+pub struct DbClosedError;
+
+/// [`Db::set_read_only`] 切换为只读后写入返回的错误，写入没有生效
+#[derive(Debug, Clone, Error, PartialEq, Eq)]
+#[error("database is read-only")]
+pub struct ReadOnlyError;
+
+/// 冻结的 memtable 或 WAL 超过上限、[`DbOptions::frozen_limit_action`] 为 [`FrozenLimitAction::Busy`] 时写入返回的错误，
+/// 写入没有生效
+#[derive(Debug, Clone, Error, PartialEq, Eq)]
+#[error("database is busy: {frozen_memtables} frozen memtables, {frozen_wals} frozen WALs waiting for flush")]
+pub struct BusyError {
+    pub frozen_memtables: usize,
+    pub frozen_wals: usize,
+}
+
+impl Drop for Db {
+    fn drop(&mut self) {
+        // 不等待快照释放：快照和迭代器不依赖 `Db`，可以比它活得更久
+        if let Err(err) = self.shutdown() {
+            error!("shutdown failed: {:#}", err);
+        }
+    }
+}
+
+/// 打开数据库的选项，各项默认值与 `db_config` 中的同名常量一致
+#[derive(Debug, Clone)]
+pub struct DbOptions {
+    /// memtable 超过该大小后冻结并刷写到 L0
+    pub memtable_size_limit: usize,
+    /// SST 和 VSST 各自的块缓存大小（字节），按缓存中的块占用的内存计算
+    pub block_cache_size: u64,
+    /// 压缩的 SST 的块以压缩后的形式放入块缓存，默认关闭，缓存解码后的块。
+    /// 开启后同样的 `block_cache_size` 可以缓存更多的块，代价是每次命中都要解压，适合内存紧张而 CPU 充裕的场景
+    pub cache_compressed_blocks: bool,
+    /// 合并和 [`Db::export_snapshot`] 输出的 SST 中 data block 的压缩方式，默认为 [`COMPACTION_COMPRESSION`]。
+    /// 刷写出的 L0 SST 不压缩，只影响之后输出的 SST
+    pub compaction_compression: CompressionType,
+    /// [`CompressionType::Zstd`] 的字典大小（字节），默认为 [`ZSTD_DICT_SIZE`]，为 0 时不训练字典。
+    /// 字典由每次合并采样的 KV 训练并保存在输出的 SST 中，适合 value 较小、单个 block 内重复较少的数据
+    pub zstd_dict_size: usize,
+    /// SST 的 data block 大小（字节），默认为 [`BLOCK_SIZE`]。较大的块压缩率更高、meta 更小，
+    /// 但点查需要读出和解码的数据更多。只影响之后刷写和合并输出的 SST
+    pub block_size: usize,
+    /// VSST 的 data block 大小（字节），默认为 [`VSST_BLOCK_SIZE`]。VSST 中的 value 很大，
+    /// 小块让每个块只包含一个 value，读取一个 value 时只需读出它所在的块
+    pub vsst_block_size: usize,
+    /// L0 的 SST 数量超过该值时合并到 L1，低优先级写入也会开始等待
+    pub l0_sst_num_limit: usize,
+    /// WAL 单个段的最大字节数
+    pub wal_segment_size_limit: u64,
+    /// WAL 单个段从创建开始允许写入的最长时间
+    pub wal_segment_age_limit: Duration,
+    /// SST 的 bloom filter 的加载方式，内存受限时可以延迟加载或不加载
+    pub filter_loading: FilterLoading,
+    /// SST 和 VSST 各自的 meta 缓存大小（字节），为 `None` 时所有 meta 常驻内存，默认不限制。
+    /// 设置后每个表只常驻 footer、块数和 key 范围，冷表的 meta 被淘汰后在下次读取时从文件中重新加载，
+    /// 用于数据量很大时限制 meta 常驻的内存
+    pub index_cache_size: Option<u64>,
+    /// WAL 记录的 MAC 校验和加密，开启后恢复时能发现被篡改或损坏的记录
+    pub wal_protection: Option<WalProtection>,
+    /// 合并读取 SST 时的优先级，设为 [`IoPriority::Background`] 时为用户读让路，降低合并期间的读延迟毛刺
+    pub compaction_io_priority: IoPriority,
+    /// 打开时跳过 MANIFEST 中当前版本不认识的变更而不是报错，默认关闭。
+    /// 用于回退到旧版本打开新版本写入的数据，只有新增的变更可以安全忽略时才应开启
+    pub skip_unknown_manifest_items: bool,
+    /// 后台删除合并后废弃的 SST / VSST 的速度上限（字节/秒），为 `None` 时不限速。
+    /// 限速时大文件分多次截断后再删除，避免一次删除占满磁盘带宽
+    pub delete_rate_limit: Option<u64>,
+    /// 一次合并的输入字节数上限，为 `None` 时不限制，默认不限制。
+    /// 与基准 SST 重叠的文件总是参与合并，超过上限时 L1 及以下不再扩大输入范围，避免单次合并耗时过长
+    pub max_compaction_bytes: Option<u64>,
+    /// 删除 SST / VSST / WAL 时移到数据目录下的 `trash` 目录而不是直接删除，保留该时间后由后台清理，
+    /// 为 `None` 时直接删除，默认不开启。用于防止误删仍被引用的文件，以及出问题后保留现场排查，
+    /// 回收站中的文件名带有删除时间，数据库不会再读取它们
+    pub trash_retention: Option<Duration>,
+    /// 打开时若 L0 的 SST 数量超过 `l0_sst_num_limit`，在返回前先合并 L0，默认关闭。
+    /// 写入压力大时崩溃可能留下大量 L0 文件，开启后打开会变慢，但避免打开后最初的读取逐个查找 L0
+    pub warmup: bool,
+    /// 缓存模式的容量（字节），为 `None` 时不开启，默认不开启。
+    /// 开启后所有 SST 和 VSST 的总大小超过容量时，后台按写入的先后淘汰最早的数据，
+    /// 被淘汰的 key 读取时不存在，适合把数据库用作持久化的缓存
+    pub cache_capacity: Option<u64>,
+    /// 打开时为缺少 bloom filter、删除标记数量等元数据的旧格式 SST 重新生成元数据，默认关闭。
+    /// 只重写文件尾，见 [`crate::admin::rebuild_legacy_metadata`]
+    pub rebuild_legacy_metadata: bool,
+    /// 长度超过该值的 value 在刷写时分离到 VSST，为 `None` 时不分离，默认为 [`MIN_VSST_SIZE`]。
+    /// 运行中可用 [`Db::set_value_separation_threshold`] 修改
+    pub value_separation_threshold: Option<u64>,
+    /// 每次状态变更（冻结 WAL、刷写、合并、删除文件）都 fsync MANIFEST 和数据目录后再进行依赖它的操作，默认关闭。
+    /// 合并输出的文件在写入 MANIFEST 之前先 fsync，之后才删除被替换的 SST，
+    /// 掉电后不会出现 MANIFEST 引用了未落盘的文件的情况。关闭时只保证进程崩溃时的一致性。
+    /// 刷写不受该选项影响，总是先 fsync 输出的文件、数据目录和 MANIFEST 记录再删除 WAL，WAL 不会在其数据落盘前被删除
+    pub strict_manifest_sync: bool,
+    /// 后台线程上的合并、淘汰等长循环和 [`Db::count`] 每处理该数量的 entry 让出一次 CPU，为 `None` 时不让出，
+    /// 默认为 [`YIELD_INTERVAL`]。线程池较小时避免一次大合并长时间独占一个核，让刷写等其他任务得以推进
+    pub yield_interval: Option<u64>,
+    /// 后台线程每隔该时间 fsync 一次当前和已冻结的 WAL，为 `None` 时不启动，默认不开启。
+    /// 写入不开启 [`WriteOptions::sync`] 时，掉电或崩溃最多丢失最近这段时间内的写入，见 [`Db::durable_seq`]
+    pub wal_sync_interval: Option<Duration>,
+    /// 文件写出超过该时间的 SST 由后台线程交给合并重写，为 `None` 时不开启，默认不开启。
+    /// 长期没有写入的 key 范围不会触发按大小的合并，其中的删除标记一直留在浅层、旧格式的文件也不会升级；
+    /// 开启后这些 SST 逐层合并到下一层，最后一层的在原层重写。SST 的年龄按文件的修改时间计算，
+    /// 复制到其他目录的文件从复制时重新计算。后台线程每隔该时间（最长 [`PERIODIC_COMPACTION_CHECK_INTERVAL`]）检查一次，
+    /// 自动合并暂停时跳过，见 [`Db::set_compactions_paused`]
+    pub periodic_compaction: Option<Duration>,
+    /// 合并填充块缓存的方式，默认为 [`CacheFillPolicy::OnWrite`]：读取输入 SST 和迁移的 VSST 时不放入缓存，
+    /// 避免把用户读取的热点块挤出缓存，合并后再把输出 SST 中覆盖了原先被缓存的热点块的 data block 读入缓存，
+    /// 避免合并后文件 id 变化导致缓存全部失效、读延迟突增
+    pub compaction_cache_fill: CacheFillPolicy,
+    /// 刷写填充块缓存的方式，默认为 [`CacheFillPolicy::None`]。设为 [`CacheFillPolicy::OnWrite`] 时
+    /// 把刷写出的 L0 SST 的所有 data block 读入缓存，适合刚写入的数据很快会被读取的场景。刷写不读取 SST，
+    /// [`CacheFillPolicy::OnRead`] 与 `None` 相同
+    pub flush_cache_fill: CacheFillPolicy,
+    /// 各层 SST 的 bloom filter 每个 key 使用的位数，第 i 项对应 Li，层数多于列表长度的层使用最后一项，至少为 1。
+    /// 为空时所有层按 1% 的假阳性率分配（约 10 位），默认为空。
+    /// 点查大多在上层就能找到，可以为上层保留较多的位数、为数据量最大的最后一层减少位数以节省内存，
+    /// 例如 `vec![10, 10, 10, 8, 6, 4]`。只影响之后刷写和合并输出的 SST
+    pub filter_bits_per_key: Vec<u32>,
+    /// 刷写和合并输出 SST 时是否检查 key 严格递增，违反时刷写或合并失败并返回 [`crate::KeyOrderError`]，
+    /// 而不是写出二分查找会找不到数据的 SST。默认只在开启 debug assertions 的构建中检查
+    pub sst_key_order_check: KeyOrderCheck,
+    /// 迭代器存活超过该时间时输出警告，并在 [`Db::health`] 中报告，用于发现忘记 drop、一直持有快照和文件的迭代器。
+    /// 警告在调用 `health` 时或迭代器 drop 时输出，每个迭代器只输出一次。默认为 `None`，不检查
+    pub iterator_age_warning: Option<Duration>,
+    /// 是否把每次写入的修改记录到变更日志，供 [`Db::start_cdc`] 投递给下游。默认为 `false`。
+    /// 变更日志和投递进度保存在系统 key 中，与修改写入同一条 WAL 记录；
+    /// 开启后记录日志的写入串行执行，每次写入的数据量约增加一倍，直到日志被投递并删除
+    pub change_capture: bool,
+    /// 刷写和合并时每隔该数量的 entry 把一个 value 交给 [`Db::add_value_inspector`] 注册的回调，至少为 1，
+    /// 默认为 [`VALUE_INSPECTION_SAMPLING`]。为 1 时检查每个 value，回调记录的是精确的计数
+    pub value_inspection_sampling: u32,
+    /// 冻结的 memtable 超过该数量时按 `frozen_limit_action` 阻塞或拒绝写入，并在 [`Db::health`] 中报告，
+    /// 为 `None` 时不限制，默认不限制。刷写持续失败或后台线程卡住时冻结的 memtable 会一直累积，设置上限避免内存无限增长
+    pub max_frozen_memtables: Option<usize>,
+    /// 已冻结、等待刷写后删除的 WAL 超过该数量时同上，为 `None` 时不限制，默认不限制
+    pub max_frozen_wals: Option<usize>,
+    /// 超过 `max_frozen_memtables` 或 `max_frozen_wals` 时写入的处理方式，默认为 [`FrozenLimitAction::Stall`]
+    pub frozen_limit_action: FrozenLimitAction,
+    /// MANIFEST 中仍被 SST 引用的 VSST 文件不存在时的处理方式，默认为 [`MissingVSstPolicy::Fail`]
+    pub missing_vsst_policy: MissingVSstPolicy,
+    /// 与其他数据库共用的刷写和合并线程池，为 `None` 时该数据库启动自己的刷写和合并线程，默认为 `None`。
+    /// 只影响 [`Db::open_file_with_options`]，见 [`SharedScheduler`]
+    pub shared_scheduler: Option<Arc<SharedScheduler>>,
+    /// 使用 `shared_scheduler` 时该数据库同时执行的刷写和合并任务数的上限，至少为 1，默认为 [`SCHEDULER_QUOTA`]。
+    /// 同一个数据库同时最多一个刷写和一个合并，为 1 时刷写和合并也不会同时执行
+    pub scheduler_quota: usize,
+    /// 单个 value 的最大字节数，默认为 [`MAX_VALUE_SIZE`]。put、`put_get` 和批量写入中有 value 超过该值时整个写入被拒绝，
+    /// 返回 [`crate::WriteRejectedError`]，回调名为 [`crate::VALUE_SIZE_VALIDATOR`]，原因为 [`Rejection::TooLarge`]，
+    /// 在写入 WAL 之前检查，超长的 value 不会被复制。只检查新的写入，已写入的 value 不受影响。
+    /// SST 和 WAL 中的长度和偏移为 u32，该值超过 4 GB 时打开失败
+    pub max_value_size: usize,
+    /// 并发的小 put / delete 分组提交，每组只写一条 WAL 记录、刷写一次写缓冲，为 `None` 时每个写入单独提交，
+    /// 默认为 `None`。适合大量线程同时写入小 value 的场景，见 [`MicroBatchOptions`]
+    pub micro_batch: Option<MicroBatchOptions>,
+    /// 缓存最近确认不存在的 key 的个数，为 `None` 时不缓存，默认为 `None`。适合反复读取同一批不存在的 key 的场景，
+    /// 命中时 get、`contains_key` 和 `value_size` 不再逐层查找；写入 key 时移除，只用于不指定快照的读取
+    pub negative_cache_capacity: Option<u64>,
+}
+
+/// 冻结的 memtable 或 WAL 超过上限时写入的处理方式，见 [`DbOptions::max_frozen_memtables`]
+#[derive(Debug, Clone, Copy, Default, Eq, PartialEq)]
+pub enum FrozenLimitAction {
+    /// 阻塞写入直到刷写完成、数量回到上限以内或数据库关闭，等待记入 [`DbStats`] 中的 `write_stalls`
+    #[default]
+    Stall,
+    /// 立即返回 [`BusyError`]，由调用方决定重试或降级
+    Busy,
+}
+
+/// 打开时 VSST 文件缺失的处理方式，见 [`DbOptions::missing_vsst_policy`]
+///
+/// 除 `Fail` 外，缺失的 VSST 记入 [`RecoveryStats`] 中的 `missing_vssts`，它们中的 value 都已丢失，
+/// 读到引用它们的 key 时当作不存在并计入 [`DbStats`] 中的 `dangling_value_reads`。
+/// 之后合并到引用它们的 SST 时引用改写为删除标记，引用全部消失后 VSST 从 MANIFEST 中删除
+#[derive(Debug, Clone, Copy, Default, Eq, PartialEq)]
+pub enum MissingVSstPolicy {
+    /// 打开失败，可以用 [`Db::open_for_repair`] 离线修复
+    #[default]
+    Fail,
+    /// 正常打开，引用等到合并时再清理，在此之前 [`Db::health`] 会报告缺失的 VSST
+    NotFound,
+    /// 正常打开，并在 [`Db::open_file_with_options`] 启动后台任务前重写所有引用缺失 VSST 的 SST
+    Repair,
+}
+
+impl Default for DbOptions {
+    fn default() -> Self {
+        Self {
+            memtable_size_limit: MEMTABLE_SIZE_LIMIT,
+            block_cache_size: BLOCK_CACHE_SIZE,
+            cache_compressed_blocks: false,
+            compaction_compression: COMPACTION_COMPRESSION,
+            zstd_dict_size: ZSTD_DICT_SIZE,
+            block_size: BLOCK_SIZE,
+            vsst_block_size: VSST_BLOCK_SIZE,
+            l0_sst_num_limit: L0_SST_NUM_LIMIT,
+            wal_segment_size_limit: WAL_SEGMENT_SIZE_LIMIT,
+            wal_segment_age_limit: WAL_SEGMENT_AGE_LIMIT,
+            filter_loading: FilterLoading::default(),
+            index_cache_size: None,
+            wal_protection: None,
+            compaction_io_priority: IoPriority::Foreground,
+            skip_unknown_manifest_items: false,
+            delete_rate_limit: None,
+            max_compaction_bytes: None,
+            trash_retention: None,
+            warmup: false,
+            cache_capacity: None,
+            rebuild_legacy_metadata: false,
+            value_separation_threshold: Some(MIN_VSST_SIZE),
+            strict_manifest_sync: false,
+            yield_interval: Some(YIELD_INTERVAL),
+            wal_sync_interval: None,
+            periodic_compaction: None,
+            compaction_cache_fill: CacheFillPolicy::OnWrite,
+            flush_cache_fill: CacheFillPolicy::None,
+            filter_bits_per_key: vec![],
+            sst_key_order_check: KeyOrderCheck::default(),
+            iterator_age_warning: None,
+            change_capture: false,
+            value_inspection_sampling: VALUE_INSPECTION_SAMPLING,
+            max_frozen_memtables: None,
+            max_frozen_wals: None,
+            frozen_limit_action: FrozenLimitAction::Stall,
+            missing_vsst_policy: MissingVSstPolicy::Fail,
+            shared_scheduler: None,
+            scheduler_quota: SCHEDULER_QUOTA,
+            max_value_size: MAX_VALUE_SIZE,
+            micro_batch: None,
+            negative_cache_capacity: None,
+        }
+    }
+}
+
+impl DbOptions {
+    /// `level` 层 SST 的 bloom filter 每个 key 使用的位数，见 [`DbOptions::filter_bits_per_key`]
+    pub(crate) fn filter_bits_per_key_of(&self, level: u32) -> Option<u32> {
+        self.filter_bits_per_key
+            .get(level as usize)
+            .or(self.filter_bits_per_key.last())
+            .copied()
+    }
+
+    /// 按选项中的分段限制和保护方式打开 WAL
+    pub(crate) fn journal_options(&self) -> JournalOptions {
+        JournalOptions {
+            max_segment_size: self.wal_segment_size_limit,
+            max_segment_age: self.wal_segment_age_limit,
+            protection: self.wal_protection,
+            read_only: false,
+        }
+    }
+}
+
+/// 单次写入的选项
+#[derive(Debug, Clone)]
+pub struct WriteOptions {
+    /// 写入 WAL 后立即刷写到文件再返回，默认开启。关闭后 WAL 留在写缓冲中，
+    /// 由之后的同步写入、WAL 切换、[`DbOptions::wal_sync_interval`] 的后台 fsync 或 drop 时刷写，崩溃时可能丢失最近的写入
+    pub sync: bool,
+    /// 跳过 WAL，只写入 memtable。进程崩溃时会丢失上次刷写以来以该方式写入的数据，
+    /// 适合可重建的缓存或批量导入（导入后调用 [`Db::flush`] 落盘）
+    pub disable_wal: bool,
+    /// 低优先级写入，后台刷写或合并积压时先等待 [`LOW_PRIORITY_WRITE_DELAY`] 再写入，
+    /// 把资源让给前台写入，适合后台导入、修复等任务
+    pub low_priority: bool,
+}
+
+/// 单次读取的选项
+#[derive(Debug, Clone)]
+pub struct ReadOptions {
+    /// 从磁盘读出的块放入块缓存，默认开启。一次性的大范围扫描可以关闭，避免把热点块挤出缓存
+    pub fill_cache: bool,
+    /// 校验从磁盘读出的块的校验和，不一致时返回 [`CorruptionError`](crate::CorruptionError)，默认关闭。
+    /// 已在块缓存中的块不会再次校验
+    pub verify_checksums: bool,
+    /// `verify_checksums` 关闭时按 1/n 抽样校验从磁盘读出的块，为 `None` 时不校验，默认不校验。
+    /// 放入块缓存的块会被之后的读取反复使用，总是校验；校验结果计入 [`DbStats`] 中的
+    /// `verified_blocks` 和 `checksum_failures`
+    pub checksum_sampling: Option<u32>,
+    /// 在指定的快照上读取，为 `None` 时读取最新的数据
+    pub snapshot: Option<Snapshot>,
+    /// 范围查询的截止时间，为 `None` 时不限制，默认不限制。超过后迭代器失效，
+    /// 创建迭代器或 `next` 返回 [`crate::ScanTimeoutError`]，避免范围过大或删除标记过多的查询长时间占用服务线程
+    pub deadline: Option<Instant>,
+    /// 范围查询能否看到迭代器创建之后的写入，默认为 [`ScanIsolation::Snapshot`]。
+    /// [`ScanIsolation::Latest`] 不能与 `snapshot` 同时指定
+    pub isolation: ScanIsolation,
+}
+
+/// 范围查询的隔离方式，见 [`ReadOptions::isolation`]
+#[derive(Debug, Clone, Copy, Default, Eq, PartialEq)]
+pub enum ScanIsolation {
+    /// 迭代器只返回创建时已提交的数据，之后的写入和删除都看不到，整个查询读到的是同一时刻的一致视图
+    #[default]
+    Snapshot,
+    /// 每次 `next` 时若数据库在迭代器当前所基于的快照之后有新的写入（或刷写、合并），在最新的数据上从当前 key 之后
+    /// 重新定位，之后返回的 key 反映 `next` 时的最新数据。已返回的 key 不会再返回，key 之间不保证一致，
+    /// 例如先返回的 key 可能已被删除、之前被跳过的 key 可能已被写入。适合监控、尾随读取等需要尽快看到新写入的场景。
+    /// 写入频繁时每次 `next` 都要重新定位，开销明显高于快照模式；迭代器读完后不再刷新，持续读取新数据用 [`Db::tail_scan`]
+    Latest,
+}
+
+impl Default for ReadOptions {
+    fn default() -> Self {
+        Self {
+            fill_cache: true,
+            verify_checksums: false,
+            checksum_sampling: None,
+            snapshot: None,
+            deadline: None,
+            isolation: ScanIsolation::Snapshot,
+        }
+    }
+}
+
+impl ReadOptions {
+    fn block_options(&self) -> BlockReadOptions {
+        BlockReadOptions {
+            fill_cache: self.fill_cache,
+            verify_checksum: self.verify_checksums,
+            checksum_sampling: self.checksum_sampling,
+        }
+    }
+}
+
+/// 点查找到的 key 的最新版本
+enum FoundEntry {
+    /// memtable 中或 SST 中未分离的 value，删除标记的 value 为空
+    Value(OpType, Bytes),
+    /// KV 分离的 entry 在 SST 中保存的 value，见 [`Entry::separated_value`]
+    Separated(Bytes),
 }
 
-pub struct Options {}
+impl Default for WriteOptions {
+    fn default() -> Self {
+        Self {
+            sync: true,
+            disable_wal: false,
+            low_priority: false,
+        }
+    }
+}
 
 impl Db {
     /// open database from file system
     #[instrument]
     pub fn open_file(path: impl AsRef<Path> + Debug) -> anyhow::Result<Db> {
+        Db::open_file_with_options(path, DbOptions::default())
+    }
+
+    /// 以指定的选项打开数据库并启动后台任务
+    #[instrument]
+    pub fn open_file_with_options(
+        path: impl AsRef<Path> + Debug,
+        options: DbOptions,
+    ) -> anyhow::Result<Db> {
         fs::create_dir_all(&path).context("create data dir failed")?;
-        let db = Db::open(&path)?;
-        db.run_background_tasks();
+        let db = Db::open_with_options(&path, options)?;
+        if db.options.missing_vsst_policy == MissingVSstPolicy::Repair {
+            // 修复失败时引用仍按不存在读取，之后的合并会继续清理，不影响打开
+            match db.daemon.repair_dangling_refs() {
+                Ok(0) => {}
+                Ok(repaired) => info!("repair {} SSTs referencing missing VSSTs", repaired),
+                Err(err) => error!("repair SSTs referencing missing VSSTs failed: {:?}", err),
+            }
+        }
+        if db.options.warmup {
+            db.compact_l0_on_open()?;
+        }
+        db.run_background_tasks()?;
+        // 恢复出的冻结 memtable 在后台刷写，之后重启不再重放它们的 WAL
+        if !db.inner.read().frozen_memtable.is_empty() {
+            db.daemon.request_flush();
+        }
         Ok(db)
     }
 
-    fn run_background_tasks(&self) {
-        let _flush_rx = self.flush_chan.1.clone();
+    /// 以指定的选项打开数据库并启动后台任务，返回可在线程间共享的句柄
+    ///
+    /// 同一数据目录已由本函数打开且句柄仍存活时直接返回该句柄，忽略 `options`；
+    /// 已由其他方式打开时返回 [`AlreadyOpenError`]
+    #[instrument]
+    pub fn open_shared(
+        path: impl AsRef<Path> + Debug,
+        options: DbOptions,
+    ) -> anyhow::Result<Arc<Db>> {
+        registry::open_shared(&path, || Db::open_file_with_options(&path, options))
+    }
+
+    /// 正常打开因 MANIFEST 引用的文件缺失或损坏而失败时，跳过这些文件离线打开，
+    /// 查看报告后可以选择提交修正后的 MANIFEST，见 [`crate::admin::open_for_repair`]
+    pub fn open_for_repair(path: impl AsRef<Path>) -> anyhow::Result<RepairSession> {
+        admin::open_for_repair(path)
+    }
+
+    /// 在后台任务启动前合并 L0，直到 L0 的 SST 数量不超过限制或合并不再减少 SST 数量
+    #[instrument(skip_all)]
+    fn compact_l0_on_open(&self) -> anyhow::Result<()> {
+        let num_of_l0 = || self.inner.read().levels[0].len();
+        let mut before = num_of_l0();
+        while before > self.options.l0_sst_num_limit {
+            info!("warmup: compact {} L0 SSTs", before);
+            self.daemon.compaction(0)?;
+            let after = num_of_l0();
+            if after >= before {
+                break;
+            }
+            before = after;
+        }
+        Ok(())
+    }
+
+    /// 标识该实例的短标签，由规范化后的数据目录路径的哈希得到，同一目录每次打开都相同
+    ///
+    /// 后台线程以 `lasagnedb-{flush,compact,delete,walsync,cdc}-{tag}` 命名，线程中的日志都在带有数据目录和该标签的
+    /// `lasagnedb` span 中，一个进程打开多个数据库时用于区分 CPU 占用和日志属于哪个实例。
+    /// Linux 上线程名最多显示前 15 个字节
+    pub fn instance_tag(&self) -> &str {
+        &self.instance_tag
+    }
+
+    fn instance_tag_of(path: &Path) -> String {
+        format!(
+            "{:08x}",
+            xxh3_64(path.as_os_str().as_encoded_bytes()) as u32
+        )
+    }
+
+    /// 以 `lasagnedb-{role}-{tag}` 命名并启动一个后台线程，`task` 在标识该实例的 span 中运行
+    fn background_span(&self, role: &str) -> tracing::Span {
+        span!(
+            tracing::Level::INFO,
+            "lasagnedb",
+            path = ?self.path,
+            tag = %self.instance_tag,
+            role
+        )
+    }
+
+    fn spawn_background(
+        &self,
+        role: &str,
+        task: impl FnOnce() + Send + 'static,
+    ) -> anyhow::Result<JoinHandle<()>> {
+        let span = self.background_span(role);
+        let yield_interval = self.options.yield_interval;
+        thread::Builder::new()
+            .name(format!("lasagnedb-{}-{}", role, self.instance_tag))
+            .spawn(move || {
+                let _yield = YieldScope::enter(yield_interval);
+                span.in_scope(task)
+            })
+            .with_context(|| format!("spawn {} thread failed", role))
+    }
+
+    /// 启动刷写、合并、删除文件、定期 fsync WAL 和定期合并的后台线程，每个线程收到一条退出消息后退出，见 [`Db::shutdown`]。
+    /// 设置了 `shared_scheduler` 时刷写和合并改为加入共享调度器
+    fn run_background_tasks(&self) -> anyhow::Result<()> {
+        let mut background_tasks = self.background_tasks.lock();
+
+        match &self.options.shared_scheduler {
+            Some(scheduler) => {
+                *self.scheduler_membership.lock() = Some(scheduler.join(
+                    self.daemon.clone(),
+                    self.options.scheduler_quota,
+                    self.background_span("shared"),
+                    self.options.yield_interval,
+                ));
+            }
+            None => self.spawn_flush_and_compaction(&mut background_tasks)?,
+        }
+
+        let _delete_rx = self.daemon.delete_receiver();
+        let _exit_rx = self.exit_chan.1.clone();
         let _daemon = self.daemon.clone();
-        thread::spawn(move || {
-            for _ in _flush_rx {
-                let _span = span!(tracing::Level::TRACE, "flush daemon");
+        let purge_trash = |daemon: &DbDaemon| {
+            if let Err(err) = daemon.purge_trash() {
+                error!("purge trash failed: {:#}", err)
+            }
+        };
+        background_tasks.push(self.spawn_background("delete", move || {
+            purge_trash(&_daemon);
+            loop {
+                let exit = crossbeam::select! {
+                    recv(_delete_rx) -> msg => msg.is_err(),
+                    recv(_exit_rx) -> _ => true,
+                    default(TRASH_PURGE_INTERVAL) => false,
+                };
+                let _span = span!(tracing::Level::TRACE, "delete daemon");
                 let _enter = _span.enter();
-                if let Err(err) = _daemon.rotate() {
-                    error!("rotate failed: {}", err)
+                // 退出前删完队列中的文件，否则要等下次打开时根据 MANIFEST 中的记录再删
+                if let Err(err) = _daemon.delete_obsolete_files() {
+                    error!("delete obsolete files failed: {:#}", err)
+                }
+                purge_trash(&_daemon);
+                if exit {
+                    return;
                 }
             }
-        });
+        })?);
+
+        if let Some(interval) = self.options.wal_sync_interval {
+            let _exit_rx = self.exit_chan.1.clone();
+            let _daemon = self.daemon.clone();
+            background_tasks.push(self.spawn_background("walsync", move || loop {
+                crossbeam::select! {
+                    // 关闭时会刷写 WAL，不需要再 fsync 一次
+                    recv(_exit_rx) -> _ => return,
+                    default(interval) => {
+                        if let Err(err) = _daemon.sync_wal() {
+                            error!("sync WAL failed: {:#}", err)
+                        }
+                    }
+                }
+            })?);
+        }
+
+        if let Some(max_age) = self.options.periodic_compaction {
+            let _exit_rx = self.exit_chan.1.clone();
+            let _daemon = self.daemon.clone();
+            let interval = max_age.min(PERIODIC_COMPACTION_CHECK_INTERVAL);
+            background_tasks.push(self.spawn_background("periodic", move || loop {
+                crossbeam::select! {
+                    recv(_exit_rx) -> _ => return,
+                    default(interval) => {
+                        if let Err(err) = _daemon.periodic_compaction(max_age) {
+                            error!("periodic compaction failed: {:#}", err)
+                        }
+                    }
+                }
+            })?);
+        }
+        Ok(())
+    }
+
+    fn spawn_flush_and_compaction(
+        &self,
+        background_tasks: &mut Vec<JoinHandle<()>>,
+    ) -> anyhow::Result<()> {
+        let flush = |daemon: &DbDaemon| {
+            if let Err(err) = daemon.run_flush_request() {
+                error!("rotate failed: {}", err)
+            }
+        };
+        let _flush_rx = self.flush_chan.1.clone();
+        let _exit_rx = self.exit_chan.1.clone();
+        let _daemon = self.daemon.clone();
+        background_tasks.push(self.spawn_background("flush", move || loop {
+            crossbeam::select! {
+                recv(_flush_rx) -> msg => match msg {
+                    Ok(_) => flush(&_daemon),
+                    Err(_) => return,
+                },
+                recv(_exit_rx) -> _ => {
+                    // 退出前已请求的刷写仍然执行，调用方可能在等待它完成
+                    while _flush_rx.try_recv().is_ok() {
+                        flush(&_daemon);
+                    }
+                    return;
+                }
+            }
+        })?);
+
         let _compaction_rx = self.compaction_chan.1.clone();
+        let _exit_rx = self.exit_chan.1.clone();
         let _daemon = self.daemon.clone();
-        thread::spawn(move || {
-            for level in _compaction_rx {
-                let _span = span!(tracing::Level::TRACE, "compaction daemon");
-                let _enter = _span.enter();
-                if let Err(err) = _daemon.compaction(level) {
-                    error!("compaction failed: {}", err)
+        background_tasks.push(self.spawn_background("compact", move || loop {
+            crossbeam::select! {
+                recv(_compaction_rx) -> msg => match msg {
+                    Ok(level) => {
+                        if let Err(err) = _daemon.run_compaction_request(level) {
+                            error!("compaction failed: {}", err)
+                        }
+                    }
+                    Err(_) => return,
+                },
+                recv(_exit_rx) -> _ => {
+                    // 合并只是整理数据，排队的请求直接取消，重新打开后会按需要重新发起
+                    while _compaction_rx.try_recv().is_ok() {
+                        _daemon.compaction_gauge.on_cancel();
+                    }
+                    return;
                 }
             }
-        });
+        })?);
+        Ok(())
     }
 
     pub(crate) fn path_of_current(base_path: impl AsRef<Path>) -> PathBuf {
@@ -118,18 +760,65 @@ impl Db {
         base_path.as_ref().join(format!("{:05}.MANIFEST", id))
     }
 
-    pub(crate) fn path_of_wal(base_path: impl AsRef<Path>, id: u32) -> PathBuf {
+    pub(crate) fn path_of_wal(base_path: impl AsRef<Path>, id: u64) -> PathBuf {
         base_path.as_ref().join(format!("{:05}.LOG", id))
     }
 
-    pub(crate) fn path_of_sst(base_path: impl AsRef<Path>, sst_id: u32) -> PathBuf {
+    /// WAL 第 0 段沿用 `{log_id}.LOG`，之后的段为 `{log_id}-{segment_id}.LOG`
+    pub(crate) fn path_of_wal_segment(
+        base_path: impl AsRef<Path>,
+        id: u64,
+        segment_id: u32,
+    ) -> PathBuf {
+        if segment_id == 0 {
+            return Db::path_of_wal(base_path, id);
+        }
+        base_path
+            .as_ref()
+            .join(format!("{:05}-{:05}.LOG", id, segment_id))
+    }
+
+    /// 打开由 `num_segments` 个段组成的 WAL
+    pub(crate) fn open_wal(
+        base_path: impl AsRef<Path>,
+        id: u64,
+        num_segments: u32,
+        options: &DbOptions,
+    ) -> anyhow::Result<Journal> {
+        let segment_paths = (0..num_segments.max(1))
+            .map(|segment_id| Db::path_of_wal_segment(&base_path, id, segment_id))
+            .collect();
+        Journal::open_segments(id, segment_paths, options.journal_options())
+    }
+
+    pub(crate) fn path_of_sst(base_path: impl AsRef<Path>, sst_id: u64) -> PathBuf {
         base_path.as_ref().join(format!("{:05}.SST", sst_id))
     }
 
-    pub(crate) fn path_of_vsst(base_path: impl AsRef<Path>, vsst_id: u32) -> PathBuf {
+    pub(crate) fn path_of_vsst(base_path: impl AsRef<Path>, vsst_id: u64) -> PathBuf {
         base_path.as_ref().join(format!("{:05}.VSST", vsst_id))
     }
 
+    pub(crate) fn path_of_trash(base_path: impl AsRef<Path>) -> PathBuf {
+        base_path.as_ref().join("trash")
+    }
+
+    pub(crate) fn path_of_staging(base_path: impl AsRef<Path>, id: u64) -> PathBuf {
+        base_path.as_ref().join(format!("{:05}.STAGE", id))
+    }
+
+    /// 删除上次运行残留的查询结果暂存文件
+    fn remove_staging_files(base_path: impl AsRef<Path>) -> anyhow::Result<()> {
+        for entry in fs::read_dir(base_path)? {
+            let path = entry?.path();
+            if path.extension().is_some_and(|ext| ext == "STAGE") {
+                debug!("remove staging file {:?}", path);
+                fs::remove_file(path)?;
+            }
+        }
+        Ok(())
+    }
+
     // TODO 太恶心了 这块要重构
     #[instrument]
     pub fn recover(
@@ -137,148 +826,167 @@ impl Db {
         manifest: Arc<Manifest>,
         sst_cache: Arc<BlockCache>,
         vsst_cache: Arc<BlockCache>,
+        options: &DbOptions,
+        recovery: &mut RecoveryStats,
     ) -> anyhow::Result<(
         Vec<Vec<Arc<SsTable>>>,     // levels
-        u32,                        // now_sst_id
-        HashMap<u32, Arc<SsTable>>, // vssts
-        u32,                        // now_vsst_id
+        u64,                        // now_sst_id
+        HashMap<u64, Arc<SsTable>>, // vssts
+        u64,                        // now_vsst_id
         Arc<MemTable>,              // memtable
-        u32,                        // now_log_id
+        u64,                        // now_log_id
         Vec<Arc<Journal>>,          // frozen_wal
         Vec<Arc<MemTable>>,         // frozen_memtable
-        HashMap<u32, u32>,          // vsst_rc
+        HashMap<u64, u32>,          // vsst_rc
+        u32,                        // now_log_segments
+        IdempotencyTokens,          // idempotency_tokens
+        Vec<(FileType, u64)>,       // pending_deletes
+        u64,                        // commit_seq
+        CumulativeStats,            // stats
     )> {
         // 从 MANIFEST 恢复元信息
-        let mut iter = ManifestIterator::create_and_seek_to_first(manifest)?;
-        let mut now_sst_id = 0;
-        let mut now_vsst_id = 0;
-        let mut sst_map: HashMap<u32, Vec<u32>> = HashMap::new();
-        let mut vsst_set: HashSet<u32> = HashSet::new();
-        let mut vsst_rc: HashMap<u32, u32> = HashMap::new();
-        let mut frozen_log_ids: Vec<u32> = vec![]; // 有顺序要求
-        let mut now_log_id = 0;
-        let mut seq_num = 1;
+        let phase_start = Instant::now();
         let iter_manifest_span = span!(tracing::Level::TRACE, "iterate manifest").entered();
-        while iter.is_valid() {
-            let record_item = iter.record_item();
-            match record_item {
-                ManifestItem::Init(_) => {}
-                ManifestItem::NewSst(level, sst_id) => {
-                    sst_map.entry(level).or_default().push(sst_id);
-                    now_sst_id = if now_sst_id > sst_id {
-                        now_sst_id
-                    } else {
-                        sst_id
-                    }
-                }
-                ManifestItem::DelSst(level, sst_id) => {
-                    if let Some(vec) = sst_map.get_mut(&level) {
-                        vec.retain(|id| *id != sst_id);
-                    }
-                }
-                ManifestItem::NewVSst(sst_id) => {
-                    vsst_set.insert(sst_id);
-                    now_vsst_id = if now_vsst_id > sst_id {
-                        now_vsst_id
-                    } else {
-                        sst_id
-                    }
-                }
-                ManifestItem::DelVSst(sst_id) => {
-                    vsst_set.remove(&sst_id);
-                }
-                ManifestItem::MaxSeqNum(_seq_num) => seq_num = _seq_num,
-                ManifestItem::FreezeAndCreateWal(old_log_id, new_log_id) => {
-                    now_log_id = new_log_id;
-                    if old_log_id != new_log_id {
-                        frozen_log_ids.push(old_log_id);
-                    }
-                }
-                ManifestItem::DelFrozenWal(log_id) => {
-                    frozen_log_ids.retain(|item| item != &log_id);
-                }
-                ManifestItem::VSstRefCnt(vsst_id, cnt) => {
-                    if cnt == 0 {
-                        vsst_rc.remove(&vsst_id);
-                    } else {
-                        vsst_rc.insert(vsst_id, cnt);
-                    }
-                }
-            }
-            iter.next()?;
-        }
+        let ManifestState {
+            sst_map,
+            vsst_set,
+            mut vsst_rc,
+            frozen_log_ids,
+            wal_segments,
+            now_sst_id,
+            now_vsst_id,
+            now_log_id,
+            pending_deletes,
+            commit_seq,
+            stats,
+            ..
+        } = ManifestState::replay(manifest)?;
         drop(iter_manifest_span);
+        recovery.manifest_replay = phase_start.elapsed();
 
         // 恢复 SST
+        let phase_start = Instant::now();
         let recover_sst_span = span!(tracing::Level::TRACE, "recover sst info").entered();
-        let mut levels: Vec<Vec<Arc<SsTable>>> = vec![];
-        levels.resize(SST_LEVEL_LIMIT as usize, vec![]);
+        let mut tables = vec![];
         for level in 0..SST_LEVEL_LIMIT {
-            let l: &mut Vec<Arc<SsTable>> = &mut levels[level as usize];
             if let Some(sst_ids) = sst_map.get(&level) {
                 for sst_id in sst_ids {
-                    let sst = Arc::new(SsTable::open(
+                    tables.push((
                         *sst_id,
-                        Some(sst_cache.clone()),
-                        FileStorage::open(Db::path_of_sst(&path, *sst_id))?,
-                    )?);
-                    l.push(sst);
+                        FileType::Sst,
+                        Db::path_of_sst(&path, *sst_id),
+                        sst_cache.clone(),
+                    ));
                 }
             }
         }
-        let mut vssts: HashMap<u32, Arc<SsTable>> = HashMap::new();
-        for vsst_id in vsst_set {
-            vssts.insert(
-                vsst_id,
-                Arc::new(SsTable::open(
-                    vsst_id,
-                    Some(vsst_cache.clone()),
-                    FileStorage::open(Db::path_of_vsst(&path, vsst_id))?,
-                )?),
-            );
+        let mut vsst_ids: Vec<u64> = vsst_set.into_iter().collect();
+        if options.missing_vsst_policy != MissingVSstPolicy::Fail {
+            vsst_ids.retain(|vsst_id| {
+                // 之前打开失败时会留下同名的空文件，同样视为缺失
+                let present = fs::metadata(Db::path_of_vsst(&path, *vsst_id))
+                    .is_ok_and(|metadata| metadata.len() > 0);
+                if present {
+                    return true;
+                }
+                error!(
+                    "{}.VSST is missing, values in it are read as not found",
+                    vsst_id
+                );
+                recovery.missing_vssts.push(*vsst_id);
+                false
+            });
+            recovery.missing_vssts.sort_unstable();
         }
-        drop(recover_sst_span);
+        for vsst_id in &vsst_ids {
+            tables.push((
+                *vsst_id,
+                FileType::VSst,
+                Db::path_of_vsst(&path, *vsst_id),
+                vsst_cache.clone(),
+            ));
+        }
+        let mut opened = Db::open_tables(&tables, options.filter_loading)?.into_iter();
 
-        // 重新执行 LOG 操作
-        let redo_log_span = span!(tracing::Level::TRACE, "redo log").entered();
-        let wal = Arc::new(Journal::open(
-            now_log_id,
-            Db::path_of_wal(&path, now_log_id),
-        )?);
-        let memtable = Arc::new(MemTable::new());
-        if wal.num_of_records() > 0 {
-            let mut wal_iter = JournalIterator::create_and_seek_to_first(wal)?;
-            while wal_iter.is_valid() {
-                let wal_item = wal_iter.record_item();
-                let entry = wal_item.as_ref();
-                let op_code = OpType::from((entry.meta & 0xFF) as u8);
-                let key = Db::make_internal_key(1, op_code, &entry.key);
-                memtable.put(key, entry.value.clone());
-                wal_iter.next()?;
+        let mut levels: Vec<Vec<Arc<SsTable>>> = vec![];
+        levels.resize(SST_LEVEL_LIMIT as usize, vec![]);
+        for level in 0..SST_LEVEL_LIMIT {
+            let num = sst_map.get(&level).map_or(0, |sst_ids| sst_ids.len());
+            levels[level as usize].extend(opened.by_ref().take(num));
+        }
+        let vssts: HashMap<u64, Arc<SsTable>> = vsst_ids.into_iter().zip(opened).collect();
+
+        // MANIFEST 中缺少引用计数的 VSST 从 SST 中统计出基准值，避免合并时按 0 计算而误删仍被引用的 VSST
+        if vssts.keys().any(|vsst_id| !vsst_rc.contains_key(vsst_id)) {
+            let refs = Db::count_vsst_refs(&levels)?;
+            for vsst_id in vssts.keys() {
+                if vsst_rc.contains_key(vsst_id) {
+                    continue;
+                }
+                let cnt = refs.get(vsst_id).cloned().unwrap_or(0);
+                warn!(
+                    "{}.VSST has no reference count, rebuilt as {}",
+                    vsst_id, cnt
+                );
+                if cnt > 0 {
+                    vsst_rc.insert(*vsst_id, cnt);
+                }
             }
         }
+        drop(recover_sst_span);
+        recovery.open_tables = phase_start.elapsed();
+
+        // 重新执行 LOG 操作，已刷写到 SST 的 WAL 在刷写完成时就被删除了，这里只会重放未刷写的部分
+        // 冻结的 WAL 比当前 WAL 旧，先重放，保证幂等 token 按写入顺序恢复
+        let phase_start = Instant::now();
+        let redo_log_span = span!(tracing::Level::TRACE, "redo log").entered();
+        let mut idempotency_tokens = IdempotencyTokens::new(IDEMPOTENCY_TOKEN_LIMIT);
+        // 重放的写入使用重启前分配过的序号之后的序号，恢复后的新写入的序号一定更大
+        let mut next_seq = commit_seq + 1;
         let mut frozen_wal = vec![];
         let mut frozen_memtable = vec![];
         for id in frozen_log_ids {
-            let _wal = Arc::new(Journal::open(id, Db::path_of_wal(&path, id))?);
+            let _wal = Arc::new(Db::open_wal(
+                &path,
+                id,
+                wal_segments.get(&id).cloned().unwrap_or(1),
+                options,
+            )?);
             let _memtable = Arc::new(MemTable::new());
-
-            if _wal.num_of_records() > 0 {
-                let mut wal_iter = JournalIterator::create_and_seek_to_first(_wal.clone())?;
-                while wal_iter.is_valid() {
-                    let wal_item = wal_iter.record_item();
-                    let entry = wal_item.as_ref();
-                    let op_code = OpType::from((entry.meta & 0xFF) as u8);
-                    let key = Db::make_internal_key(1, op_code, &entry.key);
-                    _memtable.put(key, entry.value.clone());
-                    wal_iter.next()?;
-                }
-            }
+            Db::redo_wal(_wal.clone(), &_memtable, next_seq, &mut idempotency_tokens)?;
+            next_seq += _wal.num_of_records() as u64;
+            _memtable.freeze();
 
             frozen_wal.push(_wal);
             frozen_memtable.push(_memtable);
         }
+        let now_log_segments = wal_segments.get(&now_log_id).cloned().unwrap_or(1);
+        let wal = Arc::new(Db::open_wal(&path, now_log_id, now_log_segments, options)?);
+        let memtable = Arc::new(MemTable::new());
+        Db::redo_wal(wal.clone(), &memtable, next_seq, &mut idempotency_tokens)?;
         drop(redo_log_span);
+        recovery.wal_replay = phase_start.elapsed();
+        recovery.wal_records = frozen_wal
+            .iter()
+            .chain(Some(&wal))
+            .map(|wal| wal.num_of_records() as u64)
+            .sum();
+        recovery.wal_padding_bytes = frozen_wal
+            .iter()
+            .chain(Some(&wal))
+            .map(|wal| wal.padding_bytes())
+            .sum();
+        recovery.wal_bytes = frozen_memtable
+            .iter()
+            .chain(Some(&memtable))
+            .map(|memtable| memtable.size() as u64)
+            .sum();
+
+        // WAL 中的每次写入是一条记录，按记录数推进提交序号，可能多算但不会小于重启前返回过的序号
+        let commit_seq = frozen_wal
+            .iter()
+            .chain(Some(&wal))
+            .fold(commit_seq, |seq, wal| seq + wal.num_of_records() as u64);
 
         Ok((
             levels,
@@ -290,27 +998,196 @@ impl Db {
             frozen_wal,
             frozen_memtable,
             vsst_rc,
+            now_log_segments,
+            idempotency_tokens,
+            pending_deletes,
+            commit_seq,
+            stats,
         ))
     }
 
+    /// 统计所有 SST 中指向每个 VSST 的 KV 分离项数量
+    pub(crate) fn count_vsst_refs(
+        levels: &[Vec<Arc<SsTable>>],
+    ) -> anyhow::Result<HashMap<u64, u32>> {
+        let mut refs = HashMap::new();
+        for sst in levels.iter().flatten() {
+            if sst.num_of_blocks() == 0 {
+                continue;
+            }
+            let mut iter = SsTableIterator::create_and_seek_to_first(sst.clone())?;
+            let mut checkpoint = LoopCheckpoint::new("count vsst refs");
+            while iter.is_valid() {
+                checkpoint.tick();
+                if Entry::is_separate(iter.meta()) {
+                    *refs
+                        .entry(Entry::separated_vsst_id(iter.value()))
+                        .or_insert(0) += 1;
+                }
+                iter.next()?;
+            }
+        }
+        Ok(refs)
+    }
+
+    /// 用 [`RECOVERY_OPEN_THREADS`] 个线程并行打开 SST，返回结果与 `tables` 的顺序一致，
+    /// 任一文件打开失败或文件类型不符时返回错误
+    fn open_tables(
+        tables: &[(u64, FileType, PathBuf, Arc<BlockCache>)],
+        filter_loading: FilterLoading,
+    ) -> anyhow::Result<Vec<Arc<SsTable>>> {
+        let next = AtomicUsize::new(0);
+        let failed = AtomicBool::new(false);
+        let opened: Mutex<Vec<Option<Arc<SsTable>>>> = Mutex::new(vec![None; tables.len()]);
+        let open = || -> anyhow::Result<()> {
+            while !failed.load(Ordering::Relaxed) {
+                let idx = next.fetch_add(1, Ordering::Relaxed);
+                let Some((id, file_type, path, cache)) = tables.get(idx) else {
+                    break;
+                };
+                let sst = FileStorage::open(path)
+                    .and_then(|file| {
+                        SsTable::open_with_filter_loading(
+                            *id,
+                            Some(cache.clone()),
+                            file,
+                            filter_loading,
+                        )
+                    })
+                    .and_then(|sst| {
+                        sst.check_file_type(*file_type)?;
+                        Ok(sst)
+                    })
+                    .map_err(|e| {
+                        failed.store(true, Ordering::Relaxed);
+                        e.context(format!("open {:?} failed", path))
+                    })?;
+                opened.lock()[idx] = Some(Arc::new(sst));
+            }
+            Ok(())
+        };
+
+        let threads = RECOVERY_OPEN_THREADS.min(tables.len()).max(1);
+        thread::scope(|s| {
+            let handles: Vec<_> = (0..threads).map(|_| s.spawn(open)).collect();
+            handles
+                .into_iter()
+                .try_for_each(|handle| handle.join().expect("open sst thread panicked"))
+        })?;
+        Ok(opened.into_inner().into_iter().flatten().collect())
+    }
+
+    /// 将 WAL 中的记录重放到 memtable，第 i 条记录（从 0 开始）使用序号 `first_seq + i`
+    ///
+    /// 一条记录对应一次写入，批次的 token 位于记录的开头，token 已经出现过时说明是重复提交的批次，
+    /// 跳过该记录中的修改
+    fn redo_wal(
+        wal: Arc<Journal>,
+        memtable: &MemTable,
+        first_seq: u64,
+        idempotency_tokens: &mut IdempotencyTokens,
+    ) -> anyhow::Result<()> {
+        if wal.num_of_records() == 0 {
+            return Ok(());
+        }
+        let mut wal_iter = JournalIterator::create_and_seek_to_first(wal)?;
+        let mut record_idx = 0;
+        let mut record = vec![];
+        let mut duplicated = false;
+        while wal_iter.is_valid() {
+            if wal_iter.record_idx() != record_idx {
+                Db::apply_to_memtable(memtable, first_seq + record_idx as u64, &record);
+                record.clear();
+                record_idx = wal_iter.record_idx();
+                duplicated = false;
+            }
+            let wal_item = wal_iter.record_item();
+            let entry = wal_item.as_ref();
+            if let Some(token) = IdempotencyToken::from_entry(entry) {
+                duplicated |= idempotency_tokens.contains(&token);
+                idempotency_tokens.insert(token);
+            } else if !duplicated {
+                record.push(entry.clone());
+            }
+            wal_iter.next()?;
+        }
+        Db::apply_to_memtable(memtable, first_seq + record_idx as u64, &record);
+        Ok(())
+    }
+
+    /// 以序号 `seq` 将一次写入的修改放入 memtable，跳过幂等 token
+    ///
+    /// 同一次写入中的修改共用一个序号，同一个 key 被修改多次时只保留最后一次，
+    /// 否则同序号下删除标记总是排在前面，会盖过批次中之后的 put
+    fn apply_to_memtable(memtable: &MemTable, seq: u64, entries: &[Entry]) {
+        let mut applied = HashSet::new();
+        for entry in entries.iter().rev() {
+            if entry.is_idempotency_token() || !applied.insert(&entry.key) {
+                continue;
+            }
+            let internal_key = Db::make_internal_key(seq, entry.op_type(), &entry.key);
+            memtable.put(internal_key, entry.value.clone());
+        }
+    }
+
     #[instrument]
     pub fn open(path: impl AsRef<Path> + Debug) -> anyhow::Result<Self> {
+        Db::open_with_options(path, DbOptions::default())
+    }
+
+    /// 以指定的选项打开数据库，不启动后台任务
+    #[instrument]
+    pub fn open_with_options(
+        path: impl AsRef<Path> + Debug,
+        options: DbOptions,
+    ) -> anyhow::Result<Self> {
+        if options.max_value_size > u32::MAX as usize {
+            return Err(anyhow!(
+                "max_value_size {} exceeds the 4 GB limit of the file format",
+                options.max_value_size
+            ));
+        }
+        let registration = Registration::acquire(&path)?;
+        let open_start = Instant::now();
+        let mut recovery = RecoveryStats::default();
         let current_path = Db::path_of_current(&path);
         let version = 0;
 
         let mut levels: Vec<Vec<Arc<SsTable>>> = vec![];
         levels.resize(SST_LEVEL_LIMIT as usize, vec![]);
-        let mut vssts: HashMap<u32, Arc<SsTable>> = HashMap::new();
-        let mut vsst_rc: HashMap<u32, u32> = HashMap::new();
+        let mut vssts: HashMap<u64, Arc<SsTable>> = HashMap::new();
+        let mut vsst_rc: HashMap<u64, u32> = HashMap::new();
         let mut memtable = Arc::new(MemTable::new());
         let mut frozen_wal = vec![];
         let mut frozen_memtable = vec![];
         let mut sst_id = 0;
         let mut vsst_id = 0;
         let mut log_id = 0;
-        let sst_cache = Arc::new(BlockCache::new(BLOCK_CACHE_SIZE));
-        let vsst_cache = Arc::new(BlockCache::new(BLOCK_CACHE_SIZE));
+        let mut log_segments = 1;
+        let mut idempotency_tokens = IdempotencyTokens::new(IDEMPOTENCY_TOKEN_LIMIT);
+        let mut pending_deletes = vec![];
+        let mut commit_seq = 0;
+        let mut stats = CumulativeStats::default();
+        let new_cache = || {
+            let mut cache = BlockCache::new(options.block_cache_size);
+            if options.cache_compressed_blocks {
+                cache = cache.with_compressed_blocks();
+            }
+            Arc::new(match options.index_cache_size {
+                Some(capacity) => cache.with_index_capacity(capacity),
+                None => cache,
+            })
+        };
+        let sst_cache = new_cache();
+        let vsst_cache = new_cache();
 
+        if current_path.exists() && options.rebuild_legacy_metadata {
+            let report = admin::rebuild_legacy_metadata(&path)?;
+            info!(
+                "rebuild metadata of legacy SSTs {:?} and VSSTs {:?}",
+                report.rebuilt_ssts, report.rebuilt_vssts
+            );
+        }
         if current_path.exists() {
             // 从 CURRENT 中获取当前的 MANIFEST 文件
             let current_manifest: anyhow::Result<String> = {
@@ -318,14 +1195,20 @@ impl Db {
                 File::open(current_path.as_path())?.read_to_string(&mut content)?;
                 Ok(content)
             };
-            let manifest = Arc::new(Manifest::open(
+            let manifest = Arc::new(Manifest::open_with_compat(
                 path.as_ref().join(PathBuf::from(current_manifest?)),
+                options.skip_unknown_manifest_items,
             )?);
             // 根据 MANIFEST 恢复数据
             if manifest.num_of_records() > 0 {
-                let recover_res =
-                    Db::recover(&path, manifest, sst_cache.clone(), vsst_cache.clone())?;
-                debug!("recover result: {:?}", recover_res);
+                let recover_res = Db::recover(
+                    &path,
+                    manifest,
+                    sst_cache.clone(),
+                    vsst_cache.clone(),
+                    &options,
+                    &mut recovery,
+                )?;
                 (
                     levels,
                     sst_id,
@@ -336,16 +1219,49 @@ impl Db {
                     frozen_wal,
                     frozen_memtable,
                     vsst_rc,
+                    log_segments,
+                    idempotency_tokens,
+                    pending_deletes,
+                    commit_seq,
+                    stats,
                 ) = recover_res;
+                debug!(
+                    "recover result: {:?}, commit seq: {}, stats: {:?}",
+                    (
+                        &levels,
+                        sst_id,
+                        &vssts,
+                        vsst_id,
+                        &memtable,
+                        log_id,
+                        &frozen_wal,
+                        &frozen_memtable,
+                        &vsst_rc,
+                        &log_segments,
+                        &idempotency_tokens,
+                        &pending_deletes,
+                    ),
+                    commit_seq,
+                    stats
+                );
             }
         }
 
+        Db::remove_staging_files(&path)?;
+
         // 新建 MANIFEST 和 CURRENT，TODO 删除其它多余 MANIFEST
         let manifest_path = Db::path_of_manifest(&path, version + 1);
-        let mut manifest = Manifest::open(manifest_path.as_path())?;
+        let mut manifest = Manifest::open_with_compat(
+            manifest_path.as_path(),
+            options.skip_unknown_manifest_items,
+        )?;
+        manifest.set_strict_sync(options.strict_manifest_sync);
         let mut r = RecordBuilder::new();
         r.add(ManifestItem::Init(version as i32 + 1));
+        r.add(ManifestItem::Origin(RecordOrigin::now(ManifestActor::Open)));
         r.add(ManifestItem::FreezeAndCreateWal(log_id, log_id));
+        r.add(ManifestItem::CommitSeq(commit_seq));
+        r.add(ManifestItem::Stats(stats));
         for (_level, _ssts) in levels.iter().enumerate() {
             for sst in _ssts {
                 r.add(ManifestItem::NewSst(_level as u32, sst.id()));
@@ -353,9 +1269,19 @@ impl Db {
         }
         for (_vsst_id, _) in &vssts {
             r.add(ManifestItem::NewVSst(*_vsst_id));
+            if let Some(cnt) = vsst_rc.get(_vsst_id) {
+                r.add(ManifestItem::VSstRefCnt(*_vsst_id, *cnt));
+            }
+        }
+        // 缺失的 VSST 保留到引用全部清理之后，重新打开时仍能识别出指向它们的引用
+        for vsst_id in &recovery.missing_vssts {
+            r.add(ManifestItem::NewVSst(*vsst_id));
+            if let Some(cnt) = vsst_rc.get(vsst_id) {
+                r.add(ManifestItem::VSstRefCnt(*vsst_id, *cnt));
+            }
         }
-        manifest.add(&r.build());
-        let manifest = Arc::new(RwLock::new(manifest));
+        manifest.add(&r.build())?;
+        let manifest = Arc::new(TrackedRwLock::new(manifest));
         let mut current = OpenOptions::new()
             .write(true)
             .truncate(true)
@@ -363,19 +1289,26 @@ impl Db {
             .open(current_path)?;
         assert!(manifest_path.is_file());
         current.write(manifest_path.file_name().unwrap().as_bytes())?;
+        if options.strict_manifest_sync {
+            current.sync_all()?;
+            file::sync_dir(&path)?;
+        }
 
         // 构建Db
         let flush_chan = channel::bounded(1);
         let compaction_chan = channel::unbounded();
-        let exit_chan = channel::bounded(1);
-        let inner = Arc::new(RwLock::new(Arc::new(DbInner {
-            wal: Arc::new(Journal::open(log_id, Db::path_of_wal(&path, log_id))?),
+        let exit_chan = channel::unbounded();
+        let inner = Arc::new(TrackedRwLock::new(Arc::new(DbInner {
+            wal: Arc::new(Db::open_wal(&path, log_id, log_segments, &options)?),
             frozen_wal,
             memtable,
             frozen_memtable,
             levels,
             vssts: Arc::new(RwLock::new(vssts)),
             vsst_rc: Arc::new(RwLock::new(vsst_rc)),
+            missing_vssts: Arc::new(MissingVSsts::new(recovery.missing_vssts.iter().copied())),
+            idempotency_tokens: Arc::new(Mutex::new(idempotency_tokens)),
+            commit_seq: Arc::new(CommitSequence::new(commit_seq)),
             seq_num: 1,
 
             log_id,
@@ -384,97 +1317,1261 @@ impl Db {
         })));
 
         let path = Arc::new(PathBuf::from(path.as_ref()));
-        Ok(Db {
+        let daemon = Arc::new(DbDaemon::new(DaemonParams {
             inner: inner.clone(),
-            path: path.clone(),
-            version: AtomicU64::new(version as u64),
             sst_cache: sst_cache.clone(),
             vsst_cache: vsst_cache.clone(),
-
+            manifest: manifest.clone(),
+            path: path.clone(),
             flush_chan: flush_chan.clone(),
             compaction_chan: compaction_chan.clone(),
             exit_chan: exit_chan.clone(),
-            daemon: Arc::new(DbDaemon::new(
-                inner,
-                sst_cache,
-                vsst_cache,
-                manifest.clone(),
-                path,
-                flush_chan,
-                compaction_chan,
-                exit_chan,
-            )),
+            options: options.clone(),
+            stats,
+        }));
+        // 上次运行中没来得及删除的文件，在后台任务启动后继续删除
+        daemon.schedule_delete(
+            pending_deletes
+                .into_iter()
+                .map(|(file_type, id)| ObsoleteFile::new(file_type, id, None))
+                .collect(),
+        );
+        let mut db = Db {
+            inner: inner.clone(),
+            path: path.clone(),
+            version: AtomicU64::new(version as u64),
+            sst_cache: sst_cache.clone(),
+            vsst_cache: vsst_cache.clone(),
+
+            flush_chan,
+            compaction_chan,
+            exit_chan,
+            daemon,
             manifest,
-        })
+            snapshots: Arc::new(SnapshotTracker::new(options.iterator_age_warning)),
+            watchers: Watchers::default(),
+            validators: Validators::default(),
+            key_locks: KeyLocks::default(),
+            next_staging_id: AtomicU64::new(1),
+            write_stalls: WriteStallCounters::default(),
+            recovery: RecoveryStats {
+                total: open_start.elapsed(),
+                ..recovery
+            },
+            instance_tag: Db::instance_tag_of(registration.path().unwrap_or(path.as_ref())),
+            registration,
+            micro_batch: options.micro_batch.clone().map(MicroBatcher::new),
+            negative_cache: options.negative_cache_capacity.map(NegativeCache::new),
+            tracer: Tracer::default(),
+            options,
+            closed: AtomicBool::new(false),
+            read_only: AtomicBool::new(false),
+            background_tasks: Mutex::new(vec![]),
+            scheduler_membership: Mutex::new(None),
+            change_log: None,
+        };
+        if db.options.change_capture {
+            db.change_log = Some(db.recover_change_log()?);
+        }
+        Ok(db)
+    }
+
+    /// 删除 `path` 下数据库的所有文件，之后目录为空时一并删除
+    ///
+    /// 目录中没有指向 MANIFEST 的 CURRENT 时不认为是数据库，返回错误且不删除任何文件；数据库在当前进程中打开时同样返回错误。
+    /// 只删除数据库自己的文件：WAL、SST、VSST、查询结果暂存文件、回收站、MANIFEST 和 CURRENT，
+    /// 目录中的其他文件保留。CURRENT 最后删除，中途失败时可以再次调用
+    #[instrument]
+    pub fn destroy(path: impl AsRef<Path> + Debug) -> anyhow::Result<()> {
+        let path = path.as_ref();
+        let _registration = Registration::acquire(path)?;
+        let current_path = Db::path_of_current(path);
+        let current = fs::read_to_string(&current_path)
+            .with_context(|| format!("{:?} does not contain a database", path))?;
+        if !current.trim().ends_with(".MANIFEST") {
+            return Err(anyhow!(
+                "{:?} does not contain a database, CURRENT is {:?}",
+                path,
+                current
+            ));
+        }
+
+        let trash = Db::path_of_trash(path);
+        if trash.exists() {
+            fs::remove_dir_all(&trash)?;
+        }
+        // 先删除数据文件，MANIFEST 和 CURRENT 放在最后
+        let mut manifests = vec![];
+        let mut removed = 0;
+        for entry in fs::read_dir(path)? {
+            let file_path = entry?.path();
+            let Some(name) = file_path.file_name().and_then(|name| name.to_str()) else {
+                continue;
+            };
+            let is_db_file = name.split_once('.').is_some_and(|(id, ext)| {
+                !id.is_empty()
+                    && id.chars().all(|c| c.is_ascii_digit() || c == '-')
+                    && ["LOG", "SST", "VSST", "STAGE", "MANIFEST"].contains(&ext)
+            });
+            if !is_db_file {
+                continue;
+            }
+            if name.ends_with(".MANIFEST") {
+                manifests.push(file_path);
+                continue;
+            }
+            fs::remove_file(&file_path)?;
+            removed += 1;
+        }
+        for manifest in manifests {
+            fs::remove_file(manifest)?;
+            removed += 1;
+        }
+        fs::remove_file(&current_path)?;
+        info!(
+            "destroyed database {:?}, removed {} files",
+            path,
+            removed + 1
+        );
+        // 目录中还有其他文件时保留目录
+        if fs::read_dir(path)?.next().is_none() {
+            fs::remove_dir(path)?;
+        }
+        Ok(())
     }
 
     /// close database connect, that will ensure all committed transactions will be fsync to journal
+    ///
+    /// 会阻塞直到所有快照和迭代器被释放，因此调用前需要先 drop 当前线程持有的迭代器。
+    /// 之后按 [`Db::shutdown`] 的顺序停止后台任务，写入返回 [`DbClosedError`]，读取仍然可用。重复调用什么也不做
     pub fn close(&self) -> anyhow::Result<()> {
-        unimplemented!()
+        self.snapshots.wait_all_released();
+        self.shutdown()
     }
 
-    fn make_internal_key(seq_num: u64, op_type: OpType, key: &Bytes) -> Key {
-        Key::new(key.clone(), seq_num, op_type)
+    /// 开始把之后的写入、get 和范围查询记录到 `path`，用 [`crate::trace::replay`] 重放，见 [`crate::trace`]
+    ///
+    /// `path` 不能已存在，同一时间只能有一个进行中的记录
+    pub fn start_trace(&self, path: impl AsRef<Path>, options: TraceOptions) -> anyhow::Result<()> {
+        self.check_open()?;
+        self.tracer.start(path.as_ref(), options)
     }
 
-    /// put a key-value pair
-    #[instrument(skip_all)]
-    pub fn put(&self, key: Bytes, value: Bytes) -> anyhow::Result<()> {
-        self.append(key, Some(value))
+    /// 结束进行中的记录，记录文件 fsync 后返回
+    pub fn stop_trace(&self) -> anyhow::Result<TraceSummary> {
+        self.tracer
+            .stop()?
+            .ok_or_else(|| anyhow!("no trace in progress"))
     }
 
-    /// delete value by key
-    #[instrument(skip_all)]
-    pub fn delete(&self, key: Bytes) -> anyhow::Result<()> {
-        self.append(key, None)
+    /// 当前版本，等待 inner 读锁的时间计入 [`crate::PerfContext::lock_wait`]
+    fn current_inner(&self) -> Arc<DbInner> {
+        let guard = perf_context::timed_lock(|| self.inner.read());
+        Arc::clone(&guard)
     }
 
-    /// get value by key
-    #[instrument(skip_all)]
-    pub fn get(&self, key: &Bytes) -> anyhow::Result<Option<Bytes>> {
-        let (snapshot, seq_num) = {
-            let guard = self.inner.read();
-            (Arc::clone(&guard), guard.seq_num)
-        };
-
-        let internal_key = Db::make_internal_key(seq_num, Get, key);
-
-        // memtable
-        if let Some((_, v)) = snapshot.memtable.get(&internal_key) {
-            return Ok(Some(v));
+    pub(crate) fn check_open(&self) -> Result<(), DbClosedError> {
+        if self.closed.load(Ordering::Acquire) {
+            return Err(DbClosedError);
         }
+        Ok(())
+    }
+
+    /// 停止写入和后台任务，`close` 和 drop 共用，只有第一次调用生效
+    ///
+    /// 顺序：
+    /// 1. 拒绝新的写入，等待进行中的写入完成
+    /// 2. 通知后台线程退出：已请求的刷写执行完，排队的合并取消，等待删除的文件删完
+    /// 3. 等待后台线程退出
+    /// 4. 刷写 WAL，memtable 中的数据下次打开时从 WAL 恢复
+    /// 5. 在 MANIFEST 中记录最终的提交序号和累计计数
+    fn shutdown(&self) -> anyhow::Result<()> {
+        {
+            // 写入在持有读锁时检查 closed，拿到写锁后不再有进行中的写入，之后的写入都会被拒绝
+            let _guard = self.inner.write();
+            if self.closed.swap(true, Ordering::AcqRel) {
+                return Ok(());
+            }
+        }
+        if let Err(e) = self.tracer.stop() {
+            warn!("finish trace failed: {:#}", e);
+        }
+
+        if let Some(membership) = self.scheduler_membership.lock().take() {
+            // 与独立的后台线程退出时相同：已请求的刷写仍然执行，排队的合并直接取消。
+            // 在通知删除线程退出之前执行，刷写后被替换的 WAL 仍能在退出前删除
+            membership.leave();
+            while self.daemon.take_flush_request() {
+                if let Err(err) = self.daemon.run_flush_request() {
+                    error!("rotate failed: {}", err)
+                }
+            }
+            while self.daemon.take_compaction_request().is_some() {
+                self.daemon.compaction_gauge.on_cancel();
+            }
+        }
+
+        let background_tasks = std::mem::take(&mut *self.background_tasks.lock());
+        for _ in &background_tasks {
+            let _ = self.exit_chan.0.send(());
+        }
+        for handle in background_tasks {
+            // 最后一个 `Arc<Db>` 可能在 CDC 投递线程上释放，不能等待自己退出
+            if handle.thread().id() == thread::current().id() {
+                continue;
+            }
+            if handle.join().is_err() {
+                error!("background task panicked");
+            }
+        }
+
+        let inner = {
+            let guard = self.inner.read();
+            Arc::clone(&guard)
+        };
+        inner.wal.flush()?;
+        let mut r = RecordBuilder::new();
+        r.add(ManifestItem::Origin(RecordOrigin::now(
+            ManifestActor::Close,
+        )));
+        r.add(ManifestItem::CommitSeq(inner.commit_seq.last_allocated()));
+        r.add(ManifestItem::Stats(self.daemon.counters.snapshot()));
+        self.manifest.write().add(&r.build())?;
+        info!("db {:?} closed", self.path);
+        Ok(())
+    }
+
+    /// 按组成部分估算数据库的常驻内存，供嵌入方按进程的内存预算限制写入或缓存
+    ///
+    /// memtable 按写入的 key 和 value 的字节数计算，块缓存按其中的块计算（见 [`DbStats::block_cache_memory`]）。
+    /// 不包括迭代器持有的、不在块缓存中的块和合并、刷写过程中的临时内存
+    pub fn approximate_memory_usage(&self) -> MemoryUsage {
+        let snapshot = {
+            let guard = self.inner.read();
+            Arc::clone(&guard)
+        };
+        let (filters, indexes) = self.table_memory(&snapshot);
+        MemoryUsage {
+            active_memtable: snapshot.memtable.size() as u64,
+            frozen_memtables: snapshot
+                .frozen_memtable
+                .iter()
+                .map(|memtable| memtable.size() as u64)
+                .sum(),
+            pinned_memtables: self.daemon.pinned_memtable_memory(),
+            block_cache: self.sst_cache.block_memory() + self.vsst_cache.block_memory(),
+            filters,
+            indexes,
+        }
+    }
+
+    /// SST / VSST 的 bloom filter 和 meta 占用的内存
+    fn table_memory(&self, snapshot: &DbInner) -> (u64, u64) {
+        let tables: Vec<_> = snapshot
+            .levels
+            .iter()
+            .flatten()
+            .cloned()
+            .chain(snapshot.vssts.read().values().cloned())
+            .collect();
+        let filter_memory = tables.iter().map(|sst| sst.filter_memory()).sum();
+        let index_memory = tables.iter().map(|sst| sst.index_memory()).sum::<u64>()
+            + self.sst_cache.index_memory()
+            + self.vsst_cache.index_memory();
+        (filter_memory, index_memory)
+    }
+
+    /// 后台任务队列和各层文件数等运行状态
+    pub fn stats(&self) -> DbStats {
+        let snapshot = {
+            let guard = self.inner.read();
+            Arc::clone(&guard)
+        };
+        let (filter_memory, index_memory) = self.table_memory(&snapshot);
+        let sst_blocks = self.sst_cache.block_memory_by_kind();
+        let vsst_blocks = self.vsst_cache.block_memory_by_kind();
+        let iterators = self.snapshots.iterators();
+        DbStats {
+            flush_queue: self.daemon.flush_queue_stats(),
+            flush_jobs: self.daemon.flush_job_status(),
+            compaction_queue: self.daemon.compaction_queue_stats(),
+            frozen_memtables: snapshot.frozen_memtable.len(),
+            level_files: snapshot.levels.iter().map(|level| level.len()).collect(),
+            bottom_level_overflow: DbDaemon::bottom_level_overflow(&snapshot.levels),
+            filter_memory,
+            index_memory,
+            index_loads: self.sst_cache.index_loads() + self.vsst_cache.index_loads(),
+            deduplicated_block_loads: self.sst_cache.deduplicated_loads()
+                + self.vsst_cache.deduplicated_loads(),
+            block_cache_hits: self.sst_cache.hits() + self.vsst_cache.hits(),
+            compressed_block_cache_hits: self.sst_cache.compressed_hits()
+                + self.vsst_cache.compressed_hits(),
+            block_cache_memory: sst_blocks.decoded + vsst_blocks.decoded,
+            compressed_block_cache_memory: sst_blocks.compressed + vsst_blocks.compressed,
+            delayed_background_reads: file::delayed_background_reads(),
+            cooperative_yields: cooperative_yields(),
+            read_memory: memory_limit::read_memory_used(),
+            read_memory_rejections: memory_limit::read_memory_rejections(),
+            grouped_writes: self
+                .micro_batch
+                .as_ref()
+                .map_or(0, |batcher| batcher.grouped_writes()),
+            negative_cache_hits: self.negative_cache.as_ref().map_or(0, |cache| cache.hits()),
+            pending_deletes: self.daemon.num_of_pending_deletes(),
+            cumulative: self.daemon.counters.snapshot(),
+            write_stalls: self.write_stalls.stats(),
+            verified_blocks: verified_blocks(),
+            checksum_failures: checksum_failures(),
+            recovery: self.recovery.clone(),
+            key_prefixes: self.daemon.key_prefixes.lock().clone(),
+            value_metrics: self.daemon.value_inspectors.metrics(),
+            dangling_value_reads: snapshot.missing_vssts.reads(),
+            active_iterators: iterators.len(),
+            oldest_iterator_age: iterators.first().map(|iterator| iterator.age),
+            lock_contention: LockContentionStats {
+                inner_read: self.inner.read_waits(),
+                inner_write: self.inner.write_waits(),
+                manifest_read: self.manifest.read_waits(),
+                manifest_write: self.manifest.write_waits(),
+                file_write: file::writer_lock_waits(),
+            },
+        }
+    }
+
+    /// 根据打开以来刷写和合并写入的 key 给出前缀压缩的重启间隔和 key 设计的建议，见 [`crate::KeyPrefixStats::advise`]
+    pub fn prefix_compression_advice(&self) -> PrefixAdvice {
+        self.daemon.key_prefixes.lock().advise()
+    }
+
+    /// 返回每一层的 key 范围、总大小和其中的 SST，层号从 0 开始
+    pub fn level_metadata(&self) -> Vec<LevelMetadata> {
+        let snapshot = {
+            let guard = self.inner.read();
+            Arc::clone(&guard)
+        };
+        snapshot
+            .levels
+            .iter()
+            .enumerate()
+            .map(|(level, ssts)| {
+                let files = ssts
+                    .iter()
+                    .filter(|sst| sst.num_of_blocks() > 0)
+                    .map(|sst| {
+                        let (smallest_key, largest_key) = sst.key_range();
+                        SstMetadata {
+                            id: sst.id(),
+                            size: sst.size(),
+                            num_of_pairs: sst.num_of_pairs(),
+                            num_of_deletes: sst.num_of_deletes(),
+                            max_seq: sst.max_seq(),
+                            smallest_key,
+                            largest_key,
+                            seeks: sst.seeks(),
+                            seek_misses: sst.seek_misses(),
+                        }
+                    })
+                    .collect();
+                LevelMetadata::new(level as u32, files)
+            })
+            .collect()
+    }
+
+    /// 检查后台任务是否积压，请求等待超过 [`BACKGROUND_LAG_LIMIT`]、最后一层超出大小限制，
+    /// 或有迭代器存活超过 [`DbOptions::iterator_age_warning`] 时状态为 `Degraded`
+    pub fn health(&self) -> Health {
+        let stats = self.stats();
+        let mut issues = vec![];
+        for (name, queue) in [
+            ("flush", &stats.flush_queue),
+            ("compaction", &stats.compaction_queue),
+        ] {
+            if let Some(age) = queue.oldest_pending_age {
+                if age > BACKGROUND_LAG_LIMIT {
+                    issues.push(format!(
+                        "{} request pending for {:?}, {} in queue",
+                        name, age, queue.pending
+                    ));
+                }
+            }
+        }
+        if stats.bottom_level_overflow > 0 {
+            issues.push(format!(
+                "L{} exceeds its size limit by {} bytes",
+                SST_LEVEL_LIMIT - 1,
+                stats.bottom_level_overflow
+            ));
+        }
+        if let Some(busy) = self.frozen_limit_exceeded() {
+            issues.push(format!(
+                "{} frozen memtables and {} frozen WALs exceed the limit, writes are {}",
+                busy.frozen_memtables,
+                busy.frozen_wals,
+                match self.options.frozen_limit_action {
+                    FrozenLimitAction::Stall => "stalled",
+                    FrozenLimitAction::Busy => "rejected",
+                }
+            ));
+        }
+        let snapshot = Arc::clone(&self.inner.read());
+        let dangling: Vec<u64> = {
+            let vsst_rc = snapshot.vsst_rc.read();
+            let mut ids: Vec<u64> = snapshot
+                .missing_vssts
+                .ids()
+                .filter(|vsst_id| vsst_rc.contains_key(vsst_id))
+                .collect();
+            ids.sort_unstable();
+            ids
+        };
+        if !dangling.is_empty() {
+            issues.push(format!(
+                "missing VSSTs {:?} are still referenced, {} reads returned not found",
+                dangling, stats.dangling_value_reads
+            ));
+        }
+        let overdue_iterators = self.snapshots.check_iterator_age();
+        if overdue_iterators > 0 {
+            issues.push(format!(
+                "{} iterators open for longer than {:?}, oldest for {:?}",
+                overdue_iterators,
+                self.options.iterator_age_warning.unwrap_or_default(),
+                stats.oldest_iterator_age.unwrap_or_default()
+            ));
+        }
+        Health {
+            status: if issues.is_empty() {
+                HealthStatus::Ok
+            } else {
+                HealthStatus::Degraded
+            },
+            issues,
+        }
+    }
+
+    /// 计算对 `level` 层发起合并时会选中的文件和预计数据量，不实际执行合并
+    pub fn plan_compaction(&self, level: u32) -> Option<CompactionPlan> {
+        self.plan_compaction_to(level, level + 1)
+    }
+
+    /// 计算 [`Db::compact_to`] 会选中的文件和预计数据量，不实际执行合并
+    pub fn plan_compaction_to(&self, level: u32, output_level: u32) -> Option<CompactionPlan> {
+        let snapshot = {
+            let guard = self.inner.read();
+            Arc::clone(&guard)
+        };
+        DbDaemon::plan_compaction(
+            &snapshot.levels,
+            level,
+            output_level,
+            self.options.max_compaction_bytes,
+        )
+    }
+
+    fn make_internal_key(seq_num: u64, op_type: OpType, key: &Bytes) -> Key {
+        Key::new(key.clone(), seq_num, op_type)
+    }
+
+    /// put a key-value pair
+    ///
+    /// 返回这次写入的提交序号，见 [`Db::wait_for_seq`]
+    #[instrument(skip_all)]
+    pub fn put(&self, key: Bytes, value: Bytes) -> anyhow::Result<u64> {
+        self.append(key, Some(value), &WriteOptions::default())
+    }
+
+    /// put a key-value pair with write options
+    #[instrument(skip_all)]
+    pub fn put_with_options(
+        &self,
+        key: Bytes,
+        value: Bytes,
+        options: &WriteOptions,
+    ) -> anyhow::Result<u64> {
+        self.append(key, Some(value), options)
+    }
+
+    /// delete value by key
+    ///
+    /// 返回这次写入的提交序号，见 [`Db::wait_for_seq`]
+    #[instrument(skip_all)]
+    pub fn delete(&self, key: Bytes) -> anyhow::Result<u64> {
+        self.append(key, None, &WriteOptions::default())
+    }
+
+    /// delete value by key with write options
+    #[instrument(skip_all)]
+    pub fn delete_with_options(&self, key: Bytes, options: &WriteOptions) -> anyhow::Result<u64> {
+        self.append(key, None, options)
+    }
+
+    /// 写入 key-value 并返回写入前的 value，不存在时返回 `None`
+    ///
+    /// 读取和写入之间不会有其他对该 key 的写入，适合计数器、累加器等读后写的场景，
+    /// 避免先 `get` 再 `put` 的竞争和两次查找。导入外部 SST 不受此保证
+    #[instrument(skip_all)]
+    pub fn put_get(&self, key: Bytes, value: Bytes) -> anyhow::Result<Option<Bytes>> {
+        self.put_get_with_options(key, value, &WriteOptions::default())
+    }
+
+    /// 以指定的写入选项写入 key-value 并返回写入前的 value，见 [`Db::put_get`]
+    #[instrument(skip_all)]
+    pub fn put_get_with_options(
+        &self,
+        key: Bytes,
+        value: Bytes,
+        options: &WriteOptions,
+    ) -> anyhow::Result<Option<Bytes>> {
+        let entry = self.prepare_entry(key, Some(value), options)?;
+        let _lock = perf_context::timed_lock(|| self.key_locks.lock(&entry.key));
+        let previous = self.get(&entry.key)?;
+        let guard = perf_context::timed_lock(|| self.inner.read());
+        self.write_entries(&guard, vec![entry], options)?;
+        Ok(previous)
+    }
+
+    /// 已对读取可见的最大提交序号，比它小的写入也都已可见
+    pub fn last_commit_seq(&self) -> u64 {
+        self.inner.read().commit_seq.last_visible()
+    }
+
+    /// 等待提交序号 `seq` 及之前的写入对读取可见，超时返回 `false`
+    ///
+    /// 写入返回的序号可以作为 read-your-writes 的一致性 token 传给读取方，读取方读取前先等待数据库追上该序号。
+    /// 序号在重启后继续递增，但关闭 WAL 的写入在崩溃后丢失，它们的序号仍视为已可见
+    pub fn wait_for_seq(&self, seq: u64, timeout: Duration) -> bool {
+        let commit_seq = self.inner.read().commit_seq.clone();
+        commit_seq.wait_for(seq, timeout)
+    }
+
+    /// 订阅 key 以 `prefix` 开头的修改，修改写入 WAL 和 memtable 之后送达，
+    /// drop 返回的接收端即取消订阅
+    ///
+    /// 同一次写入（批次）中的修改连续送达；只包含订阅之后提交的修改
+    pub fn watch_prefix(&self, prefix: Bytes) -> channel::Receiver<WatchEvent> {
+        self.watchers.watch(prefix)
+    }
+
+    /// 注册一个写入校验回调，同名的回调已存在时替换它
+    ///
+    /// 每次写入（单个 put / delete 或一个批次）在写入 WAL 之前按注册顺序调用所有回调，
+    /// 任一回调拒绝时写入返回 [`WriteRejectedError`](crate::WriteRejectedError)，整个批次都不会写入。
+    /// 回调在写入线程上同步执行，不持有任何数据库的锁，应尽量轻量；导入外部 SST 不经过回调
+    pub fn add_write_validator<F>(&self, name: impl Into<String>, validator: F)
+    where
+        F: Fn(&[WriteOp]) -> Result<(), Rejection> + Send + Sync + 'static,
+    {
+        self.validators.add(name.into(), Arc::new(validator));
+    }
+
+    /// 移除名为 `name` 的写入校验回调，返回是否存在
+    pub fn remove_write_validator(&self, name: &str) -> bool {
+        self.validators.remove(name)
+    }
+
+    /// 已注册的写入校验回调数量
+    pub fn num_of_write_validators(&self) -> usize {
+        self.validators.len()
+    }
+
+    /// 注册一个 value 检查回调，同名的回调已存在时替换它
+    ///
+    /// 刷写和合并写出 SST 时按 [`DbOptions::value_inspection_sampling`] 抽样，把 key 和 value 交给所有回调，
+    /// 回调在 [`ValueMetrics`] 中记录自定义的指标（例如各类记录的数量），从 [`DbStats`] 的 `value_metrics` 中读取。
+    /// 回调只能观察，不能修改或丢弃 entry；删除标记、系统 key 和合并时没有读出的 KV 分离的 value 不交给回调。
+    /// 指标从打开时开始累计，不持久化，同一个 value 每次被合并都会再次计入
+    pub fn add_value_inspector<F>(&self, name: impl Into<String>, inspector: F)
+    where
+        F: Fn(&[u8], &[u8], &mut ValueMetrics) + Send + Sync + 'static,
+    {
+        self.daemon
+            .value_inspectors
+            .add(name.into(), Arc::new(inspector));
+    }
+
+    /// 移除名为 `name` 的 value 检查回调和它累计的指标，返回是否存在
+    pub fn remove_value_inspector(&self, name: &str) -> bool {
+        self.daemon.value_inspectors.remove(name)
+    }
+
+    /// 原子地写入一个批次，返回 `false` 表示批次的幂等 token 最近已被应用过，本次写入被跳过
+    #[instrument(skip_all)]
+    pub fn write(&self, batch: WriteBatch) -> anyhow::Result<bool> {
+        self.write_with_options(batch, &WriteOptions::default())
+    }
+
+    /// 以指定的选项原子地写入一个批次，见 [`Db::write`]
+    #[instrument(skip_all)]
+    pub fn write_with_options(
+        &self,
+        batch: WriteBatch,
+        options: &WriteOptions,
+    ) -> anyhow::Result<bool> {
+        Ok(self.write_batch(batch, options)?.is_some())
+    }
+
+    /// 以指定的选项原子地写入一个批次，返回批次的提交序号，批次的幂等 token 最近已被应用过时返回 `None`
+    ///
+    /// 空批次不会写入，返回当前已可见的最大提交序号
+    #[instrument(skip_all)]
+    pub fn write_batch(
+        &self,
+        batch: WriteBatch,
+        options: &WriteOptions,
+    ) -> anyhow::Result<Option<u64>> {
+        let token = batch.idempotency_token();
+        let mut entries = batch.into_entries();
+        trace!("batch size: {}, token: {:?}", entries.len(), token);
+        if !entries.is_empty() {
+            validate::check_value_size(&entries, self.options.max_value_size)?;
+            self.validators.validate(&entries)?;
+        }
+        self.throttle_low_priority(options);
+        self.wait_for_frozen_limit()?;
+
+        let _locks = perf_context::timed_lock(|| {
+            self.key_locks.lock_all(entries.iter().map(|e| &e.key[..]))
+        });
+        // 先获取 inner 再获取 token 锁，与 rotate 的加锁顺序一致
+        let guard = perf_context::timed_lock(|| self.inner.read());
+        let Some(token) = token else {
+            if entries.is_empty() {
+                return Ok(Some(guard.commit_seq.last_visible()));
+            }
+            return self.write_entries(&guard, entries, options).map(Some);
+        };
+        let mut tokens = guard.idempotency_tokens.lock();
+        if tokens.contains(&token) {
+            debug!("skip duplicated batch, token: {:?}", token);
+            return Ok(None);
+        }
+        entries.insert(0, token.to_entry());
+        let seq = self.write_entries(&guard, entries, options)?;
+        tokens.insert(token);
+        Ok(Some(seq))
+    }
+
+    /// 立即对 `level` 层执行一次合并，没有可合并的文件时什么也不做
+    pub fn compact(&self, level: u32) -> anyhow::Result<()> {
+        self.check_open()?;
+        self.daemon.compaction(level)
+    }
+
+    /// 修改 KV 分离的阈值，为 `None` 时关闭 KV 分离，见 [`DbOptions::value_separation_threshold`]
+    ///
+    /// 之后的刷写按新的阈值分离 value；之后的合并把已分离但长度不超过新阈值的 value 读回 SST，
+    /// 并减少原 VSST 的引用计数，关闭 KV 分离时所有参与合并的 value 都会读回。
+    /// 已在 SST 中的 value 不会因为阈值降低而被分离。修改不会持久化，重新打开后使用选项中的值
+    pub fn set_value_separation_threshold(&self, threshold: Option<u64>) {
+        self.daemon.set_value_separation_threshold(threshold)
+    }
+
+    /// 当前 KV 分离的阈值，见 [`Db::set_value_separation_threshold`]
+    pub fn value_separation_threshold(&self) -> Option<u64> {
+        self.daemon.value_separation_threshold()
+    }
+
+    /// 立即合并 `level` 层的一组 SST，结果直接写入 `output_level` 层，没有可合并的文件时什么也不做
+    ///
+    /// 两层之间与输入重叠的 SST 一起参与合并。用于批量导入：关闭 WAL 写入并刷写后，
+    /// 反复对 L0 调用直到 L0 为空，把数据直接放到最后一层，避免逐层合并的写放大
+    pub fn compact_to(&self, level: u32, output_level: u32) -> anyhow::Result<()> {
+        self.check_open()?;
+        if output_level <= level || output_level >= SST_LEVEL_LIMIT {
+            return Err(anyhow!(
+                "invalid compaction output level {} for L{}, must be in ({}, {})",
+                output_level,
+                level,
+                level,
+                SST_LEVEL_LIMIT
+            ));
+        }
+        self.daemon.compaction_to(level, output_level)
+    }
+
+    /// 把 `level` 层下一次合并的输入导出到任务目录 `dir`，没有可合并的文件时返回 `None`
+    ///
+    /// 用于把大的合并交给其他进程或机器执行：对任务目录调用 [`crate::admin::run_compaction_job`] 后，
+    /// 再用 [`Db::import_compaction`] 导入输出。期间后台合并可能已经合并了同样的输入，此时导入会失败，
+    /// 需要关闭对应层的自动合并或重新导出
+    pub fn export_compaction(
+        &self,
+        level: u32,
+        dir: impl AsRef<Path>,
+    ) -> anyhow::Result<Option<CompactionJob>> {
+        self.daemon.export_compaction(level, dir.as_ref())
+    }
+
+    /// 把 `snapshot` 中可见的所有 key 的最新值写入 `dir` 中新的 SST，`dir` 可以直接作为数据库打开
+    ///
+    /// 导出的 SST 都在最后一层，互不重叠，不包含删除标记和旧版本；超过当前 KV 分离阈值的 value 写入新的 VSST，
+    /// 不引用本数据库的任何文件。所有文件落盘后才写入 CURRENT，`dir` 中已有数据库时返回错误。
+    /// 用于逻辑备份或克隆数据库，耗时与快照中的数据量成正比，期间快照引用的文件不会被删除
+    pub fn export_snapshot(
+        &self,
+        snapshot: &Snapshot,
+        dir: impl AsRef<Path>,
+    ) -> anyhow::Result<ExportReport> {
+        export::export_snapshot(
+            snapshot,
+            dir.as_ref(),
+            self.value_separation_threshold(),
+            &self.options,
+        )
+    }
+
+    /// 在数据库打开时把数据复制到 `dir`，`dir` 可以直接作为数据库打开，关闭时用 [`crate::admin::copy_db`]
+    ///
+    /// 先刷写 memtable，调用前完成的写入都在副本中。复制当前版本引用的 SST 和 VSST，文件编号和层级不变，
+    /// 副本有新的 MANIFEST 和空的 WAL。复制期间写入和读取照常进行，但新的刷写要等复制完成，
+    /// 数据量大时 memtable 可能超过大小限制。所有文件落盘后才写入 CURRENT，`dir` 中已有数据库时返回错误
+    pub fn checkpoint(&self, dir: impl AsRef<Path>) -> anyhow::Result<CopyReport> {
+        self.check_open()?;
+        self.daemon.checkpoint(dir.as_ref())
+    }
+
+    /// 导入任务目录 `dir` 中已执行的合并，输出替换输入的操作写在同一条 MANIFEST 记录中，见 [`Db::export_compaction`]
+    ///
+    /// 合并输出的是已写入的数据，不按 [`DbOptions::max_value_size`] 检查
+    pub fn import_compaction(&self, dir: impl AsRef<Path>) -> anyhow::Result<()> {
+        self.daemon.import_compaction(dir.as_ref())
+    }
+
+    /// 返回在调用线程上逐个执行刷写和合并请求的句柄，见 [`crate::SchedulerHandle`]
+    ///
+    /// 只能用于以 [`Db::open_with_options`] 打开、没有启动后台线程的数据库，否则返回错误
+    #[cfg(any(test, feature = "test-util"))]
+    pub fn scheduler(&self) -> anyhow::Result<crate::SchedulerHandle<'_>> {
+        if !self.background_tasks.lock().is_empty() {
+            return Err(anyhow!(
+                "scheduler requires a database without background threads"
+            ));
+        }
+        Ok(crate::SchedulerHandle::new(self))
+    }
+
+    /// 立即 fsync 当前和已冻结的 WAL，返回之后的持久化水位，见 [`Db::durable_seq`]
+    pub fn sync_wal(&self) -> anyhow::Result<u64> {
+        self.check_open()?;
+        self.daemon.sync_wal()
+    }
+
+    /// 已 fsync 到 WAL 的最大提交序号，提交序号不超过它且写入了 WAL 的修改掉电后都能恢复
+    ///
+    /// 打开时为恢复出的最大序号，之后由 [`DbOptions::wal_sync_interval`] 的后台线程或 [`Db::sync_wal`] 推进；
+    /// 开启 [`WriteOptions::sync`] 的写入只刷写写缓冲，不推进水位
+    pub fn durable_seq(&self) -> u64 {
+        self.daemon.durable_seq()
+    }
+
+    /// 将当前 memtable 刷写到 L0 SST，之后即使 WAL 中没有这些数据也不会丢失
+    pub fn flush(&self) -> anyhow::Result<()> {
+        self.check_open()?;
+        self.daemon.freeze_and_flush()
+    }
+
+    /// 在运行时切换只读，只读时 put / delete / write_batch 等写入返回 [`ReadOnlyError`]，读取不受影响
+    ///
+    /// 切换为只读时等待正在进行的写入完成，再像 [`Db::flush`] 一样把 memtable 刷写到 SST，返回后所有已完成的写入都在 SST 中。
+    /// 只读不停止后台合并，需要时另外调用 [`Db::set_compactions_paused`]。切换不会持久化，重新打开后可以写入
+    pub fn set_read_only(&self, read_only: bool) -> anyhow::Result<()> {
+        self.check_open()?;
+        self.read_only.store(read_only, Ordering::Release);
+        if read_only {
+            // 写入在 inner 的读锁下检查只读，拿一次写锁等待已经通过检查的写入完成
+            drop(self.inner.write());
+            self.daemon.freeze_and_flush()?;
+        }
+        Ok(())
+    }
+
+    /// 是否为只读，见 [`Db::set_read_only`]
+    pub fn is_read_only(&self) -> bool {
+        self.read_only.load(Ordering::Acquire)
+    }
+
+    /// 暂停或恢复自动合并，暂停时不再发起新的合并，排队的合并请求取消，正在执行的合并继续完成
+    ///
+    /// 恢复时按各层当前的状态重新发起需要的合并。[`Db::compact`] 等手动合并不受影响，切换不会持久化
+    pub fn set_compactions_paused(&self, paused: bool) {
+        self.daemon.set_compactions_paused(paused)
+    }
+
+    /// 自动合并是否暂停，见 [`Db::set_compactions_paused`]
+    pub fn compactions_paused(&self) -> bool {
+        self.daemon.compactions_paused()
+    }
+
+    /// get value by key
+    ///
+    /// 按 memtable -> frozen memtable -> L0 -> L1 ... 的顺序查找，遇到的第一个版本即为最新版本，
+    /// 若该版本是删除标记则直接返回 `None`，不再继续向更旧的数据查找。数据库内部使用的系统 key 总是返回 `None`
+    ///
+    /// value 按大小登记到读内存预算，超出 [`crate::set_read_memory_limit`] 时返回 [`crate::MemoryLimitError`]
+    #[instrument(skip_all)]
+    pub fn get(&self, key: &Bytes) -> anyhow::Result<Option<Bytes>> {
+        let value = self.read_latest(key, |snapshot| {
+            self.get_from(snapshot, key, MAX_SEQ_NUM, BlockReadOptions::default())
+        })?;
+        self.tracer.record_get(key, value.as_ref().map(|v| v.len()));
+        Ok(value)
+    }
+
+    /// 以指定的读取选项读取单个 key
+    pub fn get_with_options(
+        &self,
+        key: &Bytes,
+        options: &ReadOptions,
+    ) -> anyhow::Result<Option<Bytes>> {
+        let value = match options.snapshot {
+            Some(ref snapshot) => self.get_from(
+                snapshot.inner(),
+                key,
+                snapshot.commit_seq(),
+                options.block_options(),
+            ),
+            None => self.read_latest(key, |snapshot| {
+                self.get_from(snapshot, key, MAX_SEQ_NUM, options.block_options())
+            }),
+        }?;
+        self.tracer.record_get(key, value.as_ref().map(|v| v.len()));
+        Ok(value)
+    }
+
+    /// 在当前版本上读取 key，开启了 [`DbOptions::negative_cache_capacity`] 时先查缓存，命中时不执行 `read`；
+    /// `read` 返回 `None` 时 key 确认不存在，加入缓存
+    fn read_latest<T>(
+        &self,
+        key: &Bytes,
+        read: impl FnOnce(&DbInner) -> anyhow::Result<Option<T>>,
+    ) -> anyhow::Result<Option<T>> {
+        let Some(cache) = &self.negative_cache else {
+            return read(&self.current_inner());
+        };
+        if cache.contains(key) {
+            return Ok(None);
+        }
+        let epoch = cache.epoch(key);
+        let value = read(&self.current_inner())?;
+        if value.is_none() {
+            cache.insert(key, epoch);
+        }
+        Ok(value)
+    }
+
+    /// 在快照上读取单个 key，快照创建之后的写入不可见
+    pub fn get_with_snapshot(
+        &self,
+        snapshot: &Snapshot,
+        key: &Bytes,
+    ) -> anyhow::Result<Option<Bytes>> {
+        self.get_from(
+            snapshot.inner(),
+            key,
+            snapshot.commit_seq(),
+            BlockReadOptions::default(),
+        )
+    }
+
+    /// 在同一个快照上批量读取，结果与 `keys` 一一对应，`None` 表示 key 不存在；快照创建之后的写入不可见
+    pub fn get_many_with_snapshot(
+        &self,
+        snapshot: &Snapshot,
+        keys: &[Bytes],
+    ) -> anyhow::Result<Vec<Option<Bytes>>> {
+        self.multi_get_from(
+            snapshot.inner(),
+            keys,
+            snapshot.commit_seq(),
+            BlockReadOptions::default(),
+        )
+    }
+
+    /// 在一个新快照上批量读取，结果与 `keys` 一一对应，`None` 表示 key 不存在
+    ///
+    /// KV 分离的 value 按所在的 VSST 分组后批量读取，见 [`Db::read_separated_values`]；
+    /// 所有 value 的总大小超出读内存预算时返回 [`crate::MemoryLimitError`]
+    #[instrument(skip_all)]
+    pub fn multi_get(&self, keys: &[Bytes]) -> anyhow::Result<Vec<Option<Bytes>>> {
+        self.multi_get_with_options(keys, &ReadOptions::default())
+    }
+
+    /// 以指定的读取选项批量读取，未指定快照时在一个新快照上读取
+    #[instrument(skip_all)]
+    pub fn multi_get_with_options(
+        &self,
+        keys: &[Bytes],
+        options: &ReadOptions,
+    ) -> anyhow::Result<Vec<Option<Bytes>>> {
+        let values = match options.snapshot {
+            Some(ref snapshot) => self.multi_get_from(
+                snapshot.inner(),
+                keys,
+                snapshot.commit_seq(),
+                options.block_options(),
+            ),
+            None => {
+                let snapshot = self.current_inner();
+                self.multi_get_from(&snapshot, keys, MAX_SEQ_NUM, options.block_options())
+            }
+        }?;
+        for (key, value) in keys.iter().zip(&values) {
+            self.tracer.record_get(key, value.as_ref().map(|v| v.len()));
+        }
+        Ok(values)
+    }
+
+    fn multi_get_from(
+        &self,
+        snapshot: &DbInner,
+        keys: &[Bytes],
+        seq: u64,
+        options: BlockReadOptions,
+    ) -> anyhow::Result<Vec<Option<Bytes>>> {
+        let mut values = Vec::with_capacity(keys.len());
+        let mut separated = vec![];
+        // 所有 value 在返回前同时占用内存，按总大小登记，KV 分离的 value 在读取 VSST 之前登记
+        let mut reservation = memory_limit::empty_reservation();
+        for (idx, key) in keys.iter().enumerate() {
+            values.push(match self.find_entry(snapshot, key, seq, options)? {
+                None => None,
+                Some(FoundEntry::Value(op_type, value)) => {
+                    let value = Db::visible_value(op_type, value);
+                    reservation.grow(value.as_ref().map_or(0, |value| value.len()))?;
+                    value
+                }
+                Some(FoundEntry::Separated(value)) => {
+                    reservation.grow(Db::separated_value_size(&value))?;
+                    separated.push((idx, value));
+                    None
+                }
+            });
+        }
+        for (idx, value) in Db::read_separated_values(snapshot, keys, &separated, options)? {
+            values[idx] = Some(value);
+        }
+        Ok(values)
+    }
+
+    fn get_from(
+        &self,
+        snapshot: &DbInner,
+        key: &Bytes,
+        seq: u64,
+        options: BlockReadOptions,
+    ) -> anyhow::Result<Option<Bytes>> {
+        match self.find_entry(snapshot, key, seq, options)? {
+            None => Ok(None),
+            Some(FoundEntry::Value(op_type, value)) => {
+                let value = Db::visible_value(op_type, value);
+                let _reservation =
+                    memory_limit::reserve(value.as_ref().map_or(0, |value| value.len()))?;
+                Ok(value)
+            }
+            Some(FoundEntry::Separated(value)) => {
+                let _reservation = memory_limit::reserve(Db::separated_value_size(&value))?;
+                Db::read_separated_value(snapshot, key, &value, options)
+            }
+        }
+    }
+
+    /// 读取 KV 分离的 value 需要复制的字节数，旧版本写入的 SST 没有保存长度，按 0 计算
+    fn separated_value_size(separated: &[u8]) -> usize {
+        Entry::separated_value_len(separated).unwrap_or(0) as usize
+    }
+
+    /// 判断 key 是否存在，不读取 value，KV 分离的 value 也不会读取 VSST
+    #[instrument(skip_all)]
+    pub fn contains_key(&self, key: &Bytes) -> anyhow::Result<bool> {
+        let found = self.read_latest(key, |snapshot| {
+            let exists =
+                match self.find_entry(snapshot, key, MAX_SEQ_NUM, BlockReadOptions::default())? {
+                    None => false,
+                    Some(FoundEntry::Value(op_type, value)) => {
+                        Db::visible_value(op_type, value).is_some()
+                    }
+                    Some(FoundEntry::Separated(value)) => !snapshot
+                        .missing_vssts
+                        .on_read(Entry::separated_vsst_id(&value), key),
+                };
+            Ok(exists.then_some(()))
+        })?;
+        Ok(found.is_some())
+    }
 
-        // frozen memtable
+    /// 返回 key 对应的 value 的字节数，key 不存在时返回 `None`
+    ///
+    /// 不复制 value，KV 分离的 value 的长度保存在 SST 中，只有旧版本写入的 SST 才需要读取 VSST
+    #[instrument(skip_all)]
+    pub fn value_size(&self, key: &Bytes) -> anyhow::Result<Option<u64>> {
+        self.read_latest(key, |snapshot| self.value_size_from(snapshot, key))
+    }
+
+    fn value_size_from(&self, snapshot: &DbInner, key: &Bytes) -> anyhow::Result<Option<u64>> {
+        let options = BlockReadOptions::default();
+        match self.find_entry(snapshot, key, MAX_SEQ_NUM, options)? {
+            None => Ok(None),
+            Some(FoundEntry::Value(op_type, value)) => {
+                Ok(Db::visible_value(op_type, value).map(|v| v.len() as u64))
+            }
+            Some(FoundEntry::Separated(value)) => match Entry::separated_value_len(&value) {
+                _ if snapshot
+                    .missing_vssts
+                    .on_read(Entry::separated_vsst_id(&value), key) =>
+                {
+                    Ok(None)
+                }
+                Some(len) => Ok(Some(len)),
+                None => Db::read_separated_value(snapshot, key, &value, options)
+                    .map(|v| v.map(|v| v.len() as u64)),
+            },
+        }
+    }
+
+    /// 查找用户 key 的最新版本，系统 key 对用户不可见，见 [`Db::find_any_entry`]
+    fn find_entry(
+        &self,
+        snapshot: &DbInner,
+        key: &Bytes,
+        seq: u64,
+        options: BlockReadOptions,
+    ) -> anyhow::Result<Option<FoundEntry>> {
+        if is_system_key(key) {
+            return Ok(None);
+        }
+        self.find_any_entry(snapshot, key, seq, options)
+    }
+
+    /// 按 memtable -> frozen memtable -> L0 -> L1 ... 的顺序查找 key 序号不超过 `seq` 的最新版本，
+    /// KV 分离的 value 不读取 VSST
+    ///
+    /// 快照创建后的写入只会进入 memtable，SST 中没有序号，`seq` 只用于限制 memtable 中的版本；
+    /// 读取当前版本时传入 `MAX_SEQ_NUM`
+    fn find_any_entry(
+        &self,
+        snapshot: &DbInner,
+        key: &Bytes,
+        seq: u64,
+        options: BlockReadOptions,
+    ) -> anyhow::Result<Option<FoundEntry>> {
+        let internal_key = Db::make_internal_key(seq, Get, key);
+
+        // memtable
+        if let Some((k, v)) = snapshot.memtable.get(&internal_key) {
+            return Ok(Some(FoundEntry::Value(k.op_type, v)));
+        }
+
+        // frozen memtable，从新到旧查找，第一个命中的就是最新版本；冻结时建立的 key 范围和 bloom filter 排除的不用查跳表
         for memtable in snapshot.frozen_memtable.iter().rev() {
-            if let Some((_, v)) = memtable.get(&internal_key) {
-                return Ok(Some(v));
+            if !memtable.may_contain(key) {
+                continue;
+            }
+            if let Some((k, v)) = memtable.get(&internal_key) {
+                return Ok(Some(FoundEntry::Value(k.op_type, v)));
             }
         }
 
-        // sst
+        // sst，每层按新到旧的顺序探测，第一个包含 key 的 SST 中即为最新版本
         for level in 0..SST_LEVEL_LIMIT {
-            let mut iters = Vec::new();
-            iters.reserve(snapshot.levels[level as usize].len());
-            for table in snapshot.levels[level as usize].iter().rev() {
-                if table.maybe_contains_key(key) {
-                    iters.push(Box::new(VSsTableIterator::create_and_seek_to_key(
-                        table.clone(),
-                        key,
-                        snapshot.vssts.clone(),
-                    )?));
+            for table in snapshot.tables_newest_first(level) {
+                if !table.maybe_contains_key(key) {
+                    continue;
+                }
+                let iter = SsTableIterator::create_and_seek_to_key_with_options(
+                    table.clone(),
+                    key,
+                    options,
+                )?;
+                let hit = iter.is_valid() && iter.key() == key;
+                let misses = table.record_seek(hit);
+                if hit {
+                    let value = Bytes::copy_from_slice(iter.value());
+                    return Ok(Some(if Entry::is_separate(iter.meta()) {
+                        FoundEntry::Separated(value)
+                    } else {
+                        FoundEntry::Value(Entry::op_type_of(iter.meta()), value)
+                    }));
+                }
+                if misses == SEEK_MISS_COMPACTION_THRESHOLD && level + 1 < SST_LEVEL_LIMIT {
+                    // 该 SST 浪费了过多的探测，合并后它与下一层的重叠被消除
+                    debug!("{}.SST seek misses reach limit", table.id());
+                    self.daemon.request_compaction(level);
                 }
-            }
-            let iter = MergeIterator::create(iters);
-            if iter.is_valid() && iter.key() == key {
-                return Ok(Some(Bytes::copy_from_slice(iter.value())));
             }
         }
 
         Ok(None)
     }
 
+    /// VSST 的 bloom filter 判断 `key` 一定不在其中时直接返回错误，不再逐块查找，
+    /// 用于引用的 value 已被合并迁移走等情况下快速失败
+    pub(crate) fn check_vsst_contains(vsst: &SsTable, key: &Bytes) -> anyhow::Result<()> {
+        if vsst.maybe_contains_key(key) {
+            return Ok(());
+        }
+        Err(anyhow!("{}.VSST has no value for key {:?}", vsst.id(), key))
+    }
+
+    /// 读取 KV 分离的 value，`separated` 为 SST 中保存的 vsst id 和 value 长度，
+    /// VSST 已缺失时返回 `None`，见 [`MissingVSstPolicy`]
+    pub(crate) fn read_separated_value(
+        snapshot: &DbInner,
+        key: &Bytes,
+        separated: &[u8],
+        options: BlockReadOptions,
+    ) -> anyhow::Result<Option<Bytes>> {
+        let vsst_id = Entry::separated_vsst_id(separated);
+        let vsst = match snapshot.vssts.read().get(&vsst_id) {
+            None if snapshot.missing_vssts.on_read(vsst_id, key) => return Ok(None),
+            None => return Err(anyhow!("{} do not exist", vsst_id)),
+            Some(vsst) => vsst.clone(),
+        };
+        Db::check_vsst_contains(&vsst, key)?;
+        let iter =
+            SsTableIterator::create_and_seek_to_key_with_options(vsst.clone(), key, options)?;
+        // bloom filter 误判时定位到的是其他 key 的 value
+        if !iter.is_valid() || iter.key() != key {
+            return Err(anyhow!("{}.VSST has no value for key {:?}", vsst.id(), key));
+        }
+        Ok(Some(Bytes::copy_from_slice(iter.value())))
+    }
+
+    /// 批量读取 KV 分离的 value，`separated` 为 `keys` 的下标和 SST 中保存的 vsst id 和 value 长度
+    ///
+    /// 按 VSST 分组，组内按 key 排序后共用一个只向后定位的迭代器，相邻的块合并读取；
+    /// 多个 VSST 由最多 [`MULTI_GET_THREADS`] 个线程并行读取；已缺失的 VSST 中的 value 不返回
+    fn read_separated_values(
+        snapshot: &DbInner,
+        keys: &[Bytes],
+        separated: &[(usize, Bytes)],
+        options: BlockReadOptions,
+    ) -> anyhow::Result<Vec<(usize, Bytes)>> {
+        let mut groups: HashMap<u64, Vec<usize>> = HashMap::new();
+        for (idx, value) in separated {
+            groups
+                .entry(Entry::separated_vsst_id(value))
+                .or_default()
+                .push(*idx);
+        }
+        let groups = {
+            let vssts = snapshot.vssts.read();
+            groups
+                .into_iter()
+                .filter(|(vsst_id, idxs)| {
+                    let missing =
+                        vssts.get(vsst_id).is_none() && snapshot.missing_vssts.contains(*vsst_id);
+                    if missing {
+                        for idx in idxs {
+                            snapshot.missing_vssts.on_read(*vsst_id, &keys[*idx]);
+                        }
+                    }
+                    !missing
+                })
+                .map(|(vsst_id, mut idxs)| match vssts.get(&vsst_id) {
+                    None => Err(anyhow!("{} do not exist", vsst_id)),
+                    Some(vsst) => {
+                        idxs.sort_by(|a, b| keys[*a].cmp(&keys[*b]));
+                        Ok((vsst.clone(), idxs))
+                    }
+                })
+                .collect::<anyhow::Result<Vec<_>>>()?
+        };
+        if groups.is_empty() {
+            return Ok(vec![]);
+        }
+
+        let read_group = |(vsst, idxs): &(Arc<SsTable>, Vec<usize>)| {
+            for idx in idxs {
+                Db::check_vsst_contains(vsst, &keys[*idx])?;
+            }
+            let mut iter = SsTableIterator::create_and_seek_to_key_with_options(
+                vsst.clone(),
+                &keys[idxs[0]],
+                options,
+            )?;
+            let mut values = Vec::with_capacity(idxs.len());
+            for idx in idxs {
+                let key = &keys[*idx];
+                iter.seek_forward(key)?;
+                if !iter.is_valid() || iter.key() != key {
+                    return Err(anyhow!("{}.VSST has no value for key {:?}", vsst.id(), key));
+                }
+                values.push((*idx, Bytes::copy_from_slice(iter.value())));
+            }
+            Ok(values)
+        };
+        if groups.len() == 1 {
+            return read_group(&groups[0]);
+        }
+
+        let next = AtomicUsize::new(0);
+        let threads = MULTI_GET_THREADS.min(groups.len()).max(1);
+        // 调用线程在统计性能计数时，读取线程的计数合并到调用线程
+        let traced = perf_context::perf_context().is_some();
+        thread::scope(|s| {
+            let handles: Vec<_> = (0..threads)
+                .map(|_| {
+                    s.spawn(|| {
+                        let perf = traced.then(PerfContextScope::enter);
+                        let mut values = vec![];
+                        while let Some(group) = groups.get(next.fetch_add(1, Ordering::Relaxed)) {
+                            values.extend(read_group(group)?);
+                        }
+                        anyhow::Ok((values, perf.map(|perf| perf.context())))
+                    })
+                })
+                .collect();
+            let mut values = Vec::with_capacity(separated.len());
+            for handle in handles {
+                let (group_values, perf) = handle.join().expect("multi get thread panicked")?;
+                values.extend(group_values);
+                perf_context::merge(perf);
+            }
+            Ok(values)
+        })
+    }
+
+    /// 删除标记（或空 value）对用户不可见
+    fn visible_value(op_type: OpType, value: Bytes) -> Option<Bytes> {
+        if op_type == Delete || value.is_empty() {
+            None
+        } else {
+            Some(value)
+        }
+    }
+
     #[instrument(skip_all)]
-    fn append(&self, key: Bytes, value: Option<Bytes>) -> anyhow::Result<()> {
+    fn append(
+        &self,
+        key: Bytes,
+        value: Option<Bytes>,
+        options: &WriteOptions,
+    ) -> anyhow::Result<u64> {
+        let entry = self.prepare_entry(key, value, options)?;
+        let _lock = perf_context::timed_lock(|| self.key_locks.lock(&entry.key));
+        let guard = perf_context::timed_lock(|| self.inner.read());
+        match &self.micro_batch {
+            // 组员持有各自的 key 锁直到整组提交，组长的 inner 读锁保证整组写入同一个 memtable
+            Some(batcher) if batcher.accepts(&entry, options.disable_wal) => {
+                batcher.write(entry, options.sync, |entries, sync| {
+                    let options = WriteOptions {
+                        sync,
+                        ..options.clone()
+                    };
+                    self.write_entries(&guard, entries, &options)
+                })
+            }
+            _ => self.write_entries(&guard, vec![entry], options),
+        }
+    }
+
+    /// 构造单个写入的 entry 并交给校验回调，低优先级写入按需等待
+    fn prepare_entry(
+        &self,
+        key: Bytes,
+        value: Option<Bytes>,
+        options: &WriteOptions,
+    ) -> anyhow::Result<Entry> {
         let (value, op_type) = match value {
             None => (Bytes::new(), Delete),
             Some(v) => (v, Put),
@@ -482,83 +2579,459 @@ impl Db {
         trace!("key size: {}, value size: {}", key.len(), value.len());
 
         let mut entry_builder = EntryBuilder::new();
-        entry_builder
-            .op_type(op_type)
-            .key_value(key.clone(), value.clone());
-        let entry = entry_builder.build();
+        entry_builder.op_type(op_type).key_value(key, value);
+        let entry = entry_builder.try_build()?;
+        validate::check_value_size(std::slice::from_ref(&entry), self.options.max_value_size)?;
+        self.validators.validate(std::slice::from_ref(&entry))?;
+        self.throttle_low_priority(options);
+        self.wait_for_frozen_limit()?;
+        Ok(entry)
+    }
 
-        let guard = self.inner.read();
+    /// 低优先级写入在有 memtable 等待刷写、L0 SST 超过合并阈值或合并欠债过多时先等待一段时间
+    ///
+    /// 每次等待记入 [`DbStats::write_stalls`]，并以 `lasagnedb::write_stall` 为 target 输出带有原因和各项指标的事件
+    fn throttle_low_priority(&self, options: &WriteOptions) {
+        if !options.low_priority {
+            return;
+        }
+        let (frozen_memtables, l0_files, compaction_debt) = {
+            let guard = self.inner.read();
+            (
+                guard.frozen_memtable.len(),
+                guard.levels[0].len(),
+                DbDaemon::compaction_debt(&guard.levels),
+            )
+        };
+        let mut causes = vec![];
+        if frozen_memtables > 0 {
+            causes.push(StallCause::PendingMemtables);
+        }
+        if l0_files > self.options.l0_sst_num_limit {
+            causes.push(StallCause::L0Files);
+        }
+        if compaction_debt > COMPACTION_DEBT_STALL_LIMIT {
+            causes.push(StallCause::CompactionDebt);
+        }
+        if causes.is_empty() {
+            return;
+        }
+        debug!(
+            target: "lasagnedb::write_stall",
+            ?causes,
+            frozen_memtables,
+            l0_files,
+            compaction_debt,
+            delay = ?LOW_PRIORITY_WRITE_DELAY,
+            "delay low priority write"
+        );
+        self.write_stalls
+            .on_stall(&causes, LOW_PRIORITY_WRITE_DELAY);
+        thread::sleep(LOW_PRIORITY_WRITE_DELAY);
+    }
 
-        let seq_num = guard.seq_num;
-        guard.wal.write(vec![entry])?;
-        guard.wal.flush();
+    /// 冻结的 memtable 或 WAL 超过上限时返回当前的数量，见 [`DbOptions::max_frozen_memtables`]
+    fn frozen_limit_exceeded(&self) -> Option<BusyError> {
+        let guard = self.inner.read();
+        let exceeded =
+            |limit: Option<usize>, count: usize| limit.is_some_and(|limit| count > limit);
+        if exceeded(
+            self.options.max_frozen_memtables,
+            guard.frozen_memtable.len(),
+        ) || exceeded(self.options.max_frozen_wals, guard.frozen_wal.len())
+        {
+            return Some(BusyError {
+                frozen_memtables: guard.frozen_memtable.len(),
+                frozen_wals: guard.frozen_wal.len(),
+            });
+        }
+        None
+    }
 
-        let internal_key = Db::make_internal_key(seq_num, op_type, &key);
-        guard.memtable.put(internal_key, value);
+    /// 冻结的 memtable 或 WAL 超过上限时按 [`DbOptions::frozen_limit_action`] 阻塞或返回 [`BusyError`]
+    ///
+    /// 在获取 key 锁和 `inner` 的读锁之前调用，阻塞期间不妨碍刷写完成后修改 `inner`
+    fn wait_for_frozen_limit(&self) -> anyhow::Result<()> {
+        let Some(busy) = self.frozen_limit_exceeded() else {
+            return Ok(());
+        };
+        if self.options.frozen_limit_action == FrozenLimitAction::Busy {
+            return Err(busy.into());
+        }
+        warn!(
+            target: "lasagnedb::write_stall",
+            frozen_memtables = busy.frozen_memtables,
+            frozen_wals = busy.frozen_wals,
+            "stall write until frozen memtables are flushed"
+        );
+        let started_at = Instant::now();
+        let result = loop {
+            if let Err(e) = self.check_open() {
+                break Err(e.into());
+            }
+            if self.frozen_limit_exceeded().is_none() {
+                break Ok(());
+            }
+            thread::sleep(FROZEN_LIMIT_POLL_INTERVAL);
+        };
+        self.write_stalls
+            .on_stall(&[StallCause::FrozenLimit], started_at.elapsed());
+        result
+    }
 
-        if guard.memtable.size() > MEMTABLE_SIZE_LIMIT {
-            if let Err(e) = self.flush_chan.0.try_send(()) {
-                warn!("{}", e);
+    /// 将 `entries` 作为一条记录写入 WAL，再写入 memtable，幂等 token 只写入 WAL，返回这次写入的提交序号
+    fn write_entries(
+        &self,
+        inner: &DbInner,
+        mut entries: Vec<Entry>,
+        options: &WriteOptions,
+    ) -> anyhow::Result<u64> {
+        self.check_open()?;
+        if self.is_read_only() {
+            return Err(ReadOnlyError.into());
+        }
+        // 记录变更日志的写入持有变更序号的锁直到写入 memtable，变更日志的顺序与写入顺序一致
+        let capture = self
+            .change_log
+            .as_ref()
+            .and_then(|change_log| change_log.capture(&mut entries));
+        // WAL 写入成功后才分配序号，分配到发布之间不会失败，之后的写入不会一直等待
+        // 提交序号同时作为 memtable 中的 seq num，同一 key 的新版本排在旧版本前面
+        // 恢复时按记录在 WAL 中的顺序编号，序号与追加记录在同一临界区内分配，两者的顺序一致
+        let (commit_seq, wal_result) = if options.disable_wal {
+            (inner.commit_seq.allocate(), Ok(()))
+        } else {
+            let commit_seq = inner
+                .wal
+                .write_then(entries.clone(), || inner.commit_seq.allocate())?;
+            // 记录已在 WAL 中，之后刷盘或切换段失败也要写入 memtable 并发布序号，再返回错误
+            (commit_seq, self.sync_and_roll_wal(&inner.wal, options))
+        };
+        Db::apply_to_memtable(&inner.memtable, commit_seq, &entries);
+        if let Some(cache) = &self.negative_cache {
+            for entry in &entries {
+                cache.invalidate(&entry.key);
             }
         }
+        drop(capture);
+        self.watchers.notify(&entries);
+        inner.commit_seq.publish(commit_seq);
+        self.tracer.record_write(&entries);
+        let bytes: usize = entries
+            .iter()
+            .filter(|e| !e.is_idempotency_token() && !is_system_key(&e.key))
+            .map(|e| e.key.len() + e.value.len())
+            .sum();
+        self.daemon.counters.on_write(bytes as u64);
 
+        if inner.memtable.size() > self.options.memtable_size_limit {
+            self.daemon.request_flush();
+        }
+
+        wal_result?;
+        Ok(commit_seq)
+    }
+
+    /// 按 `options.sync` 刷盘，当前段超过限制时切换到新段
+    fn sync_and_roll_wal(&self, wal: &Journal, options: &WriteOptions) -> anyhow::Result<()> {
+        if options.sync {
+            wal.flush()?;
+        }
+        if wal.need_roll() {
+            self.roll_wal(wal)?;
+        }
         Ok(())
     }
 
+    /// WAL 当前段超过限制时切换到新段
+    ///
+    /// 先在 MANIFEST 中记录新段再切换，保证写入新段的数据在恢复时一定会被重放
+    fn roll_wal(&self, wal: &Journal) -> anyhow::Result<()> {
+        let mut manifest = self.manifest.write();
+        // 持有 MANIFEST 锁后再检查一次，避免并发写入重复切换
+        if !wal.need_roll() {
+            return Ok(());
+        }
+        let segment_id = wal.num_of_segments() as u32;
+        let mut r = RecordBuilder::new();
+        r.add(ManifestItem::Origin(RecordOrigin::now(
+            ManifestActor::Write,
+        )));
+        r.add(ManifestItem::NewWalSegment(wal.id(), segment_id));
+        manifest.add(&r.build())?;
+        debug!("NEW {}.LOG segment {}", wal.id(), segment_id);
+        wal.roll(Db::path_of_wal_segment(
+            self.path.as_ref(),
+            wal.id(),
+            segment_id,
+        ))
+    }
+
     #[instrument(skip_all)]
+    /// 范围查询，等价于在一个新快照上调用 [`Snapshot::scan`]
     pub fn scan(
         &self,
         lower: Bound<Bytes>,
         upper: Bound<Bytes>,
     ) -> anyhow::Result<FusedIterator<DbIterator>> {
-        let snapshot = {
-            let guard = self.inner.read();
-            Arc::clone(&guard)
-        };
+        self.tracer.record_scan(&lower, &upper);
+        self.snapshot().scan(lower, upper)
+    }
+
+    /// 按字节数分段的范围查询，等价于在一个新快照上调用 [`Snapshot::scan_bytes`]
+    ///
+    /// 用返回的 `next_key` 作为下界继续查询，每次都在新快照上进行，可以分段读取任意大的范围，
+    /// 不会长时间持有快照；段与段之间的写入可能被读到，也可能读不到
+    #[instrument(skip_all)]
+    pub fn scan_bytes(
+        &self,
+        lower: Bound<Bytes>,
+        upper: Bound<Bytes>,
+        max_bytes: usize,
+    ) -> anyhow::Result<ScanChunk> {
+        self.tracer.record_scan(&lower, &upper);
+        self.snapshot().scan_bytes(lower, upper, max_bytes)
+    }
+
+    /// 将范围切分为最多 `n` 个子范围并返回各自的迭代器，所有迭代器共享一个新快照，
+    /// 见 [`Snapshot::scan_partitions`]
+    #[instrument(skip_all)]
+    pub fn scan_partitions(
+        &self,
+        lower: Bound<Bytes>,
+        upper: Bound<Bytes>,
+        n: usize,
+    ) -> anyhow::Result<Vec<FusedIterator<DbIterator>>> {
+        self.snapshot().scan_partitions(lower, upper, n)
+    }
+
+    /// 只在 memtable 上做范围查询，等价于在一个新快照上调用 [`Snapshot::scan_memtables`]
+    #[instrument(skip_all)]
+    pub fn scan_memtables(
+        &self,
+        lower: Bound<Bytes>,
+        upper: Bound<Bytes>,
+    ) -> anyhow::Result<FusedIterator<DbIterator>> {
+        self.snapshot().scan_memtables(lower, upper)
+    }
 
-        let mut mem_iters = Vec::new();
-        mem_iters.reserve(snapshot.frozen_memtable.len() + 1);
-        mem_iters.push(Box::new(
-            snapshot.memtable.scan(lower.clone(), upper.clone()),
-        ));
-        for _memtable in snapshot.frozen_memtable.iter().rev() {
-            let memtable = _memtable.clone();
-            mem_iters.push(Box::new(memtable.scan(lower.clone(), upper.clone())));
+    /// 以指定的读取选项做范围查询，未指定快照时在一个新快照上查询
+    #[instrument(skip_all)]
+    pub fn scan_with_options(
+        &self,
+        lower: Bound<Bytes>,
+        upper: Bound<Bytes>,
+        options: &ReadOptions,
+    ) -> anyhow::Result<FusedIterator<DbIterator>> {
+        let latest = options.isolation == ScanIsolation::Latest;
+        if latest && options.snapshot.is_some() {
+            return Err(anyhow!("latest isolation cannot be used with a snapshot"));
+        }
+        self.tracer.record_scan(&lower, &upper);
+        let mut iter = match options.snapshot {
+            Some(ref snapshot) => {
+                snapshot.scan_with_options(lower, upper, options.block_options(), options.deadline)
+            }
+            None => self.snapshot().scan_with_options(
+                lower,
+                upper,
+                options.block_options(),
+                options.deadline,
+            ),
+        }?;
+        if latest {
+            iter.inner_mut()
+                .follow_latest(self.inner.clone(), options.block_options());
         }
-        let mem_iter = MergeIterator::create(mem_iters);
+        Ok(iter)
+    }
 
-        let mut sst_iters = Vec::new();
-        for level in 0..SST_LEVEL_LIMIT {
-            for table in snapshot.levels[level as usize].iter().rev() {
-                let iter = match lower.clone() {
-                    Bound::Included(key) => VSsTableIterator::create_and_seek_to_key(
-                        table.clone(),
-                        &key[..],
-                        snapshot.vssts.clone(),
-                    )?,
-                    Bound::Excluded(key) => {
-                        let mut iter = VSsTableIterator::create_and_seek_to_key(
-                            table.clone(),
-                            &key[..],
-                            snapshot.vssts.clone(),
-                        )?;
-                        if iter.is_valid() && iter.key() == key {
-                            iter.next()?;
-                        }
-                        iter
+    /// 范围查询，读完后自动在最新数据上继续，见 [`TailIterator`]
+    #[instrument(skip_all)]
+    pub fn tail_scan(
+        &self,
+        lower: Bound<Bytes>,
+        upper: Bound<Bytes>,
+    ) -> anyhow::Result<TailIterator> {
+        TailIterator::new(self.inner.clone(), self.snapshots.clone(), lower, upper)
+    }
+
+    /// 创建一个查询结果暂存区，内存中的结果超过 `memory_limit` 字节后写入数据目录下的临时文件，
+    /// 用于物化大范围查询的结果，见 [`ResultStaging`]
+    pub fn staging(&self, memory_limit: usize) -> ResultStaging {
+        let id = self.next_staging_id.fetch_add(1, Ordering::Relaxed);
+        ResultStaging::new(Db::path_of_staging(self.path.as_ref(), id), memory_limit)
+    }
+
+    /// 统计范围内未被删除的 key 数量，等价于在一个新快照上调用 [`Snapshot::count`]
+    #[instrument(skip_all)]
+    pub fn count(&self, lower: Bound<Bytes>, upper: Bound<Bytes>) -> anyhow::Result<usize> {
+        let _yield = YieldScope::enter(self.options.yield_interval);
+        self.snapshot().count(lower, upper)
+    }
+
+    /// 获取当前数据的快照
+    pub fn snapshot(&self) -> Snapshot {
+        let inner = self.current_inner();
+        Snapshot::new(inner, self.snapshots.clone())
+    }
+
+    /// 当前存活的快照数量，包括仍未释放的迭代器所持有的快照
+    pub fn num_of_active_snapshots(&self) -> usize {
+        self.snapshots.active()
+    }
+
+    /// 当前存活的迭代器，最早创建的在前。迭代器持有快照及其引用的 memtable 和文件，
+    /// 长时间存活的迭代器通常是忘记 drop，可以据此（开启 `iterator-backtrace` feature 时根据创建时的调用栈）定位
+    pub fn active_iterators(&self) -> Vec<ActiveIterator> {
+        self.snapshots.iterators()
+    }
+
+    /// 启动投递线程，把变更日志中的修改按提交顺序分批投递给 `sink`，需要打开时开启 [`DbOptions::change_capture`]
+    ///
+    /// 从上次记录的投递进度之后开始投递，包括启动之前以及上次运行中写入而没有投递的修改。
+    /// 一批修改投递成功后在同一次写入中记录投递进度并删除这批日志；投递失败时等待一段时间后重新投递同一批，
+    /// 等待时间逐次翻倍。进程在投递成功和记录进度之间退出时，这批修改在下次启动后会再次投递，即至少投递一次。
+    /// 关闭 WAL 的写入在崩溃后丢失，其中的修改也不会投递。只能启动一次，数据库关闭时投递线程退出
+    pub fn start_cdc(self: &Arc<Self>, sink: impl CdcSink) -> anyhow::Result<()> {
+        let change_log = self
+            .change_log
+            .as_ref()
+            .ok_or_else(|| anyhow!("change capture is not enabled"))?;
+        if !change_log.start() {
+            return Err(anyhow!("CDC delivery is already started"));
+        }
+        let appended = change_log.appended();
+        let exit_rx = self.exit_chan.1.clone();
+        let db = Arc::downgrade(self);
+        // 与 `Db::shutdown` 互斥，关闭后不再启动线程
+        let mut background_tasks = self.background_tasks.lock();
+        self.check_open()?;
+        background_tasks.push(self.spawn_background("cdc", move || {
+            let mut backoff = CDC_RETRY_BACKOFF;
+            loop {
+                let Some(db) = db.upgrade() else {
+                    return;
+                };
+                if db.check_open().is_err() {
+                    return;
+                }
+                let delivered = db.deliver_changes(&sink);
+                drop(db);
+                // 没有日志可投递时等待新的写入，投递失败时只等待重试的时间
+                let (wait, notified) = match delivered {
+                    Ok(0) => (CDC_POLL_INTERVAL, appended.clone()),
+                    Ok(_) => {
+                        backoff = CDC_RETRY_BACKOFF;
+                        (Duration::ZERO, channel::never())
+                    }
+                    Err(err) => {
+                        warn!("CDC delivery failed, retry in {:?}: {:#}", backoff, err);
+                        let wait = backoff;
+                        backoff = (backoff * 2).min(CDC_RETRY_MAX_BACKOFF);
+                        (wait, channel::never())
                     }
-                    Bound::Unbounded => VSsTableIterator::create_and_seek_to_first(
-                        table.clone(),
-                        snapshot.vssts.clone(),
-                    )?,
                 };
-                sst_iters.push(Box::new(iter));
+                crossbeam::select! {
+                    recv(exit_rx) -> _ => return,
+                    recv(notified) -> _ => {},
+                    default(wait) => {},
+                }
+            }
+        })?);
+        Ok(())
+    }
+
+    /// 读取系统 key，见 [`crate::system`]
+    pub(crate) fn get_system(&self, key: &Bytes) -> anyhow::Result<Option<Bytes>> {
+        debug_assert!(is_system_key(key), "{:?} is not a system key", key);
+        let snapshot = Arc::clone(&self.inner.read());
+        let options = BlockReadOptions::default();
+        match self.find_any_entry(&snapshot, key, MAX_SEQ_NUM, options)? {
+            None => Ok(None),
+            Some(FoundEntry::Value(op_type, value)) => Ok(Db::visible_value(op_type, value)),
+            Some(FoundEntry::Separated(value)) => {
+                Db::read_separated_value(&snapshot, key, &value, options)
             }
         }
-        let sst_iter = MergeIterator::create(sst_iters);
+    }
+
+    /// 写入一批系统 key，不经过写入校验，也不记录到变更日志，返回这次写入的提交序号
+    pub(crate) fn write_system(&self, entries: Vec<Entry>) -> anyhow::Result<u64> {
+        debug_assert!(
+            entries.iter().all(|e| is_system_key(&e.key)),
+            "only system keys can be written"
+        );
+        let guard = self.inner.read();
+        self.write_entries(&guard, entries, &WriteOptions::default())
+    }
+
+    /// 已投递的最大变更序号
+    fn cdc_offset(&self) -> anyhow::Result<u64> {
+        match self.get_system(&cdc::offset_key())? {
+            Some(offset) => Ok(keys::decode_u64(&offset)?),
+            None => Ok(0),
+        }
+    }
 
-        let iter = TwoMergeIterator::create(mem_iter, sst_iter)?;
+    /// 读取投递进度之后的变更日志，找到最后一条日志的序号
+    fn recover_change_log(&self) -> anyhow::Result<ChangeLog> {
+        let offset = self.cdc_offset()?;
+        let snapshot = self.snapshot();
+        let mut iter = snapshot.scan_system(
+            Bound::Included(cdc::log_key(offset + 1)),
+            keys::prefix_upper_bound(&cdc::log_prefix()),
+        )?;
+        let mut last_logged = 0;
+        while iter.is_valid() {
+            last_logged = cdc::seq_of_log_key(iter.key())?;
+            iter.next()?;
+        }
+        Ok(ChangeLog::new(offset, last_logged))
+    }
+
+    /// 把投递进度之后的最多 [`CDC_DELIVERY_BATCH`] 条变更日志中的修改投递给 `sink`，成功后记录进度并删除这些日志，
+    /// 返回投递的日志条数
+    fn deliver_changes(&self, sink: &impl CdcSink) -> anyhow::Result<usize> {
+        let offset = self.cdc_offset()?;
+        let mut events = vec![];
+        let mut delivered = vec![];
+        {
+            let snapshot = self.snapshot();
+            let mut iter = snapshot.scan_system(
+                Bound::Included(cdc::log_key(offset + 1)),
+                keys::prefix_upper_bound(&cdc::log_prefix()),
+            )?;
+            while iter.is_valid() && delivered.len() < CDC_DELIVERY_BATCH {
+                let seq = cdc::seq_of_log_key(iter.key())?;
+                events.extend(cdc::decode_log(seq, iter.value())?);
+                delivered.push((seq, Bytes::copy_from_slice(iter.key())));
+                iter.next()?;
+            }
+        }
+        let Some(&(last_seq, _)) = delivered.last() else {
+            return Ok(0);
+        };
+        sink.deliver(&events)?;
+
+        let mut entries = Vec::with_capacity(delivered.len() + 1);
+        let mut builder = EntryBuilder::new();
+        builder.op_type(Put).key_value(
+            cdc::offset_key(),
+            Bytes::copy_from_slice(&keys::encode_u64(last_seq)),
+        );
+        entries.push(builder.build());
+        for (_, key) in &delivered {
+            let mut builder = EntryBuilder::new();
+            builder.op_type(Delete).key_value(key.clone(), Bytes::new());
+            entries.push(builder.build());
+        }
+        self.write_system(entries)?;
+        Ok(delivered.len())
+    }
 
-        Ok(FusedIterator::new(DbIterator::new(iter, upper)?))
+    /// 当前的前缀订阅数量，已取消的订阅在下一次写入时才会被移除
+    pub fn num_of_watchers(&self) -> usize {
+        self.watchers.len()
     }
 }
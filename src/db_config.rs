@@ -1,12 +1,26 @@
+use std::time::Duration;
+
+use crate::checksum::ChecksumType;
+use crate::sstable::compression::CompressionType;
+
 pub const KB: usize = 1024;
 pub const MB: usize = 1024 * KB;
 pub const GB: usize = 1024 * MB;
 
+/// SST 默认的 data block 大小，见 [`crate::DbOptions::block_size`]
 pub const BLOCK_SIZE: usize = 4 * KB;
 pub const MEMTABLE_SIZE_LIMIT: usize = 4 * MB;
+/// 单个 value 的默认最大字节数，见 [`crate::DbOptions::max_value_size`]
+pub const MAX_VALUE_SIZE: usize = 256 * MB;
 pub const BLOCK_CACHE_SIZE: u64 = 8 * MB as u64;
+/// 顺序扫描 SST 时单次合并读取相邻块的最大字节数
+pub const MAX_COALESCE_READ_SIZE: u64 = 64 * KB as u64;
 pub const MIN_VSST_SIZE: u64 = 4 * KB as u64;
+/// VSST 默认的 data block 大小，见 [`crate::DbOptions::vsst_block_size`]。value 至少有 [`MIN_VSST_SIZE`] 字节，远大于该值，因此每个块只有一个 value，
+/// 读取一个 value 时不会读出同一块中的其他 value
+pub const VSST_BLOCK_SIZE: usize = 256;
 pub const SST_LEVEL_LIMIT: u32 = 6;
+pub const BLOOM_FALSE_POSITIVE_RATE: f64 = 0.01;
 
 pub const MAX_SST_SIZE: u64 = 4 * MB as u64;
 pub const MAX_LEVEL_SIZE: [u64; SST_LEVEL_LIMIT as usize] = [
@@ -20,4 +34,80 @@ pub const MAX_LEVEL_SIZE: [u64; SST_LEVEL_LIMIT as usize] = [
 
 pub const MAX_VSST_SPARE_RATIO: f32 = 0.5;
 
+/// 一次合并中从空洞率超限的 VSST 迁移到新 VSST 的 value 总大小上限，超过后剩余的 value 留到之后的合并再迁移，
+/// 避免单次合并读写过多的 value 导致耗时过长
+pub const MAX_COMPACTION_MIGRATION_SIZE: u64 = 64 * MB as u64;
+
+/// 限速删除文件时每次截断的大小
+pub const DELETE_TRUNCATE_CHUNK_SIZE: u64 = 4 * MB as u64;
+
+/// 开启回收站时后台检查并清理过期文件的间隔
+pub const TRASH_PURGE_INTERVAL: Duration = Duration::from_secs(60);
+
+/// 开启定期合并时后台检查 SST 是否过期的最长间隔，见 [`crate::DbOptions::periodic_compaction`]
+pub const PERIODIC_COMPACTION_CHECK_INTERVAL: Duration = Duration::from_secs(3600);
+
 pub const L0_SST_NUM_LIMIT: usize = 4;
+
+/// 单个 SST 被点查探测却没有找到 key 的次数达到该值时请求合并它所在的层，并优先选择它作为合并的基准 SST，
+/// 为 0 时不按未命中次数触发合并
+pub const SEEK_MISS_COMPACTION_THRESHOLD: u64 = 10_000;
+
+/// 后台线程上的合并等长循环每处理该数量的 entry 让出一次 CPU
+pub const YIELD_INTERVAL: u64 = 4096;
+
+/// 刷写和合并时每隔该数量的 entry 把一个 value 交给检查回调，见 [`crate::Db::add_value_inspector`]
+pub const VALUE_INSPECTION_SAMPLING: u32 = 16;
+
+/// 恢复时并行打开 SST / VSST 的线程数
+pub const RECOVERY_OPEN_THREADS: usize = 8;
+
+/// 批量读取时并行读取 VSST 的线程数
+pub const MULTI_GET_THREADS: usize = 4;
+
+/// 重放操作记录时每个范围查询最多读取的 KV 数，见 [`crate::trace::ReplayOptions`]
+pub const TRACE_REPLAY_SCAN_LIMIT: usize = 100;
+
+/// 使用共享调度器时每个数据库同时执行的刷写和合并任务数，见 [`crate::DbOptions::scheduler_quota`]
+pub const SCHEDULER_QUOTA: usize = 2;
+
+/// 后台 flush / compaction 请求等待超过该时间时 `Db::health` 报告 Degraded
+pub const BACKGROUND_LAG_LIMIT: Duration = Duration::from_secs(30);
+
+/// CDC 投递线程每次最多读取的变更日志条数，一条日志对应一次写入（批次）
+pub const CDC_DELIVERY_BATCH: usize = 1024;
+/// CDC 投递线程在没有收到新写入通知时检查变更日志的间隔
+pub const CDC_POLL_INTERVAL: Duration = Duration::from_secs(1);
+/// CDC 投递失败后第一次重试前等待的时间，之后每次失败翻倍，最多等待 [`CDC_RETRY_MAX_BACKOFF`]
+pub const CDC_RETRY_BACKOFF: Duration = Duration::from_millis(100);
+pub const CDC_RETRY_MAX_BACKOFF: Duration = Duration::from_secs(30);
+
+/// 记住的最近应用过的批量写入幂等 token 数量，在此窗口内重复提交的批次会被跳过
+pub const IDEMPOTENCY_TOKEN_LIMIT: usize = 100_000;
+
+/// 后台刷写或合并积压时，低优先级写入在写入前等待的时间
+pub const LOW_PRIORITY_WRITE_DELAY: Duration = Duration::from_millis(1);
+
+/// L1 及以下各层超出大小限制的总字节数（合并欠债）超过该值时低优先级写入也会等待
+pub const COMPACTION_DEBT_STALL_LIMIT: u64 = 256 * MB as u64;
+
+/// 参与分组提交的单个写入的 key 和 value 总字节数上限，见 [`crate::MicroBatchOptions`]
+pub const MICRO_BATCH_MAX_ENTRY_SIZE: usize = 256;
+/// 一组写入的总字节数上限，达到后立即提交
+pub const MICRO_BATCH_MAX_BYTES: usize = 64 * KB;
+/// 组长等待前一组提交完成的最长时间
+pub const MICRO_BATCH_MAX_DELAY: Duration = Duration::from_micros(100);
+
+/// 冻结的 memtable 或 WAL 超过上限而阻塞的写入每隔这么久检查一次是否可以继续
+pub const FROZEN_LIMIT_POLL_INTERVAL: Duration = Duration::from_millis(10);
+
+pub const WAL_SEGMENT_SIZE_LIMIT: u64 = MB as u64;
+pub const WAL_SEGMENT_AGE_LIMIT: Duration = Duration::from_secs(10 * 60);
+
+/// 新写入的 SST 中 data block 使用的校验和算法
+pub const BLOCK_CHECKSUM: ChecksumType = ChecksumType::Crc32c;
+
+/// compaction 输出 SST 时 data block 默认的压缩方式，见 [`crate::DbOptions::compaction_compression`]
+pub const COMPACTION_COMPRESSION: CompressionType = CompressionType::None;
+/// 默认的 zstd 字典大小，见 [`crate::DbOptions::zstd_dict_size`]
+pub const ZSTD_DICT_SIZE: usize = 16 * KB;
@@ -1,51 +1,211 @@
+use crate::contention::TrackedRwLock;
+use crate::db::DbInner;
+use crate::iterator::lazy_iterator::LazyIterator;
 use crate::iterator::merge_iterator::MergeIterator;
 use crate::iterator::two_merge_iterator::TwoMergeIterator;
-use crate::iterator::StorageIterator;
+use crate::iterator::{Checked, StorageIterator};
 use crate::memtable::iterator::MemTableIterator;
-use crate::sstable::iterator::VSsTableIterator;
+use crate::snapshot::{IteratorGuard, Snapshot, SnapshotTracker};
+use crate::sstable::builder::BlockReadOptions;
+use crate::sstable::iterator::{VSsTableIterator, VSstDerefCounters, VSstDerefStats};
+use crate::system::is_system_key;
 use bytes::Bytes;
 use std::ops::Bound;
+use std::sync::Arc;
+use std::time::Instant;
+use thiserror::Error;
+use tracing::debug;
 
-type DbIteratorInner =
-    TwoMergeIterator<MergeIterator<MemTableIterator>, MergeIterator<VSsTableIterator>>;
+pub(crate) type DbIteratorInner = Checked<
+    TwoMergeIterator<
+        MergeIterator<Checked<MemTableIterator>>,
+        MergeIterator<LazyIterator<Checked<VSsTableIterator>>>,
+    >,
+>;
 
+/// 范围查询超过 [`crate::ReadOptions::deadline`] 时返回的错误，迭代器随之失效
+///
+/// 之前返回的 key 都是完整的结果，可以从 `last_key` 之后（不包含）重新查询剩余的部分
+#[derive(Debug, Clone, Error, PartialEq, Eq)]
+#[error("scan deadline exceeded, last returned key: {last_key:?}")]
+pub struct ScanTimeoutError {
+    /// 超时前最后一个返回的 key，为 `None` 时还没有返回任何 key
+    pub last_key: Option<Bytes>,
+}
+
+/// [`crate::Db::scan_bytes`] 返回的一段范围查询结果
+///
+/// `next_key` 为范围中下一个未返回的 key，从它（包含）开始再次查询即可继续，为 `None` 时范围已读完
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ScanChunk {
+    pub items: Vec<(Bytes, Bytes)>,
+    pub next_key: Option<Bytes>,
+}
+
+impl ScanChunk {
+    /// 结果中 key 和 value 的总字节数
+    pub fn size(&self) -> usize {
+        self.items
+            .iter()
+            .map(|(key, value)| key.len() + value.len())
+            .sum()
+    }
+}
+
+/// 数据库迭代器，持有创建它的 [`Snapshot`]，存活期间快照引用的资源不会被释放
 pub struct DbIterator {
     iter: DbIteratorInner,
     end_bound: Bound<Bytes>,
     is_valid: bool,
+    snapshot: Snapshot,
+    deadline: Option<Instant>,
+    // 设置了截止时间时记录最后一个返回的 key，用于超时后继续查询
+    last_key: Option<Bytes>,
+    timed_out: bool,
+    // 各个 SST 的迭代器读取 KV 分离的 value 的累计统计
+    vsst_deref: Arc<VSstDerefCounters>,
+    // 登记在 `Db::active_iterators` 中，drop 时移除
+    _tracked: IteratorGuard,
+    // 是否返回系统 key，用户的范围查询跳过它们
+    include_system: bool,
+    // `ScanIsolation::Latest` 模式下重新定位所需的状态，快照模式下为 `None`
+    latest: Option<LatestSource>,
+}
+
+/// [`crate::ScanIsolation::Latest`] 模式的迭代器在最新的数据上重新定位所需的状态
+struct LatestSource {
+    db_inner: Arc<TrackedRwLock<Arc<DbInner>>>,
+    options: BlockReadOptions,
 }
 
 impl DbIterator {
-    pub(crate) fn new(iter: DbIteratorInner, end_bound: Bound<Bytes>) -> anyhow::Result<Self> {
+    pub(crate) fn new(
+        iter: DbIteratorInner,
+        end_bound: Bound<Bytes>,
+        snapshot: Snapshot,
+        deadline: Option<Instant>,
+        vsst_deref: Arc<VSstDerefCounters>,
+        include_system: bool,
+    ) -> anyhow::Result<Self> {
         let mut iter = Self {
-            is_valid: iter.is_valid(),
+            is_valid: false,
             iter,
             end_bound,
+            _tracked: snapshot.track_iterator(),
+            snapshot,
+            include_system,
+            deadline,
+            last_key: None,
+            timed_out: false,
+            vsst_deref,
+            latest: None,
         };
+        iter.check_end_bound();
+        iter.check_deadline()?;
         iter.move_to_non_delete()?;
         Ok(iter)
     }
 
-    fn next_inner(&mut self) -> anyhow::Result<()> {
-        self.iter.next()?;
+    fn check_end_bound(&mut self) {
         if !self.iter.is_valid() {
             self.is_valid = false;
-            return Ok(());
+            return;
         }
-        match self.end_bound.as_ref() {
-            Bound::Unbounded => {}
-            Bound::Included(key) => self.is_valid = self.iter.key() <= key.as_ref(),
-            Bound::Excluded(key) => self.is_valid = self.iter.key() < key.as_ref(),
+        self.is_valid = match self.end_bound.as_ref() {
+            Bound::Unbounded => true,
+            Bound::Included(key) => self.iter.key() <= key.as_ref(),
+            Bound::Excluded(key) => self.iter.key() < key.as_ref(),
+        };
+    }
+
+    /// 超过截止时间时迭代器失效并返回 [`ScanTimeoutError`]，跳过大量删除标记的过程中同样会检查
+    fn check_deadline(&mut self) -> anyhow::Result<()> {
+        if self
+            .deadline
+            .is_some_and(|deadline| Instant::now() >= deadline)
+        {
+            self.is_valid = false;
+            self.timed_out = true;
+            return Err(ScanTimeoutError {
+                last_key: self.last_key.clone(),
+            }
+            .into());
         }
         Ok(())
     }
 
-    fn move_to_non_delete(&mut self) -> anyhow::Result<()> {
-        while self.is_valid() && self.iter.value().is_empty() {
+    fn next_inner(&mut self) -> anyhow::Result<()> {
+        self.iter.next()?;
+        self.check_end_bound();
+        self.check_deadline()
+    }
+
+    /// 切换到 [`crate::ScanIsolation::Latest`] 模式，之后每次 `next` 都检查数据库是否有变化
+    pub(crate) fn follow_latest(
+        &mut self,
+        db_inner: Arc<TrackedRwLock<Arc<DbInner>>>,
+        options: BlockReadOptions,
+    ) {
+        self.latest = Some(LatestSource { db_inner, options });
+    }
+
+    /// 数据库在当前快照之后有了变化时，在最新的数据上从 `key` 之后重新定位，返回是否重新定位
+    fn reseek_latest(&mut self, key: Bytes) -> anyhow::Result<bool> {
+        let Some(latest) = &self.latest else {
+            return Ok(false);
+        };
+        let inner = Arc::clone(&latest.db_inner.read());
+        if !self.snapshot.is_stale(&inner) {
+            return Ok(false);
+        }
+        let snapshot = self.snapshot.renew(inner);
+        self.iter = snapshot.range_iter(
+            Bound::Excluded(key),
+            self.end_bound.clone(),
+            latest.options,
+            self.vsst_deref.clone(),
+        )?;
+        self.snapshot = snapshot;
+        self.check_end_bound();
+        self.check_deadline()?;
+        self.move_to_non_delete()?;
+        Ok(true)
+    }
+
+    /// 迭代器当前所基于的快照，[`crate::ScanIsolation::Latest`] 模式下重新定位后会变化
+    pub fn snapshot(&self) -> &Snapshot {
+        &self.snapshot
+    }
+
+    /// 是否因超过截止时间而提前结束，此时迭代器无效，已返回的 key 只是部分结果，见 [`ScanTimeoutError`]
+    pub fn timed_out(&self) -> bool {
+        self.timed_out
+    }
+
+    /// 到目前为止从 VSST 读取 KV 分离的 value 的次数、块来自缓存还是文件以及总耗时
+    pub fn vsst_deref_stats(&self) -> VSstDerefStats {
+        self.vsst_deref.stats()
+    }
+
+    /// 跳过当前 key 的其余版本，同一个数据源（如 memtable）中可能同时有一个 key 的多个版本
+    fn skip_current_key(&mut self) -> anyhow::Result<()> {
+        let key = Bytes::copy_from_slice(self.iter.key());
+        self.next_inner()?;
+        while self.is_valid() && self.iter.key() == key {
             self.next_inner()?;
         }
         Ok(())
     }
+
+    fn move_to_non_delete(&mut self) -> anyhow::Result<()> {
+        while self.is_valid()
+            && (self.iter.value().is_empty()
+                || (!self.include_system && is_system_key(self.iter.key())))
+        {
+            self.skip_current_key()?;
+        }
+        Ok(())
+    }
 }
 
 impl StorageIterator for DbIterator {
@@ -66,12 +226,33 @@ impl StorageIterator for DbIterator {
     }
 
     fn next(&mut self) -> anyhow::Result<()> {
-        self.next_inner()?;
+        if self.deadline.is_some() {
+            self.last_key = Some(Bytes::copy_from_slice(self.iter.key()));
+        }
+        if self.latest.is_some() && self.reseek_latest(Bytes::copy_from_slice(self.iter.key()))? {
+            return Ok(());
+        }
+        self.skip_current_key()?;
         self.move_to_non_delete()?;
         Ok(())
     }
 }
 
+impl Drop for DbIterator {
+    fn drop(&mut self) {
+        let stats = self.vsst_deref.stats();
+        if stats.dereferences() > 0 {
+            debug!(
+                "scan dereferenced {} separated values in {:?}: {} from block cache, {} from disk",
+                stats.dereferences(),
+                stats.latency,
+                stats.cache_hits,
+                stats.disk_reads
+            );
+        }
+    }
+}
+
 pub struct FusedIterator<I: StorageIterator> {
     iter: I,
 }
@@ -80,6 +261,14 @@ impl<I: StorageIterator> FusedIterator<I> {
     pub fn new(iter: I) -> Self {
         Self { iter }
     }
+
+    pub fn inner(&self) -> &I {
+        &self.iter
+    }
+
+    pub(crate) fn inner_mut(&mut self) -> &mut I {
+        &mut self.iter
+    }
 }
 
 impl<I: StorageIterator> StorageIterator for FusedIterator<I> {
@@ -106,3 +295,93 @@ impl<I: StorageIterator> StorageIterator for FusedIterator<I> {
         Ok(())
     }
 }
+
+/// 自动刷新的迭代器，用于持续读取新写入的数据
+///
+/// 当前快照读完时在最新的数据上从上一次返回的 key 之后重新定位，每个 key 只会返回一次。
+/// 只有大于上一次返回的 key 的新数据才能被读到，适合 key 单调递增（如日志、时间序列）的场景。
+/// 迭代器始终持有一个快照，drop 之前 `Db::close` 会一直等待
+pub struct TailIterator {
+    db_inner: Arc<TrackedRwLock<Arc<DbInner>>>,
+    snapshots: Arc<SnapshotTracker>,
+    iter: FusedIterator<DbIterator>,
+    lower: Bound<Bytes>,
+    upper: Bound<Bytes>,
+    last_key: Option<Bytes>,
+}
+
+impl TailIterator {
+    pub(crate) fn new(
+        db_inner: Arc<TrackedRwLock<Arc<DbInner>>>,
+        snapshots: Arc<SnapshotTracker>,
+        lower: Bound<Bytes>,
+        upper: Bound<Bytes>,
+    ) -> anyhow::Result<Self> {
+        let iter = Self::scan(&db_inner, &snapshots, lower.clone(), upper.clone())?;
+        Ok(Self {
+            db_inner,
+            snapshots,
+            iter,
+            lower,
+            upper,
+            last_key: None,
+        })
+    }
+
+    fn scan(
+        db_inner: &TrackedRwLock<Arc<DbInner>>,
+        snapshots: &Arc<SnapshotTracker>,
+        lower: Bound<Bytes>,
+        upper: Bound<Bytes>,
+    ) -> anyhow::Result<FusedIterator<DbIterator>> {
+        let inner = Arc::clone(&db_inner.read());
+        Snapshot::new(inner, snapshots.clone()).scan(lower, upper)
+    }
+
+    /// 当前快照已读完时，在最新的快照上从上一次返回的 key 之后重新定位；否则什么也不做
+    ///
+    /// 读完后没有新数据时迭代器保持无效，可以稍后再次调用
+    pub fn refresh(&mut self) -> anyhow::Result<()> {
+        if self.iter.is_valid() {
+            return Ok(());
+        }
+        let lower = match &self.last_key {
+            Some(key) => Bound::Excluded(key.clone()),
+            None => self.lower.clone(),
+        };
+        self.iter = Self::scan(&self.db_inner, &self.snapshots, lower, self.upper.clone())?;
+        Ok(())
+    }
+
+    /// 迭代器当前所基于的快照，每次刷新后都会变化
+    pub fn snapshot(&self) -> &Snapshot {
+        self.iter.inner().snapshot()
+    }
+}
+
+impl StorageIterator for TailIterator {
+    fn meta(&self) -> &[u8] {
+        self.iter.meta()
+    }
+
+    fn key(&self) -> &[u8] {
+        self.iter.key()
+    }
+
+    fn value(&self) -> &[u8] {
+        self.iter.value()
+    }
+
+    fn is_valid(&self) -> bool {
+        self.iter.is_valid()
+    }
+
+    fn next(&mut self) -> anyhow::Result<()> {
+        if !self.iter.is_valid() {
+            return Ok(());
+        }
+        self.last_key = Some(Bytes::copy_from_slice(self.iter.key()));
+        self.iter.next()?;
+        self.refresh()
+    }
+}
@@ -1,18 +1,45 @@
 use std::io::Read;
-use std::ops::Bound::Unbounded;
+use std::ops::Bound;
+use std::ops::Bound::{Excluded, Included, Unbounded};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
 use std::sync::{Arc, Once};
 use std::thread::{self, Thread};
-use std::time::Duration;
+use std::time::{Duration, Instant, SystemTime};
 
 use bytes::{Bytes, BytesMut};
+use parking_lot::Mutex;
 use tracing::{debug, info, instrument, span};
 
 use tracing_subscriber::layer::SubscriberExt;
 use tracing_subscriber::Registry;
 
-use crate::db::Db;
+use crate::batch::{IdempotencyToken, WriteBatch};
+use crate::cdc::{self, CdcEvent};
+use crate::daemon::DbDaemon;
+use crate::db::{Db, DbOptions, MissingVSstPolicy, ReadOptions, WriteOptions};
+use crate::entry::{Entry, EntryBuilder};
 use crate::iterator::StorageIterator;
-use crate::{MEMTABLE_SIZE_LIMIT, MIN_VSST_SIZE};
+use crate::meta::manifest::{Manifest, ManifestItem, ManifestState};
+use crate::record::RecordBuilder;
+use crate::snapshot::Snapshot;
+use crate::sstable::builder::{BlockReadOptions, FilterLoading, SsTable};
+use crate::sstable::compression::CompressionType;
+use crate::storage::file::IoPriority;
+use crate::storage::header::FILE_HEADER_SIZE;
+use crate::system;
+use crate::trace::{self, ReplayOptions, TraceOp, TraceOptions, TraceReader, TraceWrite};
+use crate::validate::{
+    Rejection, WriteOp, WriteRejectedError, RESERVED_KEY_VALIDATOR, VALUE_SIZE_VALIDATOR,
+};
+use crate::wal::{WalCorruptionError, WalProtection};
+use crate::watch::WatchEvent;
+use crate::{
+    admin, AlreadyOpenError, CacheFillPolicy, CompactionJob, DbClosedError, DbIterator, FileType,
+    FusedIterator, HealthStatus, ManifestActor, MicroBatchOptions, OpType, PerfContextScope,
+    ReadOnlyError, ScanIsolation, ScanTimeoutError, SchedulerStep, SharedScheduler, ValueMetrics,
+    WriteStallStats, GB, KB, L0_SST_NUM_LIMIT, LOW_PRIORITY_WRITE_DELAY, MEMTABLE_SIZE_LIMIT,
+    MIN_VSST_SIZE, SST_LEVEL_LIMIT, WAL_SEGMENT_SIZE_LIMIT,
+};
 
 impl Db {
     fn print_debug_info(&self) {
@@ -89,14 +116,16 @@ fn test_recover() {
     let _v1 = BytesMut::zeroed(MEMTABLE_SIZE_LIMIT / 40).freeze();
 
     {
-        let db = Db::open_file(data_dir.path()).unwrap();
+        let db = Db::open_with_options(data_dir.path(), DbOptions::default()).unwrap();
         db.put(big_k1.clone(), big_v1.clone()).unwrap();
         for _ in 1..50 {
             db.put(_k1.clone(), _v1.clone()).unwrap();
         }
+        // 刷写完成后 big_k1 在 L0 SST 和 VSST 中，之后的写入只在 WAL 中
+        let steps = db.scheduler().unwrap().run_until_idle().unwrap();
+        assert_eq!(steps, vec![SchedulerStep::Rotate]);
         db.put(k1.clone(), v1.clone()).unwrap();
     }
-    thread::sleep(Duration::from_secs(2));
     {
         let db = Db::open_file(data_dir.path()).unwrap();
         assert_eq!(db.get(&k1).unwrap(), Some(v1));
@@ -111,7 +140,7 @@ fn test_rotate() {
     let data_dir = tempfile::tempdir().unwrap();
     println!("tempdir: {}", data_dir.path().to_str().unwrap());
 
-    let db = Db::open_file(data_dir.path()).unwrap();
+    let db = Db::open_with_options(data_dir.path(), DbOptions::default()).unwrap();
 
     for _ in 1..50 {
         let k1 = Bytes::from("k1");
@@ -120,35 +149,78 @@ fn test_rotate() {
         db.put(k1.clone(), v1.clone()).unwrap();
     }
 
-    thread::sleep(Duration::from_secs(2));
+    // 多次超过大小限制的请求合并为一次刷写
+    let steps = db.scheduler().unwrap().run_until_idle().unwrap();
+    assert_eq!(steps, vec![SchedulerStep::Rotate]);
     db.print_debug_info();
     assert_eq!(db.inner.read().levels[0].len(), 1);
 }
 
 #[test]
-fn test_background_write() {
+fn test_precreated_wal() {
     INIT.call_once(setup);
     let data_dir = tempfile::tempdir().unwrap();
+    {
+        let db = Db::open_with_options(data_dir.path(), DbOptions::default()).unwrap();
+        let log_id = db.inner.read().log_id;
+        db.put(Bytes::from("k1"), Bytes::from("v1")).unwrap();
+        db.flush().unwrap();
+        // 冻结后提前创建了下一次冻结要用的 WAL
+        assert_eq!(db.inner.read().log_id, log_id + 1);
+        assert!(Db::path_of_wal(data_dir.path(), log_id + 2).exists());
 
-    let db = Arc::new(Db::open_file(data_dir.path()).unwrap());
-    let _db = db.clone();
-    thread::spawn(move || loop {
-        let k1 = Bytes::from("k1");
-        let v1 = BytesMut::zeroed(MEMTABLE_SIZE_LIMIT / 40).freeze();
+        db.put(Bytes::from("k2"), Bytes::from("v2")).unwrap();
+        db.flush().unwrap();
+        db.put(Bytes::from("k3"), Bytes::from("v3")).unwrap();
+        assert_eq!(db.inner.read().log_id, log_id + 2);
+        assert!(Db::path_of_wal(data_dir.path(), log_id + 3).exists());
+    }
 
-        _db.put(k1.clone(), v1.clone()).unwrap();
-        thread::sleep(Duration::from_millis(100));
-    });
+    // 提前创建但还没有使用的 WAL 是空的，不影响恢复
+    let db = Db::open_with_options(data_dir.path(), DbOptions::default()).unwrap();
+    for (k, v) in [("k1", "v1"), ("k2", "v2"), ("k3", "v3")] {
+        assert_eq!(db.get(&Bytes::from(k)).unwrap(), Some(Bytes::from(v)));
+    }
+    db.put(Bytes::from("k4"), Bytes::from("v4")).unwrap();
+    db.flush().unwrap();
+    drop(db);
+    let db = Db::open_with_options(data_dir.path(), DbOptions::default()).unwrap();
+    assert_eq!(db.get(&Bytes::from("k4")).unwrap(), Some(Bytes::from("v4")));
+}
+
+#[test]
+fn test_background_write() {
+    INIT.call_once(setup);
+    let data_dir = tempfile::tempdir().unwrap();
 
-    for i in 1..60 {
-        {
+    let db = Db::open_with_options(data_dir.path(), DbOptions::default()).unwrap();
+    let scheduler = db.scheduler().unwrap();
+    let writes = AtomicUsize::new(0);
+    let v1 = BytesMut::zeroed(MEMTABLE_SIZE_LIMIT / 40).freeze();
+    let mut round = 0;
+
+    // 写入线程持续写入，本线程同时执行刷写和合并
+    thread::scope(|s| {
+        s.spawn(|| {
+            for i in 0..400 {
+                db.put(Bytes::from(format!("k{}", i % 100)), v1.clone())
+                    .unwrap();
+                writes.fetch_add(1, Ordering::Release);
+            }
+        });
+        while writes.load(Ordering::Acquire) < 400 {
+            if scheduler.step().unwrap().is_none() {
+                thread::yield_now();
+                continue;
+            }
+            round += 1;
             let snapshot = {
                 let guard = db.inner.read();
                 guard.as_ref().clone()
             };
             let _span = span!(tracing::Level::TRACE, "test background write");
             let _enter = _span.enter();
-            debug!("{} sec", i);
+            debug!("round {}", round);
             debug!("MEM SIZE: {}KB", snapshot.memtable.size() / 1024);
             debug!("FROZEN MEM: {}", snapshot.frozen_memtable.len());
             debug!("FROZEN LOG: {}", snapshot.frozen_wal.len());
@@ -157,7 +229,18 @@ fn test_background_write() {
             debug!("VSST: {}", snapshot.vssts.read().len());
             drop(_enter);
         }
-        thread::sleep(Duration::from_secs(1));
+    });
+
+    scheduler.run_until_idle().unwrap();
+    assert!(round > 0);
+    let snapshot = db.inner.read().clone();
+    assert!(snapshot.frozen_memtable.is_empty());
+    assert!(snapshot.levels[0].len() <= L0_SST_NUM_LIMIT);
+    for i in 0..100 {
+        assert_eq!(
+            db.get(&Bytes::from(format!("k{}", i))).unwrap(),
+            Some(v1.clone())
+        );
     }
 }
 
@@ -185,3 +268,3969 @@ fn test_iterator() {
     }
     assert!(!iter.is_valid());
 }
+
+#[test]
+fn test_newest_l0_sst_wins() {
+    INIT.call_once(setup);
+    let data_dir = tempfile::tempdir().unwrap();
+
+    let k1 = Bytes::from("k1");
+    let k2 = Bytes::from("k2");
+    let k3 = Bytes::from("k3");
+    {
+        let db = Db::open_file(data_dir.path()).unwrap();
+        db.put(k1.clone(), Bytes::from("v1")).unwrap();
+        db.put(k2.clone(), Bytes::from("v2")).unwrap();
+        db.put(k3.clone(), Bytes::from("v3")).unwrap();
+        db.daemon.freeze_and_flush().unwrap();
+
+        // 同一批 key 的新版本被刷写到另一个 L0 SST
+        db.put(k1.clone(), Bytes::from("v1_1")).unwrap();
+        db.delete(k2.clone()).unwrap();
+        db.daemon.freeze_and_flush().unwrap();
+        assert_eq!(db.inner.read().levels[0].len(), 2);
+
+        // memtable 中的删除标记同样要屏蔽 SST 中的旧值
+        db.delete(k3.clone()).unwrap();
+
+        assert_eq!(db.get(&k1).unwrap(), Some(Bytes::from("v1_1")));
+        assert_eq!(db.get(&k2).unwrap(), None);
+        assert_eq!(db.get(&k3).unwrap(), None);
+
+        let mut iter = db.scan(Unbounded, Unbounded).unwrap();
+        assert_eq!(iter.key(), &k1[..]);
+        assert_eq!(iter.value(), b"v1_1");
+        iter.next().unwrap();
+        assert!(!iter.is_valid());
+    }
+    {
+        let db = Db::open_file(data_dir.path()).unwrap();
+        assert_eq!(db.get(&k1).unwrap(), Some(Bytes::from("v1_1")));
+        assert_eq!(db.get(&k2).unwrap(), None);
+        assert_eq!(db.get(&k3).unwrap(), None);
+    }
+}
+
+#[test]
+fn test_recover_wal_segments() {
+    INIT.call_once(setup);
+    let data_dir = tempfile::tempdir().unwrap();
+
+    let value = BytesMut::zeroed(WAL_SEGMENT_SIZE_LIMIT as usize / 2).freeze();
+    {
+        let db = Db::open_file(data_dir.path()).unwrap();
+        for i in 0..3 {
+            db.put(Bytes::from(format!("k{}", i)), value.clone())
+                .unwrap();
+        }
+        assert!(db.inner.read().wal.num_of_segments() > 1);
+    }
+    {
+        let db = Db::open_file(data_dir.path()).unwrap();
+        assert_eq!(db.stats().recovery.wal_records, 3);
+        for i in 0..3 {
+            assert_eq!(
+                db.get(&Bytes::from(format!("k{}", i))).unwrap(),
+                Some(value.clone())
+            );
+        }
+        db.flush().unwrap();
+    }
+    // 刷写后 WAL 的所有段都已删除，不再重放
+    let db = Db::open_file(data_dir.path()).unwrap();
+    assert_eq!(db.stats().recovery.wal_records, 0);
+    assert_eq!(db.get(&Bytes::from("k0")).unwrap(), Some(value.clone()));
+}
+
+#[test]
+fn test_snapshot_guard() {
+    INIT.call_once(setup);
+    let data_dir = tempfile::tempdir().unwrap();
+
+    let db = Arc::new(Db::open_file(data_dir.path()).unwrap());
+    for i in 0..10 {
+        db.put(
+            Bytes::from(format!("k{:02}", i)),
+            Bytes::from(format!("v{:02}", i)),
+        )
+        .unwrap();
+    }
+    assert_eq!(db.num_of_active_snapshots(), 0);
+
+    let snapshot = db.snapshot();
+    let mut iter = db.scan(Unbounded, Unbounded).unwrap();
+    assert_eq!(db.num_of_active_snapshots(), 2);
+    drop(snapshot);
+    assert_eq!(db.num_of_active_snapshots(), 1);
+
+    // 迭代过程中发生 rotate，迭代器持有的快照仍然可用
+    db.daemon.freeze_and_flush().unwrap();
+    for i in 0..10 {
+        assert_eq!(iter.key(), format!("k{:02}", i).as_bytes());
+        iter.next().unwrap();
+    }
+    assert!(!iter.is_valid());
+
+    // close 需要等待迭代器释放
+    let closer = {
+        let db = db.clone();
+        thread::spawn(move || db.close().unwrap())
+    };
+    thread::sleep(Duration::from_millis(100));
+    assert!(!closer.is_finished());
+    drop(iter);
+    closer.join().unwrap();
+    assert_eq!(db.num_of_active_snapshots(), 0);
+}
+
+#[test]
+fn test_active_iterators() {
+    let data_dir = tempfile::tempdir().unwrap();
+    let options = DbOptions {
+        iterator_age_warning: Some(Duration::from_millis(50)),
+        ..DbOptions::default()
+    };
+    let db = Db::open_with_options(data_dir.path(), options).unwrap();
+    db.put(Bytes::from("k1"), Bytes::from("v1")).unwrap();
+    assert_eq!(db.stats().active_iterators, 0);
+    assert!(db.stats().oldest_iterator_age.is_none());
+
+    // 同一快照上的多个迭代器分别登记
+    let snapshot = db.snapshot();
+    let old = snapshot.scan(Unbounded, Unbounded).unwrap();
+    thread::sleep(Duration::from_millis(100));
+    let new = snapshot.scan(Unbounded, Unbounded).unwrap();
+    drop(snapshot);
+    let iterators = db.active_iterators();
+    assert_eq!(iterators.len(), 2);
+    assert!(iterators[0].age >= Duration::from_millis(100));
+    assert!(iterators[0].age > iterators[1].age);
+    assert_eq!(
+        iterators[0].backtrace.is_some(),
+        cfg!(feature = "iterator-backtrace")
+    );
+    let stats = db.stats();
+    assert_eq!(stats.active_iterators, 2);
+    assert!(stats.oldest_iterator_age.unwrap() >= Duration::from_millis(100));
+
+    let health = db.health();
+    assert_eq!(health.status, HealthStatus::Degraded);
+    assert!(health.issues[0].contains("iterators open for longer than 50ms"));
+
+    drop(old);
+    assert_eq!(db.active_iterators().len(), 1);
+    drop(new);
+    assert_eq!(db.stats().active_iterators, 0);
+    assert!(db.health().is_ok());
+}
+
+#[test]
+fn test_get_many_with_snapshot() {
+    INIT.call_once(setup);
+    let data_dir = tempfile::tempdir().unwrap();
+
+    let db = Db::open_file(data_dir.path()).unwrap();
+    db.put(Bytes::from("k1"), Bytes::from("v1")).unwrap();
+    db.put(Bytes::from("k2"), Bytes::from("v2")).unwrap();
+    db.daemon.freeze_and_flush().unwrap();
+    db.put(Bytes::from("k3"), Bytes::from("v3")).unwrap();
+    db.delete(Bytes::from("k2")).unwrap();
+
+    let snapshot = db.snapshot();
+    // 快照之后的写入不可见
+    db.put(Bytes::from("k0"), Bytes::from("v0")).unwrap();
+    db.put(Bytes::from("k1"), Bytes::from("v1'")).unwrap();
+    db.put(Bytes::from("k2"), Bytes::from("v2'")).unwrap();
+    db.delete(Bytes::from("k3")).unwrap();
+    let keys = [
+        Bytes::from("k3"),
+        Bytes::from("k0"),
+        Bytes::from("k1"),
+        Bytes::from("k2"),
+        Bytes::from("k1"),
+    ];
+    let values = db.get_many_with_snapshot(&snapshot, &keys).unwrap();
+    assert_eq!(
+        values,
+        vec![
+            Some(Bytes::from("v3")),
+            None,
+            Some(Bytes::from("v1")),
+            None,
+            Some(Bytes::from("v1")),
+        ]
+    );
+    assert_eq!(
+        db.get_with_snapshot(&snapshot, &Bytes::from("k1")).unwrap(),
+        Some(Bytes::from("v1"))
+    );
+}
+
+#[test]
+fn test_get_with_snapshot_ignores_newer_writes() {
+    INIT.call_once(setup);
+    let data_dir = tempfile::tempdir().unwrap();
+
+    let db = Db::open_file(data_dir.path()).unwrap();
+    db.put(Bytes::from("k1"), Bytes::from("v1")).unwrap();
+    db.put(Bytes::from("k2"), Bytes::from("v1")).unwrap();
+    let snapshot = db.snapshot();
+    // 快照之后的写入进入快照持有的同一个 memtable
+    db.put(Bytes::from("k1"), Bytes::from("v2")).unwrap();
+    db.delete(Bytes::from("k2")).unwrap();
+    db.put(Bytes::from("k3"), Bytes::from("v2")).unwrap();
+
+    let get = |key: &'static str| db.get_with_snapshot(&snapshot, &Bytes::from(key)).unwrap();
+    assert_eq!(get("k1"), Some(Bytes::from("v1")));
+    assert_eq!(get("k2"), Some(Bytes::from("v1")));
+    assert_eq!(get("k3"), None);
+    assert_eq!(db.get(&Bytes::from("k1")).unwrap(), Some(Bytes::from("v2")));
+    assert_eq!(db.get(&Bytes::from("k2")).unwrap(), None);
+}
+
+#[test]
+fn test_multi_get_separated_values() {
+    INIT.call_once(setup);
+    let data_dir = tempfile::tempdir().unwrap();
+
+    let db = Db::open_file(data_dir.path()).unwrap();
+    let key = |i: usize| Bytes::from(format!("k{:03}", i));
+    let big_value = |i: usize, round: usize| {
+        let mut value = format!("{}-{}-", i, round).into_bytes();
+        value.resize(MIN_VSST_SIZE as usize + 1, b'v');
+        Bytes::from(value)
+    };
+    // 每轮刷写到不同的 VSST，后一轮覆盖前一轮的部分 key
+    for round in 0..3 {
+        for i in (round * 20)..100 {
+            db.put(key(i), big_value(i, round)).unwrap();
+        }
+        db.flush().unwrap();
+    }
+    db.put(key(5), Bytes::from("small")).unwrap();
+    db.delete(key(50)).unwrap();
+
+    let mut keys: Vec<_> = (0..110).rev().step_by(3).map(key).collect();
+    keys.push(key(5));
+    keys.push(key(50));
+    keys.push(key(99));
+    let expected: Vec<_> = keys.iter().map(|k| db.get(k).unwrap()).collect();
+    assert!(expected.iter().filter(|v| v.is_some()).count() > 30);
+    assert_eq!(db.multi_get(&keys).unwrap(), expected);
+    assert_eq!(expected[keys.len() - 3], Some(Bytes::from("small")));
+    assert_eq!(expected[keys.len() - 2], None);
+    assert_eq!(expected[keys.len() - 1], Some(big_value(99, 2)));
+
+    // 快照上的批量读取不受之后刷写和写入的影响
+    let snapshot = db.snapshot();
+    db.flush().unwrap();
+    db.put(key(99), Bytes::from("new")).unwrap();
+    let options = ReadOptions {
+        snapshot: Some(snapshot),
+        ..Default::default()
+    };
+    assert_eq!(
+        db.multi_get_with_options(&[key(99), key(1)], &options)
+            .unwrap(),
+        vec![Some(big_value(99, 2)), Some(big_value(1, 0))]
+    );
+    assert_eq!(db.multi_get(&[]).unwrap(), vec![]);
+}
+
+#[test]
+fn test_approximate_memory_usage() {
+    let data_dir = tempfile::tempdir().unwrap();
+    let db = Db::open_with_options(data_dir.path(), DbOptions::default()).unwrap();
+    let usage = db.approximate_memory_usage();
+    assert_eq!(usage.frozen_memtables + usage.pinned_memtables, 0);
+    assert_eq!(usage.block_cache, 0);
+
+    for i in 0..100 {
+        db.put(Bytes::from(format!("k{:03}", i)), Bytes::from("v"))
+            .unwrap();
+    }
+    let usage = db.approximate_memory_usage();
+    assert!(usage.active_memtable >= 100 * 5);
+
+    // 迭代器持有刷写前的 memtable，刷写后计入 pinned_memtables
+    let iter = db.scan(Unbounded, Unbounded).unwrap();
+    db.flush().unwrap();
+    let flushed = db.approximate_memory_usage();
+    assert_eq!(flushed.active_memtable, 0);
+    assert_eq!(flushed.pinned_memtables, usage.active_memtable);
+    assert!(flushed.filters > 0);
+    assert!(flushed.indexes > 0);
+    drop(iter);
+    assert_eq!(db.approximate_memory_usage().pinned_memtables, 0);
+
+    // 读取后块进入缓存
+    assert_eq!(
+        db.get(&Bytes::from("k001")).unwrap(),
+        Some(Bytes::from("v"))
+    );
+    let usage = db.approximate_memory_usage();
+    assert!(usage.block_cache > 0);
+    assert_eq!(
+        usage.total(),
+        usage.active_memtable
+            + usage.frozen_memtables
+            + usage.pinned_memtables
+            + usage.block_cache
+            + usage.filters
+            + usage.indexes
+    );
+    let stats = db.stats();
+    assert_eq!(stats.filter_memory, usage.filters);
+    assert_eq!(stats.index_memory, usage.indexes);
+    assert_eq!(stats.block_cache_memory, usage.block_cache);
+    assert_eq!(stats.compressed_block_cache_memory, 0);
+}
+
+#[test]
+fn test_cache_compressed_blocks() {
+    for compression in [CompressionType::None, CompressionType::Zstd] {
+        let data_dir = tempfile::tempdir().unwrap();
+        let options = DbOptions {
+            cache_compressed_blocks: true,
+            compaction_compression: compression,
+            ..Default::default()
+        };
+        let db = Db::open_with_options(data_dir.path(), options).unwrap();
+        for i in 0..1000 {
+            db.put(
+                Bytes::from(format!("k{:04}", i)),
+                Bytes::from("v".repeat(50)),
+            )
+            .unwrap();
+        }
+        db.flush().unwrap();
+        db.compact_to(0, 1).unwrap();
+
+        for _ in 0..2 {
+            assert_eq!(
+                db.get(&Bytes::from("k0500")).unwrap(),
+                Some(Bytes::from("v".repeat(50)))
+            );
+        }
+        let stats = db.stats();
+        let usage = db.approximate_memory_usage();
+        assert_eq!(
+            usage.block_cache,
+            stats.block_cache_memory + stats.compressed_block_cache_memory
+        );
+        if compression == CompressionType::Zstd {
+            // 压缩的块以压缩后的形式缓存，命中时再解码
+            assert_eq!(stats.compressed_block_cache_hits, 1);
+            assert_eq!(stats.block_cache_hits, 0);
+            assert!(stats.compressed_block_cache_memory > 0);
+            assert_eq!(stats.block_cache_memory, 0);
+        } else {
+            // 没有压缩的 SST 的块仍以解码后的形式缓存，命中时不需要再解码
+            assert_eq!(stats.compressed_block_cache_hits, 0);
+            assert_eq!(stats.block_cache_hits, 1);
+            assert_eq!(stats.compressed_block_cache_memory, 0);
+            assert!(stats.block_cache_memory > 0);
+        }
+    }
+}
+
+#[test]
+fn test_compaction_compression() {
+    let data_dir = tempfile::tempdir().unwrap();
+    let options = || DbOptions {
+        compaction_compression: CompressionType::Zstd,
+        zstd_dict_size: 4 * KB,
+        ..Default::default()
+    };
+    let value_of = |i: usize| {
+        Bytes::from(format!(
+            "{{\"id\":{},\"name\":\"user-{}\",\"status\":\"active\"}}",
+            i, i
+        ))
+    };
+    {
+        let db = Db::open_with_options(data_dir.path(), options()).unwrap();
+        for i in 0..2000 {
+            db.put(Bytes::from(format!("user:{:08}", i)), value_of(i))
+                .unwrap();
+        }
+        db.flush().unwrap();
+        // 刷写出的 L0 SST 不压缩
+        assert_eq!(
+            db.inner.read().levels[0][0].compression(),
+            CompressionType::None
+        );
+        db.compact_to(0, 1).unwrap();
+
+        let snapshot = db.inner.read().clone();
+        assert!(snapshot.levels[0].is_empty());
+        assert!(!snapshot.levels[1].is_empty());
+        for sst in &snapshot.levels[1] {
+            assert_eq!(sst.compression(), CompressionType::Zstd);
+            assert!(!sst.dictionary().is_empty());
+        }
+    }
+
+    let db = Db::open_with_options(data_dir.path(), options()).unwrap();
+    for sst in &db.inner.read().levels[1] {
+        assert_eq!(sst.compression(), CompressionType::Zstd);
+        assert!(!sst.dictionary().is_empty());
+    }
+    for i in (0..2000).step_by(97) {
+        assert_eq!(
+            db.get(&Bytes::from(format!("user:{:08}", i))).unwrap(),
+            Some(value_of(i))
+        );
+    }
+}
+
+#[test]
+fn test_stats_and_health() {
+    INIT.call_once(setup);
+    let data_dir = tempfile::tempdir().unwrap();
+
+    let db = Db::open_file(data_dir.path()).unwrap();
+    db.put(Bytes::from("k1"), Bytes::from("v1")).unwrap();
+    db.daemon.freeze_and_flush().unwrap();
+
+    let stats = db.stats();
+    assert_eq!(stats.level_files.len(), SST_LEVEL_LIMIT as usize);
+    assert_eq!(stats.level_files[0], 1);
+    assert_eq!(stats.frozen_memtables, 0);
+    assert_eq!(stats.flush_queue.pending, 0);
+    assert!(stats.flush_queue.oldest_pending_age.is_none());
+    assert!(db.health().is_ok());
+}
+
+#[test]
+fn test_level_metadata() {
+    INIT.call_once(setup);
+    let data_dir = tempfile::tempdir().unwrap();
+
+    let db = Db::open_file(data_dir.path()).unwrap();
+    assert!(db.level_metadata().iter().all(|l| l.files.is_empty()));
+
+    db.put(Bytes::from("k3"), Bytes::from("v3")).unwrap();
+    db.put(Bytes::from("k5"), Bytes::from("v5")).unwrap();
+    db.flush().unwrap();
+    db.put(Bytes::from("k1"), Bytes::from("v1")).unwrap();
+    db.put(Bytes::from("k4"), Bytes::from("v4")).unwrap();
+    db.delete(Bytes::from("k3")).unwrap();
+    db.flush().unwrap();
+
+    let levels = db.level_metadata();
+    assert_eq!(levels.len(), SST_LEVEL_LIMIT as usize);
+    let l0 = &levels[0];
+    assert_eq!(l0.level, 0);
+    assert_eq!(l0.files.len(), 2);
+    assert_eq!(l0.smallest_key, Some(Bytes::from("k1")));
+    assert_eq!(l0.largest_key, Some(Bytes::from("k5")));
+    assert_eq!(l0.size, l0.files.iter().map(|f| f.size).sum::<u64>());
+    assert!(l0.size > 0);
+    assert_eq!(l0.files.iter().map(|f| f.num_of_pairs).sum::<usize>(), 5);
+    assert_eq!(l0.num_of_pairs, 5);
+    assert_eq!(l0.num_of_deletes, 1);
+    assert_eq!(l0.tombstone_density(), Some(0.2));
+    assert_eq!(l0.average_file_size(), l0.size / 2);
+    assert_eq!(l0.largest_key_preview(), Some("k5".to_string()));
+    assert!(levels[1].smallest_key.is_none());
+    assert_eq!(levels[1].tombstone_density(), None);
+}
+
+#[test]
+fn test_seek_miss_stats() {
+    INIT.call_once(setup);
+    let data_dir = tempfile::tempdir().unwrap();
+
+    let db = Db::open_file(data_dir.path()).unwrap();
+    for i in 0..100 {
+        db.put(Bytes::from(format!("k{:05}", i)), Bytes::from("v"))
+            .unwrap();
+    }
+    db.flush().unwrap();
+    let file = |db: &Db| db.level_metadata()[0].files[0].clone();
+    assert_eq!((file(&db).seeks, file(&db).seek_misses), (0, 0));
+
+    for i in 0..10 {
+        assert!(db
+            .get(&Bytes::from(format!("k{:05}", i)))
+            .unwrap()
+            .is_some());
+    }
+    assert_eq!((file(&db).seeks, file(&db).seek_misses), (10, 0));
+
+    // 不存在的 key 只有 bloom filter 误判时才会探测 SST，每次探测都未命中
+    for i in 0..10000 {
+        assert!(db
+            .get(&Bytes::from(format!("x{:05}", i)))
+            .unwrap()
+            .is_none());
+    }
+    let file = file(&db);
+    assert!(file.seek_misses > 0 && file.seek_misses < 1000);
+    assert_eq!(file.seeks, 10 + file.seek_misses);
+}
+
+#[test]
+fn test_filter_loading() {
+    INIT.call_once(setup);
+    let data_dir = tempfile::tempdir().unwrap();
+
+    let key = |i: usize| Bytes::from(format!("k{:03}", i));
+    let options = |filter_loading| DbOptions {
+        filter_loading,
+        ..Default::default()
+    };
+    {
+        let db =
+            Db::open_file_with_options(data_dir.path(), options(FilterLoading::Disabled)).unwrap();
+        for i in 0..100 {
+            db.put(key(i), Bytes::from("v")).unwrap();
+        }
+        db.flush().unwrap();
+        assert_eq!(db.stats().filter_memory, 0);
+        assert_eq!(db.get(&key(1)).unwrap(), Some(Bytes::from("v")));
+    }
+
+    let db = Db::open_file_with_options(data_dir.path(), options(FilterLoading::Lazy)).unwrap();
+    assert_eq!(db.stats().filter_memory, 0);
+    assert_eq!(db.get(&key(1)).unwrap(), Some(Bytes::from("v")));
+    let lazy_memory = db.stats().filter_memory;
+    assert!(lazy_memory > 0);
+    drop(db);
+
+    let db = Db::open_file(data_dir.path()).unwrap();
+    assert!(db.stats().filter_memory >= lazy_memory);
+    assert_eq!(db.get(&key(2)).unwrap(), Some(Bytes::from("v")));
+    assert_eq!(db.get(&key(200)).unwrap(), None);
+}
+
+#[test]
+fn test_index_cache_size() {
+    INIT.call_once(setup);
+    let data_dir = tempfile::tempdir().unwrap();
+
+    let key = |i: usize| Bytes::from(format!("k{:03}", i));
+    {
+        let db = Db::open_file(data_dir.path()).unwrap();
+        for i in 0..100 {
+            db.put(key(i), Bytes::from("v")).unwrap();
+        }
+        db.flush().unwrap();
+        assert!(db.stats().index_memory > 0);
+    }
+
+    // 容量为 0 时 meta 不常驻内存，每次读取都重新加载
+    let options = DbOptions {
+        index_cache_size: Some(0),
+        ..Default::default()
+    };
+    let db = Db::open_file_with_options(data_dir.path(), options).unwrap();
+    assert_eq!(db.stats().index_memory, 0);
+    for i in 0..100 {
+        assert_eq!(db.get(&key(i)).unwrap(), Some(Bytes::from("v")));
+    }
+    let stats = db.stats();
+    assert_eq!(stats.index_memory, 0);
+    assert!(stats.index_loads > 0);
+}
+
+#[test]
+fn test_wal_protection() {
+    INIT.call_once(setup);
+    let data_dir = tempfile::tempdir().unwrap();
+    let options = DbOptions {
+        wal_protection: Some(WalProtection::encrypted([1u8; 32])),
+        ..Default::default()
+    };
+    {
+        let db = Db::open_file_with_options(data_dir.path(), options.clone()).unwrap();
+        db.put(Bytes::from("k1"), Bytes::from("v1")).unwrap();
+        db.put(Bytes::from("k2"), Bytes::from("v2")).unwrap();
+    }
+
+    // 密钥不对时无法通过校验
+    let wrong_key = DbOptions {
+        wal_protection: Some(WalProtection::encrypted([2u8; 32])),
+        ..Default::default()
+    };
+    let err = Db::open_file_with_options(data_dir.path(), wrong_key).unwrap_err();
+    assert_eq!(
+        err.downcast_ref::<WalCorruptionError>().unwrap().offset,
+        FILE_HEADER_SIZE as u64
+    );
+
+    assert!(crate::admin::verify_with_options(data_dir.path(), &options)
+        .unwrap()
+        .is_ok());
+
+    let db = Db::open_file_with_options(data_dir.path(), options).unwrap();
+    assert_eq!(db.get(&Bytes::from("k1")).unwrap(), Some(Bytes::from("v1")));
+    assert_eq!(db.get(&Bytes::from("k2")).unwrap(), Some(Bytes::from("v2")));
+}
+
+#[test]
+fn test_db_options() {
+    INIT.call_once(setup);
+    let data_dir = tempfile::tempdir().unwrap();
+    let options = DbOptions {
+        memtable_size_limit: 4 * KB,
+        wal_segment_size_limit: KB as u64,
+        ..Default::default()
+    };
+    let db = Db::open_file_with_options(data_dir.path(), options).unwrap();
+    let value = BytesMut::zeroed(KB).freeze();
+    // 未达到 memtable 大小限制前 WAL 已按段大小切换
+    for i in 0..3 {
+        db.put(Bytes::from(format!("k{}", i)), value.clone())
+            .unwrap();
+    }
+    assert!(db.inner.read().wal.num_of_segments() > 1);
+    for i in 3..8 {
+        db.put(Bytes::from(format!("k{}", i)), value.clone())
+            .unwrap();
+    }
+
+    // 超过 memtable 大小限制后后台刷写
+    for _ in 0..100 {
+        if db.stats().level_files[0] > 0 {
+            break;
+        }
+        thread::sleep(Duration::from_millis(10));
+    }
+    assert!(db.stats().level_files[0] > 0);
+    assert_eq!(db.get(&Bytes::from("k1")).unwrap(), Some(value));
+}
+
+#[test]
+fn test_block_size_options() {
+    INIT.call_once(setup);
+    // 返回 L0、L1 SST 和 VSST 的块数
+    let num_of_blocks = |options: DbOptions| {
+        let data_dir = tempfile::tempdir().unwrap();
+        let db = Db::open_with_options(data_dir.path(), options).unwrap();
+        for i in 0..200 {
+            db.put(
+                Bytes::from(format!("k{:04}", i)),
+                Bytes::from("v".repeat(50)),
+            )
+            .unwrap();
+        }
+        for i in 0..4 {
+            db.put(
+                Bytes::from(format!("big{}", i)),
+                BytesMut::zeroed(MIN_VSST_SIZE as usize * 2).freeze(),
+            )
+            .unwrap();
+        }
+        db.flush().unwrap();
+        let l0 = db.inner.read().levels[0][0].num_of_blocks();
+        let vsst = db
+            .inner
+            .read()
+            .vssts
+            .read()
+            .values()
+            .map(|vsst| vsst.num_of_blocks())
+            .sum::<usize>();
+        db.compact_to(0, 1).unwrap();
+        let l1 = db.inner.read().levels[1]
+            .iter()
+            .map(|sst| sst.num_of_blocks())
+            .sum::<usize>();
+        assert_eq!(
+            db.get(&Bytes::from("k0123")).unwrap(),
+            Some(Bytes::from("v".repeat(50)))
+        );
+        (l0, l1, vsst)
+    };
+
+    let (l0, l1, vsst) = num_of_blocks(DbOptions::default());
+    let (small_l0, small_l1, large_vsst) = num_of_blocks(DbOptions {
+        block_size: 512,
+        vsst_block_size: 64 * KB,
+        ..Default::default()
+    });
+    assert!(small_l0 > l0);
+    assert!(small_l1 > l1);
+    assert!(large_vsst < vsst);
+}
+
+#[test]
+fn test_shared_scheduler() {
+    INIT.call_once(setup);
+    let scheduler = Arc::new(SharedScheduler::new(1).unwrap());
+    let dirs: Vec<_> = (0..4).map(|_| tempfile::tempdir().unwrap()).collect();
+    let open = |dir: &tempfile::TempDir| {
+        let options = DbOptions {
+            memtable_size_limit: 4 * KB,
+            l0_sst_num_limit: 2,
+            shared_scheduler: Some(scheduler.clone()),
+            scheduler_quota: 1,
+            ..Default::default()
+        };
+        Db::open_file_with_options(dir.path(), options).unwrap()
+    };
+    let dbs: Vec<_> = dirs.iter().map(open).collect();
+    assert_eq!(scheduler.threads(), 1);
+    assert_eq!(scheduler.num_of_members(), 4);
+
+    // 每个数据库都写入足以触发多次刷写和合并的数据，一个线程轮流为它们执行
+    let value = BytesMut::zeroed(KB).freeze();
+    for i in 0..32 {
+        for db in &dbs {
+            db.put(Bytes::from(format!("k{:02}", i)), value.clone())
+                .unwrap();
+        }
+    }
+    for db in &dbs {
+        for _ in 0..500 {
+            if db.stats().level_files.iter().sum::<usize>() > 0 {
+                break;
+            }
+            thread::sleep(Duration::from_millis(10));
+        }
+        assert!(db.stats().level_files.iter().sum::<usize>() > 0);
+    }
+
+    // 关闭时退出调度，已请求的刷写执行完
+    for db in &dbs {
+        db.close().unwrap();
+    }
+    assert_eq!(scheduler.num_of_members(), 0);
+    drop(dbs);
+    for dir in &dirs {
+        let db = Db::open_file(dir.path()).unwrap();
+        for i in 0..32 {
+            assert_eq!(
+                db.get(&Bytes::from(format!("k{:02}", i))).unwrap(),
+                Some(value.clone())
+            );
+        }
+    }
+}
+
+#[test]
+fn test_compaction_io_priority() {
+    INIT.call_once(setup);
+    let data_dir = tempfile::tempdir().unwrap();
+    let options = DbOptions {
+        compaction_io_priority: IoPriority::Background,
+        ..Default::default()
+    };
+    let db = Arc::new(Db::open_with_options(data_dir.path(), options).unwrap());
+    for round in 0..4 {
+        for i in 0..100 {
+            db.put(
+                Bytes::from(format!("k{:03}", i)),
+                Bytes::from(format!("v{}-{}", round, i)),
+            )
+            .unwrap();
+        }
+        db.flush().unwrap();
+    }
+
+    // 合并的同时持续读取
+    let reader = {
+        let db = db.clone();
+        thread::spawn(move || {
+            for _ in 0..20 {
+                for i in (0..100).step_by(7) {
+                    let value = db.get(&Bytes::from(format!("k{:03}", i))).unwrap();
+                    assert_eq!(value, Some(Bytes::from(format!("v3-{}", i))));
+                }
+            }
+        })
+    };
+    db.compact(0).unwrap();
+    reader.join().unwrap();
+
+    assert_eq!(db.stats().level_files[0], 0);
+    for i in 0..100 {
+        assert_eq!(
+            db.get(&Bytes::from(format!("k{:03}", i))).unwrap(),
+            Some(Bytes::from(format!("v3-{}", i)))
+        );
+    }
+}
+
+#[test]
+fn test_compact_to_output_level() {
+    INIT.call_once(setup);
+    let data_dir = tempfile::tempdir().unwrap();
+    let key = |i: usize| Bytes::from(format!("k{:03}", i));
+    let level_sizes = |db: &Db| -> Vec<usize> {
+        db.level_metadata()
+            .iter()
+            .map(|level| level.files.len())
+            .collect()
+    };
+
+    {
+        let db = Db::open_with_options(data_dir.path(), DbOptions::default()).unwrap();
+        for i in 0..20 {
+            db.put(key(i), Bytes::from("old")).unwrap();
+        }
+        db.flush().unwrap();
+        db.compact(0).unwrap();
+        assert_eq!(level_sizes(&db), vec![0, 1, 0, 0, 0, 0]);
+
+        // L1 中重叠的旧数据一起合并，新数据覆盖旧数据
+        for i in 10..30 {
+            db.put(key(i), Bytes::from("new")).unwrap();
+        }
+        db.flush().unwrap();
+        let plan = db.plan_compaction_to(0, 5).unwrap();
+        assert_eq!(plan.output_level, 5);
+        assert_eq!(plan.intermediate_inputs.len(), 4);
+        assert_eq!(plan.intermediate_inputs[0].1.len(), 1);
+        db.compact_to(0, 5).unwrap();
+        assert_eq!(level_sizes(&db), vec![0, 0, 0, 0, 0, 1]);
+        assert!(db.compact_to(0, 0).is_err());
+        assert!(db.compact_to(0, 6).is_err());
+    }
+
+    let db = Db::open_with_options(data_dir.path(), DbOptions::default()).unwrap();
+    assert_eq!(level_sizes(&db), vec![0, 0, 0, 0, 0, 1]);
+    for i in 0..30 {
+        let expected = if i < 10 { "old" } else { "new" };
+        assert_eq!(db.get(&key(i)).unwrap(), Some(Bytes::from(expected)));
+    }
+}
+
+#[test]
+fn test_compaction_history() {
+    INIT.call_once(setup);
+    let data_dir = tempfile::tempdir().unwrap();
+    let key = |i: usize| Bytes::from(format!("k{:03}", i));
+    {
+        let db = Db::open_with_options(data_dir.path(), DbOptions::default()).unwrap();
+        for i in 0..20 {
+            db.put(key(i), Bytes::from("old")).unwrap();
+        }
+        db.flush().unwrap();
+        db.compact(0).unwrap();
+        for i in 10..30 {
+            db.put(key(i), Bytes::from("new")).unwrap();
+        }
+        db.flush().unwrap();
+        db.compact(0).unwrap();
+    }
+
+    let history = admin::compaction_history(data_dir.path()).unwrap();
+    assert_eq!(history.len(), 2);
+    let (first, second) = (&history[0], &history[1]);
+    assert!(first.record_idx < second.record_idx);
+    assert_eq!(first.summary.input_files, 1);
+    assert_eq!(first.summary.dropped_entries, 0);
+    // 第二次合并读入第一次合并的输出，覆盖的 10 个旧版本被丢弃
+    let summary = second.summary;
+    assert_eq!((summary.level, summary.output_level), (0, 1));
+    assert_eq!(summary.input_files, 2);
+    assert_eq!(second.inputs.len(), 2);
+    assert!(second.inputs.contains(&first.outputs[0]));
+    assert!(second.outputs.iter().all(|(level, _)| *level == 1));
+    assert_eq!(summary.input_entries, 40);
+    assert_eq!(summary.dropped_entries, 10);
+    assert!(summary.input_bytes > 0 && summary.output_bytes > 0);
+    assert!(summary.finished_at >= first.summary.finished_at);
+}
+
+#[test]
+fn test_manifest_audit_log() {
+    INIT.call_once(setup);
+    let data_dir = tempfile::tempdir().unwrap();
+    let started_at = SystemTime::now() - Duration::from_secs(1);
+    {
+        let db = Db::open_with_options(data_dir.path(), DbOptions::default()).unwrap();
+        db.put(Bytes::from("k1"), Bytes::from("v1")).unwrap();
+        db.flush().unwrap();
+        db.put(Bytes::from("k1"), Bytes::from("v2")).unwrap();
+        db.flush().unwrap();
+        db.compact(0).unwrap();
+        db.daemon.delete_obsolete_files().unwrap();
+        db.close().unwrap();
+    }
+
+    let log = admin::audit_log(data_dir.path()).unwrap();
+    assert_eq!(log[0].actor, Some(ManifestActor::Open));
+    assert!(log.iter().all(|r| r.time.unwrap() >= started_at));
+    let by = |actor: ManifestActor| -> Vec<&admin::AuditRecord> {
+        log.iter().filter(|r| r.actor == Some(actor)).collect()
+    };
+    let flushed: Vec<_> = by(ManifestActor::Flush)
+        .iter()
+        .flat_map(|r| r.added.clone())
+        .filter(|(file_type, _)| *file_type == FileType::Sst)
+        .collect();
+    assert_eq!(flushed, vec![(FileType::Sst, 1), (FileType::Sst, 2)]);
+    // 合并移除的 SST 随后由后台删除
+    let compaction = by(ManifestActor::Compaction);
+    assert_eq!(compaction.len(), 1);
+    let mut removed = compaction[0].removed.clone();
+    removed.sort_by_key(|(_, id)| *id);
+    assert_eq!(removed, flushed);
+    let mut deleted: Vec<_> = by(ManifestActor::Gc)
+        .iter()
+        .flat_map(|r| r.deleted.clone())
+        .collect();
+    deleted.sort_by_key(|(_, id)| *id);
+    assert_eq!(deleted, flushed);
+    assert_eq!(log.last().unwrap().actor, Some(ManifestActor::Close));
+}
+
+#[test]
+fn test_background_thread_names() {
+    INIT.call_once(setup);
+    let (dir1, dir2) = (tempfile::tempdir().unwrap(), tempfile::tempdir().unwrap());
+    let thread_names = |db: &Db| -> Vec<String> {
+        db.background_tasks
+            .lock()
+            .iter()
+            .map(|handle| handle.thread().name().unwrap().to_string())
+            .collect()
+    };
+
+    let tag = {
+        let db1 = Db::open_file(dir1.path()).unwrap();
+        let db2 = Db::open_file(dir2.path()).unwrap();
+        let tag = db1.instance_tag().to_string();
+        assert_ne!(tag, db2.instance_tag());
+        assert_eq!(
+            thread_names(&db1),
+            ["flush", "compact", "delete"]
+                .map(|role| format!("lasagnedb-{}-{}", role, tag))
+                .to_vec()
+        );
+        tag
+    };
+    // 同一目录重新打开时标签不变
+    let db1 = Db::open_file(dir1.path()).unwrap();
+    assert_eq!(db1.instance_tag(), tag);
+}
+
+#[test]
+fn test_background_delete() {
+    INIT.call_once(setup);
+    let data_dir = tempfile::tempdir().unwrap();
+    let options = DbOptions {
+        delete_rate_limit: Some(GB as u64),
+        ..Default::default()
+    };
+    let key = |i: usize| Bytes::from(format!("k{:03}", i));
+    let big_value = Bytes::from(vec![b'v'; MIN_VSST_SIZE as usize + 1]);
+
+    let obsolete_paths = {
+        let db = Db::open_with_options(data_dir.path(), options.clone()).unwrap();
+        for i in 0..10 {
+            db.put(key(i), big_value.clone()).unwrap();
+        }
+        db.flush().unwrap();
+        // 覆盖全部 KV 分离的 value，合并后 VSST 不再被引用
+        for i in 0..10 {
+            db.put(key(i), Bytes::from("v")).unwrap();
+        }
+        db.flush().unwrap();
+
+        let mut paths: Vec<_> = db.level_metadata()[0]
+            .files
+            .iter()
+            .map(|sst| Db::path_of_sst(data_dir.path(), sst.id))
+            .collect();
+        let vsst_ids: Vec<u64> = db
+            .inner
+            .read()
+            .vssts
+            .read()
+            .values()
+            .filter(|vsst| vsst.num_of_pairs() > 0)
+            .map(|vsst| vsst.id())
+            .collect();
+        assert_eq!(vsst_ids.len(), 1);
+        paths.push(Db::path_of_vsst(data_dir.path(), vsst_ids[0]));
+
+        db.compact(0).unwrap();
+        // 没有启动后台任务，合并后文件仍在等待删除
+        assert_eq!(db.stats().pending_deletes, 3);
+        assert!(paths.iter().all(|path| path.exists()));
+        paths
+    };
+
+    // 重启后从 MANIFEST 中恢复等待删除的文件
+    let db = Db::open_with_options(data_dir.path(), options.clone()).unwrap();
+    assert_eq!(db.stats().pending_deletes, 3);
+    db.daemon.delete_obsolete_files().unwrap();
+    assert_eq!(db.stats().pending_deletes, 0);
+    assert!(obsolete_paths.iter().all(|path| !path.exists()));
+    for i in 0..10 {
+        assert_eq!(db.get(&key(i)).unwrap(), Some(Bytes::from("v")));
+    }
+    drop(db);
+
+    let db = Db::open_with_options(data_dir.path(), options).unwrap();
+    assert_eq!(db.stats().pending_deletes, 0);
+}
+
+#[test]
+fn test_trash_retention() {
+    INIT.call_once(setup);
+    let data_dir = tempfile::tempdir().unwrap();
+    let trash = Db::path_of_trash(data_dir.path());
+    let options = DbOptions {
+        trash_retention: Some(Duration::from_secs(3600)),
+        ..Default::default()
+    };
+    let key = |i: usize| Bytes::from(format!("k{:03}", i));
+    let trashed = |ext: &str| {
+        std::fs::read_dir(&trash)
+            .map(|entries| {
+                entries
+                    .filter(|entry| entry.as_ref().unwrap().path().extension().unwrap() == ext)
+                    .count()
+            })
+            .unwrap_or(0)
+    };
+
+    {
+        let db = Db::open_with_options(data_dir.path(), options.clone()).unwrap();
+        for round in 0..2 {
+            for i in 0..10 {
+                db.put(key(i), Bytes::from(format!("v{}", round))).unwrap();
+            }
+            db.flush().unwrap();
+        }
+        // 已刷写的 WAL 移到回收站
+        assert_eq!(trashed("LOG"), 2);
+        db.compact(0).unwrap();
+        db.daemon.delete_obsolete_files().unwrap();
+        assert_eq!(db.stats().pending_deletes, 0);
+        assert_eq!(trashed("SST"), 2);
+        // 未到保留时间的文件不会被清理
+        db.daemon.purge_trash().unwrap();
+        assert_eq!(trashed("SST"), 2);
+        for i in 0..10 {
+            assert_eq!(db.get(&key(i)).unwrap(), Some(Bytes::from("v1")));
+        }
+    }
+
+    // 回收站中的文件名带有删除时间，超过保留时间后清理，其他文件保留
+    std::fs::write(trash.join("notes.txt"), "incident").unwrap();
+    let options = DbOptions {
+        trash_retention: Some(Duration::ZERO),
+        ..Default::default()
+    };
+    let db = Db::open_with_options(data_dir.path(), options).unwrap();
+    db.daemon.purge_trash().unwrap();
+    assert_eq!(trashed("SST") + trashed("LOG"), 0);
+    assert_eq!(trashed("txt"), 1);
+    for i in 0..10 {
+        assert_eq!(db.get(&key(i)).unwrap(), Some(Bytes::from("v1")));
+    }
+}
+
+#[test]
+fn test_coalesce_flush_requests() {
+    INIT.call_once(setup);
+    let data_dir = tempfile::tempdir().unwrap();
+    let options = DbOptions {
+        memtable_size_limit: KB,
+        ..Default::default()
+    };
+    // 不启动后台任务，刷写请求一直处于等待状态
+    let db = Db::open_with_options(data_dir.path(), options).unwrap();
+    let value = BytesMut::zeroed(KB).freeze();
+    for i in 0..10 {
+        db.put(Bytes::from(format!("k{}", i)), value.clone())
+            .unwrap();
+    }
+    let stats = db.stats();
+    assert_eq!(stats.flush_queue.pending, 1);
+    assert_eq!(stats.flush_jobs.pending, Some(1));
+    assert_eq!(db.daemon.request_flush(), 1);
+
+    let job = db.daemon.start_flush_job();
+    assert_eq!(job, Some(1));
+    // 任务开始后的请求属于新的任务
+    assert_eq!(db.daemon.request_flush(), 2);
+    assert_eq!(db.daemon.request_flush(), 2);
+    db.daemon.finish_flush_job(job);
+    let status = db.stats().flush_jobs;
+    assert_eq!(status.running, None);
+    assert_eq!(status.pending, Some(2));
+    assert!(status.is_done(1));
+    assert!(!status.is_done(2));
+}
+
+#[test]
+fn test_value_size() {
+    INIT.call_once(setup);
+    let data_dir = tempfile::tempdir().unwrap();
+
+    let db = Db::open_file(data_dir.path()).unwrap();
+    let big_value = Bytes::from(vec![b'v'; MIN_VSST_SIZE as usize + 1]);
+    db.put(Bytes::from("small"), Bytes::from("v1")).unwrap();
+    db.put(Bytes::from("big"), big_value.clone()).unwrap();
+    db.put(Bytes::from("deleted"), Bytes::from("v")).unwrap();
+    db.delete(Bytes::from("deleted")).unwrap();
+
+    let check = |db: &Db| {
+        assert_eq!(db.value_size(&Bytes::from("small")).unwrap(), Some(2));
+        assert_eq!(
+            db.value_size(&Bytes::from("big")).unwrap(),
+            Some(big_value.len() as u64)
+        );
+        assert_eq!(db.value_size(&Bytes::from("deleted")).unwrap(), None);
+        assert_eq!(db.value_size(&Bytes::from("none")).unwrap(), None);
+        assert!(db.contains_key(&Bytes::from("small")).unwrap());
+        assert!(db.contains_key(&Bytes::from("big")).unwrap());
+        assert!(!db.contains_key(&Bytes::from("deleted")).unwrap());
+        assert!(!db.contains_key(&Bytes::from("none")).unwrap());
+    };
+    check(&db);
+    // 刷写后大 value 被分离到 VSST
+    db.flush().unwrap();
+    check(&db);
+    assert_eq!(
+        db.get(&Bytes::from("big")).unwrap(),
+        Some(big_value.clone())
+    );
+}
+
+#[test]
+fn test_write_batch_idempotency_token() {
+    INIT.call_once(setup);
+    let data_dir = tempfile::tempdir().unwrap();
+
+    let batch = |token: u64, value: &'static str| {
+        let mut batch = WriteBatch::with_idempotency_token(token);
+        batch
+            .put(Bytes::from("k1"), Bytes::from(value))
+            .unwrap()
+            .delete(Bytes::from("k2"))
+            .unwrap();
+        batch
+    };
+    {
+        let db = Db::open_file(data_dir.path()).unwrap();
+        db.put(Bytes::from("k2"), Bytes::from("v2")).unwrap();
+        assert!(db.write(batch(1, "v1")).unwrap());
+        // 客户端重试
+        assert!(!db.write(batch(1, "v1-retry")).unwrap());
+        assert_eq!(db.get(&Bytes::from("k1")).unwrap(), Some(Bytes::from("v1")));
+        assert_eq!(db.get(&Bytes::from("k2")).unwrap(), None);
+
+        // WAL 中出现重复的批次时，重放只应用第一次
+        let mut dup = batch(2, "v2").into_entries();
+        dup.insert(0, IdempotencyToken::from(2u64).to_entry());
+        let wal = db.inner.read().wal.clone();
+        wal.write(dup.clone()).unwrap();
+        dup[1] = EntryBuilder::new()
+            .op_type(OpType::Put)
+            .key_value(Bytes::from("k3"), Bytes::from("v3"))
+            .build();
+        wal.write(dup).unwrap();
+        wal.flush().unwrap();
+    }
+    {
+        let db = Db::open_file(data_dir.path()).unwrap();
+        assert_eq!(db.get(&Bytes::from("k1")).unwrap(), Some(Bytes::from("v2")));
+        assert_eq!(db.get(&Bytes::from("k3")).unwrap(), None);
+        assert!(!db.write(batch(1, "v1-retry")).unwrap());
+        assert!(!db.write(batch(2, "v2-retry")).unwrap());
+
+        // 刷写后旧 WAL 被删除，token 延续到新 WAL 中
+        db.flush().unwrap();
+        assert!(db.write(batch(3, "v3")).unwrap());
+    }
+    {
+        let db = Db::open_file(data_dir.path()).unwrap();
+        assert!(!db.write(batch(1, "v1-retry")).unwrap());
+        assert!(!db.write(batch(3, "v3-retry")).unwrap());
+        assert_eq!(db.get(&Bytes::from("k1")).unwrap(), Some(Bytes::from("v3")));
+        // 没有 token 的批次不做去重
+        let mut plain = WriteBatch::new();
+        plain.put(Bytes::from("k4"), Bytes::from("v4")).unwrap();
+        assert!(db.write(plain.clone()).unwrap());
+        assert!(db.write(plain).unwrap());
+        assert_eq!(db.get(&Bytes::from("k4")).unwrap(), Some(Bytes::from("v4")));
+    }
+}
+
+#[test]
+fn test_recover_tables_in_parallel() {
+    INIT.call_once(setup);
+    let data_dir = tempfile::tempdir().unwrap();
+
+    // L0 不超过限制，不触发后台合并，SST 和 VSST 共 2 * L0_SST_NUM_LIMIT 个文件
+    let big_value = Bytes::from(vec![b'v'; MIN_VSST_SIZE as usize + 1]);
+    let levels = {
+        let db = Db::open_file(data_dir.path()).unwrap();
+        for i in 0..(L0_SST_NUM_LIMIT) {
+            db.put(
+                Bytes::from(format!("k{:03}", i)),
+                Bytes::from(format!("v{}", i)),
+            )
+            .unwrap();
+            db.put(Bytes::from(format!("big{:03}", i)), big_value.clone())
+                .unwrap();
+            db.flush().unwrap();
+        }
+        db.level_metadata()
+    };
+
+    let db = Db::open_file(data_dir.path()).unwrap();
+    assert_eq!(db.level_metadata(), levels);
+    for i in 0..(L0_SST_NUM_LIMIT) {
+        assert_eq!(
+            db.get(&Bytes::from(format!("k{:03}", i))).unwrap(),
+            Some(Bytes::from(format!("v{}", i)))
+        );
+        assert_eq!(
+            db.get(&Bytes::from(format!("big{:03}", i))).unwrap(),
+            Some(big_value.clone())
+        );
+    }
+    drop(db);
+
+    // 任一 SST 损坏时打开失败而不是 panic
+    let sst_path = Db::path_of_sst(data_dir.path(), levels[0].files[0].id);
+    std::fs::write(sst_path, b"broken").unwrap();
+    assert!(Db::open_file(data_dir.path()).is_err());
+}
+
+#[test]
+fn test_watch_prefix() {
+    INIT.call_once(setup);
+    let data_dir = tempfile::tempdir().unwrap();
+
+    let db = Db::open_file(data_dir.path()).unwrap();
+    db.put(Bytes::from("user/0"), Bytes::from("before watch"))
+        .unwrap();
+    let users = db.watch_prefix(Bytes::from("user/"));
+    let all = db.watch_prefix(Bytes::new());
+    assert_eq!(db.num_of_watchers(), 2);
+
+    db.put(Bytes::from("user/1"), Bytes::from("v1")).unwrap();
+    db.put(Bytes::from("order/1"), Bytes::from("o1")).unwrap();
+    let mut batch = WriteBatch::new();
+    batch
+        .put(Bytes::from("user/2"), Bytes::from("v2"))
+        .unwrap()
+        .delete(Bytes::from("user/1"))
+        .unwrap();
+    db.write(batch).unwrap();
+
+    let event = |key: &'static str, value: Option<&'static str>| WatchEvent {
+        key: Bytes::from(key),
+        value: value.map(Bytes::from),
+    };
+    assert_eq!(
+        users.try_iter().collect::<Vec<_>>(),
+        vec![
+            event("user/1", Some("v1")),
+            event("user/2", Some("v2")),
+            event("user/1", None),
+        ]
+    );
+    assert_eq!(all.try_iter().count(), 4);
+
+    // 接收端 drop 后取消订阅
+    drop(users);
+    db.delete(Bytes::from("user/2")).unwrap();
+    assert_eq!(db.num_of_watchers(), 1);
+    assert_eq!(all.try_recv().unwrap(), event("user/2", None));
+}
+
+#[test]
+fn test_put_get() {
+    INIT.call_once(setup);
+    let data_dir = tempfile::tempdir().unwrap();
+    let db = Arc::new(Db::open_file(data_dir.path()).unwrap());
+    let key = Bytes::from("counter");
+
+    assert_eq!(db.put_get(key.clone(), Bytes::from("a")).unwrap(), None);
+    assert_eq!(
+        db.put_get(key.clone(), Bytes::from("b")).unwrap(),
+        Some(Bytes::from("a"))
+    );
+    db.delete(key.clone()).unwrap();
+    assert_eq!(db.put_get(key.clone(), Bytes::from("c")).unwrap(), None);
+    db.flush().unwrap();
+    assert_eq!(
+        db.put_get(key.clone(), Bytes::from("d")).unwrap(),
+        Some(Bytes::from("c"))
+    );
+
+    // 并发写入时每个写入的 value 恰好被之后的一次 put_get 返回一次，读取和写入之间没有其他写入
+    let threads: Vec<_> = (0..4)
+        .map(|t| {
+            let db = db.clone();
+            let key = key.clone();
+            thread::spawn(move || {
+                (0..200)
+                    .map(|i| {
+                        let value = Bytes::from(format!("{}-{}", t, i));
+                        db.put_get(key.clone(), value).unwrap().unwrap()
+                    })
+                    .collect::<Vec<_>>()
+            })
+        })
+        .collect();
+    let mut returned: Vec<_> = threads
+        .into_iter()
+        .flat_map(|handle| handle.join().unwrap())
+        .collect();
+    returned.push(db.get(&key).unwrap().unwrap());
+    let mut written = vec![Bytes::from("d")];
+    for t in 0..4 {
+        written.extend((0..200).map(|i| Bytes::from(format!("{}-{}", t, i))));
+    }
+    returned.sort();
+    written.sort();
+    assert_eq!(returned, written);
+}
+
+#[test]
+fn test_write_validator() {
+    INIT.call_once(setup);
+    let data_dir = tempfile::tempdir().unwrap();
+
+    let db = Db::open_file(data_dir.path()).unwrap();
+    db.add_write_validator("tenant", |ops: &[WriteOp]| {
+        match ops.iter().find(|op| !op.key.starts_with(b"tenant-a/")) {
+            Some(op) => Err(Rejection::Unauthorized(format!(
+                "{:?} is not owned by tenant-a",
+                op.key
+            ))),
+            None => Ok(()),
+        }
+    });
+    db.add_write_validator("size", |ops: &[WriteOp]| {
+        for op in ops {
+            let size = op.value.map_or(0, |value| value.len());
+            if size > 8 {
+                return Err(Rejection::TooLarge {
+                    key: Bytes::copy_from_slice(op.key),
+                    size,
+                    limit: 8,
+                });
+            }
+        }
+        Ok(())
+    });
+    assert_eq!(db.num_of_write_validators(), 2);
+
+    db.put(Bytes::from("tenant-a/1"), Bytes::from("v1"))
+        .unwrap();
+    db.delete(Bytes::from("tenant-a/1")).unwrap();
+    let err = db
+        .put(Bytes::from("tenant-b/1"), Bytes::from("v1"))
+        .unwrap_err();
+    let rejected = err.downcast_ref::<WriteRejectedError>().unwrap();
+    assert_eq!(rejected.validator, "tenant");
+    assert!(matches!(rejected.rejection, Rejection::Unauthorized(_)));
+
+    // 批次中任一修改被拒绝时整个批次都不写入
+    let seq = db.last_commit_seq();
+    let mut batch = WriteBatch::new();
+    batch
+        .put(Bytes::from("tenant-a/2"), Bytes::from("v2"))
+        .unwrap();
+    batch
+        .put(Bytes::from("tenant-a/3"), Bytes::from("too large value"))
+        .unwrap();
+    let err = db.write(batch).unwrap_err();
+    assert_eq!(
+        err.downcast_ref::<WriteRejectedError>().unwrap().rejection,
+        Rejection::TooLarge {
+            key: Bytes::from("tenant-a/3"),
+            size: 15,
+            limit: 8,
+        }
+    );
+    assert_eq!(db.last_commit_seq(), seq);
+    assert_eq!(db.get(&Bytes::from("tenant-a/2")).unwrap(), None);
+
+    assert!(db.remove_write_validator("tenant"));
+    db.put(Bytes::from("tenant-b/1"), Bytes::from("v1"))
+        .unwrap();
+    assert_eq!(db.num_of_write_validators(), 1);
+}
+
+#[test]
+fn test_system_keys() {
+    INIT.call_once(setup);
+    let data_dir = tempfile::tempdir().unwrap();
+    let key = system::system_key("test", b"counter");
+    {
+        let db = Db::open_with_options(data_dir.path(), DbOptions::default()).unwrap();
+        db.put(Bytes::from("k"), Bytes::from("v")).unwrap();
+        let mut builder = EntryBuilder::new();
+        builder
+            .op_type(OpType::Put)
+            .key_value(key.clone(), Bytes::from("1"));
+        db.write_system(vec![builder.build()]).unwrap();
+        db.flush().unwrap();
+
+        // 用户的读取、范围查询和计数都看不到系统 key
+        assert_eq!(db.get_system(&key).unwrap(), Some(Bytes::from("1")));
+        assert_eq!(db.get(&key).unwrap(), None);
+        assert!(!db.contains_key(&key).unwrap());
+        assert_eq!(db.multi_get(&[key.clone()]).unwrap(), vec![None]);
+        assert_eq!(db.count(Bound::Unbounded, Bound::Unbounded).unwrap(), 1);
+        let mut iter = db.scan(Bound::Unbounded, Bound::Unbounded).unwrap();
+        assert_eq!(iter.key(), b"k");
+        iter.next().unwrap();
+        assert!(!iter.is_valid());
+        drop(iter);
+
+        let err = db.put(key.clone(), Bytes::from("2")).unwrap_err();
+        assert_eq!(
+            err.downcast_ref::<WriteRejectedError>().unwrap().validator,
+            RESERVED_KEY_VALIDATOR
+        );
+    }
+
+    let db = Db::open_with_options(data_dir.path(), DbOptions::default()).unwrap();
+    assert_eq!(db.get_system(&key).unwrap(), Some(Bytes::from("1")));
+}
+
+#[test]
+fn test_cdc() {
+    INIT.call_once(setup);
+    let data_dir = tempfile::tempdir().unwrap();
+    let options = DbOptions {
+        change_capture: true,
+        ..DbOptions::default()
+    };
+    let open = || Arc::new(Db::open_file_with_options(data_dir.path(), options.clone()).unwrap());
+    let wait_for = |events: &Mutex<Vec<CdcEvent>>, n: usize| {
+        let deadline = Instant::now() + Duration::from_secs(10);
+        while events.lock().len() < n {
+            assert!(
+                Instant::now() < deadline,
+                "timed out waiting for CDC events"
+            );
+            thread::sleep(Duration::from_millis(10));
+        }
+    };
+    let k = |i: u32| Bytes::from(format!("k{}", i));
+
+    // 启动投递之前的写入也会投递，前两次投递失败后重试同一批
+    let events = Arc::new(Mutex::new(vec![]));
+    {
+        let db = open();
+        db.put(k(1), Bytes::from("v1")).unwrap();
+        let mut batch = WriteBatch::new();
+        batch.put(k(2), Bytes::from("v2")).unwrap();
+        batch.delete(k(1)).unwrap();
+        db.write(batch).unwrap();
+
+        let err = db.put(cdc::offset_key(), Bytes::from("v")).unwrap_err();
+        assert_eq!(
+            err.downcast_ref::<WriteRejectedError>().unwrap().validator,
+            RESERVED_KEY_VALIDATOR
+        );
+
+        let attempts = Arc::new(AtomicUsize::new(0));
+        let (_events, _attempts) = (events.clone(), attempts.clone());
+        db.start_cdc(move |batch: &[CdcEvent]| {
+            if _attempts.fetch_add(1, Ordering::SeqCst) < 2 {
+                anyhow::bail!("sink unavailable");
+            }
+            _events.lock().extend_from_slice(batch);
+            Ok(())
+        })
+        .unwrap();
+        assert!(db.start_cdc(|_: &[CdcEvent]| Ok(())).is_err());
+        wait_for(&events, 3);
+        assert!(attempts.load(Ordering::SeqCst) >= 3);
+
+        // 投递进度和变更日志对用户不可见
+        let mut iter = db.scan(Unbounded, Unbounded).unwrap();
+        assert_eq!(iter.key(), k(2));
+        iter.next().unwrap();
+        assert!(!iter.is_valid());
+        drop(iter);
+        assert_eq!(db.count(Unbounded, Unbounded).unwrap(), 1);
+        db.close().unwrap();
+    }
+    let delivered = events.lock().clone();
+    assert_eq!(
+        delivered
+            .iter()
+            .map(|e| (e.key.clone(), e.value.clone()))
+            .collect::<Vec<_>>(),
+        vec![
+            (k(1), Some(Bytes::from("v1"))),
+            (k(2), Some(Bytes::from("v2"))),
+            (k(1), None),
+        ]
+    );
+    assert!(delivered[0].seq < delivered[1].seq);
+    assert_eq!(delivered[1].seq, delivered[2].seq);
+
+    // 重启后不再投递已记录进度的修改，没有启动投递时写入的修改在下次启动后投递
+    {
+        let db = open();
+        db.put(k(3), Bytes::from("v3")).unwrap();
+        db.close().unwrap();
+    }
+    let events = Arc::new(Mutex::new(vec![]));
+    let db = open();
+    let _events = events.clone();
+    db.start_cdc(move |batch: &[CdcEvent]| {
+        _events.lock().extend_from_slice(batch);
+        Ok(())
+    })
+    .unwrap();
+    db.put(k(4), Bytes::from("v4")).unwrap();
+    wait_for(&events, 2);
+    let events = events.lock().clone();
+    assert_eq!(events.len(), 2);
+    assert_eq!(events[0].key, k(3));
+    assert_eq!(events[1].key, k(4));
+    assert!(events[0].seq > delivered[2].seq);
+    assert!(events[1].seq > events[0].seq);
+
+    // 没有开启 change_capture 时不能启动投递
+    db.close().unwrap();
+    drop(db);
+    let db = Arc::new(Db::open_file(data_dir.path()).unwrap());
+    assert!(db.start_cdc(|_: &[CdcEvent]| Ok(())).is_err());
+}
+
+#[test]
+fn test_commit_seq() {
+    INIT.call_once(setup);
+    let data_dir = tempfile::tempdir().unwrap();
+    let last = {
+        let db = Arc::new(Db::open_file(data_dir.path()).unwrap());
+        assert_eq!(db.last_commit_seq(), 0);
+        let s1 = db.put(Bytes::from("k1"), Bytes::from("v1")).unwrap();
+        let s2 = db.delete(Bytes::from("k1")).unwrap();
+        assert!(s1 < s2);
+
+        let mut batch = WriteBatch::with_idempotency_token(1u64);
+        batch.put(Bytes::from("k2"), Bytes::from("v2")).unwrap();
+        let s3 = db
+            .write_batch(batch.clone(), &WriteOptions::default())
+            .unwrap()
+            .unwrap();
+        assert!(s2 < s3);
+        // 重复的批次没有写入，没有序号；空批次返回当前已可见的序号
+        assert_eq!(
+            db.write_batch(batch, &WriteOptions::default()).unwrap(),
+            None
+        );
+        assert_eq!(
+            db.write_batch(WriteBatch::new(), &WriteOptions::default())
+                .unwrap(),
+            Some(s3)
+        );
+        assert_eq!(db.last_commit_seq(), s3);
+        assert!(db.wait_for_seq(s3, Duration::ZERO));
+
+        // 等待方在写入可见后被唤醒，并能读到该写入
+        assert!(!db.wait_for_seq(s3 + 1, Duration::from_millis(10)));
+        let reader = {
+            let db = db.clone();
+            thread::spawn(move || {
+                assert!(db.wait_for_seq(s3 + 1, Duration::from_secs(10)));
+                db.get(&Bytes::from("k3")).unwrap()
+            })
+        };
+        db.flush().unwrap();
+        assert_eq!(
+            db.put(Bytes::from("k3"), Bytes::from("v3")).unwrap(),
+            s3 + 1
+        );
+        assert_eq!(reader.join().unwrap(), Some(Bytes::from("v3")));
+        db.close().unwrap();
+        s3 + 1
+    };
+
+    // 重启后序号继续递增
+    let db = Db::open_file(data_dir.path()).unwrap();
+    assert!(db.last_commit_seq() >= last);
+    assert!(db.put(Bytes::from("k4"), Bytes::from("v4")).unwrap() > last);
+}
+
+#[test]
+fn test_warmup_compact_l0() {
+    INIT.call_once(setup);
+    let data_dir = tempfile::tempdir().unwrap();
+    let num_of_l0 = |db: &Db| db.inner.read().levels[0].len();
+    let key = |i: usize| Bytes::from(format!("k{:03}", i));
+    {
+        // 模拟来不及合并，留下大量 L0 文件
+        let options = DbOptions {
+            l0_sst_num_limit: 100,
+            ..Default::default()
+        };
+        let db = Db::open_file_with_options(data_dir.path(), options).unwrap();
+        for round in 0..(L0_SST_NUM_LIMIT * 3) {
+            for i in round..(round + 10) {
+                db.put(key(i), Bytes::from(format!("v{}", round))).unwrap();
+            }
+            db.flush().unwrap();
+        }
+        assert_eq!(num_of_l0(&db), L0_SST_NUM_LIMIT * 3);
+        db.close().unwrap();
+    }
+    {
+        let db = Db::open_file(data_dir.path()).unwrap();
+        assert_eq!(num_of_l0(&db), L0_SST_NUM_LIMIT * 3);
+        db.close().unwrap();
+    }
+
+    let options = DbOptions {
+        warmup: true,
+        ..Default::default()
+    };
+    let db = Db::open_file_with_options(data_dir.path(), options).unwrap();
+    assert!(num_of_l0(&db) <= L0_SST_NUM_LIMIT);
+    let last_round = L0_SST_NUM_LIMIT * 3 - 1;
+    for i in 0..(last_round + 10) {
+        let round = i.min(last_round);
+        assert_eq!(
+            db.get(&key(i)).unwrap(),
+            Some(Bytes::from(format!("v{}", round)))
+        );
+    }
+}
+
+#[test]
+fn test_vsst_filter_fail_fast() {
+    INIT.call_once(setup);
+    let data_dir = tempfile::tempdir().unwrap();
+
+    let db = Db::open_file(data_dir.path()).unwrap();
+    let big_value = Bytes::from(vec![b'v'; MIN_VSST_SIZE as usize + 1]);
+    for i in 0..50 {
+        db.put(Bytes::from(format!("k{:03}", i * 2)), big_value.clone())
+            .unwrap();
+    }
+    db.flush().unwrap();
+
+    let snapshot = db.inner.read().clone();
+    let vsst = snapshot
+        .vssts
+        .read()
+        .values()
+        .find(|vsst| vsst.num_of_pairs() > 0)
+        .unwrap()
+        .clone();
+    let separated = Entry::separated_value(vsst.id(), big_value.len() as u64);
+    let read = |key: String| {
+        Db::read_separated_value(
+            &snapshot,
+            &Bytes::from(key),
+            &separated,
+            BlockReadOptions::default(),
+        )
+    };
+    assert_eq!(read("k010".to_string()).unwrap(), Some(big_value));
+
+    // 不在 VSST 中的 key 由 bloom filter 直接判定，几乎不读取 data block
+    let loads = db.vsst_cache.loads();
+    for i in 0..50 {
+        let err = read(format!("k{:03}", i * 2 + 1)).unwrap_err();
+        assert!(err.to_string().contains("has no value"));
+    }
+    assert!(db.vsst_cache.loads() - loads < 10);
+}
+
+#[test]
+fn test_cumulative_stats_persist() {
+    INIT.call_once(setup);
+    let data_dir = tempfile::tempdir().unwrap();
+    let big_value = Bytes::from(vec![b'v'; MIN_VSST_SIZE as usize + 1]);
+
+    let before = {
+        let db = Db::open_file(data_dir.path()).unwrap();
+        for round in 0..2 {
+            db.put(Bytes::from("big"), big_value.clone()).unwrap();
+            db.put(Bytes::from(format!("k{}", round)), Bytes::from("v"))
+                .unwrap();
+            db.flush().unwrap();
+        }
+        db.compact(0).unwrap();
+        let stats = db.stats().cumulative;
+        assert_eq!(
+            stats.bytes_written,
+            2 * (3 + big_value.len() as u64 + 2 + 1)
+        );
+        assert_eq!(stats.flushes, 2);
+        assert!(stats.flush_bytes_written > 2 * big_value.len() as u64);
+        assert_eq!(stats.compactions, 1);
+        assert!(stats.compaction_bytes_read > 0 && stats.compaction_bytes_written > 0);
+        // 第一轮的大 value 被覆盖，所在 VSST 被回收
+        assert!(stats.gc_vssts >= 1 && stats.gc_bytes > big_value.len() as u64);
+        db.close().unwrap();
+        stats
+    };
+
+    // 重启后从记录的值继续累加
+    let db = Db::open_file(data_dir.path()).unwrap();
+    assert_eq!(db.stats().cumulative, before);
+    db.put(Bytes::from("k"), Bytes::from("v")).unwrap();
+    assert_eq!(
+        db.stats().cumulative.bytes_written,
+        before.bytes_written + 2
+    );
+}
+
+#[test]
+fn test_external_compaction() {
+    INIT.call_once(setup);
+    let data_dir = tempfile::tempdir().unwrap();
+    let job_dir = tempfile::tempdir().unwrap();
+    let big_value = |round: usize| {
+        let mut value = vec![b'v'; MIN_VSST_SIZE as usize + 1];
+        value[0] = b'0' + round as u8;
+        Bytes::from(value)
+    };
+    let key = |i: usize| Bytes::from(format!("k{:03}", i));
+
+    let db = Db::open_file(data_dir.path()).unwrap();
+    for round in 0..3 {
+        for i in (round * 10)..50 {
+            db.put(key(i), Bytes::from(format!("v{}", round))).unwrap();
+        }
+        db.put(Bytes::from("big"), big_value(round)).unwrap();
+        db.delete(key(round)).unwrap();
+        db.flush().unwrap();
+    }
+    let vssts_before = db.inner.read().vssts.read().len();
+
+    let job = db.export_compaction(0, job_dir.path()).unwrap().unwrap();
+    assert_eq!(job.level, 0);
+    assert_eq!(job.inputs.len(), 3);
+    assert!(!job.is_finished());
+    // 没有执行的任务不能导入
+    assert!(db.import_compaction(job_dir.path()).is_err());
+
+    let job = admin::run_compaction_job(job_dir.path()).unwrap();
+    assert_eq!(CompactionJob::load(job_dir.path()).unwrap(), job);
+    assert!(!job.outputs.as_ref().unwrap().is_empty());
+    // 被覆盖的两个大 value 所在 VSST 的引用计数减少
+    assert_eq!(job.vsst_rc_delta.iter().map(|(_, d)| d).sum::<i32>(), -2);
+
+    db.import_compaction(job_dir.path()).unwrap();
+    let level_files = db.stats().level_files;
+    assert_eq!(level_files[0], 0);
+    assert_eq!(level_files[1], job.outputs.as_ref().unwrap().len());
+    assert!(db.inner.read().vssts.read().len() < vssts_before);
+    let check = |db: &Db| {
+        for i in 0..50 {
+            let expected = match i {
+                0..=2 => None,
+                _ => Some(Bytes::from(format!("v{}", (i / 10).min(2)))),
+            };
+            assert_eq!(db.get(&key(i)).unwrap(), expected);
+        }
+        assert_eq!(db.get(&Bytes::from("big")).unwrap(), Some(big_value(2)));
+    };
+    check(&db);
+    // 输入已被替换，重复导入失败
+    assert!(db.import_compaction(job_dir.path()).is_err());
+    db.close().unwrap();
+    drop(db);
+
+    let db = Db::open_file(data_dir.path()).unwrap();
+    assert_eq!(db.stats().level_files[0], 0);
+    check(&db);
+}
+
+#[test]
+fn test_skip_unknown_manifest_items() {
+    INIT.call_once(setup);
+    let data_dir = tempfile::tempdir().unwrap();
+    {
+        let db = Db::open_file(data_dir.path()).unwrap();
+        db.put(Bytes::from("k1"), Bytes::from("v1")).unwrap();
+        db.flush().unwrap();
+        db.close().unwrap();
+    }
+    {
+        let mut manifest = Manifest::open(Db::path_of_manifest(data_dir.path(), 1)).unwrap();
+        let mut r = RecordBuilder::new();
+        r.add(ManifestItem::Unknown(100, 8));
+        manifest.add(&r.build()).unwrap();
+    }
+
+    assert!(Db::open_file(data_dir.path()).is_err());
+    let options = DbOptions {
+        skip_unknown_manifest_items: true,
+        ..Default::default()
+    };
+    let db = Db::open_file_with_options(data_dir.path(), options.clone()).unwrap();
+    assert_eq!(db.get(&Bytes::from("k1")).unwrap(), Some(Bytes::from("v1")));
+    db.put(Bytes::from("k2"), Bytes::from("v2")).unwrap();
+    db.flush().unwrap();
+    db.close().unwrap();
+    drop(db);
+
+    // 未知变更原样保留在 MANIFEST 中，之后仍需以兼容模式打开
+    assert!(Db::open_file(data_dir.path()).is_err());
+    let db = Db::open_file_with_options(data_dir.path(), options).unwrap();
+    assert_eq!(db.get(&Bytes::from("k1")).unwrap(), Some(Bytes::from("v1")));
+    assert_eq!(db.get(&Bytes::from("k2")).unwrap(), Some(Bytes::from("v2")));
+}
+
+#[test]
+fn test_vsst_ref_count_baseline() {
+    INIT.call_once(setup);
+    let data_dir = tempfile::tempdir().unwrap();
+    let manifest_path = Db::path_of_manifest(data_dir.path(), 1);
+    let replay =
+        || ManifestState::replay(Arc::new(Manifest::open(&manifest_path).unwrap())).unwrap();
+
+    let big_value = Bytes::from(vec![b'v'; MIN_VSST_SIZE as usize + 1]);
+    {
+        let db = Db::open_file(data_dir.path()).unwrap();
+        for i in 0..3 {
+            db.put(Bytes::from(format!("k{}", i)), big_value.clone())
+                .unwrap();
+        }
+        db.put(Bytes::from("small"), Bytes::from("v")).unwrap();
+        db.flush().unwrap();
+    }
+    // 刷写时写入初始引用计数
+    let mut state = replay();
+    assert_eq!(state.vsst_set.len(), 1);
+    let vsst_id = *state.vsst_set.iter().next().unwrap();
+    assert_eq!(state.vsst_rc.get(&vsst_id), Some(&3));
+
+    // 缺少引用计数的 MANIFEST 打开时从 SST 中重建
+    state.vsst_rc.clear();
+    std::fs::remove_file(&manifest_path).unwrap();
+    let mut manifest = Manifest::open(&manifest_path).unwrap();
+    let mut r = RecordBuilder::new();
+    state.to_items(1).into_iter().for_each(|item| r.add(item));
+    manifest.add(&r.build()).unwrap();
+    drop(manifest);
+    {
+        let db = Db::open_file(data_dir.path()).unwrap();
+        assert_eq!(db.inner.read().vsst_rc.read().get(&vsst_id), Some(&3));
+        assert_eq!(db.get(&Bytes::from("k1")).unwrap(), Some(big_value.clone()));
+    }
+    assert_eq!(replay().vsst_rc.get(&vsst_id), Some(&3));
+}
+
+#[test]
+fn test_value_separation_threshold() {
+    INIT.call_once(setup);
+    let data_dir = tempfile::tempdir().unwrap();
+    let value = |len: usize| Bytes::from(vec![b'v'; len]);
+    let vsst_rc = |db: &Db| {
+        let mut rc: Vec<_> = db
+            .inner
+            .read()
+            .vsst_rc
+            .read()
+            .iter()
+            .map(|(id, cnt)| (*id, *cnt))
+            .collect();
+        rc.sort();
+        rc
+    };
+    let expected = [
+        ("a", value(5 * KB)),
+        ("b", value(10 * KB)),
+        ("c", value(6 * KB)),
+        ("d", value(2 * KB)),
+    ];
+    // L0 的 SST 互不重叠时每次只合并一个
+    let compact_level = |db: &Db, level: usize| {
+        while !db.level_metadata()[level].files.is_empty() {
+            db.compact(level as u32).unwrap();
+        }
+    };
+    let check = |db: &Db, n: usize| {
+        for (key, value) in &expected[..n] {
+            assert_eq!(db.get(&Bytes::from(*key)).unwrap(), Some(value.clone()));
+        }
+    };
+
+    {
+        let db = Db::open_with_options(data_dir.path(), DbOptions::default()).unwrap();
+        assert_eq!(db.value_separation_threshold(), Some(MIN_VSST_SIZE));
+        db.put(Bytes::from("a"), expected[0].1.clone()).unwrap();
+        db.put(Bytes::from("b"), expected[1].1.clone()).unwrap();
+        db.flush().unwrap();
+        let first_vsst = vsst_rc(&db)[0].0;
+        assert_eq!(vsst_rc(&db), vec![(first_vsst, 2)]);
+
+        // 提高阈值后刷写的 value 不再分离，合并时读回不超过阈值的已分离 value
+        db.set_value_separation_threshold(Some(8 * KB as u64));
+        db.put(Bytes::from("c"), expected[2].1.clone()).unwrap();
+        db.flush().unwrap();
+        assert_eq!(vsst_rc(&db), vec![(first_vsst, 2)]);
+        compact_level(&db, 0);
+        // 只剩 b 引用 VSST，合并时它可能被迁移到新 VSST
+        let rc = vsst_rc(&db);
+        assert_eq!(rc.iter().map(|(_, cnt)| cnt).sum::<u32>(), 1);
+        check(&db, 3);
+
+        // 降低阈值后新刷写的 value 按新阈值分离，已在 SST 中的 value 不受影响
+        db.set_value_separation_threshold(Some(KB as u64));
+        db.put(Bytes::from("d"), expected[3].1.clone()).unwrap();
+        db.flush().unwrap();
+        assert_eq!(vsst_rc(&db).len(), rc.len() + 1);
+
+        // 关闭 KV 分离后合并读回所有 value，引用计数归零的 VSST 被删除
+        db.set_value_separation_threshold(None);
+        compact_level(&db, 0);
+        compact_level(&db, 1);
+        assert_eq!(vsst_rc(&db), vec![]);
+        assert!(db.inner.read().vssts.read().is_empty());
+        check(&db, 4);
+    }
+
+    let db = Db::open_with_options(data_dir.path(), DbOptions::default()).unwrap();
+    assert_eq!(db.value_separation_threshold(), Some(MIN_VSST_SIZE));
+    assert_eq!(vsst_rc(&db), vec![]);
+    check(&db, 4);
+}
+
+#[test]
+fn test_count() {
+    INIT.call_once(setup);
+    let data_dir = tempfile::tempdir().unwrap();
+
+    let db = Db::open_file(data_dir.path()).unwrap();
+    let key = |i: usize| Bytes::from(format!("k{:02}", i));
+    let big_value = Bytes::from(vec![b'v'; MIN_VSST_SIZE as usize + 1]);
+    for i in 0..30 {
+        let value = if i % 3 == 0 {
+            big_value.clone()
+        } else {
+            Bytes::from("v")
+        };
+        db.put(key(i), value).unwrap();
+    }
+    db.flush().unwrap();
+    // SST 中的 key 在 memtable 中被删除或覆盖
+    for i in (0..30).step_by(4) {
+        db.delete(key(i)).unwrap();
+    }
+    for i in 20..40 {
+        db.put(key(i), Bytes::from("v2")).unwrap();
+    }
+    db.delete(key(35)).unwrap();
+
+    let scan_count = |lower: Bound<Bytes>, upper: Bound<Bytes>| {
+        let mut iter = db.scan(lower, upper).unwrap();
+        let mut count = 0;
+        while iter.is_valid() {
+            count += 1;
+            iter.next().unwrap();
+        }
+        count
+    };
+    assert_eq!(db.count(Unbounded, Excluded(key(20))).unwrap(), 20 - 5);
+    for (lower, upper) in [
+        (Unbounded, Unbounded),
+        (Included(key(5)), Included(key(25))),
+        (Excluded(key(8)), Excluded(key(36))),
+        (Included(key(31)), Unbounded),
+        (Unbounded, Excluded(key(10))),
+    ] {
+        assert_eq!(
+            db.count(lower.clone(), upper.clone()).unwrap(),
+            scan_count(lower, upper)
+        );
+    }
+    assert_eq!(db.count(Included(key(50)), Unbounded).unwrap(), 0);
+    assert_eq!(db.count(Excluded(key(8)), Included(key(9))).unwrap(), 1);
+}
+
+#[test]
+fn test_scan_memtables() {
+    INIT.call_once(setup);
+    let data_dir = tempfile::tempdir().unwrap();
+
+    let db = Db::open_file(data_dir.path()).unwrap();
+    let key = |i: usize| Bytes::from(format!("k{:02}", i));
+    for i in 0..10 {
+        db.put(key(i), Bytes::from("v1")).unwrap();
+    }
+    db.flush().unwrap();
+    for i in 5..15 {
+        db.put(key(i), Bytes::from("v2")).unwrap();
+    }
+    db.delete(key(8)).unwrap();
+
+    let collect = |mut iter: FusedIterator<DbIterator>| {
+        let mut pairs = vec![];
+        while iter.is_valid() {
+            pairs.push((
+                Bytes::copy_from_slice(iter.key()),
+                Bytes::copy_from_slice(iter.value()),
+            ));
+            iter.next().unwrap();
+        }
+        pairs
+    };
+    // 已刷写到 SST 的 key 不在结果中，memtable 中删除的 key 被隐藏
+    let expected: Vec<_> = (5..15)
+        .filter(|i| *i != 8)
+        .map(|i| (key(i), Bytes::from("v2")))
+        .collect();
+    assert_eq!(
+        collect(db.scan_memtables(Unbounded, Unbounded).unwrap()),
+        expected
+    );
+    assert_eq!(
+        collect(
+            db.scan_memtables(Excluded(key(5)), Included(key(9)))
+                .unwrap()
+        ),
+        expected[1..4].to_vec()
+    );
+    assert_eq!(collect(db.scan(Unbounded, Unbounded).unwrap()).len(), 14);
+    // 包含的上界 key 在 memtable 中的新版本不能被 SST 中的旧版本取代
+    assert_eq!(
+        collect(db.scan(Included(key(9)), Included(key(9))).unwrap()),
+        vec![(key(9), Bytes::from("v2"))]
+    );
+
+    db.flush().unwrap();
+    assert!(!db.scan_memtables(Unbounded, Unbounded).unwrap().is_valid());
+}
+
+#[test]
+fn test_delete_in_memtable() {
+    INIT.call_once(setup);
+    let data_dir = tempfile::tempdir().unwrap();
+
+    let db = Db::open_file(data_dir.path()).unwrap();
+    let key = |i: usize| Bytes::from(format!("k{:03}", i));
+    // 删除标记和被删除的值位于同一个 memtable 中
+    for i in 0..100 {
+        db.put(key(i), Bytes::from("v")).unwrap();
+    }
+    for i in (0..100).step_by(3) {
+        db.delete(key(i)).unwrap();
+    }
+
+    let mut iter = db.scan(Unbounded, Unbounded).unwrap();
+    let mut keys = vec![];
+    while iter.is_valid() {
+        keys.push(Bytes::copy_from_slice(iter.key()));
+        iter.next().unwrap();
+    }
+    let expected: Vec<_> = (0..100).filter(|i| i % 3 != 0).map(key).collect();
+    assert_eq!(keys, expected);
+    assert_eq!(db.count(Unbounded, Unbounded).unwrap(), expected.len());
+}
+
+#[test]
+fn test_disable_wal() {
+    INIT.call_once(setup);
+    let data_dir = tempfile::tempdir().unwrap();
+
+    let no_wal = WriteOptions {
+        disable_wal: true,
+        ..Default::default()
+    };
+    {
+        let db = Db::open_file(data_dir.path()).unwrap();
+        db.put_with_options(Bytes::from("k1"), Bytes::from("v1"), &no_wal)
+            .unwrap();
+        db.put(Bytes::from("k2"), Bytes::from("v2")).unwrap();
+        assert_eq!(db.get(&Bytes::from("k1")).unwrap(), Some(Bytes::from("v1")));
+    }
+    {
+        // 未刷写的数据不在 WAL 中，重启后丢失
+        let db = Db::open_file(data_dir.path()).unwrap();
+        assert_eq!(db.get(&Bytes::from("k1")).unwrap(), None);
+        assert_eq!(db.get(&Bytes::from("k2")).unwrap(), Some(Bytes::from("v2")));
+
+        db.put_with_options(Bytes::from("k3"), Bytes::from("v3"), &no_wal)
+            .unwrap();
+        db.delete_with_options(Bytes::from("k2"), &no_wal).unwrap();
+        db.flush().unwrap();
+    }
+    {
+        let db = Db::open_file(data_dir.path()).unwrap();
+        assert_eq!(db.get(&Bytes::from("k2")).unwrap(), None);
+        assert_eq!(db.get(&Bytes::from("k3")).unwrap(), Some(Bytes::from("v3")));
+    }
+}
+
+#[test]
+fn test_write_options() {
+    INIT.call_once(setup);
+    let data_dir = tempfile::tempdir().unwrap();
+
+    let no_sync = WriteOptions {
+        sync: false,
+        ..Default::default()
+    };
+    let low_priority = WriteOptions {
+        low_priority: true,
+        ..Default::default()
+    };
+    {
+        let db = Db::open_file(data_dir.path()).unwrap();
+        db.put_with_options(Bytes::from("k1"), Bytes::from("v1"), &no_sync)
+            .unwrap();
+        db.put_with_options(Bytes::from("k2"), Bytes::from("v2"), &low_priority)
+            .unwrap();
+        let mut batch = WriteBatch::new();
+        batch
+            .put(Bytes::from("k3"), Bytes::from("v3"))
+            .unwrap()
+            .delete(Bytes::from("k2"))
+            .unwrap();
+        assert!(db.write_with_options(batch, &no_sync).unwrap());
+        assert_eq!(db.get(&Bytes::from("k1")).unwrap(), Some(Bytes::from("v1")));
+        db.close().unwrap();
+    }
+    {
+        // 未同步的写入在正常关闭时随 WAL 一起刷写，重启后仍然存在
+        let db = Db::open_file(data_dir.path()).unwrap();
+        assert_eq!(db.get(&Bytes::from("k1")).unwrap(), Some(Bytes::from("v1")));
+        assert_eq!(db.get(&Bytes::from("k2")).unwrap(), None);
+        assert_eq!(db.get(&Bytes::from("k3")).unwrap(), Some(Bytes::from("v3")));
+    }
+}
+
+#[test]
+fn test_write_stall_stats() {
+    INIT.call_once(setup);
+    let data_dir = tempfile::tempdir().unwrap();
+    {
+        let options = DbOptions {
+            l0_sst_num_limit: 100,
+            ..Default::default()
+        };
+        let db = Db::open_file_with_options(data_dir.path(), options).unwrap();
+        for i in 0..=L0_SST_NUM_LIMIT {
+            db.put(Bytes::from(format!("k{}", i)), Bytes::from("v"))
+                .unwrap();
+            db.flush().unwrap();
+        }
+        db.close().unwrap();
+    }
+
+    // 重启后 L0 超过阈值，低优先级写入被限速并记录原因
+    let db = Db::open_file(data_dir.path()).unwrap();
+    db.put(Bytes::from("k"), Bytes::from("v")).unwrap();
+    assert_eq!(db.stats().write_stalls, WriteStallStats::default());
+    let low_priority = WriteOptions {
+        low_priority: true,
+        ..Default::default()
+    };
+    db.put_with_options(Bytes::from("k"), Bytes::from("v2"), &low_priority)
+        .unwrap();
+    let stalls = db.stats().write_stalls;
+    assert_eq!(stalls.stalls, 1);
+    assert_eq!(stalls.l0_files, 1);
+    assert_eq!(stalls.pending_memtables, 0);
+    assert_eq!(stalls.compaction_debt, 0);
+    assert_eq!(stalls.delay, LOW_PRIORITY_WRITE_DELAY);
+}
+
+#[test]
+fn test_open_registry() {
+    INIT.call_once(setup);
+    let data_dir = tempfile::tempdir().unwrap();
+    {
+        let _db = Db::open_file(data_dir.path()).unwrap();
+        let err = Db::open_file(data_dir.path()).unwrap_err();
+        let err = err.downcast_ref::<AlreadyOpenError>().unwrap();
+        assert_eq!(err.path, data_dir.path().canonicalize().unwrap());
+        // 不同写法的同一路径也能识别
+        assert!(Db::open_file(data_dir.path().join(".")).is_err());
+        assert!(Db::open_shared(data_dir.path(), DbOptions::default()).is_err());
+    }
+
+    // 关闭后可以重新打开，共享打开返回同一个句柄
+    let db = Db::open_shared(data_dir.path(), DbOptions::default()).unwrap();
+    db.put(Bytes::from("k"), Bytes::from("v")).unwrap();
+    let other = Db::open_shared(data_dir.path().join("."), DbOptions::default()).unwrap();
+    assert!(Arc::ptr_eq(&db, &other));
+    assert!(Db::open_file(data_dir.path()).is_err());
+    drop(db);
+    drop(other);
+    let db = Db::open_file(data_dir.path()).unwrap();
+    assert_eq!(db.get(&Bytes::from("k")).unwrap(), Some(Bytes::from("v")));
+}
+
+#[test]
+fn test_cache_capacity_eviction() {
+    INIT.call_once(setup);
+    let data_dir = tempfile::tempdir().unwrap();
+    let big_value = Bytes::from(vec![b'v'; MIN_VSST_SIZE as usize + 1]);
+    let key = |round: usize, i: usize| Bytes::from(format!("r{}k{:02}", round, i));
+    let total_size = |db: &Db| DbDaemon::total_size(&db.inner.read());
+
+    let rounds = 6;
+    let write_round = |db: &Db, round: usize| {
+        for i in 0..20 {
+            db.put(key(round, i), big_value.clone()).unwrap();
+        }
+        db.flush().unwrap();
+    };
+    // 先不限制容量写入一轮，得到每轮占用的空间
+    let round_size = {
+        let db = Db::open_file(data_dir.path()).unwrap();
+        write_round(&db, 0);
+        let size = total_size(&db);
+        db.close().unwrap();
+        size
+    };
+
+    let capacity = round_size * 3;
+    let options = DbOptions {
+        l0_sst_num_limit: 100,
+        cache_capacity: Some(capacity),
+        ..Default::default()
+    };
+    let db = Db::open_file_with_options(data_dir.path(), options).unwrap();
+    for round in 1..rounds {
+        write_round(&db, round);
+    }
+    db.compact(SST_LEVEL_LIMIT - 1).unwrap();
+    assert!(total_size(&db) <= capacity);
+
+    // 最早的几轮被淘汰，最近一轮保留
+    assert_eq!(db.get(&key(0, 0)).unwrap(), None);
+    assert_eq!(db.get(&key(1, 19)).unwrap(), None);
+    for i in 0..20 {
+        assert_eq!(
+            db.get(&key(rounds - 1, i)).unwrap(),
+            Some(big_value.clone())
+        );
+    }
+    assert!(db.stats().cumulative.gc_vssts >= 2);
+    drop(db);
+
+    // 淘汰写入了 MANIFEST，重启后不会恢复
+    let db = Db::open_file(data_dir.path()).unwrap();
+    assert_eq!(db.get(&key(0, 0)).unwrap(), None);
+    assert_eq!(
+        db.get(&key(rounds - 1, 0)).unwrap(),
+        Some(big_value.clone())
+    );
+}
+
+#[test]
+fn test_read_options() {
+    INIT.call_once(setup);
+    let data_dir = tempfile::tempdir().unwrap();
+    let db = Db::open_file(data_dir.path()).unwrap();
+
+    let key = |i: usize| Bytes::from(format!("key{:03}", i));
+    for i in 0..100 {
+        db.put(key(i), Bytes::from(format!("value{}", i))).unwrap();
+    }
+    db.flush().unwrap();
+    db.put(key(100), Bytes::from("value100")).unwrap();
+    let snapshot = db.snapshot();
+    // 快照之后的写入先进入快照持有的 memtable，刷写后进入新的 memtable，两者都不可见
+    db.put(key(100), Bytes::from("new")).unwrap();
+    db.flush().unwrap();
+    db.put(key(0), Bytes::from("new")).unwrap();
+
+    let options = ReadOptions {
+        fill_cache: false,
+        verify_checksums: true,
+        checksum_sampling: None,
+        snapshot: None,
+        deadline: None,
+        isolation: ScanIsolation::Snapshot,
+    };
+    assert_eq!(
+        db.get_with_options(&key(0), &options).unwrap(),
+        Some(Bytes::from("new"))
+    );
+    assert_eq!(
+        db.get_with_options(&key(50), &options).unwrap(),
+        Some(Bytes::from("value50"))
+    );
+    let mut iter = db
+        .scan_with_options(Included(key(10)), Excluded(key(20)), &options)
+        .unwrap();
+    for i in 10..20 {
+        assert_eq!(iter.key(), &key(i)[..]);
+        iter.next().unwrap();
+    }
+    assert!(!iter.is_valid());
+    assert!(db.stats().verified_blocks > 0);
+    // 不填充缓存时读取不会把块放入块缓存
+    let sst = db.inner.read().levels[0][0].clone();
+    assert!(sst.cached_key_ranges().is_empty());
+    db.get(&key(50)).unwrap();
+    assert_eq!(sst.cached_key_ranges().len(), 1);
+
+    let options = ReadOptions {
+        snapshot: Some(snapshot),
+        ..Default::default()
+    };
+    assert_eq!(
+        db.get_with_options(&key(0), &options).unwrap(),
+        Some(Bytes::from("value0"))
+    );
+    assert_eq!(
+        db.get_with_options(&key(100), &options).unwrap(),
+        Some(Bytes::from("value100"))
+    );
+    assert_eq!(
+        db.multi_get_with_options(&[key(0), key(100)], &options)
+            .unwrap(),
+        vec![Some(Bytes::from("value0")), Some(Bytes::from("value100"))]
+    );
+    let mut iter = db
+        .scan_with_options(Unbounded, Included(key(0)), &options)
+        .unwrap();
+    assert_eq!(iter.key(), &key(0)[..]);
+    assert_eq!(iter.value(), b"value0");
+    iter.next().unwrap();
+    assert!(!iter.is_valid());
+}
+
+#[test]
+fn test_export_snapshot() {
+    let data_dir = tempfile::tempdir().unwrap();
+    let export_dir = tempfile::tempdir().unwrap();
+    let export_path = export_dir.path().join("export");
+    let db = Db::open_file(data_dir.path()).unwrap();
+    let key = |i: usize| Bytes::from(format!("key{:04}", i));
+    let value = |i: usize| match i % 10 {
+        0 => Bytes::from(vec![b'v'; MIN_VSST_SIZE as usize + 1]),
+        _ => Bytes::from(format!("value{}", i)),
+    };
+    for i in 0..1000 {
+        db.put(key(i), Bytes::from("old")).unwrap();
+    }
+    db.flush().unwrap();
+    for i in 0..1000 {
+        db.put(key(i), value(i)).unwrap();
+    }
+    for i in (0..1000).step_by(3) {
+        db.delete(key(i)).unwrap();
+    }
+    db.flush().unwrap();
+    db.put(key(1), Bytes::from("in memtable")).unwrap();
+    let snapshot = db.snapshot();
+    // 快照与之后的写入不共享 memtable
+    db.flush().unwrap();
+    db.put(key(2), Bytes::from("after snapshot")).unwrap();
+
+    let collect = |db: &Db, snapshot: Option<&Snapshot>| {
+        let mut iter = match snapshot {
+            Some(snapshot) => snapshot.scan(Unbounded, Unbounded).unwrap(),
+            None => db.scan(Unbounded, Unbounded).unwrap(),
+        };
+        let mut entries = vec![];
+        while iter.is_valid() {
+            entries.push((
+                Bytes::copy_from_slice(iter.key()),
+                Bytes::copy_from_slice(iter.value()),
+            ));
+            iter.next().unwrap();
+        }
+        entries
+    };
+    let expected = collect(&db, Some(&snapshot));
+    let report = db.export_snapshot(&snapshot, &export_path).unwrap();
+    assert_eq!(report.keys, expected.len() as u64);
+    assert!(!report.vssts.is_empty());
+    // 目标目录中已有数据库时不会覆盖
+    assert!(db.export_snapshot(&snapshot, &export_path).is_err());
+    drop(snapshot);
+
+    let exported = Db::open_file(&export_path).unwrap();
+    assert_eq!(collect(&exported, None), expected);
+    assert_eq!(exported.get(&key(2)).unwrap(), Some(Bytes::from("value2")));
+    // 只有最后一层，没有删除标记和旧版本
+    let inner = exported.inner.read().clone();
+    let levels = &inner.levels;
+    assert!(levels[..SST_LEVEL_LIMIT as usize - 1]
+        .iter()
+        .all(|level| level.is_empty()));
+    let pairs: usize = levels[SST_LEVEL_LIMIT as usize - 1]
+        .iter()
+        .map(|sst| sst.num_of_pairs())
+        .sum();
+    assert_eq!(pairs, expected.len());
+    drop(inner);
+
+    // 导出的数据库可以继续写入，重新打开后数据仍在
+    exported.put(key(0), Bytes::from("new")).unwrap();
+    exported.close().unwrap();
+    drop(exported);
+    let exported = Db::open_file(&export_path).unwrap();
+    assert_eq!(exported.get(&key(0)).unwrap(), Some(Bytes::from("new")));
+    assert_eq!(exported.get(&key(10)).unwrap(), Some(value(10)));
+}
+
+#[test]
+fn test_scan_deadline() {
+    let data_dir = tempfile::tempdir().unwrap();
+    let db = Db::open_with_options(data_dir.path(), DbOptions::default()).unwrap();
+    let key = |i: usize| Bytes::from(format!("key{:03}", i));
+    for i in 0..100 {
+        db.put(key(i), Bytes::from("value")).unwrap();
+    }
+    db.flush().unwrap();
+
+    // 已过期的截止时间在创建迭代器时就返回错误
+    let options = ReadOptions {
+        deadline: Some(Instant::now()),
+        ..Default::default()
+    };
+    let err = db
+        .scan_with_options(Unbounded, Unbounded, &options)
+        .err()
+        .unwrap();
+    assert_eq!(
+        err.downcast_ref::<ScanTimeoutError>(),
+        Some(&ScanTimeoutError { last_key: None })
+    );
+
+    let options = ReadOptions {
+        deadline: Some(Instant::now() + Duration::from_millis(200)),
+        ..Default::default()
+    };
+    let mut iter = db
+        .scan_with_options(Unbounded, Unbounded, &options)
+        .unwrap();
+    for i in 0..10 {
+        assert_eq!(iter.key(), &key(i)[..]);
+        iter.next().unwrap();
+    }
+    thread::sleep(Duration::from_millis(250));
+    let err = iter.next().unwrap_err();
+    let timeout = err.downcast_ref::<ScanTimeoutError>().unwrap();
+    assert_eq!(timeout.last_key, Some(key(10)));
+    assert!(!iter.is_valid());
+    assert!(iter.inner().timed_out());
+    // 超时后的 `next` 不再报错
+    iter.next().unwrap();
+
+    // 从最后返回的 key 之后继续读完剩余的部分
+    let mut iter = db
+        .scan(Excluded(timeout.last_key.clone().unwrap()), Unbounded)
+        .unwrap();
+    for i in 11..100 {
+        assert_eq!(iter.key(), &key(i)[..]);
+        iter.next().unwrap();
+    }
+    assert!(!iter.is_valid());
+    assert!(!iter.inner().timed_out());
+}
+
+#[test]
+fn test_scan_during_rotate() {
+    INIT.call_once(setup);
+    let data_dir = tempfile::tempdir().unwrap();
+    let db = Arc::new(Db::open_file(data_dir.path()).unwrap());
+    let key = |i: usize| Bytes::from(format!("k{:05}", i));
+
+    // 扫描开始前已写入的 key 都必须可见，不论它们此时在 memtable、冻结列表还是 L0 中
+    let written = Arc::new(AtomicUsize::new(0));
+    let stop = Arc::new(AtomicBool::new(false));
+    let mut handles = vec![];
+    // 后台刷写和手动刷写同时进行
+    for _ in 0..2 {
+        let db = db.clone();
+        let stop = stop.clone();
+        handles.push(thread::spawn(move || {
+            while !stop.load(Ordering::Acquire) {
+                db.flush().unwrap();
+            }
+        }));
+    }
+    for _ in 0..2 {
+        let db = db.clone();
+        let stop = stop.clone();
+        let written = written.clone();
+        handles.push(thread::spawn(move || {
+            while !stop.load(Ordering::Acquire) {
+                let expect = written.load(Ordering::Acquire);
+                let mut iter = db.scan(Unbounded, Unbounded).unwrap();
+                let mut count = 0;
+                while iter.is_valid() && count < expect {
+                    assert_eq!(iter.key(), &key(count)[..]);
+                    assert_eq!(iter.value(), format!("v{}", count).as_bytes());
+                    count += 1;
+                    iter.next().unwrap();
+                }
+                assert_eq!(count, expect);
+            }
+        }));
+    }
+    for i in 0..3000 {
+        db.put(key(i), Bytes::from(format!("v{}", i))).unwrap();
+        written.store(i + 1, Ordering::Release);
+    }
+    stop.store(true, Ordering::Release);
+    handles.into_iter().for_each(|h| h.join().unwrap());
+    db.flush().unwrap();
+    assert_eq!(db.stats().frozen_memtables, 0);
+}
+
+#[test]
+fn test_tail_scan() {
+    INIT.call_once(setup);
+    let data_dir = tempfile::tempdir().unwrap();
+    let db = Db::open_file(data_dir.path()).unwrap();
+
+    let key = |i: usize| Bytes::from(format!("key{:03}", i));
+    for i in 0..10 {
+        db.put(key(i), Bytes::from(format!("value{}", i))).unwrap();
+    }
+    let mut iter = db.tail_scan(Included(key(0)), Excluded(key(20))).unwrap();
+    for i in 0..10 {
+        assert_eq!(iter.key(), &key(i)[..]);
+        iter.next().unwrap();
+    }
+    assert!(!iter.is_valid());
+    iter.refresh().unwrap();
+    assert!(!iter.is_valid());
+
+    // 新写入的更大的 key 在刷新后可读，已返回过的 key 不会重复返回
+    db.put(key(5), Bytes::from("new")).unwrap();
+    db.put(key(10), Bytes::from("value10")).unwrap();
+    db.flush().unwrap();
+    db.put(key(11), Bytes::from("value11")).unwrap();
+    iter.refresh().unwrap();
+    assert_eq!(iter.key(), &key(10)[..]);
+    iter.next().unwrap();
+    assert_eq!(iter.key(), &key(11)[..]);
+    assert_eq!(iter.value(), b"value11");
+
+    // 当前快照读完时 next 自动刷新
+    db.put(key(12), Bytes::from("value12")).unwrap();
+    db.put(key(30), Bytes::from("value30")).unwrap();
+    iter.next().unwrap();
+    assert_eq!(iter.key(), &key(12)[..]);
+    iter.next().unwrap();
+    assert!(!iter.is_valid());
+
+    drop(iter);
+    assert_eq!(db.num_of_active_snapshots(), 0);
+}
+
+#[test]
+fn test_staging() {
+    INIT.call_once(setup);
+    let data_dir = tempfile::tempdir().unwrap();
+    let stage_files = |path: &std::path::Path| {
+        std::fs::read_dir(path)
+            .unwrap()
+            .filter(|e| {
+                e.as_ref()
+                    .unwrap()
+                    .path()
+                    .extension()
+                    .is_some_and(|ext| ext == "STAGE")
+            })
+            .count()
+    };
+
+    {
+        let db = Db::open_file(data_dir.path()).unwrap();
+        for i in 0..100 {
+            db.put(
+                Bytes::from(format!("key{:03}", i)),
+                Bytes::from(format!("value{}", i)),
+            )
+            .unwrap();
+        }
+        let mut staging = db.staging(256);
+        let mut iter = db.scan(Unbounded, Unbounded).unwrap();
+        while iter.is_valid() {
+            staging.push(iter.key(), iter.value()).unwrap();
+            iter.next().unwrap();
+        }
+        drop(iter);
+        assert!(staging.is_spilled());
+        let result = staging.finish().unwrap();
+        assert_eq!(stage_files(data_dir.path()), 1);
+        assert_eq!(result.len(), 100);
+        for (i, item) in result.enumerate() {
+            let (key, value) = item.unwrap();
+            assert_eq!(key, Bytes::from(format!("key{:03}", i)));
+            assert_eq!(value, Bytes::from(format!("value{}", i)));
+        }
+        assert_eq!(stage_files(data_dir.path()), 0);
+
+        // 模拟崩溃时残留的暂存文件
+        let mut staging = db.staging(0);
+        staging.push(b"k", b"v").unwrap();
+        std::mem::forget(staging);
+        assert_eq!(stage_files(data_dir.path()), 1);
+    }
+    let _db = Db::open_file(data_dir.path()).unwrap();
+    assert_eq!(stage_files(data_dir.path()), 0);
+}
+
+#[test]
+fn test_flush_collapses_versions() {
+    let data_dir = tempfile::tempdir().unwrap();
+    let db = Db::open_file(data_dir.path()).unwrap();
+    for i in 0..10 {
+        db.put(Bytes::from("a"), Bytes::from(format!("a{}", i)))
+            .unwrap();
+    }
+    db.put(Bytes::from("b"), Bytes::from("b1")).unwrap();
+    db.delete(Bytes::from("b")).unwrap();
+    db.put(Bytes::from("b"), Bytes::from("b2")).unwrap();
+    db.put(Bytes::from("c"), Bytes::from("c1")).unwrap();
+    db.delete(Bytes::from("c")).unwrap();
+    // 同一批次中后面的修改生效
+    let mut batch = WriteBatch::new();
+    batch.put(Bytes::from("d"), Bytes::from("d1")).unwrap();
+    batch.delete(Bytes::from("d")).unwrap();
+    batch.delete(Bytes::from("e")).unwrap();
+    batch.put(Bytes::from("e"), Bytes::from("e1")).unwrap();
+    db.write(batch).unwrap();
+
+    let check = |db: &Db| {
+        assert_eq!(db.get(&Bytes::from("a")).unwrap(), Some(Bytes::from("a9")));
+        assert_eq!(db.get(&Bytes::from("b")).unwrap(), Some(Bytes::from("b2")));
+        assert_eq!(db.get(&Bytes::from("c")).unwrap(), None);
+        assert_eq!(db.get(&Bytes::from("d")).unwrap(), None);
+        assert_eq!(db.get(&Bytes::from("e")).unwrap(), Some(Bytes::from("e1")));
+    };
+    check(&db);
+    let snapshot = db.snapshot();
+    db.flush().unwrap();
+    check(&db);
+    assert_eq!(
+        db.get_with_snapshot(&snapshot, &Bytes::from("b")).unwrap(),
+        Some(Bytes::from("b2"))
+    );
+    drop(snapshot);
+
+    // 每个 key 只有最新版本写入 L0，c 的删除标记只遮盖同一 memtable 中的写入，不写入
+    let inner = db.inner.read().clone();
+    assert_eq!(inner.levels[0].len(), 1);
+    assert_eq!(inner.levels[0][0].num_of_pairs(), 4);
+    assert_eq!(inner.levels[0][0].num_of_deletes(), Some(1));
+    drop(inner);
+    db.close().unwrap();
+}
+
+#[test]
+fn test_flush_keeps_deletes_shadowing_older_data() {
+    let data_dir = tempfile::tempdir().unwrap();
+    let db = Db::open_file(data_dir.path()).unwrap();
+    // 只有被删除的 key 时仍写入一个删除标记
+    db.put(Bytes::from("a"), Bytes::from("a1")).unwrap();
+    db.delete(Bytes::from("a")).unwrap();
+    db.flush().unwrap();
+    assert_eq!(db.inner.read().levels[0][0].num_of_deletes(), Some(1));
+
+    db.put(Bytes::from("b"), Bytes::from("b1")).unwrap();
+    db.flush().unwrap();
+    // b 在更早的 SST 中，删除标记必须写入
+    db.put(Bytes::from("b"), Bytes::from("b2")).unwrap();
+    db.delete(Bytes::from("b")).unwrap();
+    db.put(Bytes::from("c"), Bytes::from("c1")).unwrap();
+    db.delete(Bytes::from("c")).unwrap();
+    db.put(Bytes::from("d"), Bytes::from("d1")).unwrap();
+    db.flush().unwrap();
+
+    let inner = db.inner.read().clone();
+    let newest = inner.tables_newest_first(0)[0].clone();
+    assert_eq!(newest.num_of_pairs(), 2);
+    assert_eq!(newest.num_of_deletes(), Some(1));
+    drop(inner);
+    db.close().unwrap();
+    drop(db);
+
+    let db = Db::open_file(data_dir.path()).unwrap();
+    assert_eq!(db.get(&Bytes::from("a")).unwrap(), None);
+    assert_eq!(db.get(&Bytes::from("b")).unwrap(), None);
+    assert_eq!(db.get(&Bytes::from("c")).unwrap(), None);
+    assert_eq!(db.get(&Bytes::from("d")).unwrap(), Some(Bytes::from("d1")));
+    db.close().unwrap();
+}
+
+#[test]
+fn test_l0_updates_across_flushes() {
+    let data_dir = tempfile::tempdir().unwrap();
+    let check = |db: &Db| {
+        assert_eq!(db.get(&Bytes::from("a")).unwrap(), Some(Bytes::from("a2")));
+        assert_eq!(db.get(&Bytes::from("b")).unwrap(), Some(Bytes::from("b2")));
+        assert_eq!(db.get(&Bytes::from("c")).unwrap(), None);
+        assert_eq!(db.get(&Bytes::from("d")).unwrap(), Some(Bytes::from("d0")));
+        let mut iter = db.scan(Unbounded, Unbounded).unwrap();
+        let mut kvs = vec![];
+        while iter.is_valid() {
+            kvs.push((
+                Bytes::copy_from_slice(iter.key()),
+                Bytes::copy_from_slice(iter.value()),
+            ));
+            iter.next().unwrap();
+        }
+        assert_eq!(
+            kvs,
+            vec![
+                (Bytes::from("a"), Bytes::from("a2")),
+                (Bytes::from("b"), Bytes::from("b2")),
+                (Bytes::from("d"), Bytes::from("d0")),
+            ]
+        );
+    };
+    {
+        let db = Db::open_with_options(data_dir.path(), DbOptions::default()).unwrap();
+        // 每次刷写都更新同一批 key，三个 L0 SST 的 key 范围互相重叠
+        for round in 0..3 {
+            db.put(Bytes::from("a"), Bytes::from(format!("a{}", round)))
+                .unwrap();
+            match round {
+                1 => db.delete(Bytes::from("b")).unwrap(),
+                _ => db
+                    .put(Bytes::from("b"), Bytes::from(format!("b{}", round)))
+                    .unwrap(),
+            };
+            match round {
+                0 => db.put(Bytes::from("c"), Bytes::from("c0")).unwrap(),
+                _ => db.delete(Bytes::from("c")).unwrap(),
+            };
+            if round == 0 {
+                db.put(Bytes::from("d"), Bytes::from("d0")).unwrap();
+            }
+            db.flush().unwrap();
+        }
+
+        // 后刷写的 SST 记录的最大 seq num 更大
+        let inner = db.inner.read().clone();
+        let l0 = inner.tables_newest_first(0);
+        assert_eq!(l0.len(), 3);
+        let max_seqs: Vec<_> = l0.iter().map(|table| table.max_seq().unwrap()).collect();
+        assert!(max_seqs.windows(2).all(|w| w[0] > w[1]));
+        assert!(l0.windows(2).all(|w| w[0].id() > w[1].id()));
+        assert_eq!(max_seqs[0], inner.commit_seq.last_allocated());
+        drop(inner);
+        check(&db);
+        let snapshot = db.snapshot();
+        assert_eq!(
+            db.get_with_snapshot(&snapshot, &Bytes::from("a")).unwrap(),
+            Some(Bytes::from("a2"))
+        );
+        drop(snapshot);
+        db.close().unwrap();
+    }
+
+    // 重新打开后从文件中读出最大 seq num，合并 L0 时同样以最新的版本为准
+    let db = Db::open_with_options(data_dir.path(), DbOptions::default()).unwrap();
+    check(&db);
+    while !db.inner.read().levels[0].is_empty() {
+        db.compact(0).unwrap();
+    }
+    check(&db);
+    let inner = db.inner.read().clone();
+    assert_eq!(
+        inner.levels[1]
+            .iter()
+            .filter_map(|table| table.max_seq())
+            .max(),
+        Some(inner.commit_seq.last_allocated())
+    );
+}
+
+#[test]
+fn test_strict_manifest_sync() {
+    let data_dir = tempfile::tempdir().unwrap();
+    let options = DbOptions {
+        strict_manifest_sync: true,
+        ..Default::default()
+    };
+    {
+        let db = Db::open_with_options(data_dir.path(), options.clone()).unwrap();
+        for round in 0..3 {
+            db.put(Bytes::from("a"), Bytes::from(format!("a{}", round)))
+                .unwrap();
+            db.put(Bytes::from(format!("k{}", round)), Bytes::from("v"))
+                .unwrap();
+            db.flush().unwrap();
+        }
+        // 刷写后冻结的 WAL 都已删除
+        assert!(db.inner.read().frozen_wal.is_empty());
+        while !db.inner.read().levels[0].is_empty() {
+            db.compact(0).unwrap();
+        }
+        db.close().unwrap();
+    }
+
+    let db = Db::open_with_options(data_dir.path(), options).unwrap();
+    assert_eq!(db.get(&Bytes::from("a")).unwrap(), Some(Bytes::from("a2")));
+    for round in 0..3 {
+        assert_eq!(
+            db.get(&Bytes::from(format!("k{}", round))).unwrap(),
+            Some(Bytes::from("v"))
+        );
+    }
+    assert!(db.inner.read().levels[0].is_empty());
+}
+
+#[test]
+fn test_redo_wal_keeps_newest_version() {
+    let data_dir = tempfile::tempdir().unwrap();
+    {
+        let db = Db::open_file(data_dir.path()).unwrap();
+        db.put(Bytes::from("k"), Bytes::from("v1")).unwrap();
+        db.delete(Bytes::from("k")).unwrap();
+        db.put(Bytes::from("k"), Bytes::from("v2")).unwrap();
+        db.close().unwrap();
+    }
+    let db = Db::open_file(data_dir.path()).unwrap();
+    assert_eq!(db.get(&Bytes::from("k")).unwrap(), Some(Bytes::from("v2")));
+    // 恢复后的新写入排在重放的写入之前
+    db.delete(Bytes::from("k")).unwrap();
+    assert_eq!(db.get(&Bytes::from("k")).unwrap(), None);
+    db.put(Bytes::from("k"), Bytes::from("v3")).unwrap();
+    db.flush().unwrap();
+    assert_eq!(db.get(&Bytes::from("k")).unwrap(), Some(Bytes::from("v3")));
+}
+
+#[test]
+fn test_scan_partitions() {
+    let data_dir = tempfile::tempdir().unwrap();
+    let db = Db::open_file(data_dir.path()).unwrap();
+    let value = Bytes::from(vec![b'v'; 100]);
+    for i in 0..2000 {
+        db.put(Bytes::from(format!("key{:05}", i)), value.clone())
+            .unwrap();
+    }
+    db.flush().unwrap();
+    // memtable 中的数据也会出现在对应的子范围中
+    db.put(Bytes::from("key00000a"), value.clone()).unwrap();
+    db.delete(Bytes::from("key01000")).unwrap();
+
+    let snapshot = db.snapshot();
+    let partitions = snapshot.partition_range(Unbounded, Unbounded, 4).unwrap();
+    assert_eq!(partitions.len(), 4);
+    assert_eq!(partitions[0].0, Unbounded);
+    assert_eq!(partitions[3].1, Unbounded);
+    for pair in partitions.windows(2) {
+        match (&pair[0].1, &pair[1].0) {
+            (Excluded(upper), Included(lower)) => assert_eq!(upper, lower),
+            bounds => panic!("partitions are not adjacent: {:?}", bounds),
+        }
+    }
+
+    // 之后的写入对共享的快照不可见
+    let iters = snapshot.scan_partitions(Unbounded, Unbounded, 4).unwrap();
+    db.put(Bytes::from("zzz"), value.clone()).unwrap();
+    let counts: Vec<usize> = thread::scope(|scope| {
+        let handles: Vec<_> = iters
+            .into_iter()
+            .map(|mut iter| {
+                scope.spawn(move || {
+                    let mut count = 0;
+                    while iter.is_valid() {
+                        count += 1;
+                        iter.next().unwrap();
+                    }
+                    count
+                })
+            })
+            .collect();
+        handles.into_iter().map(|h| h.join().unwrap()).collect()
+    });
+    assert_eq!(counts.iter().sum::<usize>(), 2000);
+    for count in &counts {
+        assert!(*count > 300, "unbalanced partitions: {:?}", counts);
+    }
+
+    let partitions = db
+        .scan_partitions(
+            Included(Bytes::from("key00100")),
+            Excluded(Bytes::from("key00200")),
+            4,
+        )
+        .unwrap();
+    let mut keys = vec![];
+    for mut iter in partitions {
+        while iter.is_valid() {
+            keys.push(Bytes::copy_from_slice(iter.key()));
+            iter.next().unwrap();
+        }
+    }
+    assert_eq!(keys.len(), 100);
+    assert_eq!(keys[0], Bytes::from("key00100"));
+    assert!(keys.windows(2).all(|pair| pair[0] < pair[1]));
+
+    // 范围内没有 block 边界时不切分
+    let partitions = db
+        .snapshot()
+        .partition_range(
+            Included(Bytes::from("key00100")),
+            Included(Bytes::from("key00101")),
+            4,
+        )
+        .unwrap();
+    assert_eq!(partitions.len(), 1);
+}
+
+#[test]
+fn test_key_prefix_stats() {
+    let data_dir = tempfile::tempdir().unwrap();
+    let db = Db::open_file(data_dir.path()).unwrap();
+    assert_eq!(db.stats().key_prefixes.keys, 0);
+    for i in 0..1000 {
+        db.put(
+            Bytes::from(format!("tenant-0001/orders/{:08}", i)),
+            Bytes::from("v"),
+        )
+        .unwrap();
+    }
+    db.flush().unwrap();
+    let stats = db.stats().key_prefixes;
+    assert_eq!(stats.keys, 1000);
+    assert!(stats.shared_prefix_bytes * 2 > stats.key_bytes);
+
+    let advice = db.prefix_compression_advice();
+    assert!(advice.restart_interval.is_some());
+    assert!(advice.estimated_savings > 0);
+
+    // 合并输出的 SST 也计入统计
+    db.compact(0).unwrap();
+    assert_eq!(db.stats().key_prefixes.keys, 2000);
+}
+
+#[test]
+fn test_value_inspector() {
+    let data_dir = tempfile::tempdir().unwrap();
+    let options = DbOptions {
+        value_inspection_sampling: 1,
+        ..Default::default()
+    };
+    let db = Db::open_file_with_options(data_dir.path(), options).unwrap();
+    db.add_value_inspector(
+        "record_type",
+        |_key: &[u8], value: &[u8], metrics: &mut ValueMetrics| {
+            let record_type = if value.starts_with(b"user:") {
+                "user"
+            } else {
+                "order"
+            };
+            metrics.add(record_type, 1);
+        },
+    );
+    for i in 0..10 {
+        let value = if i < 6 { "user:alice" } else { "order:42" };
+        db.put(Bytes::from(format!("key{:02}", i)), Bytes::from(value))
+            .unwrap();
+    }
+    // 删除标记不交给回调
+    db.delete(Bytes::from("key00")).unwrap();
+    assert!(db.stats().value_metrics.is_empty());
+    db.flush().unwrap();
+    let metrics = &db.stats().value_metrics["record_type"];
+    assert_eq!(metrics["user"], 5);
+    assert_eq!(metrics["order"], 4);
+
+    // 合并写出的 value 再次计入
+    db.compact(0).unwrap();
+    let metrics = &db.stats().value_metrics["record_type"];
+    assert_eq!(metrics["user"], 10);
+    assert_eq!(metrics["order"], 8);
+
+    assert!(db.remove_value_inspector("record_type"));
+    assert!(db.stats().value_metrics.is_empty());
+}
+
+#[test]
+fn test_shutdown_ordering() {
+    let data_dir = tempfile::tempdir().unwrap();
+    let db = Db::open_file(data_dir.path()).unwrap();
+    for i in 0..100 {
+        db.put(Bytes::from(format!("key{:03}", i)), Bytes::from("v1"))
+            .unwrap();
+    }
+    db.flush().unwrap();
+    db.put(Bytes::from("key000"), Bytes::from("v2")).unwrap();
+    // 关闭前请求的刷写会执行完，排队的合并被取消
+    let job = db.daemon.request_flush();
+    for _ in 0..3 {
+        db.daemon.request_compaction(1);
+    }
+    db.close().unwrap();
+    let stats = db.stats();
+    assert_eq!(stats.flush_jobs.last_completed, Some(job));
+    assert_eq!(stats.flush_queue.pending, 0);
+    assert_eq!(stats.compaction_queue.pending, 0);
+    assert!(!stats.compaction_queue.running);
+    assert!(stats.compaction_queue.oldest_pending_age.is_none());
+    let cumulative = stats.cumulative;
+    let last_seq = db.last_commit_seq();
+
+    // 关闭后拒绝写入，读取仍然可用，重复关闭什么也不做
+    let err = db
+        .put(Bytes::from("key100"), Bytes::from("v1"))
+        .unwrap_err();
+    assert!(err.downcast_ref::<DbClosedError>().is_some());
+    assert!(db.flush().is_err());
+    assert_eq!(
+        db.get(&Bytes::from("key000")).unwrap(),
+        Some(Bytes::from("v2"))
+    );
+    db.close().unwrap();
+    drop(db);
+
+    // 重新打开后数据完整，刷写没有重复执行，提交序号没有回退
+    let db = Db::open_file(data_dir.path()).unwrap();
+    assert_eq!(db.count(Unbounded, Unbounded).unwrap(), 100);
+    assert_eq!(
+        db.get(&Bytes::from("key000")).unwrap(),
+        Some(Bytes::from("v2"))
+    );
+    assert_eq!(db.stats().cumulative, cumulative);
+    assert_eq!(db.stats().level_files[0], 1);
+    assert!(db.last_commit_seq() >= last_seq);
+
+    // drop 时同样停止后台任务，memtable 中的写入从 WAL 恢复
+    db.put(Bytes::from("key100"), Bytes::from("v2")).unwrap();
+    db.flush().unwrap();
+    db.put(Bytes::from("key101"), Bytes::from("v2")).unwrap();
+    let cumulative = db.stats().cumulative;
+    drop(db);
+    let db = Db::open_file(data_dir.path()).unwrap();
+    assert_eq!(db.count(Unbounded, Unbounded).unwrap(), 102);
+    assert_eq!(
+        db.get(&Bytes::from("key101")).unwrap(),
+        Some(Bytes::from("v2"))
+    );
+    assert_eq!(db.stats().cumulative, cumulative);
+    assert_eq!(db.stats().level_files[0], 2);
+}
+
+#[test]
+fn test_scheduler_handle() {
+    let run = |dir: &std::path::Path| {
+        let options = DbOptions {
+            memtable_size_limit: 4 * KB,
+            l0_sst_num_limit: 2,
+            ..Default::default()
+        };
+        let db = Db::open_with_options(dir, options).unwrap();
+        let scheduler = db.scheduler().unwrap();
+        let mut steps = vec![];
+        for i in 0..200 {
+            db.put(
+                Bytes::from(format!("key{:03}", i % 50)),
+                Bytes::from(format!("value{:0100}", i)),
+            )
+            .unwrap();
+            steps.extend(scheduler.run_until_idle().unwrap());
+        }
+        assert_eq!(scheduler.step().unwrap(), None);
+        for i in 150..200 {
+            assert_eq!(
+                db.get(&Bytes::from(format!("key{:03}", i % 50))).unwrap(),
+                Some(Bytes::from(format!("value{:0100}", i)))
+            );
+        }
+        let stats = db.stats();
+        assert_eq!(stats.flush_queue.pending, 0);
+        assert_eq!(stats.compaction_queue.pending, 0);
+        (steps, stats.level_files)
+    };
+
+    // 同样的写入得到同样的步骤和文件布局
+    let dir1 = tempfile::tempdir().unwrap();
+    let dir2 = tempfile::tempdir().unwrap();
+    let (steps, level_files) = run(dir1.path());
+    assert_eq!(run(dir2.path()), (steps.clone(), level_files));
+    assert!(steps.contains(&SchedulerStep::Rotate));
+    assert!(steps.contains(&SchedulerStep::Compaction(0)));
+
+    // 直接触发的步骤不要求队列中有请求
+    let db = Db::open_with_options(dir1.path(), DbOptions::default()).unwrap();
+    let scheduler = db.scheduler().unwrap();
+    scheduler.rotate().unwrap();
+    scheduler.compact(0).unwrap();
+    assert_eq!(db.stats().level_files[0], 0);
+    db.close().unwrap();
+    assert!(scheduler.step().is_err());
+
+    // 有后台线程时不能使用
+    let db = Db::open_file(dir2.path()).unwrap();
+    assert!(db.scheduler().is_err());
+}
+
+#[test]
+fn test_vsst_deref_stats() {
+    let data_dir = tempfile::tempdir().unwrap();
+    let options = DbOptions {
+        value_separation_threshold: Some(64),
+        ..Default::default()
+    };
+    let db = Db::open_with_options(data_dir.path(), options).unwrap();
+    let value = BytesMut::zeroed(KB).freeze();
+    for i in 0..10 {
+        db.put(Bytes::from(format!("k{}", i)), value.clone())
+            .unwrap();
+    }
+    db.put(Bytes::from("small"), Bytes::from("v")).unwrap();
+
+    // memtable 中的 value 不需要读取 VSST
+    let scan = |db: &Db| {
+        let mut iter = db.scan(Unbounded, Unbounded).unwrap();
+        let mut count = 0;
+        while iter.is_valid() {
+            count += 1;
+            iter.next().unwrap();
+        }
+        assert_eq!(count, 11);
+        iter.inner().vsst_deref_stats()
+    };
+    assert_eq!(scan(&db).dereferences(), 0);
+
+    // 第一次从文件读取 VSST 的块，之后都在块缓存中
+    db.flush().unwrap();
+    let stats = scan(&db);
+    assert_eq!(stats.dereferences(), 10);
+    assert!(stats.disk_reads > 0);
+    assert!(stats.latency > Duration::ZERO);
+    let stats = scan(&db);
+    assert_eq!(stats.cache_hits, 10);
+    assert_eq!(stats.disk_reads, 0);
+}
+
+#[test]
+fn test_wal_sync_interval() {
+    let data_dir = tempfile::tempdir().unwrap();
+    let relaxed = WriteOptions {
+        sync: false,
+        ..Default::default()
+    };
+    {
+        let db = Db::open_with_options(data_dir.path(), DbOptions::default()).unwrap();
+        for i in 0..10 {
+            db.put_with_options(Bytes::from(format!("k{}", i)), Bytes::from("v"), &relaxed)
+                .unwrap();
+        }
+        // 没有后台线程时只能手动推进水位
+        assert_eq!(db.durable_seq(), 0);
+        let seq = db.last_commit_seq();
+        assert_eq!(db.sync_wal().unwrap(), seq);
+        assert_eq!(db.durable_seq(), seq);
+        db.flush().unwrap();
+        db.put_with_options(Bytes::from("k10"), Bytes::from("v"), &relaxed)
+            .unwrap();
+        assert_eq!(db.sync_wal().unwrap(), seq + 1);
+    }
+
+    let options = DbOptions {
+        wal_sync_interval: Some(Duration::from_millis(10)),
+        ..Default::default()
+    };
+    let db = Db::open_file_with_options(data_dir.path(), options).unwrap();
+    // 打开时恢复出的写入都已持久化
+    let seq = db.last_commit_seq();
+    assert_eq!(db.durable_seq(), seq);
+    db.put_with_options(Bytes::from("k11"), Bytes::from("v"), &relaxed)
+        .unwrap();
+    for _ in 0..200 {
+        if db.durable_seq() > seq {
+            break;
+        }
+        thread::sleep(Duration::from_millis(10));
+    }
+    assert_eq!(db.durable_seq(), seq + 1);
+    db.close().unwrap();
+    assert!(db.sync_wal().is_err());
+}
+
+#[test]
+fn test_copy_db_and_checkpoint() {
+    let src = tempfile::tempdir().unwrap();
+    let dst = tempfile::tempdir().unwrap();
+    let key = |i: usize| Bytes::from(format!("k{:03}", i));
+    let big = BytesMut::zeroed(MIN_VSST_SIZE as usize * 2).freeze();
+    {
+        let db = Db::open_with_options(src.path(), DbOptions::default()).unwrap();
+        for i in 0..100 {
+            db.put(key(i), Bytes::from(format!("v{}", i))).unwrap();
+        }
+        db.put(key(100), big.clone()).unwrap();
+        db.flush().unwrap();
+        // 只在 WAL 中的写入同样被复制
+        db.put(key(101), Bytes::from("wal")).unwrap();
+        db.close().unwrap();
+    }
+
+    // 关闭时复制
+    let copy = dst.path().join("copy");
+    let report = admin::copy_db(src.path(), &copy).unwrap();
+    assert_eq!(report.ssts.len(), 1);
+    assert_eq!(report.vssts.len(), 1);
+    assert_eq!(report.wals.len(), 1);
+    assert!(report.bytes > big.len() as u64);
+    assert!(admin::copy_db(src.path(), &copy).is_err());
+    assert_eq!(admin::versions(&copy).unwrap().count(), 1);
+    assert!(admin::verify(&copy).unwrap().is_ok());
+    {
+        let db = Db::open_with_options(&copy, DbOptions::default()).unwrap();
+        assert_eq!(db.get(&key(7)).unwrap(), Some(Bytes::from("v7")));
+        assert_eq!(db.get(&key(100)).unwrap(), Some(big.clone()));
+        assert_eq!(db.get(&key(101)).unwrap(), Some(Bytes::from("wal")));
+    }
+
+    // 校验失败时不复制
+    for (_, sst_id) in &report.ssts {
+        std::fs::remove_file(Db::path_of_sst(&copy, *sst_id)).unwrap();
+    }
+    let broken = dst.path().join("broken");
+    assert!(admin::copy_db(&copy, &broken).is_err());
+    assert!(!Db::path_of_current(&broken).exists());
+
+    // 打开时复制，之后的写入不在副本中
+    let db = Db::open_file(src.path()).unwrap();
+    assert!(admin::copy_db(src.path(), dst.path().join("open")).is_err());
+    db.put(key(102), Bytes::from("before")).unwrap();
+    let checkpoint = dst.path().join("checkpoint");
+    let report = db.checkpoint(&checkpoint).unwrap();
+    assert!(report.wals.is_empty());
+    db.put(key(103), Bytes::from("after")).unwrap();
+    db.compact(0).unwrap();
+    assert!(admin::verify(&checkpoint).unwrap().is_ok());
+    let copy = Db::open_with_options(&checkpoint, DbOptions::default()).unwrap();
+    for i in 0..100 {
+        assert_eq!(
+            copy.get(&key(i)).unwrap(),
+            Some(Bytes::from(format!("v{}", i)))
+        );
+    }
+    assert_eq!(copy.get(&key(100)).unwrap(), Some(big));
+    assert_eq!(copy.get(&key(102)).unwrap(), Some(Bytes::from("before")));
+    assert_eq!(copy.get(&key(103)).unwrap(), None);
+    assert!(copy.last_commit_seq() >= db.last_commit_seq() - 1);
+}
+
+#[test]
+fn test_destroy() {
+    let dir = tempfile::tempdir().unwrap();
+    // 不是数据库的目录不删除任何文件
+    let not_db = dir.path().join("not_db");
+    std::fs::create_dir(&not_db).unwrap();
+    std::fs::write(not_db.join("00001.SST"), b"data").unwrap();
+    assert!(Db::destroy(&not_db).is_err());
+    assert!(not_db.join("00001.SST").exists());
+
+    let path = dir.path().join("db");
+    std::fs::create_dir(&path).unwrap();
+    let big = BytesMut::zeroed(MIN_VSST_SIZE as usize * 2).freeze();
+    let db = Db::open_with_options(&path, DbOptions::default()).unwrap();
+    db.put(Bytes::from("small"), Bytes::from("value")).unwrap();
+    db.put(Bytes::from("big"), big).unwrap();
+    db.flush().unwrap();
+    db.put(Bytes::from("wal"), Bytes::from("value")).unwrap();
+    // 打开中的数据库不能删除
+    assert!(Db::destroy(&path).is_err());
+    db.close().unwrap();
+    drop(db);
+
+    // 其他文件保留，目录也随之保留
+    std::fs::write(path.join("notes.txt"), b"keep").unwrap();
+    Db::destroy(&path).unwrap();
+    let names: Vec<_> = std::fs::read_dir(&path)
+        .unwrap()
+        .map(|entry| entry.unwrap().file_name())
+        .collect();
+    assert_eq!(names, vec!["notes.txt"]);
+    assert!(Db::destroy(&path).is_err());
+
+    // 删除后可以重新创建，目录中只有数据库文件时连同目录一起删除
+    std::fs::remove_file(path.join("notes.txt")).unwrap();
+    let db = Db::open_with_options(&path, DbOptions::default()).unwrap();
+    assert_eq!(db.get(&Bytes::from("small")).unwrap(), None);
+    db.close().unwrap();
+    drop(db);
+    Db::destroy(&path).unwrap();
+    assert!(!path.exists());
+}
+
+#[test]
+fn test_cache_fill_policy() {
+    let key = |i: usize| Bytes::from(format!("k{:05}", i));
+    let cached_blocks = |sst: &Arc<SsTable>| {
+        (0..sst.num_of_blocks())
+            .filter(|idx| sst.is_cached(*idx))
+            .count()
+    };
+    for (compaction_cache_fill, flush_cache_fill) in [
+        (CacheFillPolicy::OnWrite, CacheFillPolicy::None),
+        (CacheFillPolicy::None, CacheFillPolicy::OnWrite),
+    ] {
+        let dir = tempfile::tempdir().unwrap();
+        let options = DbOptions {
+            compaction_cache_fill,
+            flush_cache_fill,
+            ..Default::default()
+        };
+        let db = Db::open_with_options(dir.path(), options).unwrap();
+        for round in 0..2 {
+            for i in 0..2000 {
+                db.put(key(i), Bytes::from(format!("value-{}-{}", round, i)))
+                    .unwrap();
+            }
+            db.flush().unwrap();
+        }
+        let inputs = db.inner.read().levels[0].clone();
+        assert_eq!(inputs.len(), 2);
+        for sst in &inputs {
+            let expected = match flush_cache_fill {
+                CacheFillPolicy::OnWrite => sst.num_of_blocks(),
+                _ => 0,
+            };
+            assert_eq!(cached_blocks(sst), expected);
+        }
+        // 读取一个 key，只有它所在的块成为热点
+        db.get(&key(1000)).unwrap().unwrap();
+
+        let cached_inputs: usize = inputs.iter().map(cached_blocks).sum();
+        db.compact(0).unwrap();
+        // 合并读取输入时不放入缓存
+        assert_eq!(
+            inputs.iter().map(cached_blocks).sum::<usize>(),
+            cached_inputs
+        );
+        let outputs = db.inner.read().levels[1].clone();
+        let total: usize = outputs.iter().map(|sst| sst.num_of_blocks()).sum();
+        let warmed: usize = outputs.iter().map(cached_blocks).sum();
+        match compaction_cache_fill {
+            // 只有覆盖了热点块的输出块被读入缓存
+            CacheFillPolicy::OnWrite => assert!(warmed > 0 && warmed < total),
+            _ => assert_eq!(warmed, 0),
+        }
+    }
+}
+
+#[test]
+fn test_missing_vsst_policy() {
+    INIT.call_once(setup);
+    let data_dir = tempfile::tempdir().unwrap();
+    let big_value = Bytes::from(vec![b'v'; MIN_VSST_SIZE as usize + 1]);
+    let vsst_id = {
+        let db = Db::open_file(data_dir.path()).unwrap();
+        // 更深层中的旧版本，丢失的新版本不能让它重新可见
+        db.put(Bytes::from("big"), Bytes::from("old")).unwrap();
+        db.flush().unwrap();
+        db.compact(0).unwrap();
+        db.put(Bytes::from("big"), big_value.clone()).unwrap();
+        db.put(Bytes::from("small"), Bytes::from("v")).unwrap();
+        db.flush().unwrap();
+        let vsst_id = *db
+            .inner
+            .read()
+            .vssts
+            .read()
+            .iter()
+            .find(|(_, vsst)| vsst.num_of_pairs() > 0)
+            .unwrap()
+            .0;
+        db.close().unwrap();
+        vsst_id
+    };
+    std::fs::remove_file(Db::path_of_vsst(data_dir.path(), vsst_id)).unwrap();
+    let open = |policy| {
+        let mut options = DbOptions::default();
+        options.missing_vsst_policy = policy;
+        Db::open_file_with_options(data_dir.path(), options)
+    };
+
+    assert!(open(MissingVSstPolicy::Fail).is_err());
+
+    {
+        let db = open(MissingVSstPolicy::NotFound).unwrap();
+        assert_eq!(db.stats().recovery.missing_vssts, vec![vsst_id]);
+        assert_eq!(db.get(&Bytes::from("big")).unwrap(), None);
+        assert!(!db.contains_key(&Bytes::from("big")).unwrap());
+        let mut iter = db.scan(Unbounded, Unbounded).unwrap();
+        assert_eq!(iter.key(), b"small");
+        iter.next().unwrap();
+        assert!(!iter.is_valid());
+        drop(iter);
+        assert_eq!(db.stats().dangling_value_reads, 3);
+        assert_eq!(db.health().status, HealthStatus::Degraded);
+        db.close().unwrap();
+    }
+
+    {
+        let db = open(MissingVSstPolicy::Repair).unwrap();
+        assert_eq!(db.get(&Bytes::from("big")).unwrap(), None);
+        assert_eq!(db.stats().dangling_value_reads, 0);
+        assert_eq!(db.health().status, HealthStatus::Ok);
+        db.close().unwrap();
+    }
+
+    // 引用清理后 VSST 从 MANIFEST 中删除，默认选项可以正常打开
+    let db = Db::open_file(data_dir.path()).unwrap();
+    assert!(db.stats().recovery.missing_vssts.is_empty());
+    assert_eq!(db.get(&Bytes::from("big")).unwrap(), None);
+    assert_eq!(
+        db.get(&Bytes::from("small")).unwrap(),
+        Some(Bytes::from("v"))
+    );
+    db.close().unwrap();
+}
+
+#[test]
+fn test_scan_isolation() {
+    INIT.call_once(setup);
+    let data_dir = tempfile::tempdir().unwrap();
+    let db = Db::open_file(data_dir.path()).unwrap();
+    let keys = |iter: &mut FusedIterator<DbIterator>| {
+        let mut keys = vec![];
+        while iter.is_valid() {
+            keys.push(Bytes::copy_from_slice(iter.key()));
+            iter.next().unwrap();
+        }
+        keys
+    };
+    for key in ["a", "c", "e"] {
+        db.put(Bytes::from(key), Bytes::from("v")).unwrap();
+    }
+
+    // 快照模式看不到迭代器创建之后写入同一个 memtable 的修改
+    let mut iter = db.scan(Unbounded, Unbounded).unwrap();
+    let snapshot = db.snapshot();
+    db.put(Bytes::from("b"), Bytes::from("v")).unwrap();
+    db.delete(Bytes::from("c")).unwrap();
+    assert_eq!(keys(&mut iter), vec!["a", "c", "e"]);
+    assert_eq!(snapshot.count(Unbounded, Unbounded).unwrap(), 3);
+    assert!(db.last_commit_seq() > snapshot.commit_seq());
+    drop(snapshot);
+
+    // 最新读模式在每次 next 时读到最新的写入，已返回的 key 不再返回
+    let options = ReadOptions {
+        isolation: ScanIsolation::Latest,
+        ..Default::default()
+    };
+    let mut iter = db
+        .scan_with_options(Unbounded, Unbounded, &options)
+        .unwrap();
+    assert_eq!(iter.key(), b"a");
+    db.delete(Bytes::from("b")).unwrap();
+    db.put(Bytes::from("c"), Bytes::from("v2")).unwrap();
+    iter.next().unwrap();
+    assert_eq!(iter.key(), b"c");
+    assert_eq!(iter.value(), b"v2");
+    // 刷写之后同样能读到新的写入
+    db.put(Bytes::from("d"), Bytes::from("v")).unwrap();
+    db.flush().unwrap();
+    db.put(Bytes::from("a0"), Bytes::from("v")).unwrap();
+    iter.next().unwrap();
+    assert_eq!(iter.key(), b"d");
+    db.delete(Bytes::from("e")).unwrap();
+    db.put(Bytes::from("f"), Bytes::from("v")).unwrap();
+    assert_eq!(keys(&mut iter), vec!["d", "f"]);
+    drop(iter);
+
+    let options = ReadOptions {
+        isolation: ScanIsolation::Latest,
+        snapshot: Some(db.snapshot()),
+        ..Default::default()
+    };
+    assert!(db
+        .scan_with_options(Unbounded, Unbounded, &options)
+        .is_err());
+}
+
+#[test]
+fn test_scan_bytes() {
+    INIT.call_once(setup);
+    let data_dir = tempfile::tempdir().unwrap();
+    let db = Db::open_file(data_dir.path()).unwrap();
+    for i in 0..20 {
+        db.put(
+            Bytes::from(format!("k{:02}", i)),
+            Bytes::from(vec![b'v'; 7]),
+        )
+        .unwrap();
+    }
+    db.flush().unwrap();
+
+    // 每个 KV 10 字节，累计达到 25 字节后停止
+    let mut lower = Unbounded;
+    let mut keys = vec![];
+    loop {
+        let chunk = db.scan_bytes(lower, Unbounded, 25).unwrap();
+        assert!(chunk.size() <= 30);
+        keys.extend(chunk.items.into_iter().map(|(key, _)| key));
+        match chunk.next_key {
+            Some(next_key) => lower = Included(next_key),
+            None => break,
+        }
+    }
+    let expected: Vec<_> = (0..20).map(|i| Bytes::from(format!("k{:02}", i))).collect();
+    assert_eq!(keys, expected);
+
+    // 单个 KV 超过限制时仍然返回它，保证能继续
+    let chunk = db
+        .scan_bytes(Included(Bytes::from("k19")), Unbounded, 1)
+        .unwrap();
+    assert_eq!(chunk.items.len(), 1);
+    assert_eq!(chunk.next_key, None);
+    let chunk = db
+        .scan_bytes(Excluded(Bytes::from("k19")), Unbounded, 1)
+        .unwrap();
+    assert!(chunk.items.is_empty());
+    db.close().unwrap();
+}
+
+#[test]
+fn test_micro_batch() {
+    INIT.call_once(setup);
+    let data_dir = tempfile::tempdir().unwrap();
+    let options = DbOptions {
+        micro_batch: Some(MicroBatchOptions {
+            max_delay: Duration::from_secs(10),
+            ..Default::default()
+        }),
+        ..Default::default()
+    };
+    let db = Db::open_file_with_options(data_dir.path(), options.clone()).unwrap();
+
+    // 第一个组长提交时阻塞在 WAL 上，期间到达的写入汇集成下一组，同一组的写入共用一个提交序号
+    // 各写入的 key 锁不在同一段，不会互相等待
+    let mut stripes = std::collections::HashSet::new();
+    let keys: Vec<Bytes> = (0..)
+        .map(|i| Bytes::from(format!("g{}", i)))
+        .filter(|key| stripes.insert(db.key_locks.stripe_of(key)))
+        .take(8)
+        .collect();
+    let wal = db.inner.read().wal.clone();
+    let append = wal.lock_append();
+    let seqs: Vec<u64> = thread::scope(|s| {
+        let handles: Vec<_> = keys
+            .iter()
+            .map(|key| {
+                let db = &db;
+                s.spawn(move || db.put(key.clone(), Bytes::from("v")).unwrap())
+            })
+            .collect();
+        // 除两个组长外都已加入第二组
+        while db.stats().grouped_writes < 6 {
+            thread::sleep(Duration::from_millis(1));
+        }
+        drop(append);
+        handles.into_iter().map(|h| h.join().unwrap()).collect()
+    });
+    assert_eq!(db.stats().grouped_writes, 6);
+    let distinct: std::collections::HashSet<_> = seqs.iter().collect();
+    assert_eq!(distinct.len(), 2);
+    assert_eq!(db.last_commit_seq(), *seqs.iter().max().unwrap());
+
+    // 并发写入时分组数不确定，每个组只分配一个提交序号
+    let grouped_before = db.stats().grouped_writes;
+    let seqs: Vec<u64> = thread::scope(|s| {
+        let handles: Vec<_> = (0..8)
+            .map(|t| {
+                let db = &db;
+                s.spawn(move || {
+                    (0..100)
+                        .map(|i| {
+                            db.put(Bytes::from(format!("k{}-{:03}", t, i)), Bytes::from("v"))
+                                .unwrap()
+                        })
+                        .collect::<Vec<_>>()
+                })
+            })
+            .collect();
+        handles
+            .into_iter()
+            .flat_map(|h| h.join().unwrap())
+            .collect()
+    });
+    let grouped = db.stats().grouped_writes;
+    let distinct: std::collections::HashSet<_> = seqs.iter().collect();
+    assert_eq!(distinct.len() as u64, 800 - (grouped - grouped_before));
+    assert_eq!(db.last_commit_seq(), *seqs.iter().max().unwrap());
+
+    // 大 value 和跳过 WAL 的写入单独提交
+    db.put(Bytes::from("big"), Bytes::from(vec![b'v'; 1024]))
+        .unwrap();
+    db.put_with_options(
+        Bytes::from("no-wal"),
+        Bytes::from("v"),
+        &WriteOptions {
+            disable_wal: true,
+            ..Default::default()
+        },
+    )
+    .unwrap();
+    assert_eq!(db.stats().grouped_writes, grouped);
+
+    db.delete(Bytes::from("k0-000")).unwrap();
+    assert_eq!(db.get(&Bytes::from("k0-000")).unwrap(), None);
+    db.close().unwrap();
+    drop(db);
+
+    // 分组写入的 WAL 记录在重启后重放
+    let db = Db::open_file_with_options(data_dir.path(), options).unwrap();
+    assert_eq!(db.get(&Bytes::from("k0-000")).unwrap(), None);
+    for t in 0..8 {
+        for i in (t == 0) as usize..100 {
+            assert_eq!(
+                db.get(&Bytes::from(format!("k{}-{:03}", t, i))).unwrap(),
+                Some(Bytes::from("v"))
+            );
+        }
+    }
+    db.close().unwrap();
+}
+
+#[test]
+fn test_negative_cache() {
+    INIT.call_once(setup);
+    let data_dir = tempfile::tempdir().unwrap();
+    let options = DbOptions {
+        negative_cache_capacity: Some(100),
+        ..DbOptions::default()
+    };
+    let db = Db::open_with_options(data_dir.path(), options).unwrap();
+    let (k1, absent) = (Bytes::from("k1"), Bytes::from("absent"));
+    db.put(k1.clone(), Bytes::from("v1")).unwrap();
+    db.flush().unwrap();
+
+    assert_eq!(db.get(&absent).unwrap(), None);
+    assert_eq!(db.stats().negative_cache_hits, 0);
+    // 命中时不再查找 SST
+    let scope = PerfContextScope::enter();
+    assert_eq!(db.get(&absent).unwrap(), None);
+    assert!(!db.contains_key(&absent).unwrap());
+    assert_eq!(db.value_size(&absent).unwrap(), None);
+    assert_eq!(scope.context().bloom_checks, 0);
+    drop(scope);
+    assert_eq!(db.stats().negative_cache_hits, 3);
+
+    // 写入后缓存失效，删除后再次确认不存在
+    db.put(absent.clone(), Bytes::from("v2")).unwrap();
+    assert_eq!(db.get(&absent).unwrap(), Some(Bytes::from("v2")));
+    db.delete(absent.clone()).unwrap();
+    assert_eq!(db.get(&absent).unwrap(), None);
+    assert_eq!(db.get(&absent).unwrap(), None);
+    assert_eq!(db.stats().negative_cache_hits, 4);
+    assert_eq!(db.get(&k1).unwrap(), Some(Bytes::from("v1")));
+    db.close().unwrap();
+}
+
+#[test]
+fn test_max_value_size() {
+    INIT.call_once(setup);
+    let data_dir = tempfile::tempdir().unwrap();
+    // 超过文件格式中 u32 长度的上限时打开失败
+    let options = DbOptions {
+        max_value_size: u32::MAX as usize + 1,
+        ..DbOptions::default()
+    };
+    let err = Db::open_with_options(data_dir.path(), options).unwrap_err();
+    assert!(err.to_string().contains("4 GB"));
+
+    let options = DbOptions {
+        max_value_size: 8,
+        ..DbOptions::default()
+    };
+    let db = Db::open_with_options(data_dir.path(), options).unwrap();
+    db.put(Bytes::from("k1"), Bytes::from("12345678")).unwrap();
+
+    let too_large = Rejection::TooLarge {
+        key: Bytes::from("k2"),
+        size: 9,
+        limit: 8,
+    };
+    let err = db
+        .put(Bytes::from("k2"), Bytes::from("123456789"))
+        .unwrap_err();
+    let rejected = err.downcast_ref::<WriteRejectedError>().unwrap();
+    assert_eq!(rejected.validator, VALUE_SIZE_VALIDATOR);
+    assert_eq!(rejected.rejection, too_large);
+
+    // 批次中有超长的 value 时整个批次都不写入
+    let seq = db.last_commit_seq();
+    let mut batch = WriteBatch::new();
+    batch.put(Bytes::from("k3"), Bytes::from("v3")).unwrap();
+    batch
+        .put(Bytes::from("k2"), Bytes::from("123456789"))
+        .unwrap();
+    let err = db.write(batch).unwrap_err();
+    assert_eq!(
+        err.downcast_ref::<WriteRejectedError>().unwrap().rejection,
+        too_large
+    );
+    assert_eq!(db.last_commit_seq(), seq);
+    assert_eq!(db.get(&Bytes::from("k2")).unwrap(), None);
+    assert_eq!(db.get(&Bytes::from("k3")).unwrap(), None);
+    assert_eq!(
+        db.get(&Bytes::from("k1")).unwrap(),
+        Some(Bytes::from("12345678"))
+    );
+    db.close().unwrap();
+}
+
+#[test]
+fn test_trace_replay() {
+    INIT.call_once(setup);
+    let dir = tempfile::tempdir().unwrap();
+    let trace_path = dir.path().join("ops.trace");
+    let db = Db::open_file(dir.path().join("db")).unwrap();
+    db.put(Bytes::from("before"), Bytes::from("v")).unwrap();
+    db.start_trace(&trace_path, TraceOptions::default())
+        .unwrap();
+    assert!(db
+        .start_trace(dir.path().join("other.trace"), TraceOptions::default())
+        .is_err());
+    db.put(Bytes::from("k1"), Bytes::from("value1")).unwrap();
+    let mut batch = WriteBatch::with_idempotency_token(7u128);
+    batch.put(Bytes::from("k2"), Bytes::from("v2")).unwrap();
+    batch.delete(Bytes::from("k1")).unwrap();
+    db.write(batch).unwrap();
+    db.multi_get(&[Bytes::from("k2"), Bytes::from("before")])
+        .unwrap();
+    db.scan(Included(Bytes::from("k")), Unbounded).unwrap();
+    let summary = db.stop_trace().unwrap();
+    assert_eq!(summary.records, 5);
+    assert!(!summary.truncated);
+    assert!(db.stop_trace().is_err());
+
+    // 幂等 token 不会被记录
+    let ops: Vec<_> = TraceReader::open(&trace_path)
+        .unwrap()
+        .map(|record| record.unwrap().op)
+        .collect();
+    assert_eq!(
+        ops[1],
+        TraceOp::Write(vec![
+            TraceWrite {
+                key: Bytes::from("k2"),
+                value_size: Some(2),
+            },
+            TraceWrite {
+                key: Bytes::from("k1"),
+                value_size: None,
+            },
+        ])
+    );
+    assert_eq!(
+        ops[3],
+        TraceOp::Get {
+            key: Bytes::from("before"),
+            value_size: Some(1),
+        }
+    );
+
+    // 记录开始之前写入的 key 在新数据库上读不到
+    let fresh = Db::open_file(dir.path().join("fresh")).unwrap();
+    let report = trace::replay(&trace_path, &fresh, &ReplayOptions::default()).unwrap();
+    assert_eq!((report.writes, report.gets, report.scans), (2, 2, 1));
+    assert_eq!(report.get_mismatches, 1);
+    assert_eq!(fresh.get(&Bytes::from("k1")).unwrap(), None);
+    assert_eq!(fresh.get(&Bytes::from("k2")).unwrap().unwrap().len(), 2);
+
+    // 达到大小上限后不再记录
+    let limited_path = dir.path().join("limited.trace");
+    let options = TraceOptions {
+        max_file_size: Some(64),
+    };
+    db.start_trace(&limited_path, options).unwrap();
+    for i in 0..10 {
+        db.put(Bytes::from(format!("key{}", i)), Bytes::from("v"))
+            .unwrap();
+    }
+    let summary = db.stop_trace().unwrap();
+    assert!(summary.truncated);
+    assert!(summary.file_size <= 64);
+    assert_eq!(
+        TraceReader::open(&limited_path).unwrap().count() as u64,
+        summary.records
+    );
+    db.close().unwrap();
+}
+
+#[test]
+fn test_read_only() {
+    INIT.call_once(setup);
+    let data_dir = tempfile::tempdir().unwrap();
+    let db = Db::open_file(data_dir.path()).unwrap();
+    db.put(Bytes::from("k1"), Bytes::from("v1")).unwrap();
+    db.put(Bytes::from("k2"), Bytes::from("v2")).unwrap();
+
+    // 切换为只读时已完成的写入刷写到 SST
+    db.set_read_only(true).unwrap();
+    assert!(db.is_read_only());
+    let stats = db.stats();
+    assert_eq!(stats.level_files[0], 1);
+    assert_eq!(stats.frozen_memtables, 0);
+
+    let err = db.put(Bytes::from("k3"), Bytes::from("v3")).unwrap_err();
+    assert_eq!(err.downcast_ref::<ReadOnlyError>(), Some(&ReadOnlyError));
+    assert!(db
+        .delete(Bytes::from("k1"))
+        .unwrap_err()
+        .is::<ReadOnlyError>());
+    let mut batch = WriteBatch::new();
+    batch.put(Bytes::from("k3"), Bytes::from("v3")).unwrap();
+    assert!(db
+        .write_batch(batch, &WriteOptions::default())
+        .unwrap_err()
+        .is::<ReadOnlyError>());
+    assert_eq!(db.get(&Bytes::from("k1")).unwrap(), Some(Bytes::from("v1")));
+    assert_eq!(db.get(&Bytes::from("k3")).unwrap(), None);
+
+    db.set_read_only(false).unwrap();
+    db.put(Bytes::from("k3"), Bytes::from("v3")).unwrap();
+    assert_eq!(db.get(&Bytes::from("k3")).unwrap(), Some(Bytes::from("v3")));
+
+    // 暂停自动合并时 L0 可以超过上限，恢复后重新发起合并
+    db.set_compactions_paused(true);
+    for i in 0..L0_SST_NUM_LIMIT {
+        db.put(Bytes::from(format!("p{}", i)), Bytes::from("v"))
+            .unwrap();
+        db.flush().unwrap();
+    }
+    thread::sleep(Duration::from_millis(100));
+    assert!(db.stats().level_files[0] > L0_SST_NUM_LIMIT);
+    db.set_compactions_paused(false);
+    let deadline = Instant::now() + Duration::from_secs(10);
+    while db.stats().level_files[0] > L0_SST_NUM_LIMIT && Instant::now() < deadline {
+        thread::sleep(Duration::from_millis(10));
+    }
+    assert!(db.stats().level_files[0] <= L0_SST_NUM_LIMIT);
+    assert_eq!(db.get(&Bytes::from("p0")).unwrap(), Some(Bytes::from("v")));
+    db.close().unwrap();
+}
+
+#[test]
+fn test_perf_context() {
+    INIT.call_once(setup);
+    let data_dir = tempfile::tempdir().unwrap();
+    let db = Db::open_file(data_dir.path()).unwrap();
+    for i in 0..100 {
+        db.put(
+            Bytes::from(format!("k{:03}", i)),
+            Bytes::from(vec![b'v'; 100]),
+        )
+        .unwrap();
+    }
+    db.flush().unwrap();
+
+    let scope = PerfContextScope::enter();
+    assert_eq!(db.get(&Bytes::from("k050")).unwrap().unwrap().len(), 100);
+    let first = scope.context();
+    assert_eq!(first.bloom_checks, 1);
+    assert_eq!(first.bloom_filtered, 0);
+    assert_eq!(first.block_reads, 1);
+    assert!(first.block_read_bytes > 0);
+
+    // 第二次读取命中块缓存
+    scope.reset();
+    db.get(&Bytes::from("k050")).unwrap();
+    let second = scope.context();
+    assert_eq!(second.block_reads, 0);
+    assert_eq!(second.block_cache_hits, 1);
+
+    scope.reset();
+    for i in 0..50 {
+        assert_eq!(db.get(&Bytes::from(format!("missing{}", i))).unwrap(), None);
+    }
+    let missing = scope.context();
+    assert_eq!(missing.bloom_checks, 50);
+    assert!(missing.bloom_filtered > 40);
+
+    // 扫描在调用 next 的线程上读块
+    scope.reset();
+    let mut iter = db.scan(Unbounded, Unbounded).unwrap();
+    let mut n = 0;
+    while iter.is_valid() {
+        n += 1;
+        iter.next().unwrap();
+    }
+    drop(iter);
+    assert_eq!(n, 100);
+    let scan = scope.context();
+    assert!(scan.block_reads + scan.block_cache_hits > 1);
+    assert_eq!(scan.bloom_checks, 0);
+    drop(scope);
+
+    // 不在作用域内时不计数
+    db.get(&Bytes::from("k001")).unwrap();
+    assert_eq!(crate::perf_context(), None);
+    db.close().unwrap();
+}
+
+#[test]
+fn test_periodic_compaction() {
+    INIT.call_once(setup);
+    let data_dir = tempfile::tempdir().unwrap();
+    let db = Db::open_file(data_dir.path()).unwrap();
+    for i in 0..100 {
+        db.put(Bytes::from(format!("k{:03}", i)), Bytes::from("v"))
+            .unwrap();
+    }
+    for i in 0..10 {
+        db.delete(Bytes::from(format!("k{:03}", i))).unwrap();
+    }
+    db.flush().unwrap();
+    let sst_ids = |db: &Db| -> Vec<Vec<u64>> {
+        db.inner
+            .read()
+            .levels
+            .iter()
+            .map(|ssts| ssts.iter().map(|sst| sst.id()).collect())
+            .collect()
+    };
+    let first = sst_ids(&db)[0][0];
+
+    // 过期的 SST 每次下沉一层，本次输出的新文件不会在同一次中再被重写
+    for level in 1..SST_LEVEL_LIMIT as usize {
+        assert_eq!(db.daemon.periodic_compaction(Duration::ZERO).unwrap(), 1);
+        let ids = sst_ids(&db);
+        assert_eq!(ids[level].len(), 1);
+        assert_eq!(ids.iter().map(|ssts| ssts.len()).sum::<usize>(), 1);
+    }
+    // 最后一层的 SST 在原层重写
+    let bottom = sst_ids(&db)[SST_LEVEL_LIMIT as usize - 1][0];
+    assert_eq!(db.daemon.periodic_compaction(Duration::ZERO).unwrap(), 1);
+    let rewritten = sst_ids(&db)[SST_LEVEL_LIMIT as usize - 1].clone();
+    assert_eq!(rewritten.len(), 1);
+    assert!(rewritten[0] > bottom && bottom > first);
+    assert_eq!(db.get(&Bytes::from("k005")).unwrap(), None);
+    assert_eq!(
+        db.get(&Bytes::from("k050")).unwrap(),
+        Some(Bytes::from("v"))
+    );
+
+    // 未过期或暂停自动合并时什么也不做
+    assert_eq!(
+        db.daemon
+            .periodic_compaction(Duration::from_secs(3600))
+            .unwrap(),
+        0
+    );
+    db.set_compactions_paused(true);
+    assert_eq!(db.daemon.periodic_compaction(Duration::ZERO).unwrap(), 0);
+    db.close().unwrap();
+    drop(db);
+
+    // 后台线程定期检查
+    let data_dir = tempfile::tempdir().unwrap();
+    let options = DbOptions {
+        periodic_compaction: Some(Duration::from_millis(100)),
+        ..Default::default()
+    };
+    let db = Db::open_file_with_options(data_dir.path(), options).unwrap();
+    db.put(Bytes::from("k"), Bytes::from("v")).unwrap();
+    db.flush().unwrap();
+    let deadline = Instant::now() + Duration::from_secs(10);
+    while db.stats().level_files[0] > 0 && Instant::now() < deadline {
+        thread::sleep(Duration::from_millis(20));
+    }
+    assert_eq!(db.stats().level_files[0], 0);
+    assert_eq!(db.get(&Bytes::from("k")).unwrap(), Some(Bytes::from("v")));
+    db.close().unwrap();
+}
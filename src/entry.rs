@@ -1,8 +1,104 @@
 use bytes::{Buf, BufMut, Bytes, BytesMut};
 use std::fmt::{Debug, Formatter};
+use std::mem;
+use thiserror::Error;
 
 use crate::OpType;
 
+#[derive(Error, Debug, Eq, PartialEq)]
+pub enum EntryError {
+    #[error("entry to be written must be Put or Delete, but got {0:?}")]
+    InvalidOpType(OpType),
+    #[error("delete entry must not carry a value, but got {0} bytes")]
+    DeleteWithValue(usize),
+    #[error("separated entry must store a vsst id and an optional u64 value length as value, but got {0} bytes")]
+    InvalidSeparatedValue(usize),
+    #[error("idempotency token entry must store a u128 token as value, but got {0} bytes")]
+    InvalidIdempotencyToken(usize),
+    #[error("entry meta sets reserved flag bits {0:#x}")]
+    ReservedFlags(u32),
+}
+
+/// entry meta 中除操作类型外的标记位
+///
+/// meta layout:
+/// ```text
+/// +------------------+-------------------+----------------------------+-------------------+
+/// | op type(bit 0-7) | separated(bit 8)  | idempotency token(bit 9)   | reserved(bit 10-) |
+/// +------------------+-------------------+----------------------------+-------------------+
+/// ```
+///
+/// 新增标记（如 TTL、压缩、merge）必须在这里登记一个保留位，并通过 [`EntryFlags::contains`] 读取
+#[derive(Copy, Clone, Default, Eq, PartialEq)]
+pub struct EntryFlags(u32);
+
+impl EntryFlags {
+    /// value 与 key 分离，SST 中的 value 为 [`Entry::separated_value`]
+    pub const SEPARATED: EntryFlags = EntryFlags(1 << 8);
+    /// WAL 中记录批量写入幂等 token 的 entry
+    pub const IDEMPOTENCY_TOKEN: EntryFlags = EntryFlags(1 << 9);
+
+    const OP_TYPE_MASK: u32 = 0xFF;
+    const KNOWN_MASK: u32 = Self::SEPARATED.0 | Self::IDEMPOTENCY_TOKEN.0;
+    /// 尚未登记的标记位
+    pub const RESERVED_MASK: u32 = !(Self::OP_TYPE_MASK | Self::KNOWN_MASK);
+
+    pub fn empty() -> Self {
+        EntryFlags(0)
+    }
+
+    /// 从 meta 中取出标记位，保留位原样保留
+    pub fn from_meta(meta: u32) -> Self {
+        EntryFlags(meta & !Self::OP_TYPE_MASK)
+    }
+
+    /// 从编码后的 meta 中取出标记位
+    pub fn from_encoded_meta(meta: &[u8]) -> Self {
+        Self::from_meta((&meta[..]).get_u32_le())
+    }
+
+    pub fn bits(&self) -> u32 {
+        self.0
+    }
+
+    /// 是否设置了 `other` 中的全部标记
+    pub fn contains(&self, other: EntryFlags) -> bool {
+        self.0 & other.0 == other.0
+    }
+
+    pub fn set(&mut self, other: EntryFlags, value: bool) {
+        if value {
+            self.0 |= other.0;
+        } else {
+            self.0 &= !other.0;
+        }
+    }
+
+    /// 设置了的保留位
+    pub fn reserved_bits(&self) -> u32 {
+        self.0 & Self::RESERVED_MASK
+    }
+}
+
+impl Debug for EntryFlags {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        let mut list = f.debug_list();
+        if self.contains(Self::SEPARATED) {
+            list.entry(&"SEPARATED");
+        }
+        if self.contains(Self::IDEMPOTENCY_TOKEN) {
+            list.entry(&"IDEMPOTENCY_TOKEN");
+        }
+        if self.reserved_bits() != 0 {
+            list.entry(&format_args!("RESERVED({:#x})", self.reserved_bits()));
+        }
+        list.finish()
+    }
+}
+
+const SEPARATED_VALUE_SIZE: usize = mem::size_of::<u32>() + mem::size_of::<u64>();
+const WIDE_SEPARATED_VALUE_SIZE: usize = mem::size_of::<u64>() * 2;
+
 /// `Entry` 是一次 KV 写入的打包格式
 ///
 /// layout:
@@ -24,8 +120,43 @@ impl Entry {
     }
 
     pub fn is_separate(meta: &[u8]) -> bool {
-        let meta = (&meta[..]).get_u32_le();
-        (meta >> 8) & 0x1 == 0x1
+        EntryFlags::from_encoded_meta(meta).contains(EntryFlags::SEPARATED)
+    }
+
+    /// KV 分离的 entry 在 SST 中的 value：vsst id(4 bytes) | value length(8 bytes)
+    ///
+    /// vsst id 超过 u32 时为 vsst id(8 bytes) | value length(8 bytes)
+    pub fn separated_value(vsst_id: u64, value_len: u64) -> Bytes {
+        let mut value = BytesMut::with_capacity(WIDE_SEPARATED_VALUE_SIZE);
+        match u32::try_from(vsst_id) {
+            Ok(vsst_id) => value.put_u32_le(vsst_id),
+            Err(_) => value.put_u64_le(vsst_id),
+        }
+        value.put_u64_le(value_len);
+        value.freeze()
+    }
+
+    /// 从 KV 分离的 entry 在 SST 中的 value 里取出 vsst id
+    pub fn separated_vsst_id(value: &[u8]) -> u64 {
+        match value.len() {
+            WIDE_SEPARATED_VALUE_SIZE => (&value[..]).get_u64_le(),
+            _ => (&value[..]).get_u32_le() as u64,
+        }
+    }
+
+    /// 从 KV 分离的 entry 在 SST 中的 value 里取出实际 value 的长度，旧版本只保存了 vsst id，返回 `None`
+    pub fn separated_value_len(value: &[u8]) -> Option<u64> {
+        match value.len() {
+            SEPARATED_VALUE_SIZE | WIDE_SEPARATED_VALUE_SIZE => {
+                Some((&value[value.len() - 8..]).get_u64_le())
+            }
+            _ => None,
+        }
+    }
+
+    /// 从编码后的 meta 中取出操作类型
+    pub fn op_type_of(meta: &[u8]) -> OpType {
+        OpType::from(meta[0])
     }
 
     pub fn size(&self) -> usize {
@@ -37,11 +168,52 @@ impl Entry {
     }
 
     pub fn op_type(&self) -> OpType {
-        OpType::from((self.meta & 0xFF) as u8)
+        OpType::from((self.meta & EntryFlags::OP_TYPE_MASK) as u8)
+    }
+
+    pub fn flags(&self) -> EntryFlags {
+        EntryFlags::from_meta(self.meta)
     }
 
     pub fn value_separate(&self) -> bool {
-        (self.meta >> 8) & 0x1 == 0x1
+        self.flags().contains(EntryFlags::SEPARATED)
+    }
+
+    /// 是否为 WAL 中记录批量写入幂等 token 的 entry，这种 entry 不会被写入 memtable
+    pub fn is_idempotency_token(&self) -> bool {
+        self.flags().contains(EntryFlags::IDEMPOTENCY_TOKEN)
+    }
+
+    /// 检查 entry 能否被写入 WAL / SST：操作类型只能是 Put 或 Delete，
+    /// Delete 不带 value，KV 分离的 entry 的 value 必须是 [`Entry::separated_value`] 或旧版本的 u32 vsst id，不能设置保留的标记位
+    pub fn validate(&self) -> Result<(), EntryError> {
+        let reserved = self.flags().reserved_bits();
+        if reserved != 0 {
+            return Err(EntryError::ReservedFlags(reserved));
+        }
+        match self.op_type() {
+            OpType::Put => {}
+            OpType::Delete => {
+                if !self.value.is_empty() {
+                    return Err(EntryError::DeleteWithValue(self.value.len()));
+                }
+            }
+            op_type => return Err(EntryError::InvalidOpType(op_type)),
+        }
+        if self.value_separate()
+            && ![
+                mem::size_of::<u32>(),
+                SEPARATED_VALUE_SIZE,
+                WIDE_SEPARATED_VALUE_SIZE,
+            ]
+            .contains(&self.value.len())
+        {
+            return Err(EntryError::InvalidSeparatedValue(self.value.len()));
+        }
+        if self.is_idempotency_token() && self.value.len() != mem::size_of::<u128>() {
+            return Err(EntryError::InvalidIdempotencyToken(self.value.len()));
+        }
+        Ok(())
     }
 
     pub fn encode(&self) -> Bytes {
@@ -76,7 +248,8 @@ impl Entry {
 impl Debug for Entry {
     fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
         f.debug_struct("Entry")
-            .field("meta", &self.meta)
+            .field("op type", &self.op_type())
+            .field("flags", &self.flags())
             .field("key len", &self.key.len())
             .field("key first 4 bytes", &(&self.key.get(..4)))
             .field("value len", &self.value.len())
@@ -98,16 +271,22 @@ impl EntryBuilder {
     }
 
     pub fn op_type(&mut self, op_type: OpType) -> &mut Self {
-        self.meta |= op_type.encode() as u32;
+        self.meta = (self.meta & !EntryFlags::OP_TYPE_MASK) | op_type.encode() as u32;
         self
     }
 
     pub fn kv_separate(&mut self, separate: bool) -> &mut Self {
-        if separate {
-            self.meta |= 1 << 8
-        } else {
-            self.meta &= !(1 << 8);
-        }
+        self.set_flag(EntryFlags::SEPARATED, separate)
+    }
+
+    pub fn idempotency_token(&mut self, token: bool) -> &mut Self {
+        self.set_flag(EntryFlags::IDEMPOTENCY_TOKEN, token)
+    }
+
+    fn set_flag(&mut self, flag: EntryFlags, value: bool) -> &mut Self {
+        let mut flags = EntryFlags::from_meta(self.meta);
+        flags.set(flag, value);
+        self.meta = (self.meta & EntryFlags::OP_TYPE_MASK) | flags.bits();
         self
     }
 
@@ -121,6 +300,13 @@ impl EntryBuilder {
         Entry::new(self.meta, self.key.clone(), self.value.clone())
     }
 
+    /// 构建一个会被写入 WAL / SST 的 entry，不满足 [`Entry::validate`] 时返回错误
+    pub fn try_build(&self) -> Result<Entry, EntryError> {
+        let entry = self.build();
+        entry.validate()?;
+        Ok(entry)
+    }
+
     pub fn empty() -> Entry {
         Entry::new(
             0,
@@ -137,9 +323,9 @@ pub mod tests {
     use rand::distributions::{Alphanumeric, DistString};
     use rand::{thread_rng, Rng};
 
-    use crate::entry::{Entry, EntryBuilder};
+    use crate::entry::{Entry, EntryBuilder, EntryError, EntryFlags};
 
-    use crate::OpType::Get;
+    use crate::OpType::{Delete, Get, Put};
 
     pub fn rand_gen_entry() -> (Bytes, Bytes, Entry) {
         let rand_str = || -> String {
@@ -175,6 +361,128 @@ pub mod tests {
         assert_eq!(entry, entry2)
     }
 
+    #[test]
+    fn test_entry_validate() {
+        let key = Bytes::from("k");
+        let value = Bytes::from("v");
+
+        let err = EntryBuilder::new()
+            .op_type(Get)
+            .key_value(key.clone(), value.clone())
+            .try_build()
+            .unwrap_err();
+        assert_eq!(err, EntryError::InvalidOpType(Get));
+
+        let err = EntryBuilder::new()
+            .op_type(Delete)
+            .key_value(key.clone(), value.clone())
+            .try_build()
+            .unwrap_err();
+        assert_eq!(err, EntryError::DeleteWithValue(1));
+
+        let err = EntryBuilder::new()
+            .op_type(Put)
+            .kv_separate(true)
+            .key_value(key.clone(), value.clone())
+            .try_build()
+            .unwrap_err();
+        assert_eq!(err, EntryError::InvalidSeparatedValue(1));
+
+        assert!(EntryBuilder::new()
+            .op_type(Put)
+            .kv_separate(true)
+            .key_value(key.clone(), Bytes::from(1u32.to_le_bytes().to_vec()))
+            .try_build()
+            .is_ok());
+        let separated = Entry::separated_value(1, 4096);
+        assert_eq!(Entry::separated_value_len(&separated), Some(4096));
+        assert_eq!(Entry::separated_value_len(&1u32.to_le_bytes()), None);
+        assert_eq!(Entry::separated_vsst_id(&separated), 1);
+        assert_eq!(Entry::separated_vsst_id(&1u32.to_le_bytes()), 1);
+        // vsst id 超过 u32 时以 u64 保存
+        let wide_id = u32::MAX as u64 + 7;
+        let wide = Entry::separated_value(wide_id, 4096);
+        assert_eq!(wide.len(), 16);
+        assert_eq!(Entry::separated_vsst_id(&wide), wide_id);
+        assert_eq!(Entry::separated_value_len(&wide), Some(4096));
+        assert!(EntryBuilder::new()
+            .op_type(Put)
+            .kv_separate(true)
+            .key_value(key.clone(), wide)
+            .try_build()
+            .is_ok());
+        assert!(EntryBuilder::new()
+            .op_type(Put)
+            .kv_separate(true)
+            .key_value(key.clone(), separated)
+            .try_build()
+            .is_ok());
+        assert!(EntryBuilder::new()
+            .op_type(Delete)
+            .key_value(key, Bytes::new())
+            .try_build()
+            .is_ok());
+    }
+
+    #[test]
+    fn test_entry_flags() {
+        let key = Bytes::from("k");
+        for op_type in [Put, Delete, Get] {
+            // 操作类型的编码不能被误认为标记
+            let entry = EntryBuilder::new()
+                .op_type(op_type)
+                .key_value(key.clone(), Bytes::new())
+                .build();
+            assert_eq!(entry.flags(), EntryFlags::empty());
+            assert!(!entry.value_separate());
+            assert!(!entry.is_idempotency_token());
+            assert!(!Entry::is_separate(&entry.encode()));
+
+            for (separate, token) in [(true, false), (false, true), (true, true)] {
+                let entry = EntryBuilder::new()
+                    .op_type(op_type)
+                    .kv_separate(separate)
+                    .idempotency_token(token)
+                    .key_value(key.clone(), Bytes::new())
+                    .build();
+                let decoded = Entry::decode(&entry.encode());
+                assert_eq!(decoded.op_type(), op_type);
+                assert_eq!(decoded.value_separate(), separate);
+                assert_eq!(decoded.is_idempotency_token(), token);
+                assert_eq!(Entry::is_separate(&entry.encode()), separate);
+                assert_eq!(decoded.flags().reserved_bits(), 0);
+            }
+        }
+
+        // 重复设置操作类型和标记时以最后一次为准
+        let entry = EntryBuilder::new()
+            .op_type(Put)
+            .kv_separate(true)
+            .op_type(Delete)
+            .kv_separate(false)
+            .key_value(key.clone(), Bytes::new())
+            .build();
+        assert_eq!(entry.op_type(), Delete);
+        assert!(!entry.value_separate());
+
+        let mut flags = EntryFlags::from_meta(Put.encode() as u32 | 1 << 8 | 1 << 12);
+        assert!(flags.contains(EntryFlags::SEPARATED));
+        assert_eq!(flags.reserved_bits(), 1 << 12);
+        flags.set(EntryFlags::SEPARATED, false);
+        assert_eq!(flags.bits(), 1 << 12);
+        assert_eq!(
+            EntryFlags::RESERVED_MASK & (EntryFlags::SEPARATED.bits() | 0xFF),
+            0
+        );
+
+        let entry = Entry {
+            meta: Put.encode() as u32 | 1 << 12,
+            key,
+            value: Bytes::new(),
+        };
+        assert_eq!(entry.validate(), Err(EntryError::ReservedFlags(1 << 12)));
+    }
+
     #[test]
     fn test_entry_empty_value() {
         let key = Bytes::from("test_key");
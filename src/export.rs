@@ -0,0 +1,188 @@
+use std::fs::{self, OpenOptions};
+use std::io::Write;
+use std::os::unix::ffi::OsStrExt;
+use std::path::Path;
+
+use anyhow::anyhow;
+use bytes::Bytes;
+use tracing::info;
+
+use crate::cooperative::LoopCheckpoint;
+use crate::entry::{Entry, EntryBuilder};
+use crate::iterator::StorageIterator;
+use crate::meta::manifest::{Manifest, ManifestActor, ManifestItem, RecordOrigin};
+use crate::record::RecordBuilder;
+use crate::snapshot::Snapshot;
+use crate::sstable::builder::SsTableBuilder;
+use crate::storage::file;
+use crate::storage::header::FileType;
+use crate::{Db, DbOptions, OpType, MAX_SST_SIZE, SST_LEVEL_LIMIT};
+
+/// [`Db::export_snapshot`] 导出的文件和数据量
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ExportReport {
+    /// 导出的 SST id，都在最后一层，按 key 升序排列
+    pub ssts: Vec<u64>,
+    /// 保存分离的 value 的 VSST id
+    pub vssts: Vec<u64>,
+    /// 导出的 key 数量
+    pub keys: u64,
+    /// 导出的 key 和 value 的总字节数
+    pub bytes: u64,
+}
+
+/// 正在写入的一个 SST 和它引用的 VSST
+struct ExportFile {
+    sst: SsTableBuilder,
+    vsst: SsTableBuilder,
+}
+
+impl ExportFile {
+    fn new(options: &DbOptions) -> Self {
+        Self {
+            sst: SsTableBuilder::with_compression(
+                options.compaction_compression,
+                options.zstd_dict_size,
+            )
+            .with_block_size(options.block_size),
+            vsst: SsTableBuilder::new()
+                .with_block_size(options.vsst_block_size)
+                .with_file_type(FileType::VSst),
+        }
+    }
+}
+
+/// 导出过程中的状态，输出的 SST 和 VSST 都从 1 开始编号
+struct Exporter<'a> {
+    dir: &'a Path,
+    max_seq: u64,
+    separation_threshold: u64,
+    /// 输出 SST 的压缩方式和块大小
+    options: &'a DbOptions,
+    file: ExportFile,
+    report: ExportReport,
+    vssts: Vec<(u64, u32)>,
+}
+
+impl Exporter<'_> {
+    fn add(&mut self, key: &[u8], value: &[u8]) -> anyhow::Result<()> {
+        let vsst_id = self.report.vssts.len() as u64 + 1;
+        let entry = EntryBuilder::new()
+            .op_type(OpType::Put)
+            .key_value(Bytes::copy_from_slice(key), Bytes::copy_from_slice(value))
+            .build();
+        if value.len() as u64 > self.separation_threshold {
+            let sst_entry = EntryBuilder::new()
+                .op_type(OpType::Put)
+                .kv_separate(true)
+                .key_value(
+                    entry.key.clone(),
+                    Entry::separated_value(vsst_id, value.len() as u64),
+                )
+                .build();
+            self.file.sst.add(&sst_entry);
+            self.file.vsst.add(&entry);
+        } else {
+            self.file.sst.add(&entry);
+        }
+        self.report.keys += 1;
+        self.report.bytes += (key.len() + value.len()) as u64;
+        if self.file.sst.size() >= MAX_SST_SIZE as usize {
+            self.finish_file()?;
+        }
+        Ok(())
+    }
+
+    /// 写出当前的 SST 和它引用的 VSST 并落盘
+    fn finish_file(&mut self) -> anyhow::Result<()> {
+        let file = std::mem::replace(&mut self.file, ExportFile::new(self.options));
+        if file.sst.is_empty() {
+            return Ok(());
+        }
+        if !file.vsst.is_empty() {
+            let vsst_id = self.report.vssts.len() as u64 + 1;
+            let vsst = file
+                .vsst
+                .build(vsst_id, None, Db::path_of_vsst(self.dir, vsst_id))?;
+            vsst.sync_all()?;
+            self.vssts.push((vsst_id, vsst.num_of_pairs() as u32));
+            self.report.vssts.push(vsst_id);
+        }
+        let sst_id = self.report.ssts.len() as u64 + 1;
+        let sst = file.sst.with_max_seq(self.max_seq).build(
+            sst_id,
+            None,
+            Db::path_of_sst(self.dir, sst_id),
+        )?;
+        sst.sync_all()?;
+        self.report.ssts.push(sst_id);
+        Ok(())
+    }
+
+    /// 写入引用所有输出文件的 MANIFEST 和 CURRENT，之后 `dir` 才能作为数据库打开
+    fn finish(mut self) -> anyhow::Result<ExportReport> {
+        self.finish_file()?;
+        let manifest_path = Db::path_of_manifest(self.dir, 1);
+        let mut manifest = Manifest::open(&manifest_path)?;
+        manifest.set_strict_sync(true);
+        let mut r = RecordBuilder::new();
+        r.add(ManifestItem::Init(1));
+        r.add(ManifestItem::Origin(RecordOrigin::now(
+            ManifestActor::Manual,
+        )));
+        r.add(ManifestItem::FreezeAndCreateWal(0, 0));
+        r.add(ManifestItem::CommitSeq(self.max_seq));
+        for sst_id in &self.report.ssts {
+            r.add(ManifestItem::NewSst(SST_LEVEL_LIMIT - 1, *sst_id));
+        }
+        for (vsst_id, rc) in &self.vssts {
+            r.add(ManifestItem::NewVSst(*vsst_id));
+            r.add(ManifestItem::VSstRefCnt(*vsst_id, *rc));
+        }
+        manifest.add(&r.build())?;
+
+        let mut current = OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .open(Db::path_of_current(self.dir))?;
+        current.write_all(manifest_path.file_name().unwrap().as_bytes())?;
+        current.sync_all()?;
+        file::sync_dir(self.dir)?;
+        Ok(self.report)
+    }
+}
+
+/// 把快照中可见的所有 key 的最新值写入 `dir`，见 [`Db::export_snapshot`]
+pub(crate) fn export_snapshot(
+    snapshot: &Snapshot,
+    dir: &Path,
+    separation_threshold: Option<u64>,
+    options: &DbOptions,
+) -> anyhow::Result<ExportReport> {
+    if Db::path_of_current(dir).exists() {
+        return Err(anyhow!("{:?} already contains a database", dir));
+    }
+    fs::create_dir_all(dir)?;
+    let mut exporter = Exporter {
+        dir,
+        max_seq: snapshot.inner().commit_seq.last_allocated(),
+        separation_threshold: separation_threshold.unwrap_or(u64::MAX),
+        options,
+        file: ExportFile::new(options),
+        report: ExportReport::default(),
+        vssts: vec![],
+    };
+    let mut checkpoint = LoopCheckpoint::new("export");
+    let mut iter = snapshot.scan(std::ops::Bound::Unbounded, std::ops::Bound::Unbounded)?;
+    while iter.is_valid() {
+        checkpoint.tick();
+        exporter.add(iter.key(), iter.value())?;
+        iter.next()?;
+    }
+    let report = exporter.finish()?;
+    info!(
+        "export {} keys ({} bytes) to {:?}: SSTs {:?}, VSSTs {:?}",
+        report.keys, report.bytes, dir, report.ssts, report.vssts
+    );
+    Ok(report)
+}
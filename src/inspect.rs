@@ -0,0 +1,184 @@
+use std::collections::BTreeMap;
+use std::sync::Arc;
+
+use parking_lot::{Mutex, RwLock};
+
+use crate::system::is_system_key;
+
+/// value 检查回调记录的指标，按名字累加，见 [`crate::Db::add_value_inspector`]
+#[derive(Debug, Default)]
+pub struct ValueMetrics {
+    counts: BTreeMap<String, u64>,
+}
+
+impl ValueMetrics {
+    /// 把名为 `metric` 的指标加上 `delta`
+    pub fn add(&mut self, metric: &str, delta: u64) {
+        match self.counts.get_mut(metric) {
+            Some(count) => *count += delta,
+            None => {
+                self.counts.insert(metric.to_string(), delta);
+            }
+        }
+    }
+}
+
+type Inspector = Arc<dyn Fn(&[u8], &[u8], &mut ValueMetrics) + Send + Sync>;
+
+/// 按注册顺序排列的 value 检查回调，以及它们打开以来累计的指标，不持久化
+pub(crate) struct ValueInspectors {
+    inspectors: RwLock<Vec<(String, Inspector)>>,
+    // 每 n 个 entry 检查一次
+    sampling: u32,
+    metrics: Mutex<BTreeMap<String, BTreeMap<String, u64>>>,
+}
+
+impl ValueInspectors {
+    pub(crate) fn new(sampling: u32) -> Self {
+        Self {
+            inspectors: RwLock::new(vec![]),
+            sampling: sampling.max(1),
+            metrics: Mutex::new(BTreeMap::new()),
+        }
+    }
+
+    /// 同名的回调已存在时替换它，保留已累计的指标
+    pub(crate) fn add(&self, name: String, inspector: Inspector) {
+        let mut inspectors = self.inspectors.write();
+        match inspectors.iter_mut().find(|(other, _)| *other == name) {
+            Some((_, existing)) => *existing = inspector,
+            None => inspectors.push((name, inspector)),
+        }
+    }
+
+    /// 移除回调和它累计的指标
+    pub(crate) fn remove(&self, name: &str) -> bool {
+        let mut inspectors = self.inspectors.write();
+        let len = inspectors.len();
+        inspectors.retain(|(other, _)| other != name);
+        self.metrics.lock().remove(name);
+        inspectors.len() < len
+    }
+
+    /// 各回调累计的指标，以回调的名字为 key，还没有记录任何指标的回调不在其中
+    pub(crate) fn metrics(&self) -> BTreeMap<String, BTreeMap<String, u64>> {
+        self.metrics.lock().clone()
+    }
+
+    /// 开始一次刷写或合并的检查，复制出当前注册的回调
+    pub(crate) fn start(&self) -> Inspection {
+        let inspectors = self.inspectors.read();
+        Inspection {
+            inspectors: inspectors
+                .iter()
+                .map(|(name, inspector)| (name.clone(), inspector.clone(), ValueMetrics::default()))
+                .collect(),
+            sampling: self.sampling,
+            seen: 0,
+        }
+    }
+
+    /// 把一次检查记录的指标计入累计值，检查期间已被移除的回调的指标丢弃
+    pub(crate) fn finish(&self, inspection: Inspection) {
+        let registered = self.inspectors.read();
+        let mut metrics = self.metrics.lock();
+        for (name, _, recorded) in inspection.inspectors {
+            let removed = !registered.iter().any(|(other, _)| *other == name);
+            if removed || recorded.counts.is_empty() {
+                continue;
+            }
+            let total = metrics.entry(name).or_default();
+            for (metric, delta) in recorded.counts {
+                *total.entry(metric).or_default() += delta;
+            }
+        }
+    }
+}
+
+impl std::fmt::Debug for ValueInspectors {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let inspectors = self.inspectors.read();
+        f.debug_list()
+            .entries(inspectors.iter().map(|(name, _)| name))
+            .finish()
+    }
+}
+
+/// 一次刷写或合并中对写出的 entry 的抽样检查，见 [`ValueInspectors::start`]
+pub(crate) struct Inspection {
+    inspectors: Vec<(String, Inspector, ValueMetrics)>,
+    sampling: u32,
+    // 已经过的 entry 数量
+    seen: u64,
+}
+
+impl Inspection {
+    /// 不调用任何回调的检查，用于不属于数据库的合并，例如外部合并任务
+    pub(crate) fn disabled() -> Self {
+        Self {
+            inspectors: vec![],
+            sampling: 1,
+            seen: 0,
+        }
+    }
+
+    /// 每 `sampling` 个 entry 把其中第一个交给所有回调，删除标记和系统 key 不计入
+    pub(crate) fn inspect(&mut self, key: &[u8], value: &[u8]) {
+        if self.inspectors.is_empty() || is_system_key(key) {
+            return;
+        }
+        self.seen += 1;
+        if !(self.seen - 1).is_multiple_of(self.sampling as u64) {
+            return;
+        }
+        for (_, inspector, metrics) in &mut self.inspectors {
+            inspector(key, value, metrics);
+        }
+    }
+}
+
+impl std::fmt::Debug for Inspection {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Inspection")
+            .field("inspectors", &self.inspectors.len())
+            .field("sampling", &self.sampling)
+            .field("seen", &self.seen)
+            .finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use crate::inspect::ValueInspectors;
+    use crate::system::system_key;
+
+    #[test]
+    fn test_value_inspectors() {
+        let inspectors = ValueInspectors::new(2);
+        inspectors.add(
+            "type".to_string(),
+            Arc::new(|_key, value, metrics| metrics.add(&format!("{}", value[0] as char), 1)),
+        );
+        let mut inspection = inspectors.start();
+        for value in [b"a1", b"b1", b"a2", b"b2", b"a3"] {
+            inspection.inspect(b"k", value);
+        }
+        // 系统 key 不计入抽样
+        inspection.inspect(&system_key("test", b"k"), b"b3");
+        inspection.inspect(b"k", b"b4");
+        inspectors.finish(inspection);
+        let metrics = inspectors.metrics();
+        assert_eq!(metrics["type"].get("a"), Some(&3));
+        assert_eq!(metrics["type"].get("b"), None);
+
+        // 检查期间移除的回调不再计入
+        let mut inspection = inspectors.start();
+        inspection.inspect(b"k", b"a");
+        assert!(inspectors.remove("type"));
+        inspectors.finish(inspection);
+        assert!(inspectors.metrics().is_empty());
+        assert!(!inspectors.remove("type"));
+    }
+}
@@ -0,0 +1,250 @@
+use std::ops::Bound;
+
+use anyhow::Result;
+use bytes::Bytes;
+
+use super::StorageIterator;
+use crate::keys::prefix_upper_bound;
+
+/// [`StorageIterator`] 的组合器，直接在迭代器上跳过和截断，不复制 key 和 value
+///
+/// 底层迭代器必须按 key 有序。所有组合器读完后保持无效，此时调用 `next` 什么也不做
+pub trait StorageIteratorExt: StorageIterator + Sized {
+    /// 只保留以 `prefix` 开头的 key，越过前缀后立即结束，不再读取之后的数据
+    fn filter_prefix(self, prefix: impl AsRef<[u8]>) -> Result<RangeIterator<Self>> {
+        let prefix = prefix.as_ref();
+        RangeIterator::create(
+            self,
+            Bound::Included(Bytes::copy_from_slice(prefix)),
+            prefix_upper_bound(prefix),
+        )
+    }
+
+    /// 只保留 `lower` 和 `upper` 之间的 key
+    ///
+    /// `lower` 之前的 key 逐个跳过，能在创建底层迭代器时定位的应直接使用带范围的扫描
+    fn filter_range(self, lower: Bound<Bytes>, upper: Bound<Bytes>) -> Result<RangeIterator<Self>> {
+        RangeIterator::create(self, lower, upper)
+    }
+
+    /// 只保留 `predicate(key, value)` 为 true 的项
+    fn filter_entries<F>(self, predicate: F) -> Result<FilterIterator<Self, F>>
+    where
+        F: FnMut(&[u8], &[u8]) -> bool,
+    {
+        FilterIterator::create(self, predicate)
+    }
+
+    /// 最多返回 `n` 项，之后不再读取底层迭代器
+    fn take_entries(self, n: usize) -> TakeIterator<Self> {
+        TakeIterator::new(self, n)
+    }
+
+    /// 从第一项开始每 `step` 项返回一项，用于抽样，`step` 必须大于 0
+    fn step_entries(self, step: usize) -> StepIterator<Self> {
+        StepIterator::new(self, step)
+    }
+}
+
+impl<I: StorageIterator> StorageIteratorExt for I {}
+
+/// 见 [`StorageIteratorExt::filter_range`] 和 [`StorageIteratorExt::filter_prefix`]
+pub struct RangeIterator<I: StorageIterator> {
+    iter: I,
+    upper: Bound<Bytes>,
+    is_valid: bool,
+}
+
+impl<I: StorageIterator> RangeIterator<I> {
+    fn create(mut iter: I, lower: Bound<Bytes>, upper: Bound<Bytes>) -> Result<Self> {
+        while iter.is_valid()
+            && match &lower {
+                Bound::Included(key) => iter.key() < key.as_ref(),
+                Bound::Excluded(key) => iter.key() <= key.as_ref(),
+                Bound::Unbounded => false,
+            }
+        {
+            iter.next()?;
+        }
+        let mut range = Self {
+            iter,
+            upper,
+            is_valid: false,
+        };
+        range.check_upper();
+        Ok(range)
+    }
+
+    fn check_upper(&mut self) {
+        self.is_valid = self.iter.is_valid()
+            && match &self.upper {
+                Bound::Included(key) => self.iter.key() <= key.as_ref(),
+                Bound::Excluded(key) => self.iter.key() < key.as_ref(),
+                Bound::Unbounded => true,
+            };
+    }
+}
+
+impl<I: StorageIterator> StorageIterator for RangeIterator<I> {
+    fn meta(&self) -> &[u8] {
+        self.iter.meta()
+    }
+
+    fn key(&self) -> &[u8] {
+        self.iter.key()
+    }
+
+    fn value(&self) -> &[u8] {
+        self.iter.value()
+    }
+
+    fn is_valid(&self) -> bool {
+        self.is_valid
+    }
+
+    fn next(&mut self) -> Result<()> {
+        if self.is_valid {
+            self.iter.next()?;
+            self.check_upper();
+        }
+        Ok(())
+    }
+}
+
+/// 见 [`StorageIteratorExt::filter_entries`]
+pub struct FilterIterator<I: StorageIterator, F> {
+    iter: I,
+    predicate: F,
+}
+
+impl<I, F> FilterIterator<I, F>
+where
+    I: StorageIterator,
+    F: FnMut(&[u8], &[u8]) -> bool,
+{
+    fn create(iter: I, predicate: F) -> Result<Self> {
+        let mut filter = Self { iter, predicate };
+        filter.skip_rejected()?;
+        Ok(filter)
+    }
+
+    fn skip_rejected(&mut self) -> Result<()> {
+        while self.iter.is_valid() && !(self.predicate)(self.iter.key(), self.iter.value()) {
+            self.iter.next()?;
+        }
+        Ok(())
+    }
+}
+
+impl<I, F> StorageIterator for FilterIterator<I, F>
+where
+    I: StorageIterator,
+    F: FnMut(&[u8], &[u8]) -> bool,
+{
+    fn meta(&self) -> &[u8] {
+        self.iter.meta()
+    }
+
+    fn key(&self) -> &[u8] {
+        self.iter.key()
+    }
+
+    fn value(&self) -> &[u8] {
+        self.iter.value()
+    }
+
+    fn is_valid(&self) -> bool {
+        self.iter.is_valid()
+    }
+
+    fn next(&mut self) -> Result<()> {
+        if self.iter.is_valid() {
+            self.iter.next()?;
+            self.skip_rejected()?;
+        }
+        Ok(())
+    }
+}
+
+/// 见 [`StorageIteratorExt::take_entries`]
+pub struct TakeIterator<I: StorageIterator> {
+    iter: I,
+    remaining: usize,
+}
+
+impl<I: StorageIterator> TakeIterator<I> {
+    fn new(iter: I, n: usize) -> Self {
+        Self { iter, remaining: n }
+    }
+}
+
+impl<I: StorageIterator> StorageIterator for TakeIterator<I> {
+    fn meta(&self) -> &[u8] {
+        self.iter.meta()
+    }
+
+    fn key(&self) -> &[u8] {
+        self.iter.key()
+    }
+
+    fn value(&self) -> &[u8] {
+        self.iter.value()
+    }
+
+    fn is_valid(&self) -> bool {
+        self.remaining > 0 && self.iter.is_valid()
+    }
+
+    fn next(&mut self) -> Result<()> {
+        if !self.is_valid() {
+            return Ok(());
+        }
+        self.remaining -= 1;
+        // 取够后不再移动底层迭代器，避免多读一个块
+        if self.remaining > 0 {
+            self.iter.next()?;
+        }
+        Ok(())
+    }
+}
+
+/// 见 [`StorageIteratorExt::step_entries`]
+pub struct StepIterator<I: StorageIterator> {
+    iter: I,
+    step: usize,
+}
+
+impl<I: StorageIterator> StepIterator<I> {
+    fn new(iter: I, step: usize) -> Self {
+        assert!(step > 0, "step must be greater than 0");
+        Self { iter, step }
+    }
+}
+
+impl<I: StorageIterator> StorageIterator for StepIterator<I> {
+    fn meta(&self) -> &[u8] {
+        self.iter.meta()
+    }
+
+    fn key(&self) -> &[u8] {
+        self.iter.key()
+    }
+
+    fn value(&self) -> &[u8] {
+        self.iter.value()
+    }
+
+    fn is_valid(&self) -> bool {
+        self.iter.is_valid()
+    }
+
+    fn next(&mut self) -> Result<()> {
+        for _ in 0..self.step {
+            if !self.iter.is_valid() {
+                break;
+            }
+            self.iter.next()?;
+        }
+        Ok(())
+    }
+}
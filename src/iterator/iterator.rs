@@ -1,10 +1,17 @@
 use anyhow::Result;
+use bytes::Bytes;
 use thiserror::Error;
 
 #[derive(Error, Debug)]
 pub enum StorageIteratorError {
     #[error("unknown iterator error")]
     Unknown,
+    #[error("{name} iterator key out of order: {key:?} after {prev:?}")]
+    OutOfOrder {
+        name: &'static str,
+        prev: Bytes,
+        key: Bytes,
+    },
 }
 
 pub trait StorageIterator {
@@ -21,4 +28,14 @@ pub trait StorageIterator {
 
     /// Move to the next position.
     fn next(&mut self) -> Result<()>;
+
+    /// 是否尚未创建，此时 `key` 只是第一个 key 的下界，`meta` 和 `value` 不可用，见 [`super::lazy_iterator::LazyIterator`]
+    fn is_pending(&self) -> bool {
+        false
+    }
+
+    /// 创建尚未创建的迭代器，之后 `key` 为实际的第一个 key，可能变大或失效
+    fn materialize(&mut self) -> Result<()> {
+        Ok(())
+    }
 }
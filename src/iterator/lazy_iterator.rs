@@ -0,0 +1,70 @@
+use anyhow::Result;
+use bytes::Bytes;
+
+use super::StorageIterator;
+
+type Open<I> = Box<dyn FnOnce() -> Result<I> + Send + Sync>;
+
+/// 延迟创建的迭代器，用于合并大量 SST 时避免在读出第一个结果前就打开并定位所有 SST
+///
+/// 创建前以 `first_key`（数据中第一个 key 的下界）参与 [`super::merge_iterator::MergeIterator`] 的排序，
+/// 直到合并需要它的数据时才创建，见 [`StorageIterator::is_pending`]
+pub struct LazyIterator<I: StorageIterator> {
+    first_key: Bytes,
+    open: Option<Open<I>>,
+    iter: Option<I>,
+}
+
+impl<I: StorageIterator> LazyIterator<I> {
+    /// `open` 创建的迭代器的第一个 key 必须不小于 `first_key`
+    pub fn new(first_key: Bytes, open: impl FnOnce() -> Result<I> + Send + Sync + 'static) -> Self {
+        Self {
+            first_key,
+            open: Some(Box::new(open)),
+            iter: None,
+        }
+    }
+
+    fn inner(&self) -> &I {
+        self.iter
+            .as_ref()
+            .expect("lazy iterator is used before materialized")
+    }
+}
+
+impl<I: StorageIterator> StorageIterator for LazyIterator<I> {
+    fn meta(&self) -> &[u8] {
+        self.inner().meta()
+    }
+
+    fn key(&self) -> &[u8] {
+        match &self.iter {
+            Some(iter) => iter.key(),
+            None => &self.first_key,
+        }
+    }
+
+    fn value(&self) -> &[u8] {
+        self.inner().value()
+    }
+
+    fn is_valid(&self) -> bool {
+        self.iter.as_ref().is_none_or(|iter| iter.is_valid())
+    }
+
+    fn next(&mut self) -> Result<()> {
+        self.materialize()?;
+        self.iter.as_mut().unwrap().next()
+    }
+
+    fn is_pending(&self) -> bool {
+        self.iter.is_none()
+    }
+
+    fn materialize(&mut self) -> Result<()> {
+        if let Some(open) = self.open.take() {
+            self.iter = Some(open()?);
+        }
+        Ok(())
+    }
+}
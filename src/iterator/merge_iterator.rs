@@ -71,6 +71,39 @@ impl<I: StorageIterator> MergeIterator<I> {
             current: Some(current),
         }
     }
+
+    /// 合并可能尚未创建的迭代器，只创建排在最前面的迭代器，其余的等到合并需要它们的数据时才创建，
+    /// 见 [`super::lazy_iterator::LazyIterator`]
+    pub fn create_lazy(iters: Vec<Box<I>>) -> Result<Self> {
+        let mut iter = Self::create(iters);
+        iter.settle()?;
+        Ok(iter)
+    }
+
+    /// 当前迭代器尚未创建时创建它，创建后 key 可能变大或失效，与堆顶比较后重新选择，直到当前迭代器已创建
+    fn settle(&mut self) -> Result<()> {
+        loop {
+            let Some(current) = self.current.as_mut() else {
+                return Ok(());
+            };
+            if !current.1.is_pending() {
+                return Ok(());
+            }
+            current.1.materialize()?;
+            if !current.1.is_valid() {
+                match self.iters.pop() {
+                    Some(iter) => *current = iter,
+                    None => return Ok(()),
+                }
+                continue;
+            }
+            if let Some(mut inner_iter) = self.iters.peek_mut() {
+                if *current < *inner_iter {
+                    std::mem::swap(&mut *inner_iter, current);
+                }
+            }
+        }
+    }
 }
 
 impl<I: StorageIterator> StorageIterator for MergeIterator<I> {
@@ -104,6 +137,21 @@ impl<I: StorageIterator> StorageIterator for MergeIterator<I> {
                 "heap invariant violated"
             );
             if inner_iter.1.key() == current.1.key() {
+                // 尚未创建的迭代器的 key 只是下界，创建后再判断是否与当前 key 相同
+                if inner_iter.1.is_pending() {
+                    if let e @ Err(_) = inner_iter.1.materialize() {
+                        PeekMut::pop(inner_iter);
+                        return e;
+                    }
+                    if !inner_iter.1.is_valid() {
+                        PeekMut::pop(inner_iter);
+                        continue;
+                    }
+                    if inner_iter.1.key() != current.1.key() {
+                        continue;
+                    }
+                }
+
                 // Case 1: an error occurred when calling `next`.
                 if let e @ Err(_) = inner_iter.1.next() {
                     PeekMut::pop(inner_iter);
@@ -126,7 +174,7 @@ impl<I: StorageIterator> StorageIterator for MergeIterator<I> {
             if let Some(iter) = self.iters.pop() {
                 *current = iter;
             }
-            return Ok(());
+            return self.settle();
         }
 
         // Otherwise, compare with heap top and swap if necessary.
@@ -136,6 +184,6 @@ impl<I: StorageIterator> StorageIterator for MergeIterator<I> {
             }
         }
 
-        Ok(())
+        self.settle()
     }
 }
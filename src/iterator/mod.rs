@@ -1,9 +1,40 @@
+pub mod adapters;
 pub mod iterator;
+pub mod lazy_iterator;
 pub mod merge_iterator;
+#[cfg(feature = "check-order")]
+pub mod order_check_iterator;
 pub mod rc_merge_iterator;
 pub mod two_merge_iterator;
 
 pub use iterator::*;
 
+/// 开启 `check-order` feature 时为检查 key 顺序的包装，否则就是迭代器本身
+#[cfg(feature = "check-order")]
+pub type Checked<I> = order_check_iterator::OrderCheckIterator<I>;
+#[cfg(not(feature = "check-order"))]
+pub type Checked<I> = I;
+
+/// 开启 `check-order` feature 时为 `iter` 包上顺序检查，`allow_duplicate` 表示是否允许相邻的 key 相同；
+/// 未开启时原样返回
+#[cfg(feature = "check-order")]
+pub fn checked<I: StorageIterator>(
+    iter: I,
+    name: &'static str,
+    allow_duplicate: bool,
+) -> Checked<I> {
+    order_check_iterator::OrderCheckIterator::create(iter, name, allow_duplicate)
+}
+
+#[cfg(not(feature = "check-order"))]
+#[inline(always)]
+pub fn checked<I: StorageIterator>(
+    iter: I,
+    _name: &'static str,
+    _allow_duplicate: bool,
+) -> Checked<I> {
+    iter
+}
+
 #[cfg(test)]
 mod tests;
@@ -0,0 +1,66 @@
+use std::cmp::Ordering;
+
+use anyhow::Result;
+use bytes::Bytes;
+
+use super::{StorageIterator, StorageIteratorError};
+
+/// 检查 key 顺序的迭代器包装，key 变小（或不允许重复时出现相同的 key）时 `next` 返回
+/// [`StorageIteratorError::OutOfOrder`]，用于尽早发现合并、seek 中的问题
+pub struct OrderCheckIterator<I: StorageIterator> {
+    iter: I,
+    name: &'static str,
+    allow_duplicate: bool,
+    prev_key: Vec<u8>,
+}
+
+impl<I: StorageIterator> OrderCheckIterator<I> {
+    pub fn create(iter: I, name: &'static str, allow_duplicate: bool) -> Self {
+        Self {
+            iter,
+            name,
+            allow_duplicate,
+            prev_key: vec![],
+        }
+    }
+}
+
+impl<I: StorageIterator> StorageIterator for OrderCheckIterator<I> {
+    fn meta(&self) -> &[u8] {
+        self.iter.meta()
+    }
+
+    fn key(&self) -> &[u8] {
+        self.iter.key()
+    }
+
+    fn value(&self) -> &[u8] {
+        self.iter.value()
+    }
+
+    fn is_valid(&self) -> bool {
+        self.iter.is_valid()
+    }
+
+    fn next(&mut self) -> Result<()> {
+        if !self.iter.is_valid() {
+            return self.iter.next();
+        }
+        self.prev_key.clear();
+        self.prev_key.extend_from_slice(self.iter.key());
+        self.iter.next()?;
+        if !self.iter.is_valid() {
+            return Ok(());
+        }
+        match self.iter.key().cmp(&self.prev_key) {
+            Ordering::Greater => Ok(()),
+            Ordering::Equal if self.allow_duplicate => Ok(()),
+            _ => Err(StorageIteratorError::OutOfOrder {
+                name: self.name,
+                prev: Bytes::copy_from_slice(&self.prev_key),
+                key: Bytes::copy_from_slice(self.iter.key()),
+            }
+            .into()),
+        }
+    }
+}
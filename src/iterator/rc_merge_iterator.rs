@@ -2,14 +2,13 @@ use crate::entry::Entry;
 use crate::iterator::merge_iterator::MergeIterator;
 use crate::sstable::iterator::{SsTableIterator, VSsTableIterator};
 use crate::StorageIterator;
-use bytes::Buf;
 use std::collections::binary_heap::PeekMut;
 use std::collections::{HashMap, HashSet};
 use std::sync::Arc;
 
 pub struct RcMergeIterator<I: StorageIterator> {
     iter: MergeIterator<I>,
-    vsst_rc_delta: HashMap<u32, i32>,
+    vsst_rc_delta: HashMap<u64, i32>,
 }
 
 impl<I: StorageIterator> RcMergeIterator<I> {
@@ -20,7 +19,7 @@ impl<I: StorageIterator> RcMergeIterator<I> {
         }
     }
 
-    pub fn vsst_rc_delta(self) -> HashMap<u32, i32> {
+    pub fn vsst_rc_delta(self) -> HashMap<u64, i32> {
         self.vsst_rc_delta
     }
 }
@@ -53,7 +52,7 @@ impl<I: StorageIterator> StorageIterator for RcMergeIterator<I> {
             if inner_iter.1.key() == current.1.key() {
                 // 当前项被忽略，如果是分离的话就减少对应 VSST 引用计数
                 if Entry::is_separate(inner_iter.1.meta()) {
-                    let vsst_id = inner_iter.1.value().get_u32_le();
+                    let vsst_id = Entry::separated_vsst_id(inner_iter.1.value());
                     self.vsst_rc_delta
                         .insert(vsst_id, self.vsst_rc_delta.get(&vsst_id).unwrap_or(&0) - 1);
                 }
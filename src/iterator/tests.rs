@@ -1,3 +1,11 @@
+use std::ops::Bound;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+
+use bytes::Bytes;
+
+use crate::iterator::adapters::StorageIteratorExt;
+use crate::iterator::lazy_iterator::LazyIterator;
 use crate::iterator::merge_iterator::MergeIterator;
 use crate::iterator::two_merge_iterator::TwoMergeIterator;
 use crate::iterator::StorageIterator;
@@ -64,6 +72,57 @@ fn test_merge_iterator() {
     assert!(!i.is_valid())
 }
 
+#[test]
+fn test_lazy_merge_iterator() {
+    let opened = Arc::new(AtomicUsize::new(0));
+    // (第一个 key 的下界, 数据)，较新的数据在前
+    let sources: Vec<(&str, Vec<(&str, &str)>)> = vec![
+        ("k2", vec![("k2", "v2_1"), ("k5", "v5")]),
+        ("k1", vec![("k1", "v1"), ("k2", "v2"), ("k3", "v3")]),
+        // 下界比实际的第一个 key 小
+        ("k0", vec![("k3", "v3_1"), ("k4", "v4")]),
+        ("k7", vec![("k7", "v7")]),
+        ("k6", vec![]),
+    ];
+    let iters = sources
+        .into_iter()
+        .map(|(first_key, data)| {
+            let opened = opened.clone();
+            let data = data
+                .into_iter()
+                .map(|(k, v)| (k.as_bytes().to_vec(), v.as_bytes().to_vec()))
+                .collect();
+            Box::new(LazyIterator::new(Bytes::from(first_key), move || {
+                opened.fetch_add(1, Ordering::Relaxed);
+                Ok(TestIterator::new(data))
+            }))
+        })
+        .collect();
+
+    let mut i = MergeIterator::create_lazy(iters).unwrap();
+    // 只有下界不大于第一个结果的迭代器被创建
+    assert_eq!((i.key(), i.value()), (&b"k1"[..], &b"v1"[..]));
+    assert_eq!(opened.load(Ordering::Relaxed), 2);
+    let mut result = vec![];
+    while i.is_valid() {
+        result.push((i.key().to_vec(), i.value().to_vec()));
+        i.next().unwrap();
+    }
+    let expected: Vec<(Vec<u8>, Vec<u8>)> = [
+        ("k1", "v1"),
+        ("k2", "v2_1"),
+        ("k3", "v3"),
+        ("k4", "v4"),
+        ("k5", "v5"),
+        ("k7", "v7"),
+    ]
+    .iter()
+    .map(|(k, v)| (k.as_bytes().to_vec(), v.as_bytes().to_vec()))
+    .collect();
+    assert_eq!(result, expected);
+    assert_eq!(opened.load(Ordering::Relaxed), 5);
+}
+
 #[test]
 fn test_two_merge_iterator() {
     let iter1 = TestIterator::new(vec![
@@ -86,3 +145,99 @@ fn test_two_merge_iterator() {
     assert_eq!(i.value(), b"v3");
     i.next().unwrap();
 }
+
+#[cfg(feature = "check-order")]
+#[test]
+fn test_order_check_iterator() {
+    use crate::iterator::order_check_iterator::OrderCheckIterator;
+    use crate::iterator::StorageIteratorError;
+
+    let data = vec![
+        (b"k1".to_vec(), b"v1".to_vec()),
+        (b"k2".to_vec(), b"v2".to_vec()),
+        (b"k2".to_vec(), b"v2_1".to_vec()),
+        (b"k1".to_vec(), b"v1_1".to_vec()),
+    ];
+
+    let mut i = OrderCheckIterator::create(TestIterator::new(data.clone()), "test", true);
+    i.next().unwrap();
+    i.next().unwrap();
+    assert_eq!(i.value(), b"v2_1");
+    let err = i.next().unwrap_err();
+    assert!(matches!(
+        err.downcast_ref::<StorageIteratorError>(),
+        Some(StorageIteratorError::OutOfOrder { name: "test", .. })
+    ));
+
+    let mut i = OrderCheckIterator::create(TestIterator::new(data), "test", false);
+    i.next().unwrap();
+    assert!(i.next().is_err());
+}
+
+#[test]
+fn test_iterator_adapters() {
+    let data = || {
+        TestIterator::new(
+            [&b"a1"[..], b"b1", b"b2", b"b\xff", b"c1", b"c2", b"c3"]
+                .iter()
+                .map(|key| (key.to_vec(), key.to_ascii_uppercase()))
+                .collect(),
+        )
+    };
+    let collect = |mut iter: Box<dyn StorageIterator>| {
+        let mut keys = vec![];
+        while iter.is_valid() {
+            keys.push(iter.key().to_vec());
+            iter.next().unwrap();
+        }
+        // 读完后再调用 next 保持无效
+        iter.next().unwrap();
+        assert!(!iter.is_valid());
+        keys
+    };
+
+    assert_eq!(
+        collect(Box::new(data().filter_prefix(b"b").unwrap())),
+        vec![b"b1".to_vec(), b"b2".to_vec(), b"b\xff".to_vec()]
+    );
+    assert!(collect(Box::new(data().filter_prefix(b"d").unwrap())).is_empty());
+    assert_eq!(
+        collect(Box::new(
+            data()
+                .filter_range(
+                    Bound::Excluded(Bytes::from("b2")),
+                    Bound::Included(Bytes::from("c2"))
+                )
+                .unwrap()
+        )),
+        vec![b"b\xff".to_vec(), b"c1".to_vec(), b"c2".to_vec()]
+    );
+    assert_eq!(
+        collect(Box::new(
+            data()
+                .filter_entries(|key, value| key.ends_with(b"1") && value[0] != b'A')
+                .unwrap()
+        )),
+        vec![b"b1".to_vec(), b"c1".to_vec()]
+    );
+    assert_eq!(
+        collect(Box::new(data().take_entries(2))),
+        vec![b"a1".to_vec(), b"b1".to_vec()]
+    );
+    assert!(collect(Box::new(data().take_entries(0))).is_empty());
+    assert_eq!(
+        collect(Box::new(data().step_entries(3))),
+        vec![b"a1".to_vec(), b"b\xff".to_vec(), b"c3".to_vec()]
+    );
+
+    // 组合使用
+    let mut iter = data()
+        .filter_prefix(b"c")
+        .unwrap()
+        .step_entries(2)
+        .take_entries(1);
+    assert_eq!(iter.key(), b"c1");
+    assert_eq!(iter.value(), b"C1");
+    iter.next().unwrap();
+    assert!(!iter.is_valid());
+}
@@ -0,0 +1,52 @@
+use parking_lot::{Mutex, MutexGuard};
+use xxhash_rust::xxh3::xxh3_64;
+
+/// key 锁的分段数，不同 key 落在同一段时互相等待
+const KEY_LOCK_STRIPES: usize = 64;
+
+/// 按 key 分段的写入锁，保证 [`crate::Db::put_get`] 的读取和写入之间同一 key 上没有其他写入
+///
+/// 所有写入在写入 WAL 和 memtable 期间持有其 key 所在段的锁，不同段上的写入互不影响
+pub(crate) struct KeyLocks {
+    stripes: Vec<Mutex<()>>,
+}
+
+impl Default for KeyLocks {
+    fn default() -> Self {
+        Self {
+            stripes: (0..KEY_LOCK_STRIPES).map(|_| Mutex::new(())).collect(),
+        }
+    }
+}
+
+impl KeyLocks {
+    pub(crate) fn stripe_of(&self, key: &[u8]) -> usize {
+        (xxh3_64(key) % self.stripes.len() as u64) as usize
+    }
+
+    pub(crate) fn lock(&self, key: &[u8]) -> MutexGuard<'_, ()> {
+        self.stripes[self.stripe_of(key)].lock()
+    }
+
+    /// 锁住所有 key 所在的段，按段的顺序加锁，并发的批次不会死锁
+    pub(crate) fn lock_all<'a>(
+        &self,
+        keys: impl Iterator<Item = &'a [u8]>,
+    ) -> Vec<MutexGuard<'_, ()>> {
+        let mut stripes: Vec<_> = keys.map(|key| self.stripe_of(key)).collect();
+        stripes.sort_unstable();
+        stripes.dedup();
+        stripes
+            .into_iter()
+            .map(|stripe| self.stripes[stripe].lock())
+            .collect()
+    }
+}
+
+impl std::fmt::Debug for KeyLocks {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("KeyLocks")
+            .field("stripes", &self.stripes.len())
+            .finish()
+    }
+}
@@ -0,0 +1,346 @@
+//! 按字节序比较时与原值顺序一致的 key 编码，用于设计可以按范围扫描的 key
+//!
+//! 数据库按字节序比较 key，直接写入小端或十进制文本的整数时 `10` 会排在 `9` 之前。
+//! 这里的编码保证编码后的字节序与原值的顺序相同：
+//!
+//! - 无符号整数按大端编码，有符号整数翻转符号位后按大端编码，都是固定 8 字节
+//! - 组合 key 的字节串分量中的 `0x00` 转义为 `0x00 0xff`，以 `0x00 0x01` 结尾，
+//!   短的字节串排在以它为前缀的长字节串之前，且不会与之后的分量混在一起比较
+//!
+//! 组合 key 的前几个分量的编码就是完整 key 的前缀，配合 [`prefix_upper_bound`] 可以扫描前几个分量相同的所有 key
+//!
+//! ```
+//! use lasagnedb::keys::{KeyBuilder, KeyReader};
+//!
+//! let key = KeyBuilder::new().append_str("user").append_u64(42).build();
+//! let mut reader = KeyReader::new(&key);
+//! assert_eq!(reader.read_str().unwrap(), "user");
+//! assert_eq!(reader.read_u64().unwrap(), 42);
+//! assert!(reader.is_empty());
+//! ```
+
+use std::ops::Bound;
+
+use bytes::{BufMut, Bytes, BytesMut};
+use thiserror::Error;
+
+/// 字节串分量中的 `0x00` 和结束标记的首字节
+const ESCAPE: u8 = 0x00;
+/// `0x00 0xff` 表示字节串中的一个 `0x00`
+const ESCAPED_ZERO: u8 = 0xff;
+/// `0x00 0x01` 表示字节串结束
+const TERMINATOR: u8 = 0x01;
+
+/// 解码 key 失败的原因，`offset` 为出错位置在 key 中的偏移
+#[derive(Debug, Clone, Error, Eq, PartialEq)]
+pub enum KeyDecodeError {
+    #[error("key truncated at offset {offset}, need {need} more bytes")]
+    Truncated { offset: usize, need: usize },
+    #[error("invalid escape byte {byte:#04x} at offset {offset}")]
+    InvalidEscape { offset: usize, byte: u8 },
+    #[error("invalid utf-8 string at offset {offset}")]
+    InvalidUtf8 { offset: usize },
+}
+
+/// 按大端编码，编码后的字节序与数值大小一致
+pub fn encode_u64(value: u64) -> [u8; 8] {
+    value.to_be_bytes()
+}
+
+/// 解码 [`encode_u64`] 的结果，只读取 `data` 的前 8 字节
+pub fn decode_u64(data: &[u8]) -> Result<u64, KeyDecodeError> {
+    let bytes = data.get(..8).ok_or(KeyDecodeError::Truncated {
+        offset: data.len(),
+        need: 8 - data.len(),
+    })?;
+    Ok(u64::from_be_bytes(bytes.try_into().unwrap()))
+}
+
+/// 翻转符号位后按大端编码，负数排在非负数之前
+pub fn encode_i64(value: i64) -> [u8; 8] {
+    encode_u64(value as u64 ^ (1 << 63))
+}
+
+/// 解码 [`encode_i64`] 的结果，只读取 `data` 的前 8 字节
+pub fn decode_i64(data: &[u8]) -> Result<i64, KeyDecodeError> {
+    Ok((decode_u64(data)? ^ (1 << 63)) as i64)
+}
+
+/// 以 `prefix` 开头的 key 的上界（不包含），`prefix` 为空或全为 0xff 时没有上界
+pub fn prefix_upper_bound(prefix: &[u8]) -> Bound<Bytes> {
+    let mut upper = prefix.to_vec();
+    while let Some(last) = upper.pop() {
+        if last < u8::MAX {
+            upper.push(last + 1);
+            return Bound::Excluded(Bytes::from(upper));
+        }
+    }
+    Bound::Unbounded
+}
+
+/// 按顺序拼接多个分量得到组合 key，组合 key 先按第一个分量排序，相同时再按之后的分量排序
+///
+/// 编码中不记录分量的类型，读取时需要按写入的顺序和类型使用 [`KeyReader`]
+#[derive(Debug, Clone, Default)]
+pub struct KeyBuilder {
+    buf: BytesMut,
+}
+
+impl KeyBuilder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// 从已有的前缀开始，前缀原样保留，不参与转义
+    pub fn with_prefix(prefix: impl AsRef<[u8]>) -> Self {
+        Self {
+            buf: BytesMut::from(prefix.as_ref()),
+        }
+    }
+
+    pub fn append_u64(mut self, value: u64) -> Self {
+        self.buf.put_slice(&encode_u64(value));
+        self
+    }
+
+    pub fn append_i64(mut self, value: i64) -> Self {
+        self.buf.put_slice(&encode_i64(value));
+        self
+    }
+
+    /// 追加一个可变长度的字节串，其中的 `0x00` 被转义，之后以结束标记分隔
+    pub fn append_bytes(mut self, value: impl AsRef<[u8]>) -> Self {
+        for &byte in value.as_ref() {
+            if byte == ESCAPE {
+                self.buf.put_slice(&[ESCAPE, ESCAPED_ZERO]);
+            } else {
+                self.buf.put_u8(byte);
+            }
+        }
+        self.buf.put_slice(&[ESCAPE, TERMINATOR]);
+        self
+    }
+
+    /// 同 [`KeyBuilder::append_bytes`]，按 UTF-8 字节排序，即按 Unicode 码点排序
+    pub fn append_str(self, value: &str) -> Self {
+        self.append_bytes(value)
+    }
+
+    pub fn len(&self) -> usize {
+        self.buf.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.buf.is_empty()
+    }
+
+    pub fn build(self) -> Bytes {
+        self.buf.freeze()
+    }
+}
+
+/// 按写入时的顺序和类型依次读出 [`KeyBuilder`] 编码的分量
+#[derive(Debug, Clone)]
+pub struct KeyReader<'a> {
+    data: &'a [u8],
+    offset: usize,
+}
+
+impl<'a> KeyReader<'a> {
+    pub fn new(data: &'a [u8]) -> Self {
+        Self { data, offset: 0 }
+    }
+
+    /// 是否已读完所有分量
+    pub fn is_empty(&self) -> bool {
+        self.offset == self.data.len()
+    }
+
+    /// 尚未读取的部分
+    pub fn remaining(&self) -> &'a [u8] {
+        &self.data[self.offset..]
+    }
+
+    /// 跳过 `len` 字节，用于跳过 [`KeyBuilder::with_prefix`] 写入的前缀
+    pub fn skip(&mut self, len: usize) -> Result<(), KeyDecodeError> {
+        self.take(len)?;
+        Ok(())
+    }
+
+    fn take(&mut self, len: usize) -> Result<&'a [u8], KeyDecodeError> {
+        let remaining = self.remaining();
+        if remaining.len() < len {
+            return Err(KeyDecodeError::Truncated {
+                offset: self.data.len(),
+                need: len - remaining.len(),
+            });
+        }
+        self.offset += len;
+        Ok(&remaining[..len])
+    }
+
+    pub fn read_u64(&mut self) -> Result<u64, KeyDecodeError> {
+        decode_u64(self.take(8)?)
+    }
+
+    pub fn read_i64(&mut self) -> Result<i64, KeyDecodeError> {
+        decode_i64(self.take(8)?)
+    }
+
+    /// 读出一个字节串分量并还原其中转义的 `0x00`
+    pub fn read_bytes(&mut self) -> Result<Vec<u8>, KeyDecodeError> {
+        let mut value = vec![];
+        loop {
+            let byte = self.take(1)?[0];
+            if byte != ESCAPE {
+                value.push(byte);
+                continue;
+            }
+            let offset = self.offset;
+            match self.take(1)?[0] {
+                TERMINATOR => return Ok(value),
+                ESCAPED_ZERO => value.push(ESCAPE),
+                byte => return Err(KeyDecodeError::InvalidEscape { offset, byte }),
+            }
+        }
+    }
+
+    pub fn read_str(&mut self) -> Result<String, KeyDecodeError> {
+        let offset = self.offset;
+        String::from_utf8(self.read_bytes()?).map_err(|_| KeyDecodeError::InvalidUtf8 { offset })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::ops::Bound;
+
+    use bytes::Bytes;
+
+    use crate::keys::{
+        decode_i64, decode_u64, encode_i64, encode_u64, prefix_upper_bound, KeyBuilder,
+        KeyDecodeError, KeyReader,
+    };
+
+    #[test]
+    fn test_integer_order() {
+        let unsigned = [
+            0,
+            1,
+            9,
+            10,
+            255,
+            256,
+            u32::MAX as u64,
+            u64::MAX - 1,
+            u64::MAX,
+        ];
+        for pair in unsigned.windows(2) {
+            assert!(encode_u64(pair[0]) < encode_u64(pair[1]));
+        }
+        let signed = [i64::MIN, -256, -1, 0, 1, 255, i64::MAX];
+        for pair in signed.windows(2) {
+            assert!(encode_i64(pair[0]) < encode_i64(pair[1]));
+        }
+        for value in unsigned {
+            assert_eq!(decode_u64(&encode_u64(value)).unwrap(), value);
+        }
+        for value in signed {
+            assert_eq!(decode_i64(&encode_i64(value)).unwrap(), value);
+        }
+        assert_eq!(
+            decode_u64(&[1, 2, 3]),
+            Err(KeyDecodeError::Truncated { offset: 3, need: 5 })
+        );
+    }
+
+    #[test]
+    fn test_composite_order() {
+        let tuples: Vec<(&[u8], i64)> = vec![
+            (b"", -1),
+            (b"", 0),
+            (b"a", i64::MIN),
+            (b"a", 5),
+            (b"a\x00", -5),
+            (b"a\x00\x00", 0),
+            (b"a\x01", 0),
+            (b"ab", -5),
+            (b"b", 0),
+            (b"\xff", 0),
+        ];
+        let keys: Vec<Bytes> = tuples
+            .iter()
+            .map(|(bytes, value)| {
+                KeyBuilder::new()
+                    .append_bytes(bytes)
+                    .append_i64(*value)
+                    .build()
+            })
+            .collect();
+        for pair in keys.windows(2) {
+            assert!(pair[0] < pair[1], "{:?} >= {:?}", pair[0], pair[1]);
+        }
+        for ((bytes, value), key) in tuples.iter().zip(&keys) {
+            let mut reader = KeyReader::new(key);
+            assert_eq!(reader.read_bytes().unwrap(), *bytes);
+            assert_eq!(reader.read_i64().unwrap(), *value);
+            assert!(reader.is_empty());
+        }
+    }
+
+    #[test]
+    fn test_prefix_scan_range() {
+        let prefix = KeyBuilder::with_prefix("t/").append_str("user").build();
+        let upper = prefix_upper_bound(&prefix);
+        let in_range = |key: &Bytes| {
+            key >= &prefix
+                && match &upper {
+                    Bound::Excluded(upper) => key < upper,
+                    _ => true,
+                }
+        };
+        let key = |name: &str, id| {
+            KeyBuilder::with_prefix("t/")
+                .append_str(name)
+                .append_u64(id)
+                .build()
+        };
+        assert!(in_range(&key("user", 0)));
+        assert!(in_range(&key("user", u64::MAX)));
+        assert!(!in_range(&key("users", 0)));
+        assert!(!in_range(&key("use", u64::MAX)));
+
+        let key = key("user", 7);
+        let mut reader = KeyReader::new(&key);
+        reader.skip(2).unwrap();
+        assert_eq!(reader.read_str().unwrap(), "user");
+        assert_eq!(reader.remaining(), encode_u64(7));
+        assert_eq!(prefix_upper_bound(b"\xff\xff"), Bound::Unbounded);
+        assert_eq!(
+            prefix_upper_bound(b"a\xff"),
+            Bound::Excluded(Bytes::from("b"))
+        );
+    }
+
+    #[test]
+    fn test_invalid_keys() {
+        let mut reader = KeyReader::new(b"ab\x00\x02");
+        assert_eq!(
+            reader.read_bytes(),
+            Err(KeyDecodeError::InvalidEscape {
+                offset: 3,
+                byte: 0x02
+            })
+        );
+        let mut reader = KeyReader::new(b"ab");
+        assert_eq!(
+            reader.read_bytes(),
+            Err(KeyDecodeError::Truncated { offset: 2, need: 1 })
+        );
+        let mut reader = KeyReader::new(b"\xff\x00\x01");
+        assert_eq!(
+            reader.read_str(),
+            Err(KeyDecodeError::InvalidUtf8 { offset: 0 })
+        );
+    }
+}
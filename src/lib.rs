@@ -1,26 +1,119 @@
 extern crate core;
 
+pub mod admin;
+mod batch;
 mod block;
 mod cache;
+mod cdc;
+mod checksum;
+mod contention;
+mod cooperative;
 mod daemon;
 mod db;
 mod db_config;
 mod db_iterator;
 mod entry;
+mod export;
+mod inspect;
 mod iterator;
+mod key_lock;
+pub mod keys;
+#[cfg(feature = "log-facade")]
+pub mod log_store;
+mod memory_limit;
 mod memtable;
 mod meta;
+mod micro_batch;
+mod negative_cache;
+mod perf_context;
+pub mod prelude;
 mod record;
+mod registry;
+#[cfg(any(test, feature = "test-util"))]
+mod scheduler;
+mod sequence;
+mod snapshot;
 mod sstable;
+mod staging;
+mod stats;
 mod storage;
+mod system;
+#[cfg(feature = "test-util")]
+pub mod test_util;
+pub mod trace;
 mod transaction;
+mod validate;
 mod value;
 mod wal;
+mod watch;
 
 #[cfg(test)]
 mod db_tests;
 
-pub use db::*;
+pub use batch::{IdempotencyToken, WriteBatch};
+pub use cache::CacheFillPolicy;
+pub use cdc::{CdcEvent, CdcSink};
+pub use checksum::ChecksumType;
+pub use daemon::{
+    CompactionJob, CompactionJobFile, CompactionPlan, SharedScheduler, COMPACTION_JOB_FILE,
+};
+pub use db::{
+    BusyError, Db, DbClosedError, DbOptions, FrozenLimitAction, MissingVSstPolicy, ReadOnlyError,
+    ReadOptions, ScanIsolation, WriteOptions,
+};
+#[cfg(feature = "legacy-exports")]
 pub use db_config::*;
+#[cfg(not(feature = "legacy-exports"))]
+pub(crate) use db_config::*;
+pub use db_config::{GB, KB, MB};
+pub use db_iterator::{DbIterator, FusedIterator, ScanChunk, ScanTimeoutError, TailIterator};
+pub use entry::EntryError;
+pub use export::ExportReport;
+pub use inspect::ValueMetrics;
+pub use iterator::adapters::{
+    FilterIterator, RangeIterator, StepIterator, StorageIteratorExt, TakeIterator,
+};
 pub use iterator::iterator::StorageIterator;
+pub use memory_limit::{
+    read_memory_limit, read_memory_used, set_read_memory_limit, MemoryLimitError,
+};
+pub use meta::manifest::{ManifestActor, RecordOrigin};
+pub use micro_batch::MicroBatchOptions;
+pub use perf_context::{perf_context, PerfContext, PerfContextScope};
+pub use registry::AlreadyOpenError;
+#[cfg(any(test, feature = "test-util"))]
+pub use scheduler::{SchedulerHandle, SchedulerStep};
+pub use snapshot::Snapshot;
+pub use sstable::builder::{CorruptionError, FilterLoading, KeyOrderCheck, KeyOrderError};
+pub use sstable::compression::CompressionType;
+pub use sstable::iterator::VSstDerefStats;
+pub use staging::{ResultStaging, StagedResult};
+pub use stats::{
+    ActiveIterator, CompactionSummary, CumulativeStats, DbStats, FlushJobId, FlushJobStatus,
+    Health, HealthStatus, KeyDesignHint, KeyPrefixStats, LevelMetadata, LockContentionStats,
+    LockWaitStats, MemoryUsage, PrefixAdvice, QueueStats, RecoveryStats, SstMetadata, StallCause,
+    WriteStallStats, PREFIX_RESTART_INTERVALS,
+};
+#[cfg(feature = "failpoints")]
+pub use storage::fault::{IoFault, IoFaults, IoOp};
+pub use storage::file::{FileOp, IoError, IoPriority};
+pub use storage::header::{FileFormatError, FileType};
+pub use validate::{
+    Rejection, WriteOp, WriteRejectedError, RESERVED_KEY_VALIDATOR, VALUE_SIZE_VALIDATOR,
+};
+pub use value::OpType;
+#[cfg(feature = "legacy-exports")]
 pub use value::*;
+#[cfg(not(feature = "legacy-exports"))]
+pub(crate) use value::*;
+pub use wal::{
+    WalCorruptionError, WalEntry, WalProtection, WalReader, WalRecord, WalRecordIterator,
+};
+pub use watch::WatchEvent;
+
+/// 数据库操作返回的错误，可以 downcast 为 [`CorruptionError`]、[`WalCorruptionError`]、[`IoError`] 等具体类型
+pub type Error = anyhow::Error;
+
+/// [`DbOptions`] 的旧名称
+#[cfg(feature = "legacy-exports")]
+pub type Options = DbOptions;
@@ -0,0 +1,267 @@
+//! 基于有序 key 的追加日志（消息存储），开启 `log-facade` feature 时可用
+//!
+//! 每个 topic 的消息以 topic 和单调递增的 offset 为 key 追加写入，offset 从 0 开始。
+//! key 用 [`crate::keys`] 编码，同一 topic 的消息在 key 空间中连续且按 offset 排列，
+//! 顺序写入、按 offset 定位和尾随读取都落在范围扫描的路径上。
+//!
+//! key 的布局（`prefix` 为 [`LogStore::new`] 的参数，用于和其他数据共用一个数据库）：
+//!
+//! - topic 的元数据：`prefix | topic`，value 为 next offset(8 bytes) | start offset(8 bytes)
+//! - 消息：`prefix | topic | offset`，排在元数据之后
+//!
+//! ```
+//! use std::sync::Arc;
+//!
+//! use bytes::Bytes;
+//! use lasagnedb::log_store::LogStore;
+//! use lasagnedb::Db;
+//!
+//! let dir = tempfile::tempdir().unwrap();
+//! let db = Arc::new(Db::open_file(dir.path()).unwrap());
+//! let log = LogStore::new(db.clone(), "log/");
+//! assert_eq!(log.append("orders", Bytes::from("created")).unwrap(), 0);
+//! assert_eq!(log.append("orders", Bytes::from("paid")).unwrap(), 1);
+//!
+//! let mut tail = log.tail("orders", 0).unwrap();
+//! assert_eq!(tail.poll().unwrap().unwrap().value, Bytes::from("created"));
+//! assert_eq!(tail.poll().unwrap().unwrap().offset, 1);
+//! assert!(tail.poll().unwrap().is_none());
+//! log.append("orders", Bytes::from("shipped")).unwrap();
+//! assert_eq!(tail.poll().unwrap().unwrap().offset, 2);
+//! drop(tail);
+//! db.close().unwrap();
+//! ```
+
+use std::collections::HashMap;
+use std::ops::Bound;
+use std::sync::Arc;
+
+use anyhow::ensure;
+use bytes::{Buf, BufMut, Bytes, BytesMut};
+use parking_lot::Mutex;
+
+use crate::keys::{self, KeyBuilder};
+use crate::{Db, StorageIterator, TailIterator, WriteBatch};
+
+/// 截断时每个批次删除的消息数量
+const TRUNCATE_BATCH: usize = 1024;
+
+/// 日志中的一条消息
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct LogMessage {
+    pub offset: u64,
+    pub value: Bytes,
+}
+
+/// topic 中现存消息的 offset 范围 `[start, next)`，`start == next` 时没有消息
+#[derive(Debug, Clone, Copy, Default, Eq, PartialEq)]
+pub struct OffsetRange {
+    /// 最早一条未被截断的消息的 offset
+    pub start: u64,
+    /// 下一条追加的消息的 offset
+    pub next: u64,
+}
+
+impl OffsetRange {
+    fn encode(&self) -> Bytes {
+        let mut value = BytesMut::with_capacity(16);
+        value.put_u64_le(self.next);
+        value.put_u64_le(self.start);
+        value.freeze()
+    }
+
+    fn decode(topic: &str, mut data: &[u8]) -> anyhow::Result<Self> {
+        ensure!(data.len() == 16, "invalid metadata of topic {:?}", topic);
+        let next = data.get_u64_le();
+        let start = data.get_u64_le();
+        Ok(Self { start, next })
+    }
+}
+
+/// 按 topic 追加、尾随读取和截断消息的日志，见 [模块文档](self)
+///
+/// 同一 topic 的追加和截断串行执行，以维护元数据中的 offset 范围；不同 topic 之间互不阻塞。
+/// 同一个数据库和前缀只应创建一个 `LogStore`，否则各自缓存的 offset 范围会冲突
+pub struct LogStore {
+    db: Arc<Db>,
+    prefix: Bytes,
+    // 各 topic 的 offset 范围，第一次用到时从元数据读出，锁保护对应 topic 的写入
+    topics: Mutex<HashMap<String, Arc<Mutex<Option<OffsetRange>>>>>,
+}
+
+impl LogStore {
+    pub fn new(db: Arc<Db>, prefix: impl Into<Bytes>) -> Self {
+        Self {
+            db,
+            prefix: prefix.into(),
+            topics: Mutex::new(HashMap::new()),
+        }
+    }
+
+    fn meta_key(&self, topic: &str) -> Bytes {
+        KeyBuilder::with_prefix(&self.prefix)
+            .append_str(topic)
+            .build()
+    }
+
+    fn message_key(&self, topic: &str, offset: u64) -> Bytes {
+        KeyBuilder::with_prefix(&self.prefix)
+            .append_str(topic)
+            .append_u64(offset)
+            .build()
+    }
+
+    /// 消息的 key 中 offset 之前的部分与元数据的 key 相同
+    fn offset_of(meta_key: &[u8], key: &[u8]) -> anyhow::Result<u64> {
+        Ok(keys::decode_u64(&key[meta_key.len()..])?)
+    }
+
+    fn topic_lock(&self, topic: &str) -> Arc<Mutex<Option<OffsetRange>>> {
+        self.topics
+            .lock()
+            .entry(topic.to_string())
+            .or_default()
+            .clone()
+    }
+
+    /// 持有 topic 的锁时读出 offset 范围，第一次用到时从元数据读出，不存在时为空的范围
+    fn load_range(
+        &self,
+        topic: &str,
+        cached: &mut Option<OffsetRange>,
+    ) -> anyhow::Result<OffsetRange> {
+        if let Some(range) = cached {
+            return Ok(*range);
+        }
+        let range = match self.db.get(&self.meta_key(topic))? {
+            Some(data) => OffsetRange::decode(topic, &data)?,
+            None => OffsetRange::default(),
+        };
+        *cached = Some(range);
+        Ok(range)
+    }
+
+    /// topic 中现存消息的 offset 范围
+    pub fn offsets(&self, topic: &str) -> anyhow::Result<OffsetRange> {
+        let lock = self.topic_lock(topic);
+        let mut cached = lock.lock();
+        self.load_range(topic, &mut cached)
+    }
+
+    /// 追加一条消息，返回它的 offset
+    pub fn append(&self, topic: &str, value: Bytes) -> anyhow::Result<u64> {
+        self.append_batch(topic, vec![value])
+    }
+
+    /// 原子地追加多条消息，offset 连续，返回第一条的 offset；`values` 为空时返回下一条消息的 offset
+    pub fn append_batch(&self, topic: &str, values: Vec<Bytes>) -> anyhow::Result<u64> {
+        let lock = self.topic_lock(topic);
+        let mut cached = lock.lock();
+        let mut range = self.load_range(topic, &mut cached)?;
+        let first = range.next;
+        if values.is_empty() {
+            return Ok(first);
+        }
+        let mut batch = WriteBatch::new();
+        for value in values {
+            batch.put(self.message_key(topic, range.next), value)?;
+            range.next += 1;
+        }
+        // 元数据与消息在同一个批次中写入，崩溃后两者一致
+        batch.put(self.meta_key(topic), range.encode())?;
+        self.db.write(batch)?;
+        *cached = Some(range);
+        Ok(first)
+    }
+
+    /// 读取 offset 不小于 `from` 的最多 `limit` 条消息
+    pub fn read(&self, topic: &str, from: u64, limit: usize) -> anyhow::Result<Vec<LogMessage>> {
+        let meta_key = self.meta_key(topic);
+        let mut iter = self.db.scan(
+            Bound::Included(self.message_key(topic, from)),
+            keys::prefix_upper_bound(&meta_key),
+        )?;
+        let mut messages = vec![];
+        while iter.is_valid() && messages.len() < limit {
+            messages.push(LogMessage {
+                offset: Self::offset_of(&meta_key, iter.key())?,
+                value: Bytes::copy_from_slice(iter.value()),
+            });
+            iter.next()?;
+        }
+        Ok(messages)
+    }
+
+    /// 从 offset `from` 开始尾随读取 topic，读完现有的消息后继续读取之后追加的消息
+    ///
+    /// 返回的 [`LogTail`] 持有快照，`Db::close` 会等待它 drop
+    pub fn tail(&self, topic: &str, from: u64) -> anyhow::Result<LogTail> {
+        let meta_key = self.meta_key(topic);
+        let iter = self.db.tail_scan(
+            Bound::Included(self.message_key(topic, from)),
+            keys::prefix_upper_bound(&meta_key),
+        )?;
+        Ok(LogTail { iter, meta_key })
+    }
+
+    /// 删除 offset 小于 `before` 的消息，返回删除的数量，`before` 超过下一条消息的 offset 时只删除到最后一条
+    ///
+    /// 消息分批删除，每批同时更新元数据中的起始 offset，中途失败时已删除的部分不会恢复
+    pub fn truncate(&self, topic: &str, before: u64) -> anyhow::Result<usize> {
+        let lock = self.topic_lock(topic);
+        let mut cached = lock.lock();
+        let mut range = self.load_range(topic, &mut cached)?;
+        let before = before.min(range.next);
+        if before <= range.start {
+            return Ok(0);
+        }
+        let meta_key = self.meta_key(topic);
+        let mut iter = self.db.scan(
+            Bound::Included(self.message_key(topic, range.start)),
+            Bound::Excluded(self.message_key(topic, before)),
+        )?;
+        let mut deleted = 0;
+        loop {
+            let mut batch = WriteBatch::new();
+            while iter.is_valid() && batch.len() < TRUNCATE_BATCH {
+                batch.delete(Bytes::copy_from_slice(iter.key()))?;
+                iter.next()?;
+            }
+            let done = !iter.is_valid();
+            deleted += batch.len();
+            range.start = if done {
+                before
+            } else {
+                Self::offset_of(&meta_key, iter.key())?
+            };
+            batch.put(meta_key.clone(), range.encode())?;
+            self.db.write(batch)?;
+            *cached = Some(range);
+            if done {
+                return Ok(deleted);
+            }
+        }
+    }
+}
+
+/// 尾随读取一个 topic 的迭代器，见 [`LogStore::tail`]
+pub struct LogTail {
+    iter: TailIterator,
+    meta_key: Bytes,
+}
+
+impl LogTail {
+    /// 返回下一条消息，已读到最新的消息时返回 `None`，之后可以再次调用以读取新追加的消息
+    pub fn poll(&mut self) -> anyhow::Result<Option<LogMessage>> {
+        self.iter.refresh()?;
+        if !self.iter.is_valid() {
+            return Ok(None);
+        }
+        let message = LogMessage {
+            offset: LogStore::offset_of(&self.meta_key, self.iter.key())?,
+            value: Bytes::copy_from_slice(self.iter.value()),
+        };
+        self.iter.next()?;
+        Ok(Some(message))
+    }
+}
@@ -0,0 +1,197 @@
+//! 读操作物化 value 的进程级内存预算
+//!
+//! get 复制出的 value、[`crate::Db::multi_get`] 和 [`crate::Db::scan_bytes`] 累积的结果、范围查询预读的块
+//! 在分配前向预算登记，进程内的所有数据库共享一个预算。超出上限时 get 和批量读取返回 [`MemoryLimitError`]，
+//! 而不是让内存无限增长；预读只是优化，超出上限时不再预读。
+//!
+//! 默认没有上限，只统计用量，见 [`set_read_memory_limit`] 和 [`crate::DbStats::read_memory`]
+
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+
+/// 读操作占用的内存超出 [`set_read_memory_limit`] 设置的上限
+#[derive(thiserror::Error, Debug, Clone, Copy, PartialEq, Eq)]
+#[error("read memory limit exceeded: requested {requested} bytes, {used} of {limit} bytes in use")]
+pub struct MemoryLimitError {
+    pub requested: usize,
+    pub used: usize,
+    pub limit: usize,
+}
+
+static READ_BUDGET: MemoryBudget = MemoryBudget::new();
+
+/// 设置进程内所有读操作物化 value 可以占用的内存（字节），为 `None` 时不限制
+///
+/// 只影响之后的登记，已占用的内存不会因上限降低而释放
+pub fn set_read_memory_limit(limit: Option<usize>) {
+    READ_BUDGET.set_limit(limit);
+}
+
+/// 当前的上限，见 [`set_read_memory_limit`]
+pub fn read_memory_limit() -> Option<usize> {
+    READ_BUDGET.limit()
+}
+
+/// 进程内正在进行的读操作物化的 value 占用的内存（字节）
+pub fn read_memory_used() -> usize {
+    READ_BUDGET.used()
+}
+
+/// 因超出上限而失败的读操作次数
+pub(crate) fn read_memory_rejections() -> u64 {
+    READ_BUDGET.rejections()
+}
+
+/// 在进程级的预算中登记 `bytes` 字节
+pub(crate) fn reserve(bytes: usize) -> Result<MemoryReservation<'static>, MemoryLimitError> {
+    READ_BUDGET.reserve(bytes)
+}
+
+/// 不登记任何内存的占用，之后按需 [`MemoryReservation::grow`]
+pub(crate) fn empty_reservation() -> MemoryReservation<'static> {
+    MemoryReservation {
+        budget: &READ_BUDGET,
+        bytes: 0,
+    }
+}
+
+#[derive(Debug)]
+pub(crate) struct MemoryBudget {
+    // usize::MAX 表示不限制
+    limit: AtomicUsize,
+    used: AtomicUsize,
+    rejections: AtomicU64,
+}
+
+impl MemoryBudget {
+    pub(crate) const fn new() -> Self {
+        Self {
+            limit: AtomicUsize::new(usize::MAX),
+            used: AtomicUsize::new(0),
+            rejections: AtomicU64::new(0),
+        }
+    }
+
+    pub(crate) fn set_limit(&self, limit: Option<usize>) {
+        self.limit
+            .store(limit.unwrap_or(usize::MAX), Ordering::Relaxed);
+    }
+
+    pub(crate) fn limit(&self) -> Option<usize> {
+        Some(self.limit.load(Ordering::Relaxed)).filter(|limit| *limit != usize::MAX)
+    }
+
+    pub(crate) fn used(&self) -> usize {
+        self.used.load(Ordering::Relaxed)
+    }
+
+    pub(crate) fn rejections(&self) -> u64 {
+        self.rejections.load(Ordering::Relaxed)
+    }
+
+    /// 登记 `bytes` 字节，登记后超出上限时不登记并返回错误，返回的占用 drop 时释放
+    pub(crate) fn reserve(&self, bytes: usize) -> Result<MemoryReservation<'_>, MemoryLimitError> {
+        let mut reservation = MemoryReservation {
+            budget: self,
+            bytes: 0,
+        };
+        reservation.grow(bytes)?;
+        Ok(reservation)
+    }
+
+    fn acquire(&self, bytes: usize) -> Result<(), MemoryLimitError> {
+        if bytes == 0 {
+            return Ok(());
+        }
+        let limit = self.limit.load(Ordering::Relaxed);
+        self.used
+            .fetch_update(Ordering::AcqRel, Ordering::Acquire, |used| {
+                used.checked_add(bytes).filter(|total| *total <= limit)
+            })
+            .map(|_| ())
+            .map_err(|used| {
+                self.rejections.fetch_add(1, Ordering::Relaxed);
+                MemoryLimitError {
+                    requested: bytes,
+                    used,
+                    limit,
+                }
+            })
+    }
+
+    fn release(&self, bytes: usize) {
+        if bytes > 0 {
+            self.used.fetch_sub(bytes, Ordering::AcqRel);
+        }
+    }
+}
+
+/// 在 [`MemoryBudget`] 中登记的一段内存，drop 时释放
+#[derive(Debug)]
+pub(crate) struct MemoryReservation<'a> {
+    budget: &'a MemoryBudget,
+    bytes: usize,
+}
+
+impl MemoryReservation<'_> {
+    /// 再登记 `bytes` 字节，超出上限时已登记的部分不变
+    pub(crate) fn grow(&mut self, bytes: usize) -> Result<(), MemoryLimitError> {
+        self.budget.acquire(bytes)?;
+        self.bytes += bytes;
+        Ok(())
+    }
+
+    /// 释放其中的 `bytes` 字节，最多释放到 0
+    pub(crate) fn shrink(&mut self, bytes: usize) {
+        let bytes = bytes.min(self.bytes);
+        self.budget.release(bytes);
+        self.bytes -= bytes;
+    }
+
+    pub(crate) fn bytes(&self) -> usize {
+        self.bytes
+    }
+}
+
+impl Drop for MemoryReservation<'_> {
+    fn drop(&mut self) {
+        self.budget.release(self.bytes);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::memory_limit::{MemoryBudget, MemoryLimitError};
+
+    #[test]
+    fn test_memory_budget() {
+        let budget = MemoryBudget::new();
+        assert_eq!(budget.limit(), None);
+        drop(budget.reserve(1 << 40).unwrap());
+        assert_eq!(budget.used(), 0);
+
+        budget.set_limit(Some(100));
+        let mut first = budget.reserve(60).unwrap();
+        assert_eq!(
+            budget.reserve(50).unwrap_err(),
+            MemoryLimitError {
+                requested: 50,
+                used: 60,
+                limit: 100,
+            }
+        );
+        assert_eq!(budget.rejections(), 1);
+
+        // 失败的 grow 不改变已登记的部分
+        assert!(first.grow(41).is_err());
+        assert_eq!(first.bytes(), 60);
+        first.grow(40).unwrap();
+        assert_eq!(budget.used(), 100);
+        first.shrink(70);
+        assert_eq!(budget.used(), 30);
+        let second = budget.reserve(70).unwrap();
+        drop(first);
+        assert_eq!(budget.used(), 70);
+        drop(second);
+        assert_eq!(budget.used(), 0);
+    }
+}
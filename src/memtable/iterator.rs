@@ -19,21 +19,36 @@ pub struct MemTableIterator {
     #[not_covariant]
     iter: Range<'this, Key, (Bound<Key>, Bound<Key>), Key, Bytes>,
     item: (Bytes, Bytes),
+    // seq num 大于该值的版本被跳过，见 `MemTable::scan_at`
+    max_seq: u64,
 }
 
 impl MemTableIterator {
-    pub fn create(map: Arc<SkipMap<Key, Bytes>>, lower: Bound<Key>, upper: Bound<Key>) -> Self {
+    pub fn create(
+        map: Arc<SkipMap<Key, Bytes>>,
+        lower: Bound<Key>,
+        upper: Bound<Key>,
+        max_seq: u64,
+    ) -> Self {
         let mut iter = MemTableIteratorBuilder {
             map,
             iter_builder: |map| map.range((lower, upper)),
             item: (Bytes::from_static(&[]), Bytes::from_static(&[])),
+            max_seq,
         }
         .build();
-        let entry = iter.with_iter_mut(|iter| MemTableIterator::entry_to_item(iter.next()));
-        iter.with_mut(|x| *x.item = entry);
+        iter.advance();
         iter
     }
 
+    fn advance(&mut self) {
+        let max_seq = *self.borrow_max_seq();
+        let entry = self.with_iter_mut(|iter| {
+            MemTableIterator::entry_to_item(iter.find(|e| e.key().seq_num <= max_seq))
+        });
+        self.with_mut(|x| *x.item = entry);
+    }
+
     fn entry_to_item(entry: Option<Entry<'_, Key, Bytes>>) -> (Bytes, Bytes) {
         entry
             .map(|x| (x.key().user_key.clone(), x.value().clone()))
@@ -59,8 +74,7 @@ impl StorageIterator for MemTableIterator {
     }
 
     fn next(&mut self) -> Result<()> {
-        let entry = self.with_iter_mut(|iter| MemTableIterator::entry_to_item(iter.next()));
-        self.with_mut(|x| *x.item = entry);
+        self.advance();
         Ok(())
     }
 }
@@ -1,7 +1,8 @@
 use std::ops::Bound;
 use std::sync::atomic::{AtomicUsize, Ordering};
-use std::sync::Arc;
+use std::sync::{Arc, OnceLock};
 
+use bloomfilter::Bloom;
 use bytes::Bytes;
 
 use crossbeam_skiplist::SkipMap;
@@ -11,11 +12,23 @@ use crate::memtable::iterator::MemTableIterator;
 
 use crate::Key;
 use crate::OpType;
+use crate::BLOOM_FALSE_POSITIVE_RATE;
+use crate::MAX_SEQ_NUM;
+
+/// 冻结后的 memtable 不再写入，记录其中 user key 的范围和 bloom filter，点查时不用访问跳表就能跳过大部分不含 key 的 memtable
+#[derive(Debug)]
+struct FrozenIndex {
+    first_key: Bytes,
+    last_key: Bytes,
+    bloom: Bloom<Bytes>,
+}
 
 #[derive(Debug)]
 pub struct MemTable {
     db: Arc<SkipMap<Key, Bytes>>,
     size: AtomicUsize,
+    // 调用 `freeze` 之后才有，空 memtable 冻结后为 `None`
+    frozen: OnceLock<Option<FrozenIndex>>,
 }
 
 impl MemTable {
@@ -23,24 +36,26 @@ impl MemTable {
         MemTable {
             db: Arc::new(SkipMap::new()),
             size: AtomicUsize::new(0),
+            frozen: OnceLock::new(),
         }
     }
 
     #[instrument(skip_all)]
     pub fn put(&self, key: Key, value: Bytes) {
+        debug_assert!(self.frozen.get().is_none(), "put into frozen memtable");
         self.size
             .fetch_add(key.len() + value.len(), Ordering::Release);
         self.db.insert(key, value);
     }
 
+    /// 查找 key 的最新版本，删除标记也会被返回（`op_type` 为 `Delete`），
+    /// 调用方据此判断是否需要继续向更旧的数据查找
     #[instrument(skip_all)]
     pub fn get(&self, key: &Key) -> Option<(Key, Bytes)> {
         match self.db.range(key..).next() {
             None => None,
             Some(e) => {
-                if e.key().op_type == OpType::Delete {
-                    None
-                } else if e.key().user_key != key.user_key {
+                if e.key().user_key != key.user_key {
                     None
                 } else {
                     Some((e.key().clone(), e.value().clone()))
@@ -50,15 +65,32 @@ impl MemTable {
     }
 
     pub fn scan(&self, begin: Bound<Bytes>, end: Bound<Bytes>) -> MemTableIterator {
+        self.scan_at(begin, end, MAX_SEQ_NUM)
+    }
+
+    /// 同 [`MemTable::scan`]，只返回 seq num 不超过 `max_seq` 的版本，即提交序号为 `max_seq` 时可见的数据
+    pub fn scan_at(
+        &self,
+        begin: Bound<Bytes>,
+        end: Bound<Bytes>,
+        max_seq: u64,
+    ) -> MemTableIterator {
         let bytes_2_key = |bound| match bound {
-            Bound::Included(_key) => Bound::Included(Key::new(_key, 1 << (7 - 1), OpType::Get)),
-            Bound::Excluded(_key) => Bound::Included(Key::new(_key, 1 << (7 - 1), OpType::Get)),
+            Bound::Included(_key) => Bound::Included(Key::new(_key, MAX_SEQ_NUM, OpType::Get)),
+            Bound::Excluded(_key) => Bound::Included(Key::new(_key, MAX_SEQ_NUM, OpType::Get)),
             Bound::Unbounded => Bound::Unbounded,
         };
-        let (lower, upper) = (bytes_2_key(begin), bytes_2_key(end));
-        MemTableIterator::create(self.db.clone(), lower, upper)
+        let lower = bytes_2_key(begin);
+        // 同一 user key 中 seq num 越大越靠前，包含上界时要用最靠后的 key，否则会漏掉上界 key 本身
+        let upper = match end {
+            Bound::Included(_key) => Bound::Included(Key::new(_key, 0, OpType::Put)),
+            _bound => bytes_2_key(_bound),
+        };
+        MemTableIterator::create(self.db.clone(), lower, upper, max_seq)
     }
 
+    /// 按 internal key 的顺序遍历所有版本：user key 升序，同一 user key 按 seq num 从新到旧，
+    /// 即每个 user key 遇到的第一项就是它的最新版本
     pub fn for_each<F: FnMut(&Key, &Bytes)>(&self, mut f: F) {
         for e in self.db.iter() {
             f(e.key(), e.value())
@@ -68,6 +100,46 @@ impl MemTable {
     pub fn clear(&mut self) {
         self.size.store(0, Ordering::Release);
         self.db.clear();
+        self.frozen.take();
+    }
+
+    /// 标记 memtable 不再写入，并建立 key 范围和 bloom filter 供 [`MemTable::may_contain`] 使用，
+    /// 需要遍历一次所有 key，重复调用时只建立一次
+    pub fn freeze(&self) {
+        self.frozen.get_or_init(|| {
+            let first_key = self.db.front()?.key().user_key.clone();
+            let last_key = self.db.back()?.key().user_key.clone();
+            let mut user_keys = Vec::new();
+            for e in self.db.iter() {
+                if user_keys.last() != Some(&e.key().user_key) {
+                    user_keys.push(e.key().user_key.clone());
+                }
+            }
+            let mut bloom = Bloom::new_for_fp_rate(user_keys.len(), BLOOM_FALSE_POSITIVE_RATE);
+            user_keys.iter().for_each(|key| bloom.set(key));
+            Some(FrozenIndex {
+                first_key,
+                last_key,
+                bloom,
+            })
+        });
+    }
+
+    pub fn is_frozen(&self) -> bool {
+        self.frozen.get().is_some()
+    }
+
+    /// memtable 中是否可能有 `user_key` 的版本，返回 `false` 时一定没有；未冻结时总是返回 `true`
+    pub fn may_contain(&self, user_key: &Bytes) -> bool {
+        match self.frozen.get() {
+            None => true,
+            Some(None) => false,
+            Some(Some(index)) => {
+                *user_key >= index.first_key
+                    && *user_key <= index.last_key
+                    && index.bloom.check(user_key)
+            }
+        }
     }
 
     pub fn size(&self) -> usize {
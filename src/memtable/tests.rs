@@ -60,3 +60,45 @@ fn test_memtable_iterator() {
     iter.next().unwrap();
     assert_eq!(iter.value(), Bytes::from("v3"));
 }
+
+#[test]
+fn test_memtable_freeze_index() {
+    let t = MemTable::new();
+    for i in (10..100).step_by(2) {
+        let key = Bytes::from(format!("k{:03}", i));
+        t.put(Key::new(key.clone(), i, OpType::Put), Bytes::from("v1"));
+        t.put(Key::new(key, i + 1, OpType::Delete), Bytes::new());
+    }
+    // 未冻结时不做判断
+    assert!(!t.is_frozen());
+    assert!(t.may_contain(&Bytes::from("k999")));
+
+    t.freeze();
+    t.freeze();
+    assert!(t.is_frozen());
+    for i in (10..100).step_by(2) {
+        assert!(t.may_contain(&Bytes::from(format!("k{:03}", i))));
+    }
+    // 范围外的 key 一定被排除，范围内不存在的 key 只有少量误判
+    assert!(!t.may_contain(&Bytes::from("k000")));
+    assert!(!t.may_contain(&Bytes::from("k100")));
+    assert!(!t.may_contain(&Bytes::from("a")));
+    let false_positives = (11..99)
+        .step_by(2)
+        .filter(|i| t.may_contain(&Bytes::from(format!("k{:03}", i))))
+        .count();
+    assert!(false_positives < 20, "{} false positives", false_positives);
+    // 删除标记仍需被查到
+    let (k, _) = t
+        .get(&Key::new(
+            Bytes::from("k010"),
+            crate::MAX_SEQ_NUM,
+            OpType::Get,
+        ))
+        .unwrap();
+    assert_eq!(k.op_type, OpType::Delete);
+
+    let empty = MemTable::new();
+    empty.freeze();
+    assert!(!empty.may_contain(&Bytes::from("k010")));
+}
@@ -1,39 +1,116 @@
+use std::collections::{HashMap, HashSet};
 use std::fmt::Debug;
 use std::mem;
 use std::path::Path;
 use std::sync::Arc;
+use std::time::{Duration, SystemTime};
 
 use anyhow::anyhow;
 use bytes::{Buf, BufMut, Bytes, BytesMut};
-use tracing::instrument;
+use tracing::{instrument, warn};
 
+use crate::meta::iterator::ManifestIterator;
 use crate::record::{Record, RecordItem};
-use crate::storage::file::FileStorage;
+use crate::stats::{CompactionSummary, CumulativeStats};
+use crate::storage::file::{sync_dir, FileStorage};
+use crate::storage::header::{FileHeader, FileType, FILE_HEADER_SIZE};
 
 #[derive(Debug)]
 pub struct Manifest {
     file: FileStorage,
     records: Vec<Arc<Record<ManifestItem>>>,
+    // 每条记录写入前 fsync 所在目录，写入后 fsync 文件，见 [`Manifest::set_strict_sync`]
+    strict_sync: bool,
 }
 
 impl Manifest {
     #[instrument]
     pub fn open(path: impl AsRef<Path> + Debug) -> anyhow::Result<Self> {
-        let file = FileStorage::open(path)?;
+        Self::open_with_compat(path, false)
+    }
+
+    /// 打开 MANIFEST，`skip_unknown_items` 为 true 时跳过当前版本不认识的变更（由更新的版本写入），
+    /// 否则遇到这样的变更时打开失败
+    ///
+    /// 跳过的变更在重放时被忽略，只适用于新增的变更类型可以安全忽略的场景，例如回退到旧版本
+    #[instrument]
+    pub fn open_with_compat(
+        path: impl AsRef<Path> + Debug,
+        skip_unknown_items: bool,
+    ) -> anyhow::Result<Self> {
+        let file = FileStorage::open(&path)?;
 
         let mut records = vec![];
         let mut buf = Bytes::from(file.read_to_end(0)?);
+        if buf.is_empty() {
+            file.write(&FileHeader::new(FileType::Manifest).encode())?;
+            file.sync()?;
+        } else {
+            // 加入文件头之前写入的 MANIFEST 以第一条记录的 checksum 开头，恒为 0
+            let is_legacy = |data: &[u8]| data.len() >= 4 && data[..4] == [0; 4];
+            if FileHeader::check(path.as_ref(), &buf, FileType::Manifest, is_legacy)?.is_some() {
+                buf.advance(FILE_HEADER_SIZE);
+            }
+        }
         while !buf.is_empty() {
-            records.push(Arc::new(Record::decode_with_bytes(&mut buf)?));
+            let record: Record<ManifestItem> = Record::decode_with_bytes(&mut buf)?;
+            for idx in 0..record.num_of_items() {
+                if let ManifestItem::Unknown(item_type, data_len) = record.item(idx) {
+                    if !skip_unknown_items {
+                        return Err(anyhow!(
+                            "unsupported manifest item type {} in {:?}, it may be written by a newer version, \
+                             open with `skip_unknown_manifest_items` to skip it",
+                            item_type,
+                            path
+                        ));
+                    }
+                    warn!(
+                        "skip unknown manifest item type {} ({} bytes) in record {} of {:?}",
+                        item_type,
+                        data_len,
+                        records.len(),
+                        path
+                    );
+                }
+            }
+            records.push(Arc::new(record));
         }
 
-        Ok(Self { file, records })
+        Ok(Self {
+            file,
+            records,
+            strict_sync: false,
+        })
     }
 
-    pub fn add(&mut self, r: &Record<ManifestItem>) {
+    /// 开启后每条记录写入前先 fsync MANIFEST 所在的目录，让记录引用的新文件在目录中落盘，
+    /// 写入后再 fsync MANIFEST 本身，[`Manifest::add`] 返回时这次状态变更在掉电后仍然有效，
+    /// 依赖它的文件删除只能发生在这之后
+    pub fn set_strict_sync(&mut self, strict_sync: bool) {
+        self.strict_sync = strict_sync;
+    }
+
+    pub fn add(&mut self, r: &Record<ManifestItem>) -> anyhow::Result<()> {
+        if self.strict_sync {
+            if let Some(dir) = self.file.path().parent() {
+                sync_dir(dir)?;
+            }
+        }
+        self.file.write(&r.encode())?;
+        #[cfg(feature = "durability-check")]
+        crate::storage::durability::on_manifest_record(self.file.path(), r, self.strict_sync);
+        if self.strict_sync {
+            self.file.sync_all()?;
+        } else {
+            self.file.sync()?;
+        }
         self.records.push(Arc::new(r.clone()));
-        self.file.write(&r.encode());
-        self.file.sync();
+        Ok(())
+    }
+
+    /// fsync MANIFEST，之前写入的记录在掉电后仍然有效
+    pub fn sync_all(&self) -> anyhow::Result<()> {
+        self.file.sync_all()
     }
 
     pub fn num_of_records(&self) -> usize {
@@ -53,6 +130,169 @@ impl Manifest {
     }
 }
 
+/// 重放 MANIFEST 得到的元数据状态
+#[derive(Debug, Default, Clone)]
+pub struct ManifestState {
+    /// level -> sst ids
+    pub sst_map: HashMap<u32, Vec<u64>>,
+    pub vsst_set: HashSet<u64>,
+    pub vsst_rc: HashMap<u64, u32>,
+    /// 冻结的 WAL，有顺序要求
+    pub frozen_log_ids: Vec<u64>,
+    /// log id -> 段数
+    pub wal_segments: HashMap<u64, u32>,
+    /// 等待删除的 SST / VSST 文件，按加入的顺序
+    pub pending_deletes: Vec<(FileType, u64)>,
+    /// 记录过的最大提交序号，不包括之后仍在 WAL 中的写入
+    pub commit_seq: u64,
+    /// 最近一次记录的累计计数
+    pub stats: CumulativeStats,
+    pub now_sst_id: u64,
+    pub now_vsst_id: u64,
+    pub now_log_id: u64,
+    pub seq_num: u64,
+}
+
+impl ManifestState {
+    pub fn replay(manifest: Arc<Manifest>) -> anyhow::Result<Self> {
+        let mut state = ManifestState {
+            seq_num: 1,
+            ..Default::default()
+        };
+        if manifest.num_of_records() == 0 {
+            return Ok(state);
+        }
+        let mut iter = ManifestIterator::create_and_seek_to_first(manifest)?;
+        while iter.is_valid() {
+            state.apply(iter.record_item());
+            iter.next()?;
+        }
+        Ok(state)
+    }
+
+    /// 只重放前 `num_records` 条记录，得到当时的状态
+    pub fn replay_until(manifest: &Manifest, num_records: usize) -> anyhow::Result<Self> {
+        let mut state = ManifestState {
+            seq_num: 1,
+            ..Default::default()
+        };
+        for idx in 0..num_records.min(manifest.num_of_records()) {
+            state.apply_record(&*manifest.read_record(idx)?);
+        }
+        Ok(state)
+    }
+
+    pub(crate) fn apply_record(&mut self, record: &Record<ManifestItem>) {
+        for i in 0..record.num_of_items() {
+            self.apply(*record.item(i));
+        }
+    }
+
+    fn apply(&mut self, item: ManifestItem) {
+        match item {
+            ManifestItem::Init(_) => {}
+            ManifestItem::NewSst(level, sst_id) => {
+                // 每次打开数据库都会追加一份完整的 SST 列表，同一个 SST 只能出现一次
+                let ssts = self.sst_map.entry(level).or_default();
+                if !ssts.contains(&sst_id) {
+                    ssts.push(sst_id);
+                }
+                self.now_sst_id = self.now_sst_id.max(sst_id);
+            }
+            ManifestItem::DelSst(level, sst_id) => {
+                if let Some(vec) = self.sst_map.get_mut(&level) {
+                    vec.retain(|id| *id != sst_id);
+                }
+            }
+            ManifestItem::NewVSst(sst_id) => {
+                self.vsst_set.insert(sst_id);
+                self.now_vsst_id = self.now_vsst_id.max(sst_id);
+            }
+            ManifestItem::DelVSst(sst_id) => {
+                self.vsst_set.remove(&sst_id);
+            }
+            ManifestItem::MaxSeqNum(seq_num) => self.seq_num = seq_num,
+            ManifestItem::FreezeAndCreateWal(old_log_id, new_log_id) => {
+                self.now_log_id = new_log_id;
+                self.wal_segments.entry(new_log_id).or_insert(1);
+                if old_log_id != new_log_id {
+                    self.frozen_log_ids.push(old_log_id);
+                }
+            }
+            ManifestItem::DelFrozenWal(log_id) => {
+                self.frozen_log_ids.retain(|item| item != &log_id);
+                self.wal_segments.remove(&log_id);
+            }
+            ManifestItem::NewWalSegment(log_id, segment_id) => {
+                let num_segments = self.wal_segments.entry(log_id).or_insert(1);
+                *num_segments = (*num_segments).max(segment_id + 1);
+            }
+            ManifestItem::VSstRefCnt(vsst_id, cnt) => {
+                if cnt == 0 {
+                    self.vsst_rc.remove(&vsst_id);
+                } else {
+                    self.vsst_rc.insert(vsst_id, cnt);
+                }
+            }
+            ManifestItem::PendingDelete(file_type, id) => {
+                if !self.pending_deletes.contains(&(file_type, id)) {
+                    self.pending_deletes.push((file_type, id));
+                }
+            }
+            ManifestItem::FileDeleted(file_type, id) => {
+                self.pending_deletes.retain(|item| item != &(file_type, id));
+            }
+            ManifestItem::CommitSeq(seq) => self.commit_seq = self.commit_seq.max(seq),
+            ManifestItem::Stats(stats) => self.stats = stats,
+            ManifestItem::CompactionSummary(_)
+            | ManifestItem::Origin(_)
+            | ManifestItem::Unknown(_, _) => {}
+        }
+    }
+
+    /// 生成一组重放后能得到当前状态的变更，用于重写 MANIFEST
+    pub fn to_items(&self, version: i32) -> Vec<ManifestItem> {
+        let mut items = vec![ManifestItem::Init(version)];
+
+        // 按冻结顺序串起所有 WAL，最后一个为当前 WAL
+        let mut log_ids = self.frozen_log_ids.clone();
+        log_ids.push(self.now_log_id);
+        items.push(ManifestItem::FreezeAndCreateWal(log_ids[0], log_ids[0]));
+        for pair in log_ids.windows(2) {
+            items.push(ManifestItem::FreezeAndCreateWal(pair[0], pair[1]));
+        }
+        for log_id in &log_ids {
+            let num_segments = self.wal_segments.get(log_id).cloned().unwrap_or(1);
+            if num_segments > 1 {
+                items.push(ManifestItem::NewWalSegment(*log_id, num_segments - 1));
+            }
+        }
+
+        let mut levels: Vec<_> = self.sst_map.iter().collect();
+        levels.sort_by_key(|(level, _)| **level);
+        for (level, sst_ids) in levels {
+            for sst_id in sst_ids {
+                items.push(ManifestItem::NewSst(*level, *sst_id));
+            }
+        }
+        let mut vsst_ids: Vec<_> = self.vsst_set.iter().cloned().collect();
+        vsst_ids.sort();
+        for vsst_id in vsst_ids {
+            items.push(ManifestItem::NewVSst(vsst_id));
+            if let Some(cnt) = self.vsst_rc.get(&vsst_id) {
+                items.push(ManifestItem::VSstRefCnt(vsst_id, *cnt));
+            }
+        }
+        for (file_type, id) in &self.pending_deletes {
+            items.push(ManifestItem::PendingDelete(*file_type, *id));
+        }
+        items.push(ManifestItem::MaxSeqNum(self.seq_num));
+        items.push(ManifestItem::CommitSeq(self.commit_seq));
+        items.push(ManifestItem::Stats(self.stats));
+        items
+    }
+}
+
 /// `ManifestItem` 是元数据的一次变更
 /// layout
 /// ```text
@@ -60,29 +300,158 @@ impl Manifest {
 /// | record type(1byte) | data len(4bytes) | data |
 /// +--------------------+------------------+------+
 /// ```
+///
+/// 文件编号（sst_id、vsst_id、log_id）为 u64，都不超过 u32 时按旧格式以 u32 写入，
+/// 否则 record type 加上 [`WIDE_ITEM_FLAG`] 并以 u64 写入，旧版本会把这样的变更当作不认识的变更
 #[derive(Copy, Clone, Debug)]
 pub enum ManifestItem {
     /// 初始化（version)
     Init(i32),
     /// 新增 SST 文件 (level, sst_id)
-    NewSst(u32, u32),
+    NewSst(u32, u64),
     /// 删除 SST 文件 (level, sst_id)
-    DelSst(u32, u32),
+    DelSst(u32, u64),
     /// 新增 vSST 文件 (vsst_id)
-    NewVSst(u32),
+    NewVSst(u64),
     /// 删除 vSST 文件 (vsst_id)
-    DelVSst(u32),
+    DelVSst(u64),
     /// 更新最大 seq num
     MaxSeqNum(u64),
     /// 冻结旧 WAL 并创建新 WAL
     /// (old_log_id, new_log_id)
     ///
     /// old_log_id == new_log_id 时是创建（第一个WAL)
-    FreezeAndCreateWal(u32, u32),
+    FreezeAndCreateWal(u64, u64),
     /// 删除冻结 WAL
-    DelFrozenWal(u32),
+    DelFrozenWal(u64),
     /// VSST 引用计数 (vsst_id, referenced_cnt)
-    VSstRefCnt(u32, u32),
+    VSstRefCnt(u64, u32),
+    /// WAL 新增段 (log_id, segment_id)
+    NewWalSegment(u64, u32),
+    /// 已从元数据中移除、等待后台删除的 SST / VSST 文件 (file_type, id)
+    ///
+    /// 与移除文件的变更写在同一条记录中，删除完成前崩溃时重启后继续删除
+    PendingDelete(FileType, u64),
+    /// 等待删除的文件已删除 (file_type, id)
+    FileDeleted(FileType, u64),
+    /// 冻结 WAL 时已提交的写入序号，见 [`crate::sequence::CommitSequence`]
+    CommitSeq(u64),
+    /// 刷写或合并完成时的累计计数，见 [`CumulativeStats`]
+    Stats(CumulativeStats),
+    /// 一次合并的概要，只用于排查问题，重放时忽略，重写 MANIFEST 时不保留
+    CompactionSummary(CompactionSummary),
+    /// 写入这条记录的操作和时间，只用于审计，重放时忽略，重写 MANIFEST 时不保留
+    ///
+    /// 之前的版本写入的记录没有这一项，不包含该项的版本需要以兼容模式打开，见 [`Manifest::open_with_compat`]
+    Origin(RecordOrigin),
+    /// 当前版本不认识的变更 (item_type, data_len)，内容被丢弃，重放时忽略
+    ///
+    /// 只有以兼容模式打开时才会保留在 MANIFEST 中，见 [`Manifest::open_with_compat`]
+    Unknown(u8, u32),
+}
+
+/// 写入 MANIFEST 记录的操作，见 [`crate::admin::audit_log`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum ManifestActor {
+    /// 打开数据库时重写 MANIFEST，记录中列出当时的全部文件
+    Open,
+    /// 关闭数据库时记录提交序号和累计计数
+    Close,
+    /// 写入时 WAL 切换到新段
+    Write,
+    /// 冻结 memtable 并切换 WAL，以及刷写 memtable 到 SST
+    Flush,
+    /// 合并，包括后台合并、定期合并、[`crate::Db::compact`] 和导入的外部合并
+    Compaction,
+    /// 淘汰超出容量的 SST，以及删除不再被引用的文件
+    Gc,
+    /// 离线的管理操作，例如修复、复制、导出数据库和重建引用计数
+    Manual,
+    /// 更新的版本定义的操作
+    Unknown(u8),
+}
+
+impl ManifestActor {
+    fn encode(&self) -> u8 {
+        match self {
+            ManifestActor::Open => 0,
+            ManifestActor::Close => 1,
+            ManifestActor::Write => 2,
+            ManifestActor::Flush => 3,
+            ManifestActor::Compaction => 4,
+            ManifestActor::Gc => 5,
+            ManifestActor::Manual => 6,
+            ManifestActor::Unknown(actor) => *actor,
+        }
+    }
+
+    fn decode(actor: u8) -> Self {
+        match actor {
+            0 => ManifestActor::Open,
+            1 => ManifestActor::Close,
+            2 => ManifestActor::Write,
+            3 => ManifestActor::Flush,
+            4 => ManifestActor::Compaction,
+            5 => ManifestActor::Gc,
+            6 => ManifestActor::Manual,
+            _ => ManifestActor::Unknown(actor),
+        }
+    }
+}
+
+/// 写入一条 MANIFEST 记录的操作和时间，见 [`ManifestItem::Origin`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RecordOrigin {
+    pub actor: ManifestActor,
+    /// 写入记录时的系统时间，精确到微秒
+    pub time: SystemTime,
+}
+
+impl RecordOrigin {
+    /// 编码后的字节数，1 字节的操作之后是以微秒保存的时间
+    const ENCODED_SIZE: usize = 1 + 8;
+
+    pub fn now(actor: ManifestActor) -> Self {
+        Self {
+            actor,
+            time: SystemTime::now(),
+        }
+    }
+
+    fn encode(&self, buf: &mut BytesMut) {
+        let time = self
+            .time
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .unwrap_or_default();
+        buf.put_u8(self.actor.encode());
+        buf.put_u64_le(time.as_micros() as u64);
+    }
+
+    fn decode(bytes: &mut Bytes) -> Self {
+        Self {
+            actor: ManifestActor::decode(bytes.get_u8()),
+            time: SystemTime::UNIX_EPOCH + Duration::from_micros(bytes.get_u64_le()),
+        }
+    }
+}
+
+/// 文件编号以 u64 写入的变更在 record type 上加的标记
+pub const WIDE_ITEM_FLAG: u8 = 0x80;
+
+fn put_id(buf: &mut BytesMut, id: u64, wide: bool) {
+    if wide {
+        buf.put_u64_le(id);
+    } else {
+        buf.put_u32_le(id as u32);
+    }
+}
+
+fn get_id(bytes: &mut Bytes, wide: bool) -> u64 {
+    if wide {
+        bytes.get_u64_le()
+    } else {
+        bytes.get_u32_le() as u64
+    }
 }
 
 impl ManifestItem {
@@ -91,30 +460,59 @@ impl ManifestItem {
         Self::decode_with_bytes(&mut bytes)
     }
 
+    /// 是否有文件编号超过 u32，需要以 u64 写入
+    pub fn is_wide(&self) -> bool {
+        let ids: &[u64] = match self {
+            ManifestItem::NewSst(_, id)
+            | ManifestItem::DelSst(_, id)
+            | ManifestItem::NewVSst(id)
+            | ManifestItem::DelVSst(id)
+            | ManifestItem::DelFrozenWal(id)
+            | ManifestItem::VSstRefCnt(id, _)
+            | ManifestItem::NewWalSegment(id, _)
+            | ManifestItem::PendingDelete(_, id)
+            | ManifestItem::FileDeleted(_, id) => &[*id],
+            ManifestItem::FreezeAndCreateWal(old_id, new_id) => &[*old_id, *new_id],
+            _ => &[],
+        };
+        ids.iter().any(|id| *id > u32::MAX as u64)
+    }
+
     #[inline]
     pub fn type_encode(&self) -> u8 {
-        match self {
-            ManifestItem::Init(_) => 0,
-            ManifestItem::NewSst(_, _) => 1,
-            ManifestItem::DelSst(_, _) => 2,
-            ManifestItem::NewVSst(_) => 3,
-            ManifestItem::DelVSst(_) => 4,
-            ManifestItem::MaxSeqNum(_) => 5,
-            ManifestItem::FreezeAndCreateWal(_, _) => 6,
-            ManifestItem::DelFrozenWal(_) => 7,
-            ManifestItem::VSstRefCnt(_, _) => 8,
-        }
+        let wide_flag = if self.is_wide() { WIDE_ITEM_FLAG } else { 0 };
+        wide_flag
+            | match self {
+                ManifestItem::Init(_) => 0,
+                ManifestItem::NewSst(_, _) => 1,
+                ManifestItem::DelSst(_, _) => 2,
+                ManifestItem::NewVSst(_) => 3,
+                ManifestItem::DelVSst(_) => 4,
+                ManifestItem::MaxSeqNum(_) => 5,
+                ManifestItem::FreezeAndCreateWal(_, _) => 6,
+                ManifestItem::DelFrozenWal(_) => 7,
+                ManifestItem::VSstRefCnt(_, _) => 8,
+                ManifestItem::NewWalSegment(_, _) => 9,
+                ManifestItem::PendingDelete(_, _) => 10,
+                ManifestItem::FileDeleted(_, _) => 11,
+                ManifestItem::CommitSeq(_) => 12,
+                ManifestItem::Stats(_) => 13,
+                ManifestItem::CompactionSummary(_) => 14,
+                ManifestItem::Origin(_) => 15,
+                ManifestItem::Unknown(item_type, _) => *item_type,
+            }
     }
 
     pub fn put_content(&self, buf: &mut BytesMut) {
+        let wide = self.is_wide();
         match self {
             ManifestItem::NewSst(level, sst_id) => {
                 buf.put_u32_le(*level);
-                buf.put_u32_le(*sst_id)
+                put_id(buf, *sst_id, wide)
             }
             ManifestItem::DelSst(level, sst_id) => {
                 buf.put_u32_le(*level);
-                buf.put_u32_le(*sst_id)
+                put_id(buf, *sst_id, wide)
             }
             ManifestItem::MaxSeqNum(seq_num) => {
                 buf.put_u64_le(*seq_num);
@@ -122,32 +520,60 @@ impl ManifestItem {
             ManifestItem::Init(version) => {
                 buf.put_i32_le(*version);
             }
-            ManifestItem::NewVSst(vsst_id) => buf.put_u32_le(*vsst_id),
-            ManifestItem::DelVSst(vsst_id) => buf.put_u32_le(*vsst_id),
+            ManifestItem::NewVSst(vsst_id) => put_id(buf, *vsst_id, wide),
+            ManifestItem::DelVSst(vsst_id) => put_id(buf, *vsst_id, wide),
             ManifestItem::FreezeAndCreateWal(old_log_id, new_log_id) => {
-                buf.put_u32_le(*old_log_id);
-                buf.put_u32_le(*new_log_id);
+                put_id(buf, *old_log_id, wide);
+                put_id(buf, *new_log_id, wide);
             }
-            ManifestItem::DelFrozenWal(log_id) => buf.put_u32_le(*log_id),
+            ManifestItem::DelFrozenWal(log_id) => put_id(buf, *log_id, wide),
             ManifestItem::VSstRefCnt(vsst_id, cnt) => {
-                buf.put_u32_le(*vsst_id);
+                put_id(buf, *vsst_id, wide);
                 buf.put_u32_le(*cnt);
             }
+            ManifestItem::NewWalSegment(log_id, segment_id) => {
+                put_id(buf, *log_id, wide);
+                buf.put_u32_le(*segment_id);
+            }
+            ManifestItem::PendingDelete(file_type, id)
+            | ManifestItem::FileDeleted(file_type, id) => {
+                buf.put_u8(file_type.encode());
+                put_id(buf, *id, wide);
+            }
+            ManifestItem::CommitSeq(seq) => buf.put_u64_le(*seq),
+            ManifestItem::Stats(stats) => stats.encode(buf),
+            ManifestItem::CompactionSummary(summary) => summary.encode(buf),
+            ManifestItem::Origin(origin) => origin.encode(buf),
+            // 内容已丢弃，以 0 填充保持长度不变
+            ManifestItem::Unknown(_, data_len) => buf.put_bytes(0, *data_len as usize),
         }
     }
 
     #[inline]
     pub fn content_size(&self) -> usize {
+        let id_size = if self.is_wide() {
+            mem::size_of::<u64>()
+        } else {
+            mem::size_of::<u32>()
+        };
         match self {
-            ManifestItem::NewSst(_, _) => mem::size_of::<u32>() * 2,
-            ManifestItem::DelSst(_, _) => mem::size_of::<u32>() * 2,
-            ManifestItem::NewVSst(_) => mem::size_of::<u32>(),
-            ManifestItem::DelVSst(_) => mem::size_of::<u32>(),
+            ManifestItem::NewSst(_, _) => mem::size_of::<u32>() + id_size,
+            ManifestItem::DelSst(_, _) => mem::size_of::<u32>() + id_size,
+            ManifestItem::NewVSst(_) => id_size,
+            ManifestItem::DelVSst(_) => id_size,
             ManifestItem::MaxSeqNum(_) => mem::size_of::<u64>(),
             ManifestItem::Init(_) => mem::size_of::<i32>(),
-            ManifestItem::FreezeAndCreateWal(_, _) => mem::size_of::<u32>() * 2,
-            ManifestItem::DelFrozenWal(_) => mem::size_of::<u32>(),
-            ManifestItem::VSstRefCnt(_, _) => mem::size_of::<u32>() * 2,
+            ManifestItem::FreezeAndCreateWal(_, _) => id_size * 2,
+            ManifestItem::DelFrozenWal(_) => id_size,
+            ManifestItem::VSstRefCnt(_, _) => id_size + mem::size_of::<u32>(),
+            ManifestItem::NewWalSegment(_, _) => id_size + mem::size_of::<u32>(),
+            ManifestItem::PendingDelete(_, _) => mem::size_of::<u8>() + id_size,
+            ManifestItem::FileDeleted(_, _) => mem::size_of::<u8>() + id_size,
+            ManifestItem::CommitSeq(_) => mem::size_of::<u64>(),
+            ManifestItem::Stats(_) => CumulativeStats::ENCODED_SIZE,
+            ManifestItem::CompactionSummary(_) => CompactionSummary::ENCODED_SIZE,
+            ManifestItem::Origin(_) => RecordOrigin::ENCODED_SIZE,
+            ManifestItem::Unknown(_, data_len) => *data_len as usize,
         }
     }
 }
@@ -163,7 +589,15 @@ impl RecordItem for ManifestItem {
 
     fn decode_with_bytes(bytes: &mut Bytes) -> anyhow::Result<Self> {
         let item_type = bytes.get_u8();
-        let _data_len = bytes.get_u32_le();
+        let data_len = bytes.get_u32_le();
+        // 只有带文件编号的变更才有 u64 格式
+        let wide = item_type & WIDE_ITEM_FLAG != 0
+            && matches!(item_type & !WIDE_ITEM_FLAG, 1..=4 | 6..=11);
+        let item_type = if wide {
+            item_type & !WIDE_ITEM_FLAG
+        } else {
+            item_type
+        };
         match item_type {
             0 => {
                 let version = bytes.get_i32_le();
@@ -171,20 +605,20 @@ impl RecordItem for ManifestItem {
             }
             1 => {
                 let level = bytes.get_u32_le();
-                let sst_id = bytes.get_u32_le();
+                let sst_id = get_id(bytes, wide);
                 Ok(ManifestItem::NewSst(level, sst_id))
             }
             2 => {
                 let level = bytes.get_u32_le();
-                let sst_id = bytes.get_u32_le();
+                let sst_id = get_id(bytes, wide);
                 Ok(ManifestItem::DelSst(level, sst_id))
             }
             3 => {
-                let sst_id = bytes.get_u32_le();
+                let sst_id = get_id(bytes, wide);
                 Ok(ManifestItem::NewVSst(sst_id))
             }
             4 => {
-                let sst_id = bytes.get_u32_le();
+                let sst_id = get_id(bytes, wide);
                 Ok(ManifestItem::DelVSst(sst_id))
             }
             5 => {
@@ -192,20 +626,57 @@ impl RecordItem for ManifestItem {
                 Ok(ManifestItem::MaxSeqNum(seq_num))
             }
             6 => {
-                let old_log_id = bytes.get_u32_le();
-                let new_log_id = bytes.get_u32_le();
+                let old_log_id = get_id(bytes, wide);
+                let new_log_id = get_id(bytes, wide);
                 Ok(ManifestItem::FreezeAndCreateWal(old_log_id, new_log_id))
             }
             7 => {
-                let log_id = bytes.get_u32_le();
+                let log_id = get_id(bytes, wide);
                 Ok(ManifestItem::DelFrozenWal(log_id))
             }
             8 => {
-                let vsst_id = bytes.get_u32_le();
+                let vsst_id = get_id(bytes, wide);
                 let cnt = bytes.get_u32_le();
                 Ok(ManifestItem::VSstRefCnt(vsst_id, cnt))
             }
-            _ => Err(anyhow!("unsupported record item type: {}", item_type)),
+            9 => {
+                let log_id = get_id(bytes, wide);
+                let segment_id = bytes.get_u32_le();
+                Ok(ManifestItem::NewWalSegment(log_id, segment_id))
+            }
+            10 | 11 => {
+                let file_type = FileType::decode(bytes.get_u8()).ok_or(anyhow!(
+                    "invalid file type in record item type {}",
+                    item_type
+                ))?;
+                let id = get_id(bytes, wide);
+                Ok(match item_type {
+                    10 => ManifestItem::PendingDelete(file_type, id),
+                    _ => ManifestItem::FileDeleted(file_type, id),
+                })
+            }
+            12 => {
+                let seq = bytes.get_u64_le();
+                Ok(ManifestItem::CommitSeq(seq))
+            }
+            13 => Ok(ManifestItem::Stats(CumulativeStats::decode(bytes))),
+            14 => Ok(ManifestItem::CompactionSummary(CompactionSummary::decode(
+                bytes,
+            ))),
+            15 => Ok(ManifestItem::Origin(RecordOrigin::decode(bytes))),
+            // 由更新的版本写入，按长度跳过，由 `Manifest::open_with_compat` 决定是否接受
+            _ => {
+                if bytes.remaining() < data_len as usize {
+                    return Err(anyhow!(
+                        "unsupported record item type: {}, data len {} exceeds remaining {} bytes",
+                        item_type,
+                        data_len,
+                        bytes.remaining()
+                    ));
+                }
+                bytes.advance(data_len as usize);
+                Ok(ManifestItem::Unknown(item_type, data_len))
+            }
         }
     }
 
@@ -1,7 +1,10 @@
 use crate::meta::iterator::ManifestIterator;
-use crate::meta::manifest::{Manifest, ManifestItem};
+use crate::meta::manifest::{Manifest, ManifestActor, ManifestItem, ManifestState, RecordOrigin};
 use crate::record::{RecordBuilder, RecordItem};
+use crate::stats::CumulativeStats;
+use crate::storage::header::{FileFormatError, FileType, FILE_HEADER_SIZE};
 use std::sync::Arc;
+use std::time::{Duration, SystemTime};
 
 #[test]
 fn test_manifest() {
@@ -20,7 +23,7 @@ fn test_manifest() {
             for item in &items {
                 rbuilder.add(*item)
             }
-            m.add(&rbuilder.build());
+            m.add(&rbuilder.build()).unwrap();
         }
     }
 
@@ -35,3 +38,249 @@ fn test_manifest() {
         manifest_iter.next().unwrap();
     }
 }
+
+#[test]
+fn test_manifest_state_round_trip() {
+    let path = tempfile::tempdir().unwrap();
+    let path = path.path();
+
+    let items = vec![
+        ManifestItem::Init(1),
+        ManifestItem::FreezeAndCreateWal(0, 0),
+        ManifestItem::NewSst(0, 1),
+        ManifestItem::NewSst(0, 1),
+        ManifestItem::NewVSst(1),
+        ManifestItem::VSstRefCnt(1, 3),
+        ManifestItem::FreezeAndCreateWal(0, 1),
+        ManifestItem::FreezeAndCreateWal(1, 2),
+        ManifestItem::NewWalSegment(2, 1),
+        ManifestItem::NewSst(1, 2),
+        ManifestItem::DelFrozenWal(0),
+        ManifestItem::PendingDelete(FileType::Sst, 3),
+        ManifestItem::PendingDelete(FileType::VSst, 2),
+        ManifestItem::PendingDelete(FileType::Sst, 4),
+        ManifestItem::FileDeleted(FileType::Sst, 3),
+        ManifestItem::CommitSeq(12),
+        ManifestItem::CommitSeq(9),
+        ManifestItem::Stats(CumulativeStats {
+            bytes_written: 100,
+            flushes: 1,
+            ..Default::default()
+        }),
+        ManifestItem::Stats(CumulativeStats {
+            bytes_written: 200,
+            flushes: 2,
+            gc_bytes: 3,
+            ..Default::default()
+        }),
+        ManifestItem::MaxSeqNum(7),
+    ];
+    let state = {
+        let mut m = Manifest::open(path.join("MANIFEST")).unwrap();
+        let mut rbuilder: RecordBuilder<ManifestItem> = RecordBuilder::new();
+        items.iter().for_each(|item| rbuilder.add(*item));
+        m.add(&rbuilder.build()).unwrap();
+        ManifestState::replay(Arc::new(m)).unwrap()
+    };
+    assert_eq!(state.sst_map[&0], vec![1]);
+    assert_eq!(state.sst_map[&1], vec![2]);
+    assert_eq!(state.frozen_log_ids, vec![1]);
+    assert_eq!(state.now_log_id, 2);
+    assert_eq!(state.wal_segments[&2], 2);
+    assert_eq!(state.vsst_rc[&1], 3);
+    assert_eq!(state.seq_num, 7);
+    assert_eq!(state.commit_seq, 12);
+    assert_eq!(state.stats.bytes_written, 200);
+    assert_eq!(state.stats.gc_bytes, 3);
+    assert_eq!(
+        state.pending_deletes,
+        vec![(FileType::VSst, 2), (FileType::Sst, 4)]
+    );
+
+    // 用 to_items 重写后重放得到相同的状态
+    let mut m = Manifest::open(path.join("MANIFEST2")).unwrap();
+    let mut rbuilder: RecordBuilder<ManifestItem> = RecordBuilder::new();
+    state
+        .to_items(1)
+        .into_iter()
+        .for_each(|item| rbuilder.add(item));
+    m.add(&rbuilder.build()).unwrap();
+    let rewritten = ManifestState::replay(Arc::new(m)).unwrap();
+    assert_eq!(rewritten.sst_map, state.sst_map);
+    assert_eq!(rewritten.vsst_set, state.vsst_set);
+    assert_eq!(rewritten.vsst_rc, state.vsst_rc);
+    assert_eq!(rewritten.frozen_log_ids, state.frozen_log_ids);
+    assert_eq!(rewritten.wal_segments, state.wal_segments);
+    assert_eq!(rewritten.now_log_id, state.now_log_id);
+    assert_eq!(rewritten.seq_num, state.seq_num);
+    assert_eq!(rewritten.pending_deletes, state.pending_deletes);
+    assert_eq!(rewritten.commit_seq, state.commit_seq);
+    assert_eq!(rewritten.stats, state.stats);
+}
+
+#[test]
+fn test_manifest_file_header() {
+    let path = tempfile::tempdir().unwrap();
+    let path = path.path().join("MANIFEST");
+    {
+        let mut m = Manifest::open(&path).unwrap();
+        let mut rbuilder: RecordBuilder<ManifestItem> = RecordBuilder::new();
+        rbuilder.add(ManifestItem::Init(1));
+        m.add(&rbuilder.build()).unwrap();
+    }
+    let data = std::fs::read(&path).unwrap();
+    assert_eq!(&data[..4], b"LSGN");
+
+    // 没有文件头的旧 MANIFEST
+    std::fs::write(&path, &data[FILE_HEADER_SIZE..]).unwrap();
+    assert_eq!(Manifest::open(&path).unwrap().num_of_records(), 1);
+
+    // 其他文件
+    std::fs::write(&path, b"not a manifest").unwrap();
+    let err = Manifest::open(&path).unwrap_err();
+    assert_eq!(
+        err.downcast_ref::<FileFormatError>(),
+        Some(&FileFormatError::BadMagic {
+            path: path.clone(),
+            expected: FileType::Manifest,
+        })
+    );
+
+    // 其他类型的数据文件
+    let mut log = data.clone();
+    log[4] = 4;
+    std::fs::write(&path, &log).unwrap();
+    let err = Manifest::open(&path).unwrap_err();
+    assert!(matches!(
+        err.downcast_ref::<FileFormatError>(),
+        Some(FileFormatError::WrongType {
+            expected: FileType::Manifest,
+            actual: FileType::Log,
+            ..
+        })
+    ));
+}
+
+#[test]
+fn test_manifest_unknown_item() {
+    let path = tempfile::tempdir().unwrap();
+    let path = path.path().join("MANIFEST");
+    {
+        let mut m = Manifest::open(&path).unwrap();
+        let mut rbuilder: RecordBuilder<ManifestItem> = RecordBuilder::new();
+        rbuilder.add(ManifestItem::Init(1));
+        rbuilder.add(ManifestItem::NewSst(0, 1));
+        m.add(&rbuilder.build()).unwrap();
+        // 模拟更新的版本写入的变更
+        let mut rbuilder: RecordBuilder<ManifestItem> = RecordBuilder::new();
+        rbuilder.add(ManifestItem::Unknown(200, 6));
+        rbuilder.add(ManifestItem::NewSst(0, 2));
+        m.add(&rbuilder.build()).unwrap();
+    }
+
+    let err = Manifest::open(&path).unwrap_err();
+    assert!(err
+        .to_string()
+        .contains("unsupported manifest item type 200"));
+
+    let m = Arc::new(Manifest::open_with_compat(&path, true).unwrap());
+    assert_eq!(m.num_of_records(), 2);
+    assert!(matches!(
+        m.read_record(1).unwrap().item(0),
+        ManifestItem::Unknown(200, 6)
+    ));
+    // 跳过未知变更后，同一条记录中的其他变更照常重放
+    let state = ManifestState::replay(m).unwrap();
+    assert_eq!(state.sst_map[&0], vec![1, 2]);
+}
+
+#[test]
+fn test_manifest_wide_file_ids() {
+    // 文件编号不超过 u32 时按旧格式写入
+    let legacy = ManifestItem::NewSst(2, 7).encode();
+    assert_eq!(&legacy[..], &[1, 8, 0, 0, 0, 2, 0, 0, 0, 7, 0, 0, 0]);
+    assert!(matches!(
+        ManifestItem::decode(&legacy).unwrap(),
+        ManifestItem::NewSst(2, 7)
+    ));
+
+    let wide_id = u32::MAX as u64 + 1;
+    let items = vec![
+        ManifestItem::NewSst(1, wide_id),
+        ManifestItem::DelSst(1, wide_id),
+        ManifestItem::NewVSst(wide_id + 1),
+        ManifestItem::VSstRefCnt(wide_id + 1, 3),
+        ManifestItem::FreezeAndCreateWal(7, wide_id + 2),
+        ManifestItem::NewWalSegment(wide_id + 2, 1),
+        ManifestItem::PendingDelete(FileType::Sst, wide_id),
+        ManifestItem::FileDeleted(FileType::Sst, wide_id),
+        ManifestItem::DelVSst(wide_id + 1),
+        ManifestItem::DelFrozenWal(wide_id + 2),
+    ];
+    for item in &items {
+        let encoded = item.encode();
+        assert!(encoded[0] & 0x80 != 0, "{:?}", item);
+        assert_eq!(encoded.len(), item.size());
+        let decoded = ManifestItem::decode(&encoded).unwrap();
+        assert_eq!(decoded.encode(), encoded, "{:?}", item);
+    }
+
+    let path = tempfile::tempdir().unwrap();
+    let path = path.path();
+    {
+        let mut m = Manifest::open(path.join("MANIFEST")).unwrap();
+        let mut rbuilder: RecordBuilder<ManifestItem> = RecordBuilder::new();
+        rbuilder.add(ManifestItem::Init(1));
+        rbuilder.add(ManifestItem::FreezeAndCreateWal(7, 7));
+        rbuilder.add(ManifestItem::NewSst(0, 5));
+        for item in &items[..5] {
+            rbuilder.add(*item);
+        }
+        m.add(&rbuilder.build()).unwrap();
+    }
+    let m = Arc::new(Manifest::open(path.join("MANIFEST")).unwrap());
+    let state = ManifestState::replay(m).unwrap();
+    assert_eq!(state.sst_map[&0], vec![5]);
+    assert!(state.sst_map[&1].is_empty());
+    assert_eq!(state.now_sst_id, wide_id);
+    assert_eq!(state.now_vsst_id, wide_id + 1);
+    assert_eq!(state.vsst_rc[&(wide_id + 1)], 3);
+    assert_eq!(state.now_log_id, wide_id + 2);
+    assert_eq!(state.frozen_log_ids, vec![7]);
+}
+
+#[test]
+fn test_manifest_record_origin() {
+    let time = SystemTime::UNIX_EPOCH + Duration::from_micros(1_700_000_000_123_456);
+    for actor in [ManifestActor::Compaction, ManifestActor::Unknown(99)] {
+        let item = ManifestItem::Origin(RecordOrigin { actor, time });
+        let encoded = item.encode();
+        assert_eq!(encoded[0], 15);
+        assert_eq!(encoded.len(), item.size());
+        // 更新的版本定义的操作按原值保留
+        assert!(matches!(
+            ManifestItem::decode(&encoded).unwrap(),
+            ManifestItem::Origin(origin) if origin == RecordOrigin { actor, time }
+        ));
+    }
+
+    // 重放时忽略，不影响同一条记录中的其他变更
+    let path = tempfile::tempdir().unwrap();
+    let path = path.path().join("MANIFEST");
+    {
+        let mut m = Manifest::open(&path).unwrap();
+        let mut rbuilder: RecordBuilder<ManifestItem> = RecordBuilder::new();
+        rbuilder.add(ManifestItem::Init(1));
+        rbuilder.add(ManifestItem::Origin(RecordOrigin::now(
+            ManifestActor::Flush,
+        )));
+        rbuilder.add(ManifestItem::NewSst(0, 1));
+        m.add(&rbuilder.build()).unwrap();
+    }
+    let state = ManifestState::replay(Arc::new(Manifest::open(&path).unwrap())).unwrap();
+    assert_eq!(state.sst_map[&0], vec![1]);
+    assert!(state
+        .to_items(1)
+        .iter()
+        .all(|item| !matches!(item, ManifestItem::Origin(_))));
+}
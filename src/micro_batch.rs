@@ -0,0 +1,208 @@
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, Instant};
+
+use anyhow::anyhow;
+use parking_lot::{Condvar, Mutex};
+
+use crate::db::{DbClosedError, ReadOnlyError};
+use crate::entry::Entry;
+use crate::{MICRO_BATCH_MAX_BYTES, MICRO_BATCH_MAX_DELAY, MICRO_BATCH_MAX_ENTRY_SIZE};
+
+/// 小写入的分组提交，见 [`crate::DbOptions::micro_batch`]
+///
+/// 并发的小 put / delete 在写入 WAL 之前先汇集成一组：没有组正在提交时写入立即提交；
+/// 有组正在提交时，之后到达的写入汇集成下一组，由其中第一个写入（组长）等到前一组提交完成、
+/// 最多等待 `max_delay` 后，把整组作为一条 WAL 记录写入、只刷写一次写缓冲，并一次写入 memtable。
+/// 写入稀疏时不增加延迟，并发越高每组越大。
+/// 组内的写入共用一个提交序号，整组同时可见，同一个 key 在组内的后一次写入生效。
+/// 每个写入都等到所在的组提交后才返回，返回前的读取读不到它，等待时间不超过 `max_delay` 加上一次提交的时间
+#[derive(Debug, Clone)]
+pub struct MicroBatchOptions {
+    /// key 和 value 总字节数不超过该值的单个 put / delete 参与分组，默认为 [`MICRO_BATCH_MAX_ENTRY_SIZE`]
+    pub max_entry_size: usize,
+    /// 一组写入的总字节数上限，达到后组长立即提交，默认为 [`MICRO_BATCH_MAX_BYTES`]
+    pub max_batch_bytes: usize,
+    /// 组长等待前一组提交完成的最长时间，超过后两组同时提交，默认为 [`MICRO_BATCH_MAX_DELAY`]
+    pub max_delay: Duration,
+}
+
+impl Default for MicroBatchOptions {
+    fn default() -> Self {
+        Self {
+            max_entry_size: MICRO_BATCH_MAX_ENTRY_SIZE,
+            max_batch_bytes: MICRO_BATCH_MAX_BYTES,
+            max_delay: MICRO_BATCH_MAX_DELAY,
+        }
+    }
+}
+
+/// 汇集小写入并由组长提交，见 [`MicroBatchOptions`]
+pub(crate) struct MicroBatcher {
+    options: MicroBatchOptions,
+    state: Mutex<BatchState>,
+    // 正在收集的组已满，或有组提交完成
+    changed: Condvar,
+    // 加入其他写入的组、由组长提交的写入数
+    grouped_writes: AtomicU64,
+}
+
+#[derive(Default)]
+struct BatchState {
+    // 组长正在收集的组，同一时间最多一个，组长提交前取走，之后到达的写入组成新的组
+    collecting: Option<Batch>,
+    // 正在提交的组数
+    committing: usize,
+    next_id: u64,
+    // 已提交、还有组员没有取走结果的组
+    results: HashMap<u64, BatchResult>,
+}
+
+struct Batch {
+    id: u64,
+    entries: Vec<Entry>,
+    bytes: usize,
+    sync: bool,
+    // 组员数，不包括组长
+    followers: usize,
+}
+
+struct BatchResult {
+    result: Result<u64, BatchError>,
+    waiting: usize,
+}
+
+#[derive(Clone)]
+enum BatchError {
+    Closed,
+    ReadOnly,
+    Other(String),
+}
+
+impl MicroBatcher {
+    pub(crate) fn new(options: MicroBatchOptions) -> Self {
+        Self {
+            options,
+            state: Mutex::new(BatchState::default()),
+            changed: Condvar::new(),
+            grouped_writes: AtomicU64::new(0),
+        }
+    }
+
+    /// 该写入是否参与分组，跳过 WAL 的写入没有需要合并的 WAL 记录
+    pub(crate) fn accepts(&self, entry: &Entry, disable_wal: bool) -> bool {
+        !disable_wal && entry.key.len() + entry.value.len() <= self.options.max_entry_size
+    }
+
+    /// 打开以来加入其他写入的组、由组长提交的写入数
+    pub(crate) fn grouped_writes(&self) -> u64 {
+        self.grouped_writes.load(Ordering::Relaxed)
+    }
+
+    /// 把 `entry` 加入正在收集的组并等待组长提交，没有正在收集的组时成为组长，
+    /// 收集结束后用 `commit` 提交整组，`commit` 的第二个参数为组内是否有写入要求刷写 WAL。
+    /// 返回所在的组的提交序号
+    pub(crate) fn write(
+        &self,
+        entry: Entry,
+        sync: bool,
+        commit: impl FnOnce(Vec<Entry>, bool) -> anyhow::Result<u64>,
+    ) -> anyhow::Result<u64> {
+        let size = entry.key.len() + entry.value.len();
+        let mut state = self.state.lock();
+        match state.collecting.as_mut() {
+            Some(batch) if batch.bytes + size <= self.options.max_batch_bytes => {
+                batch.entries.push(entry);
+                batch.bytes += size;
+                batch.sync |= sync;
+                batch.followers += 1;
+                let id = batch.id;
+                if batch.bytes >= self.options.max_batch_bytes {
+                    self.changed.notify_all();
+                }
+                self.grouped_writes.fetch_add(1, Ordering::Relaxed);
+                return self.wait_for(state, id);
+            }
+            // 正在收集的组放不下时单独提交，不等待它
+            Some(_) => {
+                drop(state);
+                return commit(vec![entry], sync);
+            }
+            None => {}
+        }
+
+        state.next_id += 1;
+        let id = state.next_id;
+        state.collecting = Some(Batch {
+            id,
+            entries: vec![entry],
+            bytes: size,
+            sync,
+            followers: 0,
+        });
+        let deadline = Instant::now() + self.options.max_delay;
+        while state.committing > 0
+            && state.collecting.as_ref().unwrap().bytes < self.options.max_batch_bytes
+        {
+            if self.changed.wait_until(&mut state, deadline).timed_out() {
+                break;
+            }
+        }
+        let batch = state.collecting.take().unwrap();
+        state.committing += 1;
+        drop(state);
+
+        let result = commit(batch.entries, batch.sync);
+        let mut state = self.state.lock();
+        state.committing -= 1;
+        if batch.followers > 0 {
+            let shared = match &result {
+                Ok(seq) => Ok(*seq),
+                Err(err) if err.is::<DbClosedError>() => Err(BatchError::Closed),
+                Err(err) if err.is::<ReadOnlyError>() => Err(BatchError::ReadOnly),
+                Err(err) => Err(BatchError::Other(format!("{:#}", err))),
+            };
+            state.results.insert(
+                id,
+                BatchResult {
+                    result: shared,
+                    waiting: batch.followers,
+                },
+            );
+        }
+        drop(state);
+        self.changed.notify_all();
+        result
+    }
+
+    fn wait_for(
+        &self,
+        mut state: parking_lot::MutexGuard<'_, BatchState>,
+        id: u64,
+    ) -> anyhow::Result<u64> {
+        loop {
+            if let Some(batch) = state.results.get_mut(&id) {
+                batch.waiting -= 1;
+                let result = batch.result.clone();
+                if batch.waiting == 0 {
+                    state.results.remove(&id);
+                }
+                return result.map_err(|err| match err {
+                    BatchError::Closed => DbClosedError.into(),
+                    BatchError::ReadOnly => ReadOnlyError.into(),
+                    BatchError::Other(err) => anyhow!("grouped write failed: {}", err),
+                });
+            }
+            self.changed.wait(&mut state);
+        }
+    }
+}
+
+impl std::fmt::Debug for MicroBatcher {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("MicroBatcher")
+            .field("options", &self.options)
+            .field("grouped_writes", &self.grouped_writes())
+            .finish()
+    }
+}
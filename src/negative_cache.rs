@@ -0,0 +1,101 @@
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use bytes::Bytes;
+use xxhash_rust::xxh3::xxh3_64;
+
+// 失效计数的分片数，写入只影响同一分片中 key 的缓存填充
+const EPOCH_STRIPES: usize = 64;
+
+/// 最近确认不存在的 key，见 [`crate::DbOptions::negative_cache_capacity`]
+///
+/// 读取在查找前记下 key 所在分片的失效计数，确认不存在并加入缓存后再检查一次，计数变化说明查找期间有写入，
+/// 撤回加入的 key；写入在写入 memtable 之后增加计数并移除 key。两者交错时，
+/// 要么读取看到了写入，要么加入的 key 被其中一方移除，缓存中不会留下已写入的 key
+pub(crate) struct NegativeCache {
+    keys: moka::sync::Cache<Bytes, ()>,
+    epochs: Vec<AtomicU64>,
+    hits: AtomicU64,
+}
+
+impl NegativeCache {
+    pub(crate) fn new(capacity: u64) -> Self {
+        Self {
+            keys: moka::sync::Cache::new(capacity),
+            epochs: (0..EPOCH_STRIPES).map(|_| AtomicU64::new(0)).collect(),
+            hits: AtomicU64::new(0),
+        }
+    }
+
+    fn epoch_of(&self, key: &[u8]) -> &AtomicU64 {
+        &self.epochs[xxh3_64(key) as usize % EPOCH_STRIPES]
+    }
+
+    /// 查找前调用，返回 key 所在分片的失效计数，用于 [`NegativeCache::insert`]
+    pub(crate) fn epoch(&self, key: &[u8]) -> u64 {
+        self.epoch_of(key).load(Ordering::SeqCst)
+    }
+
+    /// key 是否确认不存在
+    pub(crate) fn contains(&self, key: &Bytes) -> bool {
+        let hit = self.keys.contains_key(key);
+        if hit {
+            self.hits.fetch_add(1, Ordering::Relaxed);
+        }
+        hit
+    }
+
+    /// 查找确认 key 不存在后加入缓存，`epoch` 为查找前 [`NegativeCache::epoch`] 的返回值
+    pub(crate) fn insert(&self, key: &Bytes, epoch: u64) {
+        self.keys.insert(key.clone(), ());
+        if self.epoch(key) != epoch {
+            self.keys.invalidate(key);
+        }
+    }
+
+    /// 写入 memtable 之后调用，之前确认不存在的 key 不再有效
+    pub(crate) fn invalidate(&self, key: &Bytes) {
+        self.epoch_of(key).fetch_add(1, Ordering::SeqCst);
+        self.keys.invalidate(key);
+    }
+
+    /// 打开以来命中的次数
+    pub(crate) fn hits(&self) -> u64 {
+        self.hits.load(Ordering::Relaxed)
+    }
+}
+
+impl std::fmt::Debug for NegativeCache {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("NegativeCache")
+            .field("entries", &self.keys.entry_count())
+            .field("hits", &self.hits())
+            .finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use bytes::Bytes;
+
+    use crate::negative_cache::NegativeCache;
+
+    #[test]
+    fn test_negative_cache() {
+        let cache = NegativeCache::new(16);
+        let key = Bytes::from("absent");
+        let epoch = cache.epoch(&key);
+        cache.insert(&key, epoch);
+        assert!(cache.contains(&key));
+        assert_eq!(cache.hits(), 1);
+
+        cache.invalidate(&key);
+        assert!(!cache.contains(&key));
+
+        // 查找期间有写入时不加入缓存
+        let epoch = cache.epoch(&key);
+        cache.invalidate(&key);
+        cache.insert(&key, epoch);
+        assert!(!cache.contains(&key));
+        assert_eq!(cache.hits(), 1);
+    }
+}
@@ -0,0 +1,157 @@
+//! 线程级的性能计数，用于临时排查单次读写的耗时花在哪里
+//!
+//! 在 [`PerfContextScope`] 的作用域内，当前线程上的 get、scan、put 等操作把读块、块缓存命中、解压、
+//! bloom filter 探测的次数和等锁的时间累加到线程局部的 [`PerfContext`]，操作返回后由调用方读取。
+//! 迭代器在调用 `next` 的线程上读块，计数也记在该线程上；[`crate::Db::multi_get`] 并行读取 VSST 的线程的计数
+//! 合并到调用线程。不在作用域内时每个计数点只检查一次线程局部变量，不计数也不计时
+//!
+//! ```
+//! use bytes::Bytes;
+//! use lasagnedb::{Db, PerfContextScope};
+//!
+//! let dir = tempfile::tempdir().unwrap();
+//! let db = Db::open_file(dir.path()).unwrap();
+//! db.put(Bytes::from("k"), Bytes::from("v")).unwrap();
+//! db.flush().unwrap();
+//!
+//! let scope = PerfContextScope::enter();
+//! db.get(&Bytes::from("k")).unwrap();
+//! let perf = scope.context();
+//! assert_eq!(perf.bloom_checks, 1);
+//! assert_eq!(perf.block_reads + perf.block_cache_hits, 1);
+//! ```
+
+use std::cell::RefCell;
+use std::time::{Duration, Instant};
+
+thread_local! {
+    static PERF_CONTEXT: RefCell<Option<PerfContext>> = const { RefCell::new(None) };
+}
+
+/// 当前线程在 [`PerfContextScope`] 内累计的计数
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct PerfContext {
+    /// 从文件读取的 SST / VSST 块数，合并读取的相邻块分别计数
+    pub block_reads: u64,
+    /// 从文件读取的块的字节数，压缩的块按压缩后的大小计算
+    pub block_read_bytes: u64,
+    /// 块缓存命中的次数，包括命中压缩的块
+    pub block_cache_hits: u64,
+    /// 解压得到的字节数
+    pub bytes_decompressed: u64,
+    /// 用 SST 的 bloom filter 判断 key 是否存在的次数
+    pub bloom_checks: u64,
+    /// bloom filter 判断 key 一定不存在、跳过 SST 的次数
+    pub bloom_filtered: u64,
+    /// 等待数据库内部的锁的时间，包括读写获取当前版本和写入等待 key 锁
+    pub lock_wait: Duration,
+}
+
+impl PerfContext {
+    fn merge(&mut self, other: &PerfContext) {
+        self.block_reads += other.block_reads;
+        self.block_read_bytes += other.block_read_bytes;
+        self.block_cache_hits += other.block_cache_hits;
+        self.bytes_decompressed += other.bytes_decompressed;
+        self.bloom_checks += other.bloom_checks;
+        self.bloom_filtered += other.bloom_filtered;
+        self.lock_wait += other.lock_wait;
+    }
+}
+
+/// 在作用域内统计当前线程的 [`PerfContext`]，计数从 0 开始
+///
+/// 嵌套时内层单独计数，离开时累加到外层
+pub struct PerfContextScope {
+    prev: Option<PerfContext>,
+}
+
+impl PerfContextScope {
+    pub fn enter() -> Self {
+        Self {
+            prev: PERF_CONTEXT.with(|c| c.replace(Some(PerfContext::default()))),
+        }
+    }
+
+    /// 进入作用域或上次 [`PerfContextScope::reset`] 以来的计数
+    pub fn context(&self) -> PerfContext {
+        perf_context().unwrap_or_default()
+    }
+
+    /// 计数清零，用于在同一个作用域内分别统计多个操作
+    pub fn reset(&self) {
+        PERF_CONTEXT.with(|c| *c.borrow_mut() = Some(PerfContext::default()));
+    }
+}
+
+impl Drop for PerfContextScope {
+    fn drop(&mut self) {
+        PERF_CONTEXT.with(|c| {
+            let inner = c.replace(self.prev.take());
+            if let (Some(outer), Some(inner)) = (c.borrow_mut().as_mut(), inner) {
+                outer.merge(&inner);
+            }
+        });
+    }
+}
+
+/// 当前线程的计数，不在 [`PerfContextScope`] 内时为 `None`
+pub fn perf_context() -> Option<PerfContext> {
+    PERF_CONTEXT.with(|c| *c.borrow())
+}
+
+/// 在作用域内时修改当前线程的计数
+#[inline]
+pub(crate) fn record(f: impl FnOnce(&mut PerfContext)) {
+    PERF_CONTEXT.with(|c| {
+        if let Some(context) = c.borrow_mut().as_mut() {
+            f(context)
+        }
+    })
+}
+
+/// 把其他线程代为执行的计数累加到当前线程
+pub(crate) fn merge(other: Option<PerfContext>) {
+    if let Some(other) = other {
+        record(|context| context.merge(&other));
+    }
+}
+
+/// 获取锁，在作用域内时把等待的时间计入 [`PerfContext::lock_wait`]
+#[inline]
+pub(crate) fn timed_lock<T>(acquire: impl FnOnce() -> T) -> T {
+    if perf_context().is_none() {
+        return acquire();
+    }
+    let start = Instant::now();
+    let guard = acquire();
+    let elapsed = start.elapsed();
+    record(|context| context.lock_wait += elapsed);
+    guard
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::perf_context::{perf_context, record, PerfContextScope};
+
+    #[test]
+    fn test_perf_context_scope() {
+        // 不在作用域内时不计数
+        record(|c| c.block_reads += 1);
+        assert_eq!(perf_context(), None);
+
+        let outer = PerfContextScope::enter();
+        record(|c| c.block_reads += 1);
+        {
+            let inner = PerfContextScope::enter();
+            assert_eq!(inner.context().block_reads, 0);
+            record(|c| c.block_reads += 2);
+            assert_eq!(inner.context().block_reads, 2);
+        }
+        assert_eq!(outer.context().block_reads, 3);
+        outer.reset();
+        assert_eq!(outer.context().block_reads, 0);
+        drop(outer);
+        assert_eq!(perf_context(), None);
+    }
+}
@@ -0,0 +1,5 @@
+//! 常用类型，`use lasagnedb::prelude::*;` 后即可打开数据库、读写和遍历
+//!
+//! 调优用的常量不再从 crate 根导出，通过 [`DbOptions`] 设置，需要旧的导出时开启 `legacy-exports` feature
+
+pub use crate::{Db, DbOptions, Error, Snapshot, StorageIterator, StorageIteratorExt, WriteBatch};
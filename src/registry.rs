@@ -0,0 +1,92 @@
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Weak};
+use std::{fs, path};
+
+use parking_lot::Mutex;
+use thiserror::Error;
+
+use crate::Db;
+
+/// 同一进程中重复打开同一个数据目录时返回的错误
+///
+/// 同时打开会在同一组文件上运行两套后台任务，互相删除对方仍在使用的文件
+#[derive(Debug, Clone, Error, PartialEq, Eq)]
+#[error("{path:?} is already open in this process")]
+pub struct AlreadyOpenError {
+    /// 规范化后的数据目录
+    pub path: PathBuf,
+}
+
+/// 已打开的数据目录，`Shared` 保存 [`Db::open_shared`] 返回的句柄，其他打开方式为 `None`
+static OPEN_DBS: Mutex<BTreeMap<PathBuf, Option<Weak<Db>>>> = Mutex::new(BTreeMap::new());
+
+/// 串行化 [`Db::open_shared`]，避免两个调用同时打开，后一个得到 [`AlreadyOpenError`]
+static SHARED_OPEN: Mutex<()> = Mutex::new(());
+
+/// 以规范化的绝对路径作为数据目录的标识，目录不存在时退化为绝对路径
+fn canonical_path(path: &Path) -> PathBuf {
+    fs::canonicalize(path)
+        .or_else(|_| path::absolute(path))
+        .unwrap_or_else(|_| path.to_path_buf())
+}
+
+/// 数据目录在进程内的登记，随 [`Db`] 一起释放
+#[derive(Debug)]
+pub(crate) struct Registration {
+    path: Option<PathBuf>,
+}
+
+impl Registration {
+    /// 登记 `path`，已被打开时返回 [`AlreadyOpenError`]
+    pub(crate) fn acquire(path: impl AsRef<Path>) -> Result<Self, AlreadyOpenError> {
+        let path = canonical_path(path.as_ref());
+        let mut open_dbs = OPEN_DBS.lock();
+        if open_dbs.contains_key(&path) {
+            return Err(AlreadyOpenError { path });
+        }
+        open_dbs.insert(path.clone(), None);
+        Ok(Self { path: Some(path) })
+    }
+
+    pub(crate) fn path(&self) -> Option<&Path> {
+        self.path.as_deref()
+    }
+
+    /// 立即取消登记，之后 drop 不再有作用
+    pub(crate) fn release(&mut self) {
+        if let Some(path) = self.path.take() {
+            OPEN_DBS.lock().remove(&path);
+        }
+    }
+}
+
+impl Drop for Registration {
+    fn drop(&mut self) {
+        self.release();
+    }
+}
+
+/// 返回 `path` 上已打开的共享句柄，没有登记时调用 `open` 打开并登记为共享
+pub(crate) fn open_shared(
+    path: impl AsRef<Path>,
+    open: impl FnOnce() -> anyhow::Result<Db>,
+) -> anyhow::Result<Arc<Db>> {
+    let _shared_open = SHARED_OPEN.lock();
+    let path = canonical_path(path.as_ref());
+    if let Some(entry) = OPEN_DBS.lock().get(&path) {
+        // 句柄正在释放时 upgrade 失败，与非共享打开的情况一样返回错误
+        return entry
+            .as_ref()
+            .and_then(Weak::upgrade)
+            .ok_or(AlreadyOpenError { path }.into());
+    }
+    let db = Arc::new(open()?);
+    // 打开时可能创建了目录，以登记时规范化的路径为准
+    if let Some(path) = db.registration.path() {
+        if let Some(entry) = OPEN_DBS.lock().get_mut(path) {
+            *entry = Some(Arc::downgrade(&db));
+        }
+    }
+    Ok(db)
+}
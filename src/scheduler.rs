@@ -0,0 +1,85 @@
+//! 在调用线程上逐个执行后台任务的调度器，开启 `test-util` feature 后可用
+//!
+//! 以 [`Db::open_with_options`] 打开的数据库不启动后台线程，写入触发的刷写请求和刷写、合并完成后
+//! 发起的合并请求都留在队列中。测试通过 [`SchedulerHandle`] 每次取出并执行一个请求，执行完才返回，
+//! 不需要 sleep 等待后台线程，同样的写入总是得到同样的文件布局。
+//!
+//! ```
+//! use bytes::Bytes;
+//! use lasagnedb::{Db, DbOptions, SchedulerStep};
+//!
+//! let dir = tempfile::tempdir().unwrap();
+//! let options = DbOptions {
+//!     memtable_size_limit: 1024,
+//!     ..Default::default()
+//! };
+//! let db = Db::open_with_options(dir.path(), options).unwrap();
+//! db.put(Bytes::from("k"), Bytes::from(vec![0; 2048])).unwrap();
+//! let scheduler = db.scheduler().unwrap();
+//! assert_eq!(scheduler.step().unwrap(), Some(SchedulerStep::Rotate));
+//! assert_eq!(scheduler.step().unwrap(), None);
+//! assert_eq!(db.stats().level_files[0], 1);
+//! ```
+
+use crate::db::Db;
+
+/// [`SchedulerHandle`] 执行的一步
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum SchedulerStep {
+    /// memtable 超过大小限制时冻结并刷写到 L0，未超过时什么也不做
+    Rotate,
+    /// 合并该层的一组 SST，最后一层表示按 `cache_capacity` 淘汰数据
+    Compaction(u32),
+}
+
+/// 在调用线程上执行刷写和合并的句柄，见 [`Db::scheduler`]
+///
+/// 每个方法都同步执行恰好一步，与后台线程收到同样的请求时的处理相同，包括更新队列统计和刷写任务状态
+pub struct SchedulerHandle<'a> {
+    db: &'a Db,
+}
+
+impl<'a> SchedulerHandle<'a> {
+    pub(crate) fn new(db: &'a Db) -> Self {
+        Self { db }
+    }
+
+    /// 执行一次 rotate，有排队的刷写请求时同时取出它
+    pub fn rotate(&self) -> anyhow::Result<()> {
+        self.db.check_open()?;
+        self.db.daemon.take_flush_request();
+        self.db.daemon.run_flush_request()
+    }
+
+    /// 执行一次 `level` 层的合并，不影响队列中的合并请求
+    pub fn compact(&self, level: u32) -> anyhow::Result<()> {
+        self.db.check_open()?;
+        self.db.daemon.run_compaction_request(level)
+    }
+
+    /// 取出并执行一个排队的请求，刷写先于合并，没有请求时返回 `None`
+    pub fn step(&self) -> anyhow::Result<Option<SchedulerStep>> {
+        self.db.check_open()?;
+        let daemon = &self.db.daemon;
+        if daemon.take_flush_request() {
+            daemon.run_flush_request()?;
+            return Ok(Some(SchedulerStep::Rotate));
+        }
+        match daemon.take_compaction_request() {
+            Some(level) => {
+                daemon.run_compaction_request(level)?;
+                Ok(Some(SchedulerStep::Compaction(level)))
+            }
+            None => Ok(None),
+        }
+    }
+
+    /// 反复执行 [`SchedulerHandle::step`] 直到队列为空，返回依次执行的步骤
+    pub fn run_until_idle(&self) -> anyhow::Result<Vec<SchedulerStep>> {
+        let mut steps = vec![];
+        while let Some(step) = self.step()? {
+            steps.push(step);
+        }
+        Ok(steps)
+    }
+}
@@ -0,0 +1,103 @@
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, Instant};
+
+use parking_lot::{Condvar, Mutex};
+
+/// 写入的提交序号
+///
+/// 每次成功的写入（单个修改或一个批次）分配一个递增的序号，写入 memtable 之后按序号顺序对读者可见：
+/// 序号为 `n` 的写入可见时，所有更小序号的写入也都已可见。上层服务可以把写入返回的序号作为
+/// read-your-writes 的一致性 token，读取前用 [`CommitSequence::wait_for`] 等待数据库追上该序号
+///
+/// 序号不随数据持久化，重启后从 MANIFEST 记录的值加上 WAL 中的记录数继续，只保证不小于重启前返回过的序号
+#[derive(Debug)]
+pub(crate) struct CommitSequence {
+    allocated: AtomicU64,
+    visible: Mutex<u64>,
+    published: Condvar,
+}
+
+impl CommitSequence {
+    /// 从 `last` 之后继续分配序号，`last` 视为已可见
+    pub(crate) fn new(last: u64) -> Self {
+        Self {
+            allocated: AtomicU64::new(last),
+            visible: Mutex::new(last),
+            published: Condvar::new(),
+        }
+    }
+
+    /// 分配下一个序号，分配后必须调用 [`CommitSequence::publish`]，否则之后的写入会一直等待
+    pub(crate) fn allocate(&self) -> u64 {
+        self.allocated.fetch_add(1, Ordering::AcqRel) + 1
+    }
+
+    /// 等待比 `seq` 小的序号都已可见后将 `seq` 设为可见
+    pub(crate) fn publish(&self, seq: u64) {
+        let mut visible = self.visible.lock();
+        while *visible + 1 < seq {
+            self.published.wait(&mut visible);
+        }
+        *visible = seq;
+        self.published.notify_all();
+    }
+
+    /// 已可见的最大序号
+    pub(crate) fn last_visible(&self) -> u64 {
+        *self.visible.lock()
+    }
+
+    /// 已分配的最大序号，没有写入进行中时与 [`CommitSequence::last_visible`] 相同
+    pub(crate) fn last_allocated(&self) -> u64 {
+        self.allocated.load(Ordering::Acquire)
+    }
+
+    /// 等待 `seq` 可见，超时返回 `false`
+    pub(crate) fn wait_for(&self, seq: u64, timeout: Duration) -> bool {
+        let deadline = Instant::now() + timeout;
+        let mut visible = self.visible.lock();
+        while *visible < seq {
+            if self
+                .published
+                .wait_until(&mut visible, deadline)
+                .timed_out()
+            {
+                return *visible >= seq;
+            }
+        }
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::sequence::CommitSequence;
+    use std::sync::Arc;
+    use std::thread;
+    use std::time::Duration;
+
+    #[test]
+    fn test_commit_sequence() {
+        let seq = Arc::new(CommitSequence::new(10));
+        assert_eq!(seq.last_visible(), 10);
+        assert!(seq.wait_for(10, Duration::ZERO));
+        assert!(!seq.wait_for(11, Duration::from_millis(10)));
+
+        let first = seq.allocate();
+        let second = seq.allocate();
+        assert_eq!((first, second), (11, 12));
+        assert_eq!(seq.last_allocated(), 12);
+
+        // 后分配的序号要等前面的序号可见后才能可见
+        let publisher = {
+            let seq = seq.clone();
+            thread::spawn(move || seq.publish(second))
+        };
+        assert!(!seq.wait_for(12, Duration::from_millis(50)));
+        assert_eq!(seq.last_visible(), 10);
+        seq.publish(first);
+        publisher.join().unwrap();
+        assert!(seq.wait_for(12, Duration::from_secs(1)));
+        assert_eq!(seq.last_visible(), 12);
+    }
+}
@@ -0,0 +1,610 @@
+#[cfg(feature = "iterator-backtrace")]
+use std::backtrace::Backtrace;
+use std::cmp::Reverse;
+use std::collections::{BTreeMap, HashMap};
+use std::fmt::{Debug, Formatter};
+use std::ops::Bound;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use bytes::Bytes;
+use parking_lot::{Condvar, Mutex};
+
+use crate::cooperative::LoopCheckpoint;
+use crate::db::DbInner;
+use crate::db_iterator::{DbIterator, DbIteratorInner, FusedIterator, ScanChunk};
+use crate::iterator::lazy_iterator::LazyIterator;
+use crate::iterator::merge_iterator::MergeIterator;
+use crate::iterator::two_merge_iterator::TwoMergeIterator;
+use crate::iterator::{checked, Checked, StorageIterator};
+use crate::memory_limit;
+use crate::sstable::builder::BlockReadOptions;
+use crate::sstable::iterator::{SsTableIterator, VSsTableIterator, VSstDerefCounters};
+use crate::stats::ActiveIterator;
+use crate::system::is_system_key;
+use crate::SST_LEVEL_LIMIT;
+use tracing::warn;
+
+/// 记录当前存活的 [`Snapshot`] 数量，`Db::close` 据此等待所有快照（及基于快照的迭代器）释放
+///
+/// 同时记录存活的 [`DbIterator`]，用于发现长时间未释放、持有文件和快照的迭代器
+#[derive(Debug, Default)]
+pub(crate) struct SnapshotTracker {
+    active: Mutex<usize>,
+    released: Condvar,
+    next_iterator_id: AtomicU64,
+    iterators: Mutex<HashMap<u64, TrackedIterator>>,
+    iterator_age_warning: Option<Duration>,
+}
+
+#[derive(Debug)]
+struct TrackedIterator {
+    created_at: Instant,
+    // 已经因存活超过 `iterator_age_warning` 输出过警告
+    warned: bool,
+    #[cfg(feature = "iterator-backtrace")]
+    backtrace: Arc<Backtrace>,
+}
+
+impl SnapshotTracker {
+    pub(crate) fn new(iterator_age_warning: Option<Duration>) -> Self {
+        Self {
+            iterator_age_warning,
+            ..Default::default()
+        }
+    }
+
+    pub(crate) fn active(&self) -> usize {
+        *self.active.lock()
+    }
+
+    /// 存活的迭代器，最早创建的在前
+    pub(crate) fn iterators(&self) -> Vec<ActiveIterator> {
+        let now = Instant::now();
+        let mut iterators = self
+            .iterators
+            .lock()
+            .values()
+            .map(|tracked| ActiveIterator {
+                age: now.saturating_duration_since(tracked.created_at),
+                #[cfg(feature = "iterator-backtrace")]
+                backtrace: Some(tracked.backtrace.to_string()),
+                #[cfg(not(feature = "iterator-backtrace"))]
+                backtrace: None,
+            })
+            .collect::<Vec<_>>();
+        iterators.sort_by_key(|iterator| Reverse(iterator.age));
+        iterators
+    }
+
+    /// 存活时间超过 `iterator_age_warning` 的迭代器数量，每个迭代器第一次被发现时输出一次警告
+    pub(crate) fn check_iterator_age(&self) -> usize {
+        let Some(limit) = self.iterator_age_warning else {
+            return 0;
+        };
+        let mut overdue = 0;
+        for (id, tracked) in self.iterators.lock().iter_mut() {
+            let age = tracked.created_at.elapsed();
+            if age <= limit {
+                continue;
+            }
+            overdue += 1;
+            if !tracked.warned {
+                tracked.warned = true;
+                warn!(
+                    "iterator {} has been open for {:?}, longer than {:?}{}",
+                    id,
+                    age,
+                    limit,
+                    tracked.backtrace_suffix()
+                );
+            }
+        }
+        overdue
+    }
+
+    fn track_iterator(self: &Arc<Self>) -> IteratorGuard {
+        let id = self.next_iterator_id.fetch_add(1, Ordering::Relaxed);
+        self.iterators.lock().insert(
+            id,
+            TrackedIterator {
+                created_at: Instant::now(),
+                warned: false,
+                #[cfg(feature = "iterator-backtrace")]
+                backtrace: Arc::new(Backtrace::force_capture()),
+            },
+        );
+        IteratorGuard {
+            tracker: self.clone(),
+            id,
+        }
+    }
+
+    /// 阻塞直到没有存活的快照
+    pub(crate) fn wait_all_released(&self) {
+        let mut active = self.active.lock();
+        while *active > 0 {
+            self.released.wait(&mut active);
+        }
+    }
+}
+
+struct SnapshotGuard {
+    tracker: Arc<SnapshotTracker>,
+}
+
+impl SnapshotGuard {
+    fn new(tracker: Arc<SnapshotTracker>) -> Self {
+        *tracker.active.lock() += 1;
+        Self { tracker }
+    }
+}
+
+impl TrackedIterator {
+    #[cfg(feature = "iterator-backtrace")]
+    fn backtrace_suffix(&self) -> String {
+        format!(", created at:\n{}", self.backtrace)
+    }
+
+    #[cfg(not(feature = "iterator-backtrace"))]
+    fn backtrace_suffix(&self) -> String {
+        String::new()
+    }
+}
+
+/// 迭代器 drop 时从 [`SnapshotTracker`] 中移除
+pub(crate) struct IteratorGuard {
+    tracker: Arc<SnapshotTracker>,
+    id: u64,
+}
+
+impl Drop for IteratorGuard {
+    fn drop(&mut self) {
+        let Some(tracked) = self.tracker.iterators.lock().remove(&self.id) else {
+            return;
+        };
+        if let Some(limit) = self.tracker.iterator_age_warning {
+            let age = tracked.created_at.elapsed();
+            if age > limit && !tracked.warned {
+                warn!(
+                    "iterator {} was open for {:?}, longer than {:?}{}",
+                    self.id,
+                    age,
+                    limit,
+                    tracked.backtrace_suffix()
+                );
+            }
+        }
+    }
+}
+
+impl Drop for SnapshotGuard {
+    fn drop(&mut self) {
+        let mut active = self.tracker.active.lock();
+        *active -= 1;
+        if *active == 0 {
+            self.tracker.released.notify_all();
+        }
+    }
+}
+
+/// 数据库某一时刻的只读视图
+///
+/// 快照持有创建时的 memtable、SST 等资源的引用，期间发生的 rotate、compaction 不会影响快照内容，
+/// 也不会释放快照引用的文件。由快照创建的迭代器拥有该快照的一份克隆，因此迭代器存活期间
+/// 快照也一直有效。所有克隆都被 drop 后快照才算释放，`Db::close` 会等待所有快照释放
+///
+/// 快照创建后仍有写入进入它持有的 memtable，范围查询和点查（如 [`crate::Db::get_with_snapshot`]）
+/// 只返回提交序号不超过 [`Snapshot::commit_seq`] 的版本，看不到这些写入
+#[derive(Clone)]
+pub struct Snapshot {
+    inner: Arc<DbInner>,
+    // 创建时已可见的最大提交序号
+    commit_seq: u64,
+    _guard: Arc<SnapshotGuard>,
+}
+
+impl Snapshot {
+    pub(crate) fn new(inner: Arc<DbInner>, tracker: Arc<SnapshotTracker>) -> Self {
+        let commit_seq = inner.commit_seq.last_visible();
+        Self {
+            inner,
+            commit_seq,
+            _guard: Arc::new(SnapshotGuard::new(tracker)),
+        }
+    }
+
+    /// 快照创建时已可见的最大提交序号，见 [`crate::Db::last_commit_seq`]
+    pub fn commit_seq(&self) -> u64 {
+        self.commit_seq
+    }
+
+    /// 与本快照共用 `Db::close` 等待的计数，在 `inner` 上创建的新快照
+    pub(crate) fn renew(&self, inner: Arc<DbInner>) -> Snapshot {
+        Snapshot::new(inner, self._guard.tracker.clone())
+    }
+
+    /// 创建快照之后数据库是否有新的写入或刷写、合并等变化，`latest` 为数据库当前的状态
+    pub(crate) fn is_stale(&self, latest: &Arc<DbInner>) -> bool {
+        !Arc::ptr_eq(&self.inner, latest) || latest.commit_seq.last_visible() != self.commit_seq
+    }
+
+    pub(crate) fn inner(&self) -> &DbInner {
+        &self.inner
+    }
+
+    /// 登记一个基于本快照的迭代器，返回的 guard 随迭代器一起 drop
+    pub(crate) fn track_iterator(&self) -> IteratorGuard {
+        self._guard.tracker.track_iterator()
+    }
+
+    /// 在快照上做范围查询，返回的迭代器持有快照，生命周期与 `Db` 和本快照无关
+    pub fn scan(
+        &self,
+        lower: Bound<Bytes>,
+        upper: Bound<Bytes>,
+    ) -> anyhow::Result<FusedIterator<DbIterator>> {
+        self.scan_with_options(lower, upper, BlockReadOptions::default(), None)
+    }
+
+    /// 范围查询，返回的 key 和 value 累计达到 `max_bytes` 字节后停止，至少返回一个 KV，
+    /// 单个 KV 超过 `max_bytes` 时结果会超出限制，见 [`ScanChunk`]
+    ///
+    /// 结果在累积时登记到读内存预算，超出 [`crate::set_read_memory_limit`] 时返回 [`crate::MemoryLimitError`]
+    pub fn scan_bytes(
+        &self,
+        lower: Bound<Bytes>,
+        upper: Bound<Bytes>,
+        max_bytes: usize,
+    ) -> anyhow::Result<ScanChunk> {
+        let mut iter = self.scan(lower, upper)?;
+        let mut chunk = ScanChunk::default();
+        let mut size = 0;
+        let mut reservation = memory_limit::empty_reservation();
+        while iter.is_valid() {
+            if size >= max_bytes && !chunk.items.is_empty() {
+                chunk.next_key = Some(Bytes::copy_from_slice(iter.key()));
+                break;
+            }
+            let item_size = iter.key().len() + iter.value().len();
+            reservation.grow(item_size)?;
+            size += item_size;
+            chunk.items.push((
+                Bytes::copy_from_slice(iter.key()),
+                Bytes::copy_from_slice(iter.value()),
+            ));
+            iter.next()?;
+        }
+        Ok(chunk)
+    }
+
+    /// 以指定的块读取选项做范围查询，超过 `deadline` 后迭代器返回 [`crate::ScanTimeoutError`]，见 [`Snapshot::scan`]
+    pub(crate) fn scan_with_options(
+        &self,
+        lower: Bound<Bytes>,
+        upper: Bound<Bytes>,
+        options: BlockReadOptions,
+        deadline: Option<Instant>,
+    ) -> anyhow::Result<FusedIterator<DbIterator>> {
+        self.scan_range(lower, upper, options, deadline, false)
+    }
+
+    /// 范围查询，返回的 key 包括系统 key，见 [`crate::system`]
+    pub(crate) fn scan_system(
+        &self,
+        lower: Bound<Bytes>,
+        upper: Bound<Bytes>,
+    ) -> anyhow::Result<FusedIterator<DbIterator>> {
+        self.scan_range(lower, upper, BlockReadOptions::default(), None, true)
+    }
+
+    fn scan_range(
+        &self,
+        lower: Bound<Bytes>,
+        upper: Bound<Bytes>,
+        options: BlockReadOptions,
+        deadline: Option<Instant>,
+        include_system: bool,
+    ) -> anyhow::Result<FusedIterator<DbIterator>> {
+        let deref = Arc::new(VSstDerefCounters::default());
+        let iter = self.range_iter(lower, upper.clone(), options, deref.clone())?;
+        Ok(FusedIterator::new(DbIterator::new(
+            iter,
+            upper,
+            self.clone(),
+            deadline,
+            deref,
+            include_system,
+        )?))
+    }
+
+    /// 合并快照中所有 memtable 和 SST 的迭代器并定位到 `lower`，由 [`DbIterator`] 按 `upper` 截断、跳过删除标记
+    pub(crate) fn range_iter(
+        &self,
+        lower: Bound<Bytes>,
+        upper: Bound<Bytes>,
+        options: BlockReadOptions,
+        deref: Arc<VSstDerefCounters>,
+    ) -> anyhow::Result<DbIteratorInner> {
+        let snapshot = &self.inner;
+
+        // SST 的迭代器在合并需要它的数据时才创建和定位，范围查询只读少量 key 时不必定位每个 SST
+        let mut sst_iters = Vec::new();
+        for level in 0..SST_LEVEL_LIMIT {
+            for table in snapshot.tables_newest_first(level) {
+                if table.num_of_blocks() == 0 {
+                    continue;
+                }
+                let (smallest_key, largest_key) = table.key_range();
+                let first_key = match &lower {
+                    Bound::Included(key) | Bound::Excluded(key) => {
+                        if largest_key < key {
+                            continue;
+                        }
+                        smallest_key.max(key.clone())
+                    }
+                    Bound::Unbounded => smallest_key,
+                };
+                let table = table.clone();
+                let vssts = snapshot.vssts.clone();
+                let missing = snapshot.missing_vssts.clone();
+                let lower = lower.clone();
+                let deref = deref.clone();
+                sst_iters.push(Box::new(LazyIterator::new(first_key, move || {
+                    let iter = match lower {
+                        Bound::Included(key) => VSsTableIterator::create_and_seek_to_key(
+                            table,
+                            &key[..],
+                            vssts,
+                            missing,
+                            options,
+                            deref,
+                        )?,
+                        Bound::Excluded(key) => {
+                            let mut iter = VSsTableIterator::create_and_seek_to_key(
+                                table,
+                                &key[..],
+                                vssts,
+                                missing,
+                                options,
+                                deref,
+                            )?;
+                            if iter.is_valid() && iter.key() == key {
+                                iter.next()?;
+                            }
+                            iter
+                        }
+                        Bound::Unbounded => VSsTableIterator::create_and_seek_to_first(
+                            table, vssts, missing, options, deref,
+                        )?,
+                    };
+                    Ok(checked(iter, "sst", true))
+                })));
+            }
+        }
+        let sst_iter = MergeIterator::create_lazy(sst_iters)?;
+
+        self.merge_with_memtables(lower, upper, sst_iter)
+    }
+
+    /// 将 `lower` 到 `upper` 的范围按 SST 中的数据量切分为最多 `n` 个相邻的子范围，切分点都是某个 data block 的首个 key
+    ///
+    /// 子范围按 key 升序排列，合起来恰好覆盖原范围，除最后一个外上界都不包含。
+    /// 估算只使用 SST 的 block 元数据，不读取数据，memtable 中的数据不参与估算；数据不够切分时返回的子范围少于 `n` 个。
+    /// 元数据不常驻内存的 SST 需要读取其元数据，读取失败时返回错误
+    pub fn partition_range(
+        &self,
+        lower: Bound<Bytes>,
+        upper: Bound<Bytes>,
+        n: usize,
+    ) -> anyhow::Result<Vec<(Bound<Bytes>, Bound<Bytes>)>> {
+        assert!(n > 0, "n must be greater than 0");
+        let snapshot = &self.inner;
+        // 以 block 的首个 key 为切分点候选，block 的数据量都计在首个 key 上
+        let mut weights: BTreeMap<Bytes, u64> = BTreeMap::new();
+        for table in snapshot.levels.iter().flatten() {
+            for (first_key, size) in table.block_boundaries()? {
+                let after_lower = match &lower {
+                    Bound::Included(key) | Bound::Excluded(key) => first_key > key,
+                    Bound::Unbounded => true,
+                };
+                let before_upper = match &upper {
+                    Bound::Included(key) => first_key <= key,
+                    Bound::Excluded(key) => first_key < key,
+                    Bound::Unbounded => true,
+                };
+                if after_lower && before_upper {
+                    *weights.entry(first_key).or_insert(0) += size;
+                }
+            }
+        }
+
+        let total: u64 = weights.values().sum();
+        let mut split_keys = vec![];
+        let mut before = 0;
+        for (key, weight) in weights {
+            let target = total * (split_keys.len() as u64 + 1) / n as u64;
+            if split_keys.len() + 1 < n && before > 0 && before >= target {
+                split_keys.push(key);
+            }
+            before += weight;
+        }
+
+        let mut partitions = Vec::with_capacity(split_keys.len() + 1);
+        let mut partition_lower = lower;
+        for key in split_keys {
+            partitions.push((partition_lower, Bound::Excluded(key.clone())));
+            partition_lower = Bound::Included(key);
+        }
+        partitions.push((partition_lower, upper));
+        Ok(partitions)
+    }
+
+    /// 按 [`Snapshot::partition_range`] 切分范围，返回每个子范围上的迭代器
+    ///
+    /// 所有迭代器都读取本快照，互相独立，可以交给不同的线程并行处理
+    pub fn scan_partitions(
+        &self,
+        lower: Bound<Bytes>,
+        upper: Bound<Bytes>,
+        n: usize,
+    ) -> anyhow::Result<Vec<FusedIterator<DbIterator>>> {
+        self.partition_range(lower, upper, n)?
+            .into_iter()
+            .map(|(lower, upper)| self.scan(lower, upper))
+            .collect()
+    }
+
+    /// 只在快照的 memtable（当前的和冻结的）上做范围查询，不读取任何 SST
+    ///
+    /// 只能看到尚未刷写到 SST 的写入，已刷写的 key 不会出现在结果中；memtable 中的删除标记同样会隐藏对应的 key。
+    /// 用于排查刷写问题，或者低开销地查看最近的写入
+    pub fn scan_memtables(
+        &self,
+        lower: Bound<Bytes>,
+        upper: Bound<Bytes>,
+    ) -> anyhow::Result<FusedIterator<DbIterator>> {
+        let iter =
+            self.merge_with_memtables(lower, upper.clone(), MergeIterator::create(Vec::new()))?;
+        Ok(FusedIterator::new(DbIterator::new(
+            iter,
+            upper,
+            self.clone(),
+            None,
+            Arc::default(),
+            false,
+        )?))
+    }
+
+    /// 将快照的 memtable 与 `sst_iter` 合并，新的 memtable 优先
+    fn merge_with_memtables(
+        &self,
+        lower: Bound<Bytes>,
+        upper: Bound<Bytes>,
+        sst_iter: MergeIterator<LazyIterator<Checked<VSsTableIterator>>>,
+    ) -> anyhow::Result<DbIteratorInner> {
+        let snapshot = &self.inner;
+
+        let mut mem_iters = Vec::new();
+        mem_iters.reserve(snapshot.frozen_memtable.len() + 1);
+        mem_iters.push(Box::new(checked(
+            snapshot
+                .memtable
+                .scan_at(lower.clone(), upper.clone(), self.commit_seq),
+            "memtable",
+            true,
+        )));
+        for _memtable in snapshot.frozen_memtable.iter().rev() {
+            let memtable = _memtable.clone();
+            mem_iters.push(Box::new(checked(
+                memtable.scan_at(lower.clone(), upper.clone(), self.commit_seq),
+                "frozen memtable",
+                true,
+            )));
+        }
+        let mem_iter = MergeIterator::create(mem_iters);
+
+        // 同一个 memtable 中可能有同一 key 的多个版本，合并后也允许相邻的 key 相同
+        let mut iter = checked(TwoMergeIterator::create(mem_iter, sst_iter)?, "db", true);
+        // memtable 的 scan 会包含 Excluded 的下界
+        if let Bound::Excluded(key) = &lower {
+            while iter.is_valid() && iter.key() == key {
+                iter.next()?;
+            }
+        }
+        Ok(iter)
+    }
+
+    /// 统计范围内未被删除的 key 数量，与 [`Snapshot::scan`] 返回的 key 数量一致，不包括数据库内部使用的 key
+    ///
+    /// 只遍历 key：KV 分离的 value 不会从 VSST 读取，value 也不会被拷贝；key 范围与查询范围不重叠的 SST 直接跳过
+    pub fn count(&self, lower: Bound<Bytes>, upper: Bound<Bytes>) -> anyhow::Result<usize> {
+        let snapshot = &self.inner;
+
+        let mut mem_iters = Vec::with_capacity(snapshot.frozen_memtable.len() + 1);
+        mem_iters.push(Box::new(snapshot.memtable.scan_at(
+            lower.clone(),
+            upper.clone(),
+            self.commit_seq,
+        )));
+        for memtable in snapshot.frozen_memtable.iter().rev() {
+            mem_iters.push(Box::new(memtable.scan_at(
+                lower.clone(),
+                upper.clone(),
+                self.commit_seq,
+            )));
+        }
+        let mem_iter = MergeIterator::create(mem_iters);
+
+        let mut sst_iters = Vec::new();
+        for level in 0..SST_LEVEL_LIMIT {
+            for table in snapshot.tables_newest_first(level) {
+                if table.num_of_blocks() == 0 {
+                    continue;
+                }
+                let (first_key, last_key) = table.key_range();
+                let below_lower = match &lower {
+                    Bound::Included(key) => last_key < key,
+                    Bound::Excluded(key) => last_key <= key,
+                    Bound::Unbounded => false,
+                };
+                let above_upper = match &upper {
+                    Bound::Included(key) => first_key > key,
+                    Bound::Excluded(key) => first_key >= key,
+                    Bound::Unbounded => false,
+                };
+                if below_lower || above_upper {
+                    continue;
+                }
+                let iter = match &lower {
+                    Bound::Included(key) | Bound::Excluded(key) => {
+                        SsTableIterator::create_and_seek_to_key(table, &key[..])?
+                    }
+                    Bound::Unbounded => SsTableIterator::create_and_seek_to_first(table)?,
+                };
+                sst_iters.push(Box::new(iter));
+            }
+        }
+        let sst_iter = MergeIterator::create(sst_iters);
+
+        let mut iter = TwoMergeIterator::create(mem_iter, sst_iter)?;
+        if let Bound::Excluded(key) = &lower {
+            while iter.is_valid() && iter.key() == key {
+                iter.next()?;
+            }
+        }
+        let mut count = 0;
+        let mut prev_key: Option<Bytes> = None;
+        let mut checkpoint = LoopCheckpoint::new("count");
+        while iter.is_valid() {
+            checkpoint.tick();
+            let in_range = match &upper {
+                Bound::Included(key) => iter.key() <= key,
+                Bound::Excluded(key) => iter.key() < key,
+                Bound::Unbounded => true,
+            };
+            if !in_range {
+                break;
+            }
+            // 同一个 memtable 中可能有一个 key 的多个版本，只看最新的版本；删除标记的 value 为空
+            if prev_key.as_deref() != Some(iter.key()) {
+                if !iter.value().is_empty() && !is_system_key(iter.key()) {
+                    count += 1;
+                }
+                prev_key = Some(Bytes::copy_from_slice(iter.key()));
+            }
+            iter.next()?;
+        }
+        Ok(count)
+    }
+}
+
+impl Debug for Snapshot {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Snapshot")
+            .field("seq_num", &self.inner.seq_num)
+            .field("log_id", &self.inner.log_id)
+            .finish()
+    }
+}
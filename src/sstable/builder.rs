@@ -1,17 +1,199 @@
 use std::path::Path;
-use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, OnceLock};
+use std::time::{Duration, SystemTime};
 
 use anyhow::{anyhow, Result};
 use bloomfilter::Bloom;
 use bytes::{Buf, BufMut, Bytes};
+use rand::Rng;
 
-use tracing::instrument;
+use thiserror::Error;
+use tracing::{instrument, warn};
 
 use crate::block::builder::{Block, BlockBuilder};
-use crate::cache::BlockCache;
+use crate::block::iterator::BlockIterator;
+use crate::cache::{index_memory, BlockCache, BlockLoad, CachedBlock};
+use crate::checksum::ChecksumType;
 use crate::entry::Entry;
+use crate::perf_context;
+use crate::sstable::compression::{self, CompressionType};
 use crate::sstable::meta::MetaBlock;
+use crate::stats::KeyPrefixStats;
 use crate::storage::file::FileStorage;
+use crate::storage::header::{FileFormatError, FileHeader, FileType, FILE_HEADER_SIZE};
+use crate::value::OpType;
+use crate::{BLOCK_CHECKSUM, BLOCK_SIZE, BLOOM_FALSE_POSITIVE_RATE};
+
+/// SST 中的数据块无法解压或解码
+#[derive(Error, Debug, Clone, Eq, PartialEq)]
+#[error("sst {sst_id} block {block_idx} is corrupted: {reason}")]
+pub struct CorruptionError {
+    pub sst_id: u64,
+    pub block_idx: usize,
+    pub reason: String,
+}
+
+/// 加入 SST 的 key 不大于前一个 key，见 [`KeyOrderCheck`]
+#[derive(Error, Debug, Clone, Eq, PartialEq)]
+#[error(
+    "{} key {key:?} added as entry {index} after {previous:?}",
+    if .key == .previous { "duplicate" } else { "out-of-order" }
+)]
+pub struct KeyOrderError {
+    /// 出错的 entry 在加入顺序中的下标，从 0 开始
+    pub index: u32,
+    pub previous: Bytes,
+    pub key: Bytes,
+}
+
+/// 构建 SST 时是否检查加入的 key 严格递增
+///
+/// 调用方需要按 key 排序且去重后加入 entry，否则 SST 中的二分查找会静默地找不到数据。
+/// 检查时第一个不大于前一个 key 的 entry 及之后的 entry 不写入，构建时返回 [`KeyOrderError`]
+#[derive(Debug, Clone, Copy, Default, Eq, PartialEq)]
+pub enum KeyOrderCheck {
+    /// 不检查
+    Disabled,
+    /// 只在开启 debug assertions 的构建中检查
+    #[default]
+    Debug,
+    /// 总是检查，每个 entry 多一次 key 比较
+    Always,
+}
+
+impl KeyOrderCheck {
+    fn enabled(self) -> bool {
+        match self {
+            KeyOrderCheck::Disabled => false,
+            KeyOrderCheck::Debug => cfg!(debug_assertions),
+            KeyOrderCheck::Always => true,
+        }
+    }
+}
+
+/// 读到的块来自哪里
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub(crate) enum BlockSource {
+    /// 块缓存中已解码或压缩的块
+    Cache,
+    /// 从文件读出，包括等待其他线程正在进行的同一次读取
+    Disk,
+}
+
+impl BlockSource {
+    pub(crate) fn as_str(&self) -> &'static str {
+        match self {
+            BlockSource::Cache => "cache",
+            BlockSource::Disk => "disk",
+        }
+    }
+}
+
+/// 读取 data block 时的选项
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct BlockReadOptions {
+    /// 从磁盘读出的块放入块缓存
+    pub fill_cache: bool,
+    /// 校验从磁盘读出的块的校验和，不一致时返回 [`CorruptionError`]
+    pub verify_checksum: bool,
+    /// `verify_checksum` 关闭时抽样校验：放入块缓存的块总是校验，其余从磁盘读出的块随机校验 1/n
+    pub checksum_sampling: Option<u32>,
+}
+
+impl Default for BlockReadOptions {
+    fn default() -> Self {
+        Self {
+            fill_cache: true,
+            verify_checksum: false,
+            checksum_sampling: None,
+        }
+    }
+}
+
+impl BlockReadOptions {
+    /// 从磁盘读出的块是否需要校验，`cached` 表示读出的块会放入块缓存
+    fn verify_on_load(&self, cached: bool) -> bool {
+        if self.verify_checksum {
+            return true;
+        }
+        match self.checksum_sampling {
+            None => false,
+            Some(_) if cached => true,
+            Some(n) => n <= 1 || rand::thread_rng().gen_range(0..n) == 0,
+        }
+    }
+}
+
+/// 校验过校验和的块数，所有 SST 共享
+static VERIFIED_BLOCKS: AtomicU64 = AtomicU64::new(0);
+/// 校验和不一致的块数
+static CHECKSUM_FAILURES: AtomicU64 = AtomicU64::new(0);
+
+/// 校验过校验和的块数
+pub(crate) fn verified_blocks() -> u64 {
+    VERIFIED_BLOCKS.load(Ordering::Relaxed)
+}
+
+/// 校验和不一致的块数
+pub(crate) fn checksum_failures() -> u64 {
+    CHECKSUM_FAILURES.load(Ordering::Relaxed)
+}
+
+/// SST 的 bloom filter 的加载方式
+#[derive(Debug, Clone, Copy, Default, Eq, PartialEq)]
+pub enum FilterLoading {
+    /// 打开 SST 时加载
+    #[default]
+    Eager,
+    /// 第一次点查该 SST 时加载
+    Lazy,
+    /// 不加载，点查时总是探测 SST，用读取的 CPU / IO 换取更少的常驻内存
+    Disabled,
+}
+
+/// SST 的 meta
+///
+/// 块缓存开启了 meta 缓存时（见 [`BlockCache::with_index_capacity`]）meta 不常驻内存，
+/// 只保留块数和 key 范围，需要定位块时经由缓存加载
+#[derive(Debug)]
+struct TableIndex {
+    resident: Option<Arc<Vec<MetaBlock>>>,
+    num_of_blocks: usize,
+    // 首个块的首个 key 和最后一个块的最后一个 key，没有块时为 `None`
+    key_range: Option<(Bytes, Bytes)>,
+}
+
+impl TableIndex {
+    fn new(id: u64, metas: Vec<MetaBlock>, cache: Option<&Arc<BlockCache>>) -> Self {
+        let num_of_blocks = metas.len();
+        let key_range = metas
+            .first()
+            .zip(metas.last())
+            .map(|(first, last)| (first.first_key.clone(), last.last_key.clone()));
+        let metas = Arc::new(metas);
+        let resident = match cache {
+            Some(cache) if cache.caches_index() => {
+                cache.insert_index(id, metas, false);
+                None
+            }
+            _ => Some(metas),
+        };
+        Self {
+            resident,
+            num_of_blocks,
+            key_range,
+        }
+    }
+}
+
+fn decode_metas(mut buf: Bytes) -> Vec<MetaBlock> {
+    let mut metas = vec![];
+    while buf.has_remaining() {
+        metas.push(MetaBlock::decode_with_bytes(&mut buf));
+    }
+    metas
+}
 
 /// layout:
 /// ```text
@@ -30,6 +212,20 @@ use crate::storage::file::FileStorage;
 /// +------------------------+ <--- filter offset
 /// | bloom filter           |
 /// +------------------------+
+/// | zstd dictionary        |
+/// +------------------------+
+/// | footer checksum(4 bytes) |
+/// +------------------------+
+/// | max seq(8 bytes)       |
+/// +------------------------+
+/// | delete nums(4 bytes)   |
+/// +------------------------+
+/// | checksum(4 bytes)      |
+/// +------------------------+
+/// | compression(4 bytes)   |
+/// +------------------------+
+/// | dict len(4 bytes)      |
+/// +------------------------+
 /// | filter len(4 bytes)    |
 /// +------------------------+
 /// | filter offset(4 bytes) |
@@ -39,89 +235,451 @@ use crate::storage::file::FileStorage;
 /// | pair nums(4 bytes)     |
 /// +------------------------+
 /// ```
+///
+/// 启用压缩时 data block 为压缩后的数据，字典为空表示压缩时未使用字典
 #[derive(Debug)]
 pub struct SsTable {
-    id: u32,
+    id: u64,
     file: FileStorage,
-    metas: Vec<MetaBlock>,
+    // 文件尾记录的类型，旧文件为 `None`
+    file_type: Option<FileType>,
+    index: TableIndex,
     meta_offset: u32,
     cache: Option<Arc<BlockCache>>,
-    bloom: Option<Arc<Bloom<Bytes>>>,
+    // 未加载时为空，加载后为 `None` 表示 SST 中没有 filter
+    bloom: OnceLock<Option<Arc<Bloom<Bytes>>>>,
+    filter_offset: u32,
+    filter_len: u32,
+    filter_loading: FilterLoading,
+    // footer 中记录的 KV 数量
     pair_num: u32,
+    // 校验过的 KV 数量：footer 带校验和时就是 `pair_num`，否则第一次需要时数出，见 `verified_num_of_pairs`
+    verified_pair_num: OnceLock<u32>,
+    // footer 是否带校验和，格式版本 4 之前写入的文件没有
+    footer_checksum: bool,
+    // 删除标记的数量，旧格式的文件中没有记录
+    delete_num: Option<u32>,
+    // 数据的最大 seq num，旧格式的文件中没有记录
+    max_seq: Option<u64>,
+    compression: CompressionType,
+    dict: Bytes,
+    checksum_type: ChecksumType,
+    // 点查探测该 SST 的次数和其中没有找到 key 的次数，只保存在内存中
+    seeks: AtomicU64,
+    seek_misses: AtomicU64,
 }
 
 impl SsTable {
     #[instrument(skip(_block_cache))]
     pub fn open(
-        _id: u32,
+        _id: u64,
         _block_cache: Option<Arc<BlockCache>>,
         _file: FileStorage,
+    ) -> Result<Self> {
+        Self::open_with_filter_loading(_id, _block_cache, _file, FilterLoading::Eager)
+    }
+
+    /// 打开 SST，按 `filter_loading` 决定何时加载 bloom filter
+    #[instrument(skip(_block_cache))]
+    pub fn open_with_filter_loading(
+        _id: u64,
+        _block_cache: Option<Arc<BlockCache>>,
+        _file: FileStorage,
+        filter_loading: FilterLoading,
     ) -> Result<Self> {
         let file = _file;
-        let len = file.size()?;
-        let pair_num = (&file.read(len - 4, 4)?[..]).get_u32_le();
-        let meta_offset = (&file.read(len - 8, 4)?[..]).get_u32_le();
-        let filter_offset = (&file.read(len - 12, 4)?[..]).get_u32_le();
-        let filter_len = (&file.read(len - 16, 4)?[..]).get_u32_le();
-
-        let mut metas = vec![];
-        let mut buf = Bytes::from(file.read(
-            meta_offset as u64,
-            len - 16 - filter_len as u64 - meta_offset as u64,
-        )?);
-        while buf.has_remaining() {
-            metas.push(MetaBlock::decode_with_bytes(&mut buf));
-        }
-        let bloom = if filter_len == 0 {
+        let mut len = file.size()?;
+        // 文件尾的文件头，加入文件头之前写入的 SST 没有，以 footer 结尾
+        let header = if len >= FILE_HEADER_SIZE as u64 {
+            let trailer = file.read(len - FILE_HEADER_SIZE as u64, FILE_HEADER_SIZE as u64)?;
+            FileHeader::decode(file.path(), &trailer)?
+        } else {
             None
+        };
+        if header.is_some() {
+            len -= FILE_HEADER_SIZE as u64;
+        }
+        // 版本 2 起 footer 之前记录了删除标记的数量，版本 3 起再之前记录了最大 seq num，版本 4 起再之前是校验和，
+        // 没有文件头的是旧格式的 footer
+        let footer_len = match header {
+            Some(header) if header.version >= 4 => FOOTER_SIZE + 16,
+            Some(header) if header.version >= 3 => FOOTER_SIZE + 12,
+            Some(header) if header.version >= 2 => FOOTER_SIZE + 4,
+            Some(_) => FOOTER_SIZE,
+            None => LEGACY_FOOTER_SIZE,
+        };
+        if len < footer_len {
+            return Err(anyhow!(
+                "sst {} too short: {} bytes, footer needs {} bytes",
+                _id,
+                len,
+                footer_len
+            ));
+        }
+        // footer 一次读出
+        let footer_data = file.read(len - footer_len, footer_len)?;
+        let mut footer = &footer_data[..];
+        let footer_checksum = footer_len > FOOTER_SIZE + 12;
+        if footer_checksum && footer.get_u32_le() != ChecksumType::Crc32c.checksum(footer) {
+            return Err(anyhow!("sst {} footer checksum mismatch", _id));
+        }
+        let max_seq = (footer_len > FOOTER_SIZE + 4).then(|| footer.get_u64_le());
+        let delete_num = (footer_len > FOOTER_SIZE).then(|| footer.get_u32_le());
+        let len = len - footer_len;
+        // 旧格式的 block 固定使用 crc32，不压缩，没有字典
+        let (checksum_type, compression, dict_len) = match header {
+            Some(_) => (
+                ChecksumType::from(footer.get_u32_le())?,
+                CompressionType::from(footer.get_u32_le())?,
+                footer.get_u32_le(),
+            ),
+            None => (ChecksumType::Crc32, CompressionType::None, 0),
+        };
+        let filter_len = footer.get_u32_le();
+        let filter_offset = footer.get_u32_le();
+        let meta_offset = footer.get_u32_le();
+        let pair_num = footer.get_u32_le();
+
+        // meta、bloom filter 和字典是连续的，一次读出
+        let tail_len = (filter_offset as u64 + filter_len as u64 + dict_len as u64)
+            .checked_sub(meta_offset as u64)
+            .filter(|tail_len| filter_offset >= meta_offset && meta_offset as u64 + tail_len <= len)
+            .ok_or_else(|| anyhow!("sst {} has invalid footer", _id))?;
+        let tail = Bytes::from(file.read(meta_offset as u64, tail_len)?);
+        let filter_begin = (filter_offset - meta_offset) as usize;
+        let dict_begin = filter_begin + filter_len as usize;
+
+        let metas = match &_block_cache {
+            // 放入缓存的 meta 复制出来，不让 tail 中的 filter 和字典随之留在缓存中
+            Some(cache) if cache.caches_index() => {
+                decode_metas(Bytes::copy_from_slice(&tail[..filter_begin]))
+            }
+            _ => decode_metas(tail.slice(..filter_begin)),
+        };
+        let index = TableIndex::new(_id, metas, _block_cache.as_ref());
+        let bloom = OnceLock::new();
+        let dict = if filter_loading == FilterLoading::Eager {
+            bloom
+                .set(Self::decode_filter(&tail[filter_begin..dict_begin])?)
+                .unwrap();
+            tail.slice(dict_begin..)
         } else {
-            let _bloom: Bloom<Bytes> =
-                postcard::from_bytes(&file.read(filter_offset as u64, filter_len as u64)?[..])?;
-            Some(Arc::new(_bloom))
+            // 复制出字典，不让 tail 中的 filter 随字典常驻内存
+            Bytes::copy_from_slice(&tail[dict_begin..])
         };
 
         Ok(Self {
             id: _id,
             file,
-            metas,
+            file_type: header.map(|header| header.file_type),
+            index,
             meta_offset,
             cache: _block_cache,
             bloom,
+            filter_offset,
+            filter_len,
+            filter_loading,
             pair_num,
+            verified_pair_num: match footer_checksum {
+                true => OnceLock::from(pair_num),
+                false => OnceLock::new(),
+            },
+            footer_checksum,
+            delete_num,
+            max_seq,
+            compression,
+            dict,
+            checksum_type,
+            seeks: AtomicU64::new(0),
+            seek_misses: AtomicU64::new(0),
         })
     }
 
+    fn decode_filter(data: &[u8]) -> Result<Option<Arc<Bloom<Bytes>>>> {
+        if data.is_empty() {
+            return Ok(None);
+        }
+        let _bloom: Bloom<Bytes> = postcard::from_bytes(data)?;
+        Ok(Some(Arc::new(_bloom)))
+    }
+
+    /// 按加载方式返回 bloom filter，`Lazy` 时第一次调用从文件中读出，读取失败时当作没有 filter
+    fn filter(&self) -> Option<&Arc<Bloom<Bytes>>> {
+        if self.filter_loading == FilterLoading::Disabled {
+            return None;
+        }
+        if let Some(bloom) = self.bloom.get() {
+            return bloom.as_ref();
+        }
+        let bloom = self
+            .file
+            .read(self.filter_offset as u64, self.filter_len as u64)
+            .and_then(|data| Self::decode_filter(&data));
+        match bloom {
+            Ok(bloom) => self.bloom.get_or_init(|| bloom).as_ref(),
+            Err(e) => {
+                warn!("load filter of sst {} failed: {}", self.id, e);
+                None
+            }
+        }
+    }
+
+    /// 修改 bloom filter 的加载方式，`Disabled` 时释放已加载的 filter
+    pub fn with_filter_loading(mut self, filter_loading: FilterLoading) -> Self {
+        if filter_loading == FilterLoading::Disabled {
+            self.bloom = OnceLock::new();
+        }
+        self.filter_loading = filter_loading;
+        self
+    }
+
+    /// 已加载的 bloom filter 占用的内存（字节）
+    pub fn filter_memory(&self) -> u64 {
+        match self.bloom.get() {
+            Some(Some(bloom)) => bloom.number_of_bits() / 8,
+            _ => 0,
+        }
+    }
+
+    /// 常驻内存的 meta 占用的内存（字节），meta 经由缓存加载时为 0
+    pub fn index_memory(&self) -> u64 {
+        self.index
+            .resident
+            .as_ref()
+            .map_or(0, |metas| index_memory(metas))
+    }
+
+    /// 返回 meta，不常驻内存时先查缓存，未命中则从文件中读出并放入缓存
+    fn metas(&self) -> Result<Arc<Vec<MetaBlock>>> {
+        if let Some(metas) = &self.index.resident {
+            return Ok(metas.clone());
+        }
+        let cache = self
+            .cache
+            .as_ref()
+            .expect("index of sst without block cache is always resident");
+        if let Some(metas) = cache.get_index(self.id) {
+            return Ok(metas);
+        }
+        let data = self.file.read(
+            self.meta_offset as u64,
+            (self.filter_offset - self.meta_offset) as u64,
+        )?;
+        let metas = Arc::new(decode_metas(Bytes::from(data)));
+        cache.insert_index(self.id, metas.clone(), true);
+        Ok(metas)
+    }
+
     pub fn size(&self) -> u64 {
         self.file.size().map_or(0, |size| size)
     }
 
-    pub fn id(&self) -> u32 {
+    /// 文件写出以来经过的时间，SST 写出后不再修改，按文件的修改时间计算，取不到时为 `None`
+    pub(crate) fn age(&self) -> Option<Duration> {
+        let modified = self.file.modified().ok()?;
+        Some(
+            SystemTime::now()
+                .duration_since(modified)
+                .unwrap_or_default(),
+        )
+    }
+
+    /// 将文件 fsync 到磁盘，见 [`FileStorage::sync_all`]
+    pub(crate) fn sync_all(&self) -> Result<()> {
+        self.file.sync_all()
+    }
+
+    pub fn id(&self) -> u64 {
         self.id
     }
 
+    /// 文件尾记录的文件类型，加入文件头之前写入的旧文件为 `None`
+    pub fn file_type(&self) -> Option<FileType> {
+        self.file_type
+    }
+
+    /// 检查文件类型是否为 `expected`，旧文件无法判断，视为符合
+    pub fn check_file_type(&self, expected: FileType) -> Result<(), FileFormatError> {
+        match self.file_type {
+            Some(actual) if actual != expected => Err(FileFormatError::WrongType {
+                path: self.file.path().to_path_buf(),
+                expected,
+                actual,
+            }),
+            _ => Ok(()),
+        }
+    }
+
     pub fn delete(&self) -> anyhow::Result<()> {
         // TODO: reference count
         self.file.delete()
     }
 
     pub fn num_of_blocks(&self) -> usize {
-        self.metas.len()
+        self.index.num_of_blocks
     }
 
+    /// footer 中记录的 KV 数量，格式版本 4 之前写入的文件的 footer 没有校验和，记录的数量不一定可信
     pub fn num_of_pairs(&self) -> usize {
         self.pair_num as usize
     }
 
-    /// 指定 key 是否存在于 SST，基于 bloom filter，返回 true 则可能存在，false 则一定不存在
-    pub fn maybe_contains_key(&self, key: &Bytes) -> bool {
-        match &self.bloom {
+    /// 校验过的 KV 数量，用于计算 VSST 的空洞率
+    ///
+    /// footer 带校验和时直接返回记录的数量；否则第一次调用时读出所有块数出 KV 数量并缓存，
+    /// 与 footer 中记录的不一致时记录警告
+    pub(crate) fn verified_num_of_pairs(&self) -> Result<usize> {
+        if let Some(num) = self.verified_pair_num.get() {
+            return Ok(*num as usize);
+        }
+        let mut num = 0;
+        for idx in 0..self.num_of_blocks() {
+            let mut iter =
+                BlockIterator::create_and_seek_to_first(self.read_block_with_disk(idx, true)?);
+            while iter.is_valid() {
+                num += 1;
+                iter.next();
+            }
+        }
+        if num != self.pair_num {
+            warn!(
+                "sst {} records {} pairs in its footer, but has {}",
+                self.id, self.pair_num, num
+            );
+        }
+        Ok(*self.verified_pair_num.get_or_init(|| num) as usize)
+    }
+
+    /// 删除标记的数量，格式版本 2 之前写入的文件没有记录，为 `None`
+    pub fn num_of_deletes(&self) -> Option<usize> {
+        self.delete_num.map(|num| num as usize)
+    }
+
+    /// 写入的数据中最大的 seq num，格式版本 3 之前写入的文件没有记录，为 `None`
+    ///
+    /// 刷写的 SST 为 memtable 中的最大 seq num，合并输出的 SST 为所有输入中的最大值
+    pub fn max_seq(&self) -> Option<u64> {
+        self.max_seq
+    }
+
+    /// 比较两个 SST 中数据的新旧，L0 中同一个 key 以更新的 SST 中的版本为准
+    ///
+    /// 先比较最大 seq num，没有记录的旧文件视为 0，相同时 id 更大的更新
+    pub(crate) fn recency(&self) -> (u64, u64) {
+        (self.max_seq.unwrap_or(0), self.id)
+    }
+
+    /// data block 的校验和算法
+    pub fn checksum_type(&self) -> ChecksumType {
+        self.checksum_type
+    }
+
+    /// 是否是缺少文件头、删除标记数量、footer 校验和或 bloom filter，或 filter 平均每个 key 不到 1 位的旧文件，
+    /// 见 [`SsTable::rebuild_metadata`]。按 [`SsTableBuilder::with_filter_bits_per_key`] 减少位数的 filter 不算旧文件
+    pub(crate) fn needs_metadata_rebuild(&self) -> Result<bool> {
+        if self.file_type.is_none() || self.delete_num.is_none() || !self.footer_checksum {
+            return Ok(true);
+        }
+        let data = self
+            .file
+            .read(self.filter_offset as u64, self.filter_len as u64)?;
+        let Some(filter) = Self::decode_filter(&data)? else {
+            return Ok(true);
+        };
+        Ok(filter.number_of_bits() < self.pair_num as u64)
+    }
+
+    /// 按当前格式重新生成 bloom filter、KV 数量和删除标记数量，连同原样复制的 data block 写入 `path`
+    ///
+    /// data block 不重新编码，meta 中的偏移保持不变。旧文件没有记录类型时使用 `file_type`
+    pub(crate) fn rebuild_metadata(
+        &self,
+        file_type: FileType,
+        path: impl AsRef<Path>,
+    ) -> Result<()> {
+        let mut filter_keys = vec![];
+        let mut delete_num = 0;
+        for idx in 0..self.num_of_blocks() {
+            let block = self.read_block_with_disk(idx, true)?;
+            let mut iter = BlockIterator::create_and_seek_to_first(block);
+            while iter.is_valid() {
+                if Entry::op_type_of(iter.meta()) == OpType::Delete {
+                    delete_num += 1;
+                }
+                filter_keys.push(Bytes::copy_from_slice(iter.key()));
+                iter.next();
+            }
+        }
+        let mut filter =
+            Bloom::new_for_fp_rate(filter_keys.len().max(1), BLOOM_FALSE_POSITIVE_RATE);
+        filter_keys.iter().for_each(|key| filter.set(key));
+
+        let mut data = self.file.read(0, self.meta_offset as u64)?;
+        encode_tail(
+            &mut data,
+            &self.metas()?,
+            &filter,
+            &self.dict,
+            &TableFooter {
+                file_type: self.file_type.unwrap_or(file_type),
+                checksum_type: self.checksum_type,
+                compression: self.compression,
+                pair_num: filter_keys.len() as u32,
+                delete_num,
+                max_seq: self.max_seq.unwrap_or(0),
+            },
+        )?;
+        FileStorage::create(path, data)?.sync()
+    }
+
+    pub fn compression(&self) -> CompressionType {
+        self.compression
+    }
+
+    /// SST 内保存的 zstd 字典，未使用字典时为空
+    pub fn dictionary(&self) -> &[u8] {
+        &self.dict
+    }
+
+    /// 指定 user key 是否存在于 SST，基于 bloom filter，返回 true 则可能存在，false 则一定不存在
+    ///
+    /// 删除标记和 KV 分离的 entry 同样会命中，调用方需要自行根据 entry 判断是否可见
+    pub fn maybe_contains_key(&self, user_key: &Bytes) -> bool {
+        match self.filter() {
             None => true,
-            Some(bloom) => bloom.check(key),
+            Some(bloom) => {
+                let maybe = bloom.check(user_key);
+                perf_context::record(|c| {
+                    c.bloom_checks += 1;
+                    c.bloom_filtered += !maybe as u64;
+                });
+                maybe
+            }
         }
     }
 
+    /// 记录一次点查探测，`hit` 为 false 表示 bloom filter 误判或 key 不在表中，返回累计的未命中次数
+    pub(crate) fn record_seek(&self, hit: bool) -> u64 {
+        self.seeks.fetch_add(1, Ordering::Relaxed);
+        if hit {
+            self.seek_misses.load(Ordering::Relaxed)
+        } else {
+            self.seek_misses.fetch_add(1, Ordering::Relaxed) + 1
+        }
+    }
+
+    /// 打开以来点查探测该 SST 的次数
+    pub fn seeks(&self) -> u64 {
+        self.seeks.load(Ordering::Relaxed)
+    }
+
+    /// 打开以来点查探测该 SST 却没有找到 key 的次数，包括 bloom filter 误判和 key 不在表中
+    pub fn seek_misses(&self) -> u64 {
+        self.seek_misses.load(Ordering::Relaxed)
+    }
+
     pub fn is_overlap(&self, other: Arc<SsTable>) -> bool {
-        if self.metas.is_empty() || other.metas.is_empty() {
+        if self.num_of_blocks() == 0 || other.num_of_blocks() == 0 {
             return false;
         }
         let (min_key, max_key) = self.key_range();
@@ -130,42 +688,395 @@ impl SsTable {
     }
 
     pub fn key_range(&self) -> (Bytes, Bytes) {
-        (
-            self.metas.first().unwrap().first_key.clone(),
-            self.metas.last().unwrap().last_key.clone(),
-        )
+        self.index.key_range.clone().unwrap()
+    }
+
+    /// 每个 data block 的首个 key 和大小，按 key 升序
+    pub(crate) fn block_boundaries(&self) -> Result<Vec<(Bytes, u64)>> {
+        let metas = self.metas()?;
+        Ok(metas
+            .iter()
+            .enumerate()
+            .map(|(block_idx, meta)| {
+                let size = self.block_end_offset(&metas, block_idx) - meta.offset;
+                (meta.first_key.clone(), size as u64)
+            })
+            .collect())
     }
 
-    fn read_block_with_disk(&self, block_idx: usize) -> Result<Arc<Block>> {
-        let offset = self.metas[block_idx].offset;
-        let offset_end = self
-            .metas
+    fn block_end_offset(&self, metas: &[MetaBlock], block_idx: usize) -> u32 {
+        metas
             .get(block_idx + 1)
-            .map_or(self.meta_offset, |x| x.offset);
+            .map_or(self.meta_offset, |x| x.offset)
+    }
+
+    fn corruption(&self, block_idx: usize, reason: impl ToString) -> anyhow::Error {
+        CorruptionError {
+            sst_id: self.id,
+            block_idx,
+            reason: reason.to_string(),
+        }
+        .into()
+    }
+
+    fn decode_block(
+        &self,
+        block_idx: usize,
+        block_data: Bytes,
+        verify_checksum: bool,
+    ) -> Result<Arc<Block>> {
+        let block_data = compression::decompress(block_data, self.compression, &self.dict)
+            .map_err(|e| self.corruption(block_idx, e))?;
+        let block = Block::decode(block_data).map_err(|e| self.corruption(block_idx, e))?;
+        if verify_checksum {
+            VERIFIED_BLOCKS.fetch_add(1, Ordering::Relaxed);
+            if !block.verify_checksum(self.checksum_type) {
+                CHECKSUM_FAILURES.fetch_add(1, Ordering::Relaxed);
+                return Err(self.corruption(block_idx, "checksum mismatch"));
+            }
+        }
+        Ok(Arc::new(block))
+    }
+
+    fn read_block_with_disk(&self, block_idx: usize, verify_checksum: bool) -> Result<Arc<Block>> {
+        let data = self.read_raw_block(block_idx)?;
+        self.decode_block(block_idx, data, verify_checksum)
+    }
+
+    /// 从文件中读出一个块未解压的数据
+    fn read_raw_block(&self, block_idx: usize) -> Result<Bytes> {
+        let metas = self.metas()?;
+        let offset = metas[block_idx].offset;
+        let offset_end = self.block_end_offset(&metas, block_idx);
+        if offset_end < offset {
+            return Err(self.corruption(
+                block_idx,
+                format!("block range {}..{} is invalid", offset, offset_end),
+            ));
+        }
         let block_data = self
             .file
             .read(offset as u64, (offset_end - offset) as u64)?;
-        Ok(Arc::new(Block::decode(&block_data[..])))
+        perf_context::record(|c| {
+            c.block_reads += 1;
+            c.block_read_bytes += block_data.len() as u64;
+        });
+        Ok(Bytes::from(block_data))
     }
 
-    pub fn read_block(&self, block_idx: usize) -> Result<Arc<Block>> {
-        if let Some(ref block_cache) = self.cache {
-            let blk = block_cache
-                .try_get_with((self.id, block_idx), || {
-                    self.read_block_with_disk(block_idx)
-                })
-                .map_err(|e| anyhow!("{}", e))?;
-            Ok(blk)
+    /// 块放入缓存时的形式，缓存只保存其中一种形式，不会同时缓存压缩和解码后的块
+    ///
+    /// 缓存开启了压缩块（见 [`BlockCache::with_compressed_blocks`]）且 SST 有压缩时缓存 `raw`，
+    /// `raw` 可能是合并读取的读缓冲的切片，复制一份，避免缓存项让整个读缓冲无法释放
+    fn cache_entry(&self, cache: &BlockCache, block: &Arc<Block>, raw: &Bytes) -> CachedBlock {
+        if cache.caches_compressed() && self.compression != CompressionType::None {
+            CachedBlock::Compressed(Bytes::copy_from_slice(raw))
         } else {
-            self.read_block_with_disk(block_idx)
+            CachedBlock::Decoded(block.clone())
+        }
+    }
+
+    pub(crate) fn is_cached(&self, block_idx: usize) -> bool {
+        self.cache
+            .as_ref()
+            .is_some_and(|cache| cache.contains_key(&(self.id, block_idx)))
+    }
+
+    /// 当前在块缓存中的块的 key 范围，meta 读取失败时当作没有块在缓存中
+    pub(crate) fn cached_key_ranges(&self) -> Vec<(Bytes, Bytes)> {
+        if !(0..self.num_of_blocks()).any(|idx| self.is_cached(idx)) {
+            return vec![];
+        }
+        let metas = match self.metas() {
+            Ok(metas) => metas,
+            Err(e) => {
+                warn!("load index of sst {} failed: {}", self.id, e);
+                return vec![];
+            }
+        };
+        (0..metas.len())
+            .filter(|idx| self.is_cached(*idx))
+            .map(|idx| (metas[idx].first_key.clone(), metas[idx].last_key.clone()))
+            .collect()
+    }
+
+    /// 将 key 范围与 `hot_ranges` 有重叠的块读入缓存，返回读入的块数
+    ///
+    /// `hot_ranges` 需按 key 排序且互不重叠
+    pub(crate) fn warm_cache(&self, hot_ranges: &[(Bytes, Bytes)]) -> Result<usize> {
+        if self.cache.is_none() {
+            return Ok(0);
+        }
+        let mut warmed = 0;
+        for (idx, meta) in self.metas()?.iter().enumerate() {
+            // 第一个结束 key 不小于块起始 key 的热点范围
+            let i = hot_ranges.partition_point(|(_, last)| last < &meta.first_key);
+            if hot_ranges
+                .get(i)
+                .is_some_and(|(first, _)| first <= &meta.last_key)
+            {
+                self.read_block(idx)?;
+                warmed += 1;
+            }
+        }
+        Ok(warmed)
+    }
+
+    /// 将所有块读入缓存，返回读入的块数
+    pub(crate) fn warm_all(&self) -> Result<usize> {
+        if self.cache.is_none() {
+            return Ok(0);
+        }
+        for idx in 0..self.num_of_blocks() {
+            self.read_block(idx)?;
+        }
+        Ok(self.num_of_blocks())
+    }
+
+    /// 从 `block_idx` 开始读取若干个相邻的块，返回的第一个块即 `block_idx`
+    ///
+    /// 后续未被缓存的相邻块会合并到同一次磁盘读取中，总大小不超过 `max_bytes`（但至少读取一个块），
+    /// 读到的块都会放入缓存。用于顺序扫描时减少加锁、seek 和 read 的次数
+    pub fn read_blocks(&self, block_idx: usize, max_bytes: u64) -> Result<Vec<Arc<Block>>> {
+        self.read_blocks_with_options(block_idx, max_bytes, &BlockReadOptions::default())
+    }
+
+    /// 以指定的选项读取若干个相邻的块，见 [`SsTable::read_blocks`]
+    pub(crate) fn read_blocks_with_options(
+        &self,
+        block_idx: usize,
+        max_bytes: u64,
+        options: &BlockReadOptions,
+    ) -> Result<Vec<Arc<Block>>> {
+        // 只有当前线程负责读取 `block_idx` 时才合并读取，已缓存或正在被其他线程读取时使用其结果；
+        // 合并的范围在下一个已缓存或正在被读取的块之前截止，同一个块不会被并发的扫描重复读盘
+        let cache = self.cache.as_deref();
+        let mut tickets = vec![];
+        if let Some(cache) = cache {
+            match cache.begin_load((self.id, block_idx)) {
+                BlockLoad::Cached(block) => return Ok(vec![block]),
+                BlockLoad::Compressed(data) => {
+                    return Ok(vec![self.decode_block(block_idx, data, false)?])
+                }
+                BlockLoad::Waiting(loading) => return Ok(vec![loading.wait()?]),
+                BlockLoad::Leader(ticket) => tickets.push(ticket),
+            }
+        }
+
+        let metas = self.metas()?;
+        let start = metas[block_idx].offset;
+        let mut end_idx = block_idx + 1;
+        while end_idx < metas.len()
+            && (self.block_end_offset(&metas, end_idx).saturating_sub(start)) as u64 <= max_bytes
+        {
+            if let Some(cache) = cache {
+                match cache.try_begin_load((self.id, end_idx)) {
+                    Some(ticket) => tickets.push(ticket),
+                    None => break,
+                }
+            }
+            end_idx += 1;
+        }
+
+        let blocks = self.read_block_range(&metas, block_idx, end_idx, options);
+        for (i, ticket) in tickets.into_iter().enumerate() {
+            let block = blocks.as_ref().map(|blocks| &blocks[i].0);
+            let entry = match (cache, &blocks) {
+                (Some(cache), Ok(blocks)) if options.fill_cache => {
+                    Some(self.cache_entry(cache, &blocks[i].0, &blocks[i].1))
+                }
+                _ => None,
+            };
+            ticket.finish(block, entry);
+        }
+        blocks.map(|blocks| blocks.into_iter().map(|(block, _)| block).collect())
+    }
+
+    /// 一次读出 `[block_idx, end_idx)` 范围内的块并解码，同时返回每个块未解压的数据
+    ///
+    /// 不放入缓存时未压缩的块共享同一个读缓冲；放入缓存时各自复制一份，
+    /// 避免部分块被淘汰后读缓冲仍被其他块持有，缓存按块计算的内存与实际不符
+    fn read_block_range(
+        &self,
+        metas: &[MetaBlock],
+        block_idx: usize,
+        end_idx: usize,
+        options: &BlockReadOptions,
+    ) -> Result<Vec<(Arc<Block>, Bytes)>> {
+        let start = metas[block_idx].offset;
+        let end = self.block_end_offset(metas, end_idx - 1);
+        if end < start {
+            return Err(self.corruption(
+                end_idx - 1,
+                format!("block range {}..{} is invalid", start, end),
+            ));
         }
+        let data = Bytes::from(self.file.read(start as u64, (end - start) as u64)?);
+        perf_context::record(|c| {
+            c.block_reads += (end_idx - block_idx) as u64;
+            c.block_read_bytes += data.len() as u64;
+        });
+        let cached = self.cache.is_some() && options.fill_cache;
+        let copy = cached && self.compression == CompressionType::None && end_idx - block_idx > 1;
+
+        let mut blocks = Vec::with_capacity(end_idx - block_idx);
+        for idx in block_idx..end_idx {
+            let (offset, offset_end) = (metas[idx].offset, self.block_end_offset(metas, idx));
+            if offset < start || offset_end < offset || offset_end > end {
+                return Err(self.corruption(
+                    idx,
+                    format!("block range {}..{} is invalid", offset, offset_end),
+                ));
+            }
+            let begin = (offset - start) as usize;
+            let end = (offset_end - start) as usize;
+            let raw = match copy {
+                true => Bytes::copy_from_slice(&data[begin..end]),
+                false => data.slice(begin..end),
+            };
+            let block = self.decode_block(idx, raw.clone(), options.verify_on_load(cached))?;
+            blocks.push((block, raw));
+        }
+        Ok(blocks)
+    }
+
+    pub fn read_block(&self, block_idx: usize) -> Result<Arc<Block>> {
+        self.read_block_with_options(block_idx, &BlockReadOptions::default())
     }
 
-    pub fn find_block_idx(&self, key: &[u8]) -> usize {
-        self.metas
-            .partition_point(|meta| meta.first_key <= key)
-            .saturating_sub(1)
+    /// 以指定的选项读取一个块，已在缓存中的块直接返回，不再校验
+    pub(crate) fn read_block_with_options(
+        &self,
+        block_idx: usize,
+        options: &BlockReadOptions,
+    ) -> Result<Arc<Block>> {
+        self.read_block_with_source(block_idx, options)
+            .map(|(block, _)| block)
     }
+
+    /// 同 [`SsTable::read_block_with_options`]，同时返回块来自缓存还是文件
+    pub(crate) fn read_block_with_source(
+        &self,
+        block_idx: usize,
+        options: &BlockReadOptions,
+    ) -> Result<(Arc<Block>, BlockSource)> {
+        let Some(ref block_cache) = self.cache else {
+            return self
+                .read_block_with_disk(block_idx, options.verify_on_load(false))
+                .map(|block| (block, BlockSource::Disk));
+        };
+        // 不填充缓存的读取同样与其他线程共享同一次读盘，只是读到的块不放入缓存
+        let block = match block_cache.begin_load((self.id, block_idx)) {
+            BlockLoad::Cached(block) => return Ok((block, BlockSource::Cache)),
+            BlockLoad::Compressed(data) => {
+                return self
+                    .decode_block(block_idx, data, false)
+                    .map(|block| (block, BlockSource::Cache))
+            }
+            BlockLoad::Waiting(loading) => loading.wait(),
+            BlockLoad::Leader(ticket) => {
+                let verify_checksum = options.verify_on_load(options.fill_cache);
+                let loaded = self.read_raw_block(block_idx).and_then(|raw| {
+                    Ok((
+                        self.decode_block(block_idx, raw.clone(), verify_checksum)?,
+                        raw,
+                    ))
+                });
+                let entry = match &loaded {
+                    Ok((block, raw)) if options.fill_cache => {
+                        Some(self.cache_entry(block_cache, block, raw))
+                    }
+                    _ => None,
+                };
+                let block = loaded.map(|(block, _)| block);
+                ticket.finish(block.as_ref(), entry);
+                block
+            }
+        };
+        block.map(|block| (block, BlockSource::Disk))
+    }
+
+    /// 返回第一个可能包含 >= `key` 的 key 的块
+    ///
+    /// 同一个 key 的多个版本可能跨越相邻的块，因此以 `first_key < key` 划分，避免跳过前一个块中的版本；
+    /// 前一个块的 `last_key < key` 时其中没有需要的 key，直接返回下一个块，避免多读一个块
+    ///
+    /// meta 不常驻内存时可能需要从文件中读取 meta，读取失败时返回错误
+    pub fn find_block_idx(&self, key: &[u8]) -> Result<usize> {
+        let metas = self.metas()?;
+        let idx = metas
+            .partition_point(|meta| meta.first_key < key)
+            .saturating_sub(1);
+        Ok(match metas.get(idx) {
+            Some(meta) if meta.last_key < key && idx + 1 < metas.len() => idx + 1,
+            _ => idx,
+        })
+    }
+}
+
+/// checksum | compression | dict len | filter len | filter offset | meta offset | pair nums
+///
+/// 格式版本 2 起 footer 之前是 delete nums，版本 3 起再之前是 max seq，版本 4 起再之前是从 max seq 到 footer 结尾的
+/// crc32c 校验和，footer 之后是 [`FileHeader`]
+const FOOTER_SIZE: u64 = 28;
+
+/// 加入文件头之前的 footer：filter len | filter offset | meta offset | pair nums
+const LEGACY_FOOTER_SIZE: u64 = 16;
+
+/// 每个 SST 最多采样的字节数相对于字典大小的倍数
+const DICT_SAMPLE_FACTOR: usize = 100;
+
+/// footer 和文件头中除各部分偏移以外的字段
+struct TableFooter {
+    file_type: FileType,
+    checksum_type: ChecksumType,
+    compression: CompressionType,
+    pair_num: u32,
+    delete_num: u32,
+    max_seq: u64,
+}
+
+/// 在 data block 之后依次写入 meta、bloom filter、字典、footer 和文件头，返回 (meta offset, filter offset, filter len)
+fn encode_tail(
+    data: &mut Vec<u8>,
+    metas: &[MetaBlock],
+    filter: &Bloom<Bytes>,
+    dict: &[u8],
+    footer: &TableFooter,
+) -> Result<(u32, u32, u32)> {
+    let meta_offset = data.len() as u32;
+    metas
+        .iter()
+        .for_each(|meta_block| data.extend(&meta_block.encode()));
+
+    let bloom = postcard::to_allocvec(filter)?;
+    let filter_offset = data.len() as u32;
+    let filter_len = bloom.len() as u32;
+    data.extend(bloom);
+    data.extend(dict);
+
+    let mut tail = Vec::with_capacity(FOOTER_SIZE as usize + 12);
+    tail.put_u64_le(footer.max_seq);
+    tail.put_u32_le(footer.delete_num);
+    tail.put_u32_le(footer.checksum_type.encode());
+    tail.put_u32_le(footer.compression.encode());
+    tail.put_u32_le(dict.len() as u32);
+    tail.put_u32_le(filter_len);
+    tail.put_u32_le(filter_offset);
+    tail.put_u32_le(meta_offset);
+    tail.put_u32_le(footer.pair_num);
+    data.put_u32_le(ChecksumType::Crc32c.checksum(&tail));
+    data.extend(tail);
+    data.extend(FileHeader::new(footer.file_type).encode());
+    Ok((meta_offset, filter_offset, filter_len))
+}
+
+/// SST bloom filter 的 key 域：只由 user key 构成
+///
+/// 写入 filter 与查询 filter 都必须经过这里，无论 entry 是 Put、Delete 还是 KV 分离的，
+/// 若 SST 中的 key 将来带上 seq 等版本信息，需要在这里去掉，以保证同一个 user key 的所有版本映射到同一个 filter key
+fn filter_key(e: &Entry) -> Bytes {
+    e.key.clone()
 }
 
 pub struct SsTableBuilder {
@@ -173,33 +1084,146 @@ pub struct SsTableBuilder {
     first_key: Vec<u8>,
     last_key: Vec<u8>,
     meta: Vec<MetaBlock>,
-    data: Vec<u8>,
-    bloom: Bloom<Bytes>,
+    // 未压缩的 data block，压缩推迟到 build 时进行，以便使用整个 SST 的采样训练字典
+    blocks: Vec<Bytes>,
+    blocks_size: usize,
+    filter_keys: Vec<Bytes>,
     cnt: u32,
+    delete_cnt: u32,
+    max_seq: u64,
+    compression: CompressionType,
+    dict_size: usize,
+    checksum_type: ChecksumType,
+    block_size: usize,
+    samples: Vec<Vec<u8>>,
+    samples_size: usize,
+    file_type: FileType,
+    key_prefixes: KeyPrefixStats,
+    filter_bits_per_key: Option<u32>,
+    filter_seed: Option<[u8; 32]>,
+    key_order_check: bool,
+    // 第一个没有严格递增的 key，之后的 entry 都不再写入
+    key_order_error: Option<KeyOrderError>,
 }
 
 impl SsTableBuilder {
     pub fn new() -> SsTableBuilder {
+        Self::with_compression(CompressionType::None, 0)
+    }
+
+    /// `dict_size` 为 0 时不训练字典，仅对 [`CompressionType::Zstd`] 生效
+    pub fn with_compression(compression: CompressionType, dict_size: usize) -> SsTableBuilder {
+        Self::with_options(compression, dict_size, BLOCK_CHECKSUM)
+    }
+
+    /// 同 [`SsTableBuilder::with_compression`]，并指定 data block 的校验和算法
+    pub fn with_options(
+        compression: CompressionType,
+        dict_size: usize,
+        checksum_type: ChecksumType,
+    ) -> SsTableBuilder {
         SsTableBuilder {
-            builder: BlockBuilder::new(),
+            builder: BlockBuilder::with_checksum(checksum_type),
             first_key: Vec::new(),
             last_key: Vec::new(),
             meta: Vec::new(),
-            data: Vec::new(),
-            bloom: Bloom::new(20, 1000),
+            blocks: Vec::new(),
+            blocks_size: 0,
+            filter_keys: Vec::new(),
             cnt: 0,
+            delete_cnt: 0,
+            max_seq: 0,
+            compression,
+            dict_size: match compression {
+                CompressionType::Zstd => dict_size,
+                CompressionType::None => 0,
+            },
+            checksum_type,
+            block_size: BLOCK_SIZE,
+            samples: Vec::new(),
+            samples_size: 0,
+            file_type: FileType::Sst,
+            key_prefixes: KeyPrefixStats::default(),
+            filter_bits_per_key: None,
+            filter_seed: None,
+            key_order_check: false,
+            key_order_error: None,
         }
     }
 
+    /// 指定 data block 的大小，默认为 [`BLOCK_SIZE`]，需要在添加 entry 之前调用
+    ///
+    /// VSST 中的 value 很大，使用小块可以让每个块只包含一个 value，读取一个 value 时只需读出它所在的块
+    pub fn with_block_size(mut self, block_size: usize) -> Self {
+        debug_assert!(self.cnt == 0, "block size changed after adding entries");
+        self.block_size = block_size;
+        self.builder = BlockBuilder::with_options(self.checksum_type, block_size);
+        self
+    }
+
+    /// 指定写入文件尾的文件类型，默认为 [`FileType::Sst`]，构建 VSST 时使用 [`FileType::VSst`]
+    pub fn with_file_type(mut self, file_type: FileType) -> Self {
+        self.file_type = file_type;
+        self
+    }
+
+    /// 记录数据的最大 seq num，见 [`SsTable::max_seq`]，entry 本身不带 seq num，由调用方给出
+    pub fn with_max_seq(mut self, max_seq: u64) -> Self {
+        self.max_seq = max_seq;
+        self
+    }
+
+    /// bloom filter 每个 key 使用的位数，至少为 1，为 `None` 时按 [`BLOOM_FALSE_POSITIVE_RATE`] 分配
+    pub fn with_filter_bits_per_key(mut self, bits_per_key: Option<u32>) -> Self {
+        self.filter_bits_per_key = bits_per_key;
+        self
+    }
+
+    /// bloom filter 哈希函数的种子，默认每个 SST 随机生成。种子随 filter 写入文件，
+    /// 固定种子后同样的输入得到逐字节相同的文件，用于生成格式样例
+    pub fn with_filter_seed(mut self, seed: Option<[u8; 32]>) -> Self {
+        self.filter_seed = seed;
+        self
+    }
+
+    /// 检查加入的 key 是否严格递增，默认不检查；刷写和合并按 [`crate::DbOptions::sst_key_order_check`] 设置
+    pub fn with_key_order_check(mut self, check: KeyOrderCheck) -> Self {
+        self.key_order_check = check.enabled();
+        self
+    }
+
+    /// 加入一个 entry，key 必须大于之前加入的所有 key；开启 [`KeyOrderCheck`] 时违反顺序的 entry 不写入，
+    /// 由 [`SsTableBuilder::build`] 返回 [`KeyOrderError`]
     pub fn add(&mut self, e: &Entry) {
-        self.bloom.set(&e.key);
+        debug_assert!(e.validate().is_ok(), "invalid entry: {:?}", e);
+        if self.key_order_check {
+            if self.key_order_error.is_some() {
+                return;
+            }
+            // 每个 entry 加入后 last_key 都是它的 key，换块时也一样
+            if self.cnt > 0 && e.key[..] <= self.last_key[..] {
+                self.key_order_error = Some(KeyOrderError {
+                    index: self.cnt,
+                    previous: Bytes::copy_from_slice(&self.last_key),
+                    key: e.key.clone(),
+                });
+                return;
+            }
+        }
+        self.filter_keys.push(filter_key(e));
         self.cnt += 1;
+        if e.op_type() == OpType::Delete {
+            self.delete_cnt += 1;
+        }
+        self.sample(e);
 
         if self.first_key.is_empty() {
             self.first_key = e.key.to_vec();
         }
 
         if self.builder.add(e) {
+            self.key_prefixes
+                .add(&self.last_key, &e.key, self.builder.len() - 1);
             self.last_key = e.key.to_vec();
             return;
         }
@@ -207,25 +1231,46 @@ impl SsTableBuilder {
         self.finish_block();
 
         assert!(self.builder.add(e));
+        self.key_prefixes.add(&[], &e.key, 0);
         self.first_key = e.key.to_vec();
         self.last_key = e.key.to_vec();
     }
 
+    fn sample(&mut self, e: &Entry) {
+        if self.dict_size == 0 || self.samples_size >= self.dict_size * DICT_SAMPLE_FACTOR {
+            return;
+        }
+        let mut sample = Vec::with_capacity(e.key.len() + e.value.len());
+        sample.extend_from_slice(&e.key);
+        sample.extend_from_slice(&e.value);
+        self.samples_size += sample.len();
+        self.samples.push(sample);
+    }
+
     fn finish_block(&mut self) {
-        let old_builder = std::mem::replace(&mut self.builder, BlockBuilder::new());
+        let old_builder = std::mem::replace(
+            &mut self.builder,
+            BlockBuilder::with_options(self.checksum_type, self.block_size),
+        );
         let encoded_block = old_builder.build().encode();
         self.meta.push(MetaBlock {
-            offset: self.data.len() as u32,
+            offset: 0,
             first_key: std::mem::take(&mut self.first_key).into(),
             last_key: std::mem::take(&mut self.last_key).into(),
         });
-        self.data.extend(encoded_block);
+        self.blocks_size += encoded_block.len();
+        self.blocks.push(encoded_block);
+    }
+
+    /// 已加入的 key 的公共前缀统计
+    pub(crate) fn key_prefixes(&self) -> &KeyPrefixStats {
+        &self.key_prefixes
     }
 
-    // 数据大小（预估值）
+    // 数据大小（预估值，未压缩）
     pub fn size(&self) -> usize {
         self.builder.size()
-            + self.data.len()
+            + self.blocks_size
             + self.meta.len() * (self.first_key.len() + self.last_key.len())
     }
 
@@ -234,38 +1279,83 @@ impl SsTableBuilder {
         self.meta.len()
     }
 
+    /// 是否还没有加入任何 entry，空的 builder 的 `size` 也不为 0
+    pub fn is_empty(&self) -> bool {
+        self.meta.is_empty() && self.builder.is_empty()
+    }
+
     pub fn build(
         mut self,
-        id: u32,
+        id: u64,
         block_cache: Option<Arc<BlockCache>>,
         path: impl AsRef<Path>,
     ) -> Result<SsTable> {
+        if let Some(err) = self.key_order_error.take() {
+            return Err(err.into());
+        }
         self.finish_block();
 
-        let meta_offset = self.data.len() as u32;
-        self.meta
-            .iter()
-            .for_each(|meta_block| self.data.extend(&meta_block.encode()));
+        let dict = if self.dict_size > 0 {
+            compression::train_dictionary(&self.samples, self.dict_size).unwrap_or_default()
+        } else {
+            vec![]
+        };
 
-        let bloom = postcard::to_allocvec(&self.bloom)?;
-        let filter_offset = self.data.len() as u32;
-        let filter_len = bloom.len() as u32;
-        self.data.extend(bloom);
-        self.data.put_u32_le(filter_len);
-        self.data.put_u32_le(filter_offset);
+        let mut data = Vec::with_capacity(self.blocks_size);
+        for (meta, block) in self.meta.iter_mut().zip(self.blocks.iter()) {
+            meta.offset = data.len() as u32;
+            data.extend(compression::compress(block, self.compression, &dict)?);
+        }
+
+        // 在知道 key 数量后再创建 filter，保证假阳性率
+        let num_keys = self.filter_keys.len().max(1);
+        let bitmap_size = match self.filter_bits_per_key {
+            None => Bloom::<Bytes>::compute_bitmap_size(num_keys, BLOOM_FALSE_POSITIVE_RATE),
+            Some(bits_per_key) => (num_keys * bits_per_key.max(1) as usize).div_ceil(8),
+        };
+        let mut filter = match &self.filter_seed {
+            None => Bloom::new(bitmap_size, num_keys),
+            Some(seed) => Bloom::new_with_seed(bitmap_size, num_keys, seed),
+        };
+        self.filter_keys.iter().for_each(|key| filter.set(key));
 
-        self.data.put_u32_le(meta_offset);
-        self.data.put_u32_le(self.cnt);
+        let (meta_offset, filter_offset, filter_len) = encode_tail(
+            &mut data,
+            &self.meta,
+            &filter,
+            &dict,
+            &TableFooter {
+                file_type: self.file_type,
+                checksum_type: self.checksum_type,
+                compression: self.compression,
+                pair_num: self.cnt,
+                delete_num: self.delete_cnt,
+                max_seq: self.max_seq,
+            },
+        )?;
 
-        let file = FileStorage::create(path, self.data.clone())?;
+        let file = FileStorage::create(path, data)?;
         Ok(SsTable {
             id,
             file,
-            metas: self.meta,
+            file_type: Some(self.file_type),
+            index: TableIndex::new(id, self.meta, block_cache.as_ref()),
             meta_offset,
             cache: block_cache,
-            bloom: Some(Arc::new(self.bloom)),
+            bloom: OnceLock::from(Some(Arc::new(filter))),
+            filter_offset,
+            filter_len,
+            filter_loading: FilterLoading::Eager,
             pair_num: self.cnt,
+            verified_pair_num: OnceLock::from(self.cnt),
+            footer_checksum: true,
+            delete_num: Some(self.delete_cnt),
+            max_seq: Some(self.max_seq),
+            compression: self.compression,
+            dict: Bytes::from(dict),
+            checksum_type: self.checksum_type,
+            seeks: AtomicU64::new(0),
+            seek_misses: AtomicU64::new(0),
         })
     }
 }
@@ -0,0 +1,86 @@
+use anyhow::{anyhow, Result};
+use bytes::{Buf, BufMut, Bytes};
+use tracing::warn;
+
+use crate::perf_context;
+
+const ZSTD_LEVEL: i32 = 3;
+
+/// SST 数据块的压缩方式
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum CompressionType {
+    None = 0,
+    Zstd = 1,
+}
+
+impl CompressionType {
+    pub fn from(num: u32) -> Result<Self> {
+        match num {
+            0 => Ok(CompressionType::None),
+            1 => Ok(CompressionType::Zstd),
+            _ => Err(anyhow!("unsupported compression type: {}", num)),
+        }
+    }
+
+    pub fn encode(&self) -> u32 {
+        *self as u32
+    }
+}
+
+/// 压缩一个编码后的块，`dict` 为空时不使用字典
+///
+/// 压缩后的 layout:
+/// ```text
+/// +--------------------+-----------------+
+/// | raw len(4 bytes)   | compressed data |
+/// +--------------------+-----------------+
+/// ```
+pub(crate) fn compress(data: &[u8], compression: CompressionType, dict: &[u8]) -> Result<Vec<u8>> {
+    match compression {
+        CompressionType::None => Ok(data.to_vec()),
+        CompressionType::Zstd => {
+            let mut compressor = if dict.is_empty() {
+                zstd::bulk::Compressor::new(ZSTD_LEVEL)?
+            } else {
+                zstd::bulk::Compressor::with_dictionary(ZSTD_LEVEL, dict)?
+            };
+            let compressed = compressor.compress(data)?;
+            let mut buf = Vec::with_capacity(4 + compressed.len());
+            buf.put_u32_le(data.len() as u32);
+            buf.extend(compressed);
+            Ok(buf)
+        }
+    }
+}
+
+/// 解压 [`compress`] 的结果，未压缩时直接返回 `data`，不复制
+pub(crate) fn decompress(data: Bytes, compression: CompressionType, dict: &[u8]) -> Result<Bytes> {
+    match compression {
+        CompressionType::None => Ok(data),
+        CompressionType::Zstd => {
+            if data.len() < 4 {
+                return Err(anyhow!("compressed block too short: {} bytes", data.len()));
+            }
+            let raw_len = (&data[..4]).get_u32_le() as usize;
+            let mut decompressor = if dict.is_empty() {
+                zstd::bulk::Decompressor::new()?
+            } else {
+                zstd::bulk::Decompressor::with_dictionary(dict)?
+            };
+            let data = decompressor.decompress(&data[4..], raw_len)?;
+            perf_context::record(|c| c.bytes_decompressed += data.len() as u64);
+            Ok(Bytes::from(data))
+        }
+    }
+}
+
+/// 用采样数据训练 zstd 字典，样本太少等原因训练失败时返回 `None`，此时退化为不带字典的压缩
+pub(crate) fn train_dictionary(samples: &[Vec<u8>], dict_size: usize) -> Option<Vec<u8>> {
+    match zstd::dict::from_samples(samples, dict_size) {
+        Ok(dict) => Some(dict),
+        Err(e) => {
+            warn!("train zstd dictionary failed: {}", e);
+            None
+        }
+    }
+}
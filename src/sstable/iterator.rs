@@ -1,78 +1,215 @@
-use crate::block::iterator::BlockIterator;
+use crate::block::iterator::{Block, BlockIterator};
 
+use crate::entry::Entry;
 use crate::iterator::StorageIterator;
-use crate::sstable::builder::SsTable;
+use crate::memory_limit::{self, MemoryReservation};
+use crate::sstable::builder::{BlockReadOptions, BlockSource, SsTable};
+use crate::MAX_COALESCE_READ_SIZE;
 use anyhow::{anyhow, Result};
-use bytes::Buf;
 use parking_lot::RwLock;
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::sync::atomic::{AtomicU64, Ordering};
 use std::sync::Arc;
-use tracing::instrument;
+use std::time::{Duration, Instant};
+use tracing::{field, instrument, span, warn};
 
 #[derive(Debug)]
 pub struct SsTableIterator {
     table: Arc<SsTable>,
     block_iter: BlockIterator,
     block_idx: usize,
+    // 顺序读取时合并读出的后续块，第一个对应 block_idx + 1
+    prefetched: VecDeque<Arc<Block>>,
+    // 预读的块在读内存预算中的登记，超出预算的块不预读
+    prefetch_memory: MemoryReservation<'static>,
+    options: BlockReadOptions,
 }
 
 impl SsTableIterator {
-    fn seek_to_first_inner(table: &Arc<SsTable>) -> Result<(usize, BlockIterator)> {
+    fn seek_to_first_inner(
+        table: &Arc<SsTable>,
+        options: &BlockReadOptions,
+    ) -> Result<(usize, BlockIterator)> {
         Ok((
             0,
-            BlockIterator::create_and_seek_to_first(table.read_block(0)?),
+            BlockIterator::create_and_seek_to_first(table.read_block_with_options(0, options)?),
         ))
     }
 
     /// Create a new iterator and seek to the first key-value pair.
     pub fn create_and_seek_to_first(table: Arc<SsTable>) -> Result<Self> {
-        let (block_idx, block_iter) = Self::seek_to_first_inner(&table)?;
+        Self::create_and_seek_to_first_with_options(table, BlockReadOptions::default())
+    }
+
+    /// 以指定的读取选项创建迭代器并定位到第一个 key-value pair
+    pub(crate) fn create_and_seek_to_first_with_options(
+        table: Arc<SsTable>,
+        options: BlockReadOptions,
+    ) -> Result<Self> {
+        let (block_idx, block_iter) = Self::seek_to_first_inner(&table, &options)?;
         let iter = Self {
             block_iter,
             table,
             block_idx,
+            prefetched: VecDeque::new(),
+            prefetch_memory: memory_limit::empty_reservation(),
+            options,
         };
         Ok(iter)
     }
 
     /// Seek to the first key-value pair.
     pub fn seek_to_first(&mut self) -> Result<()> {
-        let (block_idx, block_iter) = Self::seek_to_first_inner(&self.table)?;
+        let (block_idx, block_iter) = Self::seek_to_first_inner(&self.table, &self.options)?;
         self.block_idx = block_idx;
         self.block_iter = block_iter;
+        self.clear_prefetched();
         Ok(())
     }
 
-    fn seek_to_key_inner(table: &Arc<SsTable>, key: &[u8]) -> Result<(usize, BlockIterator)> {
-        let mut blk_idx = table.find_block_idx(key);
-        let mut blk_iter = BlockIterator::create_and_seek_to_key(table.read_block(blk_idx)?, key);
+    /// 定位到第一个 >= `key` 的 key-value pair，读取的任一块来自文件时返回 [`BlockSource::Disk`]
+    fn seek_to_key_inner(
+        table: &Arc<SsTable>,
+        key: &[u8],
+        options: &BlockReadOptions,
+    ) -> Result<(usize, BlockIterator, BlockSource)> {
+        let mut blk_idx = table.find_block_idx(key)?;
+        let (block, mut source) = table.read_block_with_source(blk_idx, options)?;
+        let mut blk_iter = BlockIterator::create_and_seek_to_key(block, key);
         if !blk_iter.is_valid() {
             blk_idx += 1;
             if blk_idx < table.num_of_blocks() {
-                blk_iter = BlockIterator::create_and_seek_to_first(table.read_block(blk_idx)?);
+                let (block, next_source) = table.read_block_with_source(blk_idx, options)?;
+                blk_iter = BlockIterator::create_and_seek_to_first(block);
+                if next_source == BlockSource::Disk {
+                    source = BlockSource::Disk;
+                }
             }
         }
-        Ok((blk_idx, blk_iter))
+        Ok((blk_idx, blk_iter, source))
     }
 
     /// Create a new iterator and seek to the first key-value pair which >= `key`.
     pub fn create_and_seek_to_key(table: Arc<SsTable>, key: &[u8]) -> Result<Self> {
-        let (block_idx, block_iter) = Self::seek_to_key_inner(&table, key)?;
+        Self::create_and_seek_to_key_with_options(table, key, BlockReadOptions::default())
+    }
+
+    /// 以指定的读取选项创建迭代器并定位到第一个 >= `key` 的 key-value pair
+    pub(crate) fn create_and_seek_to_key_with_options(
+        table: Arc<SsTable>,
+        key: &[u8],
+        options: BlockReadOptions,
+    ) -> Result<Self> {
+        Self::create_and_seek_to_key_with_source(table, key, options).map(|(iter, _)| iter)
+    }
+
+    /// 同 [`SsTableIterator::create_and_seek_to_key_with_options`]，同时返回定位时读取的块来自缓存还是文件
+    pub(crate) fn create_and_seek_to_key_with_source(
+        table: Arc<SsTable>,
+        key: &[u8],
+        options: BlockReadOptions,
+    ) -> Result<(Self, BlockSource)> {
+        let (block_idx, block_iter, source) = Self::seek_to_key_inner(&table, key, &options)?;
         let iter = Self {
             block_iter,
             table,
             block_idx,
+            prefetched: VecDeque::new(),
+            prefetch_memory: memory_limit::empty_reservation(),
+            options,
         };
-        Ok(iter)
+        Ok((iter, source))
     }
 
     /// Seek to the first key-value pair which >= `key`.
     pub fn seek_to_key(&mut self, key: &[u8]) -> Result<()> {
-        let (block_idx, block_iter) = Self::seek_to_key_inner(&self.table, key)?;
+        let (block_idx, block_iter, _) = Self::seek_to_key_inner(&self.table, key, &self.options)?;
         self.block_iter = block_iter;
         self.block_idx = block_idx;
+        self.clear_prefetched();
         Ok(())
     }
+
+    /// 从当前位置向后定位到第一个 >= `key` 的 key-value pair，当前位置已不小于 `key` 时不移动
+    ///
+    /// 目标在当前块或已预读的块中时不再读盘，否则从目标块开始合并读取后续的块，
+    /// 适合按 key 递增的顺序反复定位
+    pub(crate) fn seek_forward(&mut self, key: &[u8]) -> Result<()> {
+        if self.is_valid() && self.key() >= key {
+            return Ok(());
+        }
+        let blk_idx = self.table.find_block_idx(key)?;
+        if blk_idx < self.block_idx {
+            return self.seek_to_key(key);
+        }
+        if blk_idx == self.block_idx {
+            self.block_iter.seek_to_key(key);
+        } else {
+            let skip = blk_idx - self.block_idx - 1;
+            let block = if skip < self.prefetched.len() {
+                for _ in 0..skip {
+                    self.pop_prefetched();
+                }
+                self.pop_prefetched().unwrap()
+            } else {
+                self.clear_prefetched();
+                self.read_and_prefetch(blk_idx)?
+            };
+            self.block_idx = blk_idx;
+            self.block_iter = BlockIterator::create_and_seek_to_key(block, key);
+        }
+        if !self.block_iter.is_valid() {
+            self.next_block()?;
+        }
+        Ok(())
+    }
+
+    /// 当前块读完后移动到下一个块，优先使用预读的块
+    fn next_block(&mut self) -> Result<()> {
+        self.block_idx += 1;
+        if self.block_idx < self.table.num_of_blocks() {
+            let block = match self.pop_prefetched() {
+                Some(block) => block,
+                None => self.read_and_prefetch(self.block_idx)?,
+            };
+            self.block_iter = BlockIterator::create_and_seek_to_first(block);
+        }
+        Ok(())
+    }
+
+    /// 读取 `block_idx` 并合并读取之后的块放入预读队列，队列为空时调用
+    ///
+    /// 合并读取的大小先登记到读内存预算，超出预算时只读取 `block_idx`；读出后按实际的块大小登记
+    fn read_and_prefetch(&mut self, block_idx: usize) -> Result<Arc<Block>> {
+        let max_bytes = match self.prefetch_memory.grow(MAX_COALESCE_READ_SIZE as usize) {
+            Ok(()) => MAX_COALESCE_READ_SIZE,
+            Err(_) => 0,
+        };
+        let blocks = self
+            .table
+            .read_blocks_with_options(block_idx, max_bytes, &self.options);
+        self.prefetch_memory.shrink(self.prefetch_memory.bytes());
+        let mut blocks = blocks?.into_iter();
+        let block = blocks.next().unwrap();
+        for block in blocks {
+            if self.prefetch_memory.grow(block.data.len()).is_err() {
+                break;
+            }
+            self.prefetched.push_back(block);
+        }
+        Ok(block)
+    }
+
+    fn pop_prefetched(&mut self) -> Option<Arc<Block>> {
+        let block = self.prefetched.pop_front()?;
+        self.prefetch_memory.shrink(block.data.len());
+        Some(block)
+    }
+
+    fn clear_prefetched(&mut self) {
+        self.prefetched.clear();
+        self.prefetch_memory.shrink(self.prefetch_memory.bytes());
+    }
 }
 
 impl StorageIterator for SsTableIterator {
@@ -96,66 +233,203 @@ impl StorageIterator for SsTableIterator {
     fn next(&mut self) -> Result<()> {
         self.block_iter.next();
         if !self.block_iter.is_valid() {
-            self.block_idx += 1;
-            if self.block_idx < self.table.num_of_blocks() {
-                self.block_iter =
-                    BlockIterator::create_and_seek_to_first(self.table.read_block(self.block_idx)?);
-            }
+            self.next_block()?;
         }
         Ok(())
     }
 }
 
+/// 一次范围查询从 VSST 读取 KV 分离的 value 的统计，见 [`crate::DbIterator::vsst_deref_stats`]
+#[derive(Debug, Clone, Copy, Default, Eq, PartialEq)]
+pub struct VSstDerefStats {
+    /// 读取的块都在块缓存中的次数
+    pub cache_hits: u64,
+    /// 需要从文件读取块的次数
+    pub disk_reads: u64,
+    /// 读取 value 的总耗时
+    pub latency: Duration,
+}
+
+impl VSstDerefStats {
+    /// 读取 KV 分离的 value 的总次数
+    pub fn dereferences(&self) -> u64 {
+        self.cache_hits + self.disk_reads
+    }
+}
+
+/// 同一次范围查询中各个 SST 的迭代器共享的计数
+#[derive(Debug, Default)]
+pub(crate) struct VSstDerefCounters {
+    cache_hits: AtomicU64,
+    disk_reads: AtomicU64,
+    latency_nanos: AtomicU64,
+}
+
+impl VSstDerefCounters {
+    fn record(&self, source: BlockSource, latency: Duration) {
+        match source {
+            BlockSource::Cache => self.cache_hits.fetch_add(1, Ordering::Relaxed),
+            BlockSource::Disk => self.disk_reads.fetch_add(1, Ordering::Relaxed),
+        };
+        self.latency_nanos
+            .fetch_add(latency.as_nanos() as u64, Ordering::Relaxed);
+    }
+
+    pub(crate) fn stats(&self) -> VSstDerefStats {
+        VSstDerefStats {
+            cache_hits: self.cache_hits.load(Ordering::Relaxed),
+            disk_reads: self.disk_reads.load(Ordering::Relaxed),
+            latency: Duration::from_nanos(self.latency_nanos.load(Ordering::Relaxed)),
+        }
+    }
+}
+
+/// 打开时文件已不存在、按 [`crate::MissingVSstPolicy`] 跳过的 VSST，所有快照共享
+///
+/// 引用它们的 value 读作不存在，读到的次数计入 [`crate::DbStats`] 中的 `dangling_value_reads`。
+/// 不在其中的 VSST 缺失时读取仍然返回错误
+#[derive(Debug, Default)]
+pub(crate) struct MissingVSsts {
+    ids: HashSet<u64>,
+    reads: AtomicU64,
+}
+
+impl MissingVSsts {
+    pub(crate) fn new(ids: impl IntoIterator<Item = u64>) -> Self {
+        Self {
+            ids: ids.into_iter().collect(),
+            reads: AtomicU64::new(0),
+        }
+    }
+
+    pub(crate) fn contains(&self, vsst_id: u64) -> bool {
+        self.ids.contains(&vsst_id)
+    }
+
+    pub(crate) fn is_empty(&self) -> bool {
+        self.ids.is_empty()
+    }
+
+    pub(crate) fn ids(&self) -> impl Iterator<Item = u64> + '_ {
+        self.ids.iter().copied()
+    }
+
+    /// 读到了 `vsst_id` 中的 value，`vsst_id` 是跳过的 VSST 时计数并返回 `true`，调用方把 value 当作不存在
+    pub(crate) fn on_read(&self, vsst_id: u64, key: &[u8]) -> bool {
+        if !self.contains(vsst_id) {
+            return false;
+        }
+        self.reads.fetch_add(1, Ordering::Relaxed);
+        warn!(
+            "value of key {:?} is in missing {}.VSST, read as not found",
+            bytes::Bytes::copy_from_slice(key),
+            vsst_id
+        );
+        true
+    }
+
+    pub(crate) fn reads(&self) -> u64 {
+        self.reads.load(Ordering::Relaxed)
+    }
+}
+
 #[derive(Debug)]
 pub struct VSsTableIterator {
     iter: SsTableIterator,
-    vssts: Arc<RwLock<HashMap<u32, Arc<SsTable>>>>,
-    value: Vec<u8>,
+    vssts: Arc<RwLock<HashMap<u64, Arc<SsTable>>>>,
+    missing: Arc<MissingVSsts>,
+    // 当前 entry 为 KV 分离时定位到 VSST 中对应 value 的块迭代器，value 直接引用 VSST 的块，不复制
+    separated: Option<BlockIterator>,
+    // 当前 entry 引用的 VSST 已缺失，value 读作空，即不存在
+    dangling: bool,
+    deref: Arc<VSstDerefCounters>,
 }
 
 impl VSsTableIterator {
     fn update_kv(&mut self) -> Result<()> {
-        let entry = self.iter.block_iter.entry();
-        if entry.value_separate() {
-            let vsst_id = (&entry.value[..]).get_u32_le();
+        // seek 的 key 超过表中最大 key 时迭代器已失效，没有值可读
+        self.dangling = false;
+        if !self.iter.is_valid() {
+            self.separated = None;
+            return Ok(());
+        }
+        let block_iter = &self.iter.block_iter;
+        if Entry::is_separate(block_iter.meta()) {
+            let vsst_id = Entry::separated_vsst_id(block_iter.value());
             let vsst = match self.vssts.read().get(&vsst_id) {
+                None if self.missing.on_read(vsst_id, block_iter.key()) => {
+                    self.separated = None;
+                    self.dangling = true;
+                    return Ok(());
+                }
                 None => return Err(anyhow!("{} do not exist", vsst_id)),
                 Some(_vsst) => _vsst.clone(),
             };
-            let mut _iter = SsTableIterator::create_and_seek_to_key(vsst, &entry.key[..])?;
-            self.value = Vec::from(_iter.value());
+            let span = span!(
+                tracing::Level::TRACE,
+                "vsst deref",
+                vsst_id,
+                source = field::Empty
+            )
+            .entered();
+            let started_at = Instant::now();
+            let (_iter, source) = SsTableIterator::create_and_seek_to_key_with_source(
+                vsst,
+                block_iter.key(),
+                self.iter.options,
+            )?;
+            span.record("source", source.as_str());
+            self.deref.record(source, started_at.elapsed());
+            self.separated = Some(_iter.block_iter);
         } else {
-            self.value = Vec::from(&entry.value[..]);
+            self.separated = None;
         }
         Ok(())
     }
 
     /// Create a new iterator and seek to the first key-value pair.
-    #[instrument]
-    pub fn create_and_seek_to_first(
+    ///
+    /// 读取 SST 和 VSST 的块时都使用 `options`，读取 VSST 的次数、块的来源和耗时记录在 `deref` 中，
+    /// 引用 `missing` 中的 VSST 的 value 读作空
+    #[instrument(skip(deref))]
+    pub(crate) fn create_and_seek_to_first(
         table: Arc<SsTable>,
-        vssts: Arc<RwLock<HashMap<u32, Arc<SsTable>>>>,
+        vssts: Arc<RwLock<HashMap<u64, Arc<SsTable>>>>,
+        missing: Arc<MissingVSsts>,
+        options: BlockReadOptions,
+        deref: Arc<VSstDerefCounters>,
     ) -> Result<Self> {
         let mut _self = Self {
-            iter: SsTableIterator::create_and_seek_to_first(table)?,
+            iter: SsTableIterator::create_and_seek_to_first_with_options(table, options)?,
             vssts,
-            value: vec![],
+            missing,
+            separated: None,
+            dangling: false,
+            deref,
         };
         _self.update_kv()?;
         Ok(_self)
     }
 
     /// Create a new iterator and seek to the first key-value pair which >= `key`.
-    #[instrument(skip(key))]
-    pub fn create_and_seek_to_key(
+    ///
+    /// 同 [`VSsTableIterator::create_and_seek_to_first`]
+    #[instrument(skip(key, deref))]
+    pub(crate) fn create_and_seek_to_key(
         table: Arc<SsTable>,
         key: &[u8],
-        vssts: Arc<RwLock<HashMap<u32, Arc<SsTable>>>>,
+        vssts: Arc<RwLock<HashMap<u64, Arc<SsTable>>>>,
+        missing: Arc<MissingVSsts>,
+        options: BlockReadOptions,
+        deref: Arc<VSstDerefCounters>,
     ) -> Result<Self> {
         let mut _self = Self {
-            iter: SsTableIterator::create_and_seek_to_key(table, key)?,
+            iter: SsTableIterator::create_and_seek_to_key_with_options(table, key, options)?,
             vssts,
-            value: vec![],
+            missing,
+            separated: None,
+            dangling: false,
+            deref,
         };
         _self.update_kv()?;
         Ok(_self)
@@ -178,7 +452,11 @@ impl StorageIterator for VSsTableIterator {
     }
 
     fn value(&self) -> &[u8] {
-        &self.value
+        match &self.separated {
+            Some(block_iter) => block_iter.value(),
+            None if self.iter.is_valid() && !self.dangling => self.iter.value(),
+            None => &[],
+        }
     }
 
     fn is_valid(&self) -> bool {
@@ -188,9 +466,6 @@ impl StorageIterator for VSsTableIterator {
     #[instrument]
     fn next(&mut self) -> Result<()> {
         self.iter.next()?;
-        if self.iter.is_valid() {
-            self.update_kv()?;
-        }
-        Ok(())
+        self.update_kv()
     }
 }
@@ -1,6 +1,7 @@
 pub mod builder;
+pub mod compression;
 pub mod iterator;
-mod meta;
+pub(crate) mod meta;
 
 #[cfg(test)]
 mod tests;
@@ -1,13 +1,23 @@
 use std::path::{Path, PathBuf};
-use std::sync::Arc;
+use std::sync::{Arc, Barrier};
+use std::thread;
 
 use crate::block::tests::rand_gen_entries;
 
-use crate::entry::Entry;
+use crate::cache::{BlockCache, BlockLoad, CachedBlock};
+use crate::checksum::ChecksumType;
+use crate::entry::{Entry, EntryBuilder};
 use crate::iterator::StorageIterator;
-use crate::sstable::builder::{SsTable, SsTableBuilder};
+use crate::sstable::builder::{
+    checksum_failures, BlockReadOptions, CorruptionError, FilterLoading, KeyOrderCheck,
+    KeyOrderError, SsTable, SsTableBuilder,
+};
+use crate::sstable::compression::CompressionType;
 use crate::sstable::iterator::SsTableIterator;
 use crate::storage::file::FileStorage;
+use crate::storage::header::{FileFormatError, FileType, FILE_HEADER_SIZE};
+use crate::{OpType, BLOCK_CACHE_SIZE};
+use bytes::Bytes;
 
 fn rand_gen_sst(path: impl AsRef<Path>) -> (SsTable, PathBuf, Vec<Entry>) {
     let mut builder = SsTableBuilder::new();
@@ -48,3 +58,705 @@ fn test_open_iter() {
         iter.next().unwrap();
     });
 }
+
+#[test]
+fn test_zstd_dictionary() {
+    let tmpdir = tempfile::tempdir().unwrap();
+    let path = tmpdir.path().join("1.db");
+    let entries: Vec<Entry> = (0..2000)
+        .map(|i| {
+            EntryBuilder::new()
+                .op_type(OpType::Put)
+                .key_value(
+                    Bytes::from(format!("user:{:08}", i)),
+                    Bytes::from(format!(
+                        "{{\"id\":{},\"name\":\"user-{}\",\"status\":\"active\",\"tags\":[\"a\",\"b\"]}}",
+                        i, i
+                    )),
+                )
+                .build()
+        })
+        .collect();
+
+    let mut builder = SsTableBuilder::with_compression(CompressionType::Zstd, 4096);
+    entries.iter().for_each(|e| builder.add(e));
+    let sst = builder.build(1, None, path.clone()).unwrap();
+    assert_eq!(sst.compression(), CompressionType::Zstd);
+    assert!(!sst.dictionary().is_empty());
+    drop(sst);
+
+    let sst = Arc::new(SsTable::open(1, None, FileStorage::open(path).unwrap()).unwrap());
+    assert_eq!(sst.compression(), CompressionType::Zstd);
+    assert!(!sst.dictionary().is_empty());
+    let mut iter = SsTableIterator::create_and_seek_to_first(sst).unwrap();
+    entries.iter().for_each(|e| {
+        assert_eq!(&e.key[..], iter.key());
+        assert_eq!(&e.value[..], iter.value());
+        iter.next().unwrap();
+    });
+    assert!(!iter.is_valid());
+}
+
+#[test]
+fn test_read_blocks_coalesce() {
+    let tmpdir = tempfile::tempdir().unwrap();
+    let path = tmpdir.path().join("1.db");
+    let mut builder = SsTableBuilder::new();
+    let entries = rand_gen_entries(1000);
+    entries.iter().for_each(|e| builder.add(e));
+    builder.build(1, None, path.clone()).unwrap();
+
+    let cache = Arc::new(BlockCache::new(BLOCK_CACHE_SIZE));
+    let sst =
+        Arc::new(SsTable::open(1, Some(cache.clone()), FileStorage::open(path).unwrap()).unwrap());
+    assert!(sst.num_of_blocks() > 4);
+
+    // 至少读取一个块
+    let blocks = sst.read_blocks(0, 0).unwrap();
+    assert_eq!(blocks.len(), 1);
+    // 已缓存的块不会被再次合并读取
+    let blocks = sst.read_blocks(1, u64::MAX).unwrap();
+    assert_eq!(blocks.len(), sst.num_of_blocks() - 1);
+    for (i, block) in blocks.iter().enumerate() {
+        assert!(cache.contains_key(&(1, i + 1)));
+        assert_eq!(block, &sst.read_block(i + 1).unwrap());
+    }
+    assert_eq!(sst.read_blocks(1, u64::MAX).unwrap().len(), 1);
+
+    let mut iter = SsTableIterator::create_and_seek_to_first(sst).unwrap();
+    entries.iter().for_each(|e| {
+        assert_eq!(&e.key[..], iter.key());
+        assert_eq!(&e.value[..], iter.value());
+        iter.next().unwrap();
+    });
+    assert!(!iter.is_valid());
+}
+
+#[test]
+fn test_concurrent_scan_single_flight() {
+    let tmpdir = tempfile::tempdir().unwrap();
+    let path = tmpdir.path().join("1.db");
+    let mut builder = SsTableBuilder::new();
+    let entries = rand_gen_entries(1000);
+    entries.iter().for_each(|e| builder.add(e));
+    builder.build(1, None, path.clone()).unwrap();
+
+    let cache = Arc::new(BlockCache::new(BLOCK_CACHE_SIZE));
+    let sst =
+        Arc::new(SsTable::open(1, Some(cache.clone()), FileStorage::open(path).unwrap()).unwrap());
+    let barrier = Arc::new(Barrier::new(8));
+    let handles = (0..8)
+        .map(|i| {
+            let (sst, barrier) = (sst.clone(), barrier.clone());
+            thread::spawn(move || {
+                barrier.wait();
+                let mut count = 0;
+                if i % 2 == 0 {
+                    let mut iter = SsTableIterator::create_and_seek_to_first(sst).unwrap();
+                    while iter.is_valid() {
+                        count += 1;
+                        iter.next().unwrap();
+                    }
+                } else {
+                    for idx in 0..sst.num_of_blocks() {
+                        count += sst.read_block(idx).unwrap().entry_num as usize;
+                    }
+                }
+                count
+            })
+        })
+        .collect::<Vec<_>>();
+    for handle in handles {
+        assert_eq!(handle.join().unwrap(), entries.len());
+    }
+    // 每个块只从磁盘读取一次
+    assert_eq!(cache.loads(), sst.num_of_blocks() as u64);
+}
+
+#[test]
+fn test_block_cache_deduplicated_load() {
+    let tmpdir = tempfile::tempdir().unwrap();
+    let (sst, _, _) = rand_gen_sst(tmpdir.path());
+    let block = sst.read_block(0).unwrap();
+
+    let cache = BlockCache::new(BLOCK_CACHE_SIZE);
+    let BlockLoad::Leader(ticket) = cache.begin_load((1, 0)) else {
+        panic!("first load should lead");
+    };
+    let BlockLoad::Waiting(loading) = cache.begin_load((1, 0)) else {
+        panic!("second load should wait");
+    };
+    assert!(cache.try_begin_load((1, 0)).is_none());
+    assert_eq!(cache.deduplicated_loads(), 1);
+    ticket.finish(Ok(&block), Some(CachedBlock::Decoded(block.clone())));
+    assert_eq!(loading.wait().unwrap(), block);
+    assert!(matches!(cache.begin_load((1, 0)), BlockLoad::Cached(_)));
+
+    // 读取者放弃读取时等待者得到错误
+    let ticket = cache.try_begin_load((1, 1)).unwrap();
+    let BlockLoad::Waiting(loading) = cache.begin_load((1, 1)) else {
+        panic!("second load should wait");
+    };
+    drop(ticket);
+    assert!(loading.wait().is_err());
+    assert_eq!(cache.loads(), 2);
+}
+
+#[test]
+fn test_compressed_block_cache() {
+    let tmpdir = tempfile::tempdir().unwrap();
+    let path = tmpdir.path().join("1.db");
+    let mut builder = SsTableBuilder::with_compression(CompressionType::Zstd, 0);
+    let entries: Vec<_> = (0..1000)
+        .map(|i| {
+            EntryBuilder::new()
+                .op_type(OpType::Put)
+                .key_value(
+                    Bytes::from(format!("key_{:05}", i)),
+                    Bytes::from(format!("value_{:05}", i).repeat(10)),
+                )
+                .build()
+        })
+        .collect();
+    entries.iter().for_each(|e| builder.add(e));
+    builder.build(1, None, path.clone()).unwrap();
+
+    let decoded = Arc::new(BlockCache::new(BLOCK_CACHE_SIZE));
+    let compressed = Arc::new(BlockCache::new(BLOCK_CACHE_SIZE).with_compressed_blocks());
+    let scan = |cache: &Arc<BlockCache>| {
+        let file = FileStorage::open(&path).unwrap();
+        let sst = Arc::new(SsTable::open(1, Some(cache.clone()), file).unwrap());
+        assert!(sst.num_of_blocks() > 4);
+        for _ in 0..2 {
+            let mut iter = SsTableIterator::create_and_seek_to_first(sst.clone()).unwrap();
+            for e in &entries {
+                assert_eq!(iter.key(), &e.key[..]);
+                assert_eq!(iter.value(), &e.value[..]);
+                iter.next().unwrap();
+            }
+            assert!(!iter.is_valid());
+        }
+        // 第二次扫描的每个块都命中缓存
+        assert_eq!(
+            cache.hits() + cache.compressed_hits(),
+            sst.num_of_blocks() as u64
+        );
+        sst.num_of_blocks() as u64
+    };
+    let num_of_blocks = scan(&decoded);
+    assert_eq!(decoded.hits(), num_of_blocks);
+    assert_eq!(decoded.block_memory_by_kind().compressed, 0);
+    scan(&compressed);
+    assert_eq!(compressed.compressed_hits(), num_of_blocks);
+    assert!(compressed.get(&(1, 0)).is_none());
+    assert!(compressed.contains_key(&(1, 0)));
+    let memory = compressed.block_memory_by_kind();
+    assert_eq!(memory.decoded, 0);
+    assert_eq!(memory.compressed, compressed.block_memory());
+    // 重复的 value 压缩后远小于解码后的块
+    assert!(compressed.block_memory() * 4 < decoded.block_memory());
+}
+
+#[test]
+fn test_block_cache_weighted_by_memory() {
+    let tmpdir = tempfile::tempdir().unwrap();
+    let path = tmpdir.path().join("1.db");
+    let mut builder = SsTableBuilder::new();
+    for i in 0..1000 {
+        builder.add(
+            &EntryBuilder::new()
+                .op_type(OpType::Put)
+                .key_value(Bytes::from(format!("key_{:05}", i)), Bytes::from("value"))
+                .build(),
+        );
+    }
+    let sst = builder.build(1, None, path.clone()).unwrap();
+    let block_memory = sst.read_block(0).unwrap().memory();
+    let cache = Arc::new(BlockCache::new(block_memory * 3));
+    let sst = SsTable::open(1, Some(cache.clone()), FileStorage::open(path).unwrap()).unwrap();
+    assert!(sst.num_of_blocks() > 6);
+    // 合并读取的块各自复制，缓存按每个块的内存计算容量，不会超出
+    sst.read_blocks(0, u64::MAX).unwrap();
+    for idx in 0..sst.num_of_blocks() {
+        sst.read_block(idx).unwrap();
+    }
+    assert!(cache.block_memory() <= block_memory * 3);
+    assert!(cache.block_memory() > 0);
+}
+
+#[test]
+fn test_bloom_filter_user_key() {
+    let tmpdir = tempfile::tempdir().unwrap();
+    let path = tmpdir.path().join("1.db");
+    let mut builder = SsTableBuilder::new();
+    let mut keys = vec![];
+    for i in 0..300 {
+        let key = Bytes::from(format!("key{:04}", i));
+        let mut entry_builder = EntryBuilder::new();
+        match i % 3 {
+            0 => entry_builder
+                .op_type(OpType::Put)
+                .key_value(key.clone(), Bytes::from("value")),
+            // 删除标记也必须能被 filter 命中，否则无法屏蔽更旧层中的值
+            1 => entry_builder
+                .op_type(OpType::Delete)
+                .key_value(key.clone(), Bytes::new()),
+            // KV 分离的 entry 的 value 是 vsst id，filter 中仍是 user key
+            _ => entry_builder
+                .op_type(OpType::Put)
+                .kv_separate(true)
+                .key_value(key.clone(), Bytes::from(7u32.to_le_bytes().to_vec())),
+        };
+        builder.add(&entry_builder.build());
+        keys.push(key);
+    }
+    builder.build(1, None, path.clone()).unwrap();
+
+    let sst = SsTable::open(1, None, FileStorage::open(path).unwrap()).unwrap();
+    keys.iter()
+        .for_each(|key| assert!(sst.maybe_contains_key(key)));
+    let false_positive = (0..1000)
+        .filter(|i| sst.maybe_contains_key(&Bytes::from(format!("missing{:04}", i))))
+        .count();
+    assert!(false_positive < 50, "false positive: {}", false_positive);
+}
+
+#[test]
+fn test_filter_loading() {
+    let tmpdir = tempfile::tempdir().unwrap();
+    let (built, path, entries) = rand_gen_sst(tmpdir.path());
+    assert!(built.filter_memory() > 0);
+    let missing = (0..1000)
+        .map(|i| Bytes::from(format!("missing{:04}", i)))
+        .collect::<Vec<_>>();
+    let open = |filter_loading| {
+        let file = FileStorage::open(&path).unwrap();
+        SsTable::open_with_filter_loading(1, None, file, filter_loading).unwrap()
+    };
+
+    let sst = open(FilterLoading::Eager);
+    assert_eq!(sst.filter_memory(), built.filter_memory());
+
+    // 第一次点查时才加载
+    let sst = open(FilterLoading::Lazy);
+    assert_eq!(sst.filter_memory(), 0);
+    entries
+        .iter()
+        .for_each(|e| assert!(sst.maybe_contains_key(&e.key)));
+    assert_eq!(sst.filter_memory(), built.filter_memory());
+    assert!(missing.iter().any(|key| !sst.maybe_contains_key(key)));
+
+    // 不加载 filter 时任何 key 都可能存在
+    let sst = open(FilterLoading::Disabled);
+    assert!(missing.iter().all(|key| sst.maybe_contains_key(key)));
+    assert_eq!(sst.filter_memory(), 0);
+    let built = built.with_filter_loading(FilterLoading::Disabled);
+    assert_eq!(built.filter_memory(), 0);
+    assert!(missing.iter().all(|key| built.maybe_contains_key(key)));
+}
+
+#[test]
+fn test_index_cache() {
+    let tmpdir = tempfile::tempdir().unwrap();
+    let path = tmpdir.path().join("1.db");
+    let entries = (0..20)
+        .map(|i| {
+            EntryBuilder::new()
+                .op_type(OpType::Put)
+                .key_value(Bytes::from(format!("key{:03}", i)), Bytes::from("v"))
+                .build()
+        })
+        .collect::<Vec<_>>();
+    let mut builder = SsTableBuilder::new().with_block_size(64);
+    entries.iter().for_each(|e| builder.add(e));
+    let built = builder.build(1, None, &path).unwrap();
+    assert!(built.num_of_blocks() > 1);
+    assert!(built.index_memory() > 0);
+    let open = |cache: &Arc<BlockCache>| {
+        let file = FileStorage::open(&path).unwrap();
+        Arc::new(SsTable::open(1, Some(cache.clone()), file).unwrap())
+    };
+    let check = |sst: &Arc<SsTable>| {
+        for e in &entries {
+            let iter = SsTableIterator::create_and_seek_to_key(sst.clone(), &e.key).unwrap();
+            assert_eq!(iter.key(), &e.key[..]);
+        }
+    };
+
+    // 打开时读出的 meta 直接放入缓存，之后的读取不再读盘
+    let cache = Arc::new(BlockCache::new(BLOCK_CACHE_SIZE).with_index_capacity(1 << 20));
+    let sst = open(&cache);
+    assert_eq!(sst.index_memory(), 0);
+    assert_eq!(cache.index_memory(), built.index_memory());
+    assert_eq!(sst.num_of_blocks(), built.num_of_blocks());
+    assert_eq!(sst.key_range(), built.key_range());
+    check(&sst);
+    assert_eq!(cache.index_loads(), 0);
+
+    // 容量放不下时 meta 被淘汰，每次需要时从文件中重新读取
+    let cache = Arc::new(BlockCache::new(BLOCK_CACHE_SIZE).with_index_capacity(0));
+    let sst = open(&cache);
+    check(&sst);
+    assert_eq!(cache.index_memory(), 0);
+    assert!(cache.index_loads() > 0);
+}
+
+#[test]
+fn test_read_corrupted_block() {
+    let tmpdir = tempfile::tempdir().unwrap();
+    let (_, path, _) = rand_gen_sst(tmpdir.path());
+
+    // 破坏第一个块中第一个 entry 的 key 长度
+    let mut data = std::fs::read(&path).unwrap();
+    data[4..12].copy_from_slice(&u64::MAX.to_le_bytes());
+    std::fs::write(&path, data).unwrap();
+
+    let cache = Arc::new(BlockCache::new(BLOCK_CACHE_SIZE));
+    let sst = SsTable::open(1, Some(cache.clone()), FileStorage::open(&path).unwrap()).unwrap();
+    let err = sst.read_block(0).unwrap_err();
+    let corruption = err.downcast_ref::<CorruptionError>().unwrap();
+    assert_eq!(corruption.sst_id, 1);
+    assert_eq!(corruption.block_idx, 0);
+    assert!(!cache.contains_key(&(1, 0)));
+    assert!(sst.read_blocks(0, u64::MAX).is_err());
+    // 其余块不受影响
+    assert!(sst.read_block(1).is_ok());
+
+    let sst = SsTable::open(1, None, FileStorage::open(&path).unwrap()).unwrap();
+    let err = sst.read_block(0).unwrap_err();
+    assert!(err.downcast_ref::<CorruptionError>().is_some());
+}
+
+#[test]
+fn test_read_block_options() {
+    let tmpdir = tempfile::tempdir().unwrap();
+    let (_, path, _) = rand_gen_sst(tmpdir.path());
+
+    // 修改第一个块中第一个 key 的内容，块仍能解码但校验和不一致
+    let mut data = std::fs::read(&path).unwrap();
+    data[12] ^= 1;
+    std::fs::write(&path, data).unwrap();
+
+    let cache = Arc::new(BlockCache::new(BLOCK_CACHE_SIZE));
+    let sst = SsTable::open(1, Some(cache.clone()), FileStorage::open(&path).unwrap()).unwrap();
+    let no_fill = BlockReadOptions {
+        fill_cache: false,
+        verify_checksum: false,
+        checksum_sampling: None,
+    };
+    sst.read_block_with_options(1, &no_fill).unwrap();
+    assert_eq!(
+        sst.read_blocks_with_options(1, u64::MAX, &no_fill)
+            .unwrap()
+            .len(),
+        sst.num_of_blocks() - 1
+    );
+    assert!(!cache.contains_key(&(1, 1)));
+
+    let verify = BlockReadOptions {
+        fill_cache: true,
+        verify_checksum: true,
+        checksum_sampling: None,
+    };
+    assert!(sst.read_block_with_options(1, &verify).is_ok());
+    let err = sst.read_block_with_options(0, &verify).unwrap_err();
+    let corruption = err.downcast_ref::<CorruptionError>().unwrap();
+    assert_eq!(corruption.block_idx, 0);
+    assert!(!cache.contains_key(&(1, 0)));
+    assert!(sst.read_blocks_with_options(0, u64::MAX, &verify).is_err());
+
+    // 抽样校验：不放入缓存的块几乎不会被抽中，放入缓存的块总是校验
+    let rarely = BlockReadOptions {
+        fill_cache: false,
+        verify_checksum: false,
+        checksum_sampling: Some(u32::MAX),
+    };
+    assert!(sst.read_block_with_options(0, &rarely).is_ok());
+    let always = BlockReadOptions {
+        checksum_sampling: Some(1),
+        ..rarely
+    };
+    let failures = checksum_failures();
+    assert!(sst.read_block_with_options(0, &always).is_err());
+    let fill = BlockReadOptions {
+        fill_cache: true,
+        ..rarely
+    };
+    assert!(sst.read_block_with_options(0, &fill).is_err());
+    assert!(sst.read_blocks_with_options(0, u64::MAX, &fill).is_err());
+    assert!(checksum_failures() >= failures + 3);
+    assert!(!cache.contains_key(&(1, 0)));
+
+    // 不校验时可以读出，之后的读取直接使用缓存
+    sst.read_block(0).unwrap();
+    assert!(sst.read_block_with_options(0, &verify).is_ok());
+}
+
+#[test]
+fn test_block_size() {
+    let tmpdir = tempfile::tempdir().unwrap();
+    let entry = |i: usize| {
+        EntryBuilder::new()
+            .op_type(OpType::Put)
+            .key_value(
+                Bytes::from(format!("key{:03}", i)),
+                Bytes::from(vec![b'v'; 1000 + i]),
+            )
+            .build()
+    };
+
+    let mut builder = SsTableBuilder::new();
+    (0..20).for_each(|i| builder.add(&entry(i)));
+    let sst = builder.build(1, None, tmpdir.path().join("1.db")).unwrap();
+    assert!(sst.num_of_blocks() < 20);
+
+    // 块大小小于 entry 时每个块只有一个 entry
+    let mut builder = SsTableBuilder::new().with_block_size(256);
+    (0..20).for_each(|i| builder.add(&entry(i)));
+    let sst = Arc::new(builder.build(2, None, tmpdir.path().join("2.db")).unwrap());
+    assert_eq!(sst.num_of_blocks(), 20);
+    for i in 0..20 {
+        let e = entry(i);
+        assert_eq!(sst.find_block_idx(&e.key).unwrap(), i);
+        let iter = SsTableIterator::create_and_seek_to_key(sst.clone(), &e.key).unwrap();
+        assert_eq!(iter.value(), &e.value[..]);
+    }
+}
+
+#[test]
+fn test_checksum_type() {
+    let tmpdir = tempfile::tempdir().unwrap();
+    let entries = rand_gen_entries(200);
+    for (id, checksum_type) in [
+        ChecksumType::Crc32,
+        ChecksumType::Crc32c,
+        ChecksumType::Xxh3,
+    ]
+    .into_iter()
+    .enumerate()
+    {
+        let path = tmpdir.path().join(format!("{}.db", id));
+        let mut builder = SsTableBuilder::with_options(CompressionType::None, 0, checksum_type);
+        entries.iter().for_each(|e| builder.add(e));
+        builder.build(id as u64, None, path.clone()).unwrap();
+
+        let sst = SsTable::open(id as u64, None, FileStorage::open(path).unwrap()).unwrap();
+        assert_eq!(sst.checksum_type(), checksum_type);
+        for idx in 0..sst.num_of_blocks() {
+            let block = sst.read_block(idx).unwrap();
+            assert!(block.verify_checksum(checksum_type));
+        }
+    }
+}
+
+#[test]
+fn test_sst_file_type() {
+    let tmpdir = tempfile::tempdir().unwrap();
+    let entries = rand_gen_entries(10);
+    let mut builder = SsTableBuilder::new().with_file_type(FileType::VSst);
+    entries.iter().for_each(|e| builder.add(e));
+    let path = tmpdir.path().join("1.VSST");
+    let vsst = builder.build(1, None, &path).unwrap();
+    assert_eq!(vsst.file_type(), Some(FileType::VSst));
+    let deletes = entries
+        .iter()
+        .filter(|e| e.op_type() == OpType::Delete)
+        .count();
+    assert_eq!(vsst.num_of_deletes(), Some(deletes));
+
+    let vsst = SsTable::open(1, None, FileStorage::open(&path).unwrap()).unwrap();
+    assert_eq!(vsst.file_type(), Some(FileType::VSst));
+    assert!(vsst.check_file_type(FileType::VSst).is_ok());
+    assert_eq!(vsst.num_of_deletes(), Some(deletes));
+    assert_eq!(
+        vsst.check_file_type(FileType::Sst).unwrap_err(),
+        FileFormatError::WrongType {
+            path: path.clone(),
+            expected: FileType::Sst,
+            actual: FileType::VSst,
+        }
+    );
+
+    // 没有文件头的旧文件仍可打开，类型未知
+    let legacy = SsTable::open(1, None, FileStorage::open(baseline_sst()).unwrap()).unwrap();
+    assert_eq!(legacy.file_type(), None);
+    assert_eq!(legacy.num_of_deletes(), None);
+    assert!(legacy.check_file_type(FileType::Sst).is_ok());
+
+    // 更新的格式版本
+    let mut newer = std::fs::read(&path).unwrap();
+    let version_idx = newer.len() - FILE_HEADER_SIZE + 5;
+    newer[version_idx] += 1;
+    std::fs::write(&path, &newer).unwrap();
+    let err = SsTable::open(1, None, FileStorage::open(&path).unwrap()).unwrap_err();
+    assert!(matches!(
+        err.downcast_ref::<FileFormatError>(),
+        Some(FileFormatError::UnsupportedVersion { .. })
+    ));
+}
+
+/// 加入文件头之前的格式写出的 SST：key000 到 key199，下标个位为 9 的是删除标记，其余的值为 value{下标}
+fn baseline_sst() -> PathBuf {
+    Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/fixtures/baseline/00001.SST")
+}
+
+#[test]
+fn test_open_baseline_sst() {
+    let sst = SsTable::open(1, None, FileStorage::open(baseline_sst()).unwrap()).unwrap();
+    assert_eq!(sst.checksum_type(), ChecksumType::Crc32);
+    assert_eq!(sst.compression(), CompressionType::None);
+    assert!(sst.dictionary().is_empty());
+    assert_eq!(sst.max_seq(), None);
+    assert_eq!(sst.num_of_pairs(), 200);
+    assert!(sst.maybe_contains_key(&Bytes::from("key123")));
+
+    let mut iter = SsTableIterator::create_and_seek_to_first(Arc::new(sst)).unwrap();
+    for i in 0..200 {
+        assert!(iter.is_valid());
+        assert_eq!(iter.key(), format!("key{:03}", i).as_bytes());
+        if i % 10 == 9 {
+            assert_eq!(Entry::op_type_of(iter.meta()), OpType::Delete);
+        } else {
+            assert_eq!(iter.value(), format!("value{:03}", i).as_bytes());
+        }
+        iter.next().unwrap();
+    }
+    assert!(!iter.is_valid());
+}
+
+#[test]
+fn test_sst_max_seq() {
+    let tmpdir = tempfile::tempdir().unwrap();
+    let entries = rand_gen_entries(10);
+    let mut builder = SsTableBuilder::new().with_max_seq(42);
+    entries.iter().for_each(|e| builder.add(e));
+    let path = tmpdir.path().join("1.SST");
+    let sst = builder.build(1, None, &path).unwrap();
+    assert_eq!(sst.max_seq(), Some(42));
+    let deletes = sst.num_of_deletes();
+
+    let sst = SsTable::open(1, None, FileStorage::open(&path).unwrap()).unwrap();
+    assert_eq!(sst.max_seq(), Some(42));
+    assert_eq!(sst.num_of_deletes(), deletes);
+
+    // 格式版本 2 的文件没有记录最大 seq num
+    let data = std::fs::read(&path).unwrap();
+    let footer_begin = data.len() - FILE_HEADER_SIZE - 28;
+    let mut v2 = data[..footer_begin - 16].to_vec();
+    v2.extend(&data[footer_begin - 4..]);
+    let version_idx = v2.len() - FILE_HEADER_SIZE + 5;
+    v2[version_idx] = 2;
+    std::fs::write(&path, &v2).unwrap();
+    let v2 = SsTable::open(1, None, FileStorage::open(&path).unwrap()).unwrap();
+    assert_eq!(v2.max_seq(), None);
+    assert_eq!(v2.num_of_deletes(), deletes);
+    assert_eq!(v2.read_block(0).unwrap(), sst.read_block(0).unwrap());
+}
+
+#[test]
+fn test_sst_footer_checksum() {
+    let tmpdir = tempfile::tempdir().unwrap();
+    let entries = rand_gen_entries(10);
+    let mut builder = SsTableBuilder::new();
+    entries.iter().for_each(|e| builder.add(e));
+    let path = tmpdir.path().join("1.SST");
+    let pairs = builder.build(1, None, &path).unwrap().num_of_pairs();
+    let sst = SsTable::open(1, None, FileStorage::open(&path).unwrap()).unwrap();
+    assert_eq!(sst.verified_num_of_pairs().unwrap(), pairs);
+    assert!(!sst.needs_metadata_rebuild().unwrap());
+
+    // footer 中的 KV 数量被改写后校验失败
+    let data = std::fs::read(&path).unwrap();
+    let pair_num_idx = data.len() - FILE_HEADER_SIZE - 4;
+    let mut corrupted = data.clone();
+    corrupted[pair_num_idx] ^= 1;
+    std::fs::write(&path, &corrupted).unwrap();
+    let err = SsTable::open(1, None, FileStorage::open(&path).unwrap()).unwrap_err();
+    assert!(err.to_string().contains("footer checksum mismatch"));
+
+    // 格式版本 3 的 footer 没有校验和，记录的数量不可信时数出实际的数量
+    let footer_begin = data.len() - FILE_HEADER_SIZE - 28;
+    let mut v3 = data[..footer_begin - 16].to_vec();
+    v3.extend(&data[footer_begin - 12..]);
+    let pair_num_idx = v3.len() - FILE_HEADER_SIZE - 4;
+    v3[pair_num_idx] = pairs as u8 + 1;
+    let version_idx = v3.len() - FILE_HEADER_SIZE + 5;
+    v3[version_idx] = 3;
+    std::fs::write(&path, &v3).unwrap();
+    let v3 = SsTable::open(1, None, FileStorage::open(&path).unwrap()).unwrap();
+    assert_eq!(v3.num_of_pairs(), pairs + 1);
+    assert_eq!(v3.verified_num_of_pairs().unwrap(), pairs);
+    assert!(v3.needs_metadata_rebuild().unwrap());
+}
+
+#[test]
+fn test_filter_bits_per_key() {
+    let tmpdir = tempfile::tempdir().unwrap();
+    let entries = (0..1000)
+        .map(|i| {
+            EntryBuilder::new()
+                .op_type(OpType::Put)
+                .key_value(Bytes::from(format!("key{:04}", i)), Bytes::from("value"))
+                .build()
+        })
+        .collect::<Vec<_>>();
+    let build = |id, bits_per_key| {
+        let mut builder = SsTableBuilder::new().with_filter_bits_per_key(bits_per_key);
+        entries.iter().for_each(|e| builder.add(e));
+        builder
+            .build(id, None, tmpdir.path().join(format!("{}.db", id)))
+            .unwrap()
+    };
+
+    let default = build(1, None);
+    let small = build(2, Some(4));
+    assert_eq!(small.filter_memory(), 1000 * 4 / 8);
+    assert!(small.filter_memory() < default.filter_memory());
+    entries
+        .iter()
+        .for_each(|e| assert!(small.maybe_contains_key(&e.key)));
+}
+
+#[test]
+fn test_key_order_check() {
+    let tmpdir = tempfile::tempdir().unwrap();
+    let put = |key: &'static str| {
+        EntryBuilder::new()
+            .op_type(OpType::Put)
+            .key_value(Bytes::from(key), Bytes::from("value"))
+            .build()
+    };
+    let build = |id, check, keys: &[&'static str]| {
+        let mut builder = SsTableBuilder::new()
+            .with_block_size(64)
+            .with_key_order_check(check);
+        keys.iter().for_each(|key| builder.add(&put(key)));
+        builder.build(id, None, tmpdir.path().join(format!("{}.db", id)))
+    };
+
+    // 跨块的乱序也能发现，报告第一个出错的 entry
+    let err = build(1, KeyOrderCheck::Always, &["a", "b", "c", "b", "a"]).unwrap_err();
+    let err = err.downcast::<KeyOrderError>().unwrap();
+    assert_eq!(
+        err,
+        KeyOrderError {
+            index: 3,
+            previous: Bytes::from("c"),
+            key: Bytes::from("b"),
+        }
+    );
+    assert!(err.to_string().starts_with("out-of-order key"));
+    let err = build(2, KeyOrderCheck::Always, &["a", "b", "b"]).unwrap_err();
+    assert!(err.to_string().starts_with("duplicate key"));
+    assert!(!tmpdir.path().join("2.db").exists());
+
+    // 关闭检查时照常写出
+    let sst = build(3, KeyOrderCheck::Disabled, &["a", "c", "b"]).unwrap();
+    assert_eq!(sst.num_of_pairs(), 3);
+    assert_eq!(
+        build(4, KeyOrderCheck::Always, &["a", "b", "c"])
+            .unwrap()
+            .num_of_pairs(),
+        3
+    );
+}
@@ -0,0 +1,258 @@
+use std::fmt::{Debug, Formatter};
+use std::path::{Path, PathBuf};
+
+use anyhow::anyhow;
+use bytes::{Buf, BufMut, Bytes, BytesMut};
+
+use crate::storage::file::FileStorage;
+
+/// 从暂存文件读回结果时每次读取的字节数
+const STAGING_READ_SIZE: usize = 64 * 1024;
+
+/// 物化范围查询结果时使用的暂存区
+///
+/// 结果先缓存在内存中，超过 `memory_limit` 后全部写入位于 `path` 的临时文件，之后的结果继续追加到文件。
+/// 调用 [`ResultStaging::finish`] 得到按写入顺序读回结果的 [`StagedResult`]，临时文件在两者 drop 时删除
+///
+/// 每条结果的格式为 key len(8 bytes) | key | value len(8 bytes) | value
+pub struct ResultStaging {
+    path: PathBuf,
+    memory_limit: usize,
+    buffer: BytesMut,
+    file: Option<FileStorage>,
+    spilled: u64,
+    len: usize,
+}
+
+impl ResultStaging {
+    pub fn new(path: impl AsRef<Path>, memory_limit: usize) -> Self {
+        Self {
+            path: PathBuf::from(path.as_ref()),
+            memory_limit,
+            buffer: BytesMut::new(),
+            file: None,
+            spilled: 0,
+            len: 0,
+        }
+    }
+
+    /// 追加一条结果，内存中的结果超过上限时写入临时文件
+    pub fn push(&mut self, key: &[u8], value: &[u8]) -> anyhow::Result<()> {
+        self.buffer.put_u64_le(key.len() as u64);
+        self.buffer.put_slice(key);
+        self.buffer.put_u64_le(value.len() as u64);
+        self.buffer.put_slice(value);
+        self.len += 1;
+        if self.buffer.len() > self.memory_limit {
+            self.spill()?;
+        }
+        Ok(())
+    }
+
+    fn spill(&mut self) -> anyhow::Result<()> {
+        if self.buffer.is_empty() {
+            return Ok(());
+        }
+        if self.file.is_none() {
+            self.file = Some(FileStorage::open(&self.path)?);
+        }
+        let file = self.file.as_ref().unwrap();
+        file.write(&self.buffer)?;
+        self.spilled += self.buffer.len() as u64;
+        self.buffer.clear();
+        Ok(())
+    }
+
+    /// 已暂存的结果数量
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// 是否已经写入了临时文件
+    pub fn is_spilled(&self) -> bool {
+        self.file.is_some()
+    }
+
+    /// 结束写入，已写入临时文件时把内存中剩余的结果也写入文件
+    pub fn finish(mut self) -> anyhow::Result<StagedResult> {
+        if self.file.is_some() {
+            self.spill()?;
+            self.file.as_ref().unwrap().sync()?;
+        }
+        Ok(StagedResult {
+            file: self.file.take(),
+            buffer: std::mem::take(&mut self.buffer).freeze(),
+            offset: 0,
+            size: self.spilled,
+            remaining: self.len,
+        })
+    }
+}
+
+impl Drop for ResultStaging {
+    fn drop(&mut self) {
+        if let Some(file) = self.file.take() {
+            let _ = file.delete();
+        }
+    }
+}
+
+impl Debug for ResultStaging {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("ResultStaging")
+            .field("path", &self.path)
+            .field("memory_limit", &self.memory_limit)
+            .field("buffered", &self.buffer.len())
+            .field("spilled", &self.spilled)
+            .field("len", &self.len)
+            .finish()
+    }
+}
+
+/// 暂存的查询结果，按写入顺序迭代 `(key, value)`
+pub struct StagedResult {
+    file: Option<FileStorage>,
+    // 未消费的结果，未写入文件时即全部结果
+    buffer: Bytes,
+    offset: u64,
+    size: u64,
+    remaining: usize,
+}
+
+impl StagedResult {
+    /// 剩余的结果数量
+    pub fn len(&self) -> usize {
+        self.remaining
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.remaining == 0
+    }
+
+    /// 保证 `buffer` 中至少有 `need` 个字节
+    fn fill(&mut self, need: usize) -> anyhow::Result<()> {
+        if self.buffer.len() >= need {
+            return Ok(());
+        }
+        let Some(file) = self.file.as_ref() else {
+            return Err(anyhow!("staged result is truncated"));
+        };
+        let read = (need - self.buffer.len())
+            .max(STAGING_READ_SIZE)
+            .min((self.size - self.offset) as usize);
+        if self.buffer.len() + read < need {
+            return Err(anyhow!("staged result is truncated"));
+        }
+        let data = file.read(self.offset, read as u64)?;
+        self.offset += read as u64;
+        let mut buffer = BytesMut::with_capacity(self.buffer.len() + data.len());
+        buffer.put_slice(&self.buffer);
+        buffer.put_slice(&data);
+        self.buffer = buffer.freeze();
+        Ok(())
+    }
+
+    fn read_bytes(&mut self) -> anyhow::Result<Bytes> {
+        self.fill(8)?;
+        let len = self.buffer.get_u64_le() as usize;
+        self.fill(len)?;
+        Ok(self.buffer.split_to(len))
+    }
+
+    fn next_item(&mut self) -> anyhow::Result<(Bytes, Bytes)> {
+        let key = self.read_bytes()?;
+        let value = self.read_bytes()?;
+        self.remaining -= 1;
+        Ok((key, value))
+    }
+}
+
+impl Iterator for StagedResult {
+    type Item = anyhow::Result<(Bytes, Bytes)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.remaining == 0 {
+            return None;
+        }
+        let item = self.next_item();
+        if item.is_err() {
+            self.remaining = 0;
+        }
+        Some(item)
+    }
+}
+
+impl Drop for StagedResult {
+    fn drop(&mut self) {
+        if let Some(file) = self.file.take() {
+            let _ = file.delete();
+        }
+    }
+}
+
+impl Debug for StagedResult {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("StagedResult")
+            .field("spilled", &self.file.is_some())
+            .field("remaining", &self.remaining)
+            .finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use bytes::Bytes;
+
+    use crate::staging::ResultStaging;
+
+    #[test]
+    fn test_result_staging() {
+        let tmpdir = tempfile::tempdir().unwrap();
+        let path = tmpdir.path().join("1.STAGE");
+        let kv = |i: usize| {
+            (
+                Bytes::from(format!("key{:05}", i)),
+                Bytes::from(format!("value{}", i).repeat(i % 7)),
+            )
+        };
+
+        // 不超过内存上限时不写文件
+        let mut staging = ResultStaging::new(&path, 1024 * 1024);
+        for i in 0..100 {
+            let (k, v) = kv(i);
+            staging.push(&k, &v).unwrap();
+        }
+        assert!(!staging.is_spilled());
+        let result = staging.finish().unwrap();
+        assert!(!path.exists());
+        let items: Vec<_> = result.map(|x| x.unwrap()).collect();
+        assert_eq!(items, (0..100).map(kv).collect::<Vec<_>>());
+
+        let mut staging = ResultStaging::new(&path, 1024);
+        for i in 0..10000 {
+            let (k, v) = kv(i);
+            staging.push(&k, &v).unwrap();
+        }
+        assert!(staging.is_spilled());
+        assert_eq!(staging.len(), 10000);
+        let mut result = staging.finish().unwrap();
+        assert!(path.exists());
+        for i in 0..10000 {
+            assert_eq!(result.next().unwrap().unwrap(), kv(i));
+        }
+        assert!(result.next().is_none());
+        drop(result);
+        assert!(!path.exists());
+
+        // 未 finish 的暂存区 drop 时删除临时文件
+        let mut staging = ResultStaging::new(&path, 0);
+        staging.push(b"k", b"v").unwrap();
+        assert!(path.exists());
+        drop(staging);
+        assert!(!path.exists());
+    }
+}
@@ -0,0 +1,856 @@
+use std::collections::{BTreeMap, VecDeque};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::time::{Duration, Instant, SystemTime};
+
+use bytes::{Buf, BufMut, Bytes};
+use parking_lot::Mutex;
+
+/// 后台任务队列（flush / compaction）的状态
+#[derive(Debug, Clone, Default, Eq, PartialEq)]
+pub struct QueueStats {
+    /// 已提交但尚未开始执行的请求数
+    pub pending: usize,
+    /// 是否有任务正在执行
+    pub running: bool,
+    /// 最早一个未开始执行的请求已等待的时间
+    pub oldest_pending_age: Option<Duration>,
+    /// 最近一次任务完成的时间
+    pub last_completed_at: Option<SystemTime>,
+}
+
+/// 后台刷写任务的 id，按请求顺序递增，从 1 开始
+pub type FlushJobId = u64;
+
+/// 后台刷写任务的状态，等待中的刷写请求会被合并为同一个任务
+#[derive(Debug, Clone, Copy, Default, Eq, PartialEq)]
+pub struct FlushJobStatus {
+    /// 已请求但尚未开始的任务
+    pub pending: Option<FlushJobId>,
+    /// 正在执行的任务
+    pub running: Option<FlushJobId>,
+    /// 最近一次完成的任务
+    pub last_completed: Option<FlushJobId>,
+}
+
+impl FlushJobStatus {
+    /// 任务 `id` 是否已经完成，任务按 id 顺序执行
+    pub fn is_done(&self, id: FlushJobId) -> bool {
+        self.last_completed.is_some_and(|last| last >= id)
+    }
+}
+
+/// 打开数据库时恢复各阶段的耗时和重放的 WAL 规模
+#[derive(Debug, Clone, Default, Eq, PartialEq)]
+pub struct RecoveryStats {
+    /// 读取并重放 MANIFEST
+    pub manifest_replay: Duration,
+    /// 打开 SST 和 VSST，包括加载 bloom filter
+    pub open_tables: Duration,
+    /// 重放冻结的和当前的 WAL 到 memtable
+    pub wal_replay: Duration,
+    /// 重放的 WAL 记录数，每次写入是一条记录
+    pub wal_records: u64,
+    /// 重放到 memtable 中的数据量
+    pub wal_bytes: u64,
+    /// WAL 段末尾跳过的零填充的字节数，不为 0 说明上次退出时有未落盘的追加
+    pub wal_padding_bytes: u64,
+    /// 打开的总耗时，不包括启动后台任务
+    pub total: Duration,
+    /// MANIFEST 中存在但文件已缺失的 VSST，见 [`crate::MissingVSstPolicy`]
+    pub missing_vssts: Vec<u64>,
+}
+
+impl RecoveryStats {
+    /// WAL 重放的速度（字节/秒）
+    pub fn wal_replay_throughput(&self) -> f64 {
+        if self.wal_replay.is_zero() {
+            return 0.0;
+        }
+        self.wal_bytes as f64 / self.wal_replay.as_secs_f64()
+    }
+}
+
+/// 数据库各部分估算的常驻内存（字节），见 [`crate::Db::approximate_memory_usage`]
+#[derive(Debug, Clone, Copy, Default, Eq, PartialEq)]
+pub struct MemoryUsage {
+    /// 正在写入的 memtable
+    pub active_memtable: u64,
+    /// 已冻结、等待刷写的 memtable
+    pub frozen_memtables: u64,
+    /// 已刷写但仍被迭代器或快照持有而没有释放的 memtable
+    pub pinned_memtables: u64,
+    /// SST 和 VSST 块缓存中的块
+    pub block_cache: u64,
+    /// 已加载的 bloom filter，同 [`DbStats::filter_memory`]
+    pub filters: u64,
+    /// SST 和 VSST 的 meta，同 [`DbStats::index_memory`]
+    pub indexes: u64,
+}
+
+impl MemoryUsage {
+    pub fn total(&self) -> u64 {
+        self.active_memtable
+            + self.frozen_memtables
+            + self.pinned_memtables
+            + self.block_cache
+            + self.filters
+            + self.indexes
+    }
+}
+
+/// 数据库运行状态
+#[derive(Debug, Clone, Default)]
+pub struct DbStats {
+    pub flush_queue: QueueStats,
+    pub flush_jobs: FlushJobStatus,
+    pub compaction_queue: QueueStats,
+    pub frozen_memtables: usize,
+    /// 每一层的 SST 数量
+    pub level_files: Vec<usize>,
+    /// 最后一层超出 `MAX_LEVEL_SIZE` 的字节数。最后一层无法再向下合并，超出后继续增长，
+    /// 不为 0 时 [`crate::Db::health`] 报告 `Degraded`
+    pub bottom_level_overflow: u64,
+    /// 已加载的 SST / VSST bloom filter 占用的内存（字节）
+    pub filter_memory: u64,
+    /// SST / VSST 的 meta 占用的内存（字节），包括常驻的 meta 和 meta 缓存，见 [`crate::DbOptions::index_cache_size`]
+    pub index_memory: u64,
+    /// meta 缓存未命中后从文件中读取 meta 的次数
+    pub index_loads: u64,
+    /// 块缓存未命中时因同一个块正被其他线程读取而没有重复读盘的次数
+    pub deduplicated_block_loads: u64,
+    /// 块缓存命中解码后的块的次数
+    pub block_cache_hits: u64,
+    /// 块缓存命中压缩的块、需要解压的次数，见 [`crate::DbOptions::cache_compressed_blocks`]
+    pub compressed_block_cache_hits: u64,
+    /// 块缓存中解码后的块占用的内存（字节）
+    pub block_cache_memory: u64,
+    /// 块缓存中压缩的块占用的内存（字节），未开启 `cache_compressed_blocks` 时为 0
+    pub compressed_block_cache_memory: u64,
+    /// 后台读因用户读正在进行而推迟的次数，进程内的所有数据库共享该计数，见 [`crate::IoPriority`]
+    pub delayed_background_reads: u64,
+    /// 合并等长循环为其他线程让出 CPU 的次数，进程内的所有数据库共享该计数，见 [`crate::DbOptions::yield_interval`]
+    pub cooperative_yields: u64,
+    /// 进程内正在进行的读操作物化的 value 和预读的块占用的内存（字节），所有数据库共享，见 [`crate::set_read_memory_limit`]
+    pub read_memory: usize,
+    /// 进程内因超出读内存上限而失败的读操作次数
+    pub read_memory_rejections: u64,
+    /// 打开以来加入其他写入的组、由组长一起提交的写入数，见 [`crate::DbOptions::micro_batch`]
+    pub grouped_writes: u64,
+    /// 打开以来读取命中不存在 key 缓存的次数，见 [`crate::DbOptions::negative_cache_capacity`]
+    pub negative_cache_hits: u64,
+    /// 等待后台删除的 SST / VSST 文件数
+    pub pending_deletes: usize,
+    /// 自数据库创建以来的累计计数，重启后延续
+    pub cumulative: CumulativeStats,
+    /// 打开以来写入被限速的次数和原因
+    pub write_stalls: WriteStallStats,
+    /// 进程内校验过校验和的块数，包括 `verify_checksums` 和抽样校验
+    pub verified_blocks: u64,
+    /// 进程内校验和不一致的块数
+    pub checksum_failures: u64,
+    /// 打开数据库时的恢复耗时
+    pub recovery: RecoveryStats,
+    /// 打开以来刷写和合并写入的 key 的公共前缀统计
+    pub key_prefixes: KeyPrefixStats,
+    /// 打开以来各 value 检查回调记录的指标，以回调的名字为 key，见 [`crate::Db::add_value_inspector`]
+    pub value_metrics: BTreeMap<String, BTreeMap<String, u64>>,
+    /// 打开以来读到引用缺失 VSST 的 value、按不存在返回的次数，见 [`crate::MissingVSstPolicy`]
+    pub dangling_value_reads: u64,
+    /// 存活的迭代器数量，见 [`crate::Db::active_iterators`]
+    pub active_iterators: usize,
+    /// 最早创建的存活迭代器已存活的时间
+    pub oldest_iterator_age: Option<Duration>,
+    pub lock_contention: LockContentionStats,
+}
+
+/// 一个存活的迭代器，见 [`crate::Db::active_iterators`]
+#[derive(Debug, Clone)]
+pub struct ActiveIterator {
+    /// 创建以来经过的时间
+    pub age: Duration,
+    /// 创建迭代器时的调用栈，只在开启 `iterator-backtrace` feature 时记录
+    pub backtrace: Option<String>,
+}
+
+/// 写入被限速的原因，一次限速可能同时有多个原因
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum StallCause {
+    /// 有冻结的 memtable 等待刷写
+    PendingMemtables,
+    /// L0 的 SST 数量超过 `l0_sst_num_limit`
+    L0Files,
+    /// L1 及以下各层超出大小限制的总字节数超过 [`crate::COMPACTION_DEBT_STALL_LIMIT`]
+    CompactionDebt,
+    /// 冻结的 memtable 或 WAL 超过 [`crate::DbOptions::max_frozen_memtables`] 或
+    /// [`crate::DbOptions::max_frozen_wals`]，所有写入都会等待
+    FrozenLimit,
+}
+
+/// 写入限速的统计，各原因的次数之和可能大于限速次数
+#[derive(Debug, Clone, Default, Eq, PartialEq)]
+pub struct WriteStallStats {
+    /// 限速次数
+    pub stalls: u64,
+    /// 原因包含 [`StallCause::PendingMemtables`] 的次数
+    pub pending_memtables: u64,
+    /// 原因包含 [`StallCause::L0Files`] 的次数
+    pub l0_files: u64,
+    /// 原因包含 [`StallCause::CompactionDebt`] 的次数
+    pub compaction_debt: u64,
+    /// 原因包含 [`StallCause::FrozenLimit`] 的次数
+    pub frozen_limit: u64,
+    /// 限速等待的总时间
+    pub delay: Duration,
+}
+
+#[derive(Debug, Default)]
+pub(crate) struct WriteStallCounters {
+    stalls: AtomicU64,
+    pending_memtables: AtomicU64,
+    l0_files: AtomicU64,
+    compaction_debt: AtomicU64,
+    frozen_limit: AtomicU64,
+    delay_nanos: AtomicU64,
+}
+
+impl WriteStallCounters {
+    pub(crate) fn on_stall(&self, causes: &[StallCause], delay: Duration) {
+        self.stalls.fetch_add(1, Ordering::Relaxed);
+        for cause in causes {
+            let counter = match cause {
+                StallCause::PendingMemtables => &self.pending_memtables,
+                StallCause::L0Files => &self.l0_files,
+                StallCause::CompactionDebt => &self.compaction_debt,
+                StallCause::FrozenLimit => &self.frozen_limit,
+            };
+            counter.fetch_add(1, Ordering::Relaxed);
+        }
+        self.delay_nanos
+            .fetch_add(delay.as_nanos() as u64, Ordering::Relaxed);
+    }
+
+    pub(crate) fn stats(&self) -> WriteStallStats {
+        WriteStallStats {
+            stalls: self.stalls.load(Ordering::Relaxed),
+            pending_memtables: self.pending_memtables.load(Ordering::Relaxed),
+            l0_files: self.l0_files.load(Ordering::Relaxed),
+            compaction_debt: self.compaction_debt.load(Ordering::Relaxed),
+            frozen_limit: self.frozen_limit.load(Ordering::Relaxed),
+            delay: Duration::from_nanos(self.delay_nanos.load(Ordering::Relaxed)),
+        }
+    }
+}
+
+/// 获取一个锁时需要等待的次数和总时间，没有等待的获取不计入
+#[derive(Debug, Clone, Copy, Default, Eq, PartialEq)]
+pub struct LockWaitStats {
+    /// 需要等待的次数
+    pub contended: u64,
+    /// 等待的总时间
+    pub wait: Duration,
+}
+
+/// 打开以来各个锁的等待，用于判断吞吐受限于锁竞争还是 IO
+#[derive(Debug, Clone, Copy, Default, Eq, PartialEq)]
+pub struct LockContentionStats {
+    /// 读取当前版本（memtable、冻结列表、各层 SST）时获取读锁，读写都要经过
+    pub inner_read: LockWaitStats,
+    /// 冻结 memtable、安装刷写和合并结果时获取写锁，持有期间阻塞所有读写
+    pub inner_write: LockWaitStats,
+    /// 读取 MANIFEST 时获取读锁
+    pub manifest_read: LockWaitStats,
+    /// 写入 MANIFEST 记录时获取写锁
+    pub manifest_write: LockWaitStats,
+    /// 写入、刷写和截断文件时获取文件的写锁，所有文件共享，包括同一进程中的其他数据库
+    pub file_write: LockWaitStats,
+}
+
+/// 自数据库创建以来的累计计数
+///
+/// 随刷写和合并的元数据变更一起写入 MANIFEST，关闭数据库时也会写入一次，重启后从最近一次记录的值继续累加，
+/// 崩溃时最后一次记录之后的计数丢失
+#[derive(Debug, Clone, Copy, Default, Eq, PartialEq)]
+pub struct CumulativeStats {
+    /// 用户写入的 key 和 value 的总字节数
+    pub bytes_written: u64,
+    /// 完成的刷写次数
+    pub flushes: u64,
+    /// 刷写输出的 SST 和 VSST 总字节数
+    pub flush_bytes_written: u64,
+    /// 完成的合并次数
+    pub compactions: u64,
+    /// 合并读入的 SST 总字节数
+    pub compaction_bytes_read: u64,
+    /// 合并输出的 SST 和 VSST 总字节数
+    pub compaction_bytes_written: u64,
+    /// 引用计数归零后被回收的 VSST 数量
+    pub gc_vssts: u64,
+    /// 被回收的 VSST 总字节数
+    pub gc_bytes: u64,
+}
+
+impl CumulativeStats {
+    /// 编码后的字节数，各项依次以 u64 小端序保存
+    pub(crate) const ENCODED_SIZE: usize = 8 * 8;
+
+    pub(crate) fn encode(&self, buf: &mut impl BufMut) {
+        for value in [
+            self.bytes_written,
+            self.flushes,
+            self.flush_bytes_written,
+            self.compactions,
+            self.compaction_bytes_read,
+            self.compaction_bytes_written,
+            self.gc_vssts,
+            self.gc_bytes,
+        ] {
+            buf.put_u64_le(value);
+        }
+    }
+
+    pub(crate) fn decode(buf: &mut impl Buf) -> Self {
+        CumulativeStats {
+            bytes_written: buf.get_u64_le(),
+            flushes: buf.get_u64_le(),
+            flush_bytes_written: buf.get_u64_le(),
+            compactions: buf.get_u64_le(),
+            compaction_bytes_read: buf.get_u64_le(),
+            compaction_bytes_written: buf.get_u64_le(),
+            gc_vssts: buf.get_u64_le(),
+            gc_bytes: buf.get_u64_le(),
+        }
+    }
+}
+
+/// 运行中累加的 [`CumulativeStats`]
+#[derive(Debug, Default)]
+pub(crate) struct CumulativeCounters {
+    bytes_written: AtomicU64,
+    flushes: AtomicU64,
+    flush_bytes_written: AtomicU64,
+    compactions: AtomicU64,
+    compaction_bytes_read: AtomicU64,
+    compaction_bytes_written: AtomicU64,
+    gc_vssts: AtomicU64,
+    gc_bytes: AtomicU64,
+}
+
+impl CumulativeCounters {
+    /// 从恢复出的计数继续累加
+    pub(crate) fn new(stats: CumulativeStats) -> Self {
+        CumulativeCounters {
+            bytes_written: AtomicU64::new(stats.bytes_written),
+            flushes: AtomicU64::new(stats.flushes),
+            flush_bytes_written: AtomicU64::new(stats.flush_bytes_written),
+            compactions: AtomicU64::new(stats.compactions),
+            compaction_bytes_read: AtomicU64::new(stats.compaction_bytes_read),
+            compaction_bytes_written: AtomicU64::new(stats.compaction_bytes_written),
+            gc_vssts: AtomicU64::new(stats.gc_vssts),
+            gc_bytes: AtomicU64::new(stats.gc_bytes),
+        }
+    }
+
+    pub(crate) fn on_write(&self, bytes: u64) {
+        self.bytes_written.fetch_add(bytes, Ordering::Relaxed);
+    }
+
+    pub(crate) fn on_flush(&self, bytes_written: u64) {
+        self.flushes.fetch_add(1, Ordering::Relaxed);
+        self.flush_bytes_written
+            .fetch_add(bytes_written, Ordering::Relaxed);
+    }
+
+    pub(crate) fn on_compaction(&self, bytes_read: u64, bytes_written: u64) {
+        self.compactions.fetch_add(1, Ordering::Relaxed);
+        self.compaction_bytes_read
+            .fetch_add(bytes_read, Ordering::Relaxed);
+        self.compaction_bytes_written
+            .fetch_add(bytes_written, Ordering::Relaxed);
+    }
+
+    pub(crate) fn on_gc(&self, bytes: u64) {
+        self.gc_vssts.fetch_add(1, Ordering::Relaxed);
+        self.gc_bytes.fetch_add(bytes, Ordering::Relaxed);
+    }
+
+    pub(crate) fn snapshot(&self) -> CumulativeStats {
+        CumulativeStats {
+            bytes_written: self.bytes_written.load(Ordering::Relaxed),
+            flushes: self.flushes.load(Ordering::Relaxed),
+            flush_bytes_written: self.flush_bytes_written.load(Ordering::Relaxed),
+            compactions: self.compactions.load(Ordering::Relaxed),
+            compaction_bytes_read: self.compaction_bytes_read.load(Ordering::Relaxed),
+            compaction_bytes_written: self.compaction_bytes_written.load(Ordering::Relaxed),
+            gc_vssts: self.gc_vssts.load(Ordering::Relaxed),
+            gc_bytes: self.gc_bytes.load(Ordering::Relaxed),
+        }
+    }
+}
+
+/// 一次合并的概要，与合并输出替换输入的变更写在同一条 MANIFEST 记录中
+///
+/// 输入和输出的文件 id 见同一条记录中的 `DelSst` / `NewSst`，用 [`crate::admin::compaction_history`] 读出
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub struct CompactionSummary {
+    /// 被合并的层
+    pub level: u32,
+    /// 合并结果写入的层
+    pub output_level: u32,
+    /// 输入的 SST 数量
+    pub input_files: u32,
+    /// 输出的 SST 和 VSST 数量
+    pub output_files: u32,
+    /// 输入的 SST 总字节数
+    pub input_bytes: u64,
+    /// 输出的 SST 和 VSST 总字节数
+    pub output_bytes: u64,
+    /// 输入的 SST 中的 entry 数
+    pub input_entries: u64,
+    /// 被更新的版本覆盖或随删除标记一起丢弃的 entry 数
+    pub dropped_entries: u64,
+    /// 从选择输入到写入 MANIFEST 的耗时，导入的外部合并不包括在外部执行的时间
+    pub duration: Duration,
+    /// 写入 MANIFEST 的时间
+    pub finished_at: SystemTime,
+}
+
+impl CompactionSummary {
+    /// 编码后的字节数，4 个 u32 之后是 6 个 u64，时间以微秒保存
+    pub(crate) const ENCODED_SIZE: usize = 4 * 4 + 6 * 8;
+
+    pub(crate) fn encode(&self, buf: &mut impl BufMut) {
+        for value in [
+            self.level,
+            self.output_level,
+            self.input_files,
+            self.output_files,
+        ] {
+            buf.put_u32_le(value);
+        }
+        let finished_at = self
+            .finished_at
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .unwrap_or_default();
+        for value in [
+            self.input_bytes,
+            self.output_bytes,
+            self.input_entries,
+            self.dropped_entries,
+            self.duration.as_micros() as u64,
+            finished_at.as_micros() as u64,
+        ] {
+            buf.put_u64_le(value);
+        }
+    }
+
+    pub(crate) fn decode(buf: &mut impl Buf) -> Self {
+        CompactionSummary {
+            level: buf.get_u32_le(),
+            output_level: buf.get_u32_le(),
+            input_files: buf.get_u32_le(),
+            output_files: buf.get_u32_le(),
+            input_bytes: buf.get_u64_le(),
+            output_bytes: buf.get_u64_le(),
+            input_entries: buf.get_u64_le(),
+            dropped_entries: buf.get_u64_le(),
+            duration: Duration::from_micros(buf.get_u64_le()),
+            finished_at: SystemTime::UNIX_EPOCH + Duration::from_micros(buf.get_u64_le()),
+        }
+    }
+
+    /// 输出字节数与输入字节数之比
+    pub fn output_ratio(&self) -> f64 {
+        if self.input_bytes == 0 {
+            return 0.0;
+        }
+        self.output_bytes as f64 / self.input_bytes as f64
+    }
+}
+
+/// 单个 SST 的 key 范围和大小
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct SstMetadata {
+    pub id: u64,
+    /// 文件大小（字节）
+    pub size: u64,
+    pub num_of_pairs: usize,
+    /// 其中删除标记的数量，旧格式的文件中没有记录时为 `None`
+    pub num_of_deletes: Option<usize>,
+    /// 其中数据的最大 seq num，旧格式的文件中没有记录时为 `None`
+    pub max_seq: Option<u64>,
+    pub smallest_key: Bytes,
+    pub largest_key: Bytes,
+    /// 打开以来点查探测该 SST 的次数
+    pub seeks: u64,
+    /// 其中没有找到 key 的次数，包括 bloom filter 误判和 key 不在表中
+    pub seek_misses: u64,
+}
+
+/// key 预览最多显示的字节数
+const KEY_PREVIEW_LEN: usize = 32;
+
+/// 某一层的 key 范围和总大小，供上层分片按实际数据分布决定拆分 / 合并
+#[derive(Debug, Clone, Default, Eq, PartialEq)]
+pub struct LevelMetadata {
+    pub level: u32,
+    /// 该层所有 SST 的大小之和（字节），不包含 KV 分离后的 VSST
+    pub size: u64,
+    /// 该层所有 SST 中的 KV 数量之和
+    pub num_of_pairs: usize,
+    /// 记录了删除标记数量的 SST 中的删除标记之和，见 [`SstMetadata::num_of_deletes`]
+    pub num_of_deletes: usize,
+    /// 该层为空时为 `None`
+    pub smallest_key: Option<Bytes>,
+    pub largest_key: Option<Bytes>,
+    pub files: Vec<SstMetadata>,
+}
+
+impl LevelMetadata {
+    pub(crate) fn new(level: u32, files: Vec<SstMetadata>) -> Self {
+        LevelMetadata {
+            level,
+            size: files.iter().map(|f| f.size).sum(),
+            num_of_pairs: files.iter().map(|f| f.num_of_pairs).sum(),
+            num_of_deletes: files.iter().filter_map(|f| f.num_of_deletes).sum(),
+            smallest_key: files.iter().map(|f| &f.smallest_key).min().cloned(),
+            largest_key: files.iter().map(|f| &f.largest_key).max().cloned(),
+            files,
+        }
+    }
+
+    /// 平均每个 SST 的大小（字节），该层为空时为 0
+    pub fn average_file_size(&self) -> u64 {
+        self.size.checked_div(self.files.len() as u64).unwrap_or(0)
+    }
+
+    /// 删除标记占 KV 数量的比例，只统计记录了删除标记数量的 SST，没有这样的 SST 时为 `None`
+    pub fn tombstone_density(&self) -> Option<f64> {
+        let pairs: usize = self
+            .files
+            .iter()
+            .filter(|f| f.num_of_deletes.is_some())
+            .map(|f| f.num_of_pairs)
+            .sum();
+        if pairs == 0 {
+            return None;
+        }
+        Some(self.num_of_deletes as f64 / pairs as f64)
+    }
+
+    /// 最小 key 的可读预览，不可打印的字节转义，过长时截断
+    pub fn smallest_key_preview(&self) -> Option<String> {
+        self.smallest_key.as_deref().map(key_preview)
+    }
+
+    /// 最大 key 的可读预览，见 [`LevelMetadata::smallest_key_preview`]
+    pub fn largest_key_preview(&self) -> Option<String> {
+        self.largest_key.as_deref().map(key_preview)
+    }
+}
+
+fn key_preview(key: &[u8]) -> String {
+    let preview = key[..key.len().min(KEY_PREVIEW_LEN)]
+        .escape_ascii()
+        .to_string();
+    if key.len() > KEY_PREVIEW_LEN {
+        format!("{}...({} bytes)", preview, key.len())
+    } else {
+        preview
+    }
+}
+
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum HealthStatus {
+    Ok,
+    /// 仍可读写，但后台任务积压，可能即将出现写入停顿，或最后一层超出了大小限制，或有迭代器存活过久
+    Degraded,
+}
+
+#[derive(Debug, Clone)]
+pub struct Health {
+    pub status: HealthStatus,
+    pub issues: Vec<String>,
+}
+
+impl Health {
+    pub fn is_ok(&self) -> bool {
+        self.status == HealthStatus::Ok
+    }
+}
+
+/// 记录后台任务队列中请求的入队时间和完成时间，队列长度由 channel 自身提供
+#[derive(Debug, Default)]
+pub(crate) struct QueueGauge {
+    enqueued_at: Mutex<VecDeque<Instant>>,
+    running: AtomicBool,
+    last_completed_at: Mutex<Option<SystemTime>>,
+}
+
+impl QueueGauge {
+    pub(crate) fn on_enqueue(&self) {
+        self.enqueued_at.lock().push_back(Instant::now());
+    }
+
+    pub(crate) fn on_start(&self) {
+        self.enqueued_at.lock().pop_front();
+        self.running.store(true, Ordering::Release);
+    }
+
+    /// 排队的请求被取消，不会执行。取消的是最近登记的请求，发送失败时撤销刚才的登记
+    pub(crate) fn on_cancel(&self) {
+        self.enqueued_at.lock().pop_back();
+    }
+
+    pub(crate) fn on_complete(&self) {
+        self.running.store(false, Ordering::Release);
+        *self.last_completed_at.lock() = Some(SystemTime::now());
+    }
+
+    pub(crate) fn stats(&self, pending: usize) -> QueueStats {
+        QueueStats {
+            pending,
+            running: self.running.load(Ordering::Acquire),
+            oldest_pending_age: self.enqueued_at.lock().front().map(|t| t.elapsed()),
+            last_completed_at: *self.last_completed_at.lock(),
+        }
+    }
+}
+
+/// [`KeyPrefixStats`] 估算的前缀压缩重启间隔，每隔这么多个 key 保存一次完整的 key
+pub const PREFIX_RESTART_INTERVALS: [u32; 4] = [4, 8, 16, 32];
+
+/// 前缀压缩时每个被压缩的 key 记录公共前缀长度的开销（字节）
+const SHARED_PREFIX_LEN_OVERHEAD: i64 = 2;
+
+/// 平均长度超过它的 key 视为过长
+const LONG_KEY_LEN: u64 = 64;
+
+/// 刷写和合并写入 SST 的 key 的公共前缀统计，打开以来累计，不持久化
+///
+/// 当前的 block 格式保存完整的 key，这里估算按不同重启间隔做前缀压缩能省去多少空间，见 [`KeyPrefixStats::advise`]
+#[derive(Debug, Clone, Default, Eq, PartialEq)]
+pub struct KeyPrefixStats {
+    pub keys: u64,
+    /// key 的总字节数
+    pub key_bytes: u64,
+    /// 每个 key 与同一 block 中前一个 key 的公共前缀长度之和
+    pub shared_prefix_bytes: u64,
+    /// 按 [`PREFIX_RESTART_INTERVALS`] 中的各个间隔做前缀压缩预计节省的字节数，
+    /// 已扣除记录公共前缀长度的开销，可能为负
+    pub restart_savings: [i64; PREFIX_RESTART_INTERVALS.len()],
+}
+
+/// 前缀压缩和 key 设计的建议，见 [`KeyPrefixStats::advise`]
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct PrefixAdvice {
+    /// 建议的重启间隔，收益不足 key 总字节数的 5% 时为 `None`
+    pub restart_interval: Option<u32>,
+    /// 按建议的间隔做前缀压缩预计节省的字节数
+    pub estimated_savings: u64,
+    pub hints: Vec<KeyDesignHint>,
+}
+
+/// 对 key 设计的建议
+#[derive(Debug, Clone, PartialEq)]
+pub enum KeyDesignHint {
+    /// key 的平均长度过长，key 同时占用 SST、bloom filter 和 memtable 的空间
+    LongKeys { avg_len: u64 },
+    /// 相邻 key 几乎没有公共前缀，key 的开头可能是哈希或随机值，前缀压缩没有收益，
+    /// 按范围读取的 key 应以有序的字段开头
+    NoSharedPrefix,
+    /// 相邻 key 的大部分字节相同，可以考虑用更短的编码表示公共部分，例如用表 id 代替表名
+    MostlySharedPrefix { ratio: f64 },
+}
+
+impl KeyPrefixStats {
+    /// 记录 `key`，`block_pos` 为它在 block 中的下标，`prev_key` 为同一 block 中的前一个 key
+    pub(crate) fn add(&mut self, prev_key: &[u8], key: &[u8], block_pos: usize) {
+        self.keys += 1;
+        self.key_bytes += key.len() as u64;
+        if block_pos == 0 {
+            return;
+        }
+        let shared = prev_key.iter().zip(key).take_while(|(a, b)| a == b).count();
+        self.shared_prefix_bytes += shared as u64;
+        for (interval, savings) in PREFIX_RESTART_INTERVALS
+            .iter()
+            .zip(self.restart_savings.iter_mut())
+        {
+            if !block_pos.is_multiple_of(*interval as usize) {
+                *savings += shared as i64 - SHARED_PREFIX_LEN_OVERHEAD;
+            }
+        }
+    }
+
+    pub(crate) fn merge(&mut self, other: &KeyPrefixStats) {
+        self.keys += other.keys;
+        self.key_bytes += other.key_bytes;
+        self.shared_prefix_bytes += other.shared_prefix_bytes;
+        for (savings, other) in self.restart_savings.iter_mut().zip(other.restart_savings) {
+            *savings += other;
+        }
+    }
+
+    /// 根据统计给出建议
+    ///
+    /// 重启间隔越大省去的空间越多，但查找时要从重启点开始逐个还原 key，
+    /// 因此选择收益达到最大收益 90% 的最小间隔
+    pub fn advise(&self) -> PrefixAdvice {
+        let mut advice = PrefixAdvice::default();
+        if self.keys == 0 {
+            return advice;
+        }
+        let best = self.restart_savings.iter().cloned().max().unwrap_or(0);
+        if best > 0 && best as u64 * 20 >= self.key_bytes {
+            let (interval, savings) = PREFIX_RESTART_INTERVALS
+                .iter()
+                .zip(self.restart_savings)
+                .find(|(_, savings)| *savings * 10 >= best * 9)
+                .unwrap();
+            advice.restart_interval = Some(*interval);
+            advice.estimated_savings = savings as u64;
+        }
+
+        let avg_len = self.key_bytes / self.keys;
+        if avg_len > LONG_KEY_LEN {
+            advice.hints.push(KeyDesignHint::LongKeys { avg_len });
+        }
+        let ratio = self.shared_prefix_bytes as f64 / self.key_bytes.max(1) as f64;
+        if self.keys > 1 && ratio < 0.1 {
+            advice.hints.push(KeyDesignHint::NoSharedPrefix);
+        } else if ratio > 0.5 {
+            advice
+                .hints
+                .push(KeyDesignHint::MostlySharedPrefix { ratio });
+        }
+        advice
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use bytes::Bytes;
+
+    use crate::stats::{
+        KeyDesignHint, KeyPrefixStats, LevelMetadata, QueueGauge, SstMetadata,
+        PREFIX_RESTART_INTERVALS,
+    };
+
+    #[test]
+    fn test_level_metadata() {
+        let level = LevelMetadata::new(1, vec![]);
+        assert_eq!(level.size, 0);
+        assert!(level.smallest_key.is_none() && level.largest_key.is_none());
+        assert_eq!(level.average_file_size(), 0);
+        assert_eq!(level.tombstone_density(), None);
+
+        let sst = |id, size, smallest: &'static str, largest: &'static str| SstMetadata {
+            id,
+            size,
+            num_of_pairs: 4,
+            num_of_deletes: Some(1),
+            max_seq: Some(id),
+            smallest_key: Bytes::from(smallest),
+            largest_key: Bytes::from(largest),
+            seeks: 0,
+            seek_misses: 0,
+        };
+        // L0 的 SST 之间可能重叠
+        let level = LevelMetadata::new(0, vec![sst(2, 10, "b", "x"), sst(1, 20, "a", "c")]);
+        assert_eq!(level.size, 30);
+        assert_eq!(level.smallest_key, Some(Bytes::from("a")));
+        assert_eq!(level.largest_key, Some(Bytes::from("x")));
+        assert_eq!(level.num_of_pairs, 8);
+        assert_eq!(level.average_file_size(), 15);
+        assert_eq!(level.tombstone_density(), Some(0.25));
+        assert_eq!(level.smallest_key_preview().unwrap(), "a");
+
+        // 旧格式的 SST 不参与删除标记比例的统计
+        let mut legacy = sst(3, 30, "y", "z");
+        legacy.num_of_deletes = None;
+        legacy.largest_key = Bytes::from(vec![0xffu8; 40]);
+        let level = LevelMetadata::new(1, vec![sst(4, 10, "a", "b"), legacy]);
+        assert_eq!(level.num_of_pairs, 8);
+        assert_eq!(level.tombstone_density(), Some(0.25));
+        assert_eq!(
+            level.largest_key_preview().unwrap(),
+            format!("{}...(40 bytes)", "\\xff".repeat(32))
+        );
+    }
+
+    #[test]
+    fn test_key_prefix_stats() {
+        let mut stats = KeyPrefixStats::default();
+        assert_eq!(stats.advise().restart_interval, None);
+
+        // 64 个 key 在同一个 block 中，相邻 key 共享 "user:00000" 等前缀
+        let keys: Vec<_> = (0..64).map(|i| format!("user:{:06}", i)).collect();
+        let mut prev: &[u8] = &[];
+        for (pos, key) in keys.iter().enumerate() {
+            stats.add(prev, key.as_bytes(), pos);
+            prev = key.as_bytes();
+        }
+        assert_eq!(stats.keys, 64);
+        assert_eq!(stats.key_bytes, 64 * 11);
+        // 与前一个 key 共享前 9 或 10 个字节
+        assert!(stats.shared_prefix_bytes >= 63 * 9);
+        let savings = stats.restart_savings;
+        assert!(savings.windows(2).all(|pair| pair[0] <= pair[1]));
+        let advice = stats.advise();
+        assert!(PREFIX_RESTART_INTERVALS.contains(&advice.restart_interval.unwrap()));
+        assert!(advice.estimated_savings > 0);
+        assert!(advice
+            .hints
+            .iter()
+            .any(|hint| matches!(hint, KeyDesignHint::MostlySharedPrefix { .. })));
+
+        // 开头不同的长 key 没有前缀压缩的收益
+        let mut stats = KeyPrefixStats::default();
+        let keys: Vec<_> = (0..64u32)
+            .map(|i| format!("{:02x}{}", i.wrapping_mul(97) % 256, "x".repeat(80)))
+            .collect();
+        let mut prev: &[u8] = &[];
+        for (pos, key) in keys.iter().enumerate() {
+            stats.add(prev, key.as_bytes(), pos % 16);
+            prev = key.as_bytes();
+        }
+        let advice = stats.advise();
+        assert_eq!(advice.restart_interval, None);
+        assert!(advice
+            .hints
+            .contains(&KeyDesignHint::LongKeys { avg_len: 82 }));
+        assert!(advice.hints.contains(&KeyDesignHint::NoSharedPrefix));
+
+        let mut merged = KeyPrefixStats::default();
+        merged.merge(&stats);
+        merged.merge(&stats);
+        assert_eq!(merged.keys, 128);
+        assert_eq!(merged.restart_savings[0], stats.restart_savings[0] * 2);
+    }
+
+    #[test]
+    fn test_queue_gauge() {
+        let gauge = QueueGauge::default();
+        let stats = gauge.stats(0);
+        assert!(!stats.running);
+        assert!(stats.oldest_pending_age.is_none());
+        assert!(stats.last_completed_at.is_none());
+
+        gauge.on_enqueue();
+        gauge.on_enqueue();
+        let stats = gauge.stats(2);
+        assert_eq!(stats.pending, 2);
+        assert!(stats.oldest_pending_age.is_some());
+
+        gauge.on_start();
+        assert!(gauge.stats(1).running);
+        assert!(gauge.stats(1).oldest_pending_age.is_some());
+        gauge.on_complete();
+        gauge.on_start();
+        gauge.on_complete();
+        let stats = gauge.stats(0);
+        assert!(!stats.running);
+        assert!(stats.oldest_pending_age.is_none());
+        assert!(stats.last_completed_at.is_some());
+    }
+}
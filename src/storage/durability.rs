@@ -0,0 +1,382 @@
+//! 持久化约定检查，调试用，需要开启 `durability-check` feature
+//!
+//! 记录经过 [`FileStorage`](crate::storage::file::FileStorage) 的每个文件的写入、flush、fsync、重命名和删除的顺序，
+//! 以及 MANIFEST 记录中引用和移除的文件，在写入 MANIFEST 记录和删除文件时检查以下约定，违反时 panic：
+//!
+//! | 时机                  | 约定                                                                                   |
+//! |-----------------------|----------------------------------------------------------------------------------------|
+//! | 记录引用 SST / VSST   | 文件已写入操作系统；严格模式下文件和它的目录项都已 fsync                               |
+//! | 删除 SST / VSST       | 记录 `PendingDelete` 的 MANIFEST 记录已写入操作系统；严格模式下该记录已 fsync          |
+//! | 删除 WAL              | 记录 `DelFrozenWal` 的 MANIFEST 记录已 fsync，该记录引用的文件和它们的目录项都已 fsync |
+//! | 删除任意文件          | 文件不再被 MANIFEST 引用                                                               |
+//!
+//! 严格模式即 [`crate::DbOptions::strict_manifest_sync`]。不是由本进程写入的文件（例如上次运行留下的文件）视为已经落盘，
+//! 回收站和暂存文件不做检查。状态是进程级的全局状态，按目录区分不同的数据库，只用于测试，会拖慢所有文件操作
+use std::collections::{BTreeMap, BTreeSet};
+use std::path::{Path, PathBuf};
+
+use parking_lot::Mutex;
+
+use crate::meta::manifest::ManifestItem;
+use crate::record::Record;
+use crate::storage::header::FileType;
+use crate::Db;
+
+static TRACKER: Mutex<Tracker> = Mutex::new(Tracker::new());
+
+/// 一个文件的操作计数，`flushed` 和 `synced` 记录 flush / fsync 时已完成的写入次数
+#[derive(Debug, Default, Clone, Copy)]
+struct FileState {
+    writes: u64,
+    flushed: u64,
+    synced: u64,
+    // 目录在文件创建（或重命名）之后已 fsync
+    entry_synced: bool,
+}
+
+impl FileState {
+    fn flushed(&self) -> bool {
+        self.flushed == self.writes
+    }
+
+    fn durable(&self) -> bool {
+        self.synced == self.writes && self.entry_synced
+    }
+}
+
+/// 某个文件被移除引用的 MANIFEST 记录
+#[derive(Debug)]
+struct Release {
+    manifest: PathBuf,
+    // 记录写入后 MANIFEST 的写入次数
+    record: u64,
+    strict: bool,
+    // 同一条记录引用的文件，删除 WAL 前必须落盘
+    referenced: Vec<PathBuf>,
+}
+
+// 目录、文件类型（见 [`FileType::encode`]）和编号
+type FileKey = (PathBuf, u8, u64);
+
+struct Tracker {
+    files: BTreeMap<PathBuf, FileState>,
+    referenced: BTreeSet<FileKey>,
+    released: BTreeMap<FileKey, Release>,
+}
+
+impl Tracker {
+    const fn new() -> Self {
+        Self {
+            files: BTreeMap::new(),
+            referenced: BTreeSet::new(),
+            released: BTreeMap::new(),
+        }
+    }
+
+    fn durable(&self, path: &Path) -> bool {
+        self.files.get(path).is_none_or(FileState::durable)
+    }
+
+    fn flushed(&self, path: &Path) -> bool {
+        self.files.get(path).is_none_or(FileState::flushed)
+    }
+}
+
+fn path_of(dir: &Path, file_type: FileType, id: u64) -> PathBuf {
+    match file_type {
+        FileType::Sst => Db::path_of_sst(dir, id),
+        FileType::VSst => Db::path_of_vsst(dir, id),
+        FileType::Log => Db::path_of_wal(dir, id),
+        FileType::Manifest => Db::path_of_manifest(dir, id as usize),
+        FileType::Trace => unreachable!("trace files are not referenced by MANIFEST"),
+    }
+}
+
+/// 从文件名解析出文件类型和编号，WAL 的各段（`{log_id}-{segment_id}.LOG`）都属于同一个编号
+fn key_of(path: &Path) -> Option<FileKey> {
+    let dir = path.parent()?.to_path_buf();
+    let (stem, ext) = path.file_name()?.to_str()?.rsplit_once('.')?;
+    let file_type = match ext {
+        "SST" => FileType::Sst,
+        "VSST" => FileType::VSst,
+        "LOG" => FileType::Log,
+        "MANIFEST" => FileType::Manifest,
+        _ => return None,
+    };
+    let id = stem.split('-').next()?.parse().ok()?;
+    Some((dir, file_type.encode(), id))
+}
+
+/// 打开文件，`existed` 为打开前文件是否已存在
+///
+/// 打开已有的文件时视为之前的写入都已落盘，保留写入次数，之前记录的 MANIFEST 记录位置仍然有效
+pub(crate) fn on_open(path: &Path, existed: bool) {
+    let mut tracker = TRACKER.lock();
+    if !existed {
+        tracker
+            .files
+            .insert(path.to_path_buf(), FileState::default());
+    } else if let Some(state) = tracker.files.get_mut(path) {
+        state.flushed = state.writes;
+        state.synced = state.writes;
+        state.entry_synced = true;
+    }
+}
+
+/// 创建文件并直接写入内容，不经过写缓冲
+pub(crate) fn on_create(path: &Path, existed: bool) {
+    let state = FileState {
+        writes: 1,
+        flushed: 1,
+        synced: 0,
+        entry_synced: existed,
+    };
+    TRACKER.lock().files.insert(path.to_path_buf(), state);
+}
+
+pub(crate) fn on_write(path: &Path) {
+    let mut tracker = TRACKER.lock();
+    let state = tracker
+        .files
+        .entry(path.to_path_buf())
+        .or_insert(FileState {
+            entry_synced: true,
+            ..FileState::default()
+        });
+    state.writes += 1;
+}
+
+pub(crate) fn on_sync(path: &Path) {
+    if let Some(state) = TRACKER.lock().files.get_mut(path) {
+        state.flushed = state.writes;
+    }
+}
+
+pub(crate) fn on_sync_all(path: &Path) {
+    if let Some(state) = TRACKER.lock().files.get_mut(path) {
+        state.flushed = state.writes;
+        state.synced = state.writes;
+    }
+}
+
+pub(crate) fn on_sync_dir(dir: &Path) {
+    let mut tracker = TRACKER.lock();
+    for (path, state) in tracker.files.iter_mut() {
+        if path.parent() == Some(dir) {
+            state.entry_synced = true;
+        }
+    }
+}
+
+/// 重命名后新的目录项在目录 fsync 之前不保证存在
+pub(crate) fn on_rename(from: &Path, to: &Path) {
+    let mut tracker = TRACKER.lock();
+    let mut state = tracker.files.remove(from).unwrap_or_default();
+    state.entry_synced = false;
+    tracker.files.insert(to.to_path_buf(), state);
+}
+
+/// 写入一条 MANIFEST 记录之后、flush 或 fsync 之前调用
+pub(crate) fn on_manifest_record(manifest: &Path, r: &Record<ManifestItem>, strict: bool) {
+    let Some(dir) = manifest.parent() else {
+        return;
+    };
+    let mut tracker = TRACKER.lock();
+    let record = tracker.files.get(manifest).map_or(0, |state| state.writes);
+    let mut referenced = Vec::new();
+    let items = || (0..r.num_of_items()).map(|idx| r.item(idx));
+    for item in items() {
+        let (file_type, id) = match item {
+            ManifestItem::NewSst(_, id) => (FileType::Sst, *id),
+            ManifestItem::NewVSst(id) => (FileType::VSst, *id),
+            _ => continue,
+        };
+        let path = path_of(dir, file_type, id);
+        if strict {
+            assert!(
+                tracker.durable(&path),
+                "durability contract violated: {:?} recorded in {:?} before it and its directory entry are synced",
+                path,
+                manifest
+            );
+        } else {
+            assert!(
+                tracker.flushed(&path),
+                "durability contract violated: {:?} recorded in {:?} before its writes are flushed",
+                path,
+                manifest
+            );
+        }
+        tracker
+            .referenced
+            .insert((dir.to_path_buf(), file_type.encode(), id));
+        referenced.push(path);
+    }
+    for item in items() {
+        let key = match item {
+            ManifestItem::PendingDelete(file_type, id) => {
+                (dir.to_path_buf(), file_type.encode(), *id)
+            }
+            ManifestItem::DelFrozenWal(id) => (dir.to_path_buf(), FileType::Log.encode(), *id),
+            _ => continue,
+        };
+        tracker.referenced.remove(&key);
+        let release = Release {
+            manifest: manifest.to_path_buf(),
+            record,
+            strict,
+            referenced: referenced.clone(),
+        };
+        tracker.released.insert(key, release);
+    }
+}
+
+/// 删除（或移到回收站）文件之前调用
+pub(crate) fn on_delete(path: &Path) {
+    let mut tracker = TRACKER.lock();
+    let Some(key) = key_of(path) else {
+        tracker.files.remove(path);
+        return;
+    };
+    assert!(
+        !tracker.referenced.contains(&key),
+        "durability contract violated: {:?} deleted while still referenced by MANIFEST",
+        path
+    );
+    if let Some(release) = tracker.released.get(&key) {
+        let manifest = tracker.files.get(&release.manifest).copied();
+        let is_wal = key.1 == FileType::Log.encode();
+        let need_sync = is_wal || release.strict;
+        if need_sync {
+            assert!(
+                manifest.is_none_or(|m| m.synced >= release.record),
+                "durability contract violated: {:?} deleted before the {:?} record releasing it is synced",
+                path,
+                release.manifest
+            );
+        } else {
+            assert!(
+                manifest.is_none_or(|m| m.flushed >= release.record),
+                "durability contract violated: {:?} deleted before the {:?} record releasing it is flushed",
+                path,
+                release.manifest
+            );
+        }
+        if is_wal {
+            for referenced in &release.referenced {
+                assert!(
+                    tracker.durable(referenced),
+                    "durability contract violated: {:?} deleted before {:?} flushed from it and its directory entry are synced",
+                    path,
+                    referenced
+                );
+            }
+        }
+    }
+    tracker.files.remove(path);
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::meta::manifest::{Manifest, ManifestItem};
+    use crate::record::RecordBuilder;
+    use crate::storage::file::{sync_dir, FileStorage};
+    use crate::storage::header::FileType;
+    use crate::Db;
+
+    fn manifest(dir: &std::path::Path, strict: bool) -> Manifest {
+        let mut manifest = Manifest::open(Db::path_of_manifest(dir, 1)).unwrap();
+        manifest.set_strict_sync(strict);
+        manifest
+    }
+
+    fn record(items: Vec<ManifestItem>) -> crate::record::Record<ManifestItem> {
+        let mut r = RecordBuilder::new();
+        for item in items {
+            r.add(item);
+        }
+        r.build()
+    }
+
+    #[test]
+    fn test_flush_order() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut manifest = manifest(dir.path(), false);
+        let wal = FileStorage::open(Db::path_of_wal(dir.path(), 1)).unwrap();
+        wal.write(b"wal").unwrap();
+        wal.sync().unwrap();
+        let sst = FileStorage::create(Db::path_of_sst(dir.path(), 1), b"sst".to_vec()).unwrap();
+        sst.sync_all().unwrap();
+        sync_dir(dir.path()).unwrap();
+        manifest
+            .add(&record(vec![
+                ManifestItem::NewSst(0, 1),
+                ManifestItem::DelFrozenWal(1),
+            ]))
+            .unwrap();
+        manifest.sync_all().unwrap();
+        wal.delete().unwrap();
+
+        // 移除引用并 flush 后，非严格模式下可以删除
+        manifest
+            .add(&record(vec![
+                ManifestItem::DelSst(0, 1),
+                ManifestItem::PendingDelete(FileType::Sst, 1),
+            ]))
+            .unwrap();
+        sst.delete().unwrap();
+    }
+
+    #[test]
+    #[should_panic(expected = "record releasing it is synced")]
+    fn test_wal_deleted_before_manifest_sync() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut manifest = manifest(dir.path(), false);
+        let wal = FileStorage::open(Db::path_of_wal(dir.path(), 1)).unwrap();
+        wal.write(b"wal").unwrap();
+        manifest
+            .add(&record(vec![ManifestItem::DelFrozenWal(1)]))
+            .unwrap();
+        wal.delete().unwrap();
+    }
+
+    #[test]
+    #[should_panic(expected = "its directory entry are synced")]
+    fn test_wal_deleted_before_sst_sync() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut manifest = manifest(dir.path(), false);
+        let wal = FileStorage::open(Db::path_of_wal(dir.path(), 1)).unwrap();
+        FileStorage::create(Db::path_of_sst(dir.path(), 1), b"sst".to_vec()).unwrap();
+        manifest
+            .add(&record(vec![
+                ManifestItem::NewSst(0, 1),
+                ManifestItem::DelFrozenWal(1),
+            ]))
+            .unwrap();
+        manifest.sync_all().unwrap();
+        wal.delete().unwrap();
+    }
+
+    #[test]
+    #[should_panic(expected = "before it and its directory entry are synced")]
+    fn test_strict_record_before_sst_sync() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut manifest = manifest(dir.path(), true);
+        FileStorage::create(Db::path_of_sst(dir.path(), 1), b"sst".to_vec()).unwrap();
+        manifest
+            .add(&record(vec![ManifestItem::NewSst(0, 1)]))
+            .unwrap();
+    }
+
+    #[test]
+    #[should_panic(expected = "still referenced by MANIFEST")]
+    fn test_referenced_file_deleted() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut manifest = manifest(dir.path(), false);
+        let sst = FileStorage::create(Db::path_of_sst(dir.path(), 1), b"sst".to_vec()).unwrap();
+        manifest
+            .add(&record(vec![ManifestItem::NewSst(0, 1)]))
+            .unwrap();
+        sst.delete().unwrap();
+    }
+}
@@ -0,0 +1,144 @@
+//! 基于 failpoint 的慢 IO / IO 错误模拟，需要开启 `failpoints` feature
+//!
+//! 所有 [`FileStorage`](crate::storage::file::FileStorage) 的读、写、sync 操作都会经过以下 failpoint：
+//!
+//! | 操作  | 延迟                    | 错误                  |
+//! |-------|-------------------------|-----------------------|
+//! | read  | `storage::read_latency` | `storage::read_error` |
+//! | write | `storage::write_latency`| `storage::write_error`|
+//! | sync  | `storage::sync_latency` | `storage::sync_error` |
+//!
+//! 既可以通过 `FAILPOINTS` 环境变量或 [`fail::cfg`] 直接配置，也可以用 [`IoFaults`] 按操作类型设置。
+//! failpoint 是进程级的全局配置，会影响进程内所有打开的数据库
+use std::time::Duration;
+
+use anyhow::{anyhow, Result};
+
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Hash)]
+pub enum IoOp {
+    Read,
+    Write,
+    Sync,
+}
+
+impl IoOp {
+    const ALL: [IoOp; 3] = [IoOp::Read, IoOp::Write, IoOp::Sync];
+
+    fn latency_point(&self) -> &'static str {
+        match self {
+            IoOp::Read => "storage::read_latency",
+            IoOp::Write => "storage::write_latency",
+            IoOp::Sync => "storage::sync_latency",
+        }
+    }
+
+    fn error_point(&self) -> &'static str {
+        match self {
+            IoOp::Read => "storage::read_error",
+            IoOp::Write => "storage::write_error",
+            IoOp::Sync => "storage::sync_error",
+        }
+    }
+}
+
+/// 某一类 IO 操作注入的故障
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct IoFault {
+    /// 每次操作额外增加的延迟
+    pub latency: Duration,
+    /// 操作失败的概率，取值范围 [0, 1]
+    pub error_rate: f64,
+}
+
+/// 按操作类型配置注入的延迟和错误率
+///
+/// ```ignore
+/// IoFaults::new()
+///     .latency(IoOp::Sync, Duration::from_millis(20))
+///     .error_rate(IoOp::Write, 0.01)
+///     .apply()?;
+/// // ...
+/// IoFaults::clear();
+/// ```
+#[derive(Debug, Clone, Default)]
+pub struct IoFaults {
+    read: IoFault,
+    write: IoFault,
+    sync: IoFault,
+}
+
+impl IoFaults {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    fn fault_mut(&mut self, op: IoOp) -> &mut IoFault {
+        match op {
+            IoOp::Read => &mut self.read,
+            IoOp::Write => &mut self.write,
+            IoOp::Sync => &mut self.sync,
+        }
+    }
+
+    pub fn fault(&self, op: IoOp) -> IoFault {
+        match op {
+            IoOp::Read => self.read,
+            IoOp::Write => self.write,
+            IoOp::Sync => self.sync,
+        }
+    }
+
+    pub fn latency(mut self, op: IoOp, latency: Duration) -> Self {
+        self.fault_mut(op).latency = latency;
+        self
+    }
+
+    pub fn error_rate(mut self, op: IoOp, error_rate: f64) -> Self {
+        self.fault_mut(op).error_rate = error_rate;
+        self
+    }
+
+    /// 将配置写入 failpoint，覆盖之前的配置
+    pub fn apply(&self) -> Result<()> {
+        for op in IoOp::ALL {
+            let fault = self.fault(op);
+            if !(0.0..=1.0).contains(&fault.error_rate) {
+                return Err(anyhow!(
+                    "error rate of {:?} must be in [0, 1], but got {}",
+                    op,
+                    fault.error_rate
+                ));
+            }
+        }
+        for op in IoOp::ALL {
+            let fault = self.fault(op);
+            if fault.latency.is_zero() {
+                fail::remove(op.latency_point());
+            } else {
+                fail::cfg(
+                    op.latency_point(),
+                    &format!("sleep({})", fault.latency.as_millis()),
+                )
+                .map_err(|e| anyhow!(e))?;
+            }
+            if fault.error_rate == 0.0 {
+                fail::remove(op.error_point());
+            } else {
+                fail::cfg(
+                    op.error_point(),
+                    &format!("{}%return", fault.error_rate * 100.0),
+                )
+                .map_err(|e| anyhow!(e))?;
+            }
+        }
+        Ok(())
+    }
+
+    /// 移除所有注入的 IO 故障
+    pub fn clear() {
+        for op in IoOp::ALL {
+            fail::remove(op.latency_point());
+            fail::remove(op.error_point());
+        }
+    }
+}
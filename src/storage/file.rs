@@ -1,110 +1,417 @@
-use std::fmt::{Debug, Formatter};
+use std::cell::Cell;
+use std::fmt::{Debug, Display, Formatter};
 use std::fs;
 use std::fs::File;
-use std::io::{BufReader, BufWriter, Read, Seek, SeekFrom, Write};
+use std::io::{BufWriter, Seek, SeekFrom, Write};
 
 use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
 use std::sync::Arc;
+use std::time::{Duration, Instant, SystemTime};
 
+use crate::contention::LockWaitCounters;
+use crate::stats::LockWaitStats;
+#[cfg(feature = "durability-check")]
+use crate::storage::durability;
 use crate::storage::ioarc::IoArc;
 use anyhow::Result;
-use parking_lot::Mutex;
+use fail::fail_point;
+use parking_lot::{Mutex, MutexGuard};
 use tracing::instrument;
 
 use crate::storage::storage::Storage;
 
-struct FileStorageInner {
-    file: Arc<File>,
-    reader: BufReader<IoArc<File>>,
-    writer: BufWriter<IoArc<File>>,
+/// 读请求的优先级，由发起读取的线程决定，见 [`IoPriorityScope`]
+#[derive(Debug, Clone, Copy, Default, Eq, PartialEq)]
+pub enum IoPriority {
+    /// 用户读，默认
+    #[default]
+    Foreground,
+    /// 后台读，有用户读正在进行时先等待一小段时间，最多等待 2ms
+    Background,
+}
+
+/// 后台读为用户读让路的最长时间，避免用户读持续不断时合并无法推进
+const BACKGROUND_READ_MAX_DELAY: Duration = Duration::from_millis(2);
+/// 后台读等待期间检查用户读是否结束的间隔
+const BACKGROUND_READ_POLL_INTERVAL: Duration = Duration::from_micros(50);
+
+thread_local! {
+    static IO_PRIORITY: Cell<IoPriority> = const { Cell::new(IoPriority::Foreground) };
+}
+
+/// 正在进行的用户读的数量，所有文件共享
+static FOREGROUND_READS: AtomicUsize = AtomicUsize::new(0);
+/// 因用户读而推迟的后台读的次数
+static DELAYED_BACKGROUND_READS: AtomicU64 = AtomicU64::new(0);
+/// 获取文件写锁时的等待，所有文件共享
+static WRITER_LOCK_WAITS: LockWaitCounters = LockWaitCounters::new();
+
+/// 在作用域内设置当前线程发起的读请求的优先级，drop 时恢复原来的优先级
+pub struct IoPriorityScope {
+    prev: IoPriority,
 }
 
-impl FileStorageInner {
-    pub fn new(file: Arc<File>) -> Self {
+impl IoPriorityScope {
+    pub fn enter(priority: IoPriority) -> Self {
         Self {
-            file: file.clone(),
-            reader: BufReader::new(IoArc::from_arc(file.clone())),
-            writer: BufWriter::new(IoArc::from_arc(file)),
+            prev: IO_PRIORITY.with(|p| p.replace(priority)),
         }
     }
 }
 
+impl Drop for IoPriorityScope {
+    fn drop(&mut self) {
+        IO_PRIORITY.with(|p| p.set(self.prev));
+    }
+}
+
+/// 当前线程发起的读请求的优先级
+pub fn current_io_priority() -> IoPriority {
+    IO_PRIORITY.with(|p| p.get())
+}
+
+/// 因用户读而推迟的后台读的次数
+pub fn delayed_background_reads() -> u64 {
+    DELAYED_BACKGROUND_READS.load(Ordering::Relaxed)
+}
+
+/// 获取文件写锁时的等待，所有文件共享
+pub(crate) fn writer_lock_waits() -> LockWaitStats {
+    WRITER_LOCK_WAITS.stats()
+}
+
+/// 按当前线程的优先级登记一次读取，用户读在 drop 时结束
+struct ReadPermit {
+    foreground: bool,
+}
+
+impl ReadPermit {
+    fn acquire() -> Self {
+        match current_io_priority() {
+            IoPriority::Foreground => {
+                FOREGROUND_READS.fetch_add(1, Ordering::AcqRel);
+                Self { foreground: true }
+            }
+            IoPriority::Background => {
+                if FOREGROUND_READS.load(Ordering::Acquire) > 0 {
+                    DELAYED_BACKGROUND_READS.fetch_add(1, Ordering::Relaxed);
+                    let start = Instant::now();
+                    while FOREGROUND_READS.load(Ordering::Acquire) > 0
+                        && start.elapsed() < BACKGROUND_READ_MAX_DELAY
+                    {
+                        std::thread::sleep(BACKGROUND_READ_POLL_INTERVAL);
+                    }
+                }
+                Self { foreground: false }
+            }
+        }
+    }
+}
+
+impl Drop for ReadPermit {
+    fn drop(&mut self) {
+        if self.foreground {
+            FOREGROUND_READS.fetch_sub(1, Ordering::AcqRel);
+        }
+    }
+}
+
+/// 文件操作的类型，见 [`IoError`]
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum FileOp {
+    Open,
+    Create,
+    Read,
+    Write,
+    Sync,
+    Rename,
+    Delete,
+    Metadata,
+    Truncate,
+}
+
+impl Display for FileOp {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        let op = match self {
+            FileOp::Open => "open",
+            FileOp::Create => "create",
+            FileOp::Read => "read",
+            FileOp::Write => "write",
+            FileOp::Sync => "sync",
+            FileOp::Rename => "rename",
+            FileOp::Delete => "delete",
+            FileOp::Metadata => "stat",
+            FileOp::Truncate => "truncate",
+        };
+        f.write_str(op)
+    }
+}
+
+/// 文件操作失败时返回的错误，带有出错的文件、操作以及读写的位置和长度，
+/// 例如 `read "db/3.SST" at offset 4096 (512 bytes) failed: failed to fill whole buffer`
+#[derive(Debug)]
+pub struct IoError {
+    pub path: PathBuf,
+    pub op: FileOp,
+    /// 读取的起始位置，其它操作为 `None`
+    pub offset: Option<u64>,
+    /// 读写的字节数，不涉及数据的操作为 `None`
+    pub len: Option<u64>,
+    pub error: std::io::Error,
+}
+
+impl IoError {
+    pub fn new(path: impl AsRef<Path>, op: FileOp, error: std::io::Error) -> Self {
+        Self {
+            path: path.as_ref().to_path_buf(),
+            op,
+            offset: None,
+            len: None,
+            error,
+        }
+    }
+
+    fn at(mut self, offset: Option<u64>, len: u64) -> Self {
+        self.offset = offset;
+        self.len = Some(len);
+        self
+    }
+
+    pub fn kind(&self) -> std::io::ErrorKind {
+        self.error.kind()
+    }
+}
+
+impl Display for IoError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} {:?}", self.op, self.path)?;
+        if let Some(offset) = self.offset {
+            write!(f, " at offset {}", offset)?;
+        }
+        if let Some(len) = self.len {
+            write!(f, " ({} bytes)", len)?;
+        }
+        write!(f, " failed: {}", self.error)
+    }
+}
+
+impl std::error::Error for IoError {}
+
+/// fsync 目录，使其中新建、重命名和删除的文件项在掉电后仍然有效
+#[cfg(unix)]
+pub fn sync_dir(path: impl AsRef<Path>) -> Result<()> {
+    let path = path.as_ref();
+    File::open(path)
+        .and_then(|dir| dir.sync_all())
+        .map_err(|e| IoError::new(path, FileOp::Sync, e))?;
+    #[cfg(feature = "durability-check")]
+    durability::on_sync_dir(path);
+    Ok(())
+}
+
+/// Windows 无法打开目录进行 fsync，文件项随文件的元数据一起落盘
+#[cfg(windows)]
+pub fn sync_dir(_path: impl AsRef<Path>) -> Result<()> {
+    Ok(())
+}
+
+/// 从 `offset` 开始读满 `buf`，不改变文件的读写位置，多个线程可以同时读取同一个文件
+#[cfg(unix)]
+fn read_exact_at(file: &File, buf: &mut [u8], offset: u64) -> std::io::Result<()> {
+    std::os::unix::fs::FileExt::read_exact_at(file, buf, offset)
+}
+
+#[cfg(windows)]
+fn read_exact_at(file: &File, mut buf: &mut [u8], mut offset: u64) -> std::io::Result<()> {
+    use std::os::windows::fs::FileExt;
+    while !buf.is_empty() {
+        match file.seek_read(buf, offset) {
+            Ok(0) => return Err(std::io::ErrorKind::UnexpectedEof.into()),
+            Ok(n) => {
+                buf = &mut buf[n..];
+                offset += n as u64;
+            }
+            Err(e) if e.kind() == std::io::ErrorKind::Interrupted => {}
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(())
+}
+
+/// 读取不经过锁，直接按偏移读文件，只有写入需要持锁
 pub struct FileStorage {
-    inner: Mutex<FileStorageInner>,
+    file: Arc<File>,
+    writer: Mutex<BufWriter<IoArc<File>>>,
     path: PathBuf,
 }
 
 impl FileStorage {
     pub fn open(path: impl AsRef<Path>) -> Result<Self> {
-        let file = Arc::new(
-            File::options()
-                .read(true)
-                .write(true)
-                .create(true)
-                .open(&path)?,
-        );
-        Ok(Self {
-            inner: Mutex::new(FileStorageInner::new(file)),
-            path: PathBuf::from(path.as_ref()),
-        })
+        let path = path.as_ref();
+        #[cfg(feature = "durability-check")]
+        durability::on_open(path, path.exists());
+        let file = File::options()
+            .read(true)
+            .write(true)
+            .create(true)
+            .open(path)
+            .map_err(|e| IoError::new(path, FileOp::Open, e))?;
+        Ok(Self::from_file(Arc::new(file), path))
+    }
+
+    fn from_file(file: Arc<File>, path: &Path) -> Self {
+        Self {
+            writer: Mutex::new(BufWriter::new(IoArc::from_arc(file.clone()))),
+            file,
+            path: PathBuf::from(path),
+        }
     }
 
     pub fn create(path: impl AsRef<Path>, data: Vec<u8>) -> Result<Self> {
+        let path = path.as_ref();
+        #[cfg(feature = "durability-check")]
+        durability::on_create(path, path.exists());
         let mut file = File::options()
             .create(true)
             .truncate(true)
             .read(true)
             .write(true)
-            .open(&path)?;
-        file.write_all(&data).unwrap();
-        Ok(Self {
-            inner: Mutex::new(FileStorageInner::new(Arc::new(file))),
-            path: PathBuf::from(path.as_ref()),
-        })
+            .open(path)
+            .map_err(|e| IoError::new(path, FileOp::Create, e))?;
+        file.write_all(&data)
+            .map_err(|e| IoError::new(path, FileOp::Write, e).at(Some(0), data.len() as u64))?;
+        Ok(Self::from_file(Arc::new(file), path))
+    }
+
+    fn lock_writer(&self) -> MutexGuard<'_, BufWriter<IoArc<File>>> {
+        WRITER_LOCK_WAITS.lock(&self.writer)
+    }
+
+    fn io_error(&self, op: FileOp, error: std::io::Error) -> IoError {
+        IoError::new(&self.path, op, error)
     }
 
     pub fn read(&self, offset: u64, len: u64) -> Result<Vec<u8>> {
+        fail_point!("storage::read_latency");
+        fail_point!("storage::read_error", |_| Err(anyhow::anyhow!(
+            "injected read error: {:?}",
+            self.path
+        )));
         let mut data = vec![0; len as usize];
-        let mut guard = self.inner.lock();
-        guard.reader.seek(SeekFrom::Start(offset))?;
-        guard.reader.read_exact(&mut data)?;
+        let _permit = ReadPermit::acquire();
+        read_exact_at(&self.file, &mut data, offset)
+            .map_err(|e| self.io_error(FileOp::Read, e).at(Some(offset), len))?;
         Ok(data)
     }
 
     pub fn read_to_end(&self, offset: u64) -> Result<Vec<u8>> {
-        let mut buf = vec![];
-        let mut guard = self.inner.lock();
-        guard.reader.seek(SeekFrom::Start(offset))?;
-        guard.reader.read_to_end(&mut buf)?;
+        fail_point!("storage::read_latency");
+        fail_point!("storage::read_error", |_| Err(anyhow::anyhow!(
+            "injected read error: {:?}",
+            self.path
+        )));
+        let len = self
+            .file
+            .metadata()
+            .map_err(|e| self.io_error(FileOp::Metadata, e))?
+            .len()
+            .saturating_sub(offset);
+        let mut buf = vec![0; len as usize];
+        let _permit = ReadPermit::acquire();
+        read_exact_at(&self.file, &mut buf, offset)
+            .map_err(|e| self.io_error(FileOp::Read, e).at(Some(offset), len))?;
         Ok(buf)
     }
 
     #[instrument(skip_all)]
-    pub fn write(&self, data: &[u8]) {
-        let mut guard = self.inner.lock();
-        guard.writer.seek(SeekFrom::End(0)).unwrap();
-        guard.writer.write_all(data).unwrap();
+    pub fn write(&self, data: &[u8]) -> Result<()> {
+        fail_point!("storage::write_latency");
+        fail_point!("storage::write_error", |_| Err(anyhow::anyhow!(
+            "injected write error: {:?}",
+            self.path
+        )));
+        let mut writer = self.lock_writer();
+        writer
+            .seek(SeekFrom::End(0))
+            .and_then(|_| writer.write_all(data))
+            .map_err(|e| self.io_error(FileOp::Write, e).at(None, data.len() as u64))?;
+        #[cfg(feature = "durability-check")]
+        durability::on_write(&self.path);
+        Ok(())
+    }
+
+    #[instrument(skip_all)]
+    pub fn sync(&self) -> Result<()> {
+        fail_point!("storage::sync_latency");
+        fail_point!("storage::sync_error", |_| Err(anyhow::anyhow!(
+            "injected sync error: {:?}",
+            self.path
+        )));
+        self.lock_writer()
+            .flush()
+            .map_err(|e| self.io_error(FileOp::Sync, e))?;
+        #[cfg(feature = "durability-check")]
+        durability::on_sync(&self.path);
+        Ok(())
     }
 
+    /// 刷写写缓冲后将文件内容和元数据 fsync 到磁盘，[`FileStorage::sync`] 只保证进程崩溃时不丢失
     #[instrument(skip_all)]
-    pub fn sync(&self) {
-        self.inner.lock().writer.flush().unwrap();
+    pub fn sync_all(&self) -> Result<()> {
+        self.sync()?;
+        self.file
+            .sync_all()
+            .map_err(|e| self.io_error(FileOp::Sync, e))?;
+        #[cfg(feature = "durability-check")]
+        durability::on_sync_all(&self.path);
+        Ok(())
+    }
+
+    /// 刷写写缓冲后把文件截断到 `len` 字节，之后的写入从新的末尾开始
+    pub fn truncate(&self, len: u64) -> Result<()> {
+        let mut writer = self.lock_writer();
+        writer
+            .flush()
+            .map_err(|e| self.io_error(FileOp::Write, e))?;
+        self.file
+            .set_len(len)
+            .map_err(|e| self.io_error(FileOp::Truncate, e))?;
+        #[cfg(feature = "durability-check")]
+        durability::on_write(&self.path);
+        Ok(())
     }
 
     pub fn rename(&self, new_path: impl AsRef<Path>) -> anyhow::Result<()> {
-        fs::rename(&self.path, &new_path)?;
+        fs::rename(&self.path, &new_path).map_err(|e| self.io_error(FileOp::Rename, e))?;
+        #[cfg(feature = "durability-check")]
+        durability::on_rename(&self.path, new_path.as_ref());
         Ok(())
     }
 
     pub fn delete(&self) -> anyhow::Result<()> {
-        fs::remove_file(&self.path)?;
+        #[cfg(feature = "durability-check")]
+        durability::on_delete(&self.path);
+        fs::remove_file(&self.path).map_err(|e| self.io_error(FileOp::Delete, e))?;
         Ok(())
     }
 
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
     pub fn size(&self) -> anyhow::Result<u64> {
-        let metadata = fs::metadata(&self.path)?;
+        let metadata = fs::metadata(&self.path).map_err(|e| self.io_error(FileOp::Metadata, e))?;
         Ok(metadata.len())
     }
+
+    /// 文件最后修改的时间
+    pub fn modified(&self) -> anyhow::Result<SystemTime> {
+        let metadata = fs::metadata(&self.path).map_err(|e| self.io_error(FileOp::Metadata, e))?;
+        metadata
+            .modified()
+            .map_err(|e| self.io_error(FileOp::Metadata, e).into())
+    }
 }
 
 impl Debug for FileStorage {
@@ -119,9 +426,14 @@ impl Storage for FileStorage {}
 
 #[cfg(test)]
 mod tests {
-    use crate::storage::file::FileStorage;
+    use crate::storage::file::{
+        current_io_priority, delayed_background_reads, FileOp, FileStorage, IoError, IoPriority,
+        IoPriorityScope, ReadPermit,
+    };
     use bytes::Bytes;
     use std::fs;
+    use std::sync::Arc;
+    use std::thread;
 
     #[test]
     fn test_file() {
@@ -129,10 +441,84 @@ mod tests {
         fs::create_dir_all(dir.path()).unwrap();
         let path = dir.path().join("TEST");
         let file = FileStorage::open(path).unwrap();
-        file.write(b"123");
-        file.sync();
+        file.write(b"123").unwrap();
+        file.sync().unwrap();
 
         let content = file.read_to_end(0).unwrap();
         assert_eq!(Bytes::from(content), Bytes::from("123"));
     }
+
+    #[test]
+    fn test_io_error_context() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("TEST");
+        let file = FileStorage::create(&path, b"123".to_vec()).unwrap();
+
+        let err = file.read(2, 10).unwrap_err();
+        let io_error = err.downcast_ref::<IoError>().unwrap();
+        assert_eq!(io_error.path, path);
+        assert_eq!(io_error.op, FileOp::Read);
+        assert_eq!(io_error.offset, Some(2));
+        assert_eq!(io_error.len, Some(10));
+        assert_eq!(io_error.kind(), std::io::ErrorKind::UnexpectedEof);
+        assert_eq!(
+            err.to_string(),
+            format!(
+                "read {:?} at offset 2 (10 bytes) failed: {}",
+                path, io_error.error
+            )
+        );
+
+        file.delete().unwrap();
+        let err = file.delete().unwrap_err();
+        let io_error = err.downcast_ref::<IoError>().unwrap();
+        assert_eq!(io_error.op, FileOp::Delete);
+        assert_eq!(io_error.kind(), std::io::ErrorKind::NotFound);
+        assert_eq!(io_error.offset, None);
+        let err = FileStorage::open(dir.path().join("missing").join("TEST")).unwrap_err();
+        assert_eq!(err.downcast_ref::<IoError>().unwrap().op, FileOp::Open);
+    }
+
+    #[test]
+    fn test_concurrent_read() {
+        let dir = tempfile::tempdir().unwrap();
+        let data: Vec<u8> = (0..64 * 1024).map(|i| (i % 251) as u8).collect();
+        let file = Arc::new(FileStorage::create(dir.path().join("TEST"), data.clone()).unwrap());
+        let data = Arc::new(data);
+
+        let handles: Vec<_> = (0..8)
+            .map(|t| {
+                let file = file.clone();
+                let data = data.clone();
+                thread::spawn(move || {
+                    for i in 0..200 {
+                        let offset = (t * 997 + i * 311) % (data.len() - 100);
+                        let read = file.read(offset as u64, 100).unwrap();
+                        assert_eq!(read, &data[offset..offset + 100]);
+                    }
+                })
+            })
+            .collect();
+        handles.into_iter().for_each(|h| h.join().unwrap());
+        assert_eq!(file.read_to_end(1000).unwrap(), &data[1000..]);
+    }
+
+    #[test]
+    fn test_io_priority() {
+        let dir = tempfile::tempdir().unwrap();
+        let file = FileStorage::create(dir.path().join("TEST"), b"123".to_vec()).unwrap();
+        assert_eq!(current_io_priority(), IoPriority::Foreground);
+
+        // 有用户读正在进行时后台读先等待
+        let foreground = ReadPermit::acquire();
+        {
+            let _scope = IoPriorityScope::enter(IoPriority::Background);
+            assert_eq!(current_io_priority(), IoPriority::Background);
+            let delayed = delayed_background_reads();
+            assert_eq!(file.read(0, 3).unwrap(), b"123");
+            assert!(delayed_background_reads() > delayed);
+        }
+        drop(foreground);
+        assert_eq!(current_io_priority(), IoPriority::Foreground);
+    }
 }
@@ -0,0 +1,201 @@
+use std::fmt::{Display, Formatter};
+use std::path::{Path, PathBuf};
+
+use thiserror::Error;
+
+/// 所有数据文件共用的魔数
+pub const FILE_MAGIC: [u8; 4] = *b"LSGN";
+/// 当前写入的文件格式版本，没有文件头的旧文件视为版本 0
+///
+/// - 1：加入文件头
+/// - 2：SST / VSST 记录删除标记的数量
+/// - 3：SST / VSST 记录其中数据的最大 seq num
+/// - 4：SST / VSST 的 footer 带校验和，其中记录的 KV 数量可信
+pub const FORMAT_VERSION: u8 = 4;
+/// 文件头（SST / VSST 为文件尾）的长度
+pub const FILE_HEADER_SIZE: usize = 8;
+
+/// 数据文件的类型
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum FileType {
+    Sst,
+    VSst,
+    Manifest,
+    Log,
+    /// 操作记录，见 [`crate::trace`]
+    Trace,
+}
+
+impl FileType {
+    pub(crate) fn encode(&self) -> u8 {
+        match self {
+            FileType::Sst => 1,
+            FileType::VSst => 2,
+            FileType::Manifest => 3,
+            FileType::Log => 4,
+            FileType::Trace => 5,
+        }
+    }
+
+    pub(crate) fn decode(data: u8) -> Option<Self> {
+        match data {
+            1 => Some(FileType::Sst),
+            2 => Some(FileType::VSst),
+            3 => Some(FileType::Manifest),
+            4 => Some(FileType::Log),
+            5 => Some(FileType::Trace),
+            _ => None,
+        }
+    }
+}
+
+impl Display for FileType {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        let name = match self {
+            FileType::Sst => "SST",
+            FileType::VSst => "VSST",
+            FileType::Manifest => "MANIFEST",
+            FileType::Log => "LOG",
+            FileType::Trace => "TRACE",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+/// 文件无法识别或类型、版本与预期不符
+#[derive(Error, Debug, Clone, Eq, PartialEq)]
+pub enum FileFormatError {
+    #[error("{path:?} is not a lasagnedb {expected} file (bad magic number), check that the data directory is correct")]
+    BadMagic { path: PathBuf, expected: FileType },
+    #[error("{path:?} is a {actual} file, but a {expected} file is expected")]
+    WrongType {
+        path: PathBuf,
+        expected: FileType,
+        actual: FileType,
+    },
+    #[error("{path:?} has format version {version}, this build supports up to version {FORMAT_VERSION}, upgrade lasagnedb to open it")]
+    UnsupportedVersion { path: PathBuf, version: u8 },
+}
+
+/// 文件头，SST / VSST 放在文件末尾，MANIFEST 和 LOG 放在文件开头
+///
+/// layout
+/// ```text
+/// +-----------------+------------------+---------------------+--------------------+
+/// | magic(4 bytes)  | file type(1 byte)| format version(1 byte) | reserved(2 bytes) |
+/// +-----------------+------------------+---------------------+--------------------+
+/// ```
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub struct FileHeader {
+    pub file_type: FileType,
+    pub version: u8,
+}
+
+impl FileHeader {
+    pub fn new(file_type: FileType) -> Self {
+        Self {
+            file_type,
+            version: FORMAT_VERSION,
+        }
+    }
+
+    pub fn encode(&self) -> [u8; FILE_HEADER_SIZE] {
+        let mut buf = [0; FILE_HEADER_SIZE];
+        buf[..4].copy_from_slice(&FILE_MAGIC);
+        buf[4] = self.file_type.encode();
+        buf[5] = self.version;
+        buf
+    }
+
+    /// 解析文件头，`data` 不以魔数开头时返回 `None`
+    pub fn decode(path: &Path, data: &[u8]) -> Result<Option<Self>, FileFormatError> {
+        if data.len() < FILE_HEADER_SIZE || data[..4] != FILE_MAGIC {
+            return Ok(None);
+        }
+        let version = data[5];
+        if version > FORMAT_VERSION {
+            return Err(FileFormatError::UnsupportedVersion {
+                path: path.to_path_buf(),
+                version,
+            });
+        }
+        let file_type = FileType::decode(data[4]).ok_or(FileFormatError::UnsupportedVersion {
+            path: path.to_path_buf(),
+            version,
+        })?;
+        Ok(Some(Self { file_type, version }))
+    }
+
+    /// 校验文件头的类型，没有文件头时由 `is_legacy` 判断是否为加入文件头之前写入的旧文件
+    pub fn check(
+        path: &Path,
+        data: &[u8],
+        expected: FileType,
+        is_legacy: impl FnOnce(&[u8]) -> bool,
+    ) -> Result<Option<Self>, FileFormatError> {
+        match Self::decode(path, data)? {
+            Some(header) if header.file_type != expected => Err(FileFormatError::WrongType {
+                path: path.to_path_buf(),
+                expected,
+                actual: header.file_type,
+            }),
+            Some(header) => Ok(Some(header)),
+            None if is_legacy(data) => Ok(None),
+            None => Err(FileFormatError::BadMagic {
+                path: path.to_path_buf(),
+                expected,
+            }),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::storage::header::{FileFormatError, FileHeader, FileType, FORMAT_VERSION};
+    use std::path::Path;
+
+    #[test]
+    fn test_file_header() {
+        let path = Path::new("TEST");
+        for file_type in [
+            FileType::Sst,
+            FileType::VSst,
+            FileType::Manifest,
+            FileType::Log,
+            FileType::Trace,
+        ] {
+            let header = FileHeader::new(file_type);
+            assert_eq!(header.version, FORMAT_VERSION);
+            assert_eq!(
+                FileHeader::decode(path, &header.encode()).unwrap(),
+                Some(header)
+            );
+        }
+        assert_eq!(FileHeader::decode(path, b"short").unwrap(), None);
+        assert_eq!(FileHeader::decode(path, &[0; 16]).unwrap(), None);
+
+        let mut data = FileHeader::new(FileType::Log).encode();
+        data[5] = FORMAT_VERSION + 1;
+        assert_eq!(
+            FileHeader::decode(path, &data).unwrap_err(),
+            FileFormatError::UnsupportedVersion {
+                path: path.to_path_buf(),
+                version: FORMAT_VERSION + 1,
+            }
+        );
+
+        let data = FileHeader::new(FileType::Sst).encode();
+        assert!(matches!(
+            FileHeader::check(path, &data, FileType::Log, |_| true),
+            Err(FileFormatError::WrongType { .. })
+        ));
+        assert_eq!(
+            FileHeader::check(path, &[0; 16], FileType::Log, |_| true).unwrap(),
+            None
+        );
+        assert!(matches!(
+            FileHeader::check(path, &[0; 16], FileType::Log, |_| false),
+            Err(FileFormatError::BadMagic { .. })
+        ));
+    }
+}
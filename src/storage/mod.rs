@@ -1,3 +1,8 @@
+#[cfg(feature = "durability-check")]
+pub(crate) mod durability;
+#[cfg(feature = "failpoints")]
+pub mod fault;
 pub mod file;
+pub mod header;
 mod ioarc;
 pub mod storage;
@@ -0,0 +1,44 @@
+//! 数据库内部使用的系统 key 空间
+//!
+//! 系统 key 以 [`SYSTEM_PREFIX`] 开头，按用途分为不同的命名空间，例如 CDC 的投递进度和变更日志在 `cdc/` 下。
+//! 用户不能写入系统 key（拒绝的回调名为 [`crate::RESERVED_KEY_VALIDATOR`]），读取、范围查询、计数、前缀订阅和导出
+//! 都看不到它们。系统 key 与用户数据写入同一个 WAL 和 SST，随用户数据一起恢复、刷写和合并，
+//! 数据库自己的元数据需要与用户的写入原子地提交时放在这里，见 [`crate::Db::write_system`]
+use bytes::{BufMut, Bytes, BytesMut};
+
+/// 所有系统 key 的前缀，以 `0xff` 开头，排在绝大多数用户 key 之后
+pub(crate) const SYSTEM_PREFIX: &[u8] = b"\xff\xff\xfflasagnedb/";
+
+/// CDC 的投递进度和变更日志，见 [`crate::cdc`]
+pub(crate) const CDC_NAMESPACE: &str = "cdc";
+
+pub(crate) fn is_system_key(key: &[u8]) -> bool {
+    key.starts_with(SYSTEM_PREFIX)
+}
+
+/// 命名空间 `namespace` 中名为 `name` 的系统 key，即 `{SYSTEM_PREFIX}{namespace}/{name}`
+pub(crate) fn system_key(namespace: &str, name: &[u8]) -> Bytes {
+    let mut key = BytesMut::with_capacity(SYSTEM_PREFIX.len() + namespace.len() + 1 + name.len());
+    key.put_slice(SYSTEM_PREFIX);
+    key.put_slice(namespace.as_bytes());
+    key.put_u8(b'/');
+    key.put_slice(name);
+    key.freeze()
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::system::{is_system_key, system_key, CDC_NAMESPACE};
+
+    #[test]
+    fn test_system_key() {
+        // 已写入的 CDC 状态的 key 不能改变
+        assert_eq!(
+            system_key(CDC_NAMESPACE, b"offset"),
+            &b"\xff\xff\xfflasagnedb/cdc/offset"[..]
+        );
+        assert!(is_system_key(&system_key("stats", b"")));
+        assert!(!is_system_key(b"\xff\xff\xfflasagnedb"));
+        assert!(!is_system_key(b"lasagnedb/cdc/offset"));
+    }
+}
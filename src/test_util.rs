@@ -0,0 +1,327 @@
+//! 崩溃恢复测试工具，开启 `test-util` feature 后可用
+//!
+//! [`CrashTest`] 在同一个数据目录上反复执行「打开 -> 运行负载 -> 模拟崩溃 -> 重新打开并校验」，
+//! 检查恢复出的数据是否满足配置的 [`Durability`] 保证，嵌入方可以用自己的写入选项和负载验证其配置。
+//!
+//! [`RecoveryBench`] 写入指定规模的 WAL 积压后测量打开数据库的各阶段耗时，见 `benches/recovery_bench.rs`。
+//!
+//! 崩溃在进程内模拟：等待后台 flush / compaction 空闲后直接丢弃 `Db`，不调用 `close` 也不执行析构，
+//! 写缓冲中尚未刷写的 WAL 数据随之丢失。每次崩溃都会泄漏该 `Db` 占用的内存和后台线程，只适合在测试中使用
+//!
+//! [`golden`] 生成文件格式的 golden 样例。
+
+pub mod golden;
+
+use std::collections::BTreeMap;
+use std::fs;
+use std::ops::Bound::Unbounded;
+use std::path::{Path, PathBuf};
+use std::thread;
+use std::time::Duration;
+
+use bytes::Bytes;
+use thiserror::Error;
+
+use crate::batch::WriteBatch;
+use crate::db::{Db, DbOptions, WriteOptions};
+use crate::iterator::StorageIterator;
+use crate::stats::RecoveryStats;
+
+/// 崩溃后要求满足的持久性保证，任何时候调用 [`Workload::flush`] 之前的写入都必须恢复
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum Durability {
+    /// 所有已返回的写入都能恢复，要求写入开启 `sync` 且不跳过 WAL
+    AllAcknowledged,
+    /// 可能丢失最近的一部分写入，但恢复出的数据必须等于按顺序执行了写入序列的某个前缀的结果
+    Prefix,
+}
+
+/// 恢复出的数据不满足持久性保证
+#[derive(Error, Debug, Clone, Eq, PartialEq)]
+#[error("crash test cycle {cycle} violated durability: {reason}")]
+pub struct DurabilityViolation {
+    pub cycle: usize,
+    pub reason: String,
+}
+
+/// 一次崩溃测试的统计
+#[derive(Debug, Clone, Default, Eq, PartialEq)]
+pub struct CrashTestReport {
+    pub cycles: usize,
+    /// 负载执行的写入数
+    pub operations: usize,
+    /// 崩溃后丢失的已返回写入数，`Durability::AllAcknowledged` 时总是 0
+    pub lost_operations: usize,
+}
+
+/// 负载在一个周期内使用的写入接口，通过它执行的写入会被记录下来，用于崩溃后校验
+pub struct Workload<'a> {
+    db: &'a Db,
+    options: &'a WriteOptions,
+    cycle: usize,
+    ops: Vec<(Bytes, Option<Bytes>)>,
+    // ops 中前 durable 个写入在崩溃后必须可见
+    durable: usize,
+}
+
+impl<'a> Workload<'a> {
+    /// 用于读取，绕过 [`Workload`] 直接写入的数据不参与校验
+    pub fn db(&self) -> &Db {
+        self.db
+    }
+
+    /// 当前周期，从 0 开始
+    pub fn cycle(&self) -> usize {
+        self.cycle
+    }
+
+    pub fn put(&mut self, key: Bytes, value: Bytes) -> anyhow::Result<()> {
+        self.db
+            .put_with_options(key.clone(), value.clone(), self.options)?;
+        self.ops.push((key, Some(value)));
+        Ok(())
+    }
+
+    pub fn delete(&mut self, key: Bytes) -> anyhow::Result<()> {
+        self.db.delete_with_options(key.clone(), self.options)?;
+        self.ops.push((key, None));
+        Ok(())
+    }
+
+    /// 将 memtable 刷写到 SST，之前的写入在崩溃后都必须可见
+    pub fn flush(&mut self) -> anyhow::Result<()> {
+        self.db.flush()?;
+        self.durable = self.ops.len();
+        Ok(())
+    }
+}
+
+/// 在 `path` 上反复运行负载并模拟崩溃，见[模块文档](self)
+pub struct CrashTest<F> {
+    path: PathBuf,
+    cycles: usize,
+    options: WriteOptions,
+    durability: Durability,
+    workload: F,
+}
+
+impl<F> CrashTest<F>
+where
+    F: FnMut(&mut Workload) -> anyhow::Result<()>,
+{
+    /// 默认运行 3 个周期，使用默认的写入选项并要求 `Durability::AllAcknowledged`
+    pub fn new(path: impl AsRef<Path>, workload: F) -> Self {
+        Self {
+            path: PathBuf::from(path.as_ref()),
+            cycles: 3,
+            options: WriteOptions::default(),
+            durability: Durability::AllAcknowledged,
+            workload,
+        }
+    }
+
+    pub fn cycles(mut self, cycles: usize) -> Self {
+        self.cycles = cycles;
+        self
+    }
+
+    pub fn write_options(mut self, options: WriteOptions) -> Self {
+        self.options = options;
+        self
+    }
+
+    pub fn durability(mut self, durability: Durability) -> Self {
+        self.durability = durability;
+        self
+    }
+
+    /// 运行所有周期，恢复出的数据不满足保证时返回 [`DurabilityViolation`]
+    pub fn run(mut self) -> anyhow::Result<CrashTestReport> {
+        let mut report = CrashTestReport::default();
+        let mut expected = {
+            let db = Db::open_file(&self.path)?;
+            let data = Self::read_all(&db)?;
+            Self::crash(db);
+            data
+        };
+
+        for cycle in 0..self.cycles {
+            let db = Db::open_file(&self.path)?;
+            let mut workload = Workload {
+                db: &db,
+                options: &self.options,
+                cycle,
+                ops: vec![],
+                durable: 0,
+            };
+            (self.workload)(&mut workload)?;
+            let (ops, durable) = (workload.ops, workload.durable);
+            Self::crash(db);
+
+            let db = Db::open_file(&self.path)?;
+            let recovered = Self::read_all(&db)?;
+            Self::crash(db);
+
+            let applied = self.check(cycle, &expected, &ops, durable, &recovered)?;
+            report.cycles += 1;
+            report.operations += ops.len();
+            report.lost_operations += ops.len() - applied;
+            expected = recovered;
+        }
+        Ok(report)
+    }
+
+    /// 返回恢复出的数据对应的写入前缀长度
+    fn check(
+        &self,
+        cycle: usize,
+        base: &BTreeMap<Bytes, Bytes>,
+        ops: &[(Bytes, Option<Bytes>)],
+        durable: usize,
+        recovered: &BTreeMap<Bytes, Bytes>,
+    ) -> Result<usize, DurabilityViolation> {
+        let min_applied = match self.durability {
+            Durability::AllAcknowledged => ops.len(),
+            Durability::Prefix => durable,
+        };
+        let mut state = base.clone();
+        for (applied, op) in ops.iter().enumerate() {
+            if applied >= min_applied && &state == recovered {
+                return Ok(applied);
+            }
+            match op {
+                (key, Some(value)) => state.insert(key.clone(), value.clone()),
+                (key, None) => state.remove(key),
+            };
+        }
+        if &state == recovered {
+            return Ok(ops.len());
+        }
+
+        let reason = match state
+            .iter()
+            .find(|(key, value)| recovered.get(*key) != Some(*value))
+        {
+            Some((key, value)) => format!(
+                "expect {:?} = {:?} after all acknowledged writes, recovered {:?}",
+                key,
+                value,
+                recovered.get(key)
+            ),
+            None => format!(
+                "recovered {} keys, expect {} after all acknowledged writes",
+                recovered.len(),
+                state.len()
+            ),
+        };
+        Err(DurabilityViolation { cycle, reason })
+    }
+
+    fn read_all(db: &Db) -> anyhow::Result<BTreeMap<Bytes, Bytes>> {
+        let mut data = BTreeMap::new();
+        let mut iter = db.scan(Unbounded, Unbounded)?;
+        while iter.is_valid() {
+            data.insert(
+                Bytes::copy_from_slice(iter.key()),
+                Bytes::copy_from_slice(iter.value()),
+            );
+            iter.next()?;
+        }
+        Ok(data)
+    }
+
+    /// 等待后台任务空闲后丢弃 `db`，不刷写任何缓冲
+    fn crash(mut db: Db) {
+        loop {
+            let stats = db.stats();
+            let idle = |queue: &crate::QueueStats| queue.pending == 0 && !queue.running;
+            if idle(&stats.flush_queue) && idle(&stats.compaction_queue) {
+                break;
+            }
+            thread::sleep(Duration::from_millis(1));
+        }
+        // 进程退出后登记随之消失，重新打开不应被拒绝
+        db.registration.release();
+        std::mem::forget(db);
+    }
+}
+
+/// 恢复性能测试：在 `path` 上写入指定规模的 WAL 积压，然后测量打开数据库的各阶段耗时
+///
+/// 积压的写入全部留在 WAL 中，打开时逐条重放，用于发现恢复性能的退化，
+/// 以及按可接受的恢复时间选择 `memtable_size_limit` 和 WAL 分段限制
+pub struct RecoveryBench {
+    path: PathBuf,
+    options: DbOptions,
+    records: usize,
+    batch_size: usize,
+    value_size: usize,
+}
+
+impl RecoveryBench {
+    /// 默认写入 10000 条记录，每条记录一个 100 字节的 value
+    pub fn new(path: impl AsRef<Path>) -> Self {
+        Self {
+            path: PathBuf::from(path.as_ref()),
+            options: DbOptions::default(),
+            records: 10000,
+            batch_size: 1,
+            value_size: 100,
+        }
+    }
+
+    /// 打开数据库使用的选项，写入积压时忽略 `memtable_size_limit`
+    pub fn options(mut self, options: DbOptions) -> Self {
+        self.options = options;
+        self
+    }
+
+    /// WAL 中的记录数
+    pub fn records(mut self, records: usize) -> Self {
+        self.records = records;
+        self
+    }
+
+    /// 每条记录（一个批次）中的写入数
+    pub fn batch_size(mut self, batch_size: usize) -> Self {
+        self.batch_size = batch_size;
+        self
+    }
+
+    pub fn value_size(mut self, value_size: usize) -> Self {
+        self.value_size = value_size;
+        self
+    }
+
+    /// 在数据目录中写入积压，期间不刷写 memtable，应在空目录上调用一次
+    pub fn populate(&self) -> anyhow::Result<()> {
+        fs::create_dir_all(&self.path)?;
+        let options = DbOptions {
+            memtable_size_limit: usize::MAX,
+            ..self.options.clone()
+        };
+        let db = Db::open_with_options(&self.path, options)?;
+        let write_options = WriteOptions {
+            sync: false,
+            ..Default::default()
+        };
+        let value = Bytes::from(vec![b'v'; self.value_size]);
+        for record in 0..self.records {
+            let mut batch = WriteBatch::new();
+            for i in 0..self.batch_size {
+                let key = format!("key{:012}", record * self.batch_size + i);
+                batch.put(Bytes::from(key), value.clone())?;
+            }
+            db.write_with_options(batch, &write_options)?;
+        }
+        db.close()
+    }
+
+    /// 打开一次数据库并返回恢复的各阶段耗时，不启动后台任务，返回前关闭数据库，积压仍留在 WAL 中
+    pub fn measure(&self) -> anyhow::Result<RecoveryStats> {
+        let db = Db::open_with_options(&self.path, self.options.clone())?;
+        let recovery = db.stats().recovery;
+        db.close()?;
+        Ok(recovery)
+    }
+}
@@ -0,0 +1,167 @@
+//! 文件格式的 golden 测试工具
+//!
+//! [`generate`] 用固定的输入写出一个完整的数据目录：刷写格式的 SST、合并输出的 SST、VSST、WAL、MANIFEST 和 CURRENT。
+//! 生成过程不依赖时间、随机数和 `HashMap` 的遍历顺序，bloom filter 使用固定的种子 [`FILTER_SEED`]，
+//! 同一版本每次生成的文件逐字节相同。
+//! 把生成的文件检入仓库作为样例后：
+//!
+//! - 与当前版本生成的文件逐字节比较，格式的意外变化会被发现；有意修改格式时重新生成样例，并保留旧样例用于兼容性测试
+//! - 用当前版本打开旧版本生成的样例，检查读出的数据等于 [`expected_contents`]，验证跨版本兼容
+//!
+//! 见 `tests/golden_format.rs`
+
+use std::collections::{BTreeMap, HashMap};
+use std::fs;
+use std::path::Path;
+use std::sync::Arc;
+
+use bytes::Bytes;
+use parking_lot::RwLock;
+
+use crate::cache::BlockCache;
+use crate::daemon::{DbDaemon, MergeParams};
+use crate::db::Db;
+use crate::entry::{Entry, EntryBuilder};
+use crate::inspect::Inspection;
+use crate::meta::manifest::{Manifest, ManifestItem};
+use crate::record::RecordBuilder;
+use crate::sstable::builder::SsTableBuilder;
+use crate::storage::header::FileType;
+use crate::wal::Journal;
+use crate::{OpType, KB, VSST_BLOCK_SIZE};
+
+/// [`generate`] 写出的文件，CURRENT 指向 MANIFEST
+pub const GOLDEN_FILES: [&str; 6] = [
+    "CURRENT",
+    "00001.MANIFEST",
+    "00001.VSST",
+    "00002.SST",
+    "00003.SST",
+    "00004.LOG",
+];
+
+const VSST_ID: u64 = 1;
+const OLDER_SST_ID: u64 = 1;
+const NEWER_SST_ID: u64 = 2;
+const LOG_ID: u64 = 4;
+const COMMIT_SEQ: u64 = 20;
+/// 生成的 SST 和 VSST 的 bloom filter 种子，种子随 filter 写入文件，随机种子会让每次生成的文件不同
+pub const FILTER_SEED: [u8; 32] = [0x5a; 32];
+
+fn put(key: &'static str, value: &'static str) -> Entry {
+    EntryBuilder::new()
+        .op_type(OpType::Put)
+        .key_value(Bytes::from(key), Bytes::from(value))
+        .build()
+}
+
+fn delete(key: &'static str) -> Entry {
+    EntryBuilder::new()
+        .op_type(OpType::Delete)
+        .key_value(Bytes::from(key), Bytes::new())
+        .build()
+}
+
+fn separated_value() -> Bytes {
+    Bytes::from(vec![b's'; 300])
+}
+
+/// 在空目录 `dir` 中写出 [`GOLDEN_FILES`]
+///
+/// - `00001.VSST`：`k03` 的 value
+/// - `00002.SST`：L0，刷写格式，`k01` 覆盖写、删除 `k02`、`k03` 的 value 分离到 `00001.VSST`
+/// - `00003.SST`：L1，`00002.SST` 与更早的 `00001.SST` 的合并输出，合并后 `00001.SST` 被删除
+/// - `00004.LOG`：当前 WAL，两条记录
+/// - `00001.MANIFEST`：一条记录，描述上述文件和 VSST 的引用计数
+pub fn generate(dir: impl AsRef<Path>) -> anyhow::Result<()> {
+    let dir = dir.as_ref();
+    fs::create_dir_all(dir)?;
+    let cache = Arc::new(BlockCache::new(64 * KB as u64));
+
+    let mut vsst_builder = SsTableBuilder::new()
+        .with_block_size(VSST_BLOCK_SIZE)
+        .with_file_type(FileType::VSst)
+        .with_filter_seed(Some(FILTER_SEED));
+    vsst_builder.add(
+        &EntryBuilder::new()
+            .op_type(OpType::Put)
+            .key_value(Bytes::from("k03"), separated_value())
+            .build(),
+    );
+    let vsst = vsst_builder.build(VSST_ID, None, Db::path_of_vsst(dir, VSST_ID))?;
+
+    let mut older = SsTableBuilder::new()
+        .with_max_seq(10)
+        .with_filter_seed(Some(FILTER_SEED));
+    for entry in [put("k01", "v1-old"), put("k02", "v2"), put("k04", "v4")] {
+        older.add(&entry);
+    }
+    let older = older.build(OLDER_SST_ID, None, Db::path_of_sst(dir, OLDER_SST_ID))?;
+
+    let mut newer = SsTableBuilder::new()
+        .with_max_seq(COMMIT_SEQ)
+        .with_filter_seed(Some(FILTER_SEED));
+    let separated = EntryBuilder::new()
+        .op_type(OpType::Put)
+        .kv_separate(true)
+        .key_value(
+            Bytes::from("k03"),
+            Entry::separated_value(VSST_ID, separated_value().len() as u64),
+        )
+        .build();
+    for entry in [put("k01", "v1"), delete("k02"), separated, put("k05", "v5")] {
+        newer.add(&entry);
+    }
+    let newer = newer.build(NEWER_SST_ID, None, Db::path_of_sst(dir, NEWER_SST_ID))?;
+
+    // 较新的输入排在前面，key 相同时取它的版本，输入顺序固定则输出固定
+    let (merged, _, _, _) = DbDaemon::merge(
+        MergeParams {
+            now_sst_id: NEWER_SST_ID,
+            now_vsst_id: VSST_ID,
+            vssts: Arc::new(RwLock::new(HashMap::from([(VSST_ID, Arc::new(vsst))]))),
+            vsst_rc: Arc::new(RwLock::new(HashMap::from([(VSST_ID, 1)]))),
+            filter_seed: Some(FILTER_SEED),
+            ..MergeParams::new(dir, cache)
+        },
+        vec![Arc::new(newer), Arc::new(older)],
+        &mut Inspection::disabled(),
+    )?;
+    anyhow::ensure!(
+        merged.len() == 1,
+        "golden compaction produced {} SSTs",
+        merged.len()
+    );
+    fs::remove_file(Db::path_of_sst(dir, OLDER_SST_ID))?;
+
+    let wal = Journal::open(LOG_ID, Db::path_of_wal(dir, LOG_ID))?;
+    wal.write(vec![put("k07", "v7"), delete("k05")])?;
+    wal.write(vec![put("k08", "v8")])?;
+    wal.sync_all()?;
+
+    let mut manifest = Manifest::open(Db::path_of_manifest(dir, 1))?;
+    let mut r = RecordBuilder::new();
+    r.add(ManifestItem::Init(1));
+    r.add(ManifestItem::FreezeAndCreateWal(LOG_ID, LOG_ID));
+    r.add(ManifestItem::CommitSeq(COMMIT_SEQ));
+    r.add(ManifestItem::NewSst(0, NEWER_SST_ID));
+    r.add(ManifestItem::NewSst(1, merged[0].id()));
+    r.add(ManifestItem::NewVSst(VSST_ID));
+    r.add(ManifestItem::VSstRefCnt(VSST_ID, 2));
+    manifest.add(&r.build())?;
+    manifest.sync_all()?;
+
+    fs::write(Db::path_of_current(dir), "00001.MANIFEST")?;
+    Ok(())
+}
+
+/// 打开 [`generate`] 生成的数据目录后应当读出的数据
+pub fn expected_contents() -> BTreeMap<Bytes, Bytes> {
+    BTreeMap::from([
+        (Bytes::from("k01"), Bytes::from("v1")),
+        (Bytes::from("k03"), separated_value()),
+        (Bytes::from("k04"), Bytes::from("v4")),
+        (Bytes::from("k07"), Bytes::from("v7")),
+        (Bytes::from("k08"), Bytes::from("v8")),
+    ])
+}
@@ -0,0 +1,595 @@
+//! 操作记录：把数据库上的写入、get 和范围查询按时间顺序记录到文件，之后在另一个数据库上重放，
+//! 用于以真实的访问模式做基准测试和复现问题
+//!
+//! [`crate::Db::start_trace`] 开始记录，[`crate::Db::stop_trace`] 或关闭数据库时结束。
+//! 记录只保存 key、value 的长度和操作距开始记录的时间，不保存 value 的内容，重放时写入同样长度的随机 value。
+//! 批量读取中的每个 key 记为一次 get；范围查询只记录上下界，重放时最多读取 [`ReplayOptions::scan_limit`] 个 KV。
+//! 数据库内部使用的系统 key 不会被记录
+//!
+//! ```
+//! use std::ops::Bound;
+//!
+//! use bytes::Bytes;
+//! use lasagnedb::trace::{self, ReplayOptions, TraceOptions};
+//! use lasagnedb::Db;
+//!
+//! let dir = tempfile::tempdir().unwrap();
+//! let trace_path = dir.path().join("ops.trace");
+//! let db = Db::open_file(dir.path().join("db")).unwrap();
+//! db.start_trace(&trace_path, TraceOptions::default()).unwrap();
+//! db.put(Bytes::from("k"), Bytes::from("value")).unwrap();
+//! db.get(&Bytes::from("k")).unwrap();
+//! db.scan(Bound::Unbounded, Bound::Unbounded).unwrap();
+//! assert_eq!(db.stop_trace().unwrap().records, 3);
+//!
+//! let fresh = Db::open_file(dir.path().join("fresh")).unwrap();
+//! let report = trace::replay(&trace_path, &fresh, &ReplayOptions::default()).unwrap();
+//! assert_eq!((report.writes, report.gets, report.scans), (1, 1, 1));
+//! assert_eq!(fresh.get(&Bytes::from("k")).unwrap().unwrap().len(), 5);
+//! ```
+
+use std::fs::{File, OpenOptions};
+use std::io::{BufReader, BufWriter, ErrorKind, Read, Write};
+use std::ops::Bound;
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use anyhow::anyhow;
+use bytes::{Buf, BufMut, Bytes, BytesMut};
+use parking_lot::Mutex;
+use rand::{RngCore, SeedableRng};
+use rand_chacha::ChaCha8Rng;
+use tracing::warn;
+
+use crate::batch::WriteBatch;
+use crate::entry::Entry;
+use crate::storage::header::{FileHeader, FileType, FILE_HEADER_SIZE};
+use crate::system::is_system_key;
+use crate::{Db, OpType, StorageIterator, TRACE_REPLAY_SCAN_LIMIT};
+
+const OP_WRITE: u8 = 1;
+const OP_GET: u8 = 2;
+const OP_SCAN: u8 = 3;
+
+/// 记录的选项，见 [`crate::Db::start_trace`]
+#[derive(Debug, Clone, Default)]
+pub struct TraceOptions {
+    /// 记录文件达到该大小（字节）后不再记录，为 `None` 时不限制，默认为 `None`
+    pub max_file_size: Option<u64>,
+}
+
+/// 一次写入中的一个 put / delete
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TraceWrite {
+    pub key: Bytes,
+    /// put 的 value 长度，delete 为 `None`
+    pub value_size: Option<u32>,
+}
+
+/// 记录的一次操作
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum TraceOp {
+    /// 一次提交的全部 put / delete，批量写入和分组提交的写入记为一次写入
+    Write(Vec<TraceWrite>),
+    /// 读取一个 key，`value_size` 为读到的 value 的长度，不存在时为 `None`
+    Get { key: Bytes, value_size: Option<u32> },
+    /// 范围查询的上下界
+    Scan {
+        lower: Bound<Bytes>,
+        upper: Bound<Bytes>,
+    },
+}
+
+/// 记录文件中的一条记录
+///
+/// layout，记录文件以 [`FileHeader`] 开头，之后是连续的记录，len 为 len 之后的字节数
+/// ```text
+/// +--------------+------------+-------------------------+---------+
+/// | len(4 bytes) | op(1 byte) | elapsed micros(8 bytes) | payload |
+/// +--------------+------------+-------------------------+---------+
+/// ```
+/// - write：`count(4 bytes)` 之后是 `count` 个 `op type(1 byte) | key len(4 bytes) | key | value len(4 bytes)`，
+///   delete 没有 value len
+/// - get：`key len(4 bytes) | key | found(1 byte) | value len(4 bytes)`
+/// - scan：下界和上界，各为 `kind(1 byte) | key len(4 bytes) | key`，无界时只有 kind
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TraceRecord {
+    /// 距开始记录的时间
+    pub elapsed: Duration,
+    pub op: TraceOp,
+}
+
+impl TraceRecord {
+    fn encode(&self, buf: &mut BytesMut) {
+        let start = buf.len();
+        buf.put_u32_le(0);
+        match &self.op {
+            TraceOp::Write(_) => buf.put_u8(OP_WRITE),
+            TraceOp::Get { .. } => buf.put_u8(OP_GET),
+            TraceOp::Scan { .. } => buf.put_u8(OP_SCAN),
+        }
+        buf.put_u64_le(self.elapsed.as_micros() as u64);
+        match &self.op {
+            TraceOp::Write(writes) => {
+                buf.put_u32_le(writes.len() as u32);
+                for write in writes {
+                    match write.value_size {
+                        Some(_) => buf.put_u8(OpType::Put as u8),
+                        None => buf.put_u8(OpType::Delete as u8),
+                    }
+                    put_key(buf, &write.key);
+                    if let Some(value_size) = write.value_size {
+                        buf.put_u32_le(value_size);
+                    }
+                }
+            }
+            TraceOp::Get { key, value_size } => {
+                put_key(buf, key);
+                buf.put_u8(value_size.is_some() as u8);
+                buf.put_u32_le(value_size.unwrap_or(0));
+            }
+            TraceOp::Scan { lower, upper } => {
+                put_bound(buf, lower);
+                put_bound(buf, upper);
+            }
+        }
+        let len = (buf.len() - start - 4) as u32;
+        buf[start..start + 4].copy_from_slice(&len.to_le_bytes());
+    }
+
+    /// 解码 len 之后的部分
+    fn decode(mut data: Bytes) -> anyhow::Result<Self> {
+        let truncated = || anyhow!("trace record is truncated");
+        if data.remaining() < 9 {
+            return Err(truncated());
+        }
+        let op = data.get_u8();
+        let elapsed = Duration::from_micros(data.get_u64_le());
+        let op = match op {
+            OP_WRITE => {
+                let count = get_u32(&mut data).ok_or_else(truncated)?;
+                let mut writes = Vec::with_capacity(count.min(1024) as usize);
+                for _ in 0..count {
+                    let op_type = get_u8(&mut data).ok_or_else(truncated)?;
+                    let key = get_key(&mut data).ok_or_else(truncated)?;
+                    let value_size = match OpType::from(op_type) {
+                        OpType::Put => Some(get_u32(&mut data).ok_or_else(truncated)?),
+                        OpType::Delete => None,
+                        OpType::Get => return Err(anyhow!("invalid trace write type {}", op_type)),
+                    };
+                    writes.push(TraceWrite { key, value_size });
+                }
+                TraceOp::Write(writes)
+            }
+            OP_GET => {
+                let key = get_key(&mut data).ok_or_else(truncated)?;
+                let found = get_u8(&mut data).ok_or_else(truncated)? != 0;
+                let value_size = get_u32(&mut data).ok_or_else(truncated)?;
+                TraceOp::Get {
+                    key,
+                    value_size: found.then_some(value_size),
+                }
+            }
+            OP_SCAN => TraceOp::Scan {
+                lower: get_bound(&mut data)?.ok_or_else(truncated)?,
+                upper: get_bound(&mut data)?.ok_or_else(truncated)?,
+            },
+            _ => return Err(anyhow!("unknown trace op {}", op)),
+        };
+        Ok(Self { elapsed, op })
+    }
+}
+
+fn put_key(buf: &mut BytesMut, key: &[u8]) {
+    buf.put_u32_le(key.len() as u32);
+    buf.put_slice(key);
+}
+
+fn put_bound(buf: &mut BytesMut, bound: &Bound<Bytes>) {
+    match bound {
+        Bound::Unbounded => buf.put_u8(0),
+        Bound::Included(key) => {
+            buf.put_u8(1);
+            put_key(buf, key);
+        }
+        Bound::Excluded(key) => {
+            buf.put_u8(2);
+            put_key(buf, key);
+        }
+    }
+}
+
+fn get_u8(data: &mut Bytes) -> Option<u8> {
+    (data.remaining() >= 1).then(|| data.get_u8())
+}
+
+fn get_u32(data: &mut Bytes) -> Option<u32> {
+    (data.remaining() >= 4).then(|| data.get_u32_le())
+}
+
+fn get_key(data: &mut Bytes) -> Option<Bytes> {
+    let len = get_u32(data)? as usize;
+    (data.remaining() >= len).then(|| data.split_to(len))
+}
+
+fn get_bound(data: &mut Bytes) -> anyhow::Result<Option<Bound<Bytes>>> {
+    Ok(match get_u8(data) {
+        None => None,
+        Some(0) => Some(Bound::Unbounded),
+        Some(1) => get_key(data).map(Bound::Included),
+        Some(2) => get_key(data).map(Bound::Excluded),
+        Some(kind) => return Err(anyhow!("invalid trace bound kind {}", kind)),
+    })
+}
+
+/// 一次记录的概要，见 [`crate::Db::stop_trace`]
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct TraceSummary {
+    pub records: u64,
+    /// 记录文件的大小（字节），包括文件头
+    pub file_size: u64,
+    /// 是否因达到 [`TraceOptions::max_file_size`] 或写入文件失败而提前停止记录
+    pub truncated: bool,
+}
+
+/// 数据库上进行中的记录
+#[derive(Default)]
+pub(crate) struct Tracer {
+    // 没有进行中的记录时操作只检查这个标记
+    active: AtomicBool,
+    writer: Mutex<Option<TraceWriter>>,
+}
+
+struct TraceWriter {
+    file: BufWriter<File>,
+    started_at: Instant,
+    options: TraceOptions,
+    summary: TraceSummary,
+}
+
+impl Tracer {
+    pub(crate) fn start(&self, path: &Path, options: TraceOptions) -> anyhow::Result<()> {
+        let mut writer = self.writer.lock();
+        if writer.is_some() {
+            return Err(anyhow!("a trace is already in progress"));
+        }
+        let mut file = BufWriter::new(OpenOptions::new().write(true).create_new(true).open(path)?);
+        file.write_all(&FileHeader::new(FileType::Trace).encode())?;
+        *writer = Some(TraceWriter {
+            file,
+            started_at: Instant::now(),
+            options,
+            summary: TraceSummary {
+                file_size: FILE_HEADER_SIZE as u64,
+                ..TraceSummary::default()
+            },
+        });
+        self.active.store(true, Ordering::Release);
+        Ok(())
+    }
+
+    /// 结束记录，没有进行中的记录时返回 `None`
+    pub(crate) fn stop(&self) -> anyhow::Result<Option<TraceSummary>> {
+        let Some(writer) = self.writer.lock().take() else {
+            return Ok(None);
+        };
+        self.active.store(false, Ordering::Release);
+        let file = writer.file.into_inner().map_err(|e| e.into_error())?;
+        file.sync_all()?;
+        Ok(Some(writer.summary))
+    }
+
+    /// 有进行中的记录时记录 `op` 返回的操作，返回 `None` 时不记录
+    #[inline]
+    pub(crate) fn record(&self, op: impl FnOnce() -> Option<TraceOp>) {
+        if !self.active.load(Ordering::Acquire) {
+            return;
+        }
+        let mut guard = self.writer.lock();
+        let Some(writer) = guard.as_mut().filter(|writer| !writer.summary.truncated) else {
+            return;
+        };
+        let Some(op) = op() else {
+            return;
+        };
+        let mut buf = BytesMut::new();
+        TraceRecord {
+            elapsed: writer.started_at.elapsed(),
+            op,
+        }
+        .encode(&mut buf);
+        let file_size = writer.summary.file_size + buf.len() as u64;
+        let result = match writer.options.max_file_size {
+            Some(max_file_size) if file_size > max_file_size => Ok(false),
+            _ => writer.file.write_all(&buf).map(|_| true),
+        };
+        match result {
+            Ok(true) => {
+                writer.summary.records += 1;
+                writer.summary.file_size = file_size;
+            }
+            Ok(false) => {
+                warn!("trace file reaches size limit, stop tracing");
+                writer.summary.truncated = true;
+                self.active.store(false, Ordering::Release);
+            }
+            Err(e) => {
+                warn!("write trace failed, stop tracing: {}", e);
+                writer.summary.truncated = true;
+                self.active.store(false, Ordering::Release);
+            }
+        }
+    }
+
+    /// 记录一次提交的写入，不包括幂等 token 和系统 key
+    pub(crate) fn record_write(&self, entries: &[Entry]) {
+        self.record(|| {
+            let writes: Vec<_> = entries
+                .iter()
+                .filter(|e| !e.is_idempotency_token() && !is_system_key(&e.key))
+                .map(|e| TraceWrite {
+                    key: e.key.clone(),
+                    value_size: (e.op_type() == OpType::Put).then_some(e.value.len() as u32),
+                })
+                .collect();
+            (!writes.is_empty()).then_some(TraceOp::Write(writes))
+        })
+    }
+
+    pub(crate) fn record_get(&self, key: &Bytes, value_size: Option<usize>) {
+        self.record(|| {
+            Some(TraceOp::Get {
+                key: key.clone(),
+                value_size: value_size.map(|size| size as u32),
+            })
+        })
+    }
+
+    pub(crate) fn record_scan(&self, lower: &Bound<Bytes>, upper: &Bound<Bytes>) {
+        self.record(|| {
+            Some(TraceOp::Scan {
+                lower: lower.clone(),
+                upper: upper.clone(),
+            })
+        })
+    }
+}
+
+impl std::fmt::Debug for Tracer {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Tracer")
+            .field("active", &self.active.load(Ordering::Relaxed))
+            .finish()
+    }
+}
+
+/// 按顺序读取记录文件中的记录
+///
+/// 记录过程中进程退出时最后一条记录可能不完整，读到不完整的记录时结束，见 [`TraceReader::truncated`]
+pub struct TraceReader {
+    file: BufReader<File>,
+    truncated: bool,
+    done: bool,
+}
+
+impl TraceReader {
+    pub fn open(path: impl AsRef<Path>) -> anyhow::Result<Self> {
+        let path = path.as_ref();
+        let mut file = BufReader::new(File::open(path)?);
+        let mut header = [0; FILE_HEADER_SIZE];
+        file.read_exact(&mut header)?;
+        FileHeader::check(path, &header, FileType::Trace, |_| false)?;
+        Ok(Self {
+            file,
+            truncated: false,
+            done: false,
+        })
+    }
+
+    /// 是否因最后一条记录不完整而提前结束
+    pub fn truncated(&self) -> bool {
+        self.truncated
+    }
+
+    fn read_record(&mut self) -> anyhow::Result<Option<TraceRecord>> {
+        let mut len = [0; 4];
+        let mut filled = 0;
+        while filled < len.len() {
+            match self.file.read(&mut len[filled..])? {
+                0 => break,
+                n => filled += n,
+            }
+        }
+        // 正好在记录之间结束时没有截断
+        if filled < len.len() {
+            self.truncated = filled > 0;
+            return Ok(None);
+        }
+        let mut data = vec![0; u32::from_le_bytes(len) as usize];
+        match self.file.read_exact(&mut data) {
+            Ok(()) => TraceRecord::decode(Bytes::from(data)).map(Some),
+            Err(e) if e.kind() == ErrorKind::UnexpectedEof => {
+                self.truncated = true;
+                Ok(None)
+            }
+            Err(e) => Err(e.into()),
+        }
+    }
+}
+
+impl Iterator for TraceReader {
+    type Item = anyhow::Result<TraceRecord>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.done {
+            return None;
+        }
+        let record = self.read_record().transpose();
+        if !matches!(record, Some(Ok(_))) {
+            self.done = true;
+        }
+        record
+    }
+}
+
+/// 重放的选项，见 [`replay`]
+#[derive(Debug, Clone)]
+pub struct ReplayOptions {
+    /// 按记录中的时间间隔执行操作，默认关闭，尽快执行全部操作
+    pub preserve_timing: bool,
+    /// 每个范围查询最多读取的 KV 数，默认为 [`TRACE_REPLAY_SCAN_LIMIT`]
+    pub scan_limit: usize,
+}
+
+impl Default for ReplayOptions {
+    fn default() -> Self {
+        Self {
+            preserve_timing: false,
+            scan_limit: TRACE_REPLAY_SCAN_LIMIT,
+        }
+    }
+}
+
+/// 一次重放的结果，见 [`replay`]
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ReplayReport {
+    pub writes: u64,
+    pub gets: u64,
+    pub scans: u64,
+    /// key 是否存在与记录中不一致的 get 次数，在空数据库上重放记录时之前已有的数据读不到
+    pub get_mismatches: u64,
+    /// 记录文件的最后一条记录是否不完整
+    pub truncated: bool,
+    pub elapsed: Duration,
+}
+
+/// 在 `db` 上按顺序重放 `path` 中记录的操作，写入的 value 是固定种子生成的随机字节，每次重放写入的内容相同
+pub fn replay(
+    path: impl AsRef<Path>,
+    db: &Db,
+    options: &ReplayOptions,
+) -> anyhow::Result<ReplayReport> {
+    let mut reader = TraceReader::open(path)?;
+    let mut rng = ChaCha8Rng::seed_from_u64(0);
+    let mut value = |size: u32| {
+        let mut value = vec![0; size as usize];
+        rng.fill_bytes(&mut value);
+        Bytes::from(value)
+    };
+    let mut report = ReplayReport::default();
+    let started_at = Instant::now();
+    for record in &mut reader {
+        let record = record?;
+        if options.preserve_timing {
+            if let Some(delay) = record.elapsed.checked_sub(started_at.elapsed()) {
+                thread::sleep(delay);
+            }
+        }
+        match record.op {
+            TraceOp::Write(mut writes) => {
+                report.writes += 1;
+                if writes.len() == 1 {
+                    let TraceWrite { key, value_size } = writes.pop().unwrap();
+                    match value_size {
+                        Some(size) => db.put(key, value(size))?,
+                        None => db.delete(key)?,
+                    };
+                } else {
+                    let mut batch = WriteBatch::new();
+                    for TraceWrite { key, value_size } in writes {
+                        match value_size {
+                            Some(size) => batch.put(key, value(size))?,
+                            None => batch.delete(key)?,
+                        };
+                    }
+                    db.write(batch)?;
+                }
+            }
+            TraceOp::Get { key, value_size } => {
+                report.gets += 1;
+                if db.get(&key)?.is_some() != value_size.is_some() {
+                    report.get_mismatches += 1;
+                }
+            }
+            TraceOp::Scan { lower, upper } => {
+                report.scans += 1;
+                let mut iter = db.scan(lower, upper)?;
+                let mut n = 0;
+                while iter.is_valid() && n < options.scan_limit {
+                    n += 1;
+                    iter.next()?;
+                }
+            }
+        }
+    }
+    report.truncated = reader.truncated();
+    report.elapsed = started_at.elapsed();
+    Ok(report)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::ops::Bound;
+    use std::time::Duration;
+
+    use bytes::{Bytes, BytesMut};
+
+    use crate::storage::header::{FileHeader, FileType};
+    use crate::trace::{TraceOp, TraceReader, TraceRecord, TraceWrite};
+
+    #[test]
+    fn test_trace_record() {
+        let records = vec![
+            TraceRecord {
+                elapsed: Duration::from_micros(1),
+                op: TraceOp::Write(vec![
+                    TraceWrite {
+                        key: Bytes::from("k1"),
+                        value_size: Some(10),
+                    },
+                    TraceWrite {
+                        key: Bytes::from("k2"),
+                        value_size: None,
+                    },
+                ]),
+            },
+            TraceRecord {
+                elapsed: Duration::from_micros(2),
+                op: TraceOp::Get {
+                    key: Bytes::from("k1"),
+                    value_size: Some(0),
+                },
+            },
+            TraceRecord {
+                elapsed: Duration::from_micros(3),
+                op: TraceOp::Scan {
+                    lower: Bound::Included(Bytes::from("a")),
+                    upper: Bound::Unbounded,
+                },
+            },
+        ];
+        let mut buf = BytesMut::new();
+        for record in &records {
+            record.encode(&mut buf);
+        }
+
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("ops.trace");
+        let mut data = FileHeader::new(FileType::Trace).encode().to_vec();
+        data.extend_from_slice(&buf);
+        std::fs::write(&path, &data).unwrap();
+        let read: Vec<_> = TraceReader::open(&path)
+            .unwrap()
+            .collect::<anyhow::Result<_>>()
+            .unwrap();
+        assert_eq!(read, records);
+
+        // 最后一条记录不完整时读到前一条为止
+        std::fs::write(&path, &data[..data.len() - 3]).unwrap();
+        let mut reader = TraceReader::open(&path).unwrap();
+        assert_eq!(reader.by_ref().count(), 2);
+        assert!(reader.truncated());
+    }
+}
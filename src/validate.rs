@@ -0,0 +1,205 @@
+use std::sync::Arc;
+
+use bytes::Bytes;
+use parking_lot::RwLock;
+use thiserror::Error;
+
+use crate::entry::Entry;
+use crate::system::is_system_key;
+use crate::OpType;
+
+/// 批次中的一个修改，`value` 为 `None` 表示删除
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub struct WriteOp<'a> {
+    pub key: &'a [u8],
+    pub value: Option<&'a [u8]>,
+}
+
+/// 校验回调拒绝写入的原因
+#[derive(Debug, Clone, Error, Eq, PartialEq)]
+pub enum Rejection {
+    /// key 的格式不符合约定
+    #[error("invalid key {key:?}: {reason}")]
+    InvalidKey { key: Bytes, reason: String },
+    /// key 或 value 超过大小限制
+    #[error("entry {key:?} is too large: {size} bytes, limit {limit} bytes")]
+    TooLarge {
+        key: Bytes,
+        size: usize,
+        limit: usize,
+    },
+    /// 没有写入权限，例如写入了其他租户的 key
+    #[error("unauthorized: {0}")]
+    Unauthorized(String),
+    #[error("{0}")]
+    Other(String),
+}
+
+/// 写入被校验回调拒绝时返回的错误，被拒绝的批次不会写入 WAL 和 memtable
+#[derive(Debug, Clone, Error, Eq, PartialEq)]
+#[error("write rejected by validator {validator:?}: {rejection}")]
+pub struct WriteRejectedError {
+    /// 拒绝写入的回调注册时的名字
+    pub validator: String,
+    pub rejection: Rejection,
+}
+
+/// 拒绝写入系统 key（见 `system` 模块）时 [`WriteRejectedError::validator`] 的值
+pub const RESERVED_KEY_VALIDATOR: &str = "lasagnedb";
+/// value 超过 [`crate::DbOptions::max_value_size`] 被拒绝时 [`WriteRejectedError::validator`] 的值
+pub const VALUE_SIZE_VALIDATOR: &str = "lasagnedb.max_value_size";
+
+type Validator = Arc<dyn Fn(&[WriteOp]) -> Result<(), Rejection> + Send + Sync>;
+
+/// 有 value 超过 `limit` 字节时拒绝整个批次，拒绝的原因为 [`Rejection::TooLarge`]
+///
+/// 在编码 WAL 记录、写入 memtable 之前检查，超长的 value 不会被复制
+pub(crate) fn check_value_size(entries: &[Entry], limit: usize) -> Result<(), WriteRejectedError> {
+    match entries
+        .iter()
+        .find(|e| !e.is_idempotency_token() && e.value.len() > limit)
+    {
+        None => Ok(()),
+        Some(e) => Err(WriteRejectedError {
+            validator: VALUE_SIZE_VALIDATOR.to_string(),
+            rejection: Rejection::TooLarge {
+                key: e.key.clone(),
+                size: e.value.len(),
+                limit,
+            },
+        }),
+    }
+}
+
+/// 按注册顺序排列的校验回调
+#[derive(Default)]
+pub(crate) struct Validators {
+    validators: RwLock<Vec<(String, Validator)>>,
+}
+
+impl Validators {
+    /// 同名的回调已存在时替换它
+    pub(crate) fn add(&self, name: String, validator: Validator) {
+        let mut validators = self.validators.write();
+        match validators.iter_mut().find(|(other, _)| *other == name) {
+            Some((_, existing)) => *existing = validator,
+            None => validators.push((name, validator)),
+        }
+    }
+
+    pub(crate) fn remove(&self, name: &str) -> bool {
+        let mut validators = self.validators.write();
+        let len = validators.len();
+        validators.retain(|(other, _)| other != name);
+        validators.len() < len
+    }
+
+    pub(crate) fn len(&self) -> usize {
+        self.validators.read().len()
+    }
+
+    /// 依次调用所有回调，第一个拒绝的回调决定返回的错误，幂等 token 不交给回调
+    ///
+    /// 系统 key 在调用回调之前拒绝，拒绝的回调名为 [`RESERVED_KEY_VALIDATOR`]
+    pub(crate) fn validate(&self, entries: &[Entry]) -> Result<(), WriteRejectedError> {
+        if let Some(e) = entries.iter().find(|e| is_system_key(&e.key)) {
+            return Err(WriteRejectedError {
+                validator: RESERVED_KEY_VALIDATOR.to_string(),
+                rejection: Rejection::InvalidKey {
+                    key: e.key.clone(),
+                    reason: "key prefix is reserved for internal use".to_string(),
+                },
+            });
+        }
+        // 复制出回调，回调中注册或移除回调不会死锁
+        let validators = self.validators.read().clone();
+        if validators.is_empty() {
+            return Ok(());
+        }
+        let ops: Vec<_> = entries
+            .iter()
+            .filter(|e| !e.is_idempotency_token())
+            .map(|e| WriteOp {
+                key: &e.key,
+                value: match e.op_type() {
+                    OpType::Delete => None,
+                    _ => Some(&e.value),
+                },
+            })
+            .collect();
+        for (name, validator) in validators {
+            validator(&ops).map_err(|rejection| WriteRejectedError {
+                validator: name,
+                rejection,
+            })?;
+        }
+        Ok(())
+    }
+}
+
+impl std::fmt::Debug for Validators {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let validators = self.validators.read();
+        f.debug_list()
+            .entries(validators.iter().map(|(name, _)| name))
+            .finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use bytes::Bytes;
+
+    use crate::entry::EntryBuilder;
+    use crate::validate::{Rejection, Validators, WriteRejectedError};
+    use crate::OpType;
+
+    #[test]
+    fn test_validators() {
+        let validators = Validators::default();
+        let entry = |key: &str, op_type| {
+            EntryBuilder::new()
+                .op_type(op_type)
+                .key_value(Bytes::from(key.to_string()), Bytes::from("v"))
+                .build()
+        };
+        let batch = vec![entry("a/1", OpType::Put), entry("b/1", OpType::Delete)];
+        assert!(validators.validate(&batch).is_ok());
+
+        validators.add(
+            "size".to_string(),
+            Arc::new(|ops| {
+                // 删除没有 value
+                assert!(ops
+                    .iter()
+                    .all(|op| op.value.is_some() == op.key.starts_with(b"a/")));
+                Ok(())
+            }),
+        );
+        validators.add(
+            "prefix".to_string(),
+            Arc::new(
+                |ops| match ops.iter().find(|op| !op.key.starts_with(b"a/")) {
+                    Some(op) => Err(Rejection::Unauthorized(format!("{:?}", op.key))),
+                    None => Ok(()),
+                },
+            ),
+        );
+        assert_eq!(
+            validators.validate(&batch).unwrap_err(),
+            WriteRejectedError {
+                validator: "prefix".to_string(),
+                rejection: Rejection::Unauthorized(format!("{:?}", b"b/1")),
+            }
+        );
+        assert!(validators.validate(&batch[..1]).is_ok());
+
+        assert!(validators.remove("prefix"));
+        assert!(!validators.remove("prefix"));
+        assert_eq!(validators.len(), 1);
+        assert!(validators.remove("size"));
+        assert!(validators.validate(&batch).is_ok());
+    }
+}
@@ -2,6 +2,10 @@ use crate::OpType::{Delete, Get, Put};
 use bytes::{Bytes, BytesMut};
 use std::cmp::Ordering;
 
+/// 编码中 seq num 只占 7 字节，写入使用的 seq num 都小于它。
+/// 查找时以它构造 key，排在同一 user key 的所有版本之前
+pub const MAX_SEQ_NUM: u64 = (1 << 56) - 1;
+
 /// Internal key in Db
 ///
 /// layout:
@@ -51,7 +55,7 @@ impl PartialEq<Self> for Key {
     fn eq(&self, other: &Self) -> bool {
         self.user_key == other.user_key
             && self.seq_num == other.seq_num
-            && self.op_type == self.op_type
+            && self.op_type == other.op_type
     }
 }
 
@@ -108,7 +112,7 @@ impl OpType {
 #[cfg(test)]
 mod tests {
     use crate::Key;
-    use crate::OpType::{Delete, Get};
+    use crate::OpType::{Delete, Get, Put};
     use bytes::Bytes;
     use std::cmp::Ordering;
 
@@ -126,4 +130,12 @@ mod tests {
         let k2 = Key::new(Bytes::from("b"), 2, Delete);
         assert_eq!(k1.cmp(&k2), Ordering::Less);
     }
+
+    #[test]
+    fn test_key_eq() {
+        let k1 = Key::new(Bytes::from("a"), 1, Put);
+        let k2 = Key::new(Bytes::from("a"), 1, Delete);
+        assert_ne!(k1, k2);
+        assert_eq!(k1, Key::new(Bytes::from("a"), 1, Put));
+    }
 }
@@ -28,6 +28,11 @@ impl JournalIterator {
         self.record_iter.record_item()
     }
 
+    /// 当前 item 所在记录的下标，同一条记录中的 item 来自同一次写入
+    pub fn record_idx(&self) -> usize {
+        self.idx
+    }
+
     #[instrument]
     pub fn next(&mut self) -> anyhow::Result<()> {
         self.record_iter.next();
@@ -1,38 +1,180 @@
 use anyhow::anyhow;
 use std::fmt::{Debug, Formatter};
 
-use std::path::Path;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
 use std::sync::Arc;
+use std::time::{Duration, Instant};
 
 use bytes::{Buf, Bytes};
-use tracing::instrument;
+use parking_lot::{Mutex, RwLock};
+use tracing::{instrument, warn};
 
 use crate::entry::Entry;
 use crate::record::{Record, RecordBuilder, RecordItem};
 use crate::storage::file::FileStorage;
+use crate::storage::header::{FileHeader, FileType, FILE_HEADER_SIZE};
+use crate::wal::reader::{is_zero_padding, split_record};
+use crate::wal::{WalCorruptionError, WalProtection};
+use crate::{WAL_SEGMENT_AGE_LIMIT, WAL_SEGMENT_SIZE_LIMIT};
 
-pub struct Journal {
-    id: u32,
+/// WAL 的选项，分段限制任一条件满足即需要切换到新段
+#[derive(Debug, Clone, Copy)]
+pub struct JournalOptions {
+    /// 单个段的最大字节数
+    pub max_segment_size: u64,
+    /// 单个段从创建开始允许写入的最长时间
+    pub max_segment_age: Duration,
+    /// 记录的 MAC 校验和加密，为 `None` 时按原格式写入
+    pub protection: Option<WalProtection>,
+    /// 只读取记录，不为空段写入文件头，也不截掉段末尾的零填充，用于检查数据库
+    pub read_only: bool,
+}
+
+impl Default for JournalOptions {
+    fn default() -> Self {
+        Self {
+            max_segment_size: WAL_SEGMENT_SIZE_LIMIT,
+            max_segment_age: WAL_SEGMENT_AGE_LIMIT,
+            protection: None,
+            read_only: false,
+        }
+    }
+}
+
+struct JournalSegment {
     file: FileStorage,
+    /// 记录占用的字节数，不含文件头
+    size: AtomicU64,
+    /// 文件头的长度，没有文件头的旧段为 0
+    header_len: u64,
+    created_at: Instant,
+}
+
+impl JournalSegment {
+    fn open(path: impl AsRef<Path>, read_only: bool) -> anyhow::Result<Self> {
+        let file = FileStorage::open(&path)?;
+        let mut size = file.size()?;
+        let header_len = if size == 0 && read_only {
+            0
+        } else if size == 0 {
+            file.write(&FileHeader::new(FileType::Log).encode())?;
+            file.sync()?;
+            FILE_HEADER_SIZE as u64
+        } else {
+            let head = file.read(0, size.min(FILE_HEADER_SIZE as u64))?;
+            // 没有魔数的是加入文件头之前写入的段
+            match FileHeader::check(path.as_ref(), &head, FileType::Log, |_| true)? {
+                Some(_) => FILE_HEADER_SIZE as u64,
+                None => 0,
+            }
+        };
+        size = size.saturating_sub(header_len);
+        Ok(Self {
+            file,
+            size: AtomicU64::new(size),
+            header_len,
+            created_at: Instant::now(),
+        })
+    }
+
+    /// 截掉 `len` 之后的内容，`len` 不含文件头
+    fn truncate(&mut self, len: u64) -> anyhow::Result<()> {
+        self.file.truncate(self.header_len + len)?;
+        self.file.sync_all()?;
+        *self.size.get_mut() = len;
+        Ok(())
+    }
+}
+
+/// 一个 memtable 对应的 WAL，由一个或多个段文件组成，只有最后一个段可写
+pub struct Journal {
+    id: u64,
+    segments: RwLock<Vec<JournalSegment>>,
+    // 追加记录的临界区，见 `write_then`
+    append: Mutex<()>,
     records: Vec<Arc<Record<JournalItem>>>,
+    // 打开时跳过的零填充的字节数
+    padding_bytes: u64,
+    options: JournalOptions,
 }
 
 impl Journal {
+    /// 打开只有一个段的 WAL
+    #[instrument]
+    pub fn open(id: u64, path: impl AsRef<Path> + Debug) -> anyhow::Result<Self> {
+        Self::open_segments(
+            id,
+            vec![PathBuf::from(path.as_ref())],
+            JournalOptions::default(),
+        )
+    }
+
+    /// 按顺序打开 WAL 的所有段，并读出其中的记录
+    ///
+    /// 段末尾的零填充不是记录也不是损坏，跳过，见 `is_zero_padding`；最后一个段之后还要追加，
+    /// 打开时截掉其中的填充，新的记录紧接在最后一条完整的记录之后，[`JournalOptions::read_only`] 时不截掉。
+    /// 其他无法解析的数据返回 [`WalCorruptionError`]
     #[instrument]
-    pub fn open(id: u32, path: impl AsRef<Path> + Debug) -> anyhow::Result<Self> {
+    pub fn open_segments(
+        id: u64,
+        segment_paths: Vec<PathBuf>,
+        options: JournalOptions,
+    ) -> anyhow::Result<Self> {
+        if segment_paths.is_empty() {
+            return Err(anyhow!("journal {} has no segment", id));
+        }
+
         // TODO 优化
-        let file = FileStorage::open(path)?;
+        let mut segments = Vec::with_capacity(segment_paths.len());
         let mut records = vec![];
-
-        let mut buf = Bytes::from(file.read_to_end(0)?);
-        while buf.has_remaining() {
-            records.push(Arc::new(Record::decode_with_bytes(&mut buf)?));
+        let mut padding_bytes = 0;
+        let num_segments = segment_paths.len();
+        for (segment_idx, path) in segment_paths.into_iter().enumerate() {
+            let mut segment = JournalSegment::open(&path, options.read_only)?;
+            let mut buf = Bytes::from(segment.file.read_to_end(segment.header_len)?);
+            let size = segment.header_len + buf.len() as u64;
+            while buf.has_remaining() {
+                let offset = size - buf.remaining() as u64;
+                if is_zero_padding(&buf) {
+                    warn!(
+                        "skip {} zero bytes at the tail of wal segment {:?} from offset {}",
+                        buf.remaining(),
+                        path,
+                        offset
+                    );
+                    padding_bytes += buf.remaining() as u64;
+                    if segment_idx + 1 == num_segments && !options.read_only {
+                        segment.truncate(offset - segment.header_len)?;
+                    }
+                    break;
+                }
+                let corruption = |reason| WalCorruptionError {
+                    journal_id: id,
+                    segment: path.clone(),
+                    offset,
+                    reason,
+                };
+                let mut record = match options.protection {
+                    Some(protection) => protection.open(id, &mut buf).map_err(corruption)?,
+                    None => split_record(&mut buf).map_err(corruption)?,
+                };
+                records.push(Arc::new(Record::decode_with_bytes(&mut record)?));
+            }
+            segments.push(segment);
         }
 
-        Ok(Self { id, file, records })
+        Ok(Self {
+            id,
+            segments: RwLock::new(segments),
+            append: Mutex::new(()),
+            records,
+            padding_bytes,
+            options,
+        })
     }
 
-    pub fn id(&self) -> u32 {
+    pub fn id(&self) -> u64 {
         self.id
     }
 
@@ -40,24 +182,106 @@ impl Journal {
         self.records.len()
     }
 
+    /// 打开时在段末尾跳过的零填充的字节数
+    pub fn padding_bytes(&self) -> u64 {
+        self.padding_bytes
+    }
+
+    pub fn num_of_segments(&self) -> usize {
+        self.segments.read().len()
+    }
+
+    /// 当前写入的段是否超过了大小或时间限制
+    pub fn need_roll(&self) -> bool {
+        let segments = self.segments.read();
+        let current = segments.last().unwrap();
+        let size = current.size.load(Ordering::Acquire);
+        size >= self.options.max_segment_size
+            || (size > 0 && current.created_at.elapsed() >= self.options.max_segment_age)
+    }
+
+    /// 从现在开始计算当前段的存在时间，提前创建、稍后才开始写入的 WAL 换入时调用，避免第一次写入就切换段
+    pub fn reset_segment_age(&self) {
+        self.segments.write().last_mut().unwrap().created_at = Instant::now();
+    }
+
+    /// 切换到位于 `path` 的新段，之后的写入都会进入新段
+    #[instrument(skip(self))]
+    pub fn roll(&self, path: impl AsRef<Path> + Debug) -> anyhow::Result<()> {
+        let segment = JournalSegment::open(path, false)?;
+        let mut segments = self.segments.write();
+        segments.last().unwrap().file.sync()?;
+        segments.push(segment);
+        Ok(())
+    }
+
+    /// 按顺序返回所有段的路径
+    pub fn segment_paths(&self) -> Vec<PathBuf> {
+        self.segments
+            .read()
+            .iter()
+            .map(|segment| segment.file.path().to_path_buf())
+            .collect()
+    }
+
     pub fn delete(&self) -> anyhow::Result<()> {
-        self.file.delete()
+        for segment in self.segments.read().iter() {
+            segment.file.delete()?;
+        }
+        Ok(())
     }
 
     #[instrument(skip_all)]
     pub fn write(&self, batches: Vec<Entry>) -> anyhow::Result<()> {
+        self.write_then(batches, || ())
+    }
+
+    /// 追加一条记录，成功后在同一临界区内调用 `on_appended`
+    ///
+    /// 恢复时按记录在 WAL 中的顺序重新编号，写入时在 `on_appended` 中分配序号，序号的顺序与记录的顺序一致
+    #[instrument(skip_all)]
+    pub fn write_then<T>(
+        &self,
+        batches: Vec<Entry>,
+        on_appended: impl FnOnce() -> T,
+    ) -> anyhow::Result<T> {
         let mut builder = RecordBuilder::with_len(batches.len());
         for i in batches {
+            debug_assert!(i.validate().is_ok(), "invalid entry: {:?}", i);
             builder.add(JournalItem(i));
         }
-        let record = builder.build();
-        self.file.write(&record.encode());
-        Ok(())
+        let mut record = builder.build().encode();
+        if let Some(protection) = self.options.protection {
+            record = protection.seal(self.id, &record);
+        }
+        let _append = self.append.lock();
+        let segments = self.segments.read();
+        let current = segments.last().unwrap();
+        current.file.write(&record)?;
+        current
+            .size
+            .fetch_add(record.len() as u64, Ordering::Release);
+        Ok(on_appended())
+    }
+
+    /// 持有追加记录的临界区，期间的写入阻塞在追加之前
+    #[cfg(test)]
+    pub(crate) fn lock_append(&self) -> parking_lot::MutexGuard<'_, ()> {
+        self.append.lock()
+    }
+
+    #[instrument]
+    pub fn flush(&self) -> anyhow::Result<()> {
+        self.segments.read().last().unwrap().file.sync()
     }
 
+    /// 刷写写缓冲后 fsync 所有段，之前写入的记录掉电后也不会丢失
     #[instrument]
-    pub fn flush(&self) {
-        self.file.sync();
+    pub fn sync_all(&self) -> anyhow::Result<()> {
+        for segment in self.segments.read().iter() {
+            segment.file.sync_all()?;
+        }
+        Ok(())
     }
 
     pub fn read_record(&self, record_idx: usize) -> anyhow::Result<Arc<Record<JournalItem>>> {
@@ -75,8 +299,13 @@ impl Journal {
 
 impl Debug for Journal {
     fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        let segments = self.segments.read();
         f.debug_struct("Journal")
-            .field("file", &self.file)
+            .field("id", &self.id)
+            .field(
+                "segments",
+                &segments.iter().map(|s| &s.file).collect::<Vec<_>>(),
+            )
             .field("records len", &self.records.len())
             .finish()
     }
@@ -1,7 +1,11 @@
 pub mod iterator;
 mod journal;
+mod protection;
+mod reader;
 
 pub use journal::*;
+pub use protection::{WalCorruptionError, WalProtection};
+pub use reader::{WalEntry, WalReader, WalRecord, WalRecordIterator};
 
 #[cfg(test)]
 mod tests;
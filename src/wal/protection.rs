@@ -0,0 +1,159 @@
+use std::fmt::{Debug, Formatter};
+use std::hash::Hasher;
+use std::path::PathBuf;
+
+use bytes::{Buf, BufMut, Bytes, BytesMut};
+use rand::{RngCore, SeedableRng};
+use rand_chacha::ChaCha20Rng;
+use siphasher::sip128::{Hasher128, SipHasher};
+use thiserror::Error;
+
+const LEN_SIZE: usize = 4;
+const NONCE_SIZE: usize = 8;
+const MAC_SIZE: usize = 16;
+/// ChaCha20 一个块的字节数，每条记录的第一个块用于派生 MAC 的密钥
+const CHACHA_BLOCK_SIZE: u128 = 64;
+
+/// WAL 中的记录校验失败或无法解析，`offset` 是出错记录在段文件中的起始位置
+#[derive(Error, Debug, Clone, Eq, PartialEq)]
+#[error("wal {journal_id} segment {segment:?} is corrupted at offset {offset}: {reason}")]
+pub struct WalCorruptionError {
+    pub journal_id: u64,
+    pub segment: PathBuf,
+    pub offset: u64,
+    pub reason: String,
+}
+
+/// WAL 记录的完整性保护，开启后每条记录都带有 MAC，恢复时可以发现被篡改或部分损坏的记录，
+/// 也可以同时加密记录内容
+///
+/// 开启保护后写入的 WAL 与未开启时格式不同，切换前需要先 flush 掉所有 memtable
+///
+/// layout
+/// ```text
+/// +-----------------+----------------+--------+----------------+
+/// | length(4 bytes) | nonce(8 bytes) | record | mac(16 bytes)  |
+/// +-----------------+----------------+--------+----------------+
+/// ```
+/// 每条记录使用随机 nonce，以 nonce 为 ChaCha20 的 stream，第一个块派生出该记录的 MAC 密钥，
+/// 之后的块作为加密 record 的密钥流。MAC 为 SipHash-2-4-128，覆盖是否加密、WAL id、length、nonce 和记录内容
+#[derive(Clone, Copy)]
+pub struct WalProtection {
+    key: [u8; 32],
+    encrypt: bool,
+}
+
+impl WalProtection {
+    /// 只校验不加密
+    pub fn mac_only(key: [u8; 32]) -> Self {
+        Self {
+            key,
+            encrypt: false,
+        }
+    }
+
+    /// 加密并校验
+    pub fn encrypted(key: [u8; 32]) -> Self {
+        Self { key, encrypt: true }
+    }
+
+    pub fn is_encrypted(&self) -> bool {
+        self.encrypt
+    }
+
+    fn keystream(&self, nonce: u64) -> (ChaCha20Rng, [u8; 16]) {
+        let mut rng = ChaCha20Rng::from_seed(self.key);
+        rng.set_stream(nonce);
+        let mut mac_key = [0u8; 16];
+        rng.fill_bytes(&mut mac_key);
+        // 跳过第一个块剩下的部分
+        rng.set_word_pos(CHACHA_BLOCK_SIZE / 4);
+        (rng, mac_key)
+    }
+
+    fn mac(
+        &self,
+        journal_id: u64,
+        mac_key: &[u8; 16],
+        header: &[u8],
+        body: &[u8],
+    ) -> [u8; MAC_SIZE] {
+        let mut hasher = SipHasher::new_with_key(mac_key);
+        // 加密和不加密写入的记录互相不能通过校验
+        hasher.write_u8(self.encrypt as u8);
+        // id 不超过 u32 时与旧版本写入的 WAL 保持一致
+        match u32::try_from(journal_id) {
+            Ok(journal_id) => hasher.write(&journal_id.to_le_bytes()),
+            Err(_) => hasher.write(&journal_id.to_le_bytes()),
+        }
+        hasher.write(header);
+        hasher.write(body);
+        hasher.finish128().as_bytes()
+    }
+
+    fn apply_keystream(rng: &mut ChaCha20Rng, data: &mut [u8]) {
+        let mut keystream = vec![0u8; data.len()];
+        rng.fill_bytes(&mut keystream);
+        data.iter_mut()
+            .zip(keystream)
+            .for_each(|(byte, key)| *byte ^= key);
+    }
+
+    /// 给编码后的记录加上 MAC，按需加密
+    pub(crate) fn seal(&self, journal_id: u64, record: &[u8]) -> Bytes {
+        let nonce = rand::thread_rng().next_u64();
+        let (mut rng, mac_key) = self.keystream(nonce);
+
+        let mut buf = BytesMut::with_capacity(LEN_SIZE + NONCE_SIZE + record.len() + MAC_SIZE);
+        buf.put_u32_le(record.len() as u32);
+        buf.put_u64_le(nonce);
+        buf.extend_from_slice(record);
+        if self.encrypt {
+            Self::apply_keystream(&mut rng, &mut buf[LEN_SIZE + NONCE_SIZE..]);
+        }
+        let (header, body) = buf.split_at(LEN_SIZE + NONCE_SIZE);
+        let mac = self.mac(journal_id, &mac_key, header, body);
+        buf.extend_from_slice(&mac);
+        buf.freeze()
+    }
+
+    /// 从 `buf` 中取出一条记录，校验 MAC 并按需解密，失败时返回原因
+    pub(crate) fn open(&self, journal_id: u64, buf: &mut Bytes) -> Result<Bytes, String> {
+        if buf.remaining() < LEN_SIZE + NONCE_SIZE + MAC_SIZE {
+            return Err(format!(
+                "truncated record header, {} bytes left",
+                buf.remaining()
+            ));
+        }
+        let len = (&buf[..LEN_SIZE]).get_u32_le() as usize;
+        if buf.remaining() < LEN_SIZE + NONCE_SIZE + len + MAC_SIZE {
+            return Err(format!(
+                "truncated record, expect {} bytes but only {} left",
+                len,
+                buf.remaining() - LEN_SIZE - NONCE_SIZE
+            ));
+        }
+
+        let header = buf.split_to(LEN_SIZE + NONCE_SIZE);
+        let nonce = (&header[LEN_SIZE..]).get_u64_le();
+        let mut body = buf.split_to(len).to_vec();
+        let expect_mac = buf.split_to(MAC_SIZE);
+
+        let (mut rng, mac_key) = self.keystream(nonce);
+        if self.mac(journal_id, &mac_key, &header, &body)[..] != expect_mac[..] {
+            return Err("mac mismatch".to_string());
+        }
+        if self.encrypt {
+            Self::apply_keystream(&mut rng, &mut body);
+        }
+        Ok(Bytes::from(body))
+    }
+}
+
+impl Debug for WalProtection {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("WalProtection")
+            .field("encrypt", &self.encrypt)
+            .finish_non_exhaustive()
+    }
+}
@@ -0,0 +1,262 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use anyhow::Context;
+use bytes::{Buf, Bytes};
+
+use crate::batch::IdempotencyToken;
+use crate::entry::Entry;
+use crate::storage::header::{FileHeader, FileType, FILE_HEADER_SIZE};
+use crate::wal::{WalCorruptionError, WalProtection};
+use crate::OpType;
+
+/// WAL 中的一个修改
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct WalEntry {
+    pub op_type: OpType,
+    pub key: Bytes,
+    /// 删除时为空
+    pub value: Bytes,
+}
+
+/// WAL 中的一条记录，对应一次写入（单个 put / delete 或一个批次）
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct WalRecord {
+    /// 记录在 WAL 中的下标，从 0 开始，即写入的先后顺序，跨段连续编号
+    pub record_idx: usize,
+    /// 设置了 [`WalReader::first_seq`] 时为该记录的提交序号，恢复时第 i 条记录使用 `first_seq + i`
+    pub seq: Option<u64>,
+    /// 记录所在的段文件
+    pub segment: PathBuf,
+    /// 记录在段文件中的起始位置
+    pub offset: u64,
+    /// 批次的幂等 token
+    pub idempotency_token: Option<IdempotencyToken>,
+    /// 按写入顺序排列的修改
+    pub entries: Vec<WalEntry>,
+}
+
+/// 独立于 [`crate::Db`] 只读地打开 WAL，按写入顺序遍历其中的记录，用于审计和排查复制链路
+///
+/// 只读取文件，不会像打开数据库那样为空文件写入文件头或截掉零填充的尾部，段末尾的零填充直接跳过。
+/// 一个 WAL 可能由多个段组成（`{id}.LOG`、`{id}-{segment}.LOG`），按段的顺序传入全部段才能得到完整的写入序列。
+/// 记录无法解析或校验失败时迭代器返回 [`WalCorruptionError`] 并结束
+#[derive(Debug, Clone)]
+pub struct WalReader {
+    segments: Vec<PathBuf>,
+    journal_id: Option<u64>,
+    protection: Option<WalProtection>,
+    first_seq: Option<u64>,
+}
+
+impl WalReader {
+    /// 读取单个 LOG 文件，之后的段用 [`WalReader::segment`] 追加
+    pub fn new(path: impl AsRef<Path>) -> Self {
+        Self {
+            segments: vec![PathBuf::from(path.as_ref())],
+            journal_id: None,
+            protection: None,
+            first_seq: None,
+        }
+    }
+
+    /// 追加 WAL 的下一个段
+    pub fn segment(mut self, path: impl AsRef<Path>) -> Self {
+        self.segments.push(PathBuf::from(path.as_ref()));
+        self
+    }
+
+    /// 开启了 WAL 保护的数据库写入的 WAL 需要传入同样的保护方式
+    pub fn protection(mut self, protection: WalProtection) -> Self {
+        self.protection = Some(protection);
+        self
+    }
+
+    /// WAL 的 id，校验 MAC 时使用，默认从第一个段的文件名中解析
+    pub fn journal_id(mut self, journal_id: u64) -> Self {
+        self.journal_id = Some(journal_id);
+        self
+    }
+
+    /// WAL 第一条记录的提交序号，设置后每条记录带有 [`WalRecord::seq`]
+    pub fn first_seq(mut self, seq: u64) -> Self {
+        self.first_seq = Some(seq);
+        self
+    }
+
+    /// 按写入顺序遍历所有段中的记录，段文件在遍历到时才读取
+    pub fn records(&self) -> WalRecordIterator<'_> {
+        WalRecordIterator {
+            reader: self,
+            journal_id: self
+                .journal_id
+                .or_else(|| journal_id_of(&self.segments[0]))
+                .unwrap_or(0),
+            segment_idx: 0,
+            buf: None,
+            record_idx: 0,
+            done: false,
+        }
+    }
+}
+
+/// 从 `{id}.LOG` 或 `{id}-{segment}.LOG` 中解析 WAL 的 id
+fn journal_id_of(path: &Path) -> Option<u64> {
+    let stem = path.file_stem()?.to_str()?;
+    stem.split('-').next()?.parse().ok()
+}
+
+/// 见 [`WalReader::records`]
+pub struct WalRecordIterator<'a> {
+    reader: &'a WalReader,
+    journal_id: u64,
+    segment_idx: usize,
+    // 当前段中未读的部分和段的总长度
+    buf: Option<(Bytes, u64)>,
+    record_idx: usize,
+    done: bool,
+}
+
+impl WalRecordIterator<'_> {
+    fn segment_path(&self) -> &Path {
+        &self.reader.segments[self.segment_idx]
+    }
+
+    fn corruption(&self, offset: u64, reason: impl ToString) -> anyhow::Error {
+        WalCorruptionError {
+            journal_id: self.journal_id,
+            segment: self.segment_path().to_path_buf(),
+            offset,
+            reason: reason.to_string(),
+        }
+        .into()
+    }
+
+    /// 读出当前段，跳过文件头
+    fn load_segment(&self) -> anyhow::Result<(Bytes, u64)> {
+        let path = self.segment_path();
+        let data = fs::read(path).with_context(|| format!("read {:?} failed", path))?;
+        let len = data.len() as u64;
+        let head = &data[..data.len().min(FILE_HEADER_SIZE)];
+        let has_header = FileHeader::check(path, head, FileType::Log, |_| true)?.is_some();
+        let mut buf = Bytes::from(data);
+        if has_header {
+            buf.advance(FILE_HEADER_SIZE);
+        }
+        Ok((buf, len))
+    }
+
+    fn next_record(&mut self) -> anyhow::Result<Option<WalRecord>> {
+        loop {
+            if self.segment_idx >= self.reader.segments.len() {
+                return Ok(None);
+            }
+            let (buf, _) = match &mut self.buf {
+                Some(buf) => buf,
+                None => self.buf.insert(self.load_segment()?),
+            };
+            if buf.has_remaining() && !is_zero_padding(buf) {
+                break;
+            }
+            self.buf = None;
+            self.segment_idx += 1;
+        }
+
+        let journal_id = self.journal_id;
+        let (buf, len) = self.buf.as_mut().unwrap();
+        let offset = *len - buf.remaining() as u64;
+        let record = match &self.reader.protection {
+            Some(protection) => protection.open(journal_id, buf),
+            None => split_record(buf),
+        };
+        let mut record = record.map_err(|reason| self.corruption(offset, reason))?;
+        // split_record 已检查过长度，保护的记录在 MAC 校验通过后再检查
+        if self.reader.protection.is_some() {
+            record_len(&record).map_err(|reason| self.corruption(offset, reason))?;
+        }
+
+        record.advance(4);
+        let num = record.get_u64_le();
+        let mut idempotency_token = None;
+        let mut entries = Vec::with_capacity(num as usize);
+        for _ in 0..num {
+            let entry = Entry::decode_with_bytes(&mut record);
+            if entry.is_idempotency_token() {
+                idempotency_token = IdempotencyToken::from_entry(&entry);
+                continue;
+            }
+            entries.push(WalEntry {
+                op_type: entry.op_type(),
+                key: entry.key,
+                value: entry.value,
+            });
+        }
+
+        let record_idx = self.record_idx;
+        self.record_idx += 1;
+        Ok(Some(WalRecord {
+            record_idx,
+            seq: self.reader.first_seq.map(|seq| seq + record_idx as u64),
+            segment: self.segment_path().to_path_buf(),
+            offset,
+            idempotency_token,
+            entries,
+        }))
+    }
+}
+
+impl Iterator for WalRecordIterator<'_> {
+    type Item = anyhow::Result<WalRecord>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.done {
+            return None;
+        }
+        let record = self.next_record().transpose();
+        self.done = !matches!(record, Some(Ok(_)));
+        record
+    }
+}
+
+/// 记录边界之后直到段末尾全为 0，是零填充的尾部而不是记录
+///
+/// 掉电后文件系统可能已经记下了追加后的文件长度、数据块却没有落盘，重启后段的尾部读出全 0。
+/// 写入的记录不会全为 0：未开启保护时记录至少包含一项，项数不为 0；开启保护时记录的长度不为 0。
+/// 只有延伸到段末尾的 0 才是填充，之后还有数据时仍按记录解析，解析失败即为损坏
+pub(crate) fn is_zero_padding(buf: &[u8]) -> bool {
+    !buf.is_empty() && buf.iter().all(|b| *b == 0)
+}
+
+/// 检查 `data` 开头的一条记录是否完整，返回其长度
+///
+/// 记录的格式见 `Record`，其中每项为一个 [`Entry`]
+fn record_len(data: &[u8]) -> Result<usize, String> {
+    let truncated = |need: usize| format!("truncated record, need {} bytes", need);
+    let read_u64 = |pos: usize| -> Result<u64, String> {
+        data.get(pos..pos + 8)
+            .map(|mut bytes| bytes.get_u64_le())
+            .ok_or_else(|| truncated(pos + 8))
+    };
+    let num = read_u64(4)?;
+    let mut pos = 12;
+    for _ in 0..num {
+        let key_len = read_u64(pos + 4)? as usize;
+        let value_pos = (pos + 12)
+            .checked_add(key_len)
+            .ok_or("invalid key length")?;
+        let value_len = read_u64(value_pos)? as usize;
+        pos = (value_pos + 8)
+            .checked_add(value_len)
+            .ok_or("invalid value length")?;
+        if pos > data.len() {
+            return Err(truncated(pos));
+        }
+    }
+    Ok(pos)
+}
+
+/// 从未开启保护的 WAL 中取出一条记录
+pub(crate) fn split_record(buf: &mut Bytes) -> Result<Bytes, String> {
+    let len = record_len(buf)?;
+    Ok(buf.split_to(len))
+}
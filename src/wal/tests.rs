@@ -1,22 +1,25 @@
+use crate::batch::IdempotencyToken;
 use crate::entry::{Entry, EntryBuilder};
+use crate::storage::header::FILE_HEADER_SIZE;
 use crate::value::OpType;
 use crate::wal::iterator::JournalIterator;
-use crate::wal::Journal;
+use crate::wal::{Journal, JournalOptions, WalCorruptionError, WalProtection, WalReader};
 use bytes::Bytes;
 use std::sync::Arc;
+use std::time::Duration;
 
 fn test_batches() -> Vec<Entry> {
     vec![
         EntryBuilder::new()
-            .op_type(OpType::Get)
+            .op_type(OpType::Put)
             .key_value(Bytes::from("k1"), Bytes::from("v1"))
             .build(),
         EntryBuilder::new()
-            .op_type(OpType::Get)
+            .op_type(OpType::Put)
             .key_value(Bytes::from("k2"), Bytes::from("v2"))
             .build(),
         EntryBuilder::new()
-            .op_type(OpType::Get)
+            .op_type(OpType::Put)
             .key_value(Bytes::from("k3"), Bytes::from("v3"))
             .build(),
     ]
@@ -43,3 +46,321 @@ fn test_journal() {
         iter.next().unwrap();
     })
 }
+
+#[test]
+fn test_journal_segments() {
+    let dir = tempfile::tempdir().unwrap();
+    let segment_path = |id: u32| dir.path().join(format!("LOG.{}", id));
+    let options = JournalOptions {
+        max_segment_size: 1,
+        max_segment_age: Duration::from_secs(3600),
+        ..Default::default()
+    };
+    {
+        let wal = Journal::open_segments(1, vec![segment_path(0)], options).unwrap();
+        assert!(!wal.need_roll());
+        wal.write(test_batches()).unwrap();
+        assert!(wal.need_roll());
+        wal.roll(segment_path(1)).unwrap();
+        assert!(!wal.need_roll());
+        wal.write(test_batches()).unwrap();
+        wal.flush().unwrap();
+        assert_eq!(wal.num_of_segments(), 2);
+    }
+
+    let wal = Arc::new(
+        Journal::open_segments(1, vec![segment_path(0), segment_path(1)], options).unwrap(),
+    );
+    assert_eq!(wal.num_of_records(), 2);
+    let mut iter = JournalIterator::create_and_seek_to_first(wal.clone()).unwrap();
+    let mut batches = test_batches();
+    batches.extend(test_batches());
+    batches.iter().for_each(|item| {
+        assert!(iter.is_valid());
+        assert_eq!(item, iter.record_item().as_ref());
+        iter.next().unwrap();
+    });
+
+    wal.delete().unwrap();
+    assert!(!segment_path(0).exists());
+    assert!(!segment_path(1).exists());
+}
+
+#[test]
+fn test_journal_protection() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("LOG");
+    let options = |protection| JournalOptions {
+        protection: Some(protection),
+        ..Default::default()
+    };
+    let key = [7u8; 32];
+    let first_record_len;
+    {
+        let wal = Journal::open_segments(
+            1,
+            vec![path.clone()],
+            options(WalProtection::encrypted(key)),
+        )
+        .unwrap();
+        wal.write(test_batches()).unwrap();
+        wal.flush().unwrap();
+        first_record_len = std::fs::metadata(&path).unwrap().len();
+        wal.write(test_batches()).unwrap();
+        wal.flush().unwrap();
+    }
+    let data = std::fs::read(&path).unwrap();
+    assert!(!data.windows(2).any(|w| w == b"v1"));
+
+    let wal = Arc::new(
+        Journal::open_segments(
+            1,
+            vec![path.clone()],
+            options(WalProtection::encrypted(key)),
+        )
+        .unwrap(),
+    );
+    assert_eq!(wal.num_of_records(), 2);
+    let mut iter = JournalIterator::create_and_seek_to_first(wal).unwrap();
+    let mut batches = test_batches();
+    batches.extend(test_batches());
+    batches.iter().for_each(|item| {
+        assert!(iter.is_valid());
+        assert_eq!(item, iter.record_item().as_ref());
+        iter.next().unwrap();
+    });
+
+    let open_err = |protection| {
+        Journal::open_segments(1, vec![path.clone()], options(protection))
+            .unwrap_err()
+            .downcast::<WalCorruptionError>()
+            .unwrap()
+    };
+    // 密钥不对时第一条记录就校验失败
+    let err = open_err(WalProtection::encrypted([8u8; 32]));
+    assert_eq!(err.offset, FILE_HEADER_SIZE as u64);
+    let err = open_err(WalProtection::mac_only(key));
+    assert_eq!(err.offset, FILE_HEADER_SIZE as u64);
+
+    // 篡改第二条记录
+    let mut tampered = data.clone();
+    tampered[first_record_len as usize + 20] ^= 1;
+    std::fs::write(&path, &tampered).unwrap();
+    let err = open_err(WalProtection::encrypted(key));
+    assert_eq!(err.offset, first_record_len);
+    assert_eq!(err.segment, path);
+
+    // 末尾的记录不完整
+    std::fs::write(&path, &data[..data.len() - 1]).unwrap();
+    let err = open_err(WalProtection::encrypted(key));
+    assert_eq!(err.offset, first_record_len);
+}
+
+#[test]
+fn test_wal_reader() {
+    let dir = tempfile::tempdir().unwrap();
+    let segment_path = |id: u32| dir.path().join(format!("00003-{}.LOG", id));
+    let options = JournalOptions {
+        max_segment_size: 1,
+        max_segment_age: Duration::from_secs(3600),
+        protection: Some(WalProtection::mac_only([7u8; 32])),
+        ..Default::default()
+    };
+    let mut batch = vec![IdempotencyToken::from(9u64).to_entry()];
+    batch.push(
+        EntryBuilder::new()
+            .op_type(OpType::Delete)
+            .key_value(Bytes::from("k1"), Bytes::new())
+            .build(),
+    );
+    {
+        let wal = Journal::open_segments(3, vec![segment_path(0)], options).unwrap();
+        wal.write(test_batches()).unwrap();
+        wal.roll(segment_path(1)).unwrap();
+        wal.write(batch).unwrap();
+        wal.flush().unwrap();
+    }
+
+    let reader = WalReader::new(segment_path(0))
+        .segment(segment_path(1))
+        .protection(options.protection.unwrap())
+        .first_seq(10);
+    let records: Vec<_> = reader.records().map(|record| record.unwrap()).collect();
+    assert_eq!(records.len(), 2);
+    assert_eq!(records[0].record_idx, 0);
+    assert_eq!(records[0].seq, Some(10));
+    assert_eq!(records[0].offset, FILE_HEADER_SIZE as u64);
+    assert_eq!(records[0].idempotency_token, None);
+    let keys: Vec<_> = records[0].entries.iter().map(|e| e.key.clone()).collect();
+    assert_eq!(keys, vec!["k1", "k2", "k3"]);
+    assert_eq!(records[1].seq, Some(11));
+    assert_eq!(records[1].segment, segment_path(1));
+    assert_eq!(records[1].idempotency_token, Some(9u64.into()));
+    assert_eq!(records[1].entries.len(), 1);
+    assert_eq!(records[1].entries[0].op_type, OpType::Delete);
+
+    // 只读取，不修改文件
+    let data = std::fs::read(segment_path(1)).unwrap();
+    std::fs::write(segment_path(1), &data[..data.len() - 1]).unwrap();
+    let mut records = reader.records();
+    assert!(records.next().unwrap().is_ok());
+    let err = records
+        .next()
+        .unwrap()
+        .unwrap_err()
+        .downcast::<WalCorruptionError>()
+        .unwrap();
+    assert_eq!(err.journal_id, 3);
+    assert_eq!(err.segment, segment_path(1));
+    assert_eq!(err.offset, FILE_HEADER_SIZE as u64);
+    assert!(records.next().is_none());
+    assert_eq!(
+        std::fs::metadata(segment_path(1)).unwrap().len(),
+        data.len() as u64 - 1
+    );
+
+    // 未开启保护的 WAL 末尾的记录不完整
+    let path = dir.path().join("00004.LOG");
+    {
+        let wal = Journal::open(4, &path).unwrap();
+        wal.write(test_batches()).unwrap();
+        wal.write(test_batches()).unwrap();
+        wal.flush().unwrap();
+    }
+    let data = std::fs::read(&path).unwrap();
+    std::fs::write(&path, &data[..data.len() - 3]).unwrap();
+    let results: Vec<_> = WalReader::new(&path).records().collect();
+    assert_eq!(results.len(), 2);
+    assert_eq!(results[0].as_ref().unwrap().entries.len(), 3);
+    let err = results[1]
+        .as_ref()
+        .unwrap_err()
+        .downcast_ref::<WalCorruptionError>()
+        .unwrap();
+    assert_eq!(err.journal_id, 4);
+}
+
+#[test]
+fn test_journal_zero_padding() {
+    let dir = tempfile::tempdir().unwrap();
+    for protection in [None, Some(WalProtection::mac_only([7u8; 32]))] {
+        let path = dir.path().join("00005.LOG");
+        let options = JournalOptions {
+            protection,
+            ..Default::default()
+        };
+        {
+            let wal = Journal::open_segments(5, vec![path.clone()], options).unwrap();
+            wal.write(test_batches()).unwrap();
+            wal.write(test_batches()).unwrap();
+            wal.flush().unwrap();
+        }
+        // 尾部填充到 4KB 对齐
+        let data = std::fs::read(&path).unwrap();
+        let mut padded = data.clone();
+        padded.resize(4096, 0);
+        std::fs::write(&path, &padded).unwrap();
+
+        let reader = WalReader::new(&path);
+        let reader = match protection {
+            Some(protection) => reader.protection(protection),
+            None => reader,
+        };
+        let records: Vec<_> = reader.records().map(|record| record.unwrap()).collect();
+        assert_eq!(records.len(), 2);
+        assert_eq!(std::fs::metadata(&path).unwrap().len(), 4096);
+
+        // 只读打开时不截掉填充
+        let read_only = JournalOptions {
+            read_only: true,
+            ..options
+        };
+        let wal = Journal::open_segments(5, vec![path.clone()], read_only).unwrap();
+        assert_eq!(wal.num_of_records(), 2);
+        assert_eq!(wal.padding_bytes(), 4096 - data.len() as u64);
+        assert_eq!(std::fs::metadata(&path).unwrap().len(), 4096);
+        drop(wal);
+
+        // 打开时截掉填充，之后的记录紧接在完整的记录之后
+        {
+            let wal = Journal::open_segments(5, vec![path.clone()], options).unwrap();
+            assert_eq!(wal.num_of_records(), 2);
+            assert_eq!(wal.padding_bytes(), 4096 - data.len() as u64);
+            assert_eq!(std::fs::metadata(&path).unwrap().len(), data.len() as u64);
+            wal.write(test_batches()).unwrap();
+            wal.flush().unwrap();
+        }
+        let wal = Journal::open_segments(5, vec![path.clone()], options).unwrap();
+        assert_eq!(wal.num_of_records(), 3);
+        assert_eq!(wal.padding_bytes(), 0);
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    // 0 之后还有数据时是损坏
+    let path = dir.path().join("00006.LOG");
+    let options = JournalOptions {
+        protection: Some(WalProtection::mac_only([7u8; 32])),
+        ..Default::default()
+    };
+    {
+        let wal = Journal::open_segments(6, vec![path.clone()], options).unwrap();
+        wal.write(test_batches()).unwrap();
+        wal.flush().unwrap();
+    }
+    let mut data = std::fs::read(&path).unwrap();
+    let record_end = data.len() as u64;
+    data.extend_from_slice(&[0; 64]);
+    data.push(1);
+    std::fs::write(&path, &data).unwrap();
+    let err = Journal::open_segments(6, vec![path.clone()], options)
+        .unwrap_err()
+        .downcast::<WalCorruptionError>()
+        .unwrap();
+    assert_eq!(err.offset, record_end);
+}
+
+#[test]
+fn test_journal_write_then_order() {
+    let dir = tempfile::tempdir().unwrap();
+    let file_path = dir.path().join("LOG");
+    let wal = Arc::new(Journal::open(1, file_path.clone()).unwrap());
+    let seq = Arc::new(std::sync::atomic::AtomicU64::new(0));
+    let handles = (0..4)
+        .map(|t| {
+            let (wal, seq) = (wal.clone(), seq.clone());
+            std::thread::spawn(move || {
+                (0..100)
+                    .map(|i| {
+                        let key = Bytes::from(format!("t{}-{}", t, i));
+                        let entry = EntryBuilder::new()
+                            .op_type(OpType::Put)
+                            .key_value(key.clone(), Bytes::from("v"))
+                            .build();
+                        let seq = wal
+                            .write_then(vec![entry], || {
+                                seq.fetch_add(1, std::sync::atomic::Ordering::SeqCst)
+                            })
+                            .unwrap();
+                        (seq, key)
+                    })
+                    .collect::<Vec<_>>()
+            })
+        })
+        .collect::<Vec<_>>();
+    let mut allocated = handles
+        .into_iter()
+        .flat_map(|h| h.join().unwrap())
+        .collect::<Vec<_>>();
+    allocated.sort();
+    drop(wal);
+
+    // 记录在 WAL 中的顺序与 `write_then` 中分配的顺序一致
+    let wal = Arc::new(Journal::open(1, file_path).unwrap());
+    let mut iter = JournalIterator::create_and_seek_to_first(wal).unwrap();
+    for (_, key) in allocated {
+        assert!(iter.is_valid());
+        assert_eq!(iter.record_item().as_ref().key, key);
+        iter.next().unwrap();
+    }
+    assert!(!iter.is_valid());
+}
@@ -0,0 +1,58 @@
+use bytes::Bytes;
+use crossbeam::channel;
+use parking_lot::Mutex;
+
+use crate::entry::Entry;
+use crate::system::is_system_key;
+use crate::OpType;
+
+/// 一次已提交的修改，`value` 为 `None` 表示删除
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct WatchEvent {
+    pub key: Bytes,
+    pub value: Option<Bytes>,
+}
+
+/// 订阅了 key 前缀的 watcher，接收端被 drop 后在下一次通知时移除
+#[derive(Debug, Default)]
+pub(crate) struct Watchers {
+    watchers: Mutex<Vec<(Bytes, channel::Sender<WatchEvent>)>>,
+}
+
+impl Watchers {
+    pub(crate) fn watch(&self, prefix: Bytes) -> channel::Receiver<WatchEvent> {
+        let (tx, rx) = channel::unbounded();
+        self.watchers.lock().push((prefix, tx));
+        rx
+    }
+
+    pub(crate) fn len(&self) -> usize {
+        self.watchers.lock().len()
+    }
+
+    /// 通知一次写入中的修改，同一次写入的修改按写入顺序连续送达
+    pub(crate) fn notify(&self, entries: &[Entry]) {
+        let mut watchers = self.watchers.lock();
+        if watchers.is_empty() {
+            return;
+        }
+        watchers.retain(|(prefix, tx)| {
+            entries
+                .iter()
+                .filter(|e| {
+                    !e.is_idempotency_token() && !is_system_key(&e.key) && e.key.starts_with(prefix)
+                })
+                .all(|e| {
+                    let value = match e.op_type() {
+                        OpType::Delete => None,
+                        _ => Some(e.value.clone()),
+                    };
+                    tx.send(WatchEvent {
+                        key: e.key.clone(),
+                        value,
+                    })
+                    .is_ok()
+                })
+        });
+    }
+}
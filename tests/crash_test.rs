@@ -0,0 +1,67 @@
+#![cfg(feature = "test-util")]
+
+use bytes::Bytes;
+use lasagnedb::test_util::{CrashTest, Durability, DurabilityViolation};
+use lasagnedb::WriteOptions;
+
+fn key(i: usize) -> Bytes {
+    Bytes::from(format!("key{:05}", i))
+}
+
+#[test]
+fn test_crash_sync_writes() {
+    let data_dir = tempfile::tempdir().unwrap();
+    let report = CrashTest::new(data_dir.path(), |w| {
+        let base = w.cycle() * 100;
+        for i in base..base + 100 {
+            w.put(key(i), Bytes::from(format!("value{}", i)))?;
+        }
+        for i in (base..base + 100).step_by(3) {
+            w.delete(key(i))?;
+        }
+        if w.cycle() == 1 {
+            w.flush()?;
+        }
+        Ok(())
+    })
+    .cycles(3)
+    .run()
+    .unwrap();
+    assert_eq!(report.cycles, 3);
+    assert_eq!(report.operations, 3 * 134);
+    assert_eq!(report.lost_operations, 0);
+}
+
+#[test]
+fn test_crash_unsynced_writes() {
+    let no_sync = WriteOptions {
+        sync: false,
+        ..Default::default()
+    };
+    let workload = |w: &mut lasagnedb::test_util::Workload| {
+        let base = w.cycle() * 1000;
+        for i in base..base + 1000 {
+            w.put(key(i), Bytes::from(format!("value{}", i)))?;
+            if i == base + 500 {
+                w.flush()?;
+            }
+        }
+        Ok(())
+    };
+
+    // 未同步的写入可能丢失，但恢复出的数据总是写入序列的前缀
+    let data_dir = tempfile::tempdir().unwrap();
+    let report = CrashTest::new(data_dir.path(), workload)
+        .write_options(no_sync.clone())
+        .durability(Durability::Prefix)
+        .run()
+        .unwrap();
+    assert!(report.lost_operations > 0);
+
+    let data_dir = tempfile::tempdir().unwrap();
+    let err = CrashTest::new(data_dir.path(), workload)
+        .write_options(no_sync)
+        .run()
+        .unwrap_err();
+    assert_eq!(err.downcast_ref::<DurabilityViolation>().unwrap().cycle, 0);
+}
@@ -0,0 +1,34 @@
+#![cfg(feature = "failpoints")]
+
+use std::time::{Duration, Instant};
+
+use bytes::Bytes;
+use lasagnedb::{Db, IoFaults, IoOp};
+
+// failpoint 是进程级配置，注入的故障会影响同一进程内的其它测试，因此单独放在一个集成测试中
+#[test]
+fn test_io_faults() {
+    let data_dir = tempfile::tempdir().unwrap();
+    let db = Db::open_file(data_dir.path()).unwrap();
+    let key = Bytes::from("k1");
+
+    IoFaults::new()
+        .error_rate(IoOp::Write, 1.0)
+        .apply()
+        .unwrap();
+    assert!(db.put(key.clone(), Bytes::from("v1")).is_err());
+
+    IoFaults::new()
+        .latency(IoOp::Sync, Duration::from_millis(50))
+        .apply()
+        .unwrap();
+    let start = Instant::now();
+    db.put(key.clone(), Bytes::from("v2")).unwrap();
+    assert!(start.elapsed() >= Duration::from_millis(50));
+
+    IoFaults::clear();
+    db.put(key.clone(), Bytes::from("v3")).unwrap();
+    assert_eq!(db.get(&key).unwrap(), Some(Bytes::from("v3")));
+
+    assert!(IoFaults::new().error_rate(IoOp::Read, 2.0).apply().is_err());
+}
@@ -0,0 +1,69 @@
+#![cfg(feature = "failpoints")]
+
+use std::collections::BTreeSet;
+use std::ffi::OsString;
+use std::fs;
+use std::path::Path;
+
+use bytes::Bytes;
+use lasagnedb::{Db, DbOptions};
+
+fn wal_files(path: &Path) -> BTreeSet<OsString> {
+    fs::read_dir(path)
+        .unwrap()
+        .map(|entry| entry.unwrap().path())
+        .filter(|path| path.extension().is_some_and(|ext| ext == "LOG"))
+        .map(|path| path.file_name().unwrap().to_owned())
+        .collect()
+}
+
+// failpoint 是进程级配置，与 `failpoints.rs` 分开，避免其中注入的 IO 故障影响这里的刷写
+#[test]
+fn test_flush_deletes_wal_after_manifest() {
+    let data_dir = tempfile::tempdir().unwrap();
+    let path = data_dir.path();
+    let k1 = Bytes::from("k1");
+    let k2 = Bytes::from("k2");
+
+    // SST 还没有记入 MANIFEST 时失败，WAL 保留，重新打开后从 WAL 恢复
+    {
+        let db = Db::open_with_options(path, DbOptions::default()).unwrap();
+        db.put(k1.clone(), Bytes::from("v1")).unwrap();
+        let wals = wal_files(path);
+        fail::cfg("flush::before_manifest", "return").unwrap();
+        assert!(db.flush().is_err());
+        fail::remove("flush::before_manifest");
+        assert!(wal_files(path).is_superset(&wals));
+    }
+    // 刷写失败留下的冻结 memtable 从 WAL 重放，再次刷写成功后 WAL 被删除，之后不再重放
+    {
+        let db = Db::open_with_options(path, DbOptions::default()).unwrap();
+        assert_eq!(db.get(&k1).unwrap(), Some(Bytes::from("v1")));
+        assert_eq!(db.stats().recovery.wal_records, 1);
+        assert_eq!(db.stats().frozen_memtables, 1);
+        db.flush().unwrap();
+        assert_eq!(db.stats().frozen_memtables, 0);
+    }
+    {
+        let db = Db::open_with_options(path, DbOptions::default()).unwrap();
+        assert_eq!(db.stats().recovery.wal_records, 0);
+        assert_eq!(db.get(&k1).unwrap(), Some(Bytes::from("v1")));
+    }
+
+    // SST 已记入 MANIFEST、WAL 还没有删除时失败，重新打开后从 SST 读取
+    {
+        let db = Db::open_with_options(path, DbOptions::default()).unwrap();
+        db.put(k2.clone(), Bytes::from("v2")).unwrap();
+        let wals = wal_files(path);
+        fail::cfg("flush::before_delete_wal", "return").unwrap();
+        assert!(db.flush().is_err());
+        fail::remove("flush::before_delete_wal");
+        assert!(wal_files(path).is_superset(&wals));
+        assert!(!db.level_metadata()[0].files.is_empty());
+    }
+    let db = Db::open_with_options(path, DbOptions::default()).unwrap();
+    assert_eq!(db.get(&k1).unwrap(), Some(Bytes::from("v1")));
+    assert_eq!(db.get(&k2).unwrap(), Some(Bytes::from("v2")));
+    db.flush().unwrap();
+    assert_eq!(db.get(&k2).unwrap(), Some(Bytes::from("v2")));
+}
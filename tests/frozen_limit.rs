@@ -0,0 +1,69 @@
+#![cfg(feature = "failpoints")]
+
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+use bytes::Bytes;
+use lasagnedb::{BusyError, Db, DbClosedError, DbOptions, FrozenLimitAction};
+
+// failpoint 是进程级配置，两个场景放在一个测试中依次执行，也不与 `flush_failpoints.rs` 共用进程
+#[test]
+fn test_frozen_limits() {
+    // 刷写持续失败时冻结的 memtable 和 WAL 留在内存中，超过上限后拒绝写入
+    let data_dir = tempfile::tempdir().unwrap();
+    let options = DbOptions {
+        max_frozen_memtables: Some(1),
+        frozen_limit_action: FrozenLimitAction::Busy,
+        ..Default::default()
+    };
+    let db = Db::open_with_options(data_dir.path(), options).unwrap();
+    fail::cfg("flush::before_manifest", "return").unwrap();
+    for i in 0..2 {
+        db.put(Bytes::from(format!("k{}", i)), Bytes::from("v"))
+            .unwrap();
+        assert!(db.flush().is_err());
+    }
+    fail::remove("flush::before_manifest");
+    assert_eq!(db.stats().frozen_memtables, 2);
+    assert!(!db.health().is_ok());
+
+    let k2 = Bytes::from("k2");
+    let err = db.put(k2.clone(), Bytes::from("v")).unwrap_err();
+    assert_eq!(
+        err.downcast_ref::<BusyError>(),
+        Some(&BusyError {
+            frozen_memtables: 2,
+            frozen_wals: 2,
+        })
+    );
+    assert_eq!(db.get(&k2).unwrap(), None);
+    // 读取不受影响
+    assert_eq!(db.get(&Bytes::from("k0")).unwrap(), Some(Bytes::from("v")));
+    drop(db);
+
+    // 默认阻塞写入，直到数据库关闭
+    let data_dir = tempfile::tempdir().unwrap();
+    let options = DbOptions {
+        max_frozen_wals: Some(0),
+        ..Default::default()
+    };
+    let db = Arc::new(Db::open_with_options(data_dir.path(), options).unwrap());
+    db.put(Bytes::from("k0"), Bytes::from("v")).unwrap();
+    fail::cfg("flush::before_manifest", "return").unwrap();
+    assert!(db.flush().is_err());
+    fail::remove("flush::before_manifest");
+
+    let writer = {
+        let db = db.clone();
+        thread::spawn(move || db.put(Bytes::from("k1"), Bytes::from("v")))
+    };
+    thread::sleep(Duration::from_millis(50));
+    assert!(!writer.is_finished());
+    db.close().unwrap();
+    let err = writer.join().unwrap().unwrap_err();
+    assert!(err.downcast_ref::<DbClosedError>().is_some());
+    let stalls = db.stats().write_stalls;
+    assert_eq!(stalls.frozen_limit, 1);
+    assert!(stalls.delay >= Duration::from_millis(50));
+}
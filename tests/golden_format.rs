@@ -0,0 +1,81 @@
+#![cfg(feature = "test-util")]
+
+use std::fs;
+use std::ops::Bound::Unbounded;
+use std::path::{Path, PathBuf};
+
+use lasagnedb::test_util::golden::{self, GOLDEN_FILES};
+use lasagnedb::{Db, StorageIterator};
+
+/// 设置该环境变量时重新生成检入的样例，而不是与之比较
+const BLESS_ENV: &str = "LASAGNEDB_BLESS";
+
+fn fixture_dir() -> PathBuf {
+    Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/fixtures/golden")
+}
+
+#[test]
+fn test_golden_files() {
+    let dir = tempfile::tempdir().unwrap();
+    golden::generate(dir.path()).unwrap();
+    let mut generated: Vec<_> = fs::read_dir(dir.path())
+        .unwrap()
+        .map(|entry| entry.unwrap().file_name().into_string().unwrap())
+        .collect();
+    generated.sort();
+    let mut expected = GOLDEN_FILES.map(String::from).to_vec();
+    expected.sort();
+    assert_eq!(generated, expected);
+
+    if std::env::var_os(BLESS_ENV).is_some() {
+        fs::create_dir_all(fixture_dir()).unwrap();
+        for name in GOLDEN_FILES {
+            fs::copy(dir.path().join(name), fixture_dir().join(name)).unwrap();
+        }
+        return;
+    }
+    for name in GOLDEN_FILES {
+        let actual = fs::read(dir.path().join(name)).unwrap();
+        let fixture = fs::read(fixture_dir().join(name)).unwrap();
+        let diff_at = actual
+            .iter()
+            .zip(&fixture)
+            .position(|(a, b)| a != b)
+            .unwrap_or(actual.len().min(fixture.len()));
+        assert!(
+            actual == fixture,
+            "{} differs from the fixture at byte {} ({} vs {} bytes), \
+             rerun with {}=1 if the format change is intended",
+            name,
+            diff_at,
+            actual.len(),
+            fixture.len(),
+            BLESS_ENV
+        );
+    }
+}
+
+#[test]
+fn test_open_golden_files() {
+    // 打开会改写 MANIFEST 和 CURRENT，在副本上进行
+    let dir = tempfile::tempdir().unwrap();
+    for name in GOLDEN_FILES {
+        fs::copy(fixture_dir().join(name), dir.path().join(name)).unwrap();
+    }
+    let db = Db::open_file(dir.path()).unwrap();
+    let mut iter = db.scan(Unbounded, Unbounded).unwrap();
+    let mut contents = vec![];
+    while iter.is_valid() {
+        contents.push((
+            bytes::Bytes::copy_from_slice(iter.key()),
+            bytes::Bytes::copy_from_slice(iter.value()),
+        ));
+        iter.next().unwrap();
+    }
+    drop(iter);
+    assert_eq!(
+        contents,
+        golden::expected_contents().into_iter().collect::<Vec<_>>()
+    );
+    db.close().unwrap();
+}
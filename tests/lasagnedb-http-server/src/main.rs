@@ -1,9 +1,14 @@
+use actix_web::error::{ErrorBadRequest, ErrorInternalServerError};
 use actix_web::{get, post, web, App, HttpResponse, HttpServer, Responder};
+use base64::engine::general_purpose::STANDARD as BASE64;
+use base64::Engine;
 use bytes::{BufMut, Bytes, BytesMut};
 use futures::StreamExt;
 use lasagnedb::{Db, StorageIterator};
-use serde::Deserialize;
+use serde::{Deserialize, Serialize};
+use std::cell::Cell;
 use std::ops::Bound;
+use std::rc::Rc;
 use std::sync::Arc;
 use tempfile::TempDir;
 use tracing::{instrument, trace};
@@ -15,15 +20,92 @@ struct ServerState {
     db: Arc<lasagnedb::Db>,
 }
 
+/// 请求中 key 的编码，`encoding=hex` 或 `encoding=base64` 时可以使用任意二进制 key，默认按 UTF-8 文本
+#[derive(Deserialize, Debug, Clone, Copy, Default)]
+#[serde(rename_all = "lowercase")]
+enum KeyEncoding {
+    #[default]
+    Utf8,
+    Hex,
+    Base64,
+}
+
+impl KeyEncoding {
+    fn decode(self, key: &str) -> actix_web::Result<Bytes> {
+        match self {
+            KeyEncoding::Utf8 => Ok(Bytes::copy_from_slice(key.as_bytes())),
+            KeyEncoding::Hex => {
+                if key.len() % 2 != 0 {
+                    return Err(ErrorBadRequest("hex key has odd length"));
+                }
+                (0..key.len())
+                    .step_by(2)
+                    .map(|i| u8::from_str_radix(key.get(i..i + 2).unwrap_or("x"), 16))
+                    .collect::<Result<Vec<u8>, _>>()
+                    .map(Bytes::from)
+                    .map_err(|_| ErrorBadRequest("invalid hex key"))
+            }
+            KeyEncoding::Base64 => BASE64
+                .decode(key)
+                .map(Bytes::from)
+                .map_err(|_| ErrorBadRequest("invalid base64 key")),
+        }
+    }
+
+    /// JSON 响应中的 key 和 value 按同样的编码输出，UTF-8 编码时遇到非 UTF-8 的数据返回错误，scan 的响应会被中断
+    fn encode(self, data: &[u8]) -> actix_web::Result<String> {
+        match self {
+            KeyEncoding::Utf8 => String::from_utf8(data.to_vec()).map_err(|_| {
+                ErrorBadRequest("data is not valid utf-8, use hex or base64 encoding")
+            }),
+            KeyEncoding::Hex => Ok(data.iter().map(|b| format!("{:02x}", b)).collect()),
+            KeyEncoding::Base64 => Ok(BASE64.encode(data)),
+        }
+    }
+}
+
 #[derive(Deserialize, Debug)]
 struct KeyRequest {
     key: String,
+    #[serde(default)]
+    encoding: KeyEncoding,
+}
+
+#[derive(Deserialize, Debug)]
+struct MGetRequest {
+    keys: Vec<String>,
+    #[serde(default)]
+    encoding: KeyEncoding,
+}
+
+/// scan 响应的格式
+#[derive(Deserialize, Debug, Clone, Copy, Default)]
+#[serde(rename_all = "lowercase")]
+enum ScanFormat {
+    /// 每个 value 为 8 字节长度加内容，不包含 key
+    #[default]
+    Binary,
+    /// `[{"key": ..., "value": ...}, ...]`，key 和 value 按请求的 `encoding` 编码
+    Json,
 }
 
 #[derive(Deserialize, Debug)]
 struct ScanRequest {
     key: String,
     count: u32,
+    #[serde(default)]
+    encoding: KeyEncoding,
+    #[serde(default)]
+    format: ScanFormat,
+    /// 设置时 key 和 value 累计约 `max_bytes` 字节后停止，下一段的起始 key 按 `encoding` 编码后放在
+    /// [`NEXT_KEY_HEADER`] 响应头中，没有该响应头时范围已读完
+    max_bytes: Option<usize>,
+}
+
+#[derive(Serialize, Debug)]
+struct ScanItem {
+    key: String,
+    value: String,
 }
 
 #[get("/info")]
@@ -37,34 +119,152 @@ async fn get(
     state: web::Data<ServerState>,
     query: web::Query<KeyRequest>,
 ) -> actix_web::Result<impl Responder> {
-    let key = Bytes::from(query.into_inner().key);
-    let data = state.db.get(&key).unwrap();
+    let query = query.into_inner();
+    let key = query.encoding.decode(&query.key)?;
+    let data = state.db.get(&key).map_err(ErrorInternalServerError)?;
     Ok(match data {
         None => HttpResponse::Ok().body(vec![]),
         Some(_data) => HttpResponse::Ok().body(_data),
     })
 }
 
+/// 请求体为 `{"keys": [...], "encoding": "hex"}`，按请求顺序返回每个 key 的结果：
+/// 1 字节的 found 标记，找到时后跟 8 字节长度和 value
+#[instrument(skip(state))]
+#[post("/mget")]
+async fn mget(
+    state: web::Data<ServerState>,
+    body: web::Json<MGetRequest>,
+) -> actix_web::Result<impl Responder> {
+    let body = body.into_inner();
+    let keys = body
+        .keys
+        .iter()
+        .map(|key| body.encoding.decode(key))
+        .collect::<actix_web::Result<Vec<Bytes>>>()?;
+    let snapshot = state.db.snapshot();
+    let values = state
+        .db
+        .get_many_with_snapshot(&snapshot, &keys)
+        .map_err(ErrorInternalServerError)?;
+
+    let mut result = BytesMut::new();
+    for value in values {
+        match value {
+            None => result.put_u8(0),
+            Some(_val) => {
+                result.put_u8(1);
+                result.put_u64_le(_val.len() as u64);
+                result.extend(_val);
+            }
+        }
+    }
+    Ok(HttpResponse::Ok().body(result.freeze()))
+}
+
+/// 单次 scan 在内存中暂存的结果上限，超过后写入临时文件
+const SCAN_MEMORY_LIMIT: usize = 4 * lasagnedb::MB;
+
+/// 单次 scan 最多返回的 key 数量
+const MAX_SCAN_COUNT: u32 = 100_000;
+
+/// 按字节数分段的 scan 响应中下一段的起始 key
+const NEXT_KEY_HEADER: &str = "x-next-key";
+
+/// 从 `key`（包含）开始最多返回 `count` 个 KV，`format=json` 时返回 [`ScanItem`] 数组，
+/// 设置 `max_bytes` 时分段返回，见 [`ScanRequest::max_bytes`]
 #[instrument(skip(state))]
 #[get("/scan")]
-async fn scan(state: web::Data<ServerState>, query: web::Query<ScanRequest>) -> impl Responder {
+async fn scan(
+    state: web::Data<ServerState>,
+    query: web::Query<ScanRequest>,
+) -> actix_web::Result<impl Responder> {
+    let query = query.into_inner();
+    if query.count > MAX_SCAN_COUNT {
+        return Err(ErrorBadRequest(format!(
+            "count must not exceed {}",
+            MAX_SCAN_COUNT
+        )));
+    }
     let mut limit = query.count;
-    let key = Bytes::from(query.into_inner().key);
+    let key = query.encoding.decode(&query.key)?;
 
-    let mut iter = state
-        .db
-        .scan(Bound::Included(key), Bound::Unbounded)
-        .unwrap();
-    let mut result = BytesMut::new();
-    while iter.is_valid() && limit > 0 {
-        let _val = iter.value();
-        result.put_u64_le(_val.len() as u64);
-        result.extend(_val);
-        limit -= 1;
-        iter.next().unwrap();
+    type Items = Box<dyn Iterator<Item = Result<(Bytes, Bytes), lasagnedb::Error>>>;
+    let (result, next_key): (Items, Option<Bytes>) = match query.max_bytes {
+        // 分段的结果不超过 `max_bytes`（外加一个 KV），直接放在内存中
+        Some(max_bytes) => {
+            let mut chunk = state
+                .db
+                .scan_bytes(Bound::Included(key), Bound::Unbounded, max_bytes)
+                .map_err(ErrorInternalServerError)?;
+            if chunk.items.len() > limit as usize {
+                chunk.next_key = Some(chunk.items[limit as usize].0.clone());
+                chunk.items.truncate(limit as usize);
+            }
+            (Box::new(chunk.items.into_iter().map(Ok)), chunk.next_key)
+        }
+        None => {
+            let mut iter = state
+                .db
+                .scan(Bound::Included(key), Bound::Unbounded)
+                .map_err(ErrorInternalServerError)?;
+            let mut staging = state.db.staging(SCAN_MEMORY_LIMIT);
+            while iter.is_valid() && limit > 0 {
+                staging
+                    .push(iter.key(), iter.value())
+                    .map_err(ErrorInternalServerError)?;
+                limit -= 1;
+                iter.next().map_err(ErrorInternalServerError)?;
+            }
+            drop(iter);
+            let result = staging.finish().map_err(ErrorInternalServerError)?;
+            (Box::new(result), None)
+        }
+    };
+
+    // 边读暂存结果边发送，响应体不会整体放在内存中
+    let encoding = query.encoding;
+    let mut response = HttpResponse::Ok();
+    if let Some(next_key) = next_key {
+        response.insert_header((NEXT_KEY_HEADER, encoding.encode(&next_key)?));
     }
-    let _result = result.freeze();
-    HttpResponse::Ok().body(_result)
+    let format = query.format;
+    let started = Rc::new(Cell::new(false));
+    let _started = started.clone();
+    let items = result.map(move |item| {
+        let (_key, _val) = item.map_err(ErrorInternalServerError)?;
+        Ok::<_, actix_web::Error>(match format {
+            ScanFormat::Binary => {
+                let mut buf = BytesMut::with_capacity(8 + _val.len());
+                buf.put_u64_le(_val.len() as u64);
+                buf.extend(_val);
+                buf.freeze()
+            }
+            ScanFormat::Json => {
+                let item = ScanItem {
+                    key: encoding.encode(&_key)?,
+                    value: encoding.encode(&_val)?,
+                };
+                let mut buf = if _started.replace(true) {
+                    vec![b',']
+                } else {
+                    vec![b'[']
+                };
+                serde_json::to_writer(&mut buf, &item).map_err(ErrorInternalServerError)?;
+                Bytes::from(buf)
+            }
+        })
+    });
+    let body = match format {
+        ScanFormat::Binary => futures::stream::iter(items).boxed_local(),
+        // 第一项自带 `[`，结果为空时只输出 `[]`
+        ScanFormat::Json => futures::stream::iter(items)
+            .chain(futures::stream::once(async move {
+                Ok(Bytes::from_static(if started.get() { b"]" } else { b"[]" }))
+            }))
+            .boxed_local(),
+    };
+    Ok(response.streaming(body))
 }
 
 #[instrument(skip(state, payload))]
@@ -73,22 +273,30 @@ async fn put(
     state: web::Data<ServerState>,
     query: web::Query<KeyRequest>,
     mut payload: web::Payload,
-) -> impl Responder {
-    let key = Bytes::from(query.into_inner().key);
+) -> actix_web::Result<impl Responder> {
+    let query = query.into_inner();
+    let key = query.encoding.decode(&query.key)?;
     let mut value = BytesMut::new();
     while let Some(item) = payload.next().await {
-        value.extend_from_slice(item.unwrap().as_ref())
+        value.extend_from_slice(item?.as_ref())
     }
-    state.db.put(key, value.freeze()).unwrap();
-    HttpResponse::Ok().finish()
+    state
+        .db
+        .put(key, value.freeze())
+        .map_err(ErrorInternalServerError)?;
+    Ok(HttpResponse::Ok().finish())
 }
 
 #[instrument(skip(state))]
 #[post("/del")]
-async fn del(state: web::Data<ServerState>, query: web::Query<KeyRequest>) -> impl Responder {
-    let key = Bytes::from(query.into_inner().key);
-    state.db.delete(key).unwrap();
-    HttpResponse::Ok().finish()
+async fn del(
+    state: web::Data<ServerState>,
+    query: web::Query<KeyRequest>,
+) -> actix_web::Result<impl Responder> {
+    let query = query.into_inner();
+    let key = query.encoding.decode(&query.key)?;
+    state.db.delete(key).map_err(ErrorInternalServerError)?;
+    Ok(HttpResponse::Ok().finish())
 }
 
 fn setup() {
@@ -121,6 +329,7 @@ async fn main() -> std::io::Result<()> {
                 db: _db.clone(),
             }))
             .service(get)
+            .service(mget)
             .service(scan)
             .service(put)
             .service(del)
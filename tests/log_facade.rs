@@ -0,0 +1,120 @@
+#![cfg(feature = "log-facade")]
+
+use std::sync::Arc;
+
+use bytes::Bytes;
+use lasagnedb::log_store::{LogMessage, LogStore, OffsetRange};
+use lasagnedb::{Db, DbOptions};
+
+fn message(offset: u64) -> LogMessage {
+    LogMessage {
+        offset,
+        value: Bytes::from(format!("message-{}", offset)),
+    }
+}
+
+#[test]
+fn test_append_read_truncate() {
+    let data_dir = tempfile::tempdir().unwrap();
+    {
+        let db = Arc::new(Db::open_file(data_dir.path()).unwrap());
+        let log = LogStore::new(db.clone(), "log/");
+        for offset in 0..10 {
+            assert_eq!(log.append("a", message(offset).value).unwrap(), offset);
+        }
+        let values = (10..3000).map(|offset| message(offset).value).collect();
+        assert_eq!(log.append_batch("a", values).unwrap(), 10);
+        // topic 之间互不影响，名字是另一个 topic 的前缀时也一样
+        assert_eq!(log.append("", Bytes::from("other")).unwrap(), 0);
+        assert_eq!(log.append("ab", Bytes::from("other")).unwrap(), 0);
+        // 同一数据库中的其他数据不会混入日志
+        db.put(Bytes::from("log/z"), Bytes::from("v")).unwrap();
+
+        assert_eq!(
+            log.read("a", 5, 3).unwrap(),
+            vec![message(5), message(6), message(7)]
+        );
+        assert_eq!(
+            log.read("a", 2998, 10).unwrap(),
+            vec![message(2998), message(2999)]
+        );
+        assert!(log.read("a", 3000, 10).unwrap().is_empty());
+
+        // 跨越多个删除批次
+        assert_eq!(log.truncate("a", 2500).unwrap(), 2500);
+        assert_eq!(log.truncate("a", 100).unwrap(), 0);
+        assert_eq!(
+            log.offsets("a").unwrap(),
+            OffsetRange {
+                start: 2500,
+                next: 3000
+            }
+        );
+        assert_eq!(log.read("a", 0, 1).unwrap(), vec![message(2500)]);
+        assert_eq!(log.read("ab", 0, 10).unwrap().len(), 1);
+        db.close().unwrap();
+    }
+
+    // 重新打开后 offset 范围从元数据恢复，继续递增
+    let db = Arc::new(Db::open_file(data_dir.path()).unwrap());
+    let log = LogStore::new(db.clone(), "log/");
+    assert_eq!(
+        log.offsets("a").unwrap(),
+        OffsetRange {
+            start: 2500,
+            next: 3000
+        }
+    );
+    assert_eq!(log.append("a", message(3000).value).unwrap(), 3000);
+    // 截断到超过末尾时删除所有消息，offset 不会重用
+    assert_eq!(log.truncate("a", u64::MAX).unwrap(), 501);
+    assert!(log.read("a", 0, 10).unwrap().is_empty());
+    assert_eq!(log.append("a", message(3001).value).unwrap(), 3001);
+    assert_eq!(log.offsets("missing").unwrap(), OffsetRange::default());
+    db.close().unwrap();
+}
+
+#[test]
+fn test_tail() {
+    let data_dir = tempfile::tempdir().unwrap();
+    let options = DbOptions {
+        memtable_size_limit: 4096,
+        ..Default::default()
+    };
+    let db = Arc::new(Db::open_file_with_options(data_dir.path(), options).unwrap());
+    let log = Arc::new(LogStore::new(db.clone(), "log/"));
+    log.append("a", message(0).value).unwrap();
+
+    let writer = {
+        let log = log.clone();
+        std::thread::spawn(move || {
+            for offset in 1..500 {
+                log.append("a", message(offset).value).unwrap();
+                log.append("b", Bytes::from("other")).unwrap();
+            }
+        })
+    };
+    // 尾随读取跨越刷写，每条消息恰好读到一次
+    let mut tail = log.tail("a", 0).unwrap();
+    let mut next = 0;
+    while next < 500 {
+        match tail.poll().unwrap() {
+            Some(received) => {
+                assert_eq!(received, message(next));
+                next += 1;
+            }
+            None => std::thread::yield_now(),
+        }
+    }
+    writer.join().unwrap();
+    assert!(tail.poll().unwrap().is_none());
+    drop(tail);
+
+    // 从中间的 offset 开始读
+    let mut tail = log.tail("a", 498).unwrap();
+    assert_eq!(tail.poll().unwrap(), Some(message(498)));
+    assert_eq!(tail.poll().unwrap(), Some(message(499)));
+    assert_eq!(tail.poll().unwrap(), None);
+    drop(tail);
+    db.close().unwrap();
+}
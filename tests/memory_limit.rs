@@ -0,0 +1,68 @@
+use std::ops::Bound;
+
+use bytes::Bytes;
+use lasagnedb::{
+    read_memory_limit, read_memory_used, set_read_memory_limit, Db, MemoryLimitError,
+    StorageIterator,
+};
+
+fn key(i: usize) -> Bytes {
+    Bytes::from(format!("key{:03}", i))
+}
+
+// 读内存上限是进程级配置，不与其他测试共用进程
+#[test]
+fn test_read_memory_limit() {
+    let data_dir = tempfile::tempdir().unwrap();
+    let db = Db::open_file(data_dir.path()).unwrap();
+    for i in 0..3 {
+        db.put(key(i), Bytes::from(vec![b'v'; 8000])).unwrap();
+    }
+
+    set_read_memory_limit(Some(10_000));
+    assert_eq!(read_memory_limit(), Some(10_000));
+    assert_eq!(db.get(&key(0)).unwrap().unwrap().len(), 8000);
+
+    let err = db.multi_get(&[key(0), key(1)]).unwrap_err();
+    assert_eq!(
+        err.downcast_ref::<MemoryLimitError>(),
+        Some(&MemoryLimitError {
+            requested: 8000,
+            used: 8000,
+            limit: 10_000,
+        })
+    );
+    assert!(db
+        .scan_bytes(Bound::Unbounded, Bound::Unbounded, usize::MAX)
+        .unwrap_err()
+        .is::<MemoryLimitError>());
+    // 单段结果不超过上限时可以分段读完
+    let chunk = db
+        .scan_bytes(Bound::Unbounded, Bound::Unbounded, 8000)
+        .unwrap();
+    assert_eq!(chunk.items.len(), 1);
+    assert_eq!(chunk.next_key, Some(key(1)));
+
+    // 上限小于一个块时不再预读，范围查询仍能读完
+    db.flush().unwrap();
+    set_read_memory_limit(Some(100));
+    assert!(db.get(&key(2)).unwrap_err().is::<MemoryLimitError>());
+    let mut iter = db.scan(Bound::Unbounded, Bound::Unbounded).unwrap();
+    let mut n = 0;
+    while iter.is_valid() {
+        n += 1;
+        iter.next().unwrap();
+    }
+    assert_eq!(n, 3);
+    drop(iter);
+
+    let stats = db.stats();
+    assert_eq!(stats.read_memory, 0);
+    assert_eq!(stats.read_memory_rejections, 3);
+
+    set_read_memory_limit(None);
+    assert_eq!(read_memory_limit(), None);
+    assert_eq!(db.multi_get(&[key(0), key(1), key(2)]).unwrap().len(), 3);
+    assert_eq!(read_memory_used(), 0);
+    db.close().unwrap();
+}
@@ -0,0 +1,21 @@
+#![cfg(feature = "test-util")]
+
+use lasagnedb::test_util::RecoveryBench;
+
+#[test]
+fn test_recovery_bench() {
+    let dir = tempfile::tempdir().unwrap();
+    let bench = RecoveryBench::new(dir.path())
+        .records(100)
+        .batch_size(2)
+        .value_size(10);
+    bench.populate().unwrap();
+
+    // 积压留在 WAL 中，每次打开都重放同样的记录
+    for _ in 0..2 {
+        let recovery = bench.measure().unwrap();
+        assert_eq!(recovery.wal_records, 100);
+        assert!(recovery.wal_bytes >= 100 * 2 * 10);
+        assert!(recovery.total >= recovery.wal_replay);
+    }
+}